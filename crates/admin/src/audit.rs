@@ -0,0 +1,184 @@
+/// Placeholder substituted for redacted secret values.
+pub const REDACTED: &str = "[REDACTED]";
+
+/// Keys whose values are always redacted, matched case-insensitively.
+pub(crate) const SECRET_KEYS: &[&str] = &[
+    "proxy-authorization",
+    "authorization",
+    "cookie",
+    "access_token",
+    "refresh_token",
+    "token",
+    "api_key",
+    "api_token",
+    "apikey",
+    "password",
+    "passwd",
+    "secret",
+];
+
+/// Extra input scanned beyond the preview length so a secret straddling the
+/// cut-off is still recognised and redacted.
+const REDACTION_LOOKAHEAD: usize = 256;
+
+/// Build a redacted preview of at most `max_bytes` bytes from raw request
+/// data (request line, headers, body). Returns `None` when previews are
+/// disabled (`max_bytes == 0`) or there is nothing to show.
+pub fn body_preview(raw: &[u8], max_bytes: usize) -> Option<String> {
+    if max_bytes == 0 || raw.is_empty() {
+        return None;
+    }
+
+    let window = &raw[..raw.len().min(max_bytes + REDACTION_LOOKAHEAD)];
+    let redacted = redact_secrets(&String::from_utf8_lossy(window));
+
+    let mut end = redacted.len().min(max_bytes);
+    while !redacted.is_char_boundary(end) {
+        end -= 1;
+    }
+    Some(redacted[..end].to_string())
+}
+
+/// Replace the values of `key: value` / `key=value` pairs for known secret
+/// keys, and of `Bearer <token>` credentials, with a placeholder.
+pub fn redact_secrets(text: &str) -> String {
+    let lower = text.to_ascii_lowercase();
+    let mut out = String::with_capacity(text.len());
+    let mut pos = 0;
+
+    while pos < text.len() {
+        match find_secret_value(&lower, pos) {
+            Some((start, end)) => {
+                out.push_str(&text[pos..start]);
+                out.push_str(REDACTED);
+                pos = end;
+            }
+            None => {
+                out.push_str(&text[pos..]);
+                break;
+            }
+        }
+    }
+    out
+}
+
+/// Find the byte range of the next secret value at or after `from`.
+fn find_secret_value(lower: &str, from: usize) -> Option<(usize, usize)> {
+    let mut best: Option<(usize, usize)> = None;
+
+    for key in SECRET_KEYS.iter().copied().chain(["bearer"]) {
+        let mut search = from;
+        while let Some(offset) = lower[search..].find(key) {
+            let key_start = search + offset;
+            search = key_start + key.len();
+            if best.is_some_and(|(start, _)| key_start >= start) {
+                break;
+            }
+            // Require a word boundary so e.g. "tokenizer" doesn't match.
+            let before = lower[..key_start].chars().next_back();
+            if before.is_some_and(|c| c.is_ascii_alphanumeric() || c == '_') {
+                continue;
+            }
+            if let Some(range) = value_after_key(lower, search, key == "bearer") {
+                best = Some(range);
+                break;
+            }
+        }
+    }
+    best
+}
+
+/// Locate the value following a key that ends at `pos`.
+fn value_after_key(lower: &str, pos: usize, bearer: bool) -> Option<(usize, usize)> {
+    let bytes = lower.as_bytes();
+    let mut i = pos;
+
+    let header_style = if bearer {
+        // "Bearer <token>": at least one space, then the token.
+        if bytes.get(i) != Some(&b' ') {
+            return None;
+        }
+        false
+    } else {
+        // Allow a closing quote on JSON keys: "password": "..."
+        if bytes.get(i) == Some(&b'"') {
+            i += 1;
+        }
+        while bytes.get(i) == Some(&b' ') {
+            i += 1;
+        }
+        match bytes.get(i) {
+            Some(b':') => true,
+            Some(b'=') => false,
+            _ => return None,
+        }
+    };
+    i += 1;
+    while matches!(bytes.get(i), Some(b' ') | Some(b'"')) {
+        i += 1;
+    }
+
+    let start = i;
+    while let Some(&b) = bytes.get(i) {
+        let stop = match b {
+            b'\r' | b'\n' | b'"' => true,
+            b'&' | b';' | b',' | b'}' | b' ' => !header_style,
+            _ => false,
+        };
+        if stop {
+            break;
+        }
+        i += 1;
+    }
+    (i > start).then_some((start, i))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_preview_disabled_by_default() {
+        assert_eq!(body_preview(b"GET / HTTP/1.1", 0), None);
+    }
+
+    #[test]
+    fn test_preview_truncated_to_limit() {
+        let raw = "a".repeat(1000);
+        let preview = body_preview(raw.as_bytes(), 64).unwrap();
+        assert_eq!(preview.len(), 64);
+
+        // Never split a multi-byte character.
+        let preview = body_preview("ééé".as_bytes(), 3).unwrap();
+        assert_eq!(preview, "é");
+    }
+
+    #[test]
+    fn test_authorization_redacted() {
+        let raw = b"GET /admin HTTP/1.1\r\nAuthorization: Bearer abc.def.ghi\r\nHost: x\r\n";
+        let preview = body_preview(raw, 512).unwrap();
+        assert!(!preview.contains("abc.def.ghi"));
+        assert!(preview.contains("Authorization: [REDACTED]"));
+        assert!(preview.contains("Host: x"));
+    }
+
+    #[test]
+    fn test_query_and_json_tokens_redacted() {
+        assert_eq!(
+            redact_secrets("/login?user=bob&password=hunter2&next=/"),
+            "/login?user=bob&password=[REDACTED]&next=/"
+        );
+        assert_eq!(
+            redact_secrets(r#"{"api_key": "k-123", "q": "x"}"#),
+            r#"{"api_key": "[REDACTED]", "q": "x"}"#
+        );
+        assert_eq!(redact_secrets("tokenizer=on"), "tokenizer=on");
+    }
+
+    #[test]
+    fn test_secret_at_cutoff_not_leaked() {
+        let raw = format!("{}token=supersecretvalue", "x".repeat(20));
+        let preview = body_preview(raw.as_bytes(), 30).unwrap();
+        assert!(!preview.contains("supers"));
+    }
+}
@@ -0,0 +1,116 @@
+use axum::extract::{Request, State};
+use axum::http::{header, HeaderMap, StatusCode};
+use axum::middleware::Next;
+use axum::response::{IntoResponse, Response};
+use axum::Json;
+use serde_json::json;
+
+use crate::state::SharedState;
+
+/// Middleware for admin routes that need more than network access: the
+/// request must carry `Authorization: Bearer <server.admin.api_token>`.
+/// Without a configured token every request is refused.
+pub async fn require_token(
+    State(state): State<SharedState>,
+    request: Request,
+    next: Next,
+) -> Response {
+    authorize(&state, request, next, false).await
+}
+
+/// Middleware for admin routes that change the proxy's config or state. Once
+/// `server.admin.api_token` is set they need it like [`require_token`], so
+/// nobody without it can read or replace it; without a token they stay open
+/// to anyone who can reach the admin listener.
+pub async fn require_token_if_set(
+    State(state): State<SharedState>,
+    request: Request,
+    next: Next,
+) -> Response {
+    authorize(&state, request, next, true).await
+}
+
+async fn authorize(state: &SharedState, request: Request, next: Next, open: bool) -> Response {
+    let authorized = {
+        let config = state.config.read().expect("config lock poisoned");
+        token_accepted(request.headers(), config.server.admin.api_token.as_deref(), open)
+    };
+    if !authorized {
+        return (
+            StatusCode::UNAUTHORIZED,
+            [(header::WWW_AUTHENTICATE, "Bearer")],
+            Json(json!({ "status": "error", "message": "admin API token required" })),
+        )
+            .into_response();
+    }
+    next.run(request).await
+}
+
+/// Whether a request with `headers` may proceed. With no (or an empty)
+/// `token` configured that's `open_without_token`; otherwise the request
+/// must present it.
+fn token_accepted(headers: &HeaderMap, token: Option<&str>, open_without_token: bool) -> bool {
+    match token.filter(|t| !t.is_empty()) {
+        Some(token) => bearer_matches(headers, Some(token)),
+        None => open_without_token,
+    }
+}
+
+/// Whether `headers` carry `token` as a bearer credential, compared in
+/// constant time so the token can't be guessed byte by byte.
+fn bearer_matches(headers: &HeaderMap, token: Option<&str>) -> bool {
+    let Some(token) = token.filter(|t| !t.is_empty()) else {
+        return false;
+    };
+    let Some(presented) = headers
+        .get(header::AUTHORIZATION)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.strip_prefix("Bearer "))
+    else {
+        return false;
+    };
+    presented.len() == token.len()
+        && presented
+            .bytes()
+            .zip(token.bytes())
+            .fold(0u8, |diff, (a, b)| diff | (a ^ b))
+            == 0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn bearer(value: &str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert(header::AUTHORIZATION, value.parse().unwrap());
+        headers
+    }
+
+    #[test]
+    fn test_bearer_token_required() {
+        let token = Some("s3cret-token");
+        assert!(bearer_matches(&bearer("Bearer s3cret-token"), token));
+        assert!(!bearer_matches(&bearer("Bearer s3cret-tokem"), token));
+        assert!(!bearer_matches(&bearer("Bearer s3cret"), token));
+        assert!(!bearer_matches(&bearer("Basic s3cret-token"), token));
+        assert!(!bearer_matches(&HeaderMap::new(), token));
+
+        // No token configured: nothing is let through
+        assert!(!bearer_matches(&bearer("Bearer "), Some("")));
+        assert!(!bearer_matches(&bearer("Bearer anything"), None));
+    }
+
+    #[test]
+    fn test_mutating_routes_locked_once_token_set() {
+        let token = Some("s3cret-token");
+        assert!(token_accepted(&bearer("Bearer s3cret-token"), token, true));
+        assert!(!token_accepted(&HeaderMap::new(), token, true));
+        assert!(!token_accepted(&bearer("Bearer wrong"), token, true));
+
+        // Open without a token, unlike the debug routes
+        assert!(token_accepted(&HeaderMap::new(), None, true));
+        assert!(token_accepted(&HeaderMap::new(), Some(""), true));
+        assert!(!token_accepted(&HeaderMap::new(), None, false));
+    }
+}
@@ -1,16 +1,23 @@
+pub mod audit;
+pub mod auth;
+pub mod reload;
 pub mod routes;
 pub mod state;
 
 use std::sync::Arc;
 
-use axum::routing::{delete, get, post};
+use axum::middleware;
+use axum::routing::{delete, get, post, put};
 use axum::Router;
 use tower_http::cors::{Any, CorsLayer};
 use tower_http::services::{ServeDir, ServeFile};
 
 use crate::state::SharedState;
 
-pub use state::{AppState, AuditLogEntry, SharedState as SharedStateType, WafMetrics};
+pub use state::{
+    AppState, AuditLogEntry, ConfigChangeEntry, MetricsSnapshot, SharedState as SharedStateType,
+    WafMetrics,
+};
 
 /// Build the Axum router with all admin API routes and middleware.
 pub fn build_router(state: SharedState) -> Router {
@@ -19,41 +26,98 @@ pub fn build_router(state: SharedState) -> Router {
         .allow_methods(Any)
         .allow_headers(Any);
 
-    let dashboard_enabled = {
+    let (dashboard_enabled, debug_enabled) = {
         let config = state.config.read().expect("config lock poisoned");
-        config.server.admin.dashboard
+        (config.server.admin.dashboard, config.server.admin.debug_endpoints)
     };
 
-    let api_router = Router::new()
+    let mut api_router = Router::new()
         // Health check
         .route("/api/health", get(routes::health::health_check))
         // Prometheus metrics
         .route("/api/metrics", get(routes::metrics::get_metrics))
         // Configuration management
-        .route(
-            "/api/config",
-            get(routes::config::get_config).put(routes::config::update_config),
-        )
+        .route("/api/config", get(routes::config::get_config))
+        .route("/api/config/effective", get(routes::config::get_effective_config))
+        .route("/api/config/history", get(routes::config::get_config_history))
         // WAF rules management
-        .route(
-            "/api/rules",
-            get(routes::rules::list_rules).post(routes::rules::add_rule),
-        )
+        .route("/api/rules", get(routes::rules::list_rules))
         .route("/api/rules/test", post(routes::rules::test_rule))
-        .route("/api/rules/{id}", delete(routes::rules::delete_rule))
         // Audit logs
         .route("/api/logs", get(routes::logs::get_logs))
         // Traffic statistics
         .route("/api/stats", get(routes::stats::get_stats))
+        .route("/api/stats/memory", get(routes::stats::get_memory_stats))
+        // Rate limiter statistics
+        .route(
+            "/api/rate-limit/stats",
+            get(routes::rate_limit_stats::get_rate_limit_stats),
+        )
+        .route(
+            "/api/rate-limit/status",
+            get(routes::rate_limit_stats::get_rate_limit_status),
+        )
         // Bot detection statistics
         .route("/api/bot-stats", get(routes::bot_stats::get_bot_stats))
+        .route(
+            "/api/bot-stats/fingerprints",
+            get(routes::bot_stats::get_top_fingerprints),
+        )
+        // Under-attack mode
+        .route("/api/mode/under-attack", get(routes::mode::get_under_attack))
+        // Flagged bot/scraper sessions
+        .route("/api/sessions/flagged", get(routes::sessions::get_flagged_sessions))
         // Anti-scraping statistics
         .route("/api/scraping-stats", get(routes::scraping_stats::get_scraping_stats))
+        .route(
+            "/api/scraping/identify",
+            post(routes::scraping_stats::identify_watermark),
+        )
         // GeoIP statistics
         .route("/api/geoip-stats", get(routes::geoip_stats::get_geoip_stats))
-        // Attach shared state and middleware
-        .with_state(state)
-        .layer(cors);
+        // IP reputation list validation and contents
+        .route(
+            "/api/ip-reputation/validate",
+            post(routes::ip_reputation::validate_ip_list),
+        )
+        .route(
+            "/api/ip-reputation/entries",
+            get(routes::ip_reputation::get_ip_list_entries),
+        );
+
+    // Routes that change config or state, locked by the API token once set
+    let mutating_router = Router::new()
+        .route("/api/config", put(routes::config::update_config))
+        .route(
+            "/api/config/rollback/{id}",
+            post(routes::config::rollback_config),
+        )
+        .route("/api/rules", post(routes::rules::add_rule))
+        .route("/api/rules/apply", post(routes::rules::apply_rules))
+        .route("/api/rules/{id}", delete(routes::rules::delete_rule))
+        .route("/api/mode/under-attack", post(routes::mode::set_under_attack))
+        .route("/api/sessions", delete(routes::sessions::clear_sessions))
+        // Immediate eviction of stale per-client state
+        .route("/api/admin/cleanup", post(routes::cleanup::run_cleanup))
+        .route_layer(middleware::from_fn_with_state(
+            state.clone(),
+            auth::require_token_if_set,
+        ));
+    api_router = api_router.merge(mutating_router);
+
+    // Debug introspection, only when explicitly enabled and with the API token
+    if debug_enabled {
+        let debug_router = Router::new()
+            .route(
+                "/api/debug/rate-limit/window",
+                get(routes::rate_limit_stats::get_sliding_window_debug),
+            )
+            .route_layer(middleware::from_fn_with_state(state.clone(), auth::require_token));
+        api_router = api_router.merge(debug_router);
+    }
+
+    // Attach shared state and middleware
+    let api_router = api_router.with_state(state).layer(cors);
 
     if dashboard_enabled {
         let dashboard_dir =
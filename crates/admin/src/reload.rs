@@ -0,0 +1,224 @@
+use layer7waf_bot_detect::diversity::FingerprintCount;
+use layer7waf_common::{AppConfig, MapFootprint, RateLimitConfig};
+use layer7waf_rate_limit::RateLimiter;
+use serde::{Deserialize, Serialize};
+use std::time::Duration;
+
+/// A proxy subsystem that can be rebuilt independently on a config change.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Subsystem {
+    /// Route matching and upstream selection (`routes`, `upstreams`).
+    Routing,
+    IpReputation,
+    #[serde(rename = "geoip")]
+    GeoIp,
+    /// Global and per-route rate limiters.
+    RateLimit,
+    BotDetection,
+    AntiScraping,
+    /// The Coraza engine and its rule set.
+    Waf,
+    /// Access log, request capture and the 5xx rate alert
+    /// (`server.access_log`, `server.capture`, `server.error_rate_alert`).
+    Logging,
+}
+
+/// A client currently flagged by bot detection or anti-scraping.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct FlaggedSession {
+    pub ip: String,
+    /// Bot score, when bot detection flagged the client.
+    pub bot_score: Option<f64>,
+    /// Scraping score, when anti-scraping flagged the client.
+    pub scraping_score: Option<f64>,
+}
+
+/// A watermark recovered from scraped content.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct IdentifiedWatermark {
+    /// The watermark's keyed hash, hex-encoded.
+    pub watermark: String,
+    /// The client it was served to, if the proxy still remembers it.
+    pub ip: Option<String>,
+}
+
+/// One of the IP reputation lists.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum IpList {
+    Blocklist,
+    Allowlist,
+}
+
+/// Applies config changes to the running proxy.
+///
+/// Everything but [`reload`](ConfigReloader::reload) defaults to a proxy
+/// without the subsystem involved: nothing to report and nothing to evict.
+pub trait ConfigReloader: Send + Sync {
+    /// Install `config` and rebuild only the listed `subsystems`; everything
+    /// else, including per-client state, is kept as is.
+    fn reload(&self, config: &AppConfig, subsystems: &[Subsystem]) -> anyhow::Result<()>;
+
+    /// The live rate limiters, labelled by scope, after a reload.
+    fn rate_limiters(&self) -> Vec<(String, RateLimiter)> {
+        Vec::new()
+    }
+
+    /// Rebuild the WAF engine with `rules` added after the configured ones.
+    /// On error the running engine is left unchanged.
+    fn apply_custom_rules(&self, _rules: &[String]) -> anyhow::Result<()> {
+        anyhow::bail!("no WAF engine to apply custom rules to")
+    }
+
+    /// The `limit` most frequent bot-detection fingerprints; empty when bot
+    /// detection is off.
+    fn top_fingerprints(&self, _limit: usize) -> Vec<FingerprintCount> {
+        Vec::new()
+    }
+
+    /// Clients whose bot-detection or anti-scraping session is above the
+    /// configured score threshold.
+    fn flagged_sessions(&self) -> Vec<FlaggedSession> {
+        Vec::new()
+    }
+
+    /// Forget the bot-detection and anti-scraping sessions of `ip`, or of
+    /// every client, so they are re-evaluated from scratch. Returns the
+    /// number of sessions removed.
+    fn clear_sessions(&self, _ip: Option<&str>) -> usize {
+        0
+    }
+
+    /// Evict stale entries from every rate limiter now, along with
+    /// bot-detection and anti-scraping sessions idle for longer than
+    /// `session_max_age`.
+    fn cleanup(&self, _session_max_age: Duration) {}
+
+    /// Entry count and approximate memory use of every per-key map: rate
+    /// limiters, bot-detection and anti-scraping state.
+    fn map_footprints(&self) -> Vec<MapFootprint> {
+        Vec::new()
+    }
+
+    /// Recover the anti-scraping watermark from `text` and the client it
+    /// was served to. `None` when `text` carries no watermark.
+    fn identify_watermark(&self, _text: &str) -> Option<IdentifiedWatermark> {
+        None
+    }
+
+    /// Every network on the live IP reputation `list`, in CIDR notation.
+    fn ip_list_entries(&self, _list: IpList) -> Vec<String> {
+        Vec::new()
+    }
+}
+
+/// List the subsystems whose config sections differ between `old` and `new`.
+pub fn changed_subsystems(old: &AppConfig, new: &AppConfig) -> Vec<Subsystem> {
+    fn differs<T: Serialize>(a: &T, b: &T) -> bool {
+        serde_json::to_value(a).ok() != serde_json::to_value(b).ok()
+    }
+
+    let routing = differs(&old.routes, &new.routes)
+        || differs(&old.upstreams, &new.upstreams)
+        || differs(&old.health_probe, &new.health_probe)
+        || old.server.client_ip_headers != new.server.client_ip_headers
+        || old.server.trusted_proxies != new.server.trusted_proxies;
+    let capacity = old.state_limits.on_overflow != new.state_limits.on_overflow
+        || old.failure_policy != new.failure_policy;
+    // Key normalization and the tarpit are read live on each request
+    let limits = |c: &AppConfig| RateLimitConfig {
+        normalize_keys: true,
+        tarpit_ms: 0,
+        tarpit_max_concurrent: 0,
+        ..c.rate_limit.clone()
+    };
+
+    let mut changed = Vec::new();
+    if routing {
+        changed.push(Subsystem::Routing);
+    }
+    if differs(&old.ip_reputation, &new.ip_reputation) {
+        changed.push(Subsystem::IpReputation);
+    }
+    if differs(&old.geoip, &new.geoip) {
+        changed.push(Subsystem::GeoIp);
+    }
+    // Per-route limiters are indexed by route, so routing changes rebuild them
+    if routing
+        || capacity
+        || differs(&limits(old), &limits(new))
+        || old.state_limits.max_rate_limit_keys != new.state_limits.max_rate_limit_keys
+    {
+        changed.push(Subsystem::RateLimit);
+    }
+    if capacity
+        || differs(&old.bot_detection, &new.bot_detection)
+        || old.state_limits.max_bot_sessions != new.state_limits.max_bot_sessions
+    {
+        changed.push(Subsystem::BotDetection);
+    }
+    if capacity
+        || differs(&old.anti_scraping, &new.anti_scraping)
+        || old.state_limits.max_scraping_sessions != new.state_limits.max_scraping_sessions
+    {
+        changed.push(Subsystem::AntiScraping);
+    }
+    // The body limit is compiled into the engines as `SecRequestBodyLimit`
+    if old.waf.rules != new.waf.rules
+        || old.waf.rulesets != new.waf.rulesets
+        || old.waf.inline_rules != new.waf.inline_rules
+        || old.waf.request_body_limit != new.waf.request_body_limit
+        || old.waf.body_budget_bytes != new.waf.body_budget_bytes
+        || old.waf.on_body_budget_exhausted != new.waf.on_body_budget_exhausted
+        || differs(&old.waf.rule_hit_sampling, &new.waf.rule_hit_sampling)
+        || differs(&old.waf.rule_grace, &new.waf.rule_grace)
+    {
+        changed.push(Subsystem::Waf);
+    }
+    if differs(&old.server.access_log, &new.server.access_log)
+        || differs(&old.server.capture, &new.server.capture)
+        || differs(&old.server.error_rate_alert, &new.server.error_rate_alert)
+    {
+        changed.push(Subsystem::Logging);
+    }
+    changed
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::state::test_state;
+
+    #[test]
+    fn test_only_changed_sections_listed() {
+        let old = test_state().config.read().unwrap().clone();
+
+        let mut new = old.clone();
+        new.geoip.blocked_countries.push("CN".to_string());
+        assert_eq!(changed_subsystems(&old, &new), vec![Subsystem::GeoIp]);
+
+        let mut new = old.clone();
+        new.waf.inline_rules.push("SecRuleEngine On".to_string());
+        new.rate_limit.default_rps += 1;
+        assert_eq!(
+            changed_subsystems(&old, &new),
+            vec![Subsystem::RateLimit, Subsystem::Waf]
+        );
+
+        let mut new = old.clone();
+        new.waf.request_body_limit += 1;
+        assert_eq!(changed_subsystems(&old, &new), vec![Subsystem::Waf]);
+
+        let mut new = old.clone();
+        new.server.error_rate_alert.enabled = !new.server.error_rate_alert.enabled;
+        assert_eq!(changed_subsystems(&old, &new), vec![Subsystem::Logging]);
+
+        // Settings read live on each request need no rebuild
+        let mut new = old.clone();
+        new.waf.max_custom_rules += 1;
+        new.rate_limit.tarpit_ms += 100;
+        new.rate_limit.normalize_keys = !new.rate_limit.normalize_keys;
+        assert!(changed_subsystems(&old, &new).is_empty());
+    }
+}
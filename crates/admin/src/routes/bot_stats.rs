@@ -1,7 +1,9 @@
 use axum::extract::State;
 use axum::Json;
-use serde::Serialize;
+use layer7waf_bot_detect::diversity::FingerprintCount;
+use serde::{Deserialize, Serialize};
 
+use crate::routes::query::ValidQuery;
 use crate::state::SharedState;
 
 #[derive(Serialize)]
@@ -30,3 +32,39 @@ pub async fn get_bot_stats(State(state): State<SharedState>) -> Json<BotStatsRes
         challenge_pass_rate,
     })
 }
+
+/// Fingerprints returned when the query doesn't set a limit.
+const DEFAULT_FINGERPRINT_LIMIT: usize = 20;
+/// Upper bound on the fingerprints returned in one response.
+const MAX_FINGERPRINT_LIMIT: usize = 1000;
+
+/// Query parameters for the top fingerprints endpoint.
+#[derive(Debug, Deserialize)]
+pub struct TopFingerprintsQuery {
+    pub limit: Option<usize>,
+}
+
+#[derive(Serialize)]
+pub struct TopFingerprintsResponse {
+    pub fingerprints: Vec<FingerprintCount>,
+}
+
+/// GET /api/bot-stats/fingerprints?limit=N
+///
+/// Returns the most frequent request fingerprints across all clients. One
+/// fingerprint with a large share of traffic suggests a single tool being
+/// run from many IPs.
+pub async fn get_top_fingerprints(
+    State(state): State<SharedState>,
+    ValidQuery(query): ValidQuery<TopFingerprintsQuery>,
+) -> Json<TopFingerprintsResponse> {
+    let limit = query
+        .limit
+        .unwrap_or(DEFAULT_FINGERPRINT_LIMIT)
+        .min(MAX_FINGERPRINT_LIMIT);
+    let reloader = state.reloader.read().expect("reloader lock poisoned").clone();
+    let fingerprints = reloader
+        .map(|r| r.top_fingerprints(limit))
+        .unwrap_or_default();
+    Json(TopFingerprintsResponse { fingerprints })
+}
@@ -0,0 +1,146 @@
+use std::collections::HashMap;
+use std::time::Duration;
+
+use axum::extract::State;
+use axum::http::StatusCode;
+use axum::Json;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+
+use crate::routes::query::ValidQuery;
+use crate::state::SharedState;
+
+fn default_session_max_age_secs() -> u64 {
+    300
+}
+
+/// Query parameters for a forced cleanup.
+#[derive(Debug, Deserialize)]
+pub struct CleanupQuery {
+    /// Bot-detection and anti-scraping sessions idle for longer than this
+    /// are evicted.
+    #[serde(default = "default_session_max_age_secs")]
+    pub session_max_age_secs: u64,
+}
+
+/// Entries of one per-key map before and after a cleanup.
+#[derive(Debug, Serialize)]
+pub struct MapCleanup {
+    pub name: String,
+    pub before: usize,
+    pub after: usize,
+}
+
+/// POST /api/admin/cleanup?session_max_age_secs=<secs>
+///
+/// Evicts stale entries from every rate limiter and idle bot-detection and
+/// anti-scraping sessions now, instead of waiting for the periodic cleanup,
+/// and returns each map's entry count before and after. Returns 503 when no
+/// proxy is attached.
+pub async fn run_cleanup(
+    State(state): State<SharedState>,
+    ValidQuery(query): ValidQuery<CleanupQuery>,
+) -> (StatusCode, Json<Value>) {
+    let reloader = state.reloader.read().expect("reloader lock poisoned").clone();
+    let Some(reloader) = reloader else {
+        return (
+            StatusCode::SERVICE_UNAVAILABLE,
+            Json(json!({
+                "status": "error",
+                "message": "no proxy attached to clean up"
+            })),
+        );
+    };
+
+    let before = reloader.map_footprints();
+    reloader.cleanup(Duration::from_secs(query.session_max_age_secs));
+    let after: HashMap<String, usize> = reloader
+        .map_footprints()
+        .into_iter()
+        .map(|map| (map.name, map.entries))
+        .collect();
+
+    let maps: Vec<MapCleanup> = before
+        .into_iter()
+        .map(|map| MapCleanup {
+            after: after.get(&map.name).copied().unwrap_or(0),
+            before: map.entries,
+            name: map.name,
+        })
+        .collect();
+    let entries_before: usize = maps.iter().map(|m| m.before).sum();
+    let entries_after: usize = maps.iter().map(|m| m.after).sum();
+    tracing::info!(entries_before, entries_after, "forced cleanup of stale entries");
+
+    (
+        StatusCode::OK,
+        Json(json!({
+            "status": "cleaned",
+            "maps": maps,
+            "entries_before": entries_before,
+            "entries_after": entries_after
+        })),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::reload::{ConfigReloader, Subsystem};
+    use crate::state::test_state;
+    use layer7waf_common::{AppConfig, MapFootprint};
+    use layer7waf_rate_limit::RateLimiter;
+    use std::sync::Arc;
+
+    /// Holds one rate limiter whose entries are all stale.
+    struct LimiterReloader {
+        limiter: RateLimiter,
+    }
+
+    impl ConfigReloader for LimiterReloader {
+        fn reload(&self, _config: &AppConfig, _subsystems: &[Subsystem]) -> anyhow::Result<()> {
+            Ok(())
+        }
+
+        fn rate_limiters(&self) -> Vec<(String, RateLimiter)> {
+            vec![("global".to_string(), self.limiter.clone())]
+        }
+
+        fn cleanup(&self, _session_max_age: Duration) {
+            self.limiter.cleanup();
+        }
+
+        fn map_footprints(&self) -> Vec<MapFootprint> {
+            vec![self.limiter.map_footprint("rate_limit:global")]
+        }
+    }
+
+    fn query() -> ValidQuery<CleanupQuery> {
+        ValidQuery(CleanupQuery { session_max_age_secs: default_session_max_age_secs() })
+    }
+
+    #[tokio::test]
+    async fn test_cleanup_evicts_stale_entries() {
+        // A zero TTL makes every tracked key stale
+        let limiter = RateLimiter::new_token_bucket(10, 10).with_key_ttl(Duration::ZERO);
+        for i in 0..3 {
+            limiter.check(&format!("10.0.0.{}", i));
+        }
+        let state = test_state();
+        *state.reloader.write().unwrap() = Some(Arc::new(LimiterReloader { limiter }));
+
+        let (status, Json(body)) = run_cleanup(State(state), query()).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body["maps"][0]["name"], "rate_limit:global");
+        assert_eq!(body["maps"][0]["before"], 3);
+        assert_eq!(body["maps"][0]["after"], 0);
+        assert_eq!(body["entries_before"], 3);
+        assert_eq!(body["entries_after"], 0);
+    }
+
+    #[tokio::test]
+    async fn test_cleanup_unavailable_without_proxy() {
+        let (status, _) = run_cleanup(State(test_state()), query()).await;
+        assert_eq!(status, StatusCode::SERVICE_UNAVAILABLE);
+    }
+}
@@ -1,28 +1,151 @@
-use axum::extract::State;
-use axum::http::StatusCode;
+use axum::extract::{Path, State};
+use axum::http::{HeaderMap, StatusCode};
 use axum::response::IntoResponse;
 use axum::Json;
 use layer7waf_common::AppConfig;
-use serde_json::json;
+use serde_json::{json, Value};
 
+use crate::audit::{REDACTED, SECRET_KEYS};
 use crate::state::SharedState;
 
+/// Header identifying who made a config change, recorded in the history.
+const ACTOR_HEADER: &str = "x-actor";
+
+fn actor_from_headers(headers: &HeaderMap) -> String {
+    headers
+        .get(ACTOR_HEADER)
+        .and_then(|v| v.to_str().ok())
+        .filter(|v| !v.is_empty())
+        .unwrap_or("anonymous")
+        .to_string()
+}
+
+fn reload_failed(e: anyhow::Error) -> (StatusCode, Json<Value>) {
+    tracing::error!(error = %e, "failed to apply configuration");
+    (
+        StatusCode::INTERNAL_SERVER_ERROR,
+        Json(json!({
+            "status": "error",
+            "message": format!("reload failed: {}", e)
+        })),
+    )
+}
+
+/// Deserialize a config submitted as JSON. On failure the error names the
+/// offending field by its path, e.g. ``missing field `listen` at server``.
+fn parse_config(value: Value) -> Result<AppConfig, (StatusCode, Json<Value>)> {
+    serde_path_to_error::deserialize(value).map_err(|e| {
+        let path = e.path().to_string();
+        let message = if path == "." {
+            format!("invalid config: {}", e.inner())
+        } else {
+            format!("invalid config: {} at {}", e.inner(), path)
+        };
+        (
+            StatusCode::BAD_REQUEST,
+            Json(json!({
+                "status": "error",
+                "message": message,
+                "path": path
+            })),
+        )
+    })
+}
+
 /// GET /api/config
 ///
-/// Returns the current WAF configuration as JSON.
+/// Returns the current WAF configuration as JSON, secrets redacted. A
+/// redacted value sent back through `PUT /api/config` keeps the secret.
 pub async fn get_config(State(state): State<SharedState>) -> impl IntoResponse {
     let config = state.config.read().expect("config lock poisoned");
-    Json(serde_json::to_value(&*config).unwrap_or(json!({"error": "serialization failed"})))
+    let mut value =
+        serde_json::to_value(&*config).unwrap_or(json!({"error": "serialization failed"}));
+    redact_config_secrets(&mut value);
+    Json(value)
+}
+
+/// GET /api/config/effective
+///
+/// Returns the running configuration with every serde default filled in,
+/// so operators can see values they never set. Secrets are redacted.
+pub async fn get_effective_config(State(state): State<SharedState>) -> Json<Value> {
+    let config = state.config.read().expect("config lock poisoned");
+    let mut value =
+        serde_json::to_value(&*config).unwrap_or(json!({"error": "serialization failed"}));
+    redact_config_secrets(&mut value);
+    Json(value)
+}
+
+/// Replace the value of every field named like a secret (the audit log's
+/// [`SECRET_KEYS`], e.g. `secret` or `api_token`), at any depth, with a
+/// placeholder.
+fn redact_config_secrets(value: &mut Value) {
+    match value {
+        Value::Object(map) => {
+            for (key, v) in map.iter_mut() {
+                let secret = SECRET_KEYS.iter().any(|k| key.eq_ignore_ascii_case(k));
+                if secret && v.is_string() {
+                    *v = Value::String(REDACTED.to_string());
+                } else {
+                    redact_config_secrets(v);
+                }
+            }
+        }
+        Value::Array(items) => items.iter_mut().for_each(redact_config_secrets),
+        _ => {}
+    }
+}
+
+/// Put back the secrets of `current` that `value`, a config read from
+/// `GET /api/config` and submitted again, still has redacted.
+fn restore_config_secrets(value: &mut Value, current: &Value) {
+    match (value, current) {
+        (Value::Object(map), Value::Object(current)) => {
+            for (key, v) in map.iter_mut() {
+                let Some(current) = current.get(key) else {
+                    continue;
+                };
+                let secret = SECRET_KEYS.iter().any(|k| key.eq_ignore_ascii_case(k));
+                if secret && v.as_str() == Some(REDACTED) {
+                    *v = current.clone();
+                } else {
+                    restore_config_secrets(v, current);
+                }
+            }
+        }
+        (Value::Array(items), Value::Array(current)) => {
+            for (v, current) in items.iter_mut().zip(current) {
+                restore_config_secrets(v, current);
+            }
+        }
+        _ => {}
+    }
 }
 
 /// PUT /api/config
 ///
 /// Accepts a full configuration as JSON, validates it, and replaces
-/// the current running configuration.
+/// the current running configuration. Only the subsystems whose settings
+/// changed are rebuilt; they are listed in the response's `reloaded` field.
+/// The change is recorded in the config history, attributed to the
+/// `X-Actor` header. A body that doesn't deserialize into a config gets a
+/// 400 naming the offending field. Secrets left redacted keep their value.
 pub async fn update_config(
     State(state): State<SharedState>,
-    Json(new_config): Json<AppConfig>,
+    headers: HeaderMap,
+    Json(mut body): Json<Value>,
 ) -> impl IntoResponse {
+    {
+        let config = state.config.read().expect("config lock poisoned");
+        if let Ok(current) = serde_json::to_value(&*config) {
+            restore_config_secrets(&mut body, &current);
+        }
+    }
+    let new_config = match parse_config(body) {
+        Ok(config) => config,
+        Err(rejection) => return rejection,
+    };
+
     // Validate the incoming configuration before applying it.
     if let Err(e) = new_config.validate() {
         return (
@@ -34,15 +157,284 @@ pub async fn update_config(
         );
     }
 
-    let mut config = state.config.write().expect("config lock poisoned");
-    *config = new_config;
+    let (entry, reloaded) = match state.apply_config(new_config, &actor_from_headers(&headers)) {
+        Ok(applied) => applied,
+        Err(e) => return reload_failed(e),
+    };
+
+    tracing::info!(
+        actor = %entry.actor,
+        diff = %entry.diff_summary,
+        reloaded = ?reloaded,
+        "configuration updated via admin API"
+    );
+
+    (
+        StatusCode::OK,
+        Json(json!({
+            "status": "updated",
+            "change_id": entry.id,
+            "reloaded": reloaded
+        })),
+    )
+}
+
+/// GET /api/config/history
+///
+/// Returns the recorded config changes, oldest first.
+pub async fn get_config_history(State(state): State<SharedState>) -> Json<Value> {
+    let history = state.config_history.read().expect("config_history lock poisoned");
+    Json(json!({ "changes": history.iter().collect::<Vec<_>>() }))
+}
+
+/// POST /api/config/rollback/:id
+///
+/// Restores the config as it was before change `id`. The rollback itself is
+/// recorded as a new change. Returns 404 if the change is no longer retained.
+pub async fn rollback_config(
+    State(state): State<SharedState>,
+    headers: HeaderMap,
+    Path(id): Path<u64>,
+) -> impl IntoResponse {
+    let snapshot = state
+        .config_history
+        .read()
+        .expect("config_history lock poisoned")
+        .iter()
+        .find(|e| e.id == id)
+        .map(|e| e.snapshot.clone());
+
+    let Some(snapshot) = snapshot else {
+        return (
+            StatusCode::NOT_FOUND,
+            Json(json!({
+                "status": "error",
+                "message": format!("config change {} not found", id)
+            })),
+        );
+    };
+
+    let restored: AppConfig = match serde_json::from_value(snapshot) {
+        Ok(config) => config,
+        Err(e) => {
+            return (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                Json(json!({
+                    "status": "error",
+                    "message": format!("failed to restore snapshot: {}", e)
+                })),
+            );
+        }
+    };
+
+    let actor = format!("{} (rollback to #{})", actor_from_headers(&headers), id);
+    let (entry, reloaded) = match state.apply_config(restored, &actor) {
+        Ok(applied) => applied,
+        Err(e) => return reload_failed(e),
+    };
 
-    tracing::info!("configuration updated via admin API");
+    tracing::info!(
+        rollback_to = id,
+        actor = %entry.actor,
+        reloaded = ?reloaded,
+        "configuration rolled back via admin API"
+    );
 
     (
         StatusCode::OK,
         Json(json!({
-            "status": "updated"
+            "status": "rolled_back",
+            "change_id": entry.id,
+            "reloaded": reloaded
         })),
     )
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::reload::{ConfigReloader, Subsystem};
+    use crate::state::test_state;
+    use layer7waf_rate_limit::RateLimiter;
+    use std::sync::Arc;
+
+    fn config_json(config: &AppConfig) -> Json<Value> {
+        Json(serde_json::to_value(config).unwrap())
+    }
+
+    async fn body_json(resp: axum::response::Response) -> Value {
+        let body = axum::body::to_bytes(resp.into_body(), usize::MAX).await.unwrap();
+        serde_json::from_slice(&body).unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_change_recorded_and_rolled_back() {
+        let state = test_state();
+        let original = state.config.read().unwrap().waf.max_custom_rules;
+
+        let mut new_config = state.config.read().unwrap().clone();
+        new_config.waf.max_custom_rules = original + 1;
+        let mut headers = HeaderMap::new();
+        headers.insert(ACTOR_HEADER, "alice".parse().unwrap());
+
+        let resp = update_config(State(state.clone()), headers.clone(), config_json(&new_config))
+            .await
+            .into_response();
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert_eq!(state.config.read().unwrap().waf.max_custom_rules, original + 1);
+
+        let Json(history) = get_config_history(State(state.clone())).await;
+        let changes = history["changes"].as_array().unwrap();
+        assert_eq!(changes.len(), 1);
+        assert_eq!(changes[0]["actor"], "alice");
+        assert_eq!(changes[0]["diff_summary"], "changed: waf.max_custom_rules");
+        assert!(changes[0].get("snapshot").is_none());
+
+        let id = changes[0]["id"].as_u64().unwrap();
+        let resp = rollback_config(State(state.clone()), headers, Path(id))
+            .await
+            .into_response();
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert_eq!(state.config.read().unwrap().waf.max_custom_rules, original);
+        assert_eq!(state.config_history.read().unwrap().len(), 2);
+    }
+
+    /// Rebuilds the global limiter whenever the rate limit subsystem reloads.
+    struct StubReloader {
+        limiters: std::sync::Mutex<Vec<(String, RateLimiter)>>,
+    }
+
+    impl ConfigReloader for StubReloader {
+        fn reload(&self, config: &AppConfig, subsystems: &[Subsystem]) -> anyhow::Result<()> {
+            if subsystems.contains(&Subsystem::RateLimit) {
+                *self.limiters.lock().unwrap() = vec![(
+                    "global".to_string(),
+                    RateLimiter::new_token_bucket(
+                        config.rate_limit.default_rps,
+                        config.rate_limit.default_burst,
+                    ),
+                )];
+            }
+            Ok(())
+        }
+
+        fn rate_limiters(&self) -> Vec<(String, RateLimiter)> {
+            self.limiters.lock().unwrap().clone()
+        }
+    }
+
+    #[tokio::test]
+    async fn test_geoip_change_keeps_rate_limit_state() {
+        let state = test_state();
+        let limiter = RateLimiter::new_token_bucket(1, 5);
+        limiter.check("10.0.0.1");
+        let reloader = Arc::new(StubReloader {
+            limiters: std::sync::Mutex::new(vec![("global".to_string(), limiter)]),
+        });
+        *state.rate_limiters.write().unwrap() = reloader.rate_limiters();
+        *state.reloader.write().unwrap() = Some(reloader);
+
+        let mut new_config = state.config.read().unwrap().clone();
+        new_config.geoip.blocked_countries.push("RU".to_string());
+        let resp = update_config(State(state.clone()), HeaderMap::new(), config_json(&new_config))
+            .await
+            .into_response();
+        assert_eq!(resp.status(), StatusCode::OK);
+        let body = body_json(resp).await;
+        assert_eq!(body["reloaded"], json!(["geoip"]));
+
+        assert!(state.rate_limiters.read().unwrap()[0].1.is_tracking("10.0.0.1"));
+
+        // A rate limit change rebuilds the limiter, dropping per-key state.
+        let mut new_config = state.config.read().unwrap().clone();
+        new_config.rate_limit.default_burst += 1;
+        update_config(State(state.clone()), HeaderMap::new(), config_json(&new_config)).await;
+        assert!(!state.rate_limiters.read().unwrap()[0].1.is_tracking("10.0.0.1"));
+    }
+
+    #[tokio::test]
+    async fn test_effective_config_resolves_defaults() {
+        let state = test_state();
+        state.config.write().unwrap().server.admin.api_token = Some("t0ps3cret".to_string());
+        let Json(effective) = get_effective_config(State(state.clone())).await;
+
+        // Sections and fields absent from the minimal config are filled in.
+        assert_eq!(effective["rate_limit"]["default_rps"], 100);
+        assert_eq!(effective["rate_limit"]["normalize_keys"], true);
+        assert_eq!(effective["bot_detection"]["score_threshold"], 0.7);
+        assert_eq!(effective["anti_scraping"]["max_tracked_paths"], 1000);
+        assert_eq!(
+            effective["waf"]["max_custom_rules"],
+            state.config.read().unwrap().waf.max_custom_rules
+        );
+
+        // Auto-generated secrets are never exposed.
+        assert_eq!(effective["bot_detection"]["js_challenge"]["secret"], REDACTED);
+        assert_eq!(effective["anti_scraping"]["captcha"]["secret"], REDACTED);
+        assert_eq!(effective["server"]["admin"]["api_token"], REDACTED);
+        let secret = state.config.read().unwrap().bot_detection.js_challenge.secret.clone();
+        assert!(!effective.to_string().contains(&secret));
+    }
+
+    #[tokio::test]
+    async fn test_config_secrets_redacted_and_kept_on_update() {
+        let state = test_state();
+        state.config.write().unwrap().server.admin.api_token = Some("t0ps3cret".to_string());
+        let resp = get_config(State(state.clone())).await.into_response();
+        let mut body = body_json(resp).await;
+        assert_eq!(body["server"]["admin"]["api_token"], REDACTED);
+        assert_eq!(body["bot_detection"]["js_challenge"]["secret"], REDACTED);
+
+        // Submitting the redacted config back changes nothing secret
+        let secret = state.config.read().unwrap().bot_detection.js_challenge.secret.clone();
+        body["waf"]["max_custom_rules"] = json!(7);
+        let resp = update_config(State(state.clone()), HeaderMap::new(), Json(body))
+            .await
+            .into_response();
+        assert_eq!(resp.status(), StatusCode::OK);
+        let config = state.config.read().unwrap();
+        assert_eq!(config.waf.max_custom_rules, 7);
+        assert_eq!(config.server.admin.api_token.as_deref(), Some("t0ps3cret"));
+        assert_eq!(config.bot_detection.js_challenge.secret, secret);
+    }
+
+    #[tokio::test]
+    async fn test_missing_field_named_in_error() {
+        let state = test_state();
+        let mut body = serde_json::to_value(&*state.config.read().unwrap()).unwrap();
+        body["server"].as_object_mut().unwrap().remove("listen");
+
+        let resp = update_config(State(state.clone()), HeaderMap::new(), Json(body))
+            .await
+            .into_response();
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+        let body = body_json(resp).await;
+        assert_eq!(body["message"], "invalid config: missing field `listen` at server");
+        assert_eq!(body["path"], "server");
+        assert!(state.config_history.read().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_wrong_type_names_nested_field() {
+        let state = test_state();
+        let mut body = serde_json::to_value(&*state.config.read().unwrap()).unwrap();
+        body["rate_limit"]["default_rps"] = json!("fast");
+
+        let resp = update_config(State(state), HeaderMap::new(), Json(body))
+            .await
+            .into_response();
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+        let body = body_json(resp).await;
+        assert_eq!(body["path"], "rate_limit.default_rps");
+        assert!(body["message"].as_str().unwrap().ends_with("at rate_limit.default_rps"));
+    }
+
+    #[tokio::test]
+    async fn test_rollback_unknown_change() {
+        let state = test_state();
+        let resp = rollback_config(State(state), HeaderMap::new(), Path(42))
+            .await
+            .into_response();
+        assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+    }
+}
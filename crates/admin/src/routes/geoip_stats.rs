@@ -1,12 +1,16 @@
+use std::collections::BTreeMap;
+
 use axum::extract::State;
 use axum::Json;
 use serde::Serialize;
 
-use crate::state::SharedState;
+use crate::state::{counts_by_label, SharedState};
 
 #[derive(Serialize)]
 pub struct GeoIpStatsResponse {
     pub geoip_blocked: u64,
+    /// Block counts keyed by reason (`blocklisted`, `not_allowlisted`, `unknown_country`).
+    pub blocked_by_reason: BTreeMap<String, u64>,
     pub geoip_lookups: u64,
     pub enabled: bool,
     pub blocked_countries: Vec<String>,
@@ -17,6 +21,8 @@ pub async fn get_geoip_stats(State(state): State<SharedState>) -> Json<GeoIpStat
     let geoip_blocked = state.metrics.geoip_blocked.get();
     let geoip_lookups = state.metrics.geoip_lookups.get();
 
+    let blocked_by_reason = counts_by_label(&state.metrics.geoip_blocked_by_reason, "reason");
+
     let config = state.config.read().expect("config lock poisoned");
     let enabled = config.geoip.enabled;
     let blocked_countries = config.geoip.blocked_countries.clone();
@@ -24,9 +30,29 @@ pub async fn get_geoip_stats(State(state): State<SharedState>) -> Json<GeoIpStat
 
     Json(GeoIpStatsResponse {
         geoip_blocked,
+        blocked_by_reason,
         geoip_lookups,
         enabled,
         blocked_countries,
         allowed_countries,
     })
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::state::test_state;
+
+    #[tokio::test]
+    async fn test_blocked_by_reason() {
+        let state = test_state();
+        let by_reason = &state.metrics.geoip_blocked_by_reason;
+        by_reason.with_label_values(&["blocklisted"]).inc_by(3);
+        by_reason.with_label_values(&["unknown_country"]).inc();
+
+        let Json(stats) = get_geoip_stats(State(state)).await;
+        assert_eq!(stats.blocked_by_reason.get("blocklisted"), Some(&3));
+        assert_eq!(stats.blocked_by_reason.get("unknown_country"), Some(&1));
+        assert_eq!(stats.blocked_by_reason.get("not_allowlisted"), None);
+    }
+}
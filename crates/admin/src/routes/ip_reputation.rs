@@ -0,0 +1,196 @@
+use axum::extract::State;
+use axum::http::StatusCode;
+use axum::response::IntoResponse;
+use axum::Json;
+use layer7waf_ip_reputation::{validate_list, ParseReport};
+use serde::Deserialize;
+use serde_json::json;
+
+use crate::reload::IpList;
+use crate::routes::query::{default_page_limit, Page, ValidQuery};
+use crate::state::SharedState;
+
+/// POST /api/ip-reputation/validate
+///
+/// Parses a blocklist or allowlist sent as the request body, in the same
+/// format as the list files, and reports how many entries would load, which
+/// lines would be skipped and a sample of the networks. Nothing is applied.
+pub async fn validate_ip_list(body: String) -> Json<ParseReport> {
+    Json(validate_list(&body))
+}
+
+/// Query parameters for listing IP reputation entries.
+#[derive(Debug, Deserialize)]
+pub struct EntriesQuery {
+    /// `blocklist` (default) or `allowlist`.
+    #[serde(default = "default_list")]
+    pub list: IpList,
+    /// Maximum number of entries to return (default: 100, at most 1000).
+    #[serde(default = "default_page_limit")]
+    pub limit: usize,
+    /// Number of entries to skip (default: 0).
+    #[serde(default)]
+    pub offset: usize,
+}
+
+fn default_list() -> IpList {
+    IpList::Blocklist
+}
+
+/// GET /api/ip-reputation/entries?list=blocklist&offset=0&limit=100
+///
+/// Returns a page of the networks on the live blocklist or allowlist, IPv4
+/// first and each family in address order. A limit over the cap is
+/// clamped; an offset past the end gets a 400. Returns 503 when no proxy is
+/// attached.
+pub async fn get_ip_list_entries(
+    State(state): State<SharedState>,
+    ValidQuery(params): ValidQuery<EntriesQuery>,
+) -> impl IntoResponse {
+    let reloader = state.reloader.read().expect("reloader lock poisoned").clone();
+    let Some(reloader) = reloader else {
+        return (
+            StatusCode::SERVICE_UNAVAILABLE,
+            Json(json!({
+                "status": "error",
+                "message": "no proxy attached to list IP reputation entries from"
+            })),
+        );
+    };
+
+    let entries = reloader.ip_list_entries(params.list);
+    let total = entries.len();
+    let page = match Page::new(params.offset, params.limit, total) {
+        Ok(page) => page,
+        Err(rejection) => return rejection,
+    };
+    let entries: Vec<String> = page.apply(entries).collect();
+
+    (
+        StatusCode::OK,
+        Json(json!({
+            "list": params.list,
+            "total": total,
+            "offset": page.offset,
+            "limit": page.limit,
+            "entries": entries
+        })),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::reload::{ConfigReloader, Subsystem};
+    use crate::state::test_state;
+    use axum::body::to_bytes;
+    use layer7waf_common::AppConfig;
+    use std::sync::Arc;
+
+    /// Serves fixed lists the way the proxy's IP reputation would.
+    struct ListReloader;
+
+    impl ConfigReloader for ListReloader {
+        fn reload(&self, _config: &AppConfig, _subsystems: &[Subsystem]) -> anyhow::Result<()> {
+            Ok(())
+        }
+
+        fn ip_list_entries(&self, list: IpList) -> Vec<String> {
+            match list {
+                IpList::Blocklist => (1..=5).map(|i| format!("10.0.0.{}/32", i)).collect(),
+                IpList::Allowlist => vec!["192.168.0.0/16".to_string()],
+            }
+        }
+    }
+
+    async fn body_json(resp: axum::response::Response) -> serde_json::Value {
+        let bytes = to_bytes(resp.into_body(), usize::MAX).await.unwrap();
+        serde_json::from_slice(&bytes).unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_validate_reports_counts_and_lines() {
+        let body = "# upload\n10.0.0.0/8\n10.0.0.300\n192.168.0.0/16\nexample.com\n";
+        let Json(report) = validate_ip_list(body.to_string()).await;
+
+        assert_eq!(report.loaded, 2);
+        assert_eq!(report.skipped, 2);
+        assert_eq!(report.entries, 2);
+        let lines: Vec<usize> = report.errors.iter().map(|e| e.line).collect();
+        assert_eq!(lines, [3, 5]);
+        assert_eq!(report.sample, ["10.0.0.0/8", "192.168.0.0/16"]);
+    }
+
+    #[tokio::test]
+    async fn test_entries_paginated() {
+        let state = test_state();
+        *state.reloader.write().unwrap() = Some(Arc::new(ListReloader));
+
+        let query = EntriesQuery {
+            list: IpList::Blocklist,
+            limit: 2,
+            offset: 3,
+        };
+        let resp = get_ip_list_entries(State(state.clone()), ValidQuery(query))
+            .await
+            .into_response();
+        assert_eq!(resp.status(), StatusCode::OK);
+        let body = body_json(resp).await;
+        assert_eq!(body["list"], "blocklist");
+        assert_eq!(body["total"], 5);
+        assert_eq!(body["entries"], json!(["10.0.0.4/32", "10.0.0.5/32"]));
+
+        let query = EntriesQuery {
+            list: IpList::Allowlist,
+            limit: 100,
+            offset: 0,
+        };
+        let resp = get_ip_list_entries(State(state), ValidQuery(query)).await.into_response();
+        let body = body_json(resp).await;
+        assert_eq!(body["entries"], json!(["192.168.0.0/16"]));
+    }
+
+    #[tokio::test]
+    async fn test_entries_limit_clamped_and_offset_checked() {
+        let state = test_state();
+        *state.reloader.write().unwrap() = Some(Arc::new(ListReloader));
+
+        let query = EntriesQuery {
+            list: IpList::Blocklist,
+            limit: 100_000_000,
+            offset: 0,
+        };
+        let resp = get_ip_list_entries(State(state.clone()), ValidQuery(query))
+            .await
+            .into_response();
+        assert_eq!(resp.status(), StatusCode::OK);
+        let body = body_json(resp).await;
+        assert_eq!(body["limit"], 1000);
+        assert_eq!(body["entries"].as_array().unwrap().len(), 5);
+
+        let query = EntriesQuery {
+            list: IpList::Blocklist,
+            limit: 100,
+            offset: 6,
+        };
+        let resp = get_ip_list_entries(State(state), ValidQuery(query))
+            .await
+            .into_response();
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+        let body = body_json(resp).await;
+        assert_eq!(body["message"], "offset 6 is past the end of the 5 entries");
+    }
+
+    #[tokio::test]
+    async fn test_entries_without_proxy_unavailable() {
+        let query = EntriesQuery {
+            list: IpList::Blocklist,
+            limit: 100,
+            offset: 0,
+        };
+        let resp = get_ip_list_entries(State(test_state()), ValidQuery(query))
+            .await
+            .into_response();
+        assert_eq!(resp.status(), StatusCode::SERVICE_UNAVAILABLE);
+    }
+}
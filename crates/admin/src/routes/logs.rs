@@ -1,15 +1,18 @@
-use axum::extract::{Query, State};
+use axum::extract::State;
+use axum::http::StatusCode;
+use axum::response::IntoResponse;
 use axum::Json;
 use serde::Deserialize;
-use serde_json::{json, Value};
+use serde_json::json;
 
+use crate::routes::query::{default_page_limit, Page, ValidQuery};
 use crate::state::SharedState;
 
 /// Query parameters for the audit log endpoint.
 #[derive(Debug, Deserialize)]
 pub struct LogQuery {
-    /// Maximum number of entries to return (default: 100).
-    #[serde(default = "default_limit")]
+    /// Maximum number of entries to return (default: 100, at most 1000).
+    #[serde(default = "default_page_limit")]
     pub limit: usize,
     /// Number of entries to skip (default: 0).
     #[serde(default)]
@@ -20,18 +23,15 @@ pub struct LogQuery {
     pub rule_id: Option<String>,
 }
 
-fn default_limit() -> usize {
-    100
-}
-
 /// GET /api/logs
 ///
 /// Returns a paginated, optionally filtered list of audit log entries
-/// from the in-memory ring buffer.
+/// from the in-memory ring buffer. A limit over the cap is clamped; an
+/// offset past the end or an unparseable parameter gets a 400.
 pub async fn get_logs(
     State(state): State<SharedState>,
-    Query(params): Query<LogQuery>,
-) -> Json<Value> {
+    ValidQuery(params): ValidQuery<LogQuery>,
+) -> impl IntoResponse {
     let logs = state.audit_log.read().expect("audit_log lock poisoned");
 
     // Apply filters.
@@ -56,17 +56,19 @@ pub async fn get_logs(
     let total = filtered.len();
 
     // Apply pagination.
-    let page: Vec<_> = filtered
-        .into_iter()
-        .skip(params.offset)
-        .take(params.limit)
-        .cloned()
-        .collect();
+    let page = match Page::new(params.offset, params.limit, total) {
+        Ok(page) => page,
+        Err(rejection) => return rejection,
+    };
+    let entries: Vec<_> = page.apply(filtered).cloned().collect();
 
-    Json(json!({
-        "total": total,
-        "offset": params.offset,
-        "limit": params.limit,
-        "entries": page
-    }))
+    (
+        StatusCode::OK,
+        Json(json!({
+            "total": total,
+            "offset": page.offset,
+            "limit": page.limit,
+            "entries": entries
+        })),
+    )
 }
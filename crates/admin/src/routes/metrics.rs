@@ -1,26 +1,39 @@
 use axum::extract::State;
-use axum::http::StatusCode;
+use axum::http::header::ACCEPT;
+use axum::http::{HeaderMap, StatusCode};
 use axum::response::IntoResponse;
 use prometheus::Encoder;
 
 use crate::state::SharedState;
 
+/// Content type for the classic Prometheus text exposition format.
+const PROMETHEUS_CONTENT_TYPE: &str = "text/plain; version=0.0.4; charset=utf-8";
+
+/// Content type for the OpenMetrics text exposition format.
+const OPENMETRICS_CONTENT_TYPE: &str = "application/openmetrics-text; version=1.0.0; charset=utf-8";
+
 /// GET /api/metrics
 ///
 /// Returns all registered Prometheus metrics in the standard text exposition format.
-pub async fn get_metrics(State(state): State<SharedState>) -> impl IntoResponse {
+/// Clients sending `Accept: application/openmetrics-text` receive the OpenMetrics
+/// content type with the mandatory `# EOF` trailer instead.
+pub async fn get_metrics(State(state): State<SharedState>, headers: HeaderMap) -> impl IntoResponse {
     let encoder = prometheus::TextEncoder::new();
     let metric_families = state.metrics.registry.gather();
 
+    let openmetrics = wants_openmetrics(&headers);
+
     let mut buffer = Vec::new();
     match encoder.encode(&metric_families, &mut buffer) {
         Ok(()) => {
-            let body = String::from_utf8(buffer).unwrap_or_default();
-            (
-                StatusCode::OK,
-                [("content-type", "text/plain; version=0.0.4; charset=utf-8")],
-                body,
-            )
+            let mut body = String::from_utf8(buffer).unwrap_or_default();
+            let content_type = if openmetrics {
+                body.push_str("# EOF\n");
+                OPENMETRICS_CONTENT_TYPE
+            } else {
+                PROMETHEUS_CONTENT_TYPE
+            };
+            (StatusCode::OK, [("content-type", content_type)], body)
         }
         Err(e) => {
             tracing::error!("failed to encode prometheus metrics: {}", e);
@@ -32,3 +45,53 @@ pub async fn get_metrics(State(state): State<SharedState>) -> impl IntoResponse
         }
     }
 }
+
+/// Returns `true` if the `Accept` header asks for the OpenMetrics format.
+fn wants_openmetrics(headers: &HeaderMap) -> bool {
+    headers
+        .get_all(ACCEPT)
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .any(|v| v.contains("application/openmetrics-text"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::state::test_state;
+    use axum::http::HeaderValue;
+
+    async fn body_string(resp: axum::response::Response) -> String {
+        let bytes = axum::body::to_bytes(resp.into_body(), usize::MAX).await.unwrap();
+        String::from_utf8(bytes.to_vec()).unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_default_prometheus_format() {
+        let state = test_state();
+        state.metrics.requests_total.inc();
+
+        let resp = get_metrics(State(state), HeaderMap::new()).await.into_response();
+        assert_eq!(resp.headers()["content-type"], PROMETHEUS_CONTENT_TYPE);
+
+        let body = body_string(resp).await;
+        assert!(body.contains("waf_requests_total 1"));
+        assert!(!body.contains("# EOF"));
+    }
+
+    #[tokio::test]
+    async fn test_openmetrics_accept_header() {
+        let state = test_state();
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            ACCEPT,
+            HeaderValue::from_static("application/openmetrics-text; version=1.0.0"),
+        );
+
+        let resp = get_metrics(State(state), headers).await.into_response();
+        assert_eq!(resp.headers()["content-type"], OPENMETRICS_CONTENT_TYPE);
+
+        let body = body_string(resp).await;
+        assert!(body.ends_with("# EOF\n"));
+    }
+}
@@ -1,9 +1,15 @@
 pub mod bot_stats;
+pub mod cleanup;
 pub mod config;
 pub mod geoip_stats;
 pub mod health;
+pub mod ip_reputation;
 pub mod logs;
 pub mod metrics;
+pub mod mode;
+pub mod query;
+pub mod rate_limit_stats;
 pub mod rules;
 pub mod scraping_stats;
+pub mod sessions;
 pub mod stats;
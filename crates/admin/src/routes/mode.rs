@@ -0,0 +1,95 @@
+use std::time::Duration;
+
+use axum::extract::State;
+use axum::http::StatusCode;
+use axum::response::IntoResponse;
+use axum::Json;
+use layer7waf_bot_detect::under_attack::UnderAttackStatus;
+use serde::Deserialize;
+use serde_json::json;
+
+use crate::state::SharedState;
+
+/// Request body for toggling under-attack mode.
+#[derive(Debug, Deserialize)]
+pub struct UnderAttackRequest {
+    pub enabled: bool,
+    /// How long the mode stays on; omitted means until disabled.
+    pub duration_secs: Option<u64>,
+}
+
+/// GET /api/mode/under-attack
+///
+/// Returns whether under-attack mode is active and when it ends.
+pub async fn get_under_attack(State(state): State<SharedState>) -> Json<UnderAttackStatus> {
+    Json(state.under_attack.status())
+}
+
+/// POST /api/mode/under-attack
+///
+/// Turns under-attack mode on or off. While on, every client except
+/// allowlisted IPs and known good bots must pass the JS challenge. Enabling
+/// requires bot detection with JS challenges, which serve the challenge.
+pub async fn set_under_attack(
+    State(state): State<SharedState>,
+    Json(req): Json<UnderAttackRequest>,
+) -> impl IntoResponse {
+    if req.enabled {
+        let challenges_available = {
+            let config = state.config.read().expect("config lock poisoned");
+            config.bot_detection.enabled && config.bot_detection.js_challenge.enabled
+        };
+        if !challenges_available {
+            return (
+                StatusCode::CONFLICT,
+                Json(json!({
+                    "status": "error",
+                    "message": "under-attack mode requires bot_detection with js_challenge enabled"
+                })),
+            );
+        }
+        state.under_attack.enable(req.duration_secs.map(Duration::from_secs));
+    } else {
+        state.under_attack.disable();
+    }
+
+    let status = state.under_attack.status();
+    tracing::warn!(
+        enabled = status.enabled,
+        expires_at = ?status.expires_at,
+        "under-attack mode changed via admin API"
+    );
+    (StatusCode::OK, Json(json!(status)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::state::test_state;
+
+    #[tokio::test]
+    async fn test_toggle_under_attack() {
+        let state = test_state();
+        state.config.write().unwrap().bot_detection.enabled = true;
+
+        let req = UnderAttackRequest { enabled: true, duration_secs: Some(300) };
+        let resp = set_under_attack(State(state.clone()), Json(req)).await.into_response();
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert!(state.under_attack.is_active());
+        let Json(status) = get_under_attack(State(state.clone())).await;
+        assert!(status.expires_at.is_some());
+
+        let req = UnderAttackRequest { enabled: false, duration_secs: None };
+        set_under_attack(State(state.clone()), Json(req)).await;
+        assert!(!state.under_attack.is_active());
+    }
+
+    #[tokio::test]
+    async fn test_under_attack_requires_bot_detection() {
+        let state = test_state();
+        let req = UnderAttackRequest { enabled: true, duration_secs: None };
+        let resp = set_under_attack(State(state.clone()), Json(req)).await.into_response();
+        assert_eq!(resp.status(), StatusCode::CONFLICT);
+        assert!(!state.under_attack.is_active());
+    }
+}
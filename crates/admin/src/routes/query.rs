@@ -0,0 +1,125 @@
+use axum::extract::{FromRequestParts, Query};
+use axum::http::request::Parts;
+use axum::http::StatusCode;
+use axum::Json;
+use serde::de::DeserializeOwned;
+use serde_json::{json, Value};
+
+/// Entries returned by a paginated endpoint when the query sets no limit.
+pub const DEFAULT_PAGE_LIMIT: usize = 100;
+/// Upper bound on the entries returned in one page; larger limits are
+/// clamped to it.
+pub const MAX_PAGE_LIMIT: usize = 1000;
+
+pub fn default_page_limit() -> usize {
+    DEFAULT_PAGE_LIMIT
+}
+
+/// Query string extractor like [`Query`], but a parameter that doesn't
+/// parse (e.g. `?limit=-1`) gets a JSON 400 naming it, in the same shape as
+/// the other admin API errors.
+#[derive(Debug)]
+pub struct ValidQuery<T>(pub T);
+
+impl<T, S> FromRequestParts<S> for ValidQuery<T>
+where
+    T: DeserializeOwned,
+    S: Send + Sync,
+{
+    type Rejection = (StatusCode, Json<Value>);
+
+    async fn from_request_parts(parts: &mut Parts, state: &S) -> Result<Self, Self::Rejection> {
+        match Query::<T>::from_request_parts(parts, state).await {
+            Ok(Query(value)) => Ok(Self(value)),
+            Err(rejection) => Err(bad_request(rejection.body_text())),
+        }
+    }
+}
+
+/// Offset and clamped limit of one page of a listing with `total` entries.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Page {
+    pub offset: usize,
+    pub limit: usize,
+}
+
+impl Page {
+    /// Clamp `limit` to [`MAX_PAGE_LIMIT`] and reject an `offset` past the
+    /// end of the listing.
+    pub fn new(
+        offset: usize,
+        limit: usize,
+        total: usize,
+    ) -> Result<Self, (StatusCode, Json<Value>)> {
+        if offset > total {
+            return Err(bad_request(format!(
+                "offset {} is past the end of the {} entries",
+                offset, total
+            )));
+        }
+        Ok(Self {
+            offset,
+            limit: limit.min(MAX_PAGE_LIMIT),
+        })
+    }
+
+    /// The entries of `items` on this page.
+    pub fn apply<I: IntoIterator>(&self, items: I) -> impl Iterator<Item = I::Item> {
+        items.into_iter().skip(self.offset).take(self.limit)
+    }
+}
+
+fn bad_request(message: String) -> (StatusCode, Json<Value>) {
+    (
+        StatusCode::BAD_REQUEST,
+        Json(json!({
+            "status": "error",
+            "message": message
+        })),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde::Deserialize;
+
+    #[derive(Debug, Deserialize)]
+    struct PageQuery {
+        #[serde(default = "default_page_limit")]
+        limit: usize,
+    }
+
+    async fn extract(uri: &str) -> Result<ValidQuery<PageQuery>, (StatusCode, Json<Value>)> {
+        let (mut parts, ()) = axum::http::Request::builder()
+            .uri(uri)
+            .body(())
+            .unwrap()
+            .into_parts();
+        ValidQuery::from_request_parts(&mut parts, &()).await
+    }
+
+    #[tokio::test]
+    async fn test_garbage_parameter_rejected() {
+        for uri in ["/api/logs?limit=-1", "/api/logs?limit=lots"] {
+            let (status, Json(body)) = extract(uri).await.unwrap_err();
+            assert_eq!(status, StatusCode::BAD_REQUEST);
+            let message = body["message"].as_str().unwrap();
+            assert!(message.contains("limit: invalid digit"), "{}", message);
+        }
+
+        let ValidQuery(query) = extract("/api/logs").await.unwrap();
+        assert_eq!(query.limit, DEFAULT_PAGE_LIMIT);
+    }
+
+    #[test]
+    fn test_page_bounds() {
+        let page = Page::new(10, 100_000_000, 50).unwrap();
+        assert_eq!(page, Page { offset: 10, limit: MAX_PAGE_LIMIT });
+        assert_eq!(page.apply(0..50).count(), 40);
+
+        assert!(Page::new(50, 10, 50).is_ok());
+        let (status, _) = Page::new(51, 10, 50).unwrap_err();
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+    }
+}
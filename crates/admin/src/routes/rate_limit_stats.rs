@@ -0,0 +1,273 @@
+use axum::extract::{Query, State};
+use axum::Json;
+use layer7waf_common::RateLimitAlgorithm;
+use layer7waf_rate_limit::normalize_rl_key;
+use serde::{Deserialize, Serialize};
+
+use crate::state::SharedState;
+
+#[derive(Serialize)]
+pub struct RateLimitStatsResponse {
+    pub limiters: Vec<LimiterStats>,
+}
+
+#[derive(Serialize)]
+pub struct LimiterStats {
+    /// `"global"` or the route the limiter is attached to.
+    pub scope: String,
+    pub algorithm: RateLimitAlgorithm,
+    pub tracked_keys: usize,
+    pub configured_rps: u64,
+    pub configured_burst: u64,
+    /// Sliding window only, e.g. 300 requests per 60 seconds.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub window_secs: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub effective_limit: Option<u64>,
+}
+
+/// GET /api/rate-limit/stats
+///
+/// Returns a snapshot of every active rate limiter: its algorithm, configured
+/// limits and the number of client keys it is currently tracking. Sliding
+/// window limiters also report the window and the requests allowed in it.
+pub async fn get_rate_limit_stats(State(state): State<SharedState>) -> Json<RateLimitStatsResponse> {
+    let limiters = state
+        .rate_limiters
+        .read()
+        .expect("rate limiter lock poisoned")
+        .iter()
+        .map(|(scope, limiter)| {
+            let stats = limiter.stats();
+            LimiterStats {
+                scope: scope.clone(),
+                algorithm: stats.algorithm,
+                tracked_keys: stats.tracked_keys,
+                configured_rps: stats.configured_rps,
+                configured_burst: stats.configured_burst,
+                window_secs: stats.window_secs,
+                effective_limit: stats.effective_limit,
+            }
+        })
+        .collect();
+
+    Json(RateLimitStatsResponse { limiters })
+}
+
+/// Query parameters for the per-key status endpoint.
+#[derive(Debug, Deserialize)]
+pub struct KeyStatusQuery {
+    /// Client key to inspect (the client IP).
+    pub key: String,
+}
+
+#[derive(Serialize)]
+pub struct RateLimitStatusResponse {
+    pub key: String,
+    pub limiters: Vec<LimiterKeyStatus>,
+}
+
+#[derive(Serialize)]
+pub struct LimiterKeyStatus {
+    pub scope: String,
+    pub algorithm: RateLimitAlgorithm,
+    /// Whether the limiter currently tracks the key.
+    pub tracked: bool,
+    /// Token balance; `None` for sliding window limiters or untracked keys.
+    pub tokens: Option<f64>,
+    pub remaining_capacity: Option<u64>,
+    pub retry_after_secs: Option<f64>,
+}
+
+/// GET /api/rate-limit/status?key=<client>
+///
+/// Returns a client's live state in every active limiter without consuming
+/// anything: its token balance, how many requests it could make right now,
+/// and how long until it may make another. The key is normalized the same
+/// way the proxy normalizes it before limiting.
+pub async fn get_rate_limit_status(
+    State(state): State<SharedState>,
+    Query(query): Query<KeyStatusQuery>,
+) -> Json<RateLimitStatusResponse> {
+    let normalize = state.config.read().expect("config lock poisoned").rate_limit.normalize_keys;
+    let key = if normalize {
+        normalize_rl_key(&query.key).into_owned()
+    } else {
+        query.key
+    };
+
+    let limiters = state
+        .rate_limiters
+        .read()
+        .expect("rate limiter lock poisoned")
+        .iter()
+        .map(|(scope, limiter)| {
+            let status = limiter.key_status(&key);
+            LimiterKeyStatus {
+                scope: scope.clone(),
+                algorithm: limiter.stats().algorithm,
+                tracked: limiter.is_tracking(&key),
+                tokens: status.as_ref().map(|s| s.tokens),
+                remaining_capacity: status.as_ref().map(|s| s.remaining_capacity),
+                retry_after_secs: status.as_ref().map(|s| s.retry_after.as_secs_f64()),
+            }
+        })
+        .collect();
+
+    Json(RateLimitStatusResponse {
+        key,
+        limiters,
+    })
+}
+
+#[derive(Serialize)]
+pub struct SlidingWindowDebugResponse {
+    pub key: String,
+    /// Sliding window limiters tracking the key.
+    pub limiters: Vec<LimiterWindowDebug>,
+}
+
+#[derive(Serialize)]
+pub struct LimiterWindowDebug {
+    pub scope: String,
+    pub current_count: u64,
+    pub previous_count: u64,
+    pub elapsed_fraction: f64,
+    pub weighted_count: f64,
+    pub limit: u64,
+    /// Multiplier on `limit` from adaptive tightening.
+    pub penalty: f64,
+}
+
+/// GET /api/debug/rate-limit/window?key=<client>
+///
+/// Returns the blended calculation every sliding window limiter would make
+/// for a client right now, without counting a request. Served only with
+/// `server.admin.debug_endpoints` enabled.
+pub async fn get_sliding_window_debug(
+    State(state): State<SharedState>,
+    Query(query): Query<KeyStatusQuery>,
+) -> Json<SlidingWindowDebugResponse> {
+    let normalize = state.config.read().expect("config lock poisoned").rate_limit.normalize_keys;
+    let key = if normalize {
+        normalize_rl_key(&query.key).into_owned()
+    } else {
+        query.key
+    };
+
+    let limiters = state
+        .rate_limiters
+        .read()
+        .expect("rate limiter lock poisoned")
+        .iter()
+        .filter_map(|(scope, limiter)| {
+            let debug = limiter.sliding_window_debug(&key)?;
+            Some(LimiterWindowDebug {
+                scope: scope.clone(),
+                current_count: debug.current_count,
+                previous_count: debug.previous_count,
+                elapsed_fraction: debug.elapsed_fraction,
+                weighted_count: debug.weighted_count,
+                limit: debug.limit,
+                penalty: debug.penalty,
+            })
+        })
+        .collect();
+
+    Json(SlidingWindowDebugResponse { key, limiters })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::state::test_state;
+    use layer7waf_rate_limit::RateLimiter;
+
+    #[tokio::test]
+    async fn test_stats_per_scope() {
+        let state = test_state();
+        let global = RateLimiter::new_token_bucket(100, 200);
+        let login = RateLimiter::new_sliding_window(5, 60);
+        global.check("10.0.0.1");
+        global.check("10.0.0.2");
+        login.check("10.0.0.1");
+        state.rate_limiters.write().unwrap().extend([
+            ("global".to_string(), global),
+            ("/login".to_string(), login),
+        ]);
+
+        let Json(resp) = get_rate_limit_stats(State(state)).await;
+        assert_eq!(resp.limiters.len(), 2);
+
+        assert_eq!(resp.limiters[0].scope, "global");
+        assert_eq!(resp.limiters[0].algorithm, RateLimitAlgorithm::TokenBucket);
+        assert_eq!(resp.limiters[0].configured_rps, 100);
+        assert_eq!(resp.limiters[0].configured_burst, 200);
+        assert_eq!(resp.limiters[0].tracked_keys, 2);
+        assert_eq!(resp.limiters[0].effective_limit, None);
+
+        assert_eq!(resp.limiters[1].scope, "/login");
+        assert_eq!(resp.limiters[1].algorithm, RateLimitAlgorithm::SlidingWindow);
+        assert_eq!(resp.limiters[1].tracked_keys, 1);
+        assert_eq!(resp.limiters[1].window_secs, Some(60));
+        assert_eq!(resp.limiters[1].effective_limit, Some(300));
+    }
+
+    #[tokio::test]
+    async fn test_key_status() {
+        let state = test_state();
+        let global = RateLimiter::new_token_bucket(1, 3);
+        let login = RateLimiter::new_sliding_window(5, 1);
+        global.check("10.0.0.1");
+        global.check("10.0.0.1");
+        login.check("10.0.0.1");
+        state.rate_limiters.write().unwrap().extend([
+            ("global".to_string(), global),
+            ("/login".to_string(), login),
+        ]);
+
+        let query = KeyStatusQuery { key: "10.0.0.1".to_string() };
+        let Json(resp) = get_rate_limit_status(State(state.clone()), Query(query)).await;
+        let global = &resp.limiters[0];
+        assert!(global.tracked);
+        assert_eq!(global.remaining_capacity, Some(1));
+        assert_eq!(global.retry_after_secs, Some(0.0));
+        let login = &resp.limiters[1];
+        assert!(login.tracked);
+        assert_eq!(login.tokens, None);
+
+        let query = KeyStatusQuery { key: "10.0.0.9".to_string() };
+        let Json(resp) = get_rate_limit_status(State(state), Query(query)).await;
+        assert!(!resp.limiters[0].tracked);
+        assert_eq!(resp.limiters[0].tokens, None);
+    }
+
+    #[tokio::test]
+    async fn test_sliding_window_debug() {
+        let state = test_state();
+        let global = RateLimiter::new_token_bucket(100, 200);
+        let login = RateLimiter::new_sliding_window(5, 1);
+        global.check("10.0.0.1");
+        login.check("10.0.0.1");
+        login.check("10.0.0.1");
+        state.rate_limiters.write().unwrap().extend([
+            ("global".to_string(), global),
+            ("/login".to_string(), login),
+        ]);
+
+        let query = KeyStatusQuery { key: "10.0.0.1".to_string() };
+        let Json(resp) = get_sliding_window_debug(State(state.clone()), Query(query)).await;
+        assert_eq!(resp.limiters.len(), 1);
+        let login = &resp.limiters[0];
+        assert_eq!(login.scope, "/login");
+        assert_eq!(login.current_count, 2);
+        assert_eq!(login.previous_count, 0);
+        assert_eq!(login.limit, 5);
+        assert!((login.weighted_count - 2.0).abs() < 1e-9);
+
+        // Inspecting didn't count a request
+        let query = KeyStatusQuery { key: "10.0.0.1".to_string() };
+        let Json(resp) = get_sliding_window_debug(State(state), Query(query)).await;
+        assert_eq!(resp.limiters[0].current_count, 2);
+    }
+}
@@ -11,19 +11,73 @@ use crate::state::SharedState;
 /// GET /api/rules
 ///
 /// Returns the list of configured WAF rule files from the config
-/// plus any custom rules added at runtime.
+/// plus any custom rules added at runtime, along with the custom rule cap.
+/// `pending` is true while the custom rules differ from those the WAF
+/// engine is enforcing.
 pub async fn list_rules(State(state): State<SharedState>) -> Json<Value> {
     let config = state.config.read().expect("config lock poisoned");
     let custom_rules = state.custom_rules.read().expect("custom_rules lock poisoned");
+    let applied = state
+        .applied_custom_rules
+        .read()
+        .expect("applied_custom_rules lock poisoned");
 
     Json(json!({
         "rule_files": config.waf.rules,
         "custom_rules": custom_rules.iter().enumerate().map(|(i, r)| {
             json!({ "id": i, "rule": r })
-        }).collect::<Vec<Value>>()
+        }).collect::<Vec<Value>>(),
+        "custom_rule_count": custom_rules.len(),
+        "max_custom_rules": config.waf.max_custom_rules,
+        "pending": *custom_rules != *applied
     }))
 }
 
+/// POST /api/rules/apply
+///
+/// Rebuilds the proxy's WAF engine with the current custom rules. Returns
+/// 400 if the engine rejects them, leaving the previous rules in force, and
+/// 503 when no proxy is attached.
+pub async fn apply_rules(State(state): State<SharedState>) -> impl IntoResponse {
+    let reloader = state.reloader.read().expect("reloader lock poisoned").clone();
+    let Some(reloader) = reloader else {
+        return (
+            StatusCode::SERVICE_UNAVAILABLE,
+            Json(json!({
+                "status": "error",
+                "message": "no proxy attached to apply rules to"
+            })),
+        );
+    };
+
+    let rules = state.custom_rules.read().expect("custom_rules lock poisoned").clone();
+    if let Err(e) = reloader.apply_custom_rules(&rules) {
+        tracing::warn!(error = %e, "failed to apply custom rules");
+        return (
+            StatusCode::BAD_REQUEST,
+            Json(json!({
+                "status": "error",
+                "message": format!("failed to apply custom rules: {}", e)
+            })),
+        );
+    }
+
+    let count = rules.len();
+    *state
+        .applied_custom_rules
+        .write()
+        .expect("applied_custom_rules lock poisoned") = rules;
+    tracing::info!(count, "custom rules applied to WAF engine");
+
+    (
+        StatusCode::OK,
+        Json(json!({
+            "status": "applied",
+            "custom_rule_count": count
+        })),
+    )
+}
+
 /// Request body for adding a new custom rule.
 #[derive(Debug, Deserialize)]
 pub struct AddRuleRequest {
@@ -33,6 +87,7 @@ pub struct AddRuleRequest {
 /// POST /api/rules
 ///
 /// Adds a custom WAF rule string (e.g. "SecRule ...") to the in-memory list.
+/// Returns 429 once `waf.max_custom_rules` rules are already present.
 pub async fn add_rule(
     State(state): State<SharedState>,
     Json(body): Json<AddRuleRequest>,
@@ -47,7 +102,25 @@ pub async fn add_rule(
         );
     }
 
+    let max_custom_rules = state
+        .config
+        .read()
+        .expect("config lock poisoned")
+        .waf
+        .max_custom_rules;
+
     let mut custom_rules = state.custom_rules.write().expect("custom_rules lock poisoned");
+    if custom_rules.len() >= max_custom_rules {
+        tracing::warn!(max_custom_rules, "rejecting custom rule: limit reached");
+        return (
+            StatusCode::TOO_MANY_REQUESTS,
+            Json(json!({
+                "status": "error",
+                "message": format!("custom rule limit of {} reached", max_custom_rules)
+            })),
+        );
+    }
+
     let id = custom_rules.len();
     custom_rules.push(body.rule.clone());
 
@@ -138,3 +211,106 @@ pub async fn test_rule(
         "message": "stub: rule evaluation not yet implemented"
     }))
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::reload::{ConfigReloader, Subsystem};
+    use crate::state::test_state;
+    use layer7waf_common::AppConfig;
+    use std::sync::{Arc, Mutex};
+
+    fn rule(s: &str) -> Json<AddRuleRequest> {
+        Json(AddRuleRequest { rule: s.to_string() })
+    }
+
+    #[tokio::test]
+    async fn test_add_rule_rejected_past_cap() {
+        let state = test_state();
+        state.config.write().unwrap().waf.max_custom_rules = 2;
+
+        for i in 0..2 {
+            let body = rule(&format!("SecRule ARGS \"x{}\" \"id:{},deny\"", i, i));
+            let resp = add_rule(State(state.clone()), body).await.into_response();
+            assert_eq!(resp.status(), StatusCode::CREATED);
+        }
+
+        let resp = add_rule(State(state.clone()), rule("SecRule ARGS \"y\" \"id:9,deny\""))
+            .await
+            .into_response();
+        assert_eq!(resp.status(), StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(state.custom_rules.read().unwrap().len(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_list_rules_reports_count_and_cap() {
+        let state = test_state();
+        state.config.write().unwrap().waf.max_custom_rules = 5;
+        state.custom_rules.write().unwrap().push("SecRule ARGS \"x\" \"id:1,deny\"".to_string());
+
+        let Json(body) = list_rules(State(state)).await;
+        assert_eq!(body["custom_rule_count"], 1);
+        assert_eq!(body["max_custom_rules"], 5);
+        assert_eq!(body["pending"], true);
+    }
+
+    struct RecordingReloader {
+        applied: Mutex<Vec<String>>,
+        fail: bool,
+    }
+
+    impl ConfigReloader for RecordingReloader {
+        fn reload(&self, _config: &AppConfig, _subsystems: &[Subsystem]) -> anyhow::Result<()> {
+            Ok(())
+        }
+
+        fn apply_custom_rules(&self, rules: &[String]) -> anyhow::Result<()> {
+            if self.fail {
+                anyhow::bail!("syntax error");
+            }
+            *self.applied.lock().unwrap() = rules.to_vec();
+            Ok(())
+        }
+    }
+
+    fn attach(state: &SharedState, fail: bool) -> Arc<RecordingReloader> {
+        let reloader = Arc::new(RecordingReloader { applied: Mutex::new(Vec::new()), fail });
+        *state.reloader.write().unwrap() = Some(reloader.clone());
+        reloader
+    }
+
+    #[tokio::test]
+    async fn test_apply_rules_feeds_reloader() {
+        let state = test_state();
+        let reloader = attach(&state, false);
+        let resp = add_rule(State(state.clone()), rule("SecRule ARGS \"x\" \"id:1,deny\""))
+            .await
+            .into_response();
+        assert_eq!(resp.status(), StatusCode::CREATED);
+
+        let resp = apply_rules(State(state.clone())).await.into_response();
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert_eq!(reloader.applied.lock().unwrap().len(), 1);
+
+        let Json(body) = list_rules(State(state)).await;
+        assert_eq!(body["pending"], false);
+    }
+
+    #[tokio::test]
+    async fn test_apply_rules_failure_stays_pending() {
+        let state = test_state();
+        attach(&state, true);
+        state.custom_rules.write().unwrap().push("SecRule broken".to_string());
+
+        let resp = apply_rules(State(state.clone())).await.into_response();
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+        let Json(body) = list_rules(State(state)).await;
+        assert_eq!(body["pending"], true);
+    }
+
+    #[tokio::test]
+    async fn test_apply_rules_without_proxy() {
+        let resp = apply_rules(State(test_state())).await.into_response();
+        assert_eq!(resp.status(), StatusCode::SERVICE_UNAVAILABLE);
+    }
+}
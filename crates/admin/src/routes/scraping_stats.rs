@@ -1,6 +1,9 @@
 use axum::extract::State;
+use axum::http::StatusCode;
+use axum::response::IntoResponse;
 use axum::Json;
-use serde::Serialize;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
 
 use crate::state::SharedState;
 
@@ -36,3 +39,95 @@ pub async fn get_scraping_stats(State(state): State<SharedState>) -> Json<Scrapi
         captcha_pass_rate,
     })
 }
+
+/// Body of `POST /api/scraping/identify`.
+#[derive(Debug, Deserialize)]
+pub struct IdentifyRequest {
+    /// Scraped content that may carry a zero-width watermark.
+    pub text: String,
+}
+
+/// POST /api/scraping/identify
+///
+/// Recovers the anti-scraping watermark from scraped content and reports
+/// which client it was served to. Returns 404 when the text carries no
+/// watermark and 503 when no proxy is attached.
+pub async fn identify_watermark(
+    State(state): State<SharedState>,
+    Json(request): Json<IdentifyRequest>,
+) -> impl IntoResponse {
+    let reloader = state.reloader.read().expect("reloader lock poisoned").clone();
+    let Some(reloader) = reloader else {
+        return (
+            StatusCode::SERVICE_UNAVAILABLE,
+            Json(json!({
+                "status": "error",
+                "message": "no proxy attached to identify watermarks with"
+            })),
+        );
+    };
+
+    match reloader.identify_watermark(&request.text) {
+        Some(identified) => (StatusCode::OK, Json(json!(identified))),
+        None => (
+            StatusCode::NOT_FOUND,
+            Json(json!({
+                "status": "error",
+                "message": "no watermark found in text"
+            })),
+        ),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::reload::{ConfigReloader, IdentifiedWatermark, Subsystem};
+    use crate::state::test_state;
+    use layer7waf_common::AppConfig;
+    use std::sync::Arc;
+
+    /// Knows a single watermark, served to 10.0.0.7.
+    struct WatermarkReloader;
+
+    impl ConfigReloader for WatermarkReloader {
+        fn reload(&self, _config: &AppConfig, _subsystems: &[Subsystem]) -> anyhow::Result<()> {
+            Ok(())
+        }
+
+        fn identify_watermark(&self, text: &str) -> Option<IdentifiedWatermark> {
+            text.contains('\u{200B}').then(|| IdentifiedWatermark {
+                watermark: "0a0b0c0d".to_string(),
+                ip: Some("10.0.0.7".to_string()),
+            })
+        }
+    }
+
+    fn identify(text: &str) -> IdentifyRequest {
+        IdentifyRequest { text: text.to_string() }
+    }
+
+    #[tokio::test]
+    async fn test_identify_reports_owner() {
+        let state = test_state();
+        *state.reloader.write().unwrap() = Some(Arc::new(WatermarkReloader));
+
+        let resp = identify_watermark(State(state.clone()), Json(identify("Price\u{200B} list")))
+            .await
+            .into_response();
+        assert_eq!(resp.status(), StatusCode::OK);
+
+        let resp = identify_watermark(State(state), Json(identify("Price list")))
+            .await
+            .into_response();
+        assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn test_identify_without_proxy_unavailable() {
+        let resp = identify_watermark(State(test_state()), Json(identify("x")))
+            .await
+            .into_response();
+        assert_eq!(resp.status(), StatusCode::SERVICE_UNAVAILABLE);
+    }
+}
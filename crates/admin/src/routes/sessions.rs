@@ -0,0 +1,134 @@
+use axum::extract::{Query, State};
+use axum::http::StatusCode;
+use axum::response::IntoResponse;
+use axum::Json;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+
+use crate::reload::FlaggedSession;
+use crate::state::SharedState;
+
+#[derive(Serialize)]
+pub struct FlaggedSessionsResponse {
+    pub sessions: Vec<FlaggedSession>,
+}
+
+/// GET /api/sessions/flagged
+///
+/// Returns every client whose bot-detection or anti-scraping score is above
+/// the configured threshold, with its scores. Empty when no proxy is
+/// attached.
+pub async fn get_flagged_sessions(State(state): State<SharedState>) -> Json<FlaggedSessionsResponse> {
+    let reloader = state.reloader.read().expect("reloader lock poisoned").clone();
+    let sessions = reloader.map(|r| r.flagged_sessions()).unwrap_or_default();
+    Json(FlaggedSessionsResponse { sessions })
+}
+
+/// Query parameters for clearing sessions.
+#[derive(Debug, Deserialize)]
+pub struct ClearSessionsQuery {
+    /// Only clear this client's sessions; all of them when unset.
+    pub ip: Option<String>,
+}
+
+/// DELETE /api/sessions?ip=<client>
+///
+/// Clears the bot-detection and anti-scraping sessions of one client, or of
+/// all clients, forcing them to be re-evaluated. Returns 503 when no proxy
+/// is attached.
+pub async fn clear_sessions(
+    State(state): State<SharedState>,
+    Query(query): Query<ClearSessionsQuery>,
+) -> impl IntoResponse {
+    let reloader = state.reloader.read().expect("reloader lock poisoned").clone();
+    let Some(reloader) = reloader else {
+        return (
+            StatusCode::SERVICE_UNAVAILABLE,
+            Json(json!({
+                "status": "error",
+                "message": "no proxy attached to clear sessions in"
+            })),
+        );
+    };
+
+    let cleared = reloader.clear_sessions(query.ip.as_deref());
+    tracing::info!(ip = ?query.ip, cleared, "detector sessions cleared");
+
+    (
+        StatusCode::OK,
+        Json(json!({
+            "status": "cleared",
+            "ip": query.ip,
+            "cleared": cleared
+        })),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::reload::{ConfigReloader, Subsystem};
+    use crate::state::test_state;
+    use layer7waf_common::AppConfig;
+    use std::sync::{Arc, Mutex};
+
+    /// Holds flagged sessions the way the proxy's detectors would.
+    struct SessionReloader {
+        flagged: Mutex<Vec<FlaggedSession>>,
+    }
+
+    impl ConfigReloader for SessionReloader {
+        fn reload(&self, _config: &AppConfig, _subsystems: &[Subsystem]) -> anyhow::Result<()> {
+            Ok(())
+        }
+
+        fn flagged_sessions(&self) -> Vec<FlaggedSession> {
+            self.flagged.lock().unwrap().clone()
+        }
+
+        fn clear_sessions(&self, ip: Option<&str>) -> usize {
+            let mut flagged = self.flagged.lock().unwrap();
+            let before = flagged.len();
+            flagged.retain(|s| ip.is_some_and(|ip| s.ip != ip));
+            before - flagged.len()
+        }
+    }
+
+    fn session(ip: &str, bot_score: f64) -> FlaggedSession {
+        FlaggedSession {
+            ip: ip.to_string(),
+            bot_score: Some(bot_score),
+            scraping_score: None,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_flagged_sessions_listed_then_cleared() {
+        let state = test_state();
+        let reloader = Arc::new(SessionReloader {
+            flagged: Mutex::new(vec![session("10.0.0.1", 0.9), session("10.0.0.2", 0.8)]),
+        });
+        *state.reloader.write().unwrap() = Some(reloader);
+
+        let Json(resp) = get_flagged_sessions(State(state.clone())).await;
+        assert_eq!(resp.sessions, vec![session("10.0.0.1", 0.9), session("10.0.0.2", 0.8)]);
+
+        let query = ClearSessionsQuery { ip: Some("10.0.0.1".to_string()) };
+        let resp = clear_sessions(State(state.clone()), Query(query)).await.into_response();
+        assert_eq!(resp.status(), StatusCode::OK);
+        let Json(resp) = get_flagged_sessions(State(state.clone())).await;
+        assert_eq!(resp.sessions, vec![session("10.0.0.2", 0.8)]);
+
+        let query = ClearSessionsQuery { ip: None };
+        clear_sessions(State(state.clone()), Query(query)).await;
+        let Json(resp) = get_flagged_sessions(State(state)).await;
+        assert!(resp.sessions.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_clear_without_proxy_unavailable() {
+        let query = ClearSessionsQuery { ip: None };
+        let resp = clear_sessions(State(test_state()), Query(query)).await.into_response();
+        assert_eq!(resp.status(), StatusCode::SERVICE_UNAVAILABLE);
+    }
+}
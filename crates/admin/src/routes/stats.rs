@@ -1,5 +1,7 @@
 use axum::extract::State;
 use axum::Json;
+use layer7waf_common::MapFootprint;
+use serde::Serialize;
 use serde_json::{json, Value};
 
 use crate::state::SharedState;
@@ -28,3 +30,72 @@ pub async fn get_stats(State(state): State<SharedState>) -> Json<Value> {
         "requests_per_second": requests_per_second
     }))
 }
+
+#[derive(Serialize)]
+pub struct MemoryStatsResponse {
+    pub maps: Vec<MapFootprint>,
+    pub total_entries: usize,
+    pub total_approx_bytes: usize,
+}
+
+/// GET /api/stats/memory
+///
+/// Returns the entry count and approximate memory use of each per-key map
+/// (rate limiters, bot-detection and anti-scraping state). Empty when no
+/// proxy is attached.
+pub async fn get_memory_stats(State(state): State<SharedState>) -> Json<MemoryStatsResponse> {
+    let reloader = state.reloader.read().expect("reloader lock poisoned").clone();
+    let maps = reloader.map(|r| r.map_footprints()).unwrap_or_default();
+    Json(MemoryStatsResponse {
+        total_entries: maps.iter().map(|m| m.entries).sum(),
+        total_approx_bytes: maps.iter().map(|m| m.approx_bytes).sum(),
+        maps,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::reload::{ConfigReloader, Subsystem};
+    use crate::state::test_state;
+    use layer7waf_common::AppConfig;
+    use std::sync::Arc;
+
+    /// Reports a fixed set of maps.
+    struct FootprintReloader;
+
+    impl ConfigReloader for FootprintReloader {
+        fn reload(&self, _config: &AppConfig, _subsystems: &[Subsystem]) -> anyhow::Result<()> {
+            Ok(())
+        }
+
+        fn map_footprints(&self) -> Vec<MapFootprint> {
+            vec![
+                MapFootprint::of::<u64>("rate_limit:global", 3),
+                MapFootprint::of::<u64>("bot_sessions", 2),
+            ]
+        }
+    }
+
+    #[tokio::test]
+    async fn test_memory_stats_totals() {
+        let state = test_state();
+        *state.reloader.write().unwrap() = Some(Arc::new(FootprintReloader));
+
+        let Json(resp) = get_memory_stats(State(state)).await;
+        assert_eq!(resp.maps.len(), 2);
+        assert_eq!(resp.maps[0].name, "rate_limit:global");
+        assert_eq!(resp.total_entries, 5);
+        assert_eq!(
+            resp.total_approx_bytes,
+            resp.maps[0].approx_bytes + resp.maps[1].approx_bytes
+        );
+    }
+
+    #[tokio::test]
+    async fn test_memory_stats_empty_without_proxy() {
+        let Json(resp) = get_memory_stats(State(test_state())).await;
+        assert!(resp.maps.is_empty());
+        assert_eq!(resp.total_entries, 0);
+    }
+}
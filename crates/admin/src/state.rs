@@ -1,9 +1,21 @@
+use std::collections::{BTreeMap, VecDeque};
 use std::sync::{Arc, RwLock};
 
+use layer7waf_bot_detect::under_attack::UnderAttackMode;
 use layer7waf_common::AppConfig;
+use layer7waf_rate_limit::RateLimiter;
+use prometheus::core::Collector;
 use prometheus::{HistogramOpts, HistogramVec, IntCounter, IntCounterVec, Opts, Registry};
 use serde::{Deserialize, Serialize};
 
+use crate::reload::{changed_subsystems, ConfigReloader, Subsystem};
+
+/// Maximum number of config changes kept in the history.
+pub const MAX_CONFIG_HISTORY: usize = 50;
+
+/// Maximum number of audit log entries kept in memory.
+pub const MAX_AUDIT_LOG_ENTRIES: usize = 10_000;
+
 /// Shared state type alias used across all route handlers.
 pub type SharedState = Arc<AppState>;
 
@@ -11,8 +23,20 @@ pub type SharedState = Arc<AppState>;
 pub struct AppState {
     pub config: RwLock<AppConfig>,
     pub metrics: WafMetrics,
-    pub audit_log: RwLock<Vec<AuditLogEntry>>,
+    pub audit_log: RwLock<VecDeque<AuditLogEntry>>,
     pub custom_rules: RwLock<Vec<String>>,
+    /// Custom rules the WAF engine was last built with; `custom_rules` may
+    /// differ until they are applied.
+    pub applied_custom_rules: RwLock<Vec<String>>,
+    /// Live rate limiters registered by the proxy, labelled by scope
+    /// (`"global"` or the route they belong to).
+    pub rate_limiters: RwLock<Vec<(String, RateLimiter)>>,
+    /// Applies config changes to the running proxy, when one is attached.
+    pub reloader: RwLock<Option<Arc<dyn ConfigReloader>>>,
+    /// Bounded history of config changes, oldest first.
+    pub config_history: RwLock<VecDeque<ConfigChangeEntry>>,
+    /// "Under attack" toggle shared with the proxy.
+    pub under_attack: Arc<UnderAttackMode>,
     pub start_time: std::time::Instant,
 }
 
@@ -33,9 +57,33 @@ pub struct WafMetrics {
     pub captchas_solved: IntCounter,
     pub responses_obfuscated: IntCounter,
     pub geoip_blocked: IntCounter,
+    pub geoip_blocked_by_reason: IntCounterVec,
     pub geoip_lookups: IntCounter,
 }
 
+/// Values of every [`WafMetrics`] counter at one moment, for callers
+/// embedding the WAF that export or assert on metrics themselves.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct MetricsSnapshot {
+    pub requests_total: u64,
+    pub requests_blocked: u64,
+    /// Hit counts keyed by rule ID.
+    pub rule_hits: BTreeMap<String, u64>,
+    pub rate_limited_total: u64,
+    pub bots_detected: u64,
+    pub challenges_issued: u64,
+    pub challenges_solved: u64,
+    pub scrapers_blocked: u64,
+    pub traps_triggered: u64,
+    pub captchas_issued: u64,
+    pub captchas_solved: u64,
+    pub responses_obfuscated: u64,
+    pub geoip_blocked: u64,
+    /// Block counts keyed by reason.
+    pub geoip_blocked_by_reason: BTreeMap<String, u64>,
+    pub geoip_lookups: u64,
+}
+
 /// A single audit log entry representing a processed request.
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct AuditLogEntry {
@@ -47,6 +95,38 @@ pub struct AuditLogEntry {
     pub rule_id: Option<String>,
     pub action: String,
     pub status: u16,
+    /// Truncated, redacted snippet of the offending request
+    /// (see `waf.audit_log.body_preview_bytes`).
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub body_preview: Option<String>,
+}
+
+impl AuditLogEntry {
+    /// Create an entry stamped with a fresh id and the current time.
+    pub fn new(client_ip: &str, method: &str, uri: &str, action: &str, status: u16) -> Self {
+        Self {
+            id: uuid::Uuid::new_v4().to_string(),
+            timestamp: chrono::Utc::now().to_rfc3339(),
+            client_ip: client_ip.to_string(),
+            method: method.to_string(),
+            uri: uri.to_string(),
+            rule_id: None,
+            action: action.to_string(),
+            status,
+            body_preview: None,
+        }
+    }
+}
+
+/// A recorded config mutation, holding the config as it was *before* the change.
+#[derive(Debug, Clone, Serialize)]
+pub struct ConfigChangeEntry {
+    pub id: u64,
+    pub timestamp: String,
+    pub actor: String,
+    pub diff_summary: String,
+    #[serde(skip_serializing)]
+    pub snapshot: serde_json::Value,
 }
 
 impl WafMetrics {
@@ -128,6 +208,12 @@ impl WafMetrics {
         )
         .expect("failed to create geoip_blocked counter");
 
+        let geoip_blocked_by_reason = IntCounterVec::new(
+            Opts::new("waf_geoip_blocked_by_reason", "Number of requests blocked by GeoIP, by reason"),
+            &["reason"],
+        )
+        .expect("failed to create geoip_blocked_by_reason counter");
+
         let geoip_lookups = IntCounter::with_opts(
             Opts::new("waf_geoip_lookups", "Total number of GeoIP lookups performed"),
         )
@@ -147,6 +233,7 @@ impl WafMetrics {
         registry.register(Box::new(captchas_solved.clone())).expect("failed to register captchas_solved");
         registry.register(Box::new(responses_obfuscated.clone())).expect("failed to register responses_obfuscated");
         registry.register(Box::new(geoip_blocked.clone())).expect("failed to register geoip_blocked");
+        registry.register(Box::new(geoip_blocked_by_reason.clone())).expect("failed to register geoip_blocked_by_reason");
         registry.register(Box::new(geoip_lookups.clone())).expect("failed to register geoip_lookups");
 
         Self {
@@ -165,9 +252,46 @@ impl WafMetrics {
             captchas_solved,
             responses_obfuscated,
             geoip_blocked,
+            geoip_blocked_by_reason,
             geoip_lookups,
         }
     }
+
+    /// Read every counter in one pass. Each counter is read atomically, but
+    /// requests finishing during the call may be counted in some fields and
+    /// not yet in others.
+    pub fn snapshot(&self) -> MetricsSnapshot {
+        MetricsSnapshot {
+            requests_total: self.requests_total.get(),
+            requests_blocked: self.requests_blocked.get(),
+            rule_hits: counts_by_label(&self.rule_hits, "rule_id"),
+            rate_limited_total: self.rate_limited_total.get(),
+            bots_detected: self.bots_detected.get(),
+            challenges_issued: self.challenges_issued.get(),
+            challenges_solved: self.challenges_solved.get(),
+            scrapers_blocked: self.scrapers_blocked.get(),
+            traps_triggered: self.traps_triggered.get(),
+            captchas_issued: self.captchas_issued.get(),
+            captchas_solved: self.captchas_solved.get(),
+            responses_obfuscated: self.responses_obfuscated.get(),
+            geoip_blocked: self.geoip_blocked.get(),
+            geoip_blocked_by_reason: counts_by_label(&self.geoip_blocked_by_reason, "reason"),
+            geoip_lookups: self.geoip_lookups.get(),
+        }
+    }
+}
+
+/// Current value of each series of `counter`, keyed by its `label` value.
+pub(crate) fn counts_by_label(counter: &IntCounterVec, label: &str) -> BTreeMap<String, u64> {
+    let mut counts = BTreeMap::new();
+    for family in counter.collect() {
+        for metric in family.get_metric() {
+            if let Some(l) = metric.get_label().iter().find(|l| l.get_name() == label) {
+                counts.insert(l.get_value().to_string(), metric.get_counter().get_value() as u64);
+            }
+        }
+    }
+    counts
 }
 
 impl AppState {
@@ -176,9 +300,158 @@ impl AppState {
         Self {
             config: RwLock::new(config),
             metrics: WafMetrics::new(),
-            audit_log: RwLock::new(Vec::new()),
+            audit_log: RwLock::new(VecDeque::new()),
             custom_rules: RwLock::new(Vec::new()),
+            applied_custom_rules: RwLock::new(Vec::new()),
+            rate_limiters: RwLock::new(Vec::new()),
+            reloader: RwLock::new(None),
+            config_history: RwLock::new(VecDeque::new()),
+            under_attack: Arc::new(UnderAttackMode::new()),
             start_time: std::time::Instant::now(),
         }
     }
+
+    /// Append an audit log entry, dropping the oldest once the log is full.
+    pub fn push_audit_entry(&self, entry: AuditLogEntry) {
+        let mut log = self.audit_log.write().expect("audit_log lock poisoned");
+        if log.len() >= MAX_AUDIT_LOG_ENTRIES {
+            log.pop_front();
+        }
+        log.push_back(entry);
+    }
+
+    /// Apply `new_config` to the running proxy, rebuilding only the
+    /// subsystems whose config changed, then record it like
+    /// [`replace_config`](Self::replace_config). On a failed reload nothing
+    /// is changed or recorded.
+    pub fn apply_config(
+        &self,
+        new_config: AppConfig,
+        actor: &str,
+    ) -> anyhow::Result<(ConfigChangeEntry, Vec<Subsystem>)> {
+        let changed = {
+            let config = self.config.read().expect("config lock poisoned");
+            changed_subsystems(&config, &new_config)
+        };
+
+        let reloader = self.reloader.read().expect("reloader lock poisoned").clone();
+        if let Some(reloader) = reloader {
+            reloader.reload(&new_config, &changed)?;
+            if changed.contains(&Subsystem::RateLimit) {
+                *self.rate_limiters.write().expect("rate_limiters lock poisoned") =
+                    reloader.rate_limiters();
+            }
+        }
+
+        Ok((self.replace_config(new_config, actor), changed))
+    }
+
+    /// Replace the running config and record the change in the history.
+    pub fn replace_config(&self, new_config: AppConfig, actor: &str) -> ConfigChangeEntry {
+        let mut config = self.config.write().expect("config lock poisoned");
+        let old_value = serde_json::to_value(&*config).unwrap_or_default();
+        let new_value = serde_json::to_value(&new_config).unwrap_or_default();
+        *config = new_config;
+
+        let mut history = self.config_history.write().expect("config_history lock poisoned");
+        let entry = ConfigChangeEntry {
+            id: history.back().map(|e| e.id + 1).unwrap_or(0),
+            timestamp: chrono::Utc::now().to_rfc3339(),
+            actor: actor.to_string(),
+            diff_summary: diff_summary(&old_value, &new_value),
+            snapshot: old_value,
+        };
+        if history.len() >= MAX_CONFIG_HISTORY {
+            history.pop_front();
+        }
+        history.push_back(entry.clone());
+        entry
+    }
+}
+
+/// Summarize which config fields differ, as a comma-separated list of dotted paths.
+fn diff_summary(old: &serde_json::Value, new: &serde_json::Value) -> String {
+    fn walk(path: &str, old: &serde_json::Value, new: &serde_json::Value, out: &mut Vec<String>) {
+        match (old, new) {
+            (serde_json::Value::Object(a), serde_json::Value::Object(b)) => {
+                let mut keys: Vec<&String> = a.keys().chain(b.keys()).collect();
+                keys.sort();
+                keys.dedup();
+                for key in keys {
+                    let child = if path.is_empty() {
+                        key.clone()
+                    } else {
+                        format!("{}.{}", path, key)
+                    };
+                    let null = serde_json::Value::Null;
+                    walk(&child, a.get(key).unwrap_or(&null), b.get(key).unwrap_or(&null), out);
+                }
+            }
+            (a, b) if a != b => out.push(path.to_string()),
+            _ => {}
+        }
+    }
+
+    let mut changed = Vec::new();
+    walk("", old, new, &mut changed);
+    if changed.is_empty() {
+        "no changes".to_string()
+    } else {
+        format!("changed: {}", changed.join(", "))
+    }
+}
+
+/// Build a `SharedState` around a minimal configuration for route handler tests.
+#[cfg(test)]
+pub(crate) fn test_state() -> SharedState {
+    let config: AppConfig = serde_json::from_value(serde_json::json!({
+        "server": { "listen": ["127.0.0.1:8080"] },
+        "upstreams": [],
+        "routes": [],
+        "waf": {}
+    }))
+    .expect("minimal test config");
+    Arc::new(AppState::new(config))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_snapshot_reflects_counters() {
+        let metrics = WafMetrics::new();
+        assert_eq!(metrics.snapshot(), MetricsSnapshot::default());
+
+        metrics.requests_total.inc_by(10);
+        metrics.requests_blocked.inc_by(3);
+        metrics.rule_hits.with_label_values(&["942100"]).inc_by(2);
+        metrics.rule_hits.with_label_values(&["941100"]).inc();
+        metrics.bots_detected.inc();
+        metrics.geoip_blocked_by_reason.with_label_values(&["blocklisted"]).inc();
+
+        let snapshot = metrics.snapshot();
+        assert_eq!(snapshot.requests_total, 10);
+        assert_eq!(snapshot.requests_blocked, 3);
+        assert_eq!(
+            snapshot.rule_hits,
+            BTreeMap::from([("941100".to_string(), 1), ("942100".to_string(), 2)])
+        );
+        assert_eq!(snapshot.bots_detected, 1);
+        assert_eq!(snapshot.geoip_blocked_by_reason["blocklisted"], 1);
+        assert_eq!(snapshot.scrapers_blocked, 0);
+    }
+
+    #[test]
+    fn test_audit_log_drops_oldest_when_full() {
+        let state = test_state();
+        for i in 0..MAX_AUDIT_LOG_ENTRIES + 2 {
+            let uri = format!("/{}", i);
+            state.push_audit_entry(AuditLogEntry::new("10.0.0.1", "GET", &uri, "blocked", 403));
+        }
+        let log = state.audit_log.read().unwrap();
+        assert_eq!(log.len(), MAX_AUDIT_LOG_ENTRIES);
+        assert_eq!(log.front().unwrap().uri, "/2");
+        assert_eq!(log.back().unwrap().uri, format!("/{}", MAX_AUDIT_LOG_ENTRIES + 1));
+    }
 }
@@ -12,8 +12,15 @@ fn sha256_hex(data: &[u8]) -> String {
 /// Generate a self-hosted math CAPTCHA HTML page.
 ///
 /// Renders an SVG with a randomized arithmetic problem and an answer form.
-/// On correct submission, sets an HMAC-signed cookie.
-pub fn generate_captcha_page(client_ip: &str, secret: &str, original_path: &str) -> String {
+/// On correct submission, sets an HMAC-signed cookie carrying
+/// `cookie_attributes`, as built by
+/// [`CookieConfig::attributes`](layer7waf_common::CookieConfig::attributes).
+pub fn generate_captcha_page(
+    client_ip: &str,
+    secret: &str,
+    original_path: &str,
+    cookie_attributes: &str,
+) -> String {
     let mut rng = rand::thread_rng();
     let a: u32 = rng.gen_range(2..50);
     let b: u32 = rng.gen_range(2..50);
@@ -119,7 +126,10 @@ pub fn generate_captcha_page(client_ip: &str, secret: &str, original_path: &str)
     html.push_str("  if (!answer) return;\n");
     html.push_str("  var token = document.querySelector('[name=__l7w_captcha_token]').value;\n");
     html.push_str("  var path = document.querySelector('[name=__l7w_captcha_path]').value;\n");
-    html.push_str("  document.cookie = '__l7w_captcha=' + encodeURIComponent(token + ':' + answer) + '; path=/; max-age=1800; SameSite=Strict';\n");
+    html.push_str(&format!(
+        "  document.cookie = '__l7w_captcha=' + encodeURIComponent(token + ':' + answer) + '; max-age=1800{}';\n",
+        cookie_attributes
+    ));
     html.push_str("  window.location.href = path;\n");
     html.push_str("});\n");
     html.push_str("</script>\n");
@@ -128,13 +138,36 @@ pub fn generate_captcha_page(client_ip: &str, secret: &str, original_path: &str)
     html
 }
 
+/// Outcome of checking a CAPTCHA cookie.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CaptchaVerdict {
+    /// Authentic, unexpired token with the correct answer.
+    Valid,
+    /// Authentic, unexpired token for this IP, but the answer is wrong.
+    /// This is the signal counted towards a brute-force lockout.
+    WrongAnswer,
+    /// Malformed, expired, forged, or issued to another IP.
+    Invalid,
+}
+
 /// Verify a CAPTCHA cookie value.
 ///
 /// Cookie format: `ip:timestamp:answer_hash:hmac:user_answer`
 pub fn verify_captcha_cookie(cookie_value: &str, client_ip: &str, secret: &str, ttl_secs: u64) -> bool {
+    check_captcha_cookie(cookie_value, client_ip, secret, ttl_secs) == CaptchaVerdict::Valid
+}
+
+/// Check a CAPTCHA cookie value, distinguishing a wrong answer from an
+/// otherwise invalid token.
+pub fn check_captcha_cookie(
+    cookie_value: &str,
+    client_ip: &str,
+    secret: &str,
+    ttl_secs: u64,
+) -> CaptchaVerdict {
     let parts: Vec<&str> = cookie_value.split(':').collect();
     if parts.len() != 5 {
-        return false;
+        return CaptchaVerdict::Invalid;
     }
 
     let (ip, ts_str, answer_hash, hmac_hex, user_answer) =
@@ -142,37 +175,41 @@ pub fn verify_captcha_cookie(cookie_value: &str, client_ip: &str, secret: &str,
 
     // Verify IP matches
     if ip != client_ip {
-        return false;
+        return CaptchaVerdict::Invalid;
     }
 
     // Verify timestamp not expired
     let ts: u64 = match ts_str.parse() {
         Ok(v) => v,
-        Err(_) => return false,
+        Err(_) => return CaptchaVerdict::Invalid,
     };
     let now = SystemTime::now()
         .duration_since(UNIX_EPOCH)
         .unwrap_or_default()
         .as_secs();
     if now.saturating_sub(ts) > ttl_secs {
-        return false;
+        return CaptchaVerdict::Invalid;
     }
 
     // Verify HMAC
     let mac_input = format!("{ip}:{ts_str}:{answer_hash}");
     let mut mac = match HmacSha256::new_from_slice(secret.as_bytes()) {
         Ok(m) => m,
-        Err(_) => return false,
+        Err(_) => return CaptchaVerdict::Invalid,
     };
     mac.update(mac_input.as_bytes());
     let expected_hmac = hex::encode(mac.finalize().into_bytes());
     if hmac_hex != expected_hmac {
-        return false;
+        return CaptchaVerdict::Invalid;
     }
 
     // Verify user answer matches the hash
     let user_answer_hash = sha256_hex(user_answer.as_bytes());
-    answer_hash == user_answer_hash
+    if answer_hash == user_answer_hash {
+        CaptchaVerdict::Valid
+    } else {
+        CaptchaVerdict::WrongAnswer
+    }
 }
 
 /// Extract the `__l7w_captcha` cookie from a Cookie header value.
@@ -187,6 +224,21 @@ pub fn extract_captcha_cookie(cookie_header: &str) -> Option<String> {
     None
 }
 
+/// Build a signed token with `correct` as the expected answer and `given` as
+/// the user's answer, in the cookie format accepted by [`check_captcha_cookie`].
+#[cfg(test)]
+pub(crate) fn signed_test_token(ip: &str, secret: &str, correct: &str, given: &str) -> String {
+    let ts = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_secs();
+    let answer_hash = sha256_hex(correct.as_bytes());
+    let mut mac = HmacSha256::new_from_slice(secret.as_bytes()).unwrap();
+    mac.update(format!("{ip}:{ts}:{answer_hash}").as_bytes());
+    let hmac_hex = hex::encode(mac.finalize().into_bytes());
+    format!("{ip}:{ts}:{answer_hash}:{hmac_hex}:{given}")
+}
+
 fn urldecode(s: &str) -> String {
     let mut result = String::with_capacity(s.len());
     let mut chars = s.chars();
@@ -214,12 +266,35 @@ mod tests {
 
     #[test]
     fn test_generate_captcha_page_contains_svg() {
-        let html = generate_captcha_page("1.2.3.4", "test-secret", "/test");
+        let html = generate_captcha_page("1.2.3.4", "test-secret", "/test", "; Path=/");
         assert!(html.contains("<svg"));
         assert!(html.contains("__l7w_captcha_token"));
         assert!(html.contains("Verification Required"));
     }
 
+    #[test]
+    fn test_captcha_cookie_attributes() {
+        use layer7waf_common::{CookieConfig, CookieSameSite};
+
+        let default = CookieConfig::default().attributes(CookieSameSite::Strict);
+        let html = generate_captcha_page("1.2.3.4", "test-secret", "/test", &default);
+        assert!(html.contains("'; max-age=1800; Path=/; SameSite=Strict; Secure';"));
+
+        let cookie = CookieConfig {
+            secure: false,
+            same_site: Some(CookieSameSite::Lax),
+            domain: Some("shop.example.com".to_string()),
+            path: "/".to_string(),
+        };
+        let html = generate_captcha_page(
+            "1.2.3.4",
+            "test-secret",
+            "/test",
+            &cookie.attributes(CookieSameSite::Strict),
+        );
+        assert!(html.contains("'; max-age=1800; Path=/; Domain=shop.example.com; SameSite=Lax';"));
+    }
+
     #[test]
     fn test_extract_captcha_cookie() {
         let cookie = "session=abc; __l7w_captcha=some%3Avalue; other=123";
@@ -270,4 +345,25 @@ mod tests {
         let cookie = format!("{ip}:{ts}:{answer_hash}:{hmac_hex}:{answer}");
         assert!(verify_captcha_cookie(&cookie, ip, secret, 3600));
     }
+
+    #[test]
+    fn test_check_captcha_wrong_answer() {
+        let ip = "10.0.0.1";
+        let secret = "test-secret";
+        let ts = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs();
+        let answer_hash = sha256_hex(b"42");
+        let mac_input = format!("{ip}:{ts}:{answer_hash}");
+        let mut mac = HmacSha256::new_from_slice(secret.as_bytes()).unwrap();
+        mac.update(mac_input.as_bytes());
+        let hmac_hex = hex::encode(mac.finalize().into_bytes());
+
+        let wrong = format!("{ip}:{ts}:{answer_hash}:{hmac_hex}:41");
+        assert_eq!(check_captcha_cookie(&wrong, ip, secret, 3600), CaptchaVerdict::WrongAnswer);
+
+        let forged = format!("{ip}:{ts}:{answer_hash}:deadbeef:41");
+        assert_eq!(check_captcha_cookie(&forged, ip, secret, 3600), CaptchaVerdict::Invalid);
+    }
 }
@@ -4,13 +4,16 @@ pub mod obfuscation;
 pub mod session;
 
 use dashmap::DashMap;
-use layer7waf_common::AntiScrapingConfig;
-use std::time::Instant;
-use tracing::{debug, info};
-
-use captcha::{extract_captcha_cookie, verify_captcha_cookie};
+use layer7waf_common::{
+    Admission, AntiScrapingConfig, CookieSameSite, InMemorySessionStore, KeyCapacity,
+    MapFootprint, OverflowAction, SessionStore,
+};
+use std::time::{Duration, Instant, SystemTime};
+use tracing::{debug, info, warn};
+
+use captcha::{check_captcha_cookie, extract_captcha_cookie, CaptchaVerdict};
 use honeypot::{generate_trap_html, inject_trap, is_trap_request};
-use obfuscation::inject_zero_width_chars;
+use obfuscation::{extract_watermark, inject_zero_width_chars, watermark_id};
 use session::ScrapingSession;
 
 /// Maximum body buffer size for response rewriting (2 MB).
@@ -31,21 +34,76 @@ pub enum ScrapingCheckResult {
     TrapTriggered,
 }
 
+/// The client a watermark was last served to.
+struct WatermarkOwner {
+    client_ip: String,
+    last_served: Instant,
+}
+
+/// Why a client's CAPTCHA state blocks its request.
+enum Lockout {
+    /// An earlier lockout hasn't expired.
+    InEffect,
+    /// This request's wrong answer started one.
+    Started,
+}
+
 /// Main anti-scraping engine.
-pub struct AntiScraper {
+///
+/// Sessions live in `S`, the in-process [`InMemorySessionStore`] by default.
+pub struct AntiScraper<S = InMemorySessionStore<ScrapingSession>> {
     config: AntiScrapingConfig,
-    sessions: DashMap<String, ScrapingSession>,
+    sessions: S,
+    /// Watermark (as returned by [`extract_watermark`]) to the client it was
+    /// served to, for identifying the source of scraped content. Kept for
+    /// `obfuscation.watermark_ttl_secs` rather than the session lifetime.
+    watermarks: DashMap<String, WatermarkOwner>,
+    watermark_capacity: KeyCapacity,
+    session_capacity: Option<KeyCapacity>,
 }
 
 impl AntiScraper {
     pub fn new(config: AntiScrapingConfig) -> Self {
+        Self::with_store(config, InMemorySessionStore::new())
+    }
+}
+
+impl<S: SessionStore<ScrapingSession>> AntiScraper<S> {
+    /// Create an AntiScraper keeping its sessions in `sessions`.
+    pub fn with_store(config: AntiScrapingConfig, sessions: S) -> Self {
+        let watermark_capacity = KeyCapacity {
+            max_keys: config.obfuscation.max_watermarks,
+            on_overflow: OverflowAction::EvictOldest,
+        };
         Self {
             config,
-            sessions: DashMap::new(),
+            sessions,
+            watermarks: DashMap::new(),
+            watermark_capacity,
+            session_capacity: None,
         }
     }
 
+    /// Bound the number of tracked sessions.
+    pub fn with_session_capacity(mut self, capacity: KeyCapacity) -> Self {
+        self.session_capacity = Some(capacity);
+        self
+    }
+
+    fn new_session(&self) -> ScrapingSession {
+        ScrapingSession::with_max_tracked_paths(self.config.max_tracked_paths)
+            .with_score_decay(self.config.score_decay_per_min)
+    }
+
+    /// The honeypot trap prefix to use given a route's own prefix, falling
+    /// back to the global `honeypot.trap_path_prefix`.
+    pub fn trap_path_prefix<'a>(&'a self, route_prefix: Option<&'a str>) -> &'a str {
+        route_prefix.unwrap_or(&self.config.honeypot.trap_path_prefix)
+    }
+
     /// Check an incoming request against anti-scraping rules.
+    ///
+    /// `trap_path_prefix` is the matched route's honeypot prefix, if it has one.
     pub fn check_request(
         &self,
         client_ip: &str,
@@ -53,47 +111,93 @@ impl AntiScraper {
         _method: &str,
         cookie_header: Option<&str>,
         bot_score: f64,
+        trap_path_prefix: Option<&str>,
     ) -> ScrapingCheckResult {
         if !self.config.enabled {
             return ScrapingCheckResult::Allow;
         }
 
+        let admission = self
+            .session_capacity
+            .map(|cap| self.sessions.admit(&cap, client_ip, |s| s.last_seen))
+            .unwrap_or(Admission::Admitted);
+
         // Check for honeypot trap
         if self.config.honeypot.enabled
-            && is_trap_request(path, &self.config.honeypot.trap_path_prefix)
+            && is_trap_request(path, self.trap_path_prefix(trap_path_prefix))
         {
             info!(client_ip = %client_ip, path = %path, "honeypot trap triggered");
-            let mut session = self.sessions.entry(client_ip.to_string()).or_insert_with(ScrapingSession::new);
-            session.trap_triggered = true;
-            session.record_request(path, bot_score);
+            if admission == Admission::Admitted {
+                self.sessions.update(client_ip, || self.new_session(), |session| {
+                    session.trap_triggered = true;
+                    session.record_request(path, bot_score, &self.config.signals);
+                });
+            }
             return ScrapingCheckResult::TrapTriggered;
         }
 
-        // Check for valid CAPTCHA cookie
-        let has_valid_captcha = if self.config.captcha.enabled {
-            cookie_header
-                .and_then(extract_captcha_cookie)
-                .map(|cookie| {
-                    verify_captcha_cookie(
-                        &cookie,
-                        client_ip,
-                        &self.config.captcha.secret,
-                        self.config.captcha.ttl_secs,
-                    )
-                })
-                .unwrap_or(false)
+        match admission {
+            Admission::Admitted => {}
+            Admission::RefusedAllow => return ScrapingCheckResult::Allow,
+            Admission::RefusedBlock => return ScrapingCheckResult::Block,
+        }
+
+        // Check the CAPTCHA cookie
+        let captcha = if self.config.captcha.enabled {
+            cookie_header.and_then(extract_captcha_cookie).map(|cookie| {
+                let verdict = check_captcha_cookie(
+                    &cookie,
+                    client_ip,
+                    &self.config.captcha.secret,
+                    self.config.captcha.ttl_secs,
+                );
+                (verdict, cookie)
+            })
         } else {
-            false
+            None
         };
+        let has_valid_captcha = matches!(captcha, Some((CaptchaVerdict::Valid, _)));
 
         // Update session
-        let mut session = self.sessions.entry(client_ip.to_string()).or_insert_with(ScrapingSession::new);
-        if has_valid_captcha {
-            session.captcha_solved = true;
-        }
-        session.record_request(path, bot_score);
-        let score = session.scraping_score;
-        drop(session);
+        let updated = self.sessions.update(client_ip, || self.new_session(), |session| {
+            if session.is_locked_out(SystemTime::now()) {
+                return Err(Lockout::InEffect);
+            }
+            match &captcha {
+                Some((CaptchaVerdict::Valid, _)) => {
+                    session.captcha_solved = true;
+                    session.reset_captcha_failures();
+                }
+                Some((CaptchaVerdict::WrongAnswer, cookie)) => {
+                    let locked = session.record_captcha_failure(
+                        cookie,
+                        self.config.captcha.max_captcha_failures,
+                        Duration::from_secs(self.config.captcha.lockout_secs),
+                    );
+                    if locked {
+                        return Err(Lockout::Started);
+                    }
+                }
+                Some((CaptchaVerdict::Invalid, _)) | None => {}
+            }
+            session.record_request(path, bot_score, &self.config.signals);
+            Ok(session.scraping_score)
+        });
+        let score = match updated {
+            Ok(score) => score,
+            Err(Lockout::InEffect) => {
+                debug!(client_ip = %client_ip, "blocked: CAPTCHA lockout in effect");
+                return ScrapingCheckResult::Block;
+            }
+            Err(Lockout::Started) => {
+                warn!(
+                    client_ip = %client_ip,
+                    lockout_secs = self.config.captcha.lockout_secs,
+                    "too many wrong CAPTCHA answers, locking out"
+                );
+                return ScrapingCheckResult::Block;
+            }
+        };
 
         debug!(client_ip = %client_ip, score, "anti-scraping score");
 
@@ -109,6 +213,7 @@ impl AntiScraper {
                             client_ip,
                             &self.config.captcha.secret,
                             path,
+                            &self.config.captcha.cookie.attributes(CookieSameSite::Strict),
                         );
                         ScrapingCheckResult::Challenge(html)
                     } else {
@@ -129,14 +234,50 @@ impl AntiScraper {
         }
     }
 
+    /// Challenge a client with a CAPTCHA regardless of its scraping score,
+    /// e.g. in place of another filter's JS challenge. Returns `Allow` if the
+    /// client already holds a valid CAPTCHA cookie and `Block` if CAPTCHAs
+    /// are disabled.
+    pub fn captcha(
+        &self,
+        client_ip: &str,
+        path: &str,
+        cookie_header: Option<&str>,
+    ) -> ScrapingCheckResult {
+        if !self.config.captcha.enabled {
+            return ScrapingCheckResult::Block;
+        }
+        let solved = cookie_header.and_then(extract_captcha_cookie).is_some_and(|cookie| {
+            let verdict = check_captcha_cookie(
+                &cookie,
+                client_ip,
+                &self.config.captcha.secret,
+                self.config.captcha.ttl_secs,
+            );
+            verdict == CaptchaVerdict::Valid
+        });
+        if solved {
+            return ScrapingCheckResult::Allow;
+        }
+        let html = captcha::generate_captcha_page(
+            client_ip,
+            &self.config.captcha.secret,
+            path,
+            &self.config.captcha.cookie.attributes(CookieSameSite::Strict),
+        );
+        ScrapingCheckResult::Challenge(html)
+    }
+
     /// Process a response body: inject honeypot traps and/or zero-width watermarks.
     ///
     /// Returns `None` if no modification was needed (non-HTML, too large, etc.).
+    /// Trap links use the route's `trap_path_prefix` when given.
     pub fn process_response(
         &self,
         client_ip: &str,
         content_type: Option<&str>,
         body: &[u8],
+        trap_path_prefix: Option<&str>,
     ) -> Option<Vec<u8>> {
         if !self.config.enabled {
             return None;
@@ -159,7 +300,7 @@ impl AntiScraper {
         // Inject honeypot trap
         if self.config.honeypot.enabled {
             let trap_html = generate_trap_html(
-                &self.config.honeypot.trap_path_prefix,
+                self.trap_path_prefix(trap_path_prefix),
                 client_ip,
                 &self.config.captcha.secret,
             );
@@ -171,7 +312,9 @@ impl AntiScraper {
 
         // Inject zero-width watermarks
         if self.config.obfuscation.enabled {
-            if let Some(with_watermark) = inject_zero_width_chars(&modified, client_ip) {
+            let secret = &self.config.captcha.secret;
+            if let Some(with_watermark) = inject_zero_width_chars(&modified, client_ip, secret) {
+                self.remember_watermark(client_ip, watermark_id(client_ip, secret));
                 modified = with_watermark;
                 was_modified = true;
             }
@@ -184,11 +327,44 @@ impl AntiScraper {
         }
     }
 
-    /// Remove stale session entries older than the given duration.
+    fn remember_watermark(&self, client_ip: &str, watermark: String) {
+        self.watermark_capacity
+            .admit(&self.watermarks, &watermark, |o| o.last_served);
+        self.watermarks.insert(
+            watermark,
+            WatermarkOwner {
+                client_ip: client_ip.to_string(),
+                last_served: Instant::now(),
+            },
+        );
+    }
+
+    /// The client a watermark was served to, given the hex watermark that
+    /// [`extract_watermark`] recovered from scraped content.
+    pub fn watermark_owner(&self, watermark: &str) -> Option<String> {
+        self.watermarks.get(watermark).map(|o| o.client_ip.clone())
+    }
+
+    /// Extract the watermark from scraped `text` and look up who it was
+    /// served to. Returns `None` if `text` carries no watermark, otherwise
+    /// the watermark and its owner if still remembered.
+    pub fn identify(&self, text: &str) -> Option<(String, Option<String>)> {
+        let watermark = extract_watermark(text)?;
+        let owner = self.watermark_owner(&watermark);
+        Some((watermark, owner))
+    }
+
+    /// Remove session entries idle longer than `max_age`, and watermarks
+    /// served longer than `obfuscation.watermark_ttl_secs` ago.
     pub fn cleanup_sessions(&self, max_age: std::time::Duration) {
+        let now = SystemTime::now();
+        self.sessions.retain(|session| {
+            now.duration_since(session.last_seen).unwrap_or_default() < max_age
+        });
+        let ttl = Duration::from_secs(self.config.obfuscation.watermark_ttl_secs);
         let now = Instant::now();
-        self.sessions
-            .retain(|_, session| now.duration_since(session.last_seen) < max_age);
+        self.watermarks
+            .retain(|_, owner| now.duration_since(owner.last_served) < ttl);
     }
 
     /// Return the number of tracked sessions.
@@ -196,12 +372,43 @@ impl AntiScraper {
         self.sessions.len()
     }
 
+    /// Tracked sessions and remembered watermarks, and their approximate
+    /// memory use.
+    pub fn map_footprints(&self) -> Vec<MapFootprint> {
+        vec![
+            MapFootprint::of::<ScrapingSession>("scraping_sessions", self.sessions.len()),
+            MapFootprint::of::<WatermarkOwner>("scraping_watermarks", self.watermarks.len()),
+        ]
+    }
+
+    /// Scraping score of `client_ip`'s session, or `None` if it isn't tracked.
+    pub fn session_score(&self, client_ip: &str) -> Option<f64> {
+        self.sessions.read(client_ip, |s| s.scraping_score)
+    }
+
     /// Return the number of sessions flagged as scrapers.
     pub fn flagged_scraper_count(&self) -> usize {
         self.sessions
-            .iter()
-            .filter(|entry| entry.value().scraping_score >= self.config.score_threshold)
-            .count()
+            .count(|session| session.scraping_score >= self.config.score_threshold)
+    }
+
+    /// Sessions flagged as scrapers, as `(client_ip, scraping_score)`.
+    pub fn flagged_sessions(&self) -> Vec<(String, f64)> {
+        self.sessions.filter_map(|client_ip, session| {
+            (session.scraping_score >= self.config.score_threshold)
+                .then(|| (client_ip.to_string(), session.scraping_score))
+        })
+    }
+
+    /// Forget every session, so all clients are re-evaluated from scratch.
+    /// Returns the number of sessions removed.
+    pub fn clear_sessions(&self) -> usize {
+        self.sessions.clear()
+    }
+
+    /// Forget `client_ip`'s session. Returns whether it was tracked.
+    pub fn clear_session(&self, client_ip: &str) -> bool {
+        self.sessions.remove(client_ip)
     }
 }
 
@@ -220,13 +427,22 @@ mod tests {
                 enabled: true,
                 ttl_secs: 1800,
                 secret: "test-secret".to_string(),
+                max_captcha_failures: 3,
+                lockout_secs: 600,
+                cookie: Default::default(),
             },
             honeypot: HoneypotConfig {
                 enabled: true,
                 trap_path_prefix: "/.well-known/l7w-trap".to_string(),
             },
-            obfuscation: ObfuscationConfig { enabled: true },
+            obfuscation: ObfuscationConfig {
+                enabled: true,
+                ..Default::default()
+            },
             score_threshold: 0.6,
+            max_tracked_paths: 1000,
+            signals: Default::default(),
+            score_decay_per_min: 0.0,
         }
     }
 
@@ -235,7 +451,7 @@ mod tests {
         let mut config = test_config(AntiScrapingMode::Block);
         config.enabled = false;
         let scraper = AntiScraper::new(config);
-        let result = scraper.check_request("1.2.3.4", "/", "GET", None, 1.0);
+        let result = scraper.check_request("1.2.3.4", "/", "GET", None, 1.0, None);
         assert!(matches!(result, ScrapingCheckResult::Allow));
     }
 
@@ -248,14 +464,48 @@ mod tests {
             "GET",
             None,
             0.0,
+            None,
         );
         assert!(matches!(result, ScrapingCheckResult::TrapTriggered));
     }
 
+    #[test]
+    fn test_route_trap_prefix() {
+        let scraper = AntiScraper::new(test_config(AntiScrapingMode::Block));
+
+        // Host A's trap is recognized under A's prefix...
+        let result = scraper.check_request("1.2.3.4", "/a-trap/x", "GET", None, 0.0, Some("/a-trap"));
+        assert!(matches!(result, ScrapingCheckResult::TrapTriggered));
+
+        // ...but not under a route with a different prefix, nor the global one
+        let result = scraper.check_request("5.6.7.8", "/a-trap/x", "GET", None, 0.0, Some("/b-trap"));
+        assert!(matches!(result, ScrapingCheckResult::Allow));
+        let result = scraper.check_request("5.6.7.8", "/a-trap/x", "GET", None, 0.0, None);
+        assert!(matches!(result, ScrapingCheckResult::Allow));
+        let result = scraper.check_request(
+            "5.6.7.8",
+            "/.well-known/l7w-trap/x",
+            "GET",
+            None,
+            0.0,
+            Some("/b-trap"),
+        );
+        assert!(matches!(result, ScrapingCheckResult::Allow));
+
+        // Injected trap links use the route's prefix
+        let body = b"<html><body><p>Hello</p></body></html>";
+        let result = scraper
+            .process_response("1.2.3.4", Some("text/html"), body, Some("/a-trap"))
+            .unwrap();
+        let result = std::str::from_utf8(&result).unwrap();
+        assert!(result.contains("href=\"/a-trap/"));
+        assert!(!result.contains("l7w-trap"));
+    }
+
     #[test]
     fn test_normal_request_allowed() {
         let scraper = AntiScraper::new(test_config(AntiScrapingMode::Block));
-        let result = scraper.check_request("1.2.3.4", "/api/data", "GET", None, 0.0);
+        let result = scraper.check_request("1.2.3.4", "/api/data", "GET", None, 0.0, None);
         assert!(matches!(result, ScrapingCheckResult::Allow));
     }
 
@@ -264,10 +514,10 @@ mod tests {
         let scraper = AntiScraper::new(test_config(AntiScrapingMode::Block));
         // High bot score (1.0) contributes 0.3 to scraping score
         // We need trap triggered or high request rate to exceed threshold
-        let result = scraper.check_request("1.2.3.4", "/.well-known/l7w-trap/x", "GET", None, 0.0);
+        let result = scraper.check_request("1.2.3.4", "/.well-known/l7w-trap/x", "GET", None, 0.0, None);
         assert!(matches!(result, ScrapingCheckResult::TrapTriggered));
         // Now subsequent requests from this IP should be blocked
-        let result = scraper.check_request("1.2.3.4", "/page", "GET", None, 0.0);
+        let result = scraper.check_request("1.2.3.4", "/page", "GET", None, 0.0, None);
         assert!(matches!(result, ScrapingCheckResult::Block));
     }
 
@@ -275,15 +525,15 @@ mod tests {
     fn test_challenge_mode_issues_captcha() {
         let scraper = AntiScraper::new(test_config(AntiScrapingMode::Challenge));
         // Trigger trap first
-        scraper.check_request("1.2.3.4", "/.well-known/l7w-trap/x", "GET", None, 0.0);
-        let result = scraper.check_request("1.2.3.4", "/page", "GET", None, 0.0);
+        scraper.check_request("1.2.3.4", "/.well-known/l7w-trap/x", "GET", None, 0.0, None);
+        let result = scraper.check_request("1.2.3.4", "/page", "GET", None, 0.0, None);
         assert!(matches!(result, ScrapingCheckResult::Challenge(_)));
     }
 
     #[test]
     fn test_detect_mode_returns_score() {
         let scraper = AntiScraper::new(test_config(AntiScrapingMode::Detect));
-        let result = scraper.check_request("1.2.3.4", "/page", "GET", None, 0.5);
+        let result = scraper.check_request("1.2.3.4", "/page", "GET", None, 0.5, None);
         assert!(matches!(result, ScrapingCheckResult::Detect { .. }));
     }
 
@@ -291,7 +541,7 @@ mod tests {
     fn test_process_response_html() {
         let scraper = AntiScraper::new(test_config(AntiScrapingMode::Block));
         let body = b"<html><body><p>Hello</p></body></html>";
-        let result = scraper.process_response("1.2.3.4", Some("text/html"), body);
+        let result = scraper.process_response("1.2.3.4", Some("text/html"), body, None);
         assert!(result.is_some());
         let result_bytes = result.unwrap();
         let result_str = std::str::from_utf8(&result_bytes).unwrap();
@@ -302,7 +552,7 @@ mod tests {
     fn test_process_response_non_html_skipped() {
         let scraper = AntiScraper::new(test_config(AntiScrapingMode::Block));
         let body = b"{'key': 'value'}";
-        let result = scraper.process_response("1.2.3.4", Some("application/json"), body);
+        let result = scraper.process_response("1.2.3.4", Some("application/json"), body, None);
         assert!(result.is_none());
     }
 
@@ -312,24 +562,66 @@ mod tests {
         config.enabled = false;
         let scraper = AntiScraper::new(config);
         let body = b"<html><body><p>Hello</p></body></html>";
-        let result = scraper.process_response("1.2.3.4", Some("text/html"), body);
+        let result = scraper.process_response("1.2.3.4", Some("text/html"), body, None);
         assert!(result.is_none());
     }
 
+    #[test]
+    fn test_watermark_identifies_client() {
+        let scraper = AntiScraper::new(test_config(AntiScrapingMode::Detect));
+        let body = b"<html><body><p>Price list</p></body></html>";
+        let served = scraper
+            .process_response("10.0.0.7", Some("text/html"), body, None)
+            .unwrap();
+        let scraped = String::from_utf8(served).unwrap();
+
+        let (watermark, owner) = scraper.identify(&scraped).unwrap();
+        assert_eq!(watermark, watermark_id("10.0.0.7", "test-secret"));
+        assert_eq!(owner.as_deref(), Some("10.0.0.7"));
+
+        // A watermark this proxy never served has no owner
+        let foreign = obfuscation::inject_zero_width_chars(body, "10.0.0.8", "other-secret")
+            .unwrap();
+        let (_, owner) = scraper.identify(std::str::from_utf8(&foreign).unwrap()).unwrap();
+        assert!(owner.is_none());
+        assert!(scraper.identify("no watermark here").is_none());
+    }
+
+    #[test]
+    fn test_watermarks_kept_past_sessions_and_bounded() {
+        let mut config = test_config(AntiScrapingMode::Detect);
+        config.obfuscation.max_watermarks = 2;
+        let scraper = AntiScraper::new(config);
+        let body = b"<html><body><p>Price list</p></body></html>";
+        let mut served = Vec::new();
+        for ip in ["10.0.0.1", "10.0.0.2", "10.0.0.3"] {
+            let page = scraper.process_response(ip, Some("text/html"), body, None).unwrap();
+            served.push(String::from_utf8(page).unwrap());
+        }
+        // The oldest watermark made room for the newest
+        assert_eq!(scraper.watermarks.len(), 2);
+        assert!(scraper.identify(&served[0]).unwrap().1.is_none());
+        assert_eq!(scraper.identify(&served[2]).unwrap().1.as_deref(), Some("10.0.0.3"));
+
+        // Sessions expiring doesn't forget who was served what
+        scraper.cleanup_sessions(Duration::ZERO);
+        assert_eq!(scraper.identify(&served[2]).unwrap().1.as_deref(), Some("10.0.0.3"));
+    }
+
     #[test]
     fn test_session_tracking() {
         let scraper = AntiScraper::new(test_config(AntiScrapingMode::Detect));
         assert_eq!(scraper.session_count(), 0);
-        scraper.check_request("1.2.3.4", "/page1", "GET", None, 0.0);
+        scraper.check_request("1.2.3.4", "/page1", "GET", None, 0.0, None);
         assert_eq!(scraper.session_count(), 1);
-        scraper.check_request("5.6.7.8", "/page1", "GET", None, 0.0);
+        scraper.check_request("5.6.7.8", "/page1", "GET", None, 0.0, None);
         assert_eq!(scraper.session_count(), 2);
     }
 
     #[test]
     fn test_cleanup_sessions() {
         let scraper = AntiScraper::new(test_config(AntiScrapingMode::Detect));
-        scraper.check_request("1.2.3.4", "/page", "GET", None, 0.0);
+        scraper.check_request("1.2.3.4", "/page", "GET", None, 0.0, None);
         assert_eq!(scraper.session_count(), 1);
         // Cleanup with zero duration should remove all
         scraper.cleanup_sessions(std::time::Duration::from_secs(0));
@@ -340,9 +632,151 @@ mod tests {
     fn test_flagged_scraper_count() {
         let scraper = AntiScraper::new(test_config(AntiScrapingMode::Detect));
         // Trigger trap for one IP
-        scraper.check_request("1.2.3.4", "/.well-known/l7w-trap/x", "GET", None, 0.0);
+        scraper.check_request("1.2.3.4", "/.well-known/l7w-trap/x", "GET", None, 0.0, None);
         // Normal request for another IP
-        scraper.check_request("5.6.7.8", "/page", "GET", None, 0.0);
+        scraper.check_request("5.6.7.8", "/page", "GET", None, 0.0, None);
         assert_eq!(scraper.flagged_scraper_count(), 1);
     }
+
+    #[test]
+    fn test_paging_not_flagged_without_path_diversity() {
+        // Fast paging plus a mild bot score: 0.3 rate + 0.2 paths + 0.12 bot
+        let page_through = |scraper: &AntiScraper| {
+            for i in 0..50 {
+                let path = format!("/catalog?page={}", i);
+                scraper.check_request("1.2.3.4", &path, "GET", None, 0.4, None);
+            }
+            scraper.flagged_scraper_count()
+        };
+        assert_eq!(page_through(&AntiScraper::new(test_config(AntiScrapingMode::Detect))), 1);
+
+        let mut config = test_config(AntiScrapingMode::Detect);
+        config.signals.path_diversity_enabled = false;
+        assert_eq!(page_through(&AntiScraper::new(config)), 0);
+    }
+
+    #[test]
+    fn test_flagged_sessions_listed_and_cleared() {
+        let scraper = AntiScraper::new(test_config(AntiScrapingMode::Detect));
+        scraper.check_request("1.2.3.4", "/.well-known/l7w-trap/x", "GET", None, 0.0, None);
+        scraper.check_request("5.6.7.8", "/page", "GET", None, 0.0, None);
+        scraper.check_request("9.9.9.9", "/.well-known/l7w-trap/y", "GET", None, 0.0, None);
+
+        let mut flagged = scraper.flagged_sessions();
+        flagged.sort_by(|a, b| a.0.cmp(&b.0));
+        let ips: Vec<&str> = flagged.iter().map(|(ip, _)| ip.as_str()).collect();
+        assert_eq!(ips, ["1.2.3.4", "9.9.9.9"]);
+        assert!(flagged.iter().all(|(_, score)| *score >= 0.6));
+
+        assert!(scraper.clear_session("1.2.3.4"));
+        assert!(!scraper.clear_session("1.2.3.4"));
+        assert_eq!(scraper.session_score("1.2.3.4"), None);
+        assert_eq!(scraper.flagged_scraper_count(), 1);
+
+        assert_eq!(scraper.clear_sessions(), 2);
+        assert_eq!(scraper.session_count(), 0);
+    }
+
+    #[test]
+    fn test_map_footprints_match_tracked_keys() {
+        let scraper = AntiScraper::new(test_config(AntiScrapingMode::Detect));
+        for ip in ["10.0.0.1", "10.0.0.2", "10.0.0.3", "10.0.0.1"] {
+            scraper.check_request(ip, "/page", "GET", None, 0.0, None);
+        }
+
+        let maps = scraper.map_footprints();
+        assert_eq!(maps.len(), 2);
+        assert_eq!(maps[0].name, "scraping_sessions");
+        assert_eq!(maps[0].entries, 3);
+        assert_eq!(maps[0].entries, scraper.session_count());
+    }
+
+    #[test]
+    fn test_session_capacity_refuses_new_ips() {
+        use layer7waf_common::{FailurePolicy, KeyOverflowPolicy};
+
+        let cap = KeyCapacity::new(1, KeyOverflowPolicy::Refuse, FailurePolicy::Block);
+        let scraper =
+            AntiScraper::new(test_config(AntiScrapingMode::Block)).with_session_capacity(cap);
+        let first = scraper.check_request("1.2.3.4", "/", "GET", None, 0.0, None);
+        assert!(matches!(first, ScrapingCheckResult::Allow));
+        let overflow = scraper.check_request("5.6.7.8", "/", "GET", None, 0.0, None);
+        assert!(matches!(overflow, ScrapingCheckResult::Block));
+        assert_eq!(scraper.session_count(), 1);
+
+        let cap = KeyCapacity::new(1, KeyOverflowPolicy::Evict, FailurePolicy::Block);
+        let scraper =
+            AntiScraper::new(test_config(AntiScrapingMode::Block)).with_session_capacity(cap);
+        scraper.check_request("1.2.3.4", "/", "GET", None, 0.0, None);
+        let result = scraper.check_request("5.6.7.8", "/", "GET", None, 0.0, None);
+        assert!(matches!(result, ScrapingCheckResult::Allow));
+        assert_eq!(scraper.session_count(), 1);
+    }
+
+    fn captcha_cookie(ip: &str, correct: &str, given: &str) -> String {
+        format!("__l7w_captcha={}", captcha::signed_test_token(ip, "test-secret", correct, given))
+    }
+
+    #[test]
+    fn test_captcha_failures_trigger_lockout() {
+        let scraper = AntiScraper::new(test_config(AntiScrapingMode::Challenge));
+        let ip = "1.2.3.4";
+
+        for i in 0..2 {
+            let cookie = captcha_cookie(ip, &format!("{}", 10 + i), "0");
+            let result = scraper.check_request(ip, "/", "GET", Some(&cookie), 0.0, None);
+            assert!(!matches!(result, ScrapingCheckResult::Block), "attempt {} blocked early", i);
+        }
+
+        // Resending the same wrong cookie doesn't count as a new attempt.
+        let cookie = captcha_cookie(ip, "11", "0");
+        let result = scraper.check_request(ip, "/", "GET", Some(&cookie), 0.0, None);
+        assert!(!matches!(result, ScrapingCheckResult::Block));
+
+        let cookie = captcha_cookie(ip, "12", "0");
+        let result = scraper.check_request(ip, "/", "GET", Some(&cookie), 0.0, None);
+        assert!(matches!(result, ScrapingCheckResult::Block));
+
+        // Locked out even with a correct answer until the cool-down expires.
+        let cookie = captcha_cookie(ip, "13", "13");
+        let result = scraper.check_request(ip, "/", "GET", Some(&cookie), 0.0, None);
+        assert!(matches!(result, ScrapingCheckResult::Block));
+    }
+
+    #[test]
+    fn test_correct_captcha_resets_failures() {
+        let scraper = AntiScraper::new(test_config(AntiScrapingMode::Challenge));
+        let ip = "1.2.3.4";
+
+        for i in 0..2 {
+            let cookie = captcha_cookie(ip, &format!("{}", 10 + i), "0");
+            scraper.check_request(ip, "/", "GET", Some(&cookie), 0.0, None);
+        }
+        assert_eq!(scraper.sessions.read(ip, |s| s.captcha_failures), Some(2));
+
+        let cookie = captcha_cookie(ip, "20", "20");
+        scraper.check_request(ip, "/", "GET", Some(&cookie), 0.0, None);
+        assert_eq!(scraper.sessions.read(ip, |s| s.captcha_failures), Some(0));
+
+        let cookie = captcha_cookie(ip, "21", "0");
+        let result = scraper.check_request(ip, "/", "GET", Some(&cookie), 0.0, None);
+        assert!(!matches!(result, ScrapingCheckResult::Block));
+    }
+
+    #[test]
+    fn test_captcha_on_demand() {
+        let ip = "1.2.3.4";
+        let scraper = AntiScraper::new(test_config(AntiScrapingMode::Detect));
+        assert!(matches!(scraper.captcha(ip, "/", None), ScrapingCheckResult::Challenge(_)));
+        let wrong = captcha_cookie(ip, "30", "0");
+        let result = scraper.captcha(ip, "/", Some(&wrong));
+        assert!(matches!(result, ScrapingCheckResult::Challenge(_)));
+        let solved = captcha_cookie(ip, "30", "30");
+        assert!(matches!(scraper.captcha(ip, "/", Some(&solved)), ScrapingCheckResult::Allow));
+
+        let mut config = test_config(AntiScrapingMode::Detect);
+        config.captcha.enabled = false;
+        let scraper = AntiScraper::new(config);
+        assert!(matches!(scraper.captcha(ip, "/", None), ScrapingCheckResult::Block));
+    }
 }
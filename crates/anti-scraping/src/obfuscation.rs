@@ -1,20 +1,29 @@
-use sha2::Digest;
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+
+type HmacSha256 = Hmac<Sha256>;
 
 // Zero-width characters used for watermarking
 const ZWC_ZERO: char = '\u{200B}'; // ZERO WIDTH SPACE  → bit 0
 const ZWC_ONE: char = '\u{200C}';  // ZERO WIDTH NON-JOINER → bit 1
 
+/// Bytes of the keyed hash a watermark carries. 128 bits keeps two clients'
+/// watermarks from colliding however many are remembered.
+const WATERMARK_BYTES: usize = 16;
+const WATERMARK_BITS: usize = WATERMARK_BYTES * 8;
+
 /// Inject zero-width character watermarks into HTML text content.
 ///
 /// Inserts invisible Unicode characters between `>` and `<` text nodes,
-/// seeded by client IP for forensic identification of scraping source.
+/// seeded by client IP and keyed with `secret` for forensic identification
+/// of scraping source.
 ///
 /// Returns `None` if the body is not valid UTF-8 or has no suitable text nodes.
-pub fn inject_zero_width_chars(body: &[u8], client_ip: &str) -> Option<Vec<u8>> {
+pub fn inject_zero_width_chars(body: &[u8], client_ip: &str, secret: &str) -> Option<Vec<u8>> {
     let body_str = std::str::from_utf8(body).ok()?;
 
-    // Generate watermark bits from IP hash
-    let watermark = generate_watermark(client_ip);
+    // Generate watermark bits from the keyed IP hash
+    let watermark = generate_watermark(client_ip, secret);
 
     let mut result = String::with_capacity(body_str.len() + watermark.len() * 10);
     let mut injected = false;
@@ -50,14 +59,33 @@ pub fn inject_zero_width_chars(body: &[u8], client_ip: &str) -> Option<Vec<u8>>
     Some(result.into_bytes())
 }
 
+/// First [`WATERMARK_BYTES`] bytes of the HMAC-SHA256 of `client_ip` under
+/// `secret`.
+///
+/// Keying the hash means a watermark can't be forged for, or matched to, an
+/// IP without the secret.
+fn keyed_hash(client_ip: &str, secret: &str) -> [u8; WATERMARK_BYTES] {
+    let mut mac = HmacSha256::new_from_slice(secret.as_bytes()).expect("HMAC key");
+    mac.update(client_ip.as_bytes());
+    let hash = mac.finalize().into_bytes();
+    let mut tag = [0u8; WATERMARK_BYTES];
+    tag.copy_from_slice(&hash[..WATERMARK_BYTES]);
+    tag
+}
+
+/// The hex watermark [`extract_watermark`] returns from content served to
+/// `client_ip`.
+pub fn watermark_id(client_ip: &str, secret: &str) -> String {
+    hex::encode(keyed_hash(client_ip, secret))
+}
+
 /// Generate a watermark string from a client IP.
 ///
-/// The watermark encodes a hash of the IP as a sequence of zero-width characters.
-fn generate_watermark(client_ip: &str) -> String {
-    let hash = sha2::Sha256::digest(client_ip.as_bytes());
-    // Use first 4 bytes (32 bits) for the watermark
+/// The watermark encodes a keyed hash of the IP as a sequence of zero-width
+/// characters.
+fn generate_watermark(client_ip: &str, secret: &str) -> String {
     let mut watermark = String::new();
-    for &byte in &hash[..4] {
+    for byte in keyed_hash(client_ip, secret) {
         for bit in (0..8).rev() {
             if (byte >> bit) & 1 == 1 {
                 watermark.push(ZWC_ONE);
@@ -80,14 +108,14 @@ pub fn extract_watermark(text: &str) -> Option<String> {
             c if c == ZWC_ZERO => bits.push(false),
             c if c == ZWC_ONE => bits.push(true),
             _ => {
-                if bits.len() >= 32 {
+                if bits.len() >= WATERMARK_BITS {
                     break;
                 }
             }
         }
     }
 
-    if bits.len() < 32 {
+    if bits.len() < WATERMARK_BITS {
         return None;
     }
 
@@ -105,42 +133,50 @@ pub fn extract_watermark(text: &str) -> Option<String> {
         }
     }
 
-    Some(hex::encode(&bytes[..4.min(bytes.len())]))
+    Some(hex::encode(&bytes[..WATERMARK_BYTES]))
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
 
+    use sha2::Digest;
+
     #[test]
     fn test_generate_watermark_consistent() {
-        let wm1 = generate_watermark("1.2.3.4");
-        let wm2 = generate_watermark("1.2.3.4");
+        let wm1 = generate_watermark("1.2.3.4", "secret");
+        let wm2 = generate_watermark("1.2.3.4", "secret");
         assert_eq!(wm1, wm2);
-        assert_eq!(wm1.chars().count(), 32); // 32 zero-width chars
+        assert_eq!(wm1.chars().count(), WATERMARK_BITS); // one zero-width char per bit
     }
 
     #[test]
     fn test_generate_watermark_different_ips() {
-        let wm1 = generate_watermark("1.2.3.4");
-        let wm2 = generate_watermark("5.6.7.8");
+        let wm1 = generate_watermark("1.2.3.4", "secret");
+        let wm2 = generate_watermark("5.6.7.8", "secret");
         assert_ne!(wm1, wm2);
     }
 
     #[test]
     fn test_extract_watermark_roundtrip() {
-        let wm = generate_watermark("10.0.0.1");
+        let wm = generate_watermark("10.0.0.1", "secret");
         let extracted = extract_watermark(&wm).unwrap();
-        // Verify it matches the first 4 bytes of the SHA256 hash
-        let hash = sha2::Sha256::digest(b"10.0.0.1");
-        let expected = hex::encode(&hash[..4]);
-        assert_eq!(extracted, expected);
+        assert_eq!(extracted, watermark_id("10.0.0.1", "secret"));
+    }
+
+    #[test]
+    fn test_keyed_watermark_differs_from_plain_hash() {
+        let plain = hex::encode(&Sha256::digest(b"10.0.0.1")[..WATERMARK_BYTES]);
+        let keyed = watermark_id("10.0.0.1", "secret");
+        assert_ne!(keyed, plain);
+        // Without the secret the IP's watermark can't be reproduced
+        assert_ne!(keyed, watermark_id("10.0.0.1", "other-secret"));
     }
 
     #[test]
     fn test_inject_zero_width_chars() {
         let body = b"<html><body><p>Hello world</p></body></html>";
-        let result = inject_zero_width_chars(body, "1.2.3.4");
+        let result = inject_zero_width_chars(body, "1.2.3.4", "secret");
         assert!(result.is_some());
         let result_bytes = result.unwrap();
         let result_str = std::str::from_utf8(&result_bytes).unwrap();
@@ -155,7 +191,7 @@ mod tests {
     #[test]
     fn test_inject_no_text_nodes() {
         let body = b"<html><body><br><br></body></html>";
-        let result = inject_zero_width_chars(body, "1.2.3.4");
+        let result = inject_zero_width_chars(body, "1.2.3.4", "secret");
         assert!(result.is_none());
     }
 
@@ -163,5 +199,9 @@ mod tests {
     fn test_extract_watermark_too_short() {
         let text = "\u{200B}\u{200C}";
         assert!(extract_watermark(text).is_none());
+
+        // The old 32-bit tags no longer identify anyone
+        let short: String = generate_watermark("10.0.0.1", "secret").chars().take(32).collect();
+        assert!(extract_watermark(&short).is_none());
     }
 }
@@ -1,74 +1,229 @@
 use std::collections::HashSet;
 use std::hash::{Hash, Hasher};
-use std::time::Instant;
+use std::time::{Duration, SystemTime};
+
+use layer7waf_common::ScrapingSignals;
+use serde::{Deserialize, Serialize};
+
+/// Default cap on the distinct path hashes remembered per session.
+pub const DEFAULT_MAX_TRACKED_PATHS: usize = 1000;
+
+/// Number of HyperLogLog registers; 256 gives roughly 6.5% standard error.
+const HLL_REGISTERS: usize = 256;
+const HLL_INDEX_BITS: u32 = 8;
+
+/// Requests closer together than this are too fast to count as good
+/// behavior, matching the one-request-per-second rate signal.
+const MIN_GOOD_REQUEST_GAP: Duration = Duration::from_secs(1);
 
 /// Per-IP session tracking for scraping detection.
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct ScrapingSession {
-    pub first_seen: Instant,
-    pub last_seen: Instant,
+    pub first_seen: SystemTime,
+    pub last_seen: SystemTime,
     pub request_count: u64,
+    /// Distinct paths visited; exact up to `max_tracked_paths`, estimated beyond.
     pub unique_path_count: u64,
     path_hashes: HashSet<u64>,
+    max_tracked_paths: usize,
+    /// Takes over counting once `path_hashes` is full.
+    path_estimator: Option<Box<PathEstimator>>,
     pub trap_triggered: bool,
     pub captcha_solved: bool,
     pub scraping_score: f64,
+    /// Score from the signals alone, before decay.
+    raw_score: f64,
+    /// Score lost per minute of good behavior; 0.0 disables decay.
+    score_decay_per_min: f64,
+    /// Last request that wasn't good behavior: unsolved CAPTCHA, too fast,
+    /// or a rise in the raw score. Decay runs from here.
+    last_high_score: SystemTime,
+    /// Consecutive wrong CAPTCHA answers.
+    pub captcha_failures: u32,
+    /// Hash of the last wrong-answer cookie, so a resent cookie counts once.
+    last_failed_captcha: Option<u64>,
+    /// Hard block after too many wrong CAPTCHA answers.
+    pub locked_until: Option<SystemTime>,
 }
 
 impl ScrapingSession {
     pub fn new() -> Self {
-        let now = Instant::now();
+        Self::with_max_tracked_paths(DEFAULT_MAX_TRACKED_PATHS)
+    }
+
+    /// Create a session remembering at most `max_tracked_paths` distinct
+    /// paths; further unique paths are still counted, approximately.
+    pub fn with_max_tracked_paths(max_tracked_paths: usize) -> Self {
+        let now = SystemTime::now();
         Self {
             first_seen: now,
             last_seen: now,
             request_count: 0,
             unique_path_count: 0,
             path_hashes: HashSet::new(),
+            max_tracked_paths,
+            path_estimator: None,
             trap_triggered: false,
             captcha_solved: false,
             scraping_score: 0.0,
+            raw_score: 0.0,
+            score_decay_per_min: 0.0,
+            last_high_score: now,
+            captcha_failures: 0,
+            last_failed_captcha: None,
+            locked_until: None,
         }
     }
 
-    /// Record a new request and recalculate the scraping score.
-    pub fn record_request(&mut self, path: &str, bot_score: f64) {
+    /// Let the score decay by `per_min` for every minute the client behaves
+    /// well: it has solved a CAPTCHA, sends at most one request a second and
+    /// triggers no new signals. A client that was briefly aggressive is
+    /// eventually un-flagged this way.
+    pub fn with_score_decay(mut self, per_min: f64) -> Self {
+        self.score_decay_per_min = per_min;
+        self
+    }
+
+    /// Whether the session is currently locked out after CAPTCHA failures.
+    /// An expired lockout is cleared.
+    pub fn is_locked_out(&mut self, now: SystemTime) -> bool {
+        match self.locked_until {
+            Some(until) if now < until => true,
+            Some(_) => {
+                self.locked_until = None;
+                false
+            }
+            None => false,
+        }
+    }
+
+    /// Count a wrong CAPTCHA answer carried by `cookie`. Once `max_failures`
+    /// distinct wrong answers accumulate, the session is locked out for
+    /// `lockout` and `true` is returned. `max_failures == 0` disables lockout.
+    pub fn record_captcha_failure(
+        &mut self,
+        cookie: &str,
+        max_failures: u32,
+        lockout: std::time::Duration,
+    ) -> bool {
+        let mut hasher = std::collections::hash_map::DefaultHasher::new();
+        cookie.hash(&mut hasher);
+        let cookie_hash = hasher.finish();
+        if self.last_failed_captcha == Some(cookie_hash) {
+            return false;
+        }
+        self.last_failed_captcha = Some(cookie_hash);
+        self.captcha_failures += 1;
+
+        if max_failures > 0 && self.captcha_failures >= max_failures {
+            self.locked_until = Some(SystemTime::now() + lockout);
+            self.captcha_failures = 0;
+            return true;
+        }
+        false
+    }
+
+    /// Clear CAPTCHA failure accounting after a correct answer.
+    pub fn reset_captcha_failures(&mut self) {
+        self.captcha_failures = 0;
+        self.last_failed_captcha = None;
+    }
+
+    /// Record a new request and recalculate the scraping score from the
+    /// enabled `signals`.
+    pub fn record_request(&mut self, path: &str, bot_score: f64, signals: &ScrapingSignals) {
+        self.record_request_at(path, bot_score, signals, SystemTime::now());
+    }
+
+    /// [`record_request`](Self::record_request) for a request made at `now`.
+    pub fn record_request_at(
+        &mut self,
+        path: &str,
+        bot_score: f64,
+        signals: &ScrapingSignals,
+        now: SystemTime,
+    ) {
+        let gap = now.duration_since(self.last_seen).unwrap_or_default();
+        let too_fast = self.request_count > 0 && gap < MIN_GOOD_REQUEST_GAP;
         self.request_count += 1;
-        self.last_seen = Instant::now();
+        self.last_seen = now;
 
         let mut hasher = std::collections::hash_map::DefaultHasher::new();
         path.hash(&mut hasher);
         let path_hash = hasher.finish();
-        if self.path_hashes.insert(path_hash) {
+        self.record_path_hash(path_hash);
+
+        let raw_score = self.compute_score(bot_score, signals);
+        if !self.captcha_solved || too_fast || raw_score > self.raw_score {
+            self.last_high_score = now;
+        }
+        self.raw_score = raw_score;
+
+        let good_for = now.duration_since(self.last_high_score).unwrap_or_default();
+        let decay = self.score_decay_per_min * good_for.as_secs_f64() / 60.0;
+        self.scraping_score = (raw_score - decay).max(0.0);
+    }
+
+    /// Number of path hashes held in memory, at most `max_tracked_paths`.
+    pub fn tracked_path_count(&self) -> usize {
+        self.path_hashes.len()
+    }
+
+    fn record_path_hash(&mut self, path_hash: u64) {
+        if self.path_hashes.contains(&path_hash) {
+            return;
+        }
+        if self.path_hashes.len() < self.max_tracked_paths {
+            self.path_hashes.insert(path_hash);
             self.unique_path_count += 1;
+            return;
         }
 
-        self.scraping_score = self.compute_score(bot_score);
+        // The set is full: count through the estimator, seeded with every
+        // path seen so far so its estimate covers the whole session.
+        let path_hashes = &self.path_hashes;
+        let estimator = self.path_estimator.get_or_insert_with(|| {
+            let mut estimator = Box::new(PathEstimator::new());
+            for &hash in path_hashes {
+                estimator.insert(hash);
+            }
+            estimator
+        });
+        estimator.insert(path_hash);
+        // Never let the estimate's error move the count backwards
+        self.unique_path_count = self.unique_path_count.max(estimator.estimate());
     }
 
-    fn compute_score(&self, bot_score: f64) -> f64 {
+    fn compute_score(&self, bot_score: f64, signals: &ScrapingSignals) -> f64 {
         let mut score = 0.0;
 
         // Trap triggered is a strong signal
-        if self.trap_triggered {
-            score += 1.0;
+        if signals.trap_enabled && self.trap_triggered {
+            score += signals.trap_weight;
         }
 
         // High request rate (more than 60 requests per minute)
-        let elapsed = self.last_seen.duration_since(self.first_seen).as_secs_f64();
-        if elapsed > 0.0 {
+        let elapsed = self
+            .last_seen
+            .duration_since(self.first_seen)
+            .unwrap_or_default()
+            .as_secs_f64();
+        if signals.request_rate_enabled && elapsed > 0.0 {
             let rps = self.request_count as f64 / elapsed;
             if rps > 1.0 {
-                score += 0.3;
+                score += signals.request_rate_weight;
             }
         }
 
         // High unique path count (crawling many pages)
-        if self.unique_path_count > 20 {
-            score += 0.2;
+        if signals.path_diversity_enabled && self.unique_path_count > 20 {
+            score += signals.path_diversity_weight;
         }
 
         // Factor in bot detection score
-        score += bot_score * 0.3;
+        if signals.bot_score_enabled {
+            score += bot_score * signals.bot_score_weight;
+        }
 
         // CAPTCHA solved reduces score
         if self.captcha_solved {
@@ -79,6 +234,42 @@ impl ScrapingSession {
     }
 }
 
+/// A HyperLogLog cardinality estimator over path hashes.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct PathEstimator {
+    registers: Vec<u8>,
+}
+
+impl PathEstimator {
+    fn new() -> Self {
+        Self {
+            registers: vec![0; HLL_REGISTERS],
+        }
+    }
+
+    fn insert(&mut self, hash: u64) {
+        let index = (hash >> (64 - HLL_INDEX_BITS)) as usize;
+        let rank = ((hash << HLL_INDEX_BITS).leading_zeros() + 1).min(64 - HLL_INDEX_BITS + 1) as u8;
+        self.registers[index] = self.registers[index].max(rank);
+    }
+
+    fn estimate(&self) -> u64 {
+        let m = HLL_REGISTERS as f64;
+        let alpha = 0.7213 / (1.0 + 1.079 / m);
+        let sum: f64 = self.registers.iter().map(|&r| 2f64.powi(-i32::from(r))).sum();
+        let raw = alpha * m * m / sum;
+
+        let zeros = self.registers.iter().filter(|&&r| r == 0).count();
+        let estimate = if raw <= 2.5 * m && zeros > 0 {
+            // Linear counting is more accurate for small cardinalities
+            m * (m / zeros as f64).ln()
+        } else {
+            raw
+        };
+        estimate.round() as u64
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -93,7 +284,7 @@ mod tests {
     #[test]
     fn test_record_request_increments_count() {
         let mut session = ScrapingSession::new();
-        session.record_request("/page1", 0.0);
+        session.record_request("/page1", 0.0, &ScrapingSignals::default());
         assert_eq!(session.request_count, 1);
         assert_eq!(session.unique_path_count, 1);
     }
@@ -101,8 +292,8 @@ mod tests {
     #[test]
     fn test_duplicate_paths_not_counted() {
         let mut session = ScrapingSession::new();
-        session.record_request("/page1", 0.0);
-        session.record_request("/page1", 0.0);
+        session.record_request("/page1", 0.0, &ScrapingSignals::default());
+        session.record_request("/page1", 0.0, &ScrapingSignals::default());
         assert_eq!(session.request_count, 2);
         assert_eq!(session.unique_path_count, 1);
     }
@@ -111,7 +302,7 @@ mod tests {
     fn test_trap_triggered_raises_score() {
         let mut session = ScrapingSession::new();
         session.trap_triggered = true;
-        session.record_request("/trap", 0.0);
+        session.record_request("/trap", 0.0, &ScrapingSignals::default());
         assert!(session.scraping_score >= 1.0);
     }
 
@@ -119,7 +310,7 @@ mod tests {
     fn test_captcha_solved_reduces_score() {
         let mut session = ScrapingSession::new();
         session.captcha_solved = true;
-        session.record_request("/page", 0.5);
+        session.record_request("/page", 0.5, &ScrapingSignals::default());
         // bot_score * 0.3 = 0.15, captcha -0.5 → clamped to 0.0
         assert!(session.scraping_score < 0.2);
     }
@@ -127,8 +318,119 @@ mod tests {
     #[test]
     fn test_bot_score_contributes() {
         let mut session = ScrapingSession::new();
-        session.record_request("/page", 1.0);
+        session.record_request("/page", 1.0, &ScrapingSignals::default());
         // bot_score * 0.3 = 0.3
         assert!(session.scraping_score >= 0.3);
     }
+
+    #[test]
+    fn test_tracked_paths_bounded() {
+        let mut session = ScrapingSession::with_max_tracked_paths(100);
+        let mut last_count = 0;
+        for i in 0..5000 {
+            session.record_request(&format!("/page/{}", i), 0.0, &ScrapingSignals::default());
+            assert!(session.unique_path_count >= last_count);
+            last_count = session.unique_path_count;
+        }
+        assert_eq!(session.tracked_path_count(), 100);
+        // Within the estimator's error of the true 5000
+        assert!((4000..6000).contains(&session.unique_path_count));
+
+        // Revisiting a remembered path never counts as new
+        session.record_request("/page/0", 0.0, &ScrapingSignals::default());
+        assert_eq!(session.unique_path_count, last_count);
+    }
+
+    #[test]
+    fn test_path_diversity_scored_past_cap() {
+        let mut session = ScrapingSession::with_max_tracked_paths(10);
+        for i in 0..50 {
+            session.record_request(&format!("/item/{}", i), 0.0, &ScrapingSignals::default());
+        }
+        assert_eq!(session.tracked_path_count(), 10);
+        assert!(session.unique_path_count > 20);
+        assert!(session.scraping_score >= 0.2);
+    }
+
+    /// A session flagged by a trap hit in a burst with a high bot score,
+    /// which then solves the CAPTCHA and browses a page every 30 seconds.
+    fn reformed_session(decay_per_min: f64, minutes: u64) -> ScrapingSession {
+        let signals = ScrapingSignals::default();
+        let start = SystemTime::now();
+        let mut session = ScrapingSession::new().with_score_decay(decay_per_min);
+        session.first_seen = start;
+        session.last_seen = start;
+        session.trap_triggered = true;
+        for i in 0..10 {
+            let at = start + Duration::from_millis(i * 100);
+            session.record_request_at("/trap", 1.0, &signals, at);
+        }
+        assert_eq!(session.scraping_score, 1.0);
+
+        session.captcha_solved = true;
+        for i in 1..=minutes * 2 {
+            let at = start + Duration::from_secs(1 + i * 30);
+            session.record_request_at("/page", 1.0, &signals, at);
+        }
+        session
+    }
+
+    #[test]
+    fn test_good_behavior_decays_score() {
+        // Trap 1.0 + bot 0.3 - CAPTCHA 0.5 keeps the raw score at 0.8
+        let session = reformed_session(0.0, 20);
+        assert!(session.scraping_score >= 0.6);
+
+        let mut session = reformed_session(0.1, 20);
+        assert!(session.scraping_score < 0.6);
+        assert_eq!(session.scraping_score, 0.0);
+
+        // Back to bursting: the decay starts over
+        let at = session.last_seen + Duration::from_millis(100);
+        session.record_request_at("/page", 1.0, &ScrapingSignals::default(), at);
+        assert!(session.scraping_score >= 0.6);
+    }
+
+    #[test]
+    fn test_no_decay_without_captcha() {
+        let signals = ScrapingSignals::default();
+        let start = SystemTime::now();
+        let mut session = ScrapingSession::new().with_score_decay(1.0);
+        session.first_seen = start;
+        session.trap_triggered = true;
+        for i in 0..20 {
+            session.record_request_at("/page", 0.0, &signals, start + Duration::from_secs(i * 60));
+        }
+        assert_eq!(session.scraping_score, 1.0);
+    }
+
+    #[test]
+    fn test_disabled_path_diversity_not_scored() {
+        // Only the path count would fire; the rate signal is left out
+        let signals = ScrapingSignals {
+            path_diversity_enabled: false,
+            request_rate_enabled: false,
+            ..Default::default()
+        };
+        let mut session = ScrapingSession::new();
+        for i in 0..50 {
+            session.record_request(&format!("/list?page={}", i), 0.0, &signals);
+        }
+        assert!(session.unique_path_count > 20);
+        assert_eq!(session.scraping_score, 0.0);
+    }
+
+    #[test]
+    fn test_signal_weights_applied() {
+        let signals = ScrapingSignals {
+            trap_weight: 0.5,
+            request_rate_enabled: false,
+            bot_score_weight: 0.1,
+            ..Default::default()
+        };
+        let mut session = ScrapingSession::new();
+        session.trap_triggered = true;
+        session.record_request("/trap", 1.0, &signals);
+        assert!((session.scraping_score - 0.6).abs() < 1e-9);
+    }
 }
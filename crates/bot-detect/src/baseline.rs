@@ -0,0 +1,220 @@
+use dashmap::DashMap;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeSet;
+use std::path::Path;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::OnceLock;
+
+use crate::fingerprint::HttpFingerprint;
+
+/// Score added to a request whose header-order fingerprint is not in the
+/// sealed baseline.
+pub const UNKNOWN_FINGERPRINT_SCORE: f64 = 0.2;
+
+/// Score added to a request whose UA family is not in the sealed baseline.
+/// Together with an unknown fingerprint this puts the request well outside
+/// normal traffic.
+pub const UNKNOWN_UA_FAMILY_SCORE: f64 = 0.2;
+
+/// Distinct fingerprints (and, separately, UA families) counted while
+/// learning. New ones beyond this are not counted; they would be too rare
+/// to make the baseline anyway.
+const MAX_OBSERVED_ENTRIES: usize = 10_000;
+
+/// The set of fingerprints considered normal once learning is sealed.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct BaselineSet {
+    pub fingerprints: BTreeSet<String>,
+    pub ua_families: BTreeSet<String>,
+    /// Requests observed while learning.
+    pub observed: u64,
+}
+
+impl BaselineSet {
+    /// Extra bot score for `fp`: zero when both its header order and UA
+    /// family were seen while learning.
+    pub fn anomaly_score(&self, fp: &HttpFingerprint) -> f64 {
+        let mut score = 0.0;
+        if !self.fingerprints.contains(&fp.header_order_hash) {
+            score += UNKNOWN_FINGERPRINT_SCORE;
+        }
+        if !self.ua_families.contains(&fp.ua_family) {
+            score += UNKNOWN_UA_FAMILY_SCORE;
+        }
+        score
+    }
+
+    /// Read a baseline previously written by [`BaselineSet::save`].
+    pub fn load(path: &Path) -> anyhow::Result<Self> {
+        let data = std::fs::read_to_string(path)?;
+        Ok(serde_json::from_str(&data)?)
+    }
+
+    /// Write the baseline to `path` as JSON, replacing the file atomically.
+    pub fn save(&self, path: &Path) -> anyhow::Result<()> {
+        let tmp = path.with_extension("tmp");
+        std::fs::write(&tmp, serde_json::to_vec_pretty(self)?)?;
+        std::fs::rename(&tmp, path)?;
+        Ok(())
+    }
+}
+
+/// Learned baseline of normal header-order fingerprints and UA families.
+///
+/// While learning, every request's fingerprint is counted. Sealing keeps
+/// those that made up at least a minimum share of traffic; from then on
+/// [`LearnedBaseline::anomaly_score`] scores anything outside that set.
+pub struct LearnedBaseline {
+    fingerprints: DashMap<String, u64>,
+    ua_families: DashMap<String, u64>,
+    observed: AtomicU64,
+    sealed: OnceLock<BaselineSet>,
+}
+
+impl LearnedBaseline {
+    /// Start a baseline in learning mode.
+    pub fn learning() -> Self {
+        Self {
+            fingerprints: DashMap::new(),
+            ua_families: DashMap::new(),
+            observed: AtomicU64::new(0),
+            sealed: OnceLock::new(),
+        }
+    }
+
+    /// A baseline that is already sealed with `set`, e.g. one loaded from disk.
+    pub fn sealed(set: BaselineSet) -> Self {
+        let baseline = Self::learning();
+        let _ = baseline.sealed.set(set);
+        baseline
+    }
+
+    pub fn is_learning(&self) -> bool {
+        self.sealed.get().is_none()
+    }
+
+    /// Count `fp` towards the baseline. Does nothing once sealed.
+    pub fn observe(&self, fp: &HttpFingerprint) {
+        if !self.is_learning() {
+            return;
+        }
+        self.observed.fetch_add(1, Ordering::Relaxed);
+        count(&self.fingerprints, &fp.header_order_hash);
+        count(&self.ua_families, &fp.ua_family);
+    }
+
+    /// Stop learning, keeping fingerprints and UA families that made up at
+    /// least `min_share` of the observed requests. Returns the sealed set,
+    /// or `None` if the baseline was already sealed.
+    pub fn seal(&self, min_share: f64) -> Option<&BaselineSet> {
+        let mut sealed_now = false;
+        let set = self.sealed.get_or_init(|| {
+            sealed_now = true;
+            let observed = self.observed.load(Ordering::Relaxed);
+            let min_count = ((observed as f64 * min_share).ceil() as u64).max(1);
+            let common = |map: &DashMap<String, u64>| {
+                map.iter()
+                    .filter(|e| *e.value() >= min_count)
+                    .map(|e| e.key().clone())
+                    .collect()
+            };
+            BaselineSet {
+                fingerprints: common(&self.fingerprints),
+                ua_families: common(&self.ua_families),
+                observed,
+            }
+        });
+        sealed_now.then_some(set)
+    }
+
+    /// The sealed baseline, if learning has finished.
+    pub fn sealed_set(&self) -> Option<&BaselineSet> {
+        self.sealed.get()
+    }
+
+    /// Extra bot score for `fp`; always zero while learning.
+    pub fn anomaly_score(&self, fp: &HttpFingerprint) -> f64 {
+        self.sealed.get().map(|set| set.anomaly_score(fp)).unwrap_or(0.0)
+    }
+}
+
+fn count(map: &DashMap<String, u64>, key: &str) {
+    if let Some(mut count) = map.get_mut(key) {
+        *count += 1;
+    } else if map.len() < MAX_OBSERVED_ENTRIES {
+        *map.entry(key.to_string()).or_insert(0) += 1;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn fp(order: &str, family: &str) -> HttpFingerprint {
+        HttpFingerprint {
+            header_order_hash: order.into(),
+            ua_family: family.into(),
+            accept_hash: "accept".into(),
+            header_values_hash: String::new(),
+            header_names: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn test_seal_keeps_common_fingerprints() {
+        let baseline = LearnedBaseline::learning();
+        for _ in 0..999 {
+            baseline.observe(&fp("chrome-order", "Chrome"));
+        }
+        baseline.observe(&fp("odd-order", "Other"));
+
+        let set = baseline.seal(0.01).unwrap();
+        assert!(!baseline.is_learning());
+        assert_eq!(set.observed, 1000);
+        assert!(set.fingerprints.contains("chrome-order"));
+        assert!(!set.fingerprints.contains("odd-order"));
+        assert!(!set.ua_families.contains("Other"));
+
+        // Nothing is learned after sealing
+        for _ in 0..1000 {
+            baseline.observe(&fp("late-order", "Late"));
+        }
+        assert!(baseline.seal(0.0).is_none());
+        assert!(!baseline.sealed_set().unwrap().fingerprints.contains("late-order"));
+    }
+
+    #[test]
+    fn test_never_seen_fingerprint_scores_higher() {
+        let baseline = LearnedBaseline::learning();
+        for _ in 0..100 {
+            baseline.observe(&fp("chrome-order", "Chrome"));
+        }
+        assert_eq!(baseline.anomaly_score(&fp("new-order", "New")), 0.0);
+
+        baseline.seal(0.01);
+        let known = baseline.anomaly_score(&fp("chrome-order", "Chrome"));
+        let new_order = baseline.anomaly_score(&fp("new-order", "Chrome"));
+        let unseen = baseline.anomaly_score(&fp("new-order", "New"));
+        assert_eq!(known, 0.0);
+        assert!(new_order > known);
+        assert!(unseen > new_order);
+    }
+
+    #[test]
+    fn test_baseline_persists() {
+        let baseline = LearnedBaseline::learning();
+        baseline.observe(&fp("chrome-order", "Chrome"));
+        let set = baseline.seal(0.0).unwrap().clone();
+
+        let path = std::env::temp_dir()
+            .join(format!("layer7waf-baseline-{}.json", std::process::id()));
+        set.save(&path).unwrap();
+        let loaded = BaselineSet::load(&path).unwrap();
+        std::fs::remove_file(&path).ok();
+
+        assert_eq!(loaded, set);
+        let restored = LearnedBaseline::sealed(loaded);
+        assert!(!restored.is_learning());
+        assert_eq!(restored.anomaly_score(&fp("chrome-order", "Chrome")), 0.0);
+    }
+}
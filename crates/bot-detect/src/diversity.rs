@@ -0,0 +1,250 @@
+use dashmap::DashMap;
+use serde::Serialize;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+
+/// Requests the histogram must have seen before any fingerprint is
+/// considered dominant, so a quiet server doesn't flag its first clients.
+pub const MIN_DOMINANCE_SAMPLE: u64 = 100;
+
+/// Fraction of a full histogram evicted in one pass, as in the per-client maps.
+const EVICT_FRACTION: usize = 10;
+
+/// Global request counts per fingerprint hash.
+///
+/// During a distributed attack many IPs present the same fingerprint, which
+/// shows up here as one hash taking a large share of traffic. The number of
+/// distinct hashes is capped; the least frequent are evicted to make room,
+/// so a flood of one-off fingerprints can't push out the common ones.
+///
+/// Every count is halved once per `half_life`, so shares reflect recent
+/// traffic and a fingerprint that stops being sent fades out.
+pub struct FingerprintHistogram {
+    entries: DashMap<String, u64>,
+    max_entries: usize,
+    total: AtomicU64,
+    half_life: Duration,
+    created: Instant,
+    /// Half-lives since `created` that the counts have been halved for.
+    decayed_periods: AtomicU64,
+}
+
+/// One fingerprint's share of the traffic seen by the histogram.
+#[derive(Debug, Clone, Serialize)]
+pub struct FingerprintCount {
+    pub hash: String,
+    pub count: u64,
+    /// Fraction of all recorded requests, in [0.0, 1.0].
+    pub share: f64,
+}
+
+impl FingerprintHistogram {
+    /// Create a histogram tracking at most `max_entries` distinct
+    /// fingerprints, halving their counts every `half_life`.
+    pub fn new(max_entries: usize, half_life: Duration) -> Self {
+        Self {
+            entries: DashMap::new(),
+            max_entries,
+            total: AtomicU64::new(0),
+            half_life: half_life.max(Duration::from_millis(1)),
+            created: Instant::now(),
+            decayed_periods: AtomicU64::new(0),
+        }
+    }
+
+    /// Count one request with fingerprint `hash`. Returns the fingerprint's
+    /// share of all recorded requests, including this one.
+    pub fn record(&self, hash: &str) -> f64 {
+        self.record_at(hash, Instant::now())
+    }
+
+    fn record_at(&self, hash: &str, now: Instant) -> f64 {
+        self.decay(now);
+        let total = self.total.fetch_add(1, Ordering::Relaxed) + 1;
+        let count = match self.entries.get_mut(hash) {
+            Some(mut count) => {
+                *count += 1;
+                *count
+            }
+            None => {
+                if self.entries.len() >= self.max_entries {
+                    self.evict_least_frequent();
+                }
+                let mut count = self.entries.entry(hash.to_string()).or_insert(0);
+                *count += 1;
+                *count
+            }
+        };
+        count as f64 / total as f64
+    }
+
+    /// Halve every count once for each half-life passed since the last
+    /// decay, dropping fingerprints whose count reaches zero.
+    fn decay(&self, now: Instant) {
+        let periods = (now.saturating_duration_since(self.created).as_millis()
+            / self.half_life.as_millis()) as u64;
+        let decayed = self.decayed_periods.load(Ordering::Relaxed);
+        if periods <= decayed
+            || self
+                .decayed_periods
+                .compare_exchange(decayed, periods, Ordering::Relaxed, Ordering::Relaxed)
+                .is_err()
+        {
+            return;
+        }
+        let shift = (periods - decayed).min(u64::BITS as u64 - 1);
+        self.entries.retain(|_, count| {
+            *count >>= shift;
+            *count > 0
+        });
+        let _ = self
+            .total
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |total| Some(total >> shift));
+    }
+
+    /// Drop the least frequent tenth of the tracked fingerprints.
+    fn evict_least_frequent(&self) {
+        let mut counts: Vec<(u64, String)> = self
+            .entries
+            .iter()
+            .map(|e| (*e.value(), e.key().clone()))
+            .collect();
+        let evict = (self.max_entries / EVICT_FRACTION).max(1).min(counts.len());
+        if evict == 0 {
+            return;
+        }
+        if evict < counts.len() {
+            counts.select_nth_unstable(evict - 1);
+        }
+        for (_, hash) in counts.into_iter().take(evict) {
+            self.entries.remove(&hash);
+        }
+    }
+
+    /// Whether `share` of traffic makes a fingerprint dominant, given the
+    /// configured `threshold`. Always false until [`MIN_DOMINANCE_SAMPLE`]
+    /// requests have been recorded.
+    pub fn is_dominant(&self, share: f64, threshold: f64) -> bool {
+        self.total() >= MIN_DOMINANCE_SAMPLE && share >= threshold
+    }
+
+    /// The `limit` most frequent fingerprints, most frequent first.
+    pub fn top(&self, limit: usize) -> Vec<FingerprintCount> {
+        self.decay(Instant::now());
+        let total = self.total().max(1) as f64;
+        let mut top: Vec<FingerprintCount> = self
+            .entries
+            .iter()
+            .map(|e| FingerprintCount {
+                hash: e.key().clone(),
+                count: *e.value(),
+                share: *e.value() as f64 / total,
+            })
+            .collect();
+        top.sort_by(|a, b| b.count.cmp(&a.count).then_with(|| a.hash.cmp(&b.hash)));
+        top.truncate(limit);
+        top
+    }
+
+    /// Total requests recorded, including those of evicted fingerprints,
+    /// decayed like the counts.
+    pub fn total(&self) -> u64 {
+        self.total.load(Ordering::Relaxed)
+    }
+
+    /// Number of distinct fingerprints currently tracked.
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const HALF_LIFE: Duration = Duration::from_secs(600);
+
+    #[test]
+    fn test_shared_fingerprint_concentrates() {
+        let histogram = FingerprintHistogram::new(64, HALF_LIFE);
+        for i in 0..200 {
+            histogram.record("scraper-tool");
+            histogram.record(&format!("browser-{}", i % 20));
+        }
+
+        let top = histogram.top(3);
+        assert_eq!(top[0].hash, "scraper-tool");
+        assert_eq!(top[0].count, 200);
+        assert!((top[0].share - 0.5).abs() < 1e-9);
+        assert!(top[1].count < 20);
+        assert_eq!(histogram.total(), 400);
+        assert_eq!(histogram.len(), 21);
+
+        let share = histogram.record("scraper-tool");
+        assert!(histogram.is_dominant(share, 0.3));
+    }
+
+    #[test]
+    fn test_rare_fingerprint_stays_low() {
+        let histogram = FingerprintHistogram::new(64, HALF_LIFE);
+        for i in 0..500 {
+            histogram.record(&format!("browser-{}", i % 10));
+        }
+
+        let share = histogram.record("rare");
+        assert!(share < 0.01);
+        assert!(!histogram.is_dominant(share, 0.3));
+        assert!(histogram.top(10).iter().all(|f| f.hash != "rare"));
+    }
+
+    #[test]
+    fn test_small_sample_never_dominant() {
+        let histogram = FingerprintHistogram::new(64, HALF_LIFE);
+        let share = histogram.record("first");
+        assert_eq!(share, 1.0);
+        assert!(!histogram.is_dominant(share, 0.3));
+    }
+
+    #[test]
+    fn test_capacity_bounded() {
+        let histogram = FingerprintHistogram::new(8, HALF_LIFE);
+        for _ in 0..50 {
+            histogram.record("frequent");
+        }
+        for i in 0..100 {
+            histogram.record(&format!("one-off-{}", i));
+        }
+        assert!(histogram.len() <= 8);
+        assert_eq!(histogram.top(1)[0].hash, "frequent");
+    }
+
+    #[test]
+    fn test_counts_decay_each_half_life() {
+        let histogram = FingerprintHistogram::new(64, HALF_LIFE);
+        let start = histogram.created;
+        for _ in 0..400 {
+            histogram.record_at("old-tool", start);
+        }
+        histogram.record_at("one-off", start);
+
+        // One half-life on: halved, and the single request is gone
+        let share = histogram.record_at("new-tool", start + HALF_LIFE);
+        assert_eq!(histogram.total(), 201);
+        assert_eq!(histogram.len(), 2);
+        assert!(share < 0.01);
+
+        // The new fingerprint overtakes the old once the old one stops
+        let later = start + HALF_LIFE * 3;
+        for _ in 0..100 {
+            histogram.record_at("new-tool", later);
+        }
+        let top = histogram.top(2);
+        assert_eq!(top[0].hash, "new-tool");
+        assert_eq!(top[0].count, 100);
+        assert_eq!(top[1].count, 50);
+    }
+}
@@ -1,7 +1,20 @@
+use dashmap::DashMap;
+use layer7waf_common::{KeyCapacity, MapFootprint, OverflowAction};
+use serde::{Deserialize, Serialize};
 use sha2::{Digest, Sha256};
+use std::time::{Duration, Instant};
+
+/// How long a cached header-order hash is reused before being recomputed.
+const HEADER_ORDER_CACHE_MAX_AGE: Duration = Duration::from_secs(300);
+
+/// Share of [`HttpFingerprint::similarity`] from the header order; the rest
+/// is split between the UA family and the Accept headers.
+const HEADER_ORDER_WEIGHT: f64 = 0.5;
+const UA_FAMILY_WEIGHT: f64 = 0.3;
+const ACCEPT_WEIGHT: f64 = 0.2;
 
 /// HTTP fingerprint computed from request headers.
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct HttpFingerprint {
     /// SHA-256 hash of ordered lowercase header names.
     pub header_order_hash: String,
@@ -9,16 +22,163 @@ pub struct HttpFingerprint {
     pub ua_family: String,
     /// Hash of the Accept header combination.
     pub accept_hash: String,
+    /// Hash of the values of the configured `fingerprint_headers`; empty
+    /// when none are configured.
+    pub header_values_hash: String,
+    /// Lowercase header names in request order, the input of
+    /// `header_order_hash`.
+    pub header_names: Vec<String>,
+}
+
+impl HttpFingerprint {
+    /// How alike two fingerprints are, from 0.0 (nothing shared) to 1.0
+    /// (identical). Header order counts by edit distance, so a tool that
+    /// adds, drops or swaps one header still scores high; the UA family and
+    /// Accept headers count only when equal, the Accept headers together
+    /// with any configured `fingerprint_headers`.
+    pub fn similarity(&self, other: &HttpFingerprint) -> f64 {
+        let longest = self.header_names.len().max(other.header_names.len());
+        let order = if longest == 0 {
+            1.0
+        } else {
+            let distance = edit_distance(&self.header_names, &other.header_names);
+            1.0 - distance as f64 / longest as f64
+        };
+        let same = |equal: bool| if equal { 1.0 } else { 0.0 };
+        HEADER_ORDER_WEIGHT * order
+            + UA_FAMILY_WEIGHT * same(self.ua_family == other.ua_family)
+            + ACCEPT_WEIGHT
+                * same(
+                    self.accept_hash == other.accept_hash
+                        && self.header_values_hash == other.header_values_hash,
+                )
+    }
+}
+
+/// Levenshtein distance between two header-name sequences: the fewest
+/// insertions, deletions and substitutions turning one into the other.
+fn edit_distance(a: &[String], b: &[String]) -> usize {
+    let mut previous: Vec<usize> = (0..=b.len()).collect();
+    let mut current = vec![0; b.len() + 1];
+    for (i, name_a) in a.iter().enumerate() {
+        current[0] = i + 1;
+        for (j, name_b) in b.iter().enumerate() {
+            let substitution = previous[j] + usize::from(name_a != name_b);
+            current[j + 1] = substitution.min(previous[j + 1] + 1).min(current[j] + 1);
+        }
+        std::mem::swap(&mut previous, &mut current);
+    }
+    previous[b.len()]
+}
+
+/// Bounded cache of header-order hashes keyed by the joined header names.
+///
+/// Real clients send few distinct header orders, so most lookups hit. The
+/// cap bounds growth when an attacker varies the order on every request;
+/// the oldest entries are evicted to make room.
+pub struct HeaderOrderCache {
+    entries: DashMap<String, CachedHash>,
+    capacity: KeyCapacity,
+    max_age: Duration,
+}
+
+struct CachedHash {
+    hash: String,
+    inserted: Instant,
+}
+
+impl HeaderOrderCache {
+    /// Create a cache holding at most `max_entries` header orders.
+    pub fn new(max_entries: usize) -> Self {
+        Self {
+            entries: DashMap::new(),
+            capacity: KeyCapacity {
+                max_keys: max_entries,
+                on_overflow: OverflowAction::EvictOldest,
+            },
+            max_age: HEADER_ORDER_CACHE_MAX_AGE,
+        }
+    }
+
+    /// Number of cached header orders.
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// Cached header orders and their approximate memory use.
+    pub fn map_footprint(&self, name: impl Into<String>) -> MapFootprint {
+        MapFootprint::of::<CachedHash>(name, self.entries.len())
+    }
+
+    fn get(&self, header_order: &str) -> Option<String> {
+        self.entries
+            .get(header_order)
+            .filter(|e| e.inserted.elapsed() < self.max_age)
+            .map(|e| e.hash.clone())
+    }
+
+    fn insert(&self, header_order: String, hash: String) {
+        self.capacity.admit(&self.entries, &header_order, |e| e.inserted);
+        self.entries.insert(
+            header_order,
+            CachedHash {
+                hash,
+                inserted: Instant::now(),
+            },
+        );
+    }
 }
 
 /// Compute an HTTP fingerprint from the given headers and method.
 ///
 /// `headers` is a slice of (name, value) pairs in the order they appeared in the request.
-pub fn compute_fingerprint(headers: &[(String, String)], _method: &str) -> HttpFingerprint {
+/// When `cache` is given, the header-order hash is looked up there first.
+pub fn compute_fingerprint(
+    headers: &[(String, String)],
+    method: &str,
+    cache: Option<&HeaderOrderCache>,
+) -> HttpFingerprint {
+    compute_fingerprint_filtered(headers, method, cache, &[], &[])
+}
+
+/// Like [`compute_fingerprint`], but only headers named in `order_headers`
+/// (case-insensitive) count toward the header order, so headers added by a
+/// CDN or load balancer don't split one client into many fingerprints. An
+/// empty `order_headers` counts every header.
+///
+/// The values of the headers named in `value_headers`, such as `Sec-CH-UA`
+/// or `Sec-Fetch-Mode`, are hashed into `header_values_hash`, catching
+/// clients that spoof only the classic headers.
+pub fn compute_fingerprint_filtered(
+    headers: &[(String, String)],
+    _method: &str,
+    cache: Option<&HeaderOrderCache>,
+    order_headers: &[String],
+    value_headers: &[String],
+) -> HttpFingerprint {
     // Header order hash: SHA-256 of lowercase header names joined by commas
-    let header_names: Vec<String> = headers.iter().map(|(k, _)| k.to_lowercase()).collect();
+    let header_names: Vec<String> = headers
+        .iter()
+        .filter(|(k, _)| {
+            order_headers.is_empty() || order_headers.iter().any(|h| h.eq_ignore_ascii_case(k))
+        })
+        .map(|(k, _)| k.to_lowercase())
+        .collect();
     let header_order_input = header_names.join(",");
-    let header_order_hash = sha256_hex(header_order_input.as_bytes());
+    let header_order_hash = match cache.and_then(|c| c.get(&header_order_input)) {
+        Some(hash) => hash,
+        None => {
+            let hash = sha256_hex(header_order_input.as_bytes());
+            if let Some(cache) = cache {
+                cache.insert(header_order_input, hash.clone());
+            }
+            hash
+        }
+    };
 
     // User-Agent family extraction
     let ua = headers
@@ -47,10 +207,31 @@ pub fn compute_fingerprint(headers: &[(String, String)], _method: &str) -> HttpF
     let accept_input = format!("{}|{}|{}", accept, accept_encoding, accept_language);
     let accept_hash = sha256_hex(accept_input.as_bytes());
 
+    // Configured header values, a missing header counting as empty
+    let header_values_hash = if value_headers.is_empty() {
+        String::new()
+    } else {
+        let values_input = value_headers
+            .iter()
+            .map(|name| {
+                let value = headers
+                    .iter()
+                    .find(|(k, _)| k.eq_ignore_ascii_case(name))
+                    .map(|(_, v)| v.as_str())
+                    .unwrap_or("");
+                format!("{}={}", name.to_lowercase(), value)
+            })
+            .collect::<Vec<_>>()
+            .join("\n");
+        sha256_hex(values_input.as_bytes())
+    };
+
     HttpFingerprint {
         header_order_hash,
         ua_family,
         accept_hash,
+        header_values_hash,
+        header_names,
     }
 }
 
@@ -114,7 +295,7 @@ mod tests {
             ("Accept-Encoding".into(), "gzip, deflate".into()),
             ("Accept-Language".into(), "en-US".into()),
         ];
-        let fp = compute_fingerprint(&headers, "GET");
+        let fp = compute_fingerprint(&headers, "GET", None);
         assert_eq!(fp.ua_family, "Chrome");
         assert!(!fp.header_order_hash.is_empty());
         assert!(!fp.accept_hash.is_empty());
@@ -152,8 +333,162 @@ mod tests {
             ("Accept".into(), "text/html".into()),
             ("Host".into(), "a.com".into()),
         ];
-        let fp1 = compute_fingerprint(&h1, "GET");
-        let fp2 = compute_fingerprint(&h2, "GET");
+        let fp1 = compute_fingerprint(&h1, "GET", None);
+        let fp2 = compute_fingerprint(&h2, "GET", None);
+        assert_ne!(fp1.header_order_hash, fp2.header_order_hash);
+    }
+
+    #[test]
+    fn test_ignored_header_does_not_change_fingerprint() {
+        let direct = vec![
+            ("Host".into(), "a.com".into()),
+            ("User-Agent".into(), "curl/8.0".into()),
+            ("Accept".into(), "*/*".into()),
+        ];
+        let via_cdn = vec![
+            ("Host".into(), "a.com".into()),
+            ("CDN-Loop".into(), "edge-7".into()),
+            ("User-Agent".into(), "curl/8.0".into()),
+            ("Accept".into(), "*/*".into()),
+        ];
+        let subset: Vec<String> = ["host", "user-agent", "Accept"].map(String::from).to_vec();
+
+        let fp1 = compute_fingerprint_filtered(&direct, "GET", None, &subset, &[]);
+        let fp2 = compute_fingerprint_filtered(&via_cdn, "GET", None, &subset, &[]);
+        assert_eq!(fp1.header_order_hash, fp2.header_order_hash);
+        assert_eq!(fp2.header_names, ["host", "user-agent", "accept"]);
+
+        let fp1 = compute_fingerprint_filtered(&direct, "GET", None, &[], &[]);
+        let fp2 = compute_fingerprint_filtered(&via_cdn, "GET", None, &[], &[]);
         assert_ne!(fp1.header_order_hash, fp2.header_order_hash);
     }
+
+    #[test]
+    fn test_configured_header_values_distinguish_fingerprints() {
+        let mut navigate = browser_headers();
+        navigate.push(("Sec-Fetch-Mode".into(), "navigate".into()));
+        let mut cors = browser_headers();
+        cors.push(("Sec-Fetch-Mode".into(), "cors".into()));
+        let value_headers = vec!["sec-fetch-mode".to_string()];
+
+        // Not configured: the header's value is ignored
+        let fp1 = compute_fingerprint(&navigate, "GET", None);
+        let fp2 = compute_fingerprint(&cors, "GET", None);
+        assert_eq!(fp1.header_values_hash, "");
+        assert_eq!(fp1.similarity(&fp2), 1.0);
+
+        let fp1 = compute_fingerprint_filtered(&navigate, "GET", None, &[], &value_headers);
+        let fp2 = compute_fingerprint_filtered(&cors, "GET", None, &[], &value_headers);
+        assert_eq!(fp1.header_order_hash, fp2.header_order_hash);
+        assert_ne!(fp1.header_values_hash, fp2.header_values_hash);
+        assert!(fp1.similarity(&fp2) < 1.0);
+    }
+
+    fn browser_headers() -> Vec<(String, String)> {
+        vec![
+            ("Host".into(), "example.com".into()),
+            ("User-Agent".into(), "Mozilla/5.0 Chrome/120".into()),
+            ("Accept".into(), "text/html".into()),
+            ("Accept-Encoding".into(), "gzip, deflate".into()),
+            ("Accept-Language".into(), "en-US".into()),
+            ("Connection".into(), "keep-alive".into()),
+        ]
+    }
+
+    #[test]
+    fn test_one_header_changed_scores_high() {
+        let base = compute_fingerprint(&browser_headers(), "GET", None);
+        assert_eq!(base.similarity(&base), 1.0);
+
+        // A tool randomizing one header: a different name in one position
+        let mut mutated = browser_headers();
+        mutated[5] = ("X-Random-7f3a".into(), "1".into());
+        let mutated = compute_fingerprint(&mutated, "GET", None);
+        assert_ne!(base.header_order_hash, mutated.header_order_hash);
+        let similarity = base.similarity(&mutated);
+        assert!(similarity > 0.9, "similarity {}", similarity);
+        assert_eq!(similarity, mutated.similarity(&base));
+
+        // An extra header appended
+        let mut extra = browser_headers();
+        extra.push(("DNT".into(), "1".into()));
+        let extra = compute_fingerprint(&extra, "GET", None);
+        assert!(base.similarity(&extra) > 0.9);
+    }
+
+    #[test]
+    fn test_unrelated_fingerprints_score_low() {
+        let browser = compute_fingerprint(&browser_headers(), "GET", None);
+        let script = compute_fingerprint(
+            &[
+                ("user-agent".into(), "python-requests/2.31.0".into()),
+                ("accept".into(), "*/*".into()),
+            ],
+            "GET",
+            None,
+        );
+        assert!(browser.similarity(&script) < 0.2);
+
+        let empty = compute_fingerprint(&[], "GET", None);
+        assert!(browser.similarity(&empty) < 0.2);
+    }
+
+    #[test]
+    fn test_edit_distance() {
+        let names = |s: &str| -> Vec<String> { s.split(',').map(String::from).collect() };
+        assert_eq!(edit_distance(&names("a,b,c"), &names("a,b,c")), 0);
+        assert_eq!(edit_distance(&names("a,b,c"), &names("a,x,c")), 1);
+        assert_eq!(edit_distance(&names("a,b,c"), &names("a,c")), 1);
+        assert_eq!(edit_distance(&names("a,b,c"), &names("b,a,c")), 2);
+        assert_eq!(edit_distance(&[], &names("a,b")), 2);
+    }
+
+    #[test]
+    fn test_header_order_cache_hit() {
+        let cache = HeaderOrderCache::new(16);
+        let headers = vec![
+            ("Host".into(), "a.com".into()),
+            ("Accept".into(), "text/html".into()),
+        ];
+        let uncached = compute_fingerprint(&headers, "GET", None);
+        let first = compute_fingerprint(&headers, "GET", Some(&cache));
+        assert_eq!(cache.len(), 1);
+
+        // Same order, different values and case: served from the cache
+        let headers = vec![
+            ("host".into(), "b.com".into()),
+            ("ACCEPT".into(), "*/*".into()),
+        ];
+        let second = compute_fingerprint(&headers, "GET", Some(&cache));
+        assert_eq!(cache.len(), 1);
+        assert_eq!(first.header_order_hash, uncached.header_order_hash);
+        assert_eq!(second.header_order_hash, uncached.header_order_hash);
+    }
+
+    #[test]
+    fn test_header_order_cache_bounded() {
+        let cache = HeaderOrderCache::new(100);
+        for i in 0..1000 {
+            let headers = vec![(format!("x-junk-{}", i), "1".to_string())];
+            compute_fingerprint(&headers, "GET", Some(&cache));
+            assert!(cache.len() <= 100);
+        }
+
+        // The most recent order survived eviction and still hashes correctly
+        let headers = vec![("x-junk-999".to_string(), "1".to_string())];
+        let expected = compute_fingerprint(&headers, "GET", None).header_order_hash;
+        assert_eq!(cache.get("x-junk-999"), Some(expected));
+    }
+
+    #[test]
+    fn test_expired_entry_recomputed() {
+        let mut cache = HeaderOrderCache::new(16);
+        cache.max_age = Duration::ZERO;
+        cache.insert("host".to_string(), "stale".to_string());
+        assert_eq!(cache.get("host"), None);
+
+        let headers = vec![("Host".to_string(), "a.com".to_string())];
+        let fp = compute_fingerprint(&headers, "GET", Some(&cache));
+        assert_ne!(fp.header_order_hash, "stale");
+    }
 }
@@ -1,5 +1,6 @@
 use hmac::{Hmac, Mac};
-use sha2::Sha256;
+use layer7waf_common::wants_problem_json;
+use sha2::{Digest, Sha256};
 use std::time::{SystemTime, UNIX_EPOCH};
 
 type HmacSha256 = Hmac<Sha256>;
@@ -8,17 +9,15 @@ type HmacSha256 = Hmac<Sha256>;
 ///
 /// The page computes SHA-256 hashes until it finds one with the required number of
 /// leading zero bits, then sets a cookie and redirects to the original URL.
-pub fn generate_challenge(client_ip: &str, difficulty: u32, secret: &str) -> String {
-    let timestamp = SystemTime::now()
-        .duration_since(UNIX_EPOCH)
-        .unwrap()
-        .as_secs();
-
-    // The challenge string the client must find a nonce for
-    let challenge_data = format!("{}:{}", client_ip, timestamp);
-
-    // Pre-compute HMAC of the challenge data for server-side verification
-    let hmac_value = compute_hmac(secret, &format!("{}:verified", challenge_data));
+/// `cookie_attributes` is appended to the cookie, as built by
+/// [`CookieConfig::attributes`](layer7waf_common::CookieConfig::attributes).
+pub fn generate_challenge(
+    client_ip: &str,
+    difficulty: u32,
+    secret: &str,
+    cookie_attributes: &str,
+) -> String {
+    let (challenge_data, timestamp, hmac_value) = new_challenge(client_ip, secret);
 
     format!(
         r#"<!DOCTYPE html>
@@ -90,9 +89,9 @@ p {{ color: #888; font-size: 14px; }}
   const elapsed = Date.now() - startTime;
   statusEl.textContent = 'Verified in ' + elapsed + 'ms. Redirecting...';
 
-  // Set verification cookie: ip:timestamp:hash:hmac
-  const cookieValue = ip + ':' + ts + ':' + hash + ':' + hmac;
-  document.cookie = '__l7w_bc=' + encodeURIComponent(cookieValue) + ';path=/;max-age=3600;SameSite=Lax';
+  // Set verification cookie: ip:timestamp:nonce:hmac
+  const cookieValue = ip + ':' + ts + ':' + nonce + ':' + hmac;
+  document.cookie = '__l7w_bc=' + encodeURIComponent(cookieValue) + '; max-age=3600{cookie_attributes}';
 
   // Redirect to the same page
   setTimeout(function() {{ window.location.reload(); }}, 500);
@@ -105,19 +104,63 @@ p {{ color: #888; font-size: 14px; }}
         hmac_value = hmac_value,
         client_ip = client_ip,
         timestamp = timestamp,
+        cookie_attributes = cookie_attributes,
     )
 }
 
+/// Generate the JSON form of the challenge for API/XHR clients, which can't
+/// run the HTML page. It carries the same proof-of-work parameters and
+/// explains how to build the `__l7w_bc` cookie.
+pub fn generate_challenge_json(client_ip: &str, difficulty: u32, secret: &str) -> String {
+    let (challenge_data, timestamp, hmac_value) = new_challenge(client_ip, secret);
+    serde_json::json!({
+        "error": "challenge_required",
+        "challenge": challenge_data,
+        "difficulty": difficulty,
+        "token": hmac_value,
+        "cookie": "__l7w_bc",
+        "instructions": format!(
+            "Find a nonce such that SHA-256(challenge + \":\" + nonce) has {} leading zero bits, \
+             then retry with the cookie __l7w_bc={}:{}:<nonce>:<token> (URL-encoded).",
+            difficulty, client_ip, timestamp
+        ),
+    })
+    .to_string()
+}
+
+/// Whether a request comes from an API or XHR client rather than a browser
+/// navigation, judged by its `Accept` and `X-Requested-With` headers.
+pub fn is_api_client(accept: Option<&str>, requested_with: Option<&str>) -> bool {
+    requested_with.is_some_and(|v| !v.trim().is_empty()) || wants_problem_json(accept)
+}
+
+/// Challenge string, timestamp and server HMAC for a new challenge.
+fn new_challenge(client_ip: &str, secret: &str) -> (String, u64, String) {
+    let timestamp = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_secs();
+
+    // The challenge string the client must find a nonce for
+    let challenge_data = format!("{}:{}", client_ip, timestamp);
+
+    // Pre-compute HMAC of the challenge data for server-side verification
+    let hmac_value = compute_hmac(secret, &format!("{}:verified", challenge_data));
+    (challenge_data, timestamp, hmac_value)
+}
+
 /// Verify a challenge cookie value.
 ///
-/// Cookie format: `ip:timestamp:hash:hmac`
+/// Cookie format: `ip:timestamp:nonce:hmac`
 ///
-/// Returns `true` if the cookie is valid (correct HMAC, within TTL, matching IP).
+/// Returns `true` if the cookie is valid (correct HMAC, within TTL, matching
+/// IP, and a nonce solving the proof-of-work at `difficulty`).
 pub fn verify_challenge_cookie(
     cookie_value: &str,
     client_ip: &str,
     secret: &str,
     ttl_secs: u64,
+    difficulty: u32,
 ) -> bool {
     let parts: Vec<&str> = cookie_value.splitn(4, ':').collect();
     if parts.len() != 4 {
@@ -126,7 +169,7 @@ pub fn verify_challenge_cookie(
 
     let cookie_ip = parts[0];
     let cookie_ts = parts[1];
-    let _cookie_hash = parts[2];
+    let cookie_nonce = parts[2];
     let cookie_hmac = parts[3];
 
     // Verify IP matches
@@ -153,11 +196,42 @@ pub fn verify_challenge_cookie(
     let challenge_data = format!("{}:{}:verified", cookie_ip, cookie_ts);
     let expected_hmac = compute_hmac(secret, &challenge_data);
 
-    cookie_hmac == expected_hmac
+    if cookie_hmac != expected_hmac {
+        return false;
+    }
+
+    // Verify the proof-of-work
+    let challenge_data = format!("{}:{}", cookie_ip, cookie_ts);
+    proof_of_work_valid(&challenge_data, cookie_nonce, difficulty)
+}
+
+/// Whether SHA-256(`challenge:nonce`) starts with `difficulty` zero bits,
+/// as the challenge page searches for.
+fn proof_of_work_valid(challenge: &str, nonce: &str, difficulty: u32) -> bool {
+    if nonce.is_empty() {
+        return false;
+    }
+    let digest = Sha256::digest(format!("{}:{}", challenge, nonce).as_bytes());
+    let mut zero_bits = 0;
+    for byte in digest {
+        zero_bits += byte.leading_zeros();
+        if byte != 0 {
+            break;
+        }
+    }
+    zero_bits >= difficulty
+}
+
+/// A nonce solving `challenge` at `difficulty`, found as the page does.
+#[cfg(test)]
+pub(crate) fn solve_challenge(challenge: &str, difficulty: u32) -> u64 {
+    (0..)
+        .find(|nonce: &u64| proof_of_work_valid(challenge, &nonce.to_string(), difficulty))
+        .unwrap()
 }
 
 /// Compute HMAC-SHA256 and return as hex string.
-fn compute_hmac(secret: &str, data: &str) -> String {
+pub(crate) fn compute_hmac(secret: &str, data: &str) -> String {
     let mut mac =
         HmacSha256::new_from_slice(secret.as_bytes()).expect("HMAC can take key of any size");
     mac.update(data.as_bytes());
@@ -208,12 +282,36 @@ mod tests {
 
     #[test]
     fn test_generate_challenge_contains_html() {
-        let html = generate_challenge("192.168.1.1", 16, "test-secret");
+        let html = generate_challenge("192.168.1.1", 16, "test-secret", "; Path=/");
         assert!(html.contains("<!DOCTYPE html>"));
         assert!(html.contains("__l7w_bc"));
         assert!(html.contains("crypto.subtle.digest"));
     }
 
+    #[test]
+    fn test_challenge_cookie_attributes() {
+        use layer7waf_common::{CookieConfig, CookieSameSite};
+
+        let default = CookieConfig::default().attributes(CookieSameSite::Lax);
+        let html = generate_challenge("192.168.1.1", 16, "test-secret", &default);
+        assert!(html.contains("'; max-age=3600; Path=/; SameSite=Lax; Secure';"));
+
+        let cookie = CookieConfig {
+            secure: true,
+            same_site: Some(CookieSameSite::None),
+            domain: Some("example.com".to_string()),
+            path: "/app".to_string(),
+        };
+        let html = generate_challenge(
+            "192.168.1.1",
+            16,
+            "test-secret",
+            &cookie.attributes(CookieSameSite::Lax),
+        );
+        assert!(html
+            .contains("'; max-age=3600; Path=/app; Domain=example.com; SameSite=None; Secure';"));
+    }
+
     #[test]
     fn test_verify_challenge_cookie_valid() {
         let secret = "test-secret-key";
@@ -226,9 +324,10 @@ mod tests {
         // Build a valid cookie
         let challenge_data = format!("{}:{}:verified", ip, now);
         let hmac = compute_hmac(secret, &challenge_data);
-        let cookie = format!("{}:{}:somehash:{}", ip, now, hmac);
+        let nonce = solve_challenge(&format!("{}:{}", ip, now), 12);
+        let cookie = format!("{}:{}:{}:{}", ip, now, nonce, hmac);
 
-        assert!(verify_challenge_cookie(&cookie, ip, secret, 3600));
+        assert!(verify_challenge_cookie(&cookie, ip, secret, 3600, 12));
     }
 
     #[test]
@@ -241,10 +340,11 @@ mod tests {
 
         let challenge_data = format!("10.0.0.1:{}:verified", now);
         let hmac = compute_hmac(secret, &challenge_data);
-        let cookie = format!("10.0.0.1:{}:somehash:{}", now, hmac);
+        let nonce = solve_challenge(&format!("10.0.0.1:{}", now), 8);
+        let cookie = format!("10.0.0.1:{}:{}:{}", now, nonce, hmac);
 
         // Different IP should fail
-        assert!(!verify_challenge_cookie(&cookie, "10.0.0.2", secret, 3600));
+        assert!(!verify_challenge_cookie(&cookie, "10.0.0.2", secret, 3600, 8));
     }
 
     #[test]
@@ -260,10 +360,11 @@ mod tests {
 
         let challenge_data = format!("{}:{}:verified", ip, old_ts);
         let hmac = compute_hmac(secret, &challenge_data);
-        let cookie = format!("{}:{}:somehash:{}", ip, old_ts, hmac);
+        let nonce = solve_challenge(&format!("{}:{}", ip, old_ts), 8);
+        let cookie = format!("{}:{}:{}:{}", ip, old_ts, nonce, hmac);
 
         // TTL of 3600 should reject a 7200-second-old cookie
-        assert!(!verify_challenge_cookie(&cookie, ip, secret, 3600));
+        assert!(!verify_challenge_cookie(&cookie, ip, secret, 3600, 8));
     }
 
     #[test]
@@ -277,4 +378,40 @@ mod tests {
             None
         );
     }
+
+    #[test]
+    fn test_api_client_detection() {
+        assert!(is_api_client(Some("application/json"), None));
+        assert!(is_api_client(None, Some("XMLHttpRequest")));
+        assert!(!is_api_client(
+            Some("text/html,application/xhtml+xml,application/json;q=0.9"),
+            None
+        ));
+        assert!(!is_api_client(Some("*/*"), None));
+        assert!(!is_api_client(None, None));
+    }
+
+    #[test]
+    fn test_json_challenge_token_verifies() {
+        let body = generate_challenge_json("1.2.3.4", 16, "secret");
+        let json: serde_json::Value = serde_json::from_str(&body).unwrap();
+        assert_eq!(json["error"], "challenge_required");
+        assert_eq!(json["difficulty"], 16);
+
+        // Following the instructions yields a cookie the server accepts
+        let challenge = json["challenge"].as_str().unwrap();
+        let ts = challenge.split(':').nth(1).unwrap();
+        let token = json["token"].as_str().unwrap();
+        let nonce = solve_challenge(challenge, 16);
+        let cookie = format!("1.2.3.4:{}:{}:{}", ts, nonce, token);
+        assert!(verify_challenge_cookie(&cookie, "1.2.3.4", "secret", 3600, 16));
+
+        // The token alone, without the work done, is refused
+        let unsolved = (0..)
+            .map(|n: u64| n.to_string())
+            .find(|n| !proof_of_work_valid(challenge, n, 16))
+            .unwrap();
+        let cookie = format!("1.2.3.4:{}:{}:{}", ts, unsolved, token);
+        assert!(!verify_challenge_cookie(&cookie, "1.2.3.4", "secret", 3600, 16));
+    }
 }
@@ -1,3 +1,7 @@
+use ipnet::IpNet;
+use layer7waf_common::VerifiedBot;
+use std::net::IpAddr;
+
 /// Classification result for a User-Agent string.
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum BotPattern {
@@ -106,6 +110,44 @@ pub fn classify_user_agent(ua: &str, allowlist: &[String]) -> BotPattern {
     BotPattern::LikelyHuman
 }
 
+/// Good bots whose claimed User-Agent is checked against the source ranges
+/// their operators publish, since anyone can send `Googlebot` in a UA.
+#[derive(Debug, Clone, Default)]
+pub struct VerifiedBots {
+    bots: Vec<(String, Vec<IpNet>)>,
+}
+
+impl VerifiedBots {
+    /// Parse `bots` once; CIDRs that don't parse are skipped (config
+    /// validation rejects them).
+    pub fn new(bots: &[VerifiedBot]) -> Self {
+        let bots = bots
+            .iter()
+            .map(|bot| {
+                let networks = bot
+                    .source_cidrs
+                    .iter()
+                    .filter_map(|cidr| cidr.parse().ok())
+                    .collect();
+                (bot.ua_substring.to_lowercase(), networks)
+            })
+            .collect();
+        Self { bots }
+    }
+
+    /// Whether `ua` names a listed bot and `client_ip` lies in one of its
+    /// source ranges.
+    pub fn verify(&self, ua: &str, client_ip: &str) -> bool {
+        let Ok(ip) = client_ip.parse::<IpAddr>() else {
+            return false;
+        };
+        let ua = ua.to_lowercase();
+        self.bots.iter().any(|(substring, networks)| {
+            ua.contains(substring.as_str()) && networks.iter().any(|n| n.contains(&ip))
+        })
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -151,4 +193,18 @@ mod tests {
             BotPattern::KnownGoodBot
         );
     }
+
+    #[test]
+    fn test_verified_bot_needs_ua_and_source() {
+        let bots = VerifiedBots::new(&[VerifiedBot {
+            ua_substring: "Googlebot".into(),
+            source_cidrs: vec!["66.249.64.0/19".into()],
+        }]);
+        let ua = "Mozilla/5.0 (compatible; Googlebot/2.1)";
+        assert!(bots.verify(ua, "66.249.66.1"));
+        assert!(!bots.verify(ua, "203.0.113.7"), "spoofed UA");
+        assert!(!bots.verify("Mozilla/5.0 (compatible; Bingbot/2.0)", "66.249.66.1"));
+        assert!(!bots.verify(ua, "not-an-ip"));
+        assert!(!VerifiedBots::default().verify(ua, "66.249.66.1"));
+    }
 }
@@ -1,15 +1,26 @@
+pub mod baseline;
+pub mod diversity;
 pub mod fingerprint;
 pub mod js_challenge;
 pub mod known_bots;
 pub mod score;
+pub mod under_attack;
 
-use dashmap::DashMap;
-use layer7waf_common::BotDetectionConfig;
-use std::time::Instant;
+use layer7waf_common::{
+    Admission, BotDetectionConfig, BotLearningConfig, CookieSameSite, InMemorySessionStore,
+    KeyCapacity, MapFootprint, NormalizedPath, SessionStore, UaPathAction,
+};
+use serde::{Deserialize, Serialize};
+use std::path::Path;
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime};
+use tracing::{info, warn};
 
-use fingerprint::compute_fingerprint;
+use baseline::{BaselineSet, LearnedBaseline};
+use diversity::{FingerprintCount, FingerprintHistogram};
+use fingerprint::{compute_fingerprint_filtered, HeaderOrderCache, HttpFingerprint};
 use js_challenge::{extract_challenge_cookie, verify_challenge_cookie};
-use known_bots::classify_user_agent;
+use known_bots::{classify_user_agent, VerifiedBots};
 use score::compute_bot_score;
 
 /// Result of a bot detection check.
@@ -25,48 +36,156 @@ pub enum BotCheckResult {
     Detect { score: f64 },
 }
 
+/// HTTP response for a [`BotCheckResult::Challenge`].
+#[derive(Debug)]
+pub struct ChallengeResponse {
+    pub status: u16,
+    pub content_type: &'static str,
+    pub body: String,
+}
+
+/// Score added to requests whose fingerprint dominates global traffic.
+const DOMINANT_FINGERPRINT_BOOST: f64 = 0.3;
+
+/// Score added to requests with a header value that isn't UTF-8; browsers
+/// never send them.
+const MALFORMED_HEADER_BOOST: f64 = 0.3;
+
+/// What is known about a request before it is scored, from the raw request
+/// and from checks that ran before bot detection.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct RequestSignals {
+    /// A header value wasn't UTF-8, which raises the score.
+    pub malformed_headers: bool,
+    /// The client IP's reputation feed score, 0.0 to 1.0. The bot score
+    /// starts no lower, so a poorly rated IP meets `score_threshold` and
+    /// `challenge_threshold` sooner.
+    pub ip_reputation: f64,
+}
+
+/// Custom scoring signal for embedders, e.g. a threat-intel lookup. Given a
+/// request's fingerprint and headers, returns a score from 0.0 (clean) to
+/// 1.0 (certain bot). Runs synchronously on every scored request, so it
+/// must be cheap.
+pub type ScorePlugin = Box<dyn Fn(&HttpFingerprint, &[(String, String)]) -> f64 + Send + Sync>;
+
 /// Per-IP session tracking entry.
-#[derive(Debug, Clone)]
-struct BotSession {
-    last_seen: Instant,
-    fingerprint_hash: String,
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct BotSession {
+    last_seen: SystemTime,
+    /// Fingerprint of the client's latest request.
+    fingerprint: HttpFingerprint,
+    /// Bot score of the client's latest request.
+    score: f64,
 }
 
 /// Bot detection engine wrapping all sub-modules.
-pub struct BotDetector {
+///
+/// Sessions live in `S`, the in-process [`InMemorySessionStore`] by default.
+pub struct BotDetector<S = InMemorySessionStore<BotSession>> {
     config: BotDetectionConfig,
-    sessions: DashMap<String, BotSession>,
+    sessions: S,
+    session_capacity: Option<KeyCapacity>,
+    header_order_cache: Option<HeaderOrderCache>,
+    fingerprints: Option<Arc<FingerprintHistogram>>,
+    baseline: Option<LearnedBaseline>,
+    verified_bots: VerifiedBots,
+    /// When a baseline still learning gets sealed.
+    learn_until: Instant,
+    /// Custom signal and the weight its output is added to the score with.
+    score_plugin: Option<(ScorePlugin, f64)>,
 }
 
 impl BotDetector {
     /// Create a new BotDetector from the given configuration.
     pub fn new(config: BotDetectionConfig) -> Self {
+        Self::with_store(config, InMemorySessionStore::new())
+    }
+}
+
+impl<S: SessionStore<BotSession>> BotDetector<S> {
+    /// Create a BotDetector keeping its sessions in `sessions`.
+    pub fn with_store(config: BotDetectionConfig, sessions: S) -> Self {
+        let header_order_cache = (config.header_order_cache_size > 0)
+            .then(|| HeaderOrderCache::new(config.header_order_cache_size));
+        let fingerprints = (config.fingerprint_histogram_size > 0).then(|| {
+            Arc::new(FingerprintHistogram::new(
+                config.fingerprint_histogram_size,
+                Duration::from_secs(config.fingerprint_half_life_secs),
+            ))
+        });
+        let baseline = config.learning.enabled.then(|| load_baseline(&config.learning));
+        let learn_until = Instant::now() + Duration::from_secs(config.learning.duration_secs);
+        let verified_bots = VerifiedBots::new(&config.verified_bots);
         Self {
             config,
-            sessions: DashMap::new(),
+            sessions,
+            session_capacity: None,
+            header_order_cache,
+            fingerprints,
+            baseline,
+            verified_bots,
+            learn_until,
+            score_plugin: None,
         }
     }
 
+    /// Bound the number of tracked sessions.
+    pub fn with_session_capacity(mut self, capacity: KeyCapacity) -> Self {
+        self.session_capacity = Some(capacity);
+        self
+    }
+
+    /// Add `plugin`'s output, times `weight`, to every request's bot score.
+    /// A plugin returning 0.0 leaves scores unchanged.
+    pub fn with_score_plugin(mut self, weight: f64, plugin: ScorePlugin) -> Self {
+        self.score_plugin = Some((plugin, weight));
+        self
+    }
+
     /// Perform a bot detection check on the incoming request.
     ///
     /// # Arguments
     /// - `client_ip`: The client's IP address as a string.
     /// - `headers`: Request headers as (name, value) pairs in order.
     /// - `method`: HTTP method (GET, POST, etc.).
+    /// - `path`: Request path, matched against `require_challenge_paths`.
     /// - `cookie_header`: The raw `Cookie` header value, if present.
     pub fn check(
         &self,
         client_ip: &str,
         headers: &[(String, String)],
         method: &str,
+        path: &str,
+        cookie_header: Option<&str>,
+    ) -> BotCheckResult {
+        let signals = RequestSignals::default();
+        self.check_with_signals(client_ip, headers, method, path, cookie_header, signals)
+    }
+
+    /// Like [`BotDetector::check`], for a request with earlier `signals`
+    /// about it folded into its score.
+    pub fn check_with_signals(
+        &self,
+        client_ip: &str,
+        headers: &[(String, String)],
+        method: &str,
+        path: &str,
         cookie_header: Option<&str>,
+        signals: RequestSignals,
     ) -> BotCheckResult {
         if !self.config.enabled {
             return BotCheckResult::Allow;
         }
 
         // 1. Compute HTTP fingerprint
-        let fp = compute_fingerprint(headers, method);
+        let fp = compute_fingerprint_filtered(
+            headers,
+            method,
+            self.header_order_cache.as_ref(),
+            &self.config.fingerprint_order_headers,
+            &self.config.fingerprint_headers,
+        );
 
         // 2. Classify User-Agent
         let ua = headers
@@ -85,45 +204,147 @@ impl BotDetector {
                     client_ip,
                     &self.config.js_challenge.secret,
                     self.config.js_challenge.ttl_secs,
+                    self.config.js_challenge.difficulty,
                 )
             })
             .unwrap_or(false);
 
-        // 4. Compute composite score
-        let bot_score = compute_bot_score(&fp, bot_pattern, has_valid_challenge, headers);
+        // 3b. User-Agents in `force_challenge_ua` skip scoring
+        if self.forces_challenge(ua) {
+            return match self.config.mode {
+                layer7waf_common::BotDetectionMode::Block => BotCheckResult::Block,
+                layer7waf_common::BotDetectionMode::Challenge => {
+                    self.challenge_result(client_ip, has_valid_challenge)
+                }
+                layer7waf_common::BotDetectionMode::Detect => BotCheckResult::Detect { score: 1.0 },
+            };
+        }
 
-        // 5. Track session
-        self.sessions.insert(
-            client_ip.to_string(),
-            BotSession {
-                last_seen: Instant::now(),
-                fingerprint_hash: fp.header_order_hash.clone(),
-            },
-        );
+        // 4. Compute composite score, raised for malformed header bytes, to
+        // the IP's reputation and if this fingerprint dominates global
+        // traffic (one tool run from many IPs)
+        let mut bot_score = compute_bot_score(&fp, bot_pattern, has_valid_challenge, headers);
+        if let Some((ref plugin, weight)) = self.score_plugin {
+            bot_score = (bot_score + plugin(&fp, headers).clamp(0.0, 1.0) * weight).clamp(0.0, 1.0);
+        }
+        if signals.malformed_headers && !has_valid_challenge {
+            bot_score = (bot_score + MALFORMED_HEADER_BOOST).min(1.0);
+        }
+        if !has_valid_challenge {
+            bot_score = bot_score.max(signals.ip_reputation.clamp(0.0, 1.0));
+        }
+        if let Some(ref histogram) = self.fingerprints {
+            let share = histogram.record(&fp.header_order_hash);
+            let dominant = self
+                .config
+                .dominant_fingerprint_share
+                .is_some_and(|threshold| histogram.is_dominant(share, threshold));
+            if dominant && !has_valid_challenge {
+                bot_score = (bot_score + DOMINANT_FINGERPRINT_BOOST).min(1.0);
+            }
+        }
+
+        // 4b. Learning mode: count the fingerprint until the baseline is
+        // sealed, then score fingerprints outside it
+        let mut learning = false;
+        if let Some(ref baseline) = self.baseline {
+            if baseline.is_learning() && Instant::now() >= self.learn_until {
+                self.seal_baseline();
+            }
+            if baseline.is_learning() {
+                baseline.observe(&fp);
+                learning = true;
+            } else if !has_valid_challenge {
+                bot_score = (bot_score + baseline.anomaly_score(&fp)).min(1.0);
+            }
+        }
+
+        // 5. Track session (subject to the session cap)
+        let admission = self
+            .session_capacity
+            .map(|cap| self.sessions.admit(&cap, client_ip, |s| s.last_seen))
+            .unwrap_or(Admission::Admitted);
+        match admission {
+            Admission::Admitted => {
+                self.sessions.insert(
+                    client_ip,
+                    BotSession {
+                        last_seen: SystemTime::now(),
+                        fingerprint: fp.clone(),
+                        score: bot_score,
+                    },
+                );
+            }
+            Admission::RefusedAllow => {}
+            Admission::RefusedBlock => return BotCheckResult::Block,
+        }
 
         // 6. Known good bots always pass
         if bot_pattern == known_bots::BotPattern::KnownGoodBot {
             return BotCheckResult::Allow;
         }
 
-        // 7. Apply mode-specific logic
+        // 7. Paths in `require_challenge_paths` need a passed JS challenge
+        // whatever the score; a score that blocks still blocks
+        let result = if learning {
+            // Nothing is blocked or challenged on score while learning
+            BotCheckResult::Detect { score: bot_score }
+        } else {
+            self.scored_result(client_ip, bot_score, has_valid_challenge)
+        };
+        match result {
+            BotCheckResult::Allow | BotCheckResult::Detect { .. }
+                if !has_valid_challenge && self.requires_challenge(path) =>
+            {
+                self.challenge_result(client_ip, false)
+            }
+            result => result,
+        }
+    }
+
+    /// Whether `ua` contains one of `force_challenge_ua`.
+    fn forces_challenge(&self, ua: &str) -> bool {
+        if self.config.force_challenge_ua.is_empty() {
+            return false;
+        }
+        let ua = ua.to_ascii_lowercase();
+        self.config
+            .force_challenge_ua
+            .iter()
+            .any(|substring| ua.contains(&substring.to_ascii_lowercase()))
+    }
+
+    /// Whether `path` is under one of `require_challenge_paths`, after
+    /// normalization so re-spellings of a protected path are still gated.
+    fn requires_challenge(&self, path: &str) -> bool {
+        if self.config.require_challenge_paths.is_empty() {
+            return false;
+        }
+        let path = NormalizedPath::new(path);
+        self.config
+            .require_challenge_paths
+            .iter()
+            .any(|prefix| path.has_prefix(prefix))
+    }
+
+    /// Verdict for a bot score under the configured mode. In block mode,
+    /// scores between `challenge_threshold` and `score_threshold` are
+    /// challenged.
+    fn scored_result(
+        &self,
+        client_ip: &str,
+        bot_score: f64,
+        has_valid_challenge: bool,
+    ) -> BotCheckResult {
+        let challenge_band = self
+            .config
+            .challenge_threshold
+            .is_some_and(|threshold| bot_score >= threshold);
         if bot_score >= self.config.score_threshold {
             match self.config.mode {
                 layer7waf_common::BotDetectionMode::Block => BotCheckResult::Block,
                 layer7waf_common::BotDetectionMode::Challenge => {
-                    if has_valid_challenge {
-                        // Already passed challenge, allow through
-                        BotCheckResult::Allow
-                    } else if self.config.js_challenge.enabled {
-                        let html = js_challenge::generate_challenge(
-                            client_ip,
-                            self.config.js_challenge.difficulty,
-                            &self.config.js_challenge.secret,
-                        );
-                        BotCheckResult::Challenge(html)
-                    } else {
-                        BotCheckResult::Block
-                    }
+                    self.challenge_result(client_ip, has_valid_challenge)
                 }
                 layer7waf_common::BotDetectionMode::Detect => {
                     BotCheckResult::Detect { score: bot_score }
@@ -134,28 +355,281 @@ impl BotDetector {
                 layer7waf_common::BotDetectionMode::Detect => {
                     BotCheckResult::Detect { score: bot_score }
                 }
+                layer7waf_common::BotDetectionMode::Block if challenge_band => {
+                    self.challenge_result(client_ip, has_valid_challenge)
+                }
                 _ => BotCheckResult::Allow,
             }
         }
     }
 
+    /// Verdict of the first `ua_path_rules` entry matching this request's
+    /// User-Agent and `path`, if any. Consulted before [`BotDetector::check`]
+    /// so that, e.g., a monitoring probe reaches its health path without
+    /// being scored there.
+    pub fn ua_path_override(
+        &self,
+        headers: &[(String, String)],
+        path: &str,
+    ) -> Option<BotCheckResult> {
+        if !self.config.enabled || self.config.ua_path_rules.is_empty() {
+            return None;
+        }
+        let ua = headers
+            .iter()
+            .find(|(k, _)| k.eq_ignore_ascii_case("user-agent"))
+            .map(|(_, v)| v.to_ascii_lowercase())
+            .unwrap_or_default();
+        let path = NormalizedPath::new(path);
+        let rule = self.config.ua_path_rules.iter().find(|rule| {
+            path.has_prefix(&rule.path_prefix)
+                && ua.contains(&rule.ua_substring.to_ascii_lowercase())
+        })?;
+        Some(match rule.action {
+            UaPathAction::Allow => BotCheckResult::Allow,
+            UaPathAction::Block => BotCheckResult::Block,
+        })
+    }
+
+    /// End the learning period now: seal the baseline and save it to
+    /// `learning.baseline_path`, if set. The file is written on a thread of
+    /// its own, as sealing usually happens on a request. Returns false if
+    /// learning mode is off or the baseline was already sealed.
+    pub fn seal_baseline(&self) -> bool {
+        let Some(ref baseline) = self.baseline else {
+            return false;
+        };
+        let Some(set) = baseline.seal(self.config.learning.min_share) else {
+            return false;
+        };
+        info!(
+            observed = set.observed,
+            fingerprints = set.fingerprints.len(),
+            ua_families = set.ua_families.len(),
+            "bot detection baseline sealed"
+        );
+        if let Some(path) = self.config.learning.baseline_path.clone() {
+            let set = set.clone();
+            let saved = std::thread::Builder::new()
+                .name("baseline-writer".into())
+                .spawn(move || {
+                    if let Err(e) = set.save(Path::new(&path)) {
+                        warn!(path = %path, error = %e, "failed to save bot detection baseline");
+                    }
+                });
+            if let Err(e) = saved {
+                warn!(error = %e, "failed to start the bot detection baseline writer");
+            }
+        }
+        true
+    }
+
+    /// Whether learning mode is on and the baseline is not yet sealed.
+    pub fn is_learning(&self) -> bool {
+        self.baseline.as_ref().is_some_and(|b| b.is_learning())
+    }
+
+    /// Check a request while under-attack mode is on: every client must hold
+    /// a valid challenge cookie, except good bots verified by their source
+    /// address (`verified_bots`). A good-bot User-Agent alone is not enough.
+    /// Sessions and scores are not updated.
+    pub fn check_under_attack(
+        &self,
+        client_ip: &str,
+        headers: &[(String, String)],
+        cookie_header: Option<&str>,
+    ) -> BotCheckResult {
+        let ua = headers
+            .iter()
+            .find(|(k, _)| k.eq_ignore_ascii_case("user-agent"))
+            .map(|(_, v)| v.as_str())
+            .unwrap_or("");
+        if self.verified_bots.verify(ua, client_ip) {
+            return BotCheckResult::Allow;
+        }
+        self.challenge(client_ip, cookie_header)
+    }
+
+    /// Challenge a client regardless of its bot score, e.g. on behalf of
+    /// another filter. Returns `Allow` if the client already holds a valid
+    /// challenge cookie and `Block` if JS challenges are disabled.
+    pub fn challenge(&self, client_ip: &str, cookie_header: Option<&str>) -> BotCheckResult {
+        let has_valid_challenge = cookie_header
+            .and_then(extract_challenge_cookie)
+            .map(|cookie| {
+                verify_challenge_cookie(
+                    &cookie,
+                    client_ip,
+                    &self.config.js_challenge.secret,
+                    self.config.js_challenge.ttl_secs,
+                    self.config.js_challenge.difficulty,
+                )
+            })
+            .unwrap_or(false);
+        self.challenge_result(client_ip, has_valid_challenge)
+    }
+
+    fn challenge_result(&self, client_ip: &str, has_valid_challenge: bool) -> BotCheckResult {
+        if has_valid_challenge {
+            // Already passed challenge, allow through
+            BotCheckResult::Allow
+        } else if self.config.js_challenge.enabled {
+            let html = js_challenge::generate_challenge(
+                client_ip,
+                self.config.js_challenge.difficulty,
+                &self.config.js_challenge.secret,
+                &self.config.js_challenge.cookie.attributes(CookieSameSite::Lax),
+            );
+            BotCheckResult::Challenge(html)
+        } else {
+            BotCheckResult::Block
+        }
+    }
+
+    /// Build the response for a challenge: the HTML page for browsers, or a
+    /// 403 with a JSON description of the challenge for API/XHR clients,
+    /// which the HTML page would only break. `accept` and `requested_with`
+    /// are the request's `Accept` and `X-Requested-With` headers.
+    pub fn challenge_response(
+        &self,
+        client_ip: &str,
+        html: String,
+        accept: Option<&str>,
+        requested_with: Option<&str>,
+    ) -> ChallengeResponse {
+        if js_challenge::is_api_client(accept, requested_with) {
+            ChallengeResponse {
+                status: 403,
+                content_type: "application/json",
+                body: js_challenge::generate_challenge_json(
+                    client_ip,
+                    self.config.js_challenge.difficulty,
+                    &self.config.js_challenge.secret,
+                ),
+            }
+        } else {
+            ChallengeResponse {
+                status: 200,
+                content_type: "text/html; charset=utf-8",
+                body: html,
+            }
+        }
+    }
+
     /// Remove stale session entries older than the given duration.
     pub fn cleanup_sessions(&self, max_age: std::time::Duration) {
-        let now = Instant::now();
-        self.sessions
-            .retain(|_, session| now.duration_since(session.last_seen) < max_age);
+        let now = SystemTime::now();
+        self.sessions.retain(|session| {
+            now.duration_since(session.last_seen).unwrap_or_default() < max_age
+        });
     }
 
     /// Return the number of tracked sessions.
     pub fn session_count(&self) -> usize {
         self.sessions.len()
     }
+
+    /// Entry counts and approximate memory use of the detector's per-key
+    /// maps: sessions, the header-order cache and the fingerprint histogram.
+    pub fn map_footprints(&self) -> Vec<MapFootprint> {
+        let mut maps = vec![MapFootprint::of::<BotSession>("bot_sessions", self.sessions.len())];
+        if let Some(cache) = &self.header_order_cache {
+            maps.push(cache.map_footprint("bot_header_order_cache"));
+        }
+        if let Some(fingerprints) = &self.fingerprints {
+            maps.push(MapFootprint::of::<u64>("bot_fingerprints", fingerprints.len()));
+        }
+        maps
+    }
+
+    /// Bot score of `client_ip`'s latest checked request, whatever the
+    /// decision was. `None` if the client isn't tracked.
+    pub fn session_score(&self, client_ip: &str) -> Option<f64> {
+        self.sessions.read(client_ip, |s| s.score)
+    }
+
+    /// Sessions whose latest score reached `score_threshold`, as
+    /// `(client_ip, score)`.
+    pub fn flagged_sessions(&self) -> Vec<(String, f64)> {
+        self.sessions.filter_map(|client_ip, session| {
+            (session.score >= self.config.score_threshold)
+                .then(|| (client_ip.to_string(), session.score))
+        })
+    }
+
+    /// Forget every session, so all clients are re-evaluated from scratch.
+    /// Returns the number of sessions removed.
+    pub fn clear_sessions(&self) -> usize {
+        self.sessions.clear()
+    }
+
+    /// Forget `client_ip`'s session. Returns whether it was tracked.
+    pub fn clear_session(&self, client_ip: &str) -> bool {
+        self.sessions.remove(client_ip)
+    }
+
+    /// Other tracked clients whose latest fingerprint is at least
+    /// `min_similarity` alike to `client_ip`'s, most similar first. Catches
+    /// one tool spread over many IPs even when it mutates a header per
+    /// request. Scans every session, so meant for on-demand analysis rather
+    /// than the request path.
+    pub fn similar_clients(&self, client_ip: &str, min_similarity: f64) -> Vec<(String, f64)> {
+        let Some(fingerprint) = self.sessions.read(client_ip, |s| s.fingerprint.clone()) else {
+            return Vec::new();
+        };
+        let mut similar: Vec<(String, f64)> = self.sessions.filter_map(|other, session| {
+            if other == client_ip {
+                return None;
+            }
+            let similarity = fingerprint.similarity(&session.fingerprint);
+            (similarity >= min_similarity).then(|| (other.to_string(), similarity))
+        });
+        similar.sort_by(|a, b| b.1.total_cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+        similar
+    }
+
+    /// The `limit` most frequent fingerprints across all clients. Empty when
+    /// the histogram is disabled.
+    pub fn top_fingerprints(&self, limit: usize) -> Vec<FingerprintCount> {
+        self.fingerprints
+            .as_ref()
+            .map(|h| h.top(limit))
+            .unwrap_or_default()
+    }
+}
+
+/// Load the sealed baseline from `baseline_path`, or start learning if
+/// there is none.
+fn load_baseline(config: &BotLearningConfig) -> LearnedBaseline {
+    if let Some(ref path) = config.baseline_path {
+        if Path::new(path).exists() {
+            match BaselineSet::load(Path::new(path)) {
+                Ok(set) => {
+                    info!(
+                        path = %path,
+                        fingerprints = set.fingerprints.len(),
+                        "loaded bot detection baseline"
+                    );
+                    return LearnedBaseline::sealed(set);
+                }
+                Err(e) => {
+                    warn!(
+                        path = %path,
+                        error = %e,
+                        "invalid bot detection baseline, learning a new one"
+                    );
+                }
+            }
+        }
+    }
+    info!(duration_secs = config.duration_secs, "bot detection learning mode started");
+    LearnedBaseline::learning()
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
-    use layer7waf_common::{BotDetectionConfig, BotDetectionMode, JsChallengeConfig};
+    use layer7waf_common::{BotDetectionConfig, BotDetectionMode, JsChallengeConfig, UaPathRule};
 
     fn test_config(mode: BotDetectionMode) -> BotDetectionConfig {
         BotDetectionConfig {
@@ -166,9 +640,22 @@ mod tests {
                 difficulty: 16,
                 ttl_secs: 3600,
                 secret: "test-secret".to_string(),
+                cookie: Default::default(),
             },
             score_threshold: 0.7,
+            challenge_threshold: None,
             known_bots_allowlist: vec![],
+            header_order_cache_size: 1024,
+            fingerprint_histogram_size: 1024,
+            fingerprint_half_life_secs: 600,
+            dominant_fingerprint_share: None,
+            learning: Default::default(),
+            ua_path_rules: vec![],
+            require_challenge_paths: vec![],
+            force_challenge_ua: vec![],
+            verified_bots: vec![],
+            fingerprint_order_headers: vec![],
+            fingerprint_headers: vec![],
         }
     }
 
@@ -198,41 +685,301 @@ mod tests {
         let mut config = test_config(BotDetectionMode::Block);
         config.enabled = false;
         let detector = BotDetector::new(config);
-        let result = detector.check("1.2.3.4", &curl_headers(), "GET", None);
+        let result = detector.check("1.2.3.4", &curl_headers(), "GET", "/", None);
         assert!(matches!(result, BotCheckResult::Allow));
     }
 
     #[test]
     fn test_browser_request_allowed() {
         let detector = BotDetector::new(test_config(BotDetectionMode::Block));
-        let result = detector.check("1.2.3.4", &browser_headers(), "GET", None);
+        let result = detector.check("1.2.3.4", &browser_headers(), "GET", "/", None);
         assert!(matches!(result, BotCheckResult::Allow));
     }
 
     #[test]
     fn test_curl_blocked_in_block_mode() {
         let detector = BotDetector::new(test_config(BotDetectionMode::Block));
-        let result = detector.check("1.2.3.4", &curl_headers(), "GET", None);
+        let result = detector.check("1.2.3.4", &curl_headers(), "GET", "/", None);
         assert!(matches!(result, BotCheckResult::Block));
     }
 
     #[test]
     fn test_curl_challenged_in_challenge_mode() {
         let detector = BotDetector::new(test_config(BotDetectionMode::Challenge));
-        let result = detector.check("1.2.3.4", &curl_headers(), "GET", None);
+        let result = detector.check("1.2.3.4", &curl_headers(), "GET", "/", None);
         assert!(matches!(result, BotCheckResult::Challenge(_)));
     }
 
     #[test]
     fn test_curl_detected_in_detect_mode() {
         let detector = BotDetector::new(test_config(BotDetectionMode::Detect));
-        let result = detector.check("1.2.3.4", &curl_headers(), "GET", None);
+        let result = detector.check("1.2.3.4", &curl_headers(), "GET", "/", None);
         match result {
             BotCheckResult::Detect { score } => assert!(score >= 0.7),
             other => panic!("expected Detect, got {:?}", other),
         }
     }
 
+    fn score(result: BotCheckResult) -> f64 {
+        match result {
+            BotCheckResult::Detect { score } => score,
+            other => panic!("expected Detect, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_challenge_threshold_three_bands() {
+        let mut config = test_config(BotDetectionMode::Block);
+        config.challenge_threshold = Some(0.4);
+        let detector = BotDetector::new(config);
+
+        // Low: a browser scores 0.1
+        let result = detector.check("1.2.3.4", &browser_headers(), "GET", "/", None);
+        assert!(matches!(result, BotCheckResult::Allow));
+
+        // Middle: a generic bot UA with browser headers scores 0.5
+        let mut headers = browser_headers();
+        headers[1].1 = "MyCustomBot/1.0".into();
+        let result = detector.check("1.2.3.5", &headers, "GET", "/", None);
+        assert!(matches!(result, BotCheckResult::Challenge(_)));
+
+        // High: curl is blocked outright
+        let result = detector.check("1.2.3.6", &curl_headers(), "GET", "/", None);
+        assert!(matches!(result, BotCheckResult::Block));
+    }
+
+    #[test]
+    fn test_malformed_headers_raise_score() {
+        let detector = BotDetector::new(test_config(BotDetectionMode::Detect));
+        let clean = score(detector.check("1.2.3.4", &browser_headers(), "GET", "/", None));
+        let mut headers = browser_headers();
+        headers.push(("X-Note".into(), "caf\u{e9}".into()));
+        let signals = RequestSignals {
+            malformed_headers: true,
+            ..Default::default()
+        };
+        let malformed =
+            score(detector.check_with_signals("1.2.3.4", &headers, "GET", "/", None, signals));
+        assert!((malformed - clean - MALFORMED_HEADER_BOOST).abs() < 1e-9);
+    }
+
+    #[test]
+    fn test_ip_reputation_raises_score_to_thresholds() {
+        let mut config = test_config(BotDetectionMode::Block);
+        config.challenge_threshold = Some(0.4);
+        let detector = BotDetector::new(config);
+        let reputation = |ip_reputation| RequestSignals {
+            ip_reputation,
+            ..Default::default()
+        };
+
+        // A browser scoring 0.1 is challenged, then blocked, from a badly rated IP
+        let check = |ip, signals| {
+            detector.check_with_signals(ip, &browser_headers(), "GET", "/", None, signals)
+        };
+        assert!(matches!(check("1.2.3.4", reputation(0.2)), BotCheckResult::Allow));
+        assert!(matches!(check("1.2.3.5", reputation(0.5)), BotCheckResult::Challenge(_)));
+        assert!(matches!(check("1.2.3.6", reputation(0.9)), BotCheckResult::Block));
+        assert_eq!(detector.session_score("1.2.3.6"), Some(0.9));
+    }
+
+    #[test]
+    fn test_required_challenge_paths_gate() {
+        let mut config = test_config(BotDetectionMode::Block);
+        config.require_challenge_paths = vec!["/checkout".to_string(), "/admin/login".to_string()];
+        let detector = BotDetector::new(config);
+
+        // A low-scoring browser is challenged on a protected path only
+        let result = detector.check("1.2.3.4", &browser_headers(), "GET", "/checkout/pay", None);
+        assert!(matches!(result, BotCheckResult::Challenge(_)));
+        assert!(detector.session_score("1.2.3.4").unwrap() < 0.7);
+        let result = detector.check("1.2.3.4", &browser_headers(), "GET", "/products", None);
+        assert!(matches!(result, BotCheckResult::Allow));
+
+        // Once the challenge is passed, the path is open
+        let now = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap()
+            .as_secs();
+        let hmac = js_challenge::compute_hmac("test-secret", &format!("1.2.3.4:{}:verified", now));
+        let nonce = js_challenge::solve_challenge(&format!("1.2.3.4:{}", now), 16);
+        let cookie = format!("__l7w_bc=1.2.3.4:{}:{}:{}", now, nonce, hmac);
+        let result =
+            detector.check("1.2.3.4", &browser_headers(), "GET", "/checkout", Some(&cookie));
+        assert!(matches!(result, BotCheckResult::Allow));
+
+        // A score that blocks still blocks
+        let result = detector.check("5.6.7.8", &curl_headers(), "GET", "/admin/login", None);
+        assert!(matches!(result, BotCheckResult::Block));
+    }
+
+    #[test]
+    fn test_required_challenge_paths_not_bypassed_by_respelling() {
+        let mut config = test_config(BotDetectionMode::Block);
+        config.require_challenge_paths = vec!["/checkout".to_string()];
+        let detector = BotDetector::new(config);
+
+        for path in ["//checkout", "/%63heckout", "/Checkout/pay", "/static/../checkout"] {
+            let result = detector.check("1.2.3.4", &browser_headers(), "GET", path, None);
+            assert!(matches!(result, BotCheckResult::Challenge(_)), "{}", path);
+        }
+        // Only whole segments match
+        let result = detector.check("1.2.3.4", &browser_headers(), "GET", "/checkoutfoo", None);
+        assert!(matches!(result, BotCheckResult::Allow));
+    }
+
+    #[test]
+    fn test_forced_ua_challenged_despite_low_score() {
+        let mut headers = browser_headers();
+        headers[1].1.push_str(" ScrapeKit/2.1");
+        let mut config = test_config(BotDetectionMode::Challenge);
+        config.force_challenge_ua = vec!["scrapekit".to_string()];
+        let detector = BotDetector::new(config.clone());
+
+        // The browser-like headers score well under the threshold
+        let result = BotDetector::new(test_config(BotDetectionMode::Challenge))
+            .check("1.2.3.4", &headers, "GET", "/", None);
+        assert!(matches!(result, BotCheckResult::Allow));
+
+        for _ in 0..3 {
+            let result = detector.check("1.2.3.4", &headers, "GET", "/", None);
+            assert!(matches!(result, BotCheckResult::Challenge(_)));
+        }
+        let result = detector.check("1.2.3.4", &browser_headers(), "GET", "/", None);
+        assert!(matches!(result, BotCheckResult::Allow));
+
+        config.mode = BotDetectionMode::Block;
+        let result = BotDetector::new(config).check("1.2.3.4", &headers, "GET", "/", None);
+        assert!(matches!(result, BotCheckResult::Block));
+    }
+
+    #[test]
+    fn test_score_plugin_blended_into_score() {
+        let detector = BotDetector::new(test_config(BotDetectionMode::Block))
+            .with_score_plugin(0.8, Box::new(|_, _| 1.0));
+        let result = detector.check("1.2.3.4", &browser_headers(), "GET", "/", None);
+        assert!(matches!(result, BotCheckResult::Block));
+        assert!(detector.session_score("1.2.3.4").unwrap() >= 0.7);
+
+        let plain = BotDetector::new(test_config(BotDetectionMode::Block));
+        plain.check("1.2.3.4", &browser_headers(), "GET", "/", None);
+        let detector = BotDetector::new(test_config(BotDetectionMode::Block))
+            .with_score_plugin(0.8, Box::new(|_, _| 0.0));
+        let result = detector.check("1.2.3.4", &browser_headers(), "GET", "/", None);
+        assert!(matches!(result, BotCheckResult::Allow));
+        assert_eq!(detector.session_score("1.2.3.4"), plain.session_score("1.2.3.4"));
+    }
+
+    #[test]
+    fn test_learning_mode_never_blocks() {
+        let mut config = test_config(BotDetectionMode::Block);
+        config.learning.enabled = true;
+        let detector = BotDetector::new(config);
+        assert!(detector.is_learning());
+
+        let result = detector.check("1.2.3.4", &curl_headers(), "GET", "/", None);
+        assert!(score(result) >= 0.7);
+    }
+
+    #[test]
+    fn test_sealed_baseline_scores_unseen_fingerprint_higher() {
+        let mut config = test_config(BotDetectionMode::Detect);
+        config.learning.enabled = true;
+        let detector = BotDetector::new(config);
+        for _ in 0..50 {
+            detector.check("1.2.3.4", &browser_headers(), "GET", "/", None);
+        }
+        assert!(detector.seal_baseline());
+        assert!(!detector.seal_baseline());
+        assert!(!detector.is_learning());
+
+        let mut reordered = browser_headers();
+        reordered.reverse();
+        let known = score(detector.check("1.2.3.4", &browser_headers(), "GET", "/", None));
+        let unseen = score(detector.check("1.2.3.4", &reordered, "GET", "/", None));
+        assert!(unseen > known, "unseen {} vs known {}", unseen, known);
+    }
+
+    #[test]
+    fn test_sealed_baseline_saved_off_request_path() {
+        let path = std::env::temp_dir()
+            .join(format!("layer7waf_baseline_seal_{}.json", std::process::id()));
+        let mut config = test_config(BotDetectionMode::Detect);
+        config.learning.enabled = true;
+        config.learning.baseline_path = Some(path.to_string_lossy().into_owned());
+        let detector = BotDetector::new(config);
+        detector.check("1.2.3.4", &browser_headers(), "GET", "/", None);
+        assert!(detector.seal_baseline());
+
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while !path.exists() && Instant::now() < deadline {
+            std::thread::sleep(Duration::from_millis(10));
+        }
+        let saved = BaselineSet::load(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(saved.observed, 1);
+    }
+
+    #[test]
+    fn test_ua_path_rule_scoped_to_path() {
+        let mut config = test_config(BotDetectionMode::Block);
+        config.ua_path_rules = vec![
+            UaPathRule {
+                ua_substring: "Curl/".to_string(),
+                path_prefix: "/health".to_string(),
+                action: UaPathAction::Allow,
+            },
+            UaPathRule {
+                ua_substring: "Chrome".to_string(),
+                path_prefix: "/internal".to_string(),
+                action: UaPathAction::Block,
+            },
+        ];
+        let detector = BotDetector::new(config);
+
+        let probe = curl_headers();
+        assert!(matches!(
+            detector.ua_path_override(&probe, "/health/live"),
+            Some(BotCheckResult::Allow)
+        ));
+        // Elsewhere the probe is scored and blocked as usual
+        assert!(detector.ua_path_override(&probe, "/admin").is_none());
+        assert!(matches!(
+            detector.check("1.2.3.4", &probe, "GET", "/", None),
+            BotCheckResult::Block
+        ));
+
+        assert!(matches!(
+            detector.ua_path_override(&browser_headers(), "/internal/stats"),
+            Some(BotCheckResult::Block)
+        ));
+        assert!(detector.ua_path_override(&browser_headers(), "/health").is_none());
+
+        // Re-spelled paths hit the same rules; neighbouring paths don't
+        for path in ["//internal/stats", "/%69nternal", "/Internal"] {
+            assert!(
+                matches!(
+                    detector.ua_path_override(&browser_headers(), path),
+                    Some(BotCheckResult::Block)
+                ),
+                "{}",
+                path
+            );
+        }
+        assert!(detector.ua_path_override(&probe, "/healthcheck-admin").is_none());
+    }
+
+    #[test]
+    fn test_session_score_kept_for_allowed_requests() {
+        let detector = BotDetector::new(test_config(BotDetectionMode::Block));
+        assert!(detector.session_score("1.2.3.4").is_none());
+
+        let result = detector.check("1.2.3.4", &browser_headers(), "GET", "/", None);
+        assert!(matches!(result, BotCheckResult::Allow));
+        let score = detector.session_score("1.2.3.4").unwrap();
+        assert!(score > 0.0 && score < 0.7, "{}", score);
+    }
+
     #[test]
     fn test_googlebot_always_allowed() {
         let detector = BotDetector::new(test_config(BotDetectionMode::Block));
@@ -243,17 +990,196 @@ mod tests {
                 "Mozilla/5.0 (compatible; Googlebot/2.1)".into(),
             ),
         ];
-        let result = detector.check("66.249.66.1", &headers, "GET", None);
+        let result = detector.check("66.249.66.1", &headers, "GET", "/", None);
+        assert!(matches!(result, BotCheckResult::Allow));
+    }
+
+    #[test]
+    fn test_explicit_challenge_ignores_mode_and_score() {
+        let detector = BotDetector::new(test_config(BotDetectionMode::Detect));
+        let result = detector.challenge("1.2.3.4", None);
+        assert!(matches!(result, BotCheckResult::Challenge(_)));
+
+        let mut config = test_config(BotDetectionMode::Challenge);
+        config.js_challenge.enabled = false;
+        let detector = BotDetector::new(config);
+        assert!(matches!(detector.challenge("1.2.3.4", None), BotCheckResult::Block));
+    }
+
+    #[test]
+    fn test_under_attack_challenges_browsers() {
+        let detector = BotDetector::new(test_config(BotDetectionMode::Detect));
+        let result = detector.check_under_attack("1.2.3.4", &browser_headers(), None);
+        assert!(matches!(result, BotCheckResult::Challenge(_)));
+
+        // A Googlebot UA alone is challenged like anyone else
+        let googlebot = vec![(
+            "User-Agent".to_string(),
+            "Mozilla/5.0 (compatible; Googlebot/2.1)".to_string(),
+        )];
+        let result = detector.check_under_attack("66.249.66.1", &googlebot, None);
+        assert!(matches!(result, BotCheckResult::Challenge(_)));
+
+        let mut config = test_config(BotDetectionMode::Detect);
+        config.verified_bots = vec![layer7waf_common::VerifiedBot {
+            ua_substring: "googlebot".into(),
+            source_cidrs: vec!["66.249.64.0/19".into()],
+        }];
+        let detector = BotDetector::new(config);
+        let result = detector.check_under_attack("66.249.66.1", &googlebot, None);
         assert!(matches!(result, BotCheckResult::Allow));
+        let result = detector.check_under_attack("203.0.113.7", &googlebot, None);
+        assert!(matches!(result, BotCheckResult::Challenge(_)));
+    }
+
+    #[test]
+    fn test_xhr_gets_json_challenge() {
+        let detector = BotDetector::new(test_config(BotDetectionMode::Challenge));
+        let BotCheckResult::Challenge(html) = detector.challenge("1.2.3.4", None) else {
+            panic!("expected a challenge");
+        };
+
+        let resp = detector.challenge_response(
+            "1.2.3.4",
+            html.clone(),
+            Some("*/*"),
+            Some("XMLHttpRequest"),
+        );
+        assert_eq!(resp.status, 403);
+        assert_eq!(resp.content_type, "application/json");
+        let json: serde_json::Value = serde_json::from_str(&resp.body).unwrap();
+        assert_eq!(json["error"], "challenge_required");
+
+        let resp = detector.challenge_response(
+            "1.2.3.4",
+            html,
+            Some("text/html,application/xhtml+xml"),
+            None,
+        );
+        assert_eq!(resp.status, 200);
+        assert!(resp.body.contains("<!DOCTYPE html>"));
     }
 
     #[test]
     fn test_session_tracking() {
         let detector = BotDetector::new(test_config(BotDetectionMode::Detect));
         assert_eq!(detector.session_count(), 0);
-        detector.check("1.2.3.4", &browser_headers(), "GET", None);
+        detector.check("1.2.3.4", &browser_headers(), "GET", "/", None);
         assert_eq!(detector.session_count(), 1);
-        detector.check("5.6.7.8", &browser_headers(), "GET", None);
+        detector.check("5.6.7.8", &browser_headers(), "GET", "/", None);
         assert_eq!(detector.session_count(), 2);
     }
+
+    #[test]
+    fn test_flagged_sessions_listed_and_cleared() {
+        let detector = BotDetector::new(test_config(BotDetectionMode::Detect));
+        detector.check("1.2.3.4", &curl_headers(), "GET", "/", None);
+        detector.check("5.6.7.8", &browser_headers(), "GET", "/", None);
+        detector.check("9.9.9.9", &curl_headers(), "GET", "/", None);
+
+        let mut flagged = detector.flagged_sessions();
+        flagged.sort_by(|a, b| a.0.cmp(&b.0));
+        let ips: Vec<&str> = flagged.iter().map(|(ip, _)| ip.as_str()).collect();
+        assert_eq!(ips, ["1.2.3.4", "9.9.9.9"]);
+        assert!(flagged.iter().all(|(_, score)| *score >= 0.7));
+
+        assert!(detector.clear_session("1.2.3.4"));
+        assert!(!detector.clear_session("1.2.3.4"));
+        assert_eq!(detector.session_score("1.2.3.4"), None);
+
+        assert_eq!(detector.clear_sessions(), 2);
+        assert_eq!(detector.session_count(), 0);
+        assert!(detector.flagged_sessions().is_empty());
+    }
+
+    #[test]
+    fn test_similar_clients_clustered() {
+        let detector = BotDetector::new(test_config(BotDetectionMode::Detect));
+        detector.check("1.2.3.4", &browser_headers(), "GET", "/", None);
+        // The same tool from another IP, one header randomized
+        let mut mutated = browser_headers();
+        mutated.push(("X-Nonce-91ac".into(), "1".into()));
+        detector.check("5.6.7.8", &mutated, "GET", "/", None);
+        detector.check("9.9.9.9", &curl_headers(), "GET", "/", None);
+
+        let similar = detector.similar_clients("1.2.3.4", 0.8);
+        let ips: Vec<&str> = similar.iter().map(|(ip, _)| ip.as_str()).collect();
+        assert_eq!(ips, ["5.6.7.8"]);
+        assert!(detector.similar_clients("10.0.0.1", 0.0).is_empty());
+    }
+
+    #[test]
+    fn test_map_footprints_match_tracked_keys() {
+        let detector = BotDetector::new(test_config(BotDetectionMode::Detect));
+        for ip in ["10.0.0.1", "10.0.0.2", "10.0.0.3"] {
+            detector.check(ip, &browser_headers(), "GET", "/", None);
+        }
+        detector.check("10.0.0.4", &curl_headers(), "GET", "/", None);
+
+        let entries: Vec<(String, usize)> = detector
+            .map_footprints()
+            .into_iter()
+            .map(|m| (m.name, m.entries))
+            .collect();
+        assert_eq!(
+            entries,
+            [
+                ("bot_sessions".to_string(), 4),
+                ("bot_header_order_cache".to_string(), 2),
+                ("bot_fingerprints".to_string(), 2),
+            ]
+        );
+        assert_eq!(detector.map_footprints()[0].entries, detector.session_count());
+    }
+
+    #[test]
+    fn test_session_capacity_overflow() {
+        use layer7waf_common::{FailurePolicy, KeyOverflowPolicy};
+
+        let cap = KeyCapacity::new(2, KeyOverflowPolicy::Evict, FailurePolicy::Allow);
+        let detector =
+            BotDetector::new(test_config(BotDetectionMode::Detect)).with_session_capacity(cap);
+        for ip in ["10.0.0.1", "10.0.0.2", "10.0.0.3", "10.0.0.4"] {
+            detector.check(ip, &browser_headers(), "GET", "/", None);
+        }
+        assert!(detector.session_count() <= 2);
+
+        let cap = KeyCapacity::new(1, KeyOverflowPolicy::Refuse, FailurePolicy::Block);
+        let detector =
+            BotDetector::new(test_config(BotDetectionMode::Block)).with_session_capacity(cap);
+        let first = detector.check("10.0.0.1", &browser_headers(), "GET", "/", None);
+        assert!(matches!(first, BotCheckResult::Allow));
+        let overflow = detector.check("10.0.0.2", &browser_headers(), "GET", "/", None);
+        assert!(matches!(overflow, BotCheckResult::Block));
+        assert_eq!(detector.session_count(), 1);
+    }
+
+    #[test]
+    fn test_dominant_fingerprint_raises_score() {
+        let mut config = test_config(BotDetectionMode::Detect);
+        config.dominant_fingerprint_share = Some(0.5);
+        let detector = BotDetector::new(config);
+
+        let score = |result| match result {
+            BotCheckResult::Detect { score } => score,
+            other => panic!("expected Detect, got {:?}", other),
+        };
+        let baseline = score(detector.check("10.0.0.1", &browser_headers(), "GET", "/", None));
+        for i in 0..150 {
+            let ip = format!("10.0.{}.{}", i / 250, i % 250);
+            detector.check(&ip, &browser_headers(), "GET", "/", None);
+        }
+        let raised = score(detector.check("10.1.0.1", &browser_headers(), "GET", "/", None));
+        assert!(raised >= baseline + DOMINANT_FINGERPRINT_BOOST - 1e-9);
+
+        // A rare fingerprint is left alone
+        let rare = score(detector.check("10.2.0.1", &curl_headers(), "GET", "/", None));
+        let fresh = BotDetector::new(test_config(BotDetectionMode::Detect));
+        assert_eq!(rare, score(fresh.check("10.2.0.1", &curl_headers(), "GET", "/", None)));
+
+        let top = detector.top_fingerprints(2);
+        assert_eq!(top.len(), 2);
+        assert_eq!(top[0].count, 152);
+        assert_eq!(top[1].count, 1);
+    }
 }
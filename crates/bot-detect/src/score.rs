@@ -47,6 +47,8 @@ mod tests {
             header_order_hash: "abc".into(),
             ua_family: "Chrome".into(),
             accept_hash: "def".into(),
+            header_values_hash: String::new(),
+            header_names: Vec::new(),
         }
     }
 
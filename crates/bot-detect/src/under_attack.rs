@@ -0,0 +1,100 @@
+use serde::Serialize;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// "Under attack" toggle: while active, every client that isn't allowlisted
+/// or a known good bot must pass the JS challenge, whatever its bot score.
+///
+/// Shared between the admin API, which flips it, and the proxy, which reads
+/// it on every request. Lives outside the reloadable components so a config
+/// reload doesn't switch it off.
+#[derive(Debug, Default)]
+pub struct UnderAttackMode {
+    enabled: AtomicBool,
+    /// Unix timestamp at which the mode switches itself off; 0 means never.
+    expires_at: AtomicU64,
+}
+
+/// Snapshot of [`UnderAttackMode`] for the admin API.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub struct UnderAttackStatus {
+    pub enabled: bool,
+    /// Unix timestamp at which the mode ends, if it was enabled for a duration.
+    pub expires_at: Option<u64>,
+}
+
+impl UnderAttackMode {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Turn the mode on, for `duration` if given or until disabled otherwise.
+    pub fn enable(&self, duration: Option<Duration>) {
+        let expires_at = duration.map(|d| unix_now() + d.as_secs().max(1)).unwrap_or(0);
+        self.expires_at.store(expires_at, Ordering::Relaxed);
+        self.enabled.store(true, Ordering::Release);
+    }
+
+    pub fn disable(&self) {
+        self.enabled.store(false, Ordering::Release);
+        self.expires_at.store(0, Ordering::Relaxed);
+    }
+
+    /// Whether the mode is on and hasn't expired.
+    pub fn is_active(&self) -> bool {
+        self.is_active_at(unix_now())
+    }
+
+    fn is_active_at(&self, now: u64) -> bool {
+        if !self.enabled.load(Ordering::Acquire) {
+            return false;
+        }
+        let expires_at = self.expires_at.load(Ordering::Relaxed);
+        expires_at == 0 || now < expires_at
+    }
+
+    pub fn status(&self) -> UnderAttackStatus {
+        let enabled = self.is_active();
+        let expires_at = self.expires_at.load(Ordering::Relaxed);
+        UnderAttackStatus {
+            enabled,
+            expires_at: (enabled && expires_at != 0).then_some(expires_at),
+        }
+    }
+}
+
+fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_enable_and_disable() {
+        let mode = UnderAttackMode::new();
+        assert!(!mode.is_active());
+
+        mode.enable(None);
+        assert!(mode.is_active());
+        assert_eq!(mode.status(), UnderAttackStatus { enabled: true, expires_at: None });
+
+        mode.disable();
+        assert!(!mode.is_active());
+    }
+
+    #[test]
+    fn test_duration_expires() {
+        let mode = UnderAttackMode::new();
+        mode.enable(Some(Duration::from_secs(60)));
+        let expires_at = mode.status().expires_at.unwrap();
+
+        assert!(mode.is_active());
+        assert!(mode.is_active_at(expires_at - 1));
+        assert!(!mode.is_active_at(expires_at));
+    }
+}
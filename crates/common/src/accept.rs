@@ -0,0 +1,28 @@
+//! Reading what a client asked for in its `Accept` header.
+
+/// Whether the client asked for JSON in its `Accept` header and didn't
+/// also ask for HTML, as browser navigations do. Such clients get errors,
+/// blocks and challenges as JSON rather than pages they can't use.
+pub fn wants_problem_json(accept: Option<&str>) -> bool {
+    accept.is_some_and(|accept| {
+        let accept = accept.to_ascii_lowercase();
+        (accept.contains("application/problem+json") || accept.contains("application/json"))
+            && !accept.contains("text/html")
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_json_wanted_only_without_html() {
+        assert!(wants_problem_json(Some("application/json")));
+        assert!(wants_problem_json(Some("Application/Problem+JSON")));
+        assert!(!wants_problem_json(Some(
+            "text/html,application/xhtml+xml,application/json;q=0.9"
+        )));
+        assert!(!wants_problem_json(Some("*/*")));
+        assert!(!wants_problem_json(None));
+    }
+}
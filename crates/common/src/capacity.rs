@@ -0,0 +1,207 @@
+//! Bounded per-key maps.
+//!
+//! The rate limiter, bot detector and anti-scraper each keep a `DashMap` keyed
+//! by client. Without a cap, an attacker rotating keys (e.g. forged
+//! `X-Forwarded-For`) can grow these maps without bound between cleanup ticks.
+
+use std::borrow::Borrow;
+use std::hash::Hash;
+use std::mem::size_of;
+
+use dashmap::DashMap;
+use serde::Serialize;
+
+use crate::config::{FailurePolicy, KeyOverflowPolicy, StateLimitsConfig};
+
+/// Fraction of a full map evicted in one pass, so eviction cost is amortised
+/// over many inserts instead of scanning the map on every new key.
+const EVICT_FRACTION: usize = 10;
+
+/// Assumed average length of a map key, most of which are client IPs.
+const APPROX_KEY_BYTES: usize = 24;
+
+/// What to do when a bounded map is full and a new key arrives.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OverflowAction {
+    /// Evict the least recently seen entries to make room.
+    EvictOldest,
+    /// Don't track the key and let the request through.
+    Allow,
+    /// Don't track the key and reject the request.
+    Block,
+}
+
+/// Entry cap for a per-key map plus the action taken on overflow.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct KeyCapacity {
+    pub max_keys: usize,
+    pub on_overflow: OverflowAction,
+}
+
+/// Result of asking a bounded map to admit a key.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Admission {
+    /// The key is (or may now be) tracked.
+    Admitted,
+    /// The map is full; the request should be allowed without tracking.
+    RefusedAllow,
+    /// The map is full; the request should be blocked.
+    RefusedBlock,
+}
+
+impl KeyCapacity {
+    /// Build a capacity from a configured cap and the overflow/failure policies.
+    pub fn new(max_keys: usize, overflow: KeyOverflowPolicy, failure: FailurePolicy) -> Self {
+        let on_overflow = match (overflow, failure) {
+            (KeyOverflowPolicy::Evict, _) => OverflowAction::EvictOldest,
+            (KeyOverflowPolicy::Refuse, FailurePolicy::Allow) => OverflowAction::Allow,
+            (KeyOverflowPolicy::Refuse, FailurePolicy::Block) => OverflowAction::Block,
+        };
+        Self { max_keys, on_overflow }
+    }
+
+    /// Capacities for the rate limiter, bot detector and anti-scraper maps.
+    pub fn from_state_limits(
+        limits: &StateLimitsConfig,
+        failure: FailurePolicy,
+    ) -> (Self, Self, Self) {
+        (
+            Self::new(limits.max_rate_limit_keys, limits.on_overflow, failure),
+            Self::new(limits.max_bot_sessions, limits.on_overflow, failure),
+            Self::new(limits.max_scraping_sessions, limits.on_overflow, failure),
+        )
+    }
+
+    /// Make sure `key` may be inserted into `map` without exceeding the cap.
+    ///
+    /// Existing keys are always admitted. `last_seen` extracts each entry's
+    /// recency, used to pick eviction victims.
+    pub fn admit<K, Q, V, T>(
+        &self,
+        map: &DashMap<K, V>,
+        key: &Q,
+        last_seen: impl Fn(&V) -> T,
+    ) -> Admission
+    where
+        K: Eq + Hash + Clone + Borrow<Q>,
+        Q: Eq + Hash + ?Sized,
+        T: Ord,
+    {
+        if map.len() < self.max_keys || map.contains_key(key) {
+            return Admission::Admitted;
+        }
+
+        match self.on_overflow {
+            OverflowAction::EvictOldest => {
+                let count = (self.max_keys / EVICT_FRACTION).max(1) + map.len() - self.max_keys;
+                evict_oldest(map, count, last_seen);
+                Admission::Admitted
+            }
+            OverflowAction::Allow => Admission::RefusedAllow,
+            OverflowAction::Block => Admission::RefusedBlock,
+        }
+    }
+}
+
+/// Entry count and rough memory use of one per-key map.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct MapFootprint {
+    pub name: String,
+    pub entries: usize,
+    /// `entries` times the approximate size of one entry: the key's `String`
+    /// and text plus the inline value. The map's own overhead and heap data
+    /// owned by the values aren't counted.
+    pub approx_bytes: usize,
+}
+
+impl MapFootprint {
+    /// Footprint of a `DashMap<String, V>` holding `entries` entries.
+    pub fn of<V>(name: impl Into<String>, entries: usize) -> Self {
+        let per_entry = size_of::<String>() + APPROX_KEY_BYTES + size_of::<V>();
+        Self {
+            name: name.into(),
+            entries,
+            approx_bytes: entries * per_entry,
+        }
+    }
+}
+
+/// Remove the `count` least recently seen entries from `map`.
+fn evict_oldest<K, V, T>(map: &DashMap<K, V>, count: usize, last_seen: impl Fn(&V) -> T)
+where
+    K: Eq + Hash + Clone,
+    T: Ord,
+{
+    let mut entries: Vec<(T, K)> = map
+        .iter()
+        .map(|e| (last_seen(e.value()), e.key().clone()))
+        .collect();
+    let count = count.min(entries.len());
+    if count == 0 {
+        return;
+    }
+    if count < entries.len() {
+        entries.select_nth_unstable_by(count - 1, |(a, _), (b, _)| a.cmp(b));
+    }
+    for (_, key) in entries.into_iter().take(count) {
+        map.remove(&key);
+    }
+    tracing::debug!(evicted = count, remaining = map.len(), "evicted oldest keys over capacity");
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::{Duration, Instant};
+
+    fn filled(n: usize) -> DashMap<String, Instant> {
+        let map = DashMap::new();
+        let base = Instant::now();
+        for i in 0..n {
+            map.insert(format!("k{}", i), base + Duration::from_millis(i as u64));
+        }
+        map
+    }
+
+    #[test]
+    fn test_footprint_scales_with_entries() {
+        let empty = MapFootprint::of::<u64>("m", 0);
+        assert_eq!(empty.approx_bytes, 0);
+
+        let one = MapFootprint::of::<u64>("m", 1);
+        let many = MapFootprint::of::<u64>("m", 100);
+        assert_eq!(many.entries, 100);
+        assert_eq!(many.approx_bytes, 100 * one.approx_bytes);
+        assert!(one.approx_bytes >= size_of::<String>() + size_of::<u64>());
+    }
+
+    #[test]
+    fn test_evicts_oldest_when_full() {
+        let map = filled(10);
+        let cap = KeyCapacity::new(10, KeyOverflowPolicy::Evict, FailurePolicy::Allow);
+
+        assert_eq!(cap.admit(&map, "new", |v| *v), Admission::Admitted);
+        assert_eq!(map.len(), 9);
+        assert!(!map.contains_key("k0"), "oldest entry should be evicted");
+        assert!(map.contains_key("k9"));
+    }
+
+    #[test]
+    fn test_existing_key_admitted_when_full() {
+        let map = filled(10);
+        let cap = KeyCapacity::new(10, KeyOverflowPolicy::Refuse, FailurePolicy::Block);
+        assert_eq!(cap.admit(&map, "k3", |v| *v), Admission::Admitted);
+        assert_eq!(map.len(), 10);
+    }
+
+    #[test]
+    fn test_refuse_follows_failure_policy() {
+        let map = filled(5);
+        let allow = KeyCapacity::new(5, KeyOverflowPolicy::Refuse, FailurePolicy::Allow);
+        let block = KeyCapacity::new(5, KeyOverflowPolicy::Refuse, FailurePolicy::Block);
+
+        assert_eq!(allow.admit(&map, "new", |v| *v), Admission::RefusedAllow);
+        assert_eq!(block.admit(&map, "new", |v| *v), Admission::RefusedBlock);
+        assert_eq!(map.len(), 5, "refusal must not evict");
+    }
+}
@@ -1,4 +1,6 @@
+use chrono::{DateTime, Utc};
 use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
 use std::path::PathBuf;
 
 /// Top-level WAF configuration.
@@ -18,6 +20,159 @@ pub struct AppConfig {
     pub anti_scraping: AntiScrapingConfig,
     #[serde(default)]
     pub geoip: GeoIpConfig,
+    #[serde(default = "default_failure_policy")]
+    pub failure_policy: FailurePolicy,
+    #[serde(default)]
+    pub state_limits: StateLimitsConfig,
+    #[serde(default)]
+    pub security_headers: SecurityHeadersConfig,
+    /// Origin/Referer checks for state-changing requests; the first rule
+    /// whose `path_prefix` matches applies.
+    #[serde(default)]
+    pub csrf_protection: Vec<CsrfRule>,
+    #[serde(default)]
+    pub security: SecurityConfig,
+    /// Load balancer or orchestrator health checks answered before any
+    /// security check. Unset treats them as normal traffic.
+    #[serde(default)]
+    pub health_probe: Option<HealthProbeConfig>,
+}
+
+/// How the protection layers interact when they disagree.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SecurityConfig {
+    /// Whether an IP on the `ip_reputation` allowlist skips GeoIP along
+    /// with every other check. When `false`, allowlisted IPs from a blocked
+    /// country are still blocked; the allowlist then only exempts them from
+    /// the later checks.
+    #[serde(default = "default_true")]
+    pub allowlist_overrides_geoip: bool,
+    /// Whether IP reputation, GeoIP, bot detection and anti-scraping each
+    /// block on their own or only feed a weighted score.
+    #[serde(default = "default_decision_mode")]
+    pub decision_mode: DecisionMode,
+    /// Weights and threshold of the `aggregate` decision mode.
+    #[serde(default)]
+    pub aggregation: AggregationConfig,
+    /// Which challenge bot detection and GeoIP serve, by client capability.
+    #[serde(default)]
+    pub challenge_selection: ChallengeSelectionConfig,
+}
+
+impl Default for SecurityConfig {
+    fn default() -> Self {
+        Self {
+            allowlist_overrides_geoip: true,
+            decision_mode: default_decision_mode(),
+            aggregation: AggregationConfig::default(),
+            challenge_selection: ChallengeSelectionConfig::default(),
+        }
+    }
+}
+
+/// Picks between the bot-detection JS challenge and the anti-scraping
+/// CAPTCHA when bot detection or GeoIP challenges a client. A client that
+/// can't run JS would only loop on the proof-of-work page. The defaults
+/// always serve the JS challenge.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ChallengeSelectionConfig {
+    /// Challenge for browsers that can run the JS challenge.
+    #[serde(default = "default_challenge_type")]
+    pub js_capable: ChallengeType,
+    /// Challenge for clients that can't: a `User-Agent` containing one of
+    /// `no_js_user_agents`, or an `Accept` header without `text/html`.
+    #[serde(default = "default_challenge_type")]
+    pub no_js: ChallengeType,
+    /// Case-insensitive `User-Agent` substrings of clients without JS.
+    #[serde(default = "default_no_js_user_agents")]
+    pub no_js_user_agents: Vec<String>,
+}
+
+impl Default for ChallengeSelectionConfig {
+    fn default() -> Self {
+        Self {
+            js_capable: default_challenge_type(),
+            no_js: default_challenge_type(),
+            no_js_user_agents: default_no_js_user_agents(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ChallengeType {
+    /// The bot-detection proof-of-work page.
+    JsChallenge,
+    /// The anti-scraping math CAPTCHA; blocks if anti-scraping or its
+    /// CAPTCHA is disabled.
+    Captcha,
+    /// No challenge: reject the request.
+    Block,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum DecisionMode {
+    /// The first subsystem to block a request rejects it.
+    ShortCircuit,
+    /// Each subsystem contributes a 0.0-1.0 sub-score times its weight; the
+    /// request is blocked only when the sum reaches the threshold.
+    Aggregate,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AggregationConfig {
+    /// Weighted sum at which a request is blocked. With the default weights
+    /// of 1.0, one subsystem at full strength still blocks alone while
+    /// weaker signals must combine.
+    #[serde(default = "default_aggregation_threshold")]
+    pub threshold: f64,
+    #[serde(default)]
+    pub weights: SignalWeights,
+}
+
+impl Default for AggregationConfig {
+    fn default() -> Self {
+        Self {
+            threshold: default_aggregation_threshold(),
+            weights: SignalWeights::default(),
+        }
+    }
+}
+
+/// Weight of each subsystem's sub-score in the `aggregate` decision mode.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SignalWeights {
+    #[serde(default = "default_signal_weight")]
+    pub ip_reputation: f64,
+    #[serde(default = "default_signal_weight")]
+    pub geoip: f64,
+    #[serde(default = "default_signal_weight")]
+    pub bot: f64,
+    #[serde(default = "default_signal_weight")]
+    pub scraping: f64,
+}
+
+impl Default for SignalWeights {
+    fn default() -> Self {
+        Self {
+            ip_reputation: default_signal_weight(),
+            geoip: default_signal_weight(),
+            bot: default_signal_weight(),
+            scraping: default_signal_weight(),
+        }
+    }
+}
+
+/// What the proxy does when a security decision cannot be made, e.g. when
+/// the client IP cannot be determined.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum FailurePolicy {
+    /// Fail open: let the request through, skipping the affected checks.
+    Allow,
+    /// Fail closed: reject the request.
+    Block,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -27,6 +182,230 @@ pub struct ServerConfig {
     pub tls: Option<TlsConfig>,
     #[serde(default)]
     pub admin: AdminConfig,
+    /// OpenTelemetry span export; requires the proxy's `otel` feature.
+    #[serde(default)]
+    pub tracing: Option<TracingConfig>,
+    /// Total time allowed for a request through the proxy, including the
+    /// upstream exchange; exceeding it returns 504. Unset means no deadline.
+    #[serde(default)]
+    pub request_timeout_ms: Option<u64>,
+    /// Record how long each protection subsystem takes to decide on a
+    /// request, as the `layer7waf_subsystem_duration_seconds` histogram.
+    #[serde(default)]
+    pub subsystem_timing: bool,
+    /// Requests processed at once before new ones are rejected with 503
+    /// ahead of any protection stage; allowlisted IPs are exempt. Unset
+    /// means no limit.
+    #[serde(default)]
+    pub max_concurrent_requests: Option<usize>,
+    /// Send allowed requests upstream with an `X-L7W-Trust-Score` header
+    /// (0-100) derived from the bot, scraping and reputation checks; left
+    /// off when none of them ran. A client's own header is always removed.
+    #[serde(default)]
+    pub emit_trust_score: bool,
+    /// Response content types streamed through untouched: their bodies are
+    /// never buffered, scanned or rewritten. `type/*` matches a whole type.
+    #[serde(default = "default_never_buffer_content_types")]
+    pub never_buffer_content_types: Vec<String>,
+    /// Most `X-Forwarded-For` entries examined when finding the client IP.
+    #[serde(default = "default_max_forwarded_hops")]
+    pub max_forwarded_hops: usize,
+    /// What to do with an `X-Forwarded-For` longer than `max_forwarded_hops`.
+    #[serde(default = "default_forwarded_hops_overflow")]
+    pub forwarded_hops_overflow: ForwardedHopsOverflow,
+    /// Expect a PROXY protocol (v1 or v2) header on every connection and
+    /// take the client address from it. `X-Forwarded-For` is then ignored,
+    /// since the load balancer in front doesn't set it.
+    #[serde(default)]
+    pub proxy_protocol: bool,
+    /// Headers a CDN sets to the client address, such as `CF-Connecting-IP`
+    /// or `True-Client-IP`, in order of preference. The first one present
+    /// is used ahead of `X-Forwarded-For`, but only on connections from
+    /// `trusted_proxies`.
+    #[serde(default)]
+    pub client_ip_headers: Vec<String>,
+    /// CIDRs of the proxies whose `client_ip_headers` are believed.
+    #[serde(default)]
+    pub trusted_proxies: Vec<String>,
+    /// Reject with 400 requests whose body framing is ambiguous: a
+    /// `Content-Length` with a `Transfer-Encoding`, several `Content-Length`
+    /// values, or a non-numeric one.
+    #[serde(default)]
+    pub strict_framing: bool,
+    /// Per-request access log line.
+    #[serde(default)]
+    pub access_log: AccessLogConfig,
+    /// Sampled request metadata written for offline rule tuning.
+    #[serde(default)]
+    pub capture: CaptureConfig,
+    /// Warning logged when the share of 5xx responses surges.
+    #[serde(default)]
+    pub error_rate_alert: ErrorRateAlertConfig,
+    /// Response served instead of a bare connect failure when no server of
+    /// the request's upstream is available.
+    #[serde(default)]
+    pub unavailable_response: UnavailableResponseConfig,
+    /// Planned maintenance switch, flippable through the admin API.
+    #[serde(default)]
+    pub maintenance: MaintenanceConfig,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AccessLogConfig {
+    #[serde(default = "default_access_log_format")]
+    pub format: AccessLogFormat,
+    /// File `common`/`combined` lines are appended to; stdout when unset.
+    /// JSON entries always go through the tracing log.
+    #[serde(default)]
+    pub path: Option<PathBuf>,
+}
+
+impl Default for AccessLogConfig {
+    fn default() -> Self {
+        Self {
+            format: default_access_log_format(),
+            path: None,
+        }
+    }
+}
+
+/// Request capture: a sample of requests is appended to a JSONL file as
+/// anonymized metadata (no client IP, bodies or header values), for
+/// replaying against rule changes.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CaptureConfig {
+    #[serde(default)]
+    pub enabled: bool,
+    /// Fraction of requests captured, 0.0-1.0.
+    #[serde(default = "default_capture_sample_rate")]
+    pub sample_rate: f64,
+    /// JSONL file captured requests are appended to; required when enabled.
+    #[serde(default)]
+    pub path: Option<PathBuf>,
+}
+
+impl Default for CaptureConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            sample_rate: default_capture_sample_rate(),
+            path: None,
+        }
+    }
+}
+
+/// Warn when more than `threshold` of the responses over the last
+/// `window_secs` were 5xx, once there have been `min_responses`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ErrorRateAlertConfig {
+    #[serde(default)]
+    pub enabled: bool,
+    #[serde(default = "default_error_rate_window_secs")]
+    pub window_secs: u64,
+    /// Fraction of responses, 0.0-1.0.
+    #[serde(default = "default_error_rate_threshold")]
+    pub threshold: f64,
+    #[serde(default = "default_error_rate_min_responses")]
+    pub min_responses: u64,
+}
+
+impl Default for ErrorRateAlertConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            window_secs: default_error_rate_window_secs(),
+            threshold: default_error_rate_threshold(),
+            min_responses: default_error_rate_min_responses(),
+        }
+    }
+}
+
+/// Maintenance page for requests whose upstream has no server available,
+/// e.g. every server failed its last connection attempt.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct UnavailableResponseConfig {
+    #[serde(default = "default_unavailable_status")]
+    pub status: u16,
+    #[serde(default = "default_unavailable_content_type")]
+    pub content_type: String,
+    #[serde(default = "default_unavailable_body")]
+    pub body: String,
+}
+
+impl Default for UnavailableResponseConfig {
+    fn default() -> Self {
+        Self {
+            status: default_unavailable_status(),
+            content_type: default_unavailable_content_type(),
+            body: default_unavailable_body(),
+        }
+    }
+}
+
+/// While enabled, every proxied request except those to `bypass_paths` is
+/// answered with a 503 and `Retry-After`, leaving the rest of the config in
+/// place. The admin API is served separately and stays reachable.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct MaintenanceConfig {
+    #[serde(default)]
+    pub enabled: bool,
+    #[serde(default = "default_maintenance_retry_after_secs")]
+    pub retry_after_secs: u64,
+    #[serde(default = "default_unavailable_content_type")]
+    pub content_type: String,
+    #[serde(default = "default_unavailable_body")]
+    pub body: String,
+    /// Path prefixes proxied as usual during maintenance, e.g. `/status`.
+    #[serde(default)]
+    pub bypass_paths: Vec<String>,
+}
+
+impl Default for MaintenanceConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            retry_after_secs: default_maintenance_retry_after_secs(),
+            content_type: default_unavailable_content_type(),
+            body: default_unavailable_body(),
+            bypass_paths: vec![],
+        }
+    }
+}
+
+/// Layout of the per-request access log.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum AccessLogFormat {
+    /// Structured `request completed` tracing event.
+    Json,
+    /// NCSA Common Log Format: `%h %l %u %t "%r" %>s %b`.
+    Common,
+    /// Common Log Format followed by the quoted Referer and User-Agent.
+    Combined,
+}
+
+/// Handling of an `X-Forwarded-For` header with more entries than
+/// `server.max_forwarded_hops`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ForwardedHopsOverflow {
+    /// Only look at the last `max_forwarded_hops` entries, the ones added
+    /// by the proxies nearest to us.
+    Truncate,
+    /// Reject the request with 400.
+    Reject,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TracingConfig {
+    /// OTLP/HTTP traces endpoint.
+    #[serde(default = "default_otlp_endpoint")]
+    pub endpoint: String,
+    #[serde(default = "default_service_name")]
+    pub service_name: String,
+    /// Fraction of new traces sampled, 0.0-1.0.
+    #[serde(default = "default_sample_rate")]
+    pub sample_rate: f64,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -41,6 +420,16 @@ pub struct AdminConfig {
     pub listen: String,
     #[serde(default = "default_true")]
     pub dashboard: bool,
+    /// Serve the `/api/debug/*` routes, which expose per-client limiter
+    /// internals. They require `api_token`.
+    #[serde(default)]
+    pub debug_endpoints: bool,
+    /// Bearer token the `/api/debug/*` routes require. Once set, the routes
+    /// that change config or state (config updates and rollbacks, custom
+    /// rules, under-attack mode, session clearing, cleanup) require it too;
+    /// without it they are open to anyone who can reach `listen`.
+    #[serde(default)]
+    pub api_token: Option<String>,
 }
 
 impl Default for AdminConfig {
@@ -48,6 +437,8 @@ impl Default for AdminConfig {
         Self {
             listen: default_admin_listen(),
             dashboard: true,
+            debug_endpoints: false,
+            api_token: None,
         }
     }
 }
@@ -86,6 +477,124 @@ pub struct RouteConfig {
     pub waf: RouteWafConfig,
     #[serde(default)]
     pub rate_limit: Option<RouteRateLimitConfig>,
+    #[serde(default = "default_security_headers_mode")]
+    pub security_headers_mode: SecurityHeadersMode,
+    #[serde(default = "default_forward_headers_policy")]
+    pub forward_headers_policy: ForwardHeadersPolicy,
+    /// Header names for `forward_headers_policy` (case-insensitive).
+    #[serde(default)]
+    pub forward_headers: Vec<String>,
+    /// Honeypot trap prefix for this route, overriding
+    /// `anti_scraping.honeypot.trap_path_prefix`. Must lie under
+    /// `path_prefix` so trap hits are matched back to this route.
+    #[serde(default)]
+    pub trap_path_prefix: Option<String>,
+    #[serde(default)]
+    pub geoip: RouteGeoIpConfig,
+}
+
+/// Per-route GeoIP switch. Routes with it off skip the lookup entirely.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RouteGeoIpConfig {
+    #[serde(default = "default_true")]
+    pub enabled: bool,
+}
+
+impl Default for RouteGeoIpConfig {
+    fn default() -> Self {
+        Self { enabled: true }
+    }
+}
+
+/// Which client request headers a route forwards to its upstream.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ForwardHeadersPolicy {
+    /// Forward every client header.
+    All,
+    /// Forward only the headers in `forward_headers`.
+    Allowlist,
+    /// Forward everything except the headers in `forward_headers`.
+    Denylist,
+}
+
+/// Response headers added to every proxied response.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SecurityHeadersConfig {
+    #[serde(default)]
+    pub enabled: bool,
+    #[serde(default = "default_security_headers")]
+    pub headers: BTreeMap<String, String>,
+    /// Upstream response headers that reveal the backend's software, removed
+    /// from every response whether or not `enabled` is set.
+    #[serde(default = "default_strip_response_headers")]
+    pub strip_response_headers: Vec<String>,
+    /// `Server` value sent in place of the upstream's.
+    #[serde(default)]
+    pub server_header_override: Option<String>,
+}
+
+impl Default for SecurityHeadersConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            headers: default_security_headers(),
+            strip_response_headers: default_strip_response_headers(),
+            server_header_override: None,
+        }
+    }
+}
+
+/// Requests to `path_prefix` using one of `methods` must carry an `Origin`
+/// (or, failing that, `Referer`) header whose origin is in `allowed_origins`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CsrfRule {
+    pub path_prefix: String,
+    /// Origins compared on scheme, host and port. A bare host, optionally
+    /// with a port (`example.com`, `app.example.com:8443`), means https;
+    /// give the scheme (`http://localhost:3000`) for anything else.
+    pub allowed_origins: Vec<String>,
+    #[serde(default = "default_csrf_methods")]
+    pub methods: Vec<String>,
+}
+
+/// A health probe: a request for `path` whose User-Agent contains
+/// `user_agent` (case-insensitive), from a socket address in `source_cidrs`.
+/// Matching requests skip every security subsystem and the request metrics;
+/// with `action: pass`, only a bare GET or HEAD with no query, body or
+/// forwarding headers does.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct HealthProbeConfig {
+    pub path: String,
+    pub user_agent: String,
+    /// Networks the probes connect from, e.g. the node or load balancer
+    /// range. Checked against the socket peer, never `X-Forwarded-For`.
+    pub source_cidrs: Vec<String>,
+    #[serde(default = "default_health_probe_action")]
+    pub action: HealthProbeAction,
+}
+
+/// How a matching health probe is handled.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum HealthProbeAction {
+    /// Answer 200 from the proxy itself.
+    Respond,
+    /// Forward to the route's upstream, so the probe checks the backend too.
+    Pass,
+}
+
+/// How a route applies the configured security headers when the upstream
+/// response already carries one of them.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SecurityHeadersMode {
+    /// Only add headers the upstream didn't set.
+    Append,
+    /// Overwrite any upstream value.
+    Replace,
+    /// Don't touch this route's responses.
+    Skip,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -94,6 +603,10 @@ pub struct RouteWafConfig {
     pub enabled: bool,
     #[serde(default = "default_waf_mode")]
     pub mode: WafMode,
+    /// Name of a `waf.rulesets` entry to evaluate this route's requests
+    /// with instead of `waf.rules`.
+    #[serde(default)]
+    pub ruleset: Option<String>,
 }
 
 impl Default for RouteWafConfig {
@@ -101,6 +614,7 @@ impl Default for RouteWafConfig {
         Self {
             enabled: true,
             mode: WafMode::Block,
+            ruleset: None,
         }
     }
 }
@@ -119,6 +633,36 @@ pub struct RouteRateLimitConfig {
     pub burst: u64,
     #[serde(default = "default_rate_limit_algorithm")]
     pub algorithm: RateLimitAlgorithm,
+    /// Idle seconds before a client key is evicted; `None` uses the algorithm default.
+    #[serde(default)]
+    pub key_ttl_secs: Option<u64>,
+    /// Sliding window only: lower the limit of clients that keep hitting it.
+    #[serde(default)]
+    pub adaptive: Option<AdaptiveLimitConfig>,
+}
+
+/// Per-client tightening of a sliding window limit. Each window in which a
+/// client was refused multiplies its limit by `tighten_factor`, down to
+/// `floor` times the configured limit; each window without a refusal adds
+/// `restore_step` back, up to the configured limit.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct AdaptiveLimitConfig {
+    #[serde(default = "default_adaptive_tighten_factor")]
+    pub tighten_factor: f64,
+    #[serde(default = "default_adaptive_restore_step")]
+    pub restore_step: f64,
+    #[serde(default = "default_adaptive_floor")]
+    pub floor: f64,
+}
+
+impl Default for AdaptiveLimitConfig {
+    fn default() -> Self {
+        Self {
+            tighten_factor: default_adaptive_tighten_factor(),
+            restore_step: default_adaptive_restore_step(),
+            floor: default_adaptive_floor(),
+        }
+    }
 }
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
@@ -126,24 +670,104 @@ pub struct RouteRateLimitConfig {
 pub enum RateLimitAlgorithm {
     TokenBucket,
     SlidingWindow,
+    /// At most `burst` requests per client per UTC day; `rps` is ignored.
+    DailyQuota,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct WafConfig {
     #[serde(default)]
     pub rules: Vec<String>,
+    /// Named alternatives to `rules` (rule file glob patterns) that routes
+    /// select with `waf.ruleset`. Each gets its own engine.
+    #[serde(default)]
+    pub rulesets: HashMap<String, Vec<String>>,
+    /// SecLang directives appended verbatim after the included rule files.
+    #[serde(default)]
+    pub inline_rules: Vec<String>,
     #[serde(default = "default_body_limit")]
     pub request_body_limit: usize,
     #[serde(default)]
     pub audit_log: AuditLogConfig,
+    #[serde(default = "default_max_custom_rules")]
+    pub max_custom_rules: usize,
+    /// Times the request URI is percent-decoded before the WAF sees it, up
+    /// to [`MAX_DECODE_DEPTH`]; 0 passes it as received. Escapes still left
+    /// after this many passes mark the request as suspicious.
+    #[serde(default = "default_decode_depth")]
+    pub decode_depth: u32,
+    /// Body bytes handed to the WAF at once across all requests, bounding
+    /// the memory copied into the Go side by concurrent large bodies.
+    #[serde(default = "default_body_budget_bytes")]
+    pub body_budget_bytes: usize,
+    /// What happens to a body arriving while `body_budget_bytes` is spent.
+    #[serde(default = "default_body_budget_action")]
+    pub on_body_budget_exhausted: BodyBudgetAction,
+    #[serde(default)]
+    pub rule_hit_sampling: RuleHitSamplingConfig,
+    /// Newly deployed rules whose matches only log, even in block mode,
+    /// until their grace period is over.
+    #[serde(default)]
+    pub rule_grace: Vec<RuleGraceConfig>,
+}
+
+/// A rule still in its non-blocking period after deployment.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RuleGraceConfig {
+    /// Rule ID, as in its `id:` action.
+    pub id: String,
+    pub deployed_at: DateTime<Utc>,
+    #[serde(default = "default_rule_grace_hours")]
+    pub grace_hours: u64,
+}
+
+/// Sampled logging of WAF rule hits, so a noisy rule's matched requests
+/// can be inspected without logging every hit.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RuleHitSamplingConfig {
+    #[serde(default)]
+    pub enabled: bool,
+    /// Log one in this many hits of each rule, starting with the first.
+    #[serde(default = "default_rule_hit_sample_rate")]
+    pub sample_rate: u64,
+    /// How often the per-rule hit counts since the last summary are logged.
+    #[serde(default = "default_rule_hit_summary_interval_secs")]
+    pub summary_interval_secs: u64,
+}
+
+impl Default for RuleHitSamplingConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            sample_rate: default_rule_hit_sample_rate(),
+            summary_interval_secs: default_rule_hit_summary_interval_secs(),
+        }
+    }
 }
 
+/// What happens to a body the WAF has no budget left to scan.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum BodyBudgetAction {
+    /// Pass the body through unscanned, logging it.
+    Skip,
+    /// Hold the request until earlier scans free enough budget.
+    Wait,
+}
+
+/// Upper bound for `waf.decode_depth`.
+pub const MAX_DECODE_DEPTH: u32 = 8;
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct AuditLogConfig {
     #[serde(default)]
     pub enabled: bool,
     #[serde(default = "default_audit_log_path")]
     pub path: PathBuf,
+    /// Bytes of the offending request kept in audit entries for blocked
+    /// requests, after redacting secrets. 0 disables previews.
+    #[serde(default)]
+    pub body_preview_bytes: usize,
 }
 
 impl Default for AuditLogConfig {
@@ -151,6 +775,7 @@ impl Default for AuditLogConfig {
         Self {
             enabled: false,
             path: default_audit_log_path(),
+            body_preview_bytes: 0,
         }
     }
 }
@@ -163,6 +788,35 @@ pub struct RateLimitConfig {
     pub default_rps: u64,
     #[serde(default = "default_burst")]
     pub default_burst: u64,
+    /// Canonicalize client keys (case, trailing dot, IPv6 form) before
+    /// limiting, so equivalent spellings share a bucket.
+    #[serde(default = "default_true")]
+    pub normalize_keys: bool,
+    /// New downstream connections per second allowed per client IP,
+    /// limited separately from requests. Unset disables the limit.
+    #[serde(default)]
+    pub connection_rps: Option<u64>,
+    #[serde(default = "default_connection_burst")]
+    pub connection_burst: u64,
+    /// Separate limit for authenticated requests, keyed by their session
+    /// identifier instead of the client IP. Unset limits every request by IP.
+    #[serde(default)]
+    pub authenticated: Option<AuthenticatedRateLimitConfig>,
+    /// Requests allowed per client IP per UTC day, on top of the per-second
+    /// limits and reset at midnight UTC. Unset disables the quota. Once
+    /// `state_limits.max_rate_limit_keys` clients are counted, new ones are
+    /// refused per `failure_policy` rather than evicting anyone.
+    #[serde(default)]
+    pub daily_quota: Option<u64>,
+    /// Hold a rate-limited request this long before answering its 429, so
+    /// the client can't retry straight away. 0 answers immediately. Must be
+    /// less than `server.request_timeout_ms` when that is set.
+    #[serde(default)]
+    pub tarpit_ms: u64,
+    /// Rate-limited requests held at once; beyond this the 429 is sent
+    /// immediately, so a flood can't tie up every connection.
+    #[serde(default = "default_tarpit_max_concurrent")]
+    pub tarpit_max_concurrent: usize,
 }
 
 impl Default for RateLimitConfig {
@@ -171,16 +825,105 @@ impl Default for RateLimitConfig {
             enabled: false,
             default_rps: default_rps(),
             default_burst: default_burst(),
+            normalize_keys: true,
+            connection_rps: None,
+            connection_burst: default_connection_burst(),
+            authenticated: None,
+            daily_quota: None,
+            tarpit_ms: 0,
+            tarpit_max_concurrent: default_tarpit_max_concurrent(),
         }
     }
 }
 
+/// Limit for requests carrying a session cookie or auth header. Only the
+/// identifier's presence is checked, not its validity, so each forged value
+/// would get a bucket of its own; all of a client IP's authenticated
+/// requests are therefore also capped at `per_ip_factor` times the limit.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AuthenticatedRateLimitConfig {
+    /// Cookie whose value identifies the session.
+    #[serde(default)]
+    pub cookie: Option<String>,
+    /// Header whose value identifies the session, e.g. `Authorization`.
+    /// Checked when the cookie is absent.
+    #[serde(default)]
+    pub header: Option<String>,
+    #[serde(flatten)]
+    pub limit: RouteRateLimitConfig,
+    /// Multiple of `rps` and `burst` allowed per client IP across all its
+    /// sessions, so rotating identifiers can't escape rate limiting.
+    #[serde(default = "default_authenticated_per_ip_factor")]
+    pub per_ip_factor: u64,
+}
+
+/// Caps on the per-client maps kept by the rate limiter, bot detector and
+/// anti-scraper, bounding memory between cleanup ticks.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct StateLimitsConfig {
+    #[serde(default = "default_max_tracked_keys")]
+    pub max_rate_limit_keys: usize,
+    #[serde(default = "default_max_tracked_keys")]
+    pub max_bot_sessions: usize,
+    #[serde(default = "default_max_tracked_keys")]
+    pub max_scraping_sessions: usize,
+    #[serde(default = "default_key_overflow_policy")]
+    pub on_overflow: KeyOverflowPolicy,
+}
+
+impl Default for StateLimitsConfig {
+    fn default() -> Self {
+        Self {
+            max_rate_limit_keys: default_max_tracked_keys(),
+            max_bot_sessions: default_max_tracked_keys(),
+            max_scraping_sessions: default_max_tracked_keys(),
+            on_overflow: default_key_overflow_policy(),
+        }
+    }
+}
+
+/// What a full per-client map does when a new key arrives.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum KeyOverflowPolicy {
+    /// Evict the least recently seen entries to make room.
+    Evict,
+    /// Don't track the new key; the request is allowed or blocked per `failure_policy`.
+    Refuse,
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct IpReputationConfig {
     #[serde(default)]
     pub blocklist: Option<PathBuf>,
     #[serde(default)]
     pub allowlist: Option<PathBuf>,
+    /// Scored feed entries (`<ip or cidr> <score>`) that raise a client's
+    /// risk score instead of blocking it. The bot score, held against
+    /// `bot_detection.score_threshold` and `challenge_threshold`, and the bot
+    /// signal anti-scraping weighs start no lower than the feed score; with
+    /// `security.decision_mode: aggregate` the bot score leaves it out, as
+    /// it counts under the `ip_reputation` weight instead.
+    #[serde(default)]
+    pub scores: Option<PathBuf>,
+    #[serde(default)]
+    pub waf_penalty: WafPenaltyConfig,
+    /// After a reload, entries new to the blocklist are only logged as
+    /// would-block for this many seconds before being enforced. 0 enforces
+    /// them immediately.
+    #[serde(default)]
+    pub blocklist_grace_secs: u64,
+    /// Verdict for IPv4 clients on neither list.
+    #[serde(default = "default_ip_family_policy")]
+    pub ipv4_policy: IpFamilyPolicy,
+    /// Verdict for IPv6 clients on neither list, e.g. `block` on a service
+    /// whose lists only cover IPv4.
+    #[serde(default = "default_ip_family_policy")]
+    pub ipv6_policy: IpFamilyPolicy,
+    /// Still audit requests from allowlisted clients, which skip every check,
+    /// with the action `allowlisted`, and count them.
+    #[serde(default)]
+    pub log_allowlisted: bool,
 }
 
 impl Default for IpReputationConfig {
@@ -188,32 +931,195 @@ impl Default for IpReputationConfig {
         Self {
             blocklist: None,
             allowlist: None,
+            scores: None,
+            waf_penalty: WafPenaltyConfig::default(),
+            blocklist_grace_secs: 0,
+            ipv4_policy: default_ip_family_policy(),
+            ipv6_policy: default_ip_family_policy(),
+            log_allowlisted: false,
+        }
+    }
+}
+
+/// Baseline verdict for one IP family, applied after the allowlist and
+/// blocklist have had no opinion.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum IpFamilyPolicy {
+    /// Leave the decision to the other checks.
+    Allow,
+    /// Block every address of the family that isn't allowlisted.
+    Block,
+}
+
+/// Temporarily block IPs that keep tripping WAF rules.
+///
+/// Each WAF block adds one to the client's count, which halves every
+/// `half_life_secs`; once it reaches `threshold` the IP is blocked outright
+/// for `block_secs`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct WafPenaltyConfig {
+    #[serde(default)]
+    pub enabled: bool,
+    #[serde(default = "default_waf_penalty_threshold")]
+    pub threshold: u32,
+    #[serde(default = "default_waf_penalty_half_life_secs")]
+    pub half_life_secs: u64,
+    #[serde(default = "default_waf_penalty_block_secs")]
+    pub block_secs: u64,
+    /// IPs tracked at once; the least recently penalized are evicted. Also
+    /// caps the IPs blocked at once, evicting the blocks ending soonest.
+    #[serde(default = "default_max_tracked_keys")]
+    pub max_tracked_ips: usize,
+}
+
+impl Default for WafPenaltyConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            threshold: default_waf_penalty_threshold(),
+            half_life_secs: default_waf_penalty_half_life_secs(),
+            block_secs: default_waf_penalty_block_secs(),
+            max_tracked_ips: default_max_tracked_keys(),
         }
     }
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
-pub struct BotDetectionConfig {
+pub struct BotDetectionConfig {
+    #[serde(default)]
+    pub enabled: bool,
+    #[serde(default = "default_bot_detection_mode")]
+    pub mode: BotDetectionMode,
+    #[serde(default)]
+    pub js_challenge: JsChallengeConfig,
+    #[serde(default = "default_score_threshold")]
+    pub score_threshold: f64,
+    /// In `block` mode, scores from this value up to `score_threshold` get a
+    /// JS challenge instead of passing. Unset keeps a single threshold.
+    #[serde(default)]
+    pub challenge_threshold: Option<f64>,
+    #[serde(default)]
+    pub known_bots_allowlist: Vec<String>,
+    /// Entries in the header-order fingerprint cache; 0 disables it.
+    #[serde(default = "default_header_order_cache_size")]
+    pub header_order_cache_size: usize,
+    /// Distinct fingerprints counted in the global histogram; 0 disables it.
+    #[serde(default = "default_fingerprint_histogram_size")]
+    pub fingerprint_histogram_size: usize,
+    /// Seconds after which every histogram count is halved, so the shares
+    /// reflect recent traffic rather than everything since startup.
+    #[serde(default = "default_fingerprint_half_life_secs")]
+    pub fingerprint_half_life_secs: u64,
+    /// Share of traffic (0.0-1.0) above which a single fingerprint's requests
+    /// get a higher bot score. Unset leaves scores unchanged.
+    #[serde(default)]
+    pub dominant_fingerprint_share: Option<f64>,
+    #[serde(default)]
+    pub learning: BotLearningConfig,
+    /// Allow or block specific User-Agent + path combinations before any
+    /// scoring. The first matching rule wins.
+    #[serde(default)]
+    pub ua_path_rules: Vec<UaPathRule>,
+    /// Path prefixes (e.g. `/checkout`) where a client must hold a valid
+    /// JS challenge cookie whatever its score; without one it is challenged.
+    /// Matched case-insensitively on whole segments of the decoded path.
+    #[serde(default)]
+    pub require_challenge_paths: Vec<String>,
+    /// User-Agent substrings (case-insensitive) that skip scoring: matching
+    /// clients are challenged in `challenge` mode and blocked in `block` mode.
+    #[serde(default)]
+    pub force_challenge_ua: Vec<String>,
+    /// Good bots exempt from under-attack challenges. A client claiming a
+    /// good bot's User-Agent is only exempt from a listed source range.
+    #[serde(default)]
+    pub verified_bots: Vec<VerifiedBot>,
+    /// Header names (case-insensitive) counted in the header-order
+    /// fingerprint; others are ignored. Empty counts every header.
+    #[serde(default)]
+    pub fingerprint_order_headers: Vec<String>,
+    /// Header names (case-insensitive) whose values, e.g. `Sec-CH-UA` or
+    /// `Sec-Fetch-Mode`, feed an extra fingerprint component. Empty leaves
+    /// it out.
+    #[serde(default)]
+    pub fingerprint_headers: Vec<String>,
+}
+
+impl Default for BotDetectionConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            mode: BotDetectionMode::Challenge,
+            js_challenge: JsChallengeConfig::default(),
+            score_threshold: default_score_threshold(),
+            challenge_threshold: None,
+            known_bots_allowlist: vec![],
+            header_order_cache_size: default_header_order_cache_size(),
+            fingerprint_histogram_size: default_fingerprint_histogram_size(),
+            fingerprint_half_life_secs: default_fingerprint_half_life_secs(),
+            dominant_fingerprint_share: None,
+            learning: BotLearningConfig::default(),
+            ua_path_rules: vec![],
+            require_challenge_paths: vec![],
+            force_challenge_ua: vec![],
+            verified_bots: vec![],
+            fingerprint_order_headers: vec![],
+            fingerprint_headers: vec![],
+        }
+    }
+}
+
+/// Fixed bot-detection verdict for requests whose User-Agent contains
+/// `ua_substring` (case-insensitive) and whose path lies under `path_prefix`,
+/// compared on whole segments of the decoded path.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct UaPathRule {
+    pub ua_substring: String,
+    pub path_prefix: String,
+    pub action: UaPathAction,
+}
+
+/// A good bot identified by a User-Agent substring (case-insensitive) and
+/// the source ranges its operator publishes.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct VerifiedBot {
+    pub ua_substring: String,
+    pub source_cidrs: Vec<String>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum UaPathAction {
+    Allow,
+    Block,
+}
+
+/// Learning mode: observe normal traffic for a while without blocking, then
+/// score fingerprints outside the learned baseline as more bot-like.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct BotLearningConfig {
     #[serde(default)]
     pub enabled: bool,
-    #[serde(default = "default_bot_detection_mode")]
-    pub mode: BotDetectionMode,
-    #[serde(default)]
-    pub js_challenge: JsChallengeConfig,
-    #[serde(default = "default_score_threshold")]
-    pub score_threshold: f64,
+    /// How long to observe traffic before sealing the baseline.
+    #[serde(default = "default_learning_duration")]
+    pub duration_secs: u64,
+    /// File the sealed baseline is saved to and loaded from on startup; a
+    /// baseline found there skips the learning period.
     #[serde(default)]
-    pub known_bots_allowlist: Vec<String>,
+    pub baseline_path: Option<String>,
+    /// Minimum share of observed requests (0.0-1.0) a fingerprint or UA
+    /// family needs to make it into the sealed baseline.
+    #[serde(default = "default_learning_min_share")]
+    pub min_share: f64,
 }
 
-impl Default for BotDetectionConfig {
+impl Default for BotLearningConfig {
     fn default() -> Self {
         Self {
             enabled: false,
-            mode: BotDetectionMode::Challenge,
-            js_challenge: JsChallengeConfig::default(),
-            score_threshold: default_score_threshold(),
-            known_bots_allowlist: vec![],
+            duration_secs: default_learning_duration(),
+            baseline_path: None,
+            min_share: default_learning_min_share(),
         }
     }
 }
@@ -236,6 +1142,9 @@ pub struct JsChallengeConfig {
     pub ttl_secs: u64,
     #[serde(default = "default_challenge_secret")]
     pub secret: String,
+    /// Attributes of the `__l7w_bc` cookie; `SameSite` defaults to `Lax`.
+    #[serde(default)]
+    pub cookie: CookieConfig,
 }
 
 impl Default for JsChallengeConfig {
@@ -245,6 +1154,97 @@ impl Default for JsChallengeConfig {
             difficulty: default_challenge_difficulty(),
             ttl_secs: default_challenge_ttl(),
             secret: default_challenge_secret(),
+            cookie: CookieConfig::default(),
+        }
+    }
+}
+
+/// `SameSite` attribute of a challenge or CAPTCHA cookie.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum CookieSameSite {
+    Strict,
+    Lax,
+    /// Sent on cross-site requests too; browsers require `secure` with it.
+    None,
+}
+
+impl CookieSameSite {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            CookieSameSite::Strict => "Strict",
+            CookieSameSite::Lax => "Lax",
+            CookieSameSite::None => "None",
+        }
+    }
+}
+
+/// Attributes of a cookie the browser sets after passing a challenge.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CookieConfig {
+    /// Only send the cookie over HTTPS. Turn off for plain-HTTP sites, where
+    /// browsers refuse to set a `Secure` cookie.
+    #[serde(default = "default_true")]
+    pub secure: bool,
+    /// Unset uses the cookie's own default.
+    #[serde(default)]
+    pub same_site: Option<CookieSameSite>,
+    /// Share the cookie across subdomains, e.g. `example.com`. Unset limits
+    /// it to the host that served the challenge.
+    #[serde(default)]
+    pub domain: Option<String>,
+    #[serde(default = "default_cookie_path")]
+    pub path: String,
+}
+
+impl CookieConfig {
+    /// The attribute part of the cookie string, e.g.
+    /// `; Path=/; SameSite=Lax; Secure`. `default_same_site` applies when
+    /// `same_site` is unset.
+    pub fn attributes(&self, default_same_site: CookieSameSite) -> String {
+        let mut attributes = format!("; Path={}", self.path);
+        if let Some(domain) = &self.domain {
+            attributes.push_str("; Domain=");
+            attributes.push_str(domain);
+        }
+        attributes.push_str("; SameSite=");
+        attributes.push_str(self.same_site.unwrap_or(default_same_site).as_str());
+        if self.secure {
+            attributes.push_str("; Secure");
+        }
+        attributes
+    }
+
+    fn validate(&self, name: &str) -> anyhow::Result<()> {
+        if self.same_site == Some(CookieSameSite::None) && !self.secure {
+            anyhow::bail!("{}.same_site none requires secure", name);
+        }
+        // Both end up inside a JS string literal on the challenge page
+        let safe =
+            |c: char| c.is_ascii_graphic() && !matches!(c, ';' | '\'' | '"' | '\\' | '<' | '>');
+        if !self.path.starts_with('/') || !self.path.chars().all(safe) {
+            anyhow::bail!("{}.path '{}' must be an absolute URL path", name, self.path);
+        }
+        if let Some(domain) = &self.domain {
+            let valid = !domain.is_empty()
+                && domain
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || c == '.' || c == '-');
+            if !valid {
+                anyhow::bail!("{}.domain '{}' is not a valid domain", name, domain);
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Default for CookieConfig {
+    fn default() -> Self {
+        Self {
+            secure: true,
+            same_site: None,
+            domain: None,
+            path: default_cookie_path(),
         }
     }
 }
@@ -263,6 +1263,18 @@ pub struct AntiScrapingConfig {
     pub obfuscation: ObfuscationConfig,
     #[serde(default = "default_scraping_score_threshold")]
     pub score_threshold: f64,
+    /// Distinct paths remembered per session; beyond this the unique path
+    /// count is estimated so memory per client stays bounded.
+    #[serde(default = "default_max_tracked_paths")]
+    pub max_tracked_paths: usize,
+    /// Which signals add to a session's scraping score, and how much.
+    #[serde(default)]
+    pub signals: ScrapingSignals,
+    /// Score a session loses per minute of good behavior (CAPTCHA solved,
+    /// at most one request a second, no new signals), so a reformed client
+    /// is eventually un-flagged. 0.0 keeps scores from decaying.
+    #[serde(default)]
+    pub score_decay_per_min: f64,
 }
 
 impl Default for AntiScrapingConfig {
@@ -274,6 +1286,51 @@ impl Default for AntiScrapingConfig {
             honeypot: HoneypotConfig::default(),
             obfuscation: ObfuscationConfig::default(),
             score_threshold: default_scraping_score_threshold(),
+            max_tracked_paths: default_max_tracked_paths(),
+            signals: ScrapingSignals::default(),
+            score_decay_per_min: 0.0,
+        }
+    }
+}
+
+/// Contributions to the scraping score. Each signal can be turned off on its
+/// own, e.g. `path_diversity` on sites where users legitimately page through
+/// many URLs.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ScrapingSignals {
+    /// A honeypot trap was requested.
+    #[serde(default = "default_true")]
+    pub trap_enabled: bool,
+    #[serde(default = "default_trap_weight")]
+    pub trap_weight: f64,
+    /// More than one request per second over the session.
+    #[serde(default = "default_true")]
+    pub request_rate_enabled: bool,
+    #[serde(default = "default_request_rate_weight")]
+    pub request_rate_weight: f64,
+    /// More than 20 distinct paths visited.
+    #[serde(default = "default_true")]
+    pub path_diversity_enabled: bool,
+    #[serde(default = "default_path_diversity_weight")]
+    pub path_diversity_weight: f64,
+    /// The bot-detection score, multiplied by the weight.
+    #[serde(default = "default_true")]
+    pub bot_score_enabled: bool,
+    #[serde(default = "default_bot_score_weight")]
+    pub bot_score_weight: f64,
+}
+
+impl Default for ScrapingSignals {
+    fn default() -> Self {
+        Self {
+            trap_enabled: true,
+            trap_weight: default_trap_weight(),
+            request_rate_enabled: true,
+            request_rate_weight: default_request_rate_weight(),
+            path_diversity_enabled: true,
+            path_diversity_weight: default_path_diversity_weight(),
+            bot_score_enabled: true,
+            bot_score_weight: default_bot_score_weight(),
         }
     }
 }
@@ -294,6 +1351,15 @@ pub struct CaptchaConfig {
     pub ttl_secs: u64,
     #[serde(default = "default_challenge_secret")]
     pub secret: String,
+    /// Wrong answers allowed before the IP is hard-blocked; 0 disables the lockout.
+    #[serde(default = "default_max_captcha_failures")]
+    pub max_captcha_failures: u32,
+    #[serde(default = "default_captcha_lockout_secs")]
+    pub lockout_secs: u64,
+    /// Attributes of the `__l7w_captcha` cookie; `SameSite` defaults to
+    /// `Strict`.
+    #[serde(default)]
+    pub cookie: CookieConfig,
 }
 
 impl Default for CaptchaConfig {
@@ -302,6 +1368,9 @@ impl Default for CaptchaConfig {
             enabled: true,
             ttl_secs: default_captcha_ttl(),
             secret: default_challenge_secret(),
+            max_captcha_failures: default_max_captcha_failures(),
+            lockout_secs: default_captcha_lockout_secs(),
+            cookie: CookieConfig::default(),
         }
     }
 }
@@ -327,11 +1396,22 @@ impl Default for HoneypotConfig {
 pub struct ObfuscationConfig {
     #[serde(default)]
     pub enabled: bool,
+    /// How long the client a watermark was served to is remembered, for
+    /// identifying scraped content that turns up later.
+    #[serde(default = "default_watermark_ttl_secs")]
+    pub watermark_ttl_secs: u64,
+    /// Watermarks remembered at once; the least recently served are evicted.
+    #[serde(default = "default_max_tracked_keys")]
+    pub max_watermarks: usize,
 }
 
 impl Default for ObfuscationConfig {
     fn default() -> Self {
-        Self { enabled: false }
+        Self {
+            enabled: false,
+            watermark_ttl_secs: default_watermark_ttl_secs(),
+            max_watermarks: default_max_tracked_keys(),
+        }
     }
 }
 
@@ -349,6 +1429,27 @@ pub struct GeoIpConfig {
     pub mode: GeoIpMode,
     #[serde(default = "default_geoip_default_action")]
     pub default_action: GeoIpDefaultAction,
+    /// Handling for clients whose country can't be determined. Falls back to
+    /// `default_action` when unset.
+    #[serde(default)]
+    pub unknown_action: Option<GeoIpUnknownAction>,
+    /// What to do when the database fails its load-time sanity lookup.
+    #[serde(default = "default_geoip_self_test")]
+    pub self_test: GeoIpSelfTest,
+    /// Client IPs whose GeoIP decision is cached until the database is
+    /// reloaded; 0 disables the cache.
+    #[serde(default = "default_geoip_decision_cache_size")]
+    pub decision_cache_size: usize,
+}
+
+impl GeoIpConfig {
+    /// The action applied to unknown-country clients.
+    pub fn effective_unknown_action(&self) -> GeoIpUnknownAction {
+        self.unknown_action.unwrap_or(match self.default_action {
+            GeoIpDefaultAction::Allow => GeoIpUnknownAction::Allow,
+            GeoIpDefaultAction::Block => GeoIpUnknownAction::Block,
+        })
+    }
 }
 
 impl Default for GeoIpConfig {
@@ -360,6 +1461,9 @@ impl Default for GeoIpConfig {
             allowed_countries: vec![],
             mode: GeoIpMode::Block,
             default_action: GeoIpDefaultAction::Allow,
+            unknown_action: None,
+            self_test: default_geoip_self_test(),
+            decision_cache_size: default_geoip_decision_cache_size(),
         }
     }
 }
@@ -378,7 +1482,86 @@ pub enum GeoIpDefaultAction {
     Block,
 }
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum GeoIpUnknownAction {
+    Allow,
+    Block,
+    /// Serve the JS challenge instead of a hard block.
+    Challenge,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum GeoIpSelfTest {
+    /// Skip the check.
+    Off,
+    /// Log a warning and use the database anyway.
+    Warn,
+    /// Refuse to load the database.
+    Fail,
+}
+
 // Default value helpers
+fn default_error_rate_window_secs() -> u64 {
+    60
+}
+fn default_error_rate_threshold() -> f64 {
+    0.05
+}
+fn default_error_rate_min_responses() -> u64 {
+    100
+}
+fn default_rule_grace_hours() -> u64 {
+    24
+}
+fn default_max_forwarded_hops() -> usize {
+    20
+}
+fn default_forwarded_hops_overflow() -> ForwardedHopsOverflow {
+    ForwardedHopsOverflow::Truncate
+}
+fn default_never_buffer_content_types() -> Vec<String> {
+    vec![
+        "video/*".to_string(),
+        "application/octet-stream".to_string(),
+        "text/event-stream".to_string(),
+    ]
+}
+
+fn default_decision_mode() -> DecisionMode {
+    DecisionMode::ShortCircuit
+}
+fn default_aggregation_threshold() -> f64 {
+    1.0
+}
+fn default_signal_weight() -> f64 {
+    1.0
+}
+
+fn default_access_log_format() -> AccessLogFormat {
+    AccessLogFormat::Json
+}
+fn default_capture_sample_rate() -> f64 {
+    0.01
+}
+fn default_unavailable_status() -> u16 {
+    503
+}
+fn default_unavailable_content_type() -> String {
+    "text/html; charset=utf-8".to_string()
+}
+fn default_unavailable_body() -> String {
+    "<!DOCTYPE html>\n<html><head><title>Service Unavailable</title></head>\n\
+     <body><h1>Service Unavailable</h1>\n\
+     <p>We are down for maintenance. Please try again shortly.</p></body></html>\n"
+        .to_string()
+}
+
+fn default_maintenance_retry_after_secs() -> u64 {
+    300
+}
+
 fn default_admin_listen() -> String {
     "127.0.0.1:9090".to_string()
 }
@@ -403,9 +1586,45 @@ fn default_waf_mode() -> WafMode {
 fn default_rate_limit_algorithm() -> RateLimitAlgorithm {
     RateLimitAlgorithm::TokenBucket
 }
+fn default_authenticated_per_ip_factor() -> u64 {
+    4
+}
 fn default_body_limit() -> usize {
     13_107_200 // ~12.5 MB
 }
+fn default_max_custom_rules() -> usize {
+    1000
+}
+fn default_csrf_methods() -> Vec<String> {
+    ["POST", "PUT", "PATCH", "DELETE"].map(String::from).to_vec()
+}
+fn default_decode_depth() -> u32 {
+    2
+}
+fn default_body_budget_bytes() -> usize {
+    268_435_456 // 256 MiB
+}
+fn default_body_budget_action() -> BodyBudgetAction {
+    BodyBudgetAction::Skip
+}
+fn default_rule_hit_sample_rate() -> u64 {
+    100
+}
+fn default_rule_hit_summary_interval_secs() -> u64 {
+    60
+}
+fn default_challenge_type() -> ChallengeType {
+    ChallengeType::JsChallenge
+}
+fn default_no_js_user_agents() -> Vec<String> {
+    vec!["lynx".to_string(), "links".to_string(), "w3m".to_string()]
+}
+fn default_ip_family_policy() -> IpFamilyPolicy {
+    IpFamilyPolicy::Allow
+}
+fn default_health_probe_action() -> HealthProbeAction {
+    HealthProbeAction::Respond
+}
 fn default_audit_log_path() -> PathBuf {
     PathBuf::from("/var/log/layer7waf/audit.log")
 }
@@ -415,12 +1634,33 @@ fn default_rps() -> u64 {
 fn default_burst() -> u64 {
     200
 }
+fn default_connection_burst() -> u64 {
+    20
+}
+fn default_tarpit_max_concurrent() -> usize {
+    1000
+}
 fn default_bot_detection_mode() -> BotDetectionMode {
     BotDetectionMode::Challenge
 }
 fn default_score_threshold() -> f64 {
     0.7
 }
+fn default_header_order_cache_size() -> usize {
+    1024
+}
+fn default_fingerprint_histogram_size() -> usize {
+    10_000
+}
+fn default_fingerprint_half_life_secs() -> u64 {
+    600
+}
+fn default_learning_duration() -> u64 {
+    86_400
+}
+fn default_learning_min_share() -> f64 {
+    0.001
+}
 fn default_challenge_difficulty() -> u32 {
     16
 }
@@ -433,18 +1673,111 @@ fn default_anti_scraping_mode() -> AntiScrapingMode {
 fn default_scraping_score_threshold() -> f64 {
     0.6
 }
+fn default_max_tracked_paths() -> usize {
+    1000
+}
+fn default_trap_weight() -> f64 {
+    1.0
+}
+fn default_request_rate_weight() -> f64 {
+    0.3
+}
+fn default_path_diversity_weight() -> f64 {
+    0.2
+}
+fn default_bot_score_weight() -> f64 {
+    0.3
+}
+fn default_cookie_path() -> String {
+    "/".to_string()
+}
 fn default_captcha_ttl() -> u64 {
     1800
 }
+fn default_max_captcha_failures() -> u32 {
+    5
+}
+fn default_captcha_lockout_secs() -> u64 {
+    600
+}
 fn default_trap_path_prefix() -> String {
     "/.well-known/l7w-trap".to_string()
 }
 fn default_geoip_mode() -> GeoIpMode {
     GeoIpMode::Block
 }
+fn default_geoip_self_test() -> GeoIpSelfTest {
+    GeoIpSelfTest::Warn
+}
+fn default_geoip_decision_cache_size() -> usize {
+    10_000
+}
 fn default_geoip_default_action() -> GeoIpDefaultAction {
     GeoIpDefaultAction::Allow
 }
+fn default_otlp_endpoint() -> String {
+    "http://localhost:4318/v1/traces".to_string()
+}
+fn default_service_name() -> String {
+    "layer7waf".to_string()
+}
+fn default_sample_rate() -> f64 {
+    1.0
+}
+fn default_security_headers_mode() -> SecurityHeadersMode {
+    SecurityHeadersMode::Replace
+}
+fn default_forward_headers_policy() -> ForwardHeadersPolicy {
+    ForwardHeadersPolicy::All
+}
+fn default_security_headers() -> BTreeMap<String, String> {
+    [
+        ("X-Content-Type-Options", "nosniff"),
+        ("X-Frame-Options", "DENY"),
+        ("Referrer-Policy", "strict-origin-when-cross-origin"),
+        ("Content-Security-Policy", "default-src 'self'"),
+    ]
+    .into_iter()
+    .map(|(k, v)| (k.to_string(), v.to_string()))
+    .collect()
+}
+fn default_strip_response_headers() -> Vec<String> {
+    vec![
+        "Server".to_string(),
+        "X-Powered-By".to_string(),
+        "X-AspNet-Version".to_string(),
+    ]
+}
+fn default_adaptive_tighten_factor() -> f64 {
+    0.5
+}
+fn default_adaptive_restore_step() -> f64 {
+    0.1
+}
+fn default_adaptive_floor() -> f64 {
+    0.2
+}
+fn default_max_tracked_keys() -> usize {
+    100_000
+}
+fn default_watermark_ttl_secs() -> u64 {
+    7 * 86400
+}
+fn default_waf_penalty_threshold() -> u32 {
+    5
+}
+fn default_waf_penalty_half_life_secs() -> u64 {
+    60
+}
+fn default_waf_penalty_block_secs() -> u64 {
+    900
+}
+fn default_key_overflow_policy() -> KeyOverflowPolicy {
+    KeyOverflowPolicy::Evict
+}
+fn default_failure_policy() -> FailurePolicy {
+    FailurePolicy::Allow
+}
 fn default_challenge_secret() -> String {
     use std::time::{SystemTime, UNIX_EPOCH};
     let ts = SystemTime::now()
@@ -470,6 +1803,52 @@ impl AppConfig {
         }
 
         for route in &self.routes {
+            if let Some(ref trap) = route.trap_path_prefix {
+                if !trap.starts_with(&route.path_prefix) {
+                    anyhow::bail!(
+                        "route trap_path_prefix '{}' is outside its path_prefix '{}'",
+                        trap,
+                        route.path_prefix
+                    );
+                }
+            }
+            if let Some(ref ruleset) = route.waf.ruleset {
+                if !self.waf.rulesets.contains_key(ruleset) {
+                    anyhow::bail!(
+                        "route references unknown WAF ruleset '{}' (host={:?}, path={})",
+                        ruleset,
+                        route.host,
+                        route.path_prefix
+                    );
+                }
+            }
+            if let Some(adaptive) = route.rate_limit.as_ref().and_then(|rl| rl.adaptive.as_ref()) {
+                let in_unit = |v: f64| v > 0.0 && v <= 1.0;
+                if !in_unit(adaptive.tighten_factor)
+                    || !in_unit(adaptive.floor)
+                    || !in_unit(adaptive.restore_step)
+                {
+                    anyhow::bail!(
+                        "route rate_limit.adaptive factors must be in (0, 1] (path={})",
+                        route.path_prefix
+                    );
+                }
+            }
+            if let Some(ref rl) = route.rate_limit {
+                // Evicting a key before its window is over resets the client
+                let window = match rl.algorithm {
+                    RateLimitAlgorithm::TokenBucket => rl.burst.div_ceil(rl.rps.max(1)),
+                    RateLimitAlgorithm::SlidingWindow => 1,
+                    RateLimitAlgorithm::DailyQuota => 86_400,
+                };
+                if rl.key_ttl_secs.is_some_and(|ttl| ttl < window) {
+                    anyhow::bail!(
+                        "route rate_limit.key_ttl_secs must be at least its {}s window (path={})",
+                        window,
+                        route.path_prefix
+                    );
+                }
+            }
             let upstream_exists = self.upstreams.iter().any(|u| u.name == route.upstream);
             if !upstream_exists {
                 anyhow::bail!(
@@ -487,6 +1866,292 @@ impl AppConfig {
             }
         }
 
+        for (i, rule) in self.waf.inline_rules.iter().enumerate() {
+            if rule.contains('\0') {
+                anyhow::bail!("waf.inline_rules[{}] contains a NUL byte", i);
+            }
+        }
+
+        if self.server.max_concurrent_requests == Some(0) {
+            anyhow::bail!("server.max_concurrent_requests must be greater than 0");
+        }
+
+        if self.server.request_timeout_ms == Some(0) {
+            anyhow::bail!("server.request_timeout_ms must be greater than 0");
+        }
+
+        if self.server.max_forwarded_hops == 0 {
+            anyhow::bail!("server.max_forwarded_hops must be greater than 0");
+        }
+
+        let alert = &self.server.error_rate_alert;
+        if alert.enabled {
+            if alert.window_secs == 0 {
+                anyhow::bail!("server.error_rate_alert.window_secs must be greater than 0");
+            }
+            if !(alert.threshold > 0.0 && alert.threshold <= 1.0) {
+                anyhow::bail!("server.error_rate_alert.threshold must be in (0, 1]");
+            }
+        }
+
+        let admin = &self.server.admin;
+        if admin.debug_endpoints && admin.api_token.as_deref().is_none_or(str::is_empty) {
+            anyhow::bail!("server.admin.debug_endpoints needs server.admin.api_token");
+        }
+
+        for cidr in &self.server.trusted_proxies {
+            if cidr.parse::<ipnet::IpNet>().is_err() {
+                anyhow::bail!("server.trusted_proxies entry '{}' is not a CIDR", cidr);
+            }
+        }
+
+        for rule in &self.csrf_protection {
+            if !rule.path_prefix.starts_with('/') {
+                anyhow::bail!(
+                    "csrf_protection path_prefix '{}' must start with '/'",
+                    rule.path_prefix
+                );
+            }
+            if rule.allowed_origins.is_empty() {
+                anyhow::bail!(
+                    "csrf_protection rule for '{}' needs at least one allowed origin",
+                    rule.path_prefix
+                );
+            }
+            for origin in &rule.allowed_origins {
+                if let Some((scheme, _)) = origin.split_once("://") {
+                    if !scheme.eq_ignore_ascii_case("http") && !scheme.eq_ignore_ascii_case("https")
+                    {
+                        anyhow::bail!(
+                            "csrf_protection allowed origin '{}' must be http or https",
+                            origin
+                        );
+                    }
+                }
+            }
+        }
+
+        if let Some(ref probe) = self.health_probe {
+            if !probe.path.starts_with('/') {
+                anyhow::bail!("health_probe.path '{}' must start with '/'", probe.path);
+            }
+            if probe.user_agent.is_empty() || probe.source_cidrs.is_empty() {
+                anyhow::bail!("health_probe needs a user_agent and at least one source CIDR");
+            }
+            for cidr in &probe.source_cidrs {
+                if cidr.parse::<ipnet::IpNet>().is_err() {
+                    anyhow::bail!("health_probe.source_cidrs entry '{}' is not a CIDR", cidr);
+                }
+            }
+        }
+
+        if self.rate_limit.connection_rps == Some(0) || self.rate_limit.connection_burst == 0 {
+            anyhow::bail!("rate_limit.connection_rps and connection_burst must be greater than 0");
+        }
+        if self.rate_limit.daily_quota == Some(0) {
+            anyhow::bail!("rate_limit.daily_quota must be greater than 0");
+        }
+        if self.rate_limit.tarpit_ms > 60_000 {
+            anyhow::bail!("rate_limit.tarpit_ms must be at most 60000");
+        }
+        if let Some(timeout) = self.server.request_timeout_ms {
+            if self.rate_limit.tarpit_ms > 0 && self.rate_limit.tarpit_ms >= timeout {
+                anyhow::bail!("rate_limit.tarpit_ms must be less than server.request_timeout_ms");
+            }
+        }
+
+        let penalty = &self.ip_reputation.waf_penalty;
+        if penalty.enabled
+            && (penalty.threshold == 0
+                || penalty.half_life_secs == 0
+                || penalty.block_secs == 0
+                || penalty.max_tracked_ips == 0)
+        {
+            anyhow::bail!(
+                "ip_reputation.waf_penalty threshold, half_life_secs, block_secs and \
+                 max_tracked_ips must be greater than 0"
+            );
+        }
+
+        let aggregation = &self.security.aggregation;
+        let weights = &aggregation.weights;
+        if self.security.decision_mode == DecisionMode::Aggregate
+            && (!(aggregation.threshold > 0.0 && aggregation.threshold.is_finite())
+                || [weights.ip_reputation, weights.geoip, weights.bot, weights.scraping]
+                    .iter()
+                    .any(|w| !(*w >= 0.0 && w.is_finite())))
+        {
+            anyhow::bail!(
+                "security.aggregation threshold must be greater than 0 and weights at least 0"
+            );
+        }
+
+        if let Some(ref auth) = self.rate_limit.authenticated {
+            if auth.cookie.is_none() && auth.header.is_none() {
+                anyhow::bail!("rate_limit.authenticated needs a cookie or header name");
+            }
+            if auth.limit.rps == 0 {
+                anyhow::bail!("rate_limit.authenticated.rps must be greater than 0");
+            }
+            if auth.per_ip_factor == 0 {
+                anyhow::bail!("rate_limit.authenticated.per_ip_factor must be greater than 0");
+            }
+        }
+
+        let signals = &self.anti_scraping.signals;
+        if [
+            signals.trap_weight,
+            signals.request_rate_weight,
+            signals.path_diversity_weight,
+            signals.bot_score_weight,
+        ]
+        .iter()
+        .any(|w| !(*w >= 0.0 && w.is_finite()))
+        {
+            anyhow::bail!("anti_scraping.signals weights must be at least 0");
+        }
+        let obfuscation = &self.anti_scraping.obfuscation;
+        if obfuscation.watermark_ttl_secs == 0 || obfuscation.max_watermarks == 0 {
+            anyhow::bail!(
+                "anti_scraping.obfuscation watermark_ttl_secs and max_watermarks must be \
+                 greater than 0"
+            );
+        }
+        let decay = self.anti_scraping.score_decay_per_min;
+        if !(decay >= 0.0 && decay.is_finite()) {
+            anyhow::bail!("anti_scraping.score_decay_per_min must be at least 0");
+        }
+
+        if self.waf.decode_depth > MAX_DECODE_DEPTH {
+            anyhow::bail!("waf.decode_depth must be at most {}", MAX_DECODE_DEPTH);
+        }
+        if self.waf.body_budget_bytes == 0 {
+            anyhow::bail!("waf.body_budget_bytes must be greater than 0");
+        }
+        let sampling = &self.waf.rule_hit_sampling;
+        if sampling.enabled && (sampling.sample_rate == 0 || sampling.summary_interval_secs == 0) {
+            anyhow::bail!(
+                "waf.rule_hit_sampling.sample_rate and summary_interval_secs must be greater than 0"
+            );
+        }
+        for grace in &self.waf.rule_grace {
+            if grace.id.is_empty() || !grace.id.bytes().all(|b| b.is_ascii_digit()) {
+                anyhow::bail!("waf.rule_grace id '{}' is not a rule ID", grace.id);
+            }
+        }
+
+        if self.bot_detection.fingerprint_histogram_size > 0
+            && self.bot_detection.fingerprint_half_life_secs == 0
+        {
+            anyhow::bail!("bot_detection.fingerprint_half_life_secs must be greater than 0");
+        }
+        if let Some(share) = self.bot_detection.dominant_fingerprint_share {
+            if !(share > 0.0 && share <= 1.0) {
+                anyhow::bail!("bot_detection.dominant_fingerprint_share must be in (0.0, 1.0]");
+            }
+        }
+
+        if let Some(threshold) = self.bot_detection.challenge_threshold {
+            if !(0.0..self.bot_detection.score_threshold).contains(&threshold) {
+                anyhow::bail!(
+                    "bot_detection.challenge_threshold must be in [0.0, score_threshold)"
+                );
+            }
+            if self.bot_detection.mode == BotDetectionMode::Block
+                && !self.bot_detection.js_challenge.enabled
+            {
+                anyhow::bail!("bot_detection.challenge_threshold needs js_challenge.enabled");
+            }
+        }
+
+        if !self.bot_detection.require_challenge_paths.is_empty() {
+            if !self.bot_detection.js_challenge.enabled {
+                anyhow::bail!("bot_detection.require_challenge_paths needs js_challenge.enabled");
+            }
+            if let Some(prefix) = self
+                .bot_detection
+                .require_challenge_paths
+                .iter()
+                .find(|p| !p.starts_with('/'))
+            {
+                anyhow::bail!(
+                    "bot_detection.require_challenge_paths entry '{}' must start with '/'",
+                    prefix
+                );
+            }
+        }
+
+        if self.bot_detection.force_challenge_ua.iter().any(|ua| ua.is_empty()) {
+            anyhow::bail!("bot_detection.force_challenge_ua entries must not be empty");
+        }
+
+        self.bot_detection
+            .js_challenge
+            .cookie
+            .validate("bot_detection.js_challenge.cookie")?;
+        self.anti_scraping
+            .captcha
+            .cookie
+            .validate("anti_scraping.captcha.cookie")?;
+
+        for rule in &self.bot_detection.ua_path_rules {
+            if rule.ua_substring.trim().is_empty() {
+                anyhow::bail!("bot_detection.ua_path_rules entries need a non-empty ua_substring");
+            }
+            if !rule.path_prefix.starts_with('/') {
+                anyhow::bail!(
+                    "bot_detection.ua_path_rules path_prefix '{}' must start with '/'",
+                    rule.path_prefix
+                );
+            }
+        }
+
+        for bot in &self.bot_detection.verified_bots {
+            if bot.ua_substring.trim().is_empty() || bot.source_cidrs.is_empty() {
+                anyhow::bail!(
+                    "bot_detection.verified_bots entries need a ua_substring and a source CIDR"
+                );
+            }
+            for cidr in &bot.source_cidrs {
+                if cidr.parse::<ipnet::IpNet>().is_err() {
+                    anyhow::bail!(
+                        "bot_detection.verified_bots source_cidrs entry '{}' is not a CIDR",
+                        cidr
+                    );
+                }
+            }
+        }
+
+        let learning = &self.bot_detection.learning;
+        if learning.enabled {
+            if learning.duration_secs == 0 {
+                anyhow::bail!("bot_detection.learning.duration_secs must be greater than 0");
+            }
+            if !(0.0..1.0).contains(&learning.min_share) {
+                anyhow::bail!("bot_detection.learning.min_share must be in [0.0, 1.0)");
+            }
+        }
+
+        if let Some(ref tracing) = self.server.tracing {
+            if !(0.0..=1.0).contains(&tracing.sample_rate) {
+                anyhow::bail!("server.tracing.sample_rate must be between 0.0 and 1.0");
+            }
+        }
+
+        let capture = &self.server.capture;
+        if capture.enabled {
+            if !(0.0..=1.0).contains(&capture.sample_rate) {
+                anyhow::bail!("server.capture.sample_rate must be between 0.0 and 1.0");
+            }
+            if capture.path.is_none() {
+                anyhow::bail!("server.capture.path is required when capture is enabled");
+            }
+        }
+
+        if !(100..=599).contains(&self.server.unavailable_response.status) {
+            anyhow::bail!("server.unavailable_response.status must be between 100 and 599");
+        }
+
         Ok(())
     }
 }
@@ -1,5 +1,17 @@
+pub mod accept;
+pub mod capacity;
 pub mod config;
 pub mod error;
+pub mod path;
+#[cfg(feature = "redis")]
+pub mod redis_session_store;
+pub mod session_store;
 
+pub use accept::*;
+pub use capacity::*;
 pub use config::*;
 pub use error::*;
+pub use path::*;
+#[cfg(feature = "redis")]
+pub use redis_session_store::*;
+pub use session_store::*;
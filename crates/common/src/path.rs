@@ -0,0 +1,125 @@
+//! Path prefix matching that can't be sidestepped by re-spelling the path.
+
+/// Percent-decoding passes applied before matching, enough to undo the
+/// double and triple encoding clients use to slip past prefix rules.
+const DECODE_PASSES: usize = 3;
+
+/// A request path in a canonical form for prefix rules: percent-decoded,
+/// with empty, `.` and `..` segments resolved, `\` treated as `/`, and
+/// ASCII-lowercased.
+///
+/// Upstreams routinely treat `//checkout`, `/%63heckout` and `/Checkout`
+/// as `/checkout`, so a rule matched against the raw path is bypassed by
+/// any of them.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct NormalizedPath(String);
+
+impl NormalizedPath {
+    pub fn new(path: &str) -> Self {
+        let mut decoded = path.to_string();
+        for _ in 0..DECODE_PASSES {
+            match percent_decode(&decoded) {
+                Some(next) => decoded = next,
+                None => break,
+            }
+        }
+
+        let mut segments: Vec<&str> = Vec::new();
+        for segment in decoded.split(['/', '\\']) {
+            match segment {
+                "" | "." => {}
+                ".." => {
+                    segments.pop();
+                }
+                segment => segments.push(segment),
+            }
+        }
+        let mut normalized = String::with_capacity(decoded.len() + 1);
+        for segment in &segments {
+            normalized.push('/');
+            normalized.push_str(segment);
+        }
+        if normalized.is_empty() {
+            normalized.push('/');
+        }
+        normalized.make_ascii_lowercase();
+        Self(normalized)
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+
+    /// Whether this path is `prefix` or lies beneath it. The prefix is
+    /// normalized the same way and matched on whole segments, so
+    /// `/checkout` covers `/checkout/pay` but not `/checkoutfoo`.
+    pub fn has_prefix(&self, prefix: &str) -> bool {
+        let prefix = Self::new(prefix);
+        if prefix.0 == "/" {
+            return true;
+        }
+        match self.0.strip_prefix(&prefix.0) {
+            Some(rest) => rest.is_empty() || rest.starts_with('/'),
+            None => false,
+        }
+    }
+}
+
+/// One percent-decoding pass, or `None` if `s` has no decodable escapes.
+/// Decoded bytes that aren't UTF-8 are replaced rather than rejected.
+fn percent_decode(s: &str) -> Option<String> {
+    let bytes = s.as_bytes();
+    let mut out = Vec::with_capacity(bytes.len());
+    let mut changed = false;
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == b'%'
+            && i + 2 < bytes.len()
+            && bytes[i + 1].is_ascii_hexdigit()
+            && bytes[i + 2].is_ascii_hexdigit()
+        {
+            let hex = std::str::from_utf8(&bytes[i + 1..i + 3]).unwrap();
+            out.push(u8::from_str_radix(hex, 16).unwrap());
+            changed = true;
+            i += 3;
+            continue;
+        }
+        out.push(bytes[i]);
+        i += 1;
+    }
+    changed.then(|| String::from_utf8_lossy(&out).into_owned())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_respelled_paths_normalized() {
+        for path in [
+            "/checkout",
+            "//checkout",
+            "/%63heckout",
+            "/%2563heckout",
+            "/Checkout",
+            "/./checkout/",
+            "/static/../checkout",
+            "\\checkout",
+        ] {
+            assert_eq!(NormalizedPath::new(path).as_str(), "/checkout", "{}", path);
+        }
+        assert_eq!(NormalizedPath::new("").as_str(), "/");
+        assert_eq!(NormalizedPath::new("/../..").as_str(), "/");
+    }
+
+    #[test]
+    fn test_prefix_matches_whole_segments() {
+        let path = NormalizedPath::new("//Checkout/pay");
+        assert!(path.has_prefix("/checkout"));
+        assert!(path.has_prefix("/checkout/"));
+        assert!(path.has_prefix("/"));
+        assert!(NormalizedPath::new("/checkout").has_prefix("/checkout"));
+        assert!(!NormalizedPath::new("/checkoutfoo").has_prefix("/checkout"));
+        assert!(!NormalizedPath::new("/shop/checkout").has_prefix("/checkout"));
+    }
+}
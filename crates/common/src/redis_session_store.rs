@@ -0,0 +1,281 @@
+//! A [`SessionStore`] in Redis, so several proxy instances share sessions.
+
+use std::marker::PhantomData;
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime};
+
+use redis::{Commands, Connection, RedisResult, Script};
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use tracing::warn;
+
+use crate::capacity::{Admission, KeyCapacity};
+use crate::session_store::{SessionStore, Versioned};
+
+/// How long a connect, read or write may take before the operation is given
+/// up, unless set with [`RedisSessionStore::with_timeout`].
+pub const DEFAULT_TIMEOUT: Duration = Duration::from_millis(100);
+
+/// Keys asked for per `SCAN` round trip when visiting every session.
+const SCAN_BATCH: usize = 1000;
+
+/// Writes `ARGV[2]` as the session in `KEYS[1]` under a fresh version from
+/// the `KEYS[2]` counter, if the stored version is `ARGV[1]` ("" for an
+/// untracked key, "*" for any), and resets its expiry to `ARGV[3]` ms.
+const WRITE_SCRIPT: &str = r"
+local stored = redis.call('HGET', KEYS[1], 'v')
+if ARGV[1] ~= '*' and (stored or '') ~= ARGV[1] then
+    return 0
+end
+local version = redis.call('INCR', KEYS[2])
+redis.call('HSET', KEYS[1], 'v', version, 's', ARGV[2])
+redis.call('PEXPIRE', KEYS[1], ARGV[3])
+return 1
+";
+
+/// Sessions kept in Redis as hashes of their version and JSON encoding,
+/// under `{prefix}s:{key}`.
+///
+/// Calls are Redis round trips made on the calling thread, over connections
+/// pooled here. Sessions expire in Redis after `ttl` without a write, which
+/// is also what bounds their number: [`admit`](SessionStore::admit) always
+/// admits. A Redis error, or a connect, read or write slower than the
+/// timeout, is logged and the operation skipped, so an unreachable server
+/// loses session state and costs each request at most the timeout.
+///
+/// [`len`](SessionStore::len), [`count`](SessionStore::count) and
+/// [`filter_map`](SessionStore::filter_map) `SCAN` every session key, the
+/// last two also fetching every session in one pipelined round trip; they
+/// cost time in proportion to the sessions stored, wherever they're called
+/// from, metrics and memory stats included.
+pub struct RedisSessionStore<S> {
+    client: redis::Client,
+    idle: Mutex<Vec<Connection>>,
+    prefix: String,
+    ttl: Duration,
+    timeout: Duration,
+    write: Script,
+    _session: PhantomData<fn() -> S>,
+}
+
+impl<S> RedisSessionStore<S> {
+    /// A store on the server at `url` (e.g. `redis://10.0.0.5:6379/0`),
+    /// keeping its keys under `prefix` so detectors can share a server.
+    pub fn open(url: &str, prefix: impl Into<String>, ttl: Duration) -> RedisResult<Self> {
+        Ok(Self {
+            client: redis::Client::open(url)?,
+            idle: Mutex::new(Vec::new()),
+            prefix: prefix.into(),
+            ttl,
+            timeout: DEFAULT_TIMEOUT,
+            write: Script::new(WRITE_SCRIPT),
+            _session: PhantomData,
+        })
+    }
+
+    /// Give up on connects, reads and writes taking longer than `timeout`.
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    fn session_key(&self, key: &str) -> String {
+        format!("{}s:{}", self.prefix, key)
+    }
+
+    fn version_key(&self) -> String {
+        format!("{}version", self.prefix)
+    }
+
+    /// Run `op` on a pooled connection, opening one if none is idle. A
+    /// connection that failed is dropped rather than pooled.
+    fn with_connection<T>(
+        &self,
+        op: impl FnOnce(&mut Connection) -> RedisResult<T>,
+    ) -> RedisResult<T> {
+        let pooled = self.idle.lock().unwrap().pop();
+        let mut connection = match pooled {
+            Some(connection) => connection,
+            None => {
+                let connection = self.client.get_connection_with_timeout(self.timeout)?;
+                connection.set_read_timeout(Some(self.timeout))?;
+                connection.set_write_timeout(Some(self.timeout))?;
+                connection
+            }
+        };
+        let result = op(&mut connection);
+        if result.is_ok() {
+            self.idle.lock().unwrap().push(connection);
+        }
+        result
+    }
+
+    /// Every session key under the prefix.
+    fn session_keys(&self) -> RedisResult<Vec<String>> {
+        let pattern = format!("{}s:*", self.prefix);
+        self.with_connection(|c| {
+            let mut scan = redis::cmd("SCAN");
+            scan.cursor_arg(0).arg("MATCH").arg(&pattern).arg("COUNT").arg(SCAN_BATCH);
+            Ok(scan.iter::<String>(c)?.collect())
+        })
+    }
+
+    fn logged<T>(&self, op: &str, result: RedisResult<T>) -> Option<T> {
+        result
+            .map_err(|e| warn!(op, prefix = %self.prefix, error = %e, "Redis session store error"))
+            .ok()
+    }
+}
+
+impl<S: Serialize + DeserializeOwned> RedisSessionStore<S> {
+    fn write(&self, key: &str, expected: &str, session: &S) -> RedisResult<bool> {
+        let encoded = serde_json::to_string(session).map_err(|e| {
+            redis::RedisError::from((redis::ErrorKind::TypeError, "encode", e.to_string()))
+        })?;
+        let ttl_ms = self.ttl.as_millis() as u64;
+        self.with_connection(|c| {
+            self.write
+                .key(self.session_key(key))
+                .key(self.version_key())
+                .arg(expected)
+                .arg(&encoded)
+                .arg(ttl_ms)
+                .invoke::<i64>(c)
+                .map(|written| written == 1)
+        })
+    }
+
+    /// The sessions stored under `keys`, skipping any gone or undecodable.
+    fn sessions(&self, keys: &[String]) -> RedisResult<Vec<(String, S)>> {
+        let mut fetch = redis::pipe();
+        for key in keys {
+            fetch.hget(key, "s");
+        }
+        let encoded: Vec<Option<String>> = self.with_connection(|c| fetch.query(c))?;
+        Ok(keys
+            .iter()
+            .zip(encoded)
+            .filter_map(|(key, encoded)| {
+                let session = serde_json::from_str(&encoded?).ok()?;
+                Some((key.clone(), session))
+            })
+            .collect())
+    }
+
+    fn delete(&self, keys: &[String]) -> RedisResult<usize> {
+        if keys.is_empty() {
+            return Ok(0);
+        }
+        self.with_connection(|c| c.del(keys))
+    }
+
+    fn client_key<'a>(&self, session_key: &'a str) -> &'a str {
+        &session_key[self.prefix.len() + 2..]
+    }
+}
+
+impl<S> SessionStore<S> for RedisSessionStore<S>
+where
+    S: Serialize + DeserializeOwned + Send + Sync,
+{
+    fn get(&self, key: &str) -> Option<Versioned<S>> {
+        let stored = self.with_connection(|c| {
+            c.hget::<_, _, (Option<u64>, Option<String>)>(self.session_key(key), &["v", "s"])
+        });
+        let (version, encoded) = self.logged("get", stored)?;
+        let version = version?;
+        match encoded.and_then(|s| serde_json::from_str(&s).ok()) {
+            Some(session) => Some(Versioned { version, session }),
+            None => {
+                // Written in another format, by an older or newer build say.
+                // Left in place it would look untracked yet refuse the write
+                // creating it, so it's dropped and the key starts over.
+                warn!(prefix = %self.prefix, key, "dropping undecodable session");
+                self.remove(key);
+                None
+            }
+        }
+    }
+
+    /// A Redis error counts as stored, so [`update`](SessionStore::update)
+    /// doesn't retry against an unreachable server.
+    fn compare_and_set(&self, key: &str, expected: Option<u64>, session: S) -> bool {
+        let expected = expected.map(|v| v.to_string()).unwrap_or_default();
+        self.logged("compare_and_set", self.write(key, &expected, &session))
+            .unwrap_or(true)
+    }
+
+    fn insert(&self, key: &str, session: S) {
+        self.logged("insert", self.write(key, "*", &session));
+    }
+
+    fn remove(&self, key: &str) -> bool {
+        let removed = self.delete(&[self.session_key(key)]);
+        self.logged("remove", removed).is_some_and(|n| n > 0)
+    }
+
+    fn clear(&self) -> usize {
+        let removed = self.session_keys().and_then(|keys| self.delete(&keys));
+        self.logged("clear", removed).unwrap_or(0)
+    }
+
+    fn retain(&self, keep: impl Fn(&S) -> bool) {
+        let stale = self.session_keys().and_then(|keys| {
+            let sessions = self.sessions(&keys)?;
+            let stale: Vec<String> = sessions
+                .into_iter()
+                .filter(|(_, session)| !keep(session))
+                .map(|(key, _)| key)
+                .collect();
+            self.delete(&stale)
+        });
+        self.logged("retain", stale);
+    }
+
+    fn filter_map<T>(&self, f: impl Fn(&str, &S) -> Option<T>) -> Vec<T> {
+        let sessions = self.session_keys().and_then(|keys| self.sessions(&keys));
+        self.logged("filter_map", sessions)
+            .unwrap_or_default()
+            .iter()
+            .filter_map(|(key, session)| f(self.client_key(key), session))
+            .collect()
+    }
+
+    fn count(&self, f: impl Fn(&S) -> bool) -> usize {
+        let sessions = self.session_keys().and_then(|keys| self.sessions(&keys));
+        self.logged("count", sessions)
+            .unwrap_or_default()
+            .iter()
+            .filter(|(_, session)| f(session))
+            .count()
+    }
+
+    fn len(&self) -> usize {
+        self.logged("len", self.session_keys()).map_or(0, |keys| keys.len())
+    }
+
+    fn admit(
+        &self,
+        _capacity: &KeyCapacity,
+        _key: &str,
+        _last_seen: impl Fn(&S) -> SystemTime,
+    ) -> Admission {
+        Admission::Admitted
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_keys_namespaced_under_prefix() {
+        let store: RedisSessionStore<u32> =
+            RedisSessionStore::open("redis://127.0.0.1/", "l7w:bot:", Duration::from_secs(60))
+                .unwrap();
+        let key = store.session_key("1.2.3.4");
+        assert_eq!(key, "l7w:bot:s:1.2.3.4");
+        assert_eq!(store.client_key(&key), "1.2.3.4");
+        assert_eq!(store.version_key(), "l7w:bot:version");
+    }
+}
@@ -0,0 +1,434 @@
+//! Per-client session storage for the bot detector and anti-scraper.
+
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::SystemTime;
+
+use dashmap::mapref::entry::Entry;
+use dashmap::DashMap;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+
+use crate::capacity::{Admission, KeyCapacity};
+
+/// Reads and writes [`SessionStore::update`] attempts before it stops
+/// yielding to concurrent writers and overwrites the session.
+pub const MAX_UPDATE_ATTEMPTS: usize = 8;
+
+/// A session as read from a store, with the version a write of it must
+/// match.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Versioned<S> {
+    pub version: u64,
+    pub session: S,
+}
+
+/// Storage of the session `S` kept for each client.
+///
+/// The detectors keep all of their logic and only read, write and evict
+/// sessions through the store, so the default in-process map can be swapped
+/// for one shared between processes, such as `RedisSessionStore` (the
+/// `redis` feature). Sessions therefore record wall-clock times and must
+/// round-trip through serde. Concurrent requests from one client race on
+/// its session, so writes are compare-and-set against the version read.
+pub trait SessionStore<S: Serialize + DeserializeOwned>: Send + Sync {
+    /// `key`'s session and its version, or `None` if the key isn't tracked.
+    fn get(&self, key: &str) -> Option<Versioned<S>>;
+
+    /// Store `session` for `key` if its stored version is still `expected`,
+    /// or, for `None`, if the key isn't tracked. Returns `false`, storing
+    /// nothing, when another write got in first.
+    fn compare_and_set(&self, key: &str, expected: Option<u64>, session: S) -> bool;
+
+    /// Store `session` for `key`, replacing any earlier one.
+    fn insert(&self, key: &str, session: S);
+
+    /// Forget `key`'s session. Returns whether it was tracked.
+    fn remove(&self, key: &str) -> bool;
+
+    /// Forget every session. Returns the number removed.
+    fn clear(&self) -> usize;
+
+    /// Keep only the sessions satisfying `keep`; used by the periodic cleanup.
+    fn retain(&self, keep: impl Fn(&S) -> bool);
+
+    /// Collect `f`'s output over every session, skipping those it returns
+    /// `None` for. Visits every session, so not meant for the request path.
+    fn filter_map<T>(&self, f: impl Fn(&str, &S) -> Option<T>) -> Vec<T>;
+
+    /// Number of sessions satisfying `f`. Visits every session.
+    fn count(&self, f: impl Fn(&S) -> bool) -> usize;
+
+    /// Number of sessions currently tracked.
+    fn len(&self) -> usize;
+
+    /// Whether no sessions are tracked.
+    fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Make room for `key` under `capacity`; see [`KeyCapacity::admit`].
+    fn admit(
+        &self,
+        capacity: &KeyCapacity,
+        key: &str,
+        last_seen: impl Fn(&S) -> SystemTime,
+    ) -> Admission;
+
+    /// Run `f` on `key`'s session, creating it with `init` if the key isn't
+    /// tracked, store the result and return `f`'s. A conflicting write
+    /// makes it start over from a fresh read, so `f` may run more than once;
+    /// after [`MAX_UPDATE_ATTEMPTS`] the last result is stored regardless.
+    fn update<R>(
+        &self,
+        key: &str,
+        mut init: impl FnMut() -> S,
+        mut f: impl FnMut(&mut S) -> R,
+    ) -> R {
+        let mut attempt = 1;
+        loop {
+            let current = self.get(key);
+            let expected = current.as_ref().map(|c| c.version);
+            let mut session = match current {
+                Some(current) => current.session,
+                None => init(),
+            };
+            let result = f(&mut session);
+            if attempt == MAX_UPDATE_ATTEMPTS {
+                self.insert(key, session);
+                return result;
+            }
+            if self.compare_and_set(key, expected, session) {
+                return result;
+            }
+            attempt += 1;
+        }
+    }
+
+    /// Run `f` on `key`'s session without modifying it. Returns `None` if
+    /// the key isn't tracked.
+    fn read<R>(&self, key: &str, f: impl FnOnce(&S) -> R) -> Option<R> {
+        self.get(key).map(|current| f(&current.session))
+    }
+}
+
+/// The default store: a [`DashMap`] in process memory.
+pub struct InMemorySessionStore<S> {
+    map: DashMap<String, Versioned<S>>,
+    /// Versions come from one counter, so a key removed and tracked again
+    /// never repeats a version an earlier reader holds.
+    next_version: AtomicU64,
+}
+
+impl<S> InMemorySessionStore<S> {
+    pub fn new() -> Self {
+        Self {
+            map: DashMap::new(),
+            next_version: AtomicU64::new(1),
+        }
+    }
+
+    fn next_version(&self) -> u64 {
+        self.next_version.fetch_add(1, Ordering::Relaxed)
+    }
+}
+
+impl<S> Default for InMemorySessionStore<S> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<S> SessionStore<S> for InMemorySessionStore<S>
+where
+    S: Clone + Serialize + DeserializeOwned + Send + Sync,
+{
+    fn get(&self, key: &str) -> Option<Versioned<S>> {
+        self.map.get(key).map(|entry| entry.value().clone())
+    }
+
+    fn compare_and_set(&self, key: &str, expected: Option<u64>, session: S) -> bool {
+        let version = self.next_version();
+        match self.map.entry(key.to_string()) {
+            Entry::Occupied(mut entry) if Some(entry.get().version) == expected => {
+                entry.insert(Versioned { version, session });
+                true
+            }
+            Entry::Vacant(entry) if expected.is_none() => {
+                entry.insert(Versioned { version, session });
+                true
+            }
+            _ => false,
+        }
+    }
+
+    fn insert(&self, key: &str, session: S) {
+        let version = self.next_version();
+        self.map.insert(key.to_string(), Versioned { version, session });
+    }
+
+    fn remove(&self, key: &str) -> bool {
+        self.map.remove(key).is_some()
+    }
+
+    fn clear(&self) -> usize {
+        let count = self.map.len();
+        self.map.clear();
+        count
+    }
+
+    fn retain(&self, keep: impl Fn(&S) -> bool) {
+        self.map.retain(|_key, entry| keep(&entry.session));
+    }
+
+    fn filter_map<T>(&self, f: impl Fn(&str, &S) -> Option<T>) -> Vec<T> {
+        self.map
+            .iter()
+            .filter_map(|entry| f(entry.key(), &entry.value().session))
+            .collect()
+    }
+
+    fn count(&self, f: impl Fn(&S) -> bool) -> usize {
+        self.map.iter().filter(|entry| f(&entry.value().session)).count()
+    }
+
+    fn len(&self) -> usize {
+        self.map.len()
+    }
+
+    fn admit(
+        &self,
+        capacity: &KeyCapacity,
+        key: &str,
+        last_seen: impl Fn(&S) -> SystemTime,
+    ) -> Admission {
+        capacity.admit(&self.map, key, |entry| last_seen(&entry.session))
+    }
+
+    /// Updated in place under the map's entry lock, so never retried.
+    fn update<R>(
+        &self,
+        key: &str,
+        mut init: impl FnMut() -> S,
+        mut f: impl FnMut(&mut S) -> R,
+    ) -> R {
+        let mut entry = self.map.entry(key.to_string()).or_insert_with(|| Versioned {
+            version: 0,
+            session: init(),
+        });
+        entry.version = self.next_version();
+        f(&mut entry.session)
+    }
+
+    fn read<R>(&self, key: &str, f: impl FnOnce(&S) -> R) -> Option<R> {
+        self.map.get(key).map(|entry| f(&entry.value().session))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::{FailurePolicy, KeyOverflowPolicy};
+    use serde::Deserialize;
+    use std::time::Duration;
+
+    #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+    struct Session {
+        last_seen: SystemTime,
+        hits: u32,
+    }
+
+    fn session(last_seen: SystemTime) -> Session {
+        Session { last_seen, hits: 0 }
+    }
+
+    #[test]
+    fn test_update_creates_then_mutates() {
+        let store = InMemorySessionStore::new();
+        let now = SystemTime::now();
+        for expected in 1..=3 {
+            let hits = store.update("1.2.3.4", || session(now), |s| {
+                s.hits += 1;
+                s.hits
+            });
+            assert_eq!(hits, expected);
+        }
+        assert_eq!(store.read("1.2.3.4", |s| s.hits), Some(3));
+        assert_eq!(store.read("5.6.7.8", |s| s.hits), None);
+
+        store.insert("1.2.3.4", session(now));
+        assert_eq!(store.read("1.2.3.4", |s| s.hits), Some(0));
+        assert_eq!(store.len(), 1);
+    }
+
+    #[test]
+    fn test_compare_and_set_rejects_stale_versions() {
+        let store = InMemorySessionStore::new();
+        let now = SystemTime::now();
+        assert!(store.compare_and_set("k", None, session(now)));
+        assert!(!store.compare_and_set("k", None, session(now)), "already tracked");
+
+        let read = store.get("k").unwrap();
+        let mut next = read.session.clone();
+        next.hits = 1;
+        assert!(store.compare_and_set("k", Some(read.version), next.clone()));
+        // A second writer holding the same read loses
+        assert!(!store.compare_and_set("k", Some(read.version), next));
+
+        // Removing and re-tracking the key doesn't revive the old version
+        store.remove("k");
+        store.insert("k", session(now));
+        assert!(!store.compare_and_set("k", Some(read.version), session(now)));
+    }
+
+    #[test]
+    fn test_default_update_retries_on_conflict() {
+        /// Loses the first write to a concurrent writer.
+        struct Contended {
+            inner: InMemorySessionStore<Session>,
+            raced: std::sync::atomic::AtomicBool,
+        }
+
+        impl SessionStore<Session> for Contended {
+            fn get(&self, key: &str) -> Option<Versioned<Session>> {
+                self.inner.get(key)
+            }
+            fn compare_and_set(&self, key: &str, expected: Option<u64>, s: Session) -> bool {
+                if !self.raced.swap(true, Ordering::Relaxed) {
+                    self.inner.update(key, || session(s.last_seen), |s| s.hits += 10);
+                }
+                self.inner.compare_and_set(key, expected, s)
+            }
+            fn insert(&self, key: &str, s: Session) {
+                self.inner.insert(key, s)
+            }
+            fn remove(&self, key: &str) -> bool {
+                self.inner.remove(key)
+            }
+            fn clear(&self) -> usize {
+                self.inner.clear()
+            }
+            fn retain(&self, keep: impl Fn(&Session) -> bool) {
+                self.inner.retain(keep)
+            }
+            fn filter_map<T>(&self, f: impl Fn(&str, &Session) -> Option<T>) -> Vec<T> {
+                self.inner.filter_map(f)
+            }
+            fn count(&self, f: impl Fn(&Session) -> bool) -> usize {
+                self.inner.count(f)
+            }
+            fn len(&self) -> usize {
+                self.inner.len()
+            }
+            fn admit(
+                &self,
+                capacity: &KeyCapacity,
+                key: &str,
+                last_seen: impl Fn(&Session) -> SystemTime,
+            ) -> Admission {
+                self.inner.admit(capacity, key, last_seen)
+            }
+        }
+
+        let store = Contended {
+            inner: InMemorySessionStore::new(),
+            raced: Default::default(),
+        };
+        let now = SystemTime::now();
+        let mut attempts = 0;
+        let hits = store.update("k", || session(now), |s| {
+            attempts += 1;
+            s.hits += 1;
+            s.hits
+        });
+        // The concurrent write is kept, not overwritten
+        assert_eq!(attempts, 2);
+        assert_eq!(hits, 11);
+        assert_eq!(store.read("k", |s| s.hits), Some(11));
+    }
+
+    #[test]
+    fn test_update_stops_retrying_a_write_it_keeps_losing() {
+        /// Every compare-and-set loses.
+        struct Stuck(InMemorySessionStore<Session>);
+
+        impl SessionStore<Session> for Stuck {
+            fn get(&self, key: &str) -> Option<Versioned<Session>> {
+                self.0.get(key)
+            }
+            fn compare_and_set(&self, _key: &str, _expected: Option<u64>, _s: Session) -> bool {
+                false
+            }
+            fn insert(&self, key: &str, s: Session) {
+                self.0.insert(key, s)
+            }
+            fn remove(&self, key: &str) -> bool {
+                self.0.remove(key)
+            }
+            fn clear(&self) -> usize {
+                self.0.clear()
+            }
+            fn retain(&self, keep: impl Fn(&Session) -> bool) {
+                self.0.retain(keep)
+            }
+            fn filter_map<T>(&self, f: impl Fn(&str, &Session) -> Option<T>) -> Vec<T> {
+                self.0.filter_map(f)
+            }
+            fn count(&self, f: impl Fn(&Session) -> bool) -> usize {
+                self.0.count(f)
+            }
+            fn len(&self) -> usize {
+                self.0.len()
+            }
+            fn admit(
+                &self,
+                capacity: &KeyCapacity,
+                key: &str,
+                last_seen: impl Fn(&Session) -> SystemTime,
+            ) -> Admission {
+                self.0.admit(capacity, key, last_seen)
+            }
+        }
+
+        let store = Stuck(InMemorySessionStore::new());
+        let mut attempts = 0;
+        store.update("k", || session(SystemTime::now()), |s| {
+            attempts += 1;
+            s.hits = attempts;
+        });
+        assert_eq!(attempts, MAX_UPDATE_ATTEMPTS as u32);
+        assert_eq!(store.read("k", |s| s.hits), Some(MAX_UPDATE_ATTEMPTS as u32));
+    }
+
+    #[test]
+    fn test_cleanup_and_removal() {
+        let store = InMemorySessionStore::new();
+        let base = SystemTime::now();
+        for i in 0..4 {
+            store.insert(&format!("10.0.0.{}", i), session(base + Duration::from_secs(i)));
+        }
+
+        store.retain(|s| s.last_seen >= base + Duration::from_secs(2));
+        let mut keys = store.filter_map(|key, _| Some(key.to_string()));
+        keys.sort();
+        assert_eq!(keys, vec!["10.0.0.2", "10.0.0.3"]);
+        assert_eq!(store.count(|s| s.last_seen > base + Duration::from_secs(2)), 1);
+
+        assert!(store.remove("10.0.0.2"));
+        assert!(!store.remove("10.0.0.2"));
+        assert_eq!(store.clear(), 1);
+        assert!(store.is_empty());
+    }
+
+    #[test]
+    fn test_admit_evicts_oldest() {
+        let store = InMemorySessionStore::new();
+        let base = SystemTime::now();
+        for i in 0..10 {
+            store.insert(&format!("k{}", i), session(base + Duration::from_millis(i)));
+        }
+        let cap = KeyCapacity::new(10, KeyOverflowPolicy::Evict, FailurePolicy::Allow);
+        assert_eq!(store.admit(&cap, "new", |s| s.last_seen), Admission::Admitted);
+        assert_eq!(store.len(), 9);
+        assert!(store.get("k0").is_none());
+    }
+}
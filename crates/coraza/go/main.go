@@ -189,6 +189,45 @@ func coraza_intervention_url(txID C.uint64_t) *C.char {
 	return C.CString(it.Data)
 }
 
+//export coraza_intervention_rule_id
+func coraza_intervention_rule_id(txID C.uint64_t) C.int {
+	val, ok := txInstances.Load(uint64(txID))
+	if !ok {
+		return 0
+	}
+	tx := val.(types.Transaction)
+
+	if it := tx.Interruption(); it != nil {
+		return C.int(it.RuleID)
+	}
+	return 0
+}
+
+// Matched rules carrying a message, as a JSON array of rule IDs. Rules
+// without one (e.g. CRS setup actions) match every request and say
+// nothing about it.
+//
+//export coraza_matched_rules
+func coraza_matched_rules(txID C.uint64_t) *C.char {
+	val, ok := txInstances.Load(uint64(txID))
+	if !ok {
+		return nil
+	}
+	tx := val.(types.Transaction)
+
+	ids := []int{}
+	for _, mr := range tx.MatchedRules() {
+		if mr.Message() != "" {
+			ids = append(ids, mr.Rule().ID())
+		}
+	}
+	out, err := json.Marshal(ids)
+	if err != nil {
+		return nil
+	}
+	return C.CString(string(out))
+}
+
 //export coraza_free_transaction
 func coraza_free_transaction(txID C.uint64_t) {
 	val, ok := txInstances.LoadAndDelete(uint64(txID))
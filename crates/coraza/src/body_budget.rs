@@ -0,0 +1,110 @@
+use std::sync::Arc;
+
+use layer7waf_common::BodyBudgetAction;
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+
+/// Global cap on body bytes being handed to the WAF at once.
+///
+/// Every body passed to the Go side is copied across the FFI boundary, so a
+/// burst of large bodies can spike memory well past `SecRequestBodyLimit`
+/// times one. Each body scan holds its size in bytes from this budget until
+/// it finishes; once the budget is spent, further bodies are skipped or wait,
+/// per `on_exhausted`.
+pub struct BodyBudget {
+    permits: Arc<Semaphore>,
+    max_bytes: u32,
+    on_exhausted: BodyBudgetAction,
+}
+
+/// Budget held by one in-flight body scan, returned when dropped.
+#[derive(Debug)]
+pub struct BodyBudgetPermit {
+    _permit: OwnedSemaphorePermit,
+}
+
+impl BodyBudget {
+    /// A budget of `max_bytes` in-flight body bytes, capped at 4 GiB.
+    pub fn new(max_bytes: usize, on_exhausted: BodyBudgetAction) -> Self {
+        let max_bytes = u32::try_from(max_bytes).unwrap_or(u32::MAX);
+        Self {
+            permits: Arc::new(Semaphore::new(max_bytes as usize)),
+            max_bytes,
+            on_exhausted,
+        }
+    }
+
+    /// Reserve budget for a body of `len` bytes. A body larger than the
+    /// whole budget reserves all of it, so it can still be scanned alone.
+    ///
+    /// Returns `None` when the body should go unscanned: the budget is
+    /// spent and the action is `skip`. With `wait`, resolves once enough
+    /// earlier scans have finished.
+    pub async fn acquire(&self, len: usize) -> Option<BodyBudgetPermit> {
+        let bytes = u32::try_from(len).unwrap_or(u32::MAX).min(self.max_bytes);
+        let permit = match self.on_exhausted {
+            BodyBudgetAction::Skip => self.permits.clone().try_acquire_many_owned(bytes).ok()?,
+            BodyBudgetAction::Wait => self.permits.clone().acquire_many_owned(bytes).await.ok()?,
+        };
+        Some(BodyBudgetPermit { _permit: permit })
+    }
+
+    /// Whether this budget was built from these settings, so a reload that
+    /// leaves them alone can keep it and the permits held against it.
+    pub fn is_configured_as(&self, max_bytes: usize, on_exhausted: BodyBudgetAction) -> bool {
+        self.max_bytes == u32::try_from(max_bytes).unwrap_or(u32::MAX)
+            && self.on_exhausted == on_exhausted
+    }
+
+    /// Bytes of budget not held by any scan.
+    pub fn available(&self) -> usize {
+        self.permits.available_permits()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    const MAX_BODY: usize = 1024 * 1024;
+
+    #[tokio::test]
+    async fn test_exhausted_budget_skips_bodies() {
+        let budget = BodyBudget::new(2 * MAX_BODY, BodyBudgetAction::Skip);
+        let first = budget.acquire(MAX_BODY).await.expect("within budget");
+        let second = budget.acquire(MAX_BODY).await.expect("within budget");
+        assert_eq!(budget.available(), 0);
+        assert!(budget.acquire(MAX_BODY).await.is_none(), "third body goes unscanned");
+
+        drop(first);
+        assert!(budget.acquire(MAX_BODY).await.is_some());
+        drop(second);
+    }
+
+    #[tokio::test]
+    async fn test_exhausted_budget_applies_backpressure() {
+        let budget = Arc::new(BodyBudget::new(MAX_BODY, BodyBudgetAction::Wait));
+        let first = budget.acquire(MAX_BODY).await.unwrap();
+
+        let waiting = tokio::spawn({
+            let budget = budget.clone();
+            async move { budget.acquire(MAX_BODY).await.is_some() }
+        });
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert!(!waiting.is_finished(), "second body waits for the first");
+
+        drop(first);
+        let acquired = tokio::time::timeout(Duration::from_secs(1), waiting).await;
+        assert!(acquired.unwrap().unwrap());
+        assert_eq!(budget.available(), MAX_BODY);
+    }
+
+    #[tokio::test]
+    async fn test_oversized_body_takes_whole_budget() {
+        let budget = BodyBudget::new(MAX_BODY, BodyBudgetAction::Skip);
+        let permit = budget.acquire(10 * MAX_BODY).await.expect("scanned alone");
+        assert_eq!(budget.available(), 0);
+        assert!(budget.acquire(1).await.is_none());
+        drop(permit);
+    }
+}
@@ -23,6 +23,8 @@ extern "C" {
     ) -> c_int;
     pub fn coraza_intervention_status(tx_id: u64) -> c_int;
     pub fn coraza_intervention_url(tx_id: u64) -> *mut c_char;
+    pub fn coraza_intervention_rule_id(tx_id: u64) -> c_int;
+    pub fn coraza_matched_rules(tx_id: u64) -> *mut c_char;
     pub fn coraza_free_transaction(tx_id: u64);
     pub fn coraza_free_waf(waf_id: u64);
 }
@@ -1,4 +1,10 @@
+pub mod body_budget;
 pub mod ffi;
+pub mod rule_grace;
+pub mod rule_sampler;
 pub mod transaction;
 
+pub use body_budget::{BodyBudget, BodyBudgetPermit};
+pub use rule_grace::RuleGraceRegistry;
+pub use rule_sampler::RuleHitSampler;
 pub use transaction::{WafAction, WafEngine, WafTransaction};
@@ -0,0 +1,105 @@
+use std::collections::HashMap;
+
+use chrono::{DateTime, Duration, Utc};
+use layer7waf_common::RuleGraceConfig;
+
+/// Newly deployed rules that only log their matches until their grace
+/// period ends, so false positives show up before real users are blocked.
+pub struct RuleGraceRegistry {
+    /// When each rule's grace period ends.
+    grace_until: HashMap<String, DateTime<Utc>>,
+}
+
+impl RuleGraceRegistry {
+    pub fn new(rules: &[RuleGraceConfig]) -> Self {
+        let grace_until = rules
+            .iter()
+            .map(|rule| {
+                let hours = i64::try_from(rule.grace_hours).unwrap_or(i64::MAX);
+                let until = rule
+                    .deployed_at
+                    .checked_add_signed(Duration::try_hours(hours).unwrap_or(Duration::MAX))
+                    .unwrap_or(DateTime::<Utc>::MAX_UTC);
+                (rule.id.clone(), until)
+            })
+            .collect();
+        Self { grace_until }
+    }
+
+    /// Whether `rule_id` is still in its grace period at `now`.
+    pub fn in_grace(&self, rule_id: &str, now: DateTime<Utc>) -> bool {
+        self.grace_until.get(rule_id).is_some_and(|until| now < *until)
+    }
+
+    /// Whether a block is waived because the rules behind it are all in
+    /// grace: the rule that interrupted, or, when that one only adds up
+    /// the others' findings (CRS anomaly scoring), every other rule that
+    /// matched. A rule out of grace among them keeps the block.
+    pub fn waives(
+        &self,
+        interrupting_rule: Option<&str>,
+        matched_rules: &[String],
+        now: DateTime<Utc>,
+    ) -> bool {
+        if interrupting_rule.is_some_and(|id| self.in_grace(id, now)) {
+            return true;
+        }
+        let mut contributing = matched_rules
+            .iter()
+            .filter(|id| Some(id.as_str()) != interrupting_rule)
+            .peekable();
+        contributing.peek().is_some() && contributing.all(|id| self.in_grace(id, now))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn registry(now: DateTime<Utc>) -> RuleGraceRegistry {
+        RuleGraceRegistry::new(&[
+            RuleGraceConfig {
+                id: "1000001".to_string(),
+                deployed_at: now - Duration::hours(2),
+                grace_hours: 24,
+            },
+            RuleGraceConfig {
+                id: "1000002".to_string(),
+                deployed_at: now - Duration::hours(48),
+                grace_hours: 24,
+            },
+        ])
+    }
+
+    fn ids(ids: &[&str]) -> Vec<String> {
+        ids.iter().map(|id| id.to_string()).collect()
+    }
+
+    #[test]
+    fn test_rule_in_grace_does_not_block() {
+        let now = Utc::now();
+        let registry = registry(now);
+        assert!(registry.in_grace("1000001", now));
+        assert!(registry.waives(Some("1000001"), &ids(&["1000001"]), now));
+
+        // Anomaly scoring: the evaluation rule interrupts for a rule in grace
+        assert!(registry.waives(Some("949110"), &ids(&["1000001", "949110"]), now));
+    }
+
+    #[test]
+    fn test_rule_out_of_grace_blocks() {
+        let now = Utc::now();
+        let registry = registry(now);
+        assert!(!registry.in_grace("1000002", now));
+        assert!(!registry.waives(Some("1000002"), &ids(&["1000002"]), now));
+
+        // Unlisted rules, alone or next to one in grace, keep blocking
+        assert!(!registry.waives(Some("942100"), &ids(&["942100"]), now));
+        let mixed = ids(&["1000001", "942100", "949110"]);
+        assert!(!registry.waives(Some("949110"), &mixed, now));
+        assert!(!registry.waives(None, &[], now));
+
+        // Grace runs out
+        assert!(!registry.waives(Some("1000001"), &ids(&["1000001"]), now + Duration::hours(23)));
+    }
+}
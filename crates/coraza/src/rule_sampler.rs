@@ -0,0 +1,128 @@
+use std::time::Duration;
+
+use dashmap::DashMap;
+use layer7waf_common::RuleHitSamplingConfig;
+use tracing::info;
+
+/// Per-rule sampled logging of WAF rule hits.
+///
+/// A rule firing thousands of times a second can't have every hit logged,
+/// so only one in `sample_rate` hits of each rule is, starting with the
+/// first. The hits counted since the last [`log_summary`](Self::log_summary)
+/// give the aggregate view.
+pub struct RuleHitSampler {
+    sample_rate: u64,
+    summary_interval: Duration,
+    hits: DashMap<String, RuleHits>,
+}
+
+#[derive(Default)]
+struct RuleHits {
+    total: u64,
+    since_summary: u64,
+}
+
+impl RuleHitSampler {
+    pub fn new(config: &RuleHitSamplingConfig) -> Self {
+        Self {
+            sample_rate: config.sample_rate.max(1),
+            summary_interval: Duration::from_secs(config.summary_interval_secs),
+            hits: DashMap::new(),
+        }
+    }
+
+    /// Count a hit of `rule_id`, returning whether it is one to log.
+    pub fn record(&self, rule_id: &str) -> bool {
+        let mut hits = self.hits.entry(rule_id.to_string()).or_default();
+        hits.total += 1;
+        hits.since_summary += 1;
+        (hits.total - 1).is_multiple_of(self.sample_rate)
+    }
+
+    /// Count a hit of `rule_id` on a request to `uri` from `client_ip`,
+    /// logging it if it is sampled.
+    pub fn sample(&self, rule_id: &str, client_ip: &str, uri: &str) {
+        if self.record(rule_id) {
+            info!(
+                rule_id,
+                client_ip,
+                uri,
+                sample_rate = self.sample_rate,
+                "sampled WAF rule hit"
+            );
+        }
+    }
+
+    /// Hits per rule since the previous call, most frequent first. Rules
+    /// without hits in that time are left out.
+    pub fn take_summary(&self) -> Vec<(String, u64)> {
+        let mut summary: Vec<(String, u64)> = self
+            .hits
+            .iter_mut()
+            .filter_map(|mut entry| {
+                let count = std::mem::take(&mut entry.since_summary);
+                (count > 0).then(|| (entry.key().clone(), count))
+            })
+            .collect();
+        summary.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+        summary
+    }
+
+    /// Log the hits per rule since the previous summary.
+    pub fn log_summary(&self) {
+        for (rule_id, hits) in self.take_summary() {
+            info!(
+                rule_id = %rule_id,
+                hits,
+                interval_secs = self.summary_interval.as_secs(),
+                "WAF rule hit summary"
+            );
+        }
+    }
+
+    /// How often [`log_summary`](Self::log_summary) should be called.
+    pub fn summary_interval(&self) -> Duration {
+        self.summary_interval
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sampler(sample_rate: u64) -> RuleHitSampler {
+        RuleHitSampler::new(&RuleHitSamplingConfig {
+            enabled: true,
+            sample_rate,
+            summary_interval_secs: 60,
+        })
+    }
+
+    #[test]
+    fn test_logs_configured_fraction_of_hits() {
+        let sampler = sampler(100);
+        let logged = (0..10_000).filter(|_| sampler.record("942100")).count();
+        assert!((95..=105).contains(&logged), "logged {} of 10000 hits", logged);
+
+        // Each rule is sampled on its own, so a rare rule's first hit shows up
+        assert!(sampler.record("941100"));
+        assert!(!sampler.record("941100"));
+    }
+
+    #[test]
+    fn test_summary_counts_hits_since_last() {
+        let sampler = sampler(10);
+        for _ in 0..3 {
+            sampler.record("942100");
+        }
+        sampler.record("941100");
+        assert_eq!(
+            sampler.take_summary(),
+            vec![("942100".to_string(), 3), ("941100".to_string(), 1)]
+        );
+
+        sampler.record("941100");
+        assert_eq!(sampler.take_summary(), vec![("941100".to_string(), 1)]);
+        assert!(sampler.take_summary().is_empty());
+    }
+}
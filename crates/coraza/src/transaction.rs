@@ -1,6 +1,7 @@
 use std::ffi::{CStr, CString};
 use std::os::raw::{c_int, c_void};
 
+use crate::body_budget::BodyBudget;
 use crate::ffi;
 
 /// Represents the WAF engine decision for a given processing phase.
@@ -54,12 +55,19 @@ pub struct WafTransaction {
 impl WafTransaction {
     /// Create a new transaction bound to the given WAF engine.
     ///
-    /// # Panics
-    /// Panics if the Go side returns 0.
-    pub fn new(engine: &WafEngine) -> Self {
+    /// Returns an error if the Go side fails to create one, e.g. under
+    /// memory pressure.
+    pub fn new(engine: &WafEngine) -> Result<Self, String> {
         let tx_id = unsafe { ffi::coraza_new_transaction(engine.waf_id) };
-        assert!(tx_id != 0, "coraza_new_transaction failed");
-        Self { tx_id }
+        Self::from_id(tx_id)
+    }
+
+    /// Wrap a transaction ID returned by the Go side, where 0 means failure.
+    fn from_id(tx_id: u64) -> Result<Self, String> {
+        if tx_id == 0 {
+            return Err("coraza_new_transaction failed".to_string());
+        }
+        Ok(Self { tx_id })
     }
 
     /// Process request headers through the WAF.
@@ -105,6 +113,24 @@ impl WafTransaction {
         self.interpret_status(rc)
     }
 
+    /// Process request body bytes through the WAF, holding their size from
+    /// `budget` meanwhile. Returns `None`, with the body unscanned, when the
+    /// budget is spent and set to skip.
+    pub async fn process_request_body_within(
+        &self,
+        body: &[u8],
+        budget: &BodyBudget,
+    ) -> Option<WafAction> {
+        let Some(_permit) = budget.acquire(body.len()).await else {
+            tracing::warn!(
+                bytes = body.len(),
+                "WAF body budget exhausted, request body not scanned"
+            );
+            return None;
+        };
+        Some(self.process_request_body(body))
+    }
+
     /// Process response headers through the WAF.
     ///
     /// `headers` is a slice of `(name, value)` pairs.
@@ -146,6 +172,28 @@ impl WafTransaction {
         self.interpret_status(rc)
     }
 
+    /// ID of the rule that interrupted the transaction, if one did.
+    pub fn interrupting_rule(&self) -> Option<String> {
+        let id = unsafe { ffi::coraza_intervention_rule_id(self.tx_id) };
+        (id > 0).then(|| id.to_string())
+    }
+
+    /// IDs of the rules that matched so far and carry a message.
+    pub fn matched_rules(&self) -> Vec<String> {
+        let ptr = unsafe { ffi::coraza_matched_rules(self.tx_id) };
+        if ptr.is_null() {
+            return Vec::new();
+        }
+        let json = unsafe { CStr::from_ptr(ptr) }.to_string_lossy().into_owned();
+        // The Go side allocated with C.CString; we must free it.
+        unsafe {
+            libc_free(ptr as *mut c_void);
+        }
+        serde_json::from_str::<Vec<i64>>(&json)
+            .map(|ids| ids.iter().map(i64::to_string).collect())
+            .unwrap_or_default()
+    }
+
     /// Convert a C return code into a `WafAction`, checking for redirects.
     fn interpret_status(&self, rc: c_int) -> WafAction {
         if rc <= 0 {
@@ -189,3 +237,14 @@ extern "C" {
     #[link_name = "free"]
     fn libc_free(ptr: *mut c_void);
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_failed_creation_is_an_error() {
+        let err = WafTransaction::from_id(0).err().expect("ID 0 is a failed creation");
+        assert_eq!(err, "coraza_new_transaction failed");
+    }
+}
@@ -1,9 +1,16 @@
+use std::fmt;
 use std::net::IpAddr;
 use std::path::Path;
+use std::sync::atomic::{AtomicU64, Ordering};
 use std::sync::Arc;
+use std::time::Instant;
 
 use arc_swap::ArcSwap;
-use layer7waf_common::{GeoIpConfig, GeoIpDefaultAction, GeoIpMode};
+use dashmap::DashMap;
+use layer7waf_common::{
+    GeoIpConfig, GeoIpMode, GeoIpSelfTest, GeoIpUnknownAction, KeyCapacity, MapFootprint,
+    OverflowAction,
+};
 use tracing::{debug, info, warn};
 
 /// Result of a GeoIP check against the configured country lists.
@@ -12,13 +19,48 @@ pub enum GeoIpAction {
     /// Request is allowed through.
     Allow,
     /// Request should be blocked (country matched blocklist or failed allowlist).
-    Block { country: String },
+    Block { country: String, reason: GeoBlockReason },
     /// Request is allowed but flagged for logging (detect mode).
-    Detect { country: String },
+    Detect { country: String, reason: GeoBlockReason },
+    /// Client should pass a challenge before proceeding
+    /// (`unknown_action: challenge`).
+    Challenge { country: String, reason: GeoBlockReason },
     /// Country could not be determined (private IP, lookup failure, etc.).
     Unknown,
 }
 
+/// Why a request was blocked (or would have been, in detect mode).
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum GeoBlockReason {
+    /// Country is on `blocked_countries`.
+    Blocklisted,
+    /// `allowed_countries` is set and the country is not on it.
+    NotAllowlisted,
+    /// Country could not be determined and `default_action` is `block`.
+    UnknownCountry,
+}
+
+impl GeoBlockReason {
+    /// Stable label used in logs and metrics.
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            GeoBlockReason::Blocklisted => "blocklisted",
+            GeoBlockReason::NotAllowlisted => "not_allowlisted",
+            GeoBlockReason::UnknownCountry => "unknown_country",
+        }
+    }
+}
+
+impl fmt::Display for GeoBlockReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// Well-known public address (Google Public DNS) looked up by the self-test;
+/// any country database should place it somewhere.
+const SELF_TEST_ADDR: IpAddr = IpAddr::V4(std::net::Ipv4Addr::new(8, 8, 8, 8));
+
 /// Minimal struct for deserializing the country ISO code from MaxMind DB.
 #[derive(serde::Deserialize)]
 struct CountryRecord {
@@ -30,23 +72,68 @@ struct CountryInfo {
     iso_code: Option<String>,
 }
 
+/// Bounded cache of GeoIP decisions keyed by client IP. A decision only
+/// changes with the database, so entries live until the next reload; the
+/// oldest are evicted when the cache is full.
+struct DecisionCache {
+    entries: DashMap<String, CachedDecision>,
+    capacity: KeyCapacity,
+}
+
+struct CachedDecision {
+    action: GeoIpAction,
+    inserted: Instant,
+}
+
+impl DecisionCache {
+    fn new(max_entries: usize) -> Self {
+        Self {
+            entries: DashMap::new(),
+            capacity: KeyCapacity {
+                max_keys: max_entries,
+                on_overflow: OverflowAction::EvictOldest,
+            },
+        }
+    }
+
+    fn get(&self, key: &str) -> Option<GeoIpAction> {
+        self.entries.get(key).map(|e| e.action.clone())
+    }
+
+    fn insert(&self, key: String, action: GeoIpAction) {
+        self.capacity.admit(&self.entries, &key, |e| e.inserted);
+        self.entries.insert(
+            key,
+            CachedDecision {
+                action,
+                inserted: Instant::now(),
+            },
+        );
+    }
+}
+
 /// GeoIP filter using a MaxMind `.mmdb` database.
 ///
 /// Uses `ArcSwap` for lock-free hot-reload of the database file.
 pub struct GeoIpFilter {
     reader: ArcSwap<Option<maxminddb::Reader<Vec<u8>>>>,
     config: GeoIpConfig,
+    decisions: Option<DecisionCache>,
+    /// Checks that went to the database, i.e. missed the decision cache.
+    database_lookups: AtomicU64,
 }
 
 impl GeoIpFilter {
     /// Create a new `GeoIpFilter` from the given config.
     ///
-    /// Opens the `.mmdb` file at `config.database_path` if configured.
+    /// Opens the `.mmdb` file at `config.database_path` if configured and
+    /// checks it per `config.self_test` (see [`Self::self_test`]).
     pub fn new(config: GeoIpConfig) -> anyhow::Result<Self> {
         let reader = if let Some(ref path) = config.database_path {
             match maxminddb::Reader::open_readfile(path) {
                 Ok(r) => {
                     info!(path = %path.display(), "loaded GeoIP database");
+                    check_database(&r, path, config.self_test)?;
                     Some(r)
                 }
                 Err(e) => {
@@ -62,17 +149,22 @@ impl GeoIpFilter {
             None
         };
 
-        Ok(Self {
-            reader: ArcSwap::from_pointee(reader),
-            config,
-        })
+        Ok(Self::with_reader(reader, config))
     }
 
     /// Create a `GeoIpFilter` without a database (for testing or when disabled).
     pub fn new_empty(config: GeoIpConfig) -> Self {
+        Self::with_reader(None, config)
+    }
+
+    fn with_reader(reader: Option<maxminddb::Reader<Vec<u8>>>, config: GeoIpConfig) -> Self {
+        let decisions = (config.decision_cache_size > 0)
+            .then(|| DecisionCache::new(config.decision_cache_size));
         Self {
-            reader: ArcSwap::from_pointee(None),
+            reader: ArcSwap::from_pointee(reader),
             config,
+            decisions,
+            database_lookups: AtomicU64::new(0),
         }
     }
 
@@ -90,23 +182,65 @@ impl GeoIpFilter {
         }
     }
 
+    /// Confirm the loaded database answers lookups: a well-known public IP
+    /// must resolve to a country. Catches truncated or wrong-type databases
+    /// before real traffic does. Returns the country found.
+    pub fn self_test(&self) -> anyhow::Result<String> {
+        let guard = self.reader.load();
+        let reader = guard
+            .as_ref()
+            .as_ref()
+            .ok_or_else(|| anyhow::anyhow!("no GeoIP database loaded"))?;
+        self_test_reader(reader)
+    }
+
     /// Check an IP address against the configured country blocklist/allowlist.
+    /// Decisions are served from the decision cache when it is enabled.
     pub fn check(&self, addr: IpAddr) -> GeoIpAction {
-        let country = match self.lookup_country(addr) {
+        let Some(ref decisions) = self.decisions else {
+            return self.check_uncached(addr);
+        };
+        let key = addr.to_string();
+        if let Some(action) = decisions.get(&key) {
+            return action;
+        }
+        let action = self.check_uncached(addr);
+        decisions.insert(key, action.clone());
+        action
+    }
+
+    fn check_uncached(&self, addr: IpAddr) -> GeoIpAction {
+        self.database_lookups.fetch_add(1, Ordering::Relaxed);
+        self.check_country(self.lookup_country(addr))
+    }
+
+    /// Number of checks that looked the client up in the database rather
+    /// than the decision cache.
+    pub fn database_lookups(&self) -> u64 {
+        self.database_lookups.load(Ordering::Relaxed)
+    }
+
+    /// Cached decisions and their approximate memory use.
+    pub fn map_footprint(&self) -> MapFootprint {
+        let entries = self.decisions.as_ref().map_or(0, |d| d.entries.len());
+        MapFootprint::of::<CachedDecision>("geoip:decisions", entries)
+    }
+
+    /// Apply the country lists to an already looked-up country code.
+    fn check_country(&self, country: Option<String>) -> GeoIpAction {
+        let country = match country {
             Some(c) => c,
             None => {
-                // Country unknown — apply default action
-                return match self.config.default_action {
-                    GeoIpDefaultAction::Allow => GeoIpAction::Unknown,
-                    GeoIpDefaultAction::Block => {
-                        if self.config.mode == GeoIpMode::Detect {
-                            GeoIpAction::Unknown
-                        } else {
-                            GeoIpAction::Block {
-                                country: "unknown".to_string(),
-                            }
-                        }
-                    }
+                // Country unknown — detect mode never interferes
+                if self.config.mode == GeoIpMode::Detect {
+                    return GeoIpAction::Unknown;
+                }
+                let country = "unknown".to_string();
+                let reason = GeoBlockReason::UnknownCountry;
+                return match self.config.effective_unknown_action() {
+                    GeoIpUnknownAction::Allow => GeoIpAction::Unknown,
+                    GeoIpUnknownAction::Block => GeoIpAction::Block { country, reason },
+                    GeoIpUnknownAction::Challenge => GeoIpAction::Challenge { country, reason },
                 };
             }
         };
@@ -122,10 +256,7 @@ impl GeoIpFilter {
                 .any(|c| c.to_uppercase() == country_upper);
 
             if !is_allowed {
-                return match self.config.mode {
-                    GeoIpMode::Block => GeoIpAction::Block { country },
-                    GeoIpMode::Detect => GeoIpAction::Detect { country },
-                };
+                return self.flag(country, GeoBlockReason::NotAllowlisted);
             }
             return GeoIpAction::Allow;
         }
@@ -139,31 +270,86 @@ impl GeoIpFilter {
                 .any(|c| c.to_uppercase() == country_upper);
 
             if is_blocked {
-                return match self.config.mode {
-                    GeoIpMode::Block => GeoIpAction::Block { country },
-                    GeoIpMode::Detect => GeoIpAction::Detect { country },
-                };
+                return self.flag(country, GeoBlockReason::Blocklisted);
             }
         }
 
         GeoIpAction::Allow
     }
 
+    /// Block or detect `country` for `reason`, depending on the mode.
+    fn flag(&self, country: String, reason: GeoBlockReason) -> GeoIpAction {
+        match self.config.mode {
+            GeoIpMode::Block => GeoIpAction::Block { country, reason },
+            GeoIpMode::Detect => GeoIpAction::Detect { country, reason },
+        }
+    }
+
     /// Hot-reload the MaxMind database from a new path.
     pub fn reload(&self, path: &Path) -> anyhow::Result<()> {
         let reader = maxminddb::Reader::open_readfile(path).map_err(|e| {
             anyhow::anyhow!("failed to reload GeoIP database {}: {}", path.display(), e)
         })?;
+        check_database(&reader, path, self.config.self_test)?;
         self.reader.store(Arc::new(Some(reader)));
+        if let Some(ref decisions) = self.decisions {
+            decisions.entries.clear();
+        }
         info!(path = %path.display(), "reloaded GeoIP database");
         Ok(())
     }
 }
 
+/// Look up [`SELF_TEST_ADDR`] and return its country.
+fn self_test_reader(reader: &maxminddb::Reader<Vec<u8>>) -> anyhow::Result<String> {
+    let record: CountryRecord = reader
+        .lookup(SELF_TEST_ADDR)
+        .map_err(|e| anyhow::anyhow!("self-test lookup of {} failed: {}", SELF_TEST_ADDR, e))?;
+    record
+        .country
+        .and_then(|c| c.iso_code)
+        .ok_or_else(|| anyhow::anyhow!("self-test lookup of {} returned no country", SELF_TEST_ADDR))
+}
+
+/// Log the database metadata and run the self-test, failing or warning as
+/// `mode` says.
+fn check_database(
+    reader: &maxminddb::Reader<Vec<u8>>,
+    path: &Path,
+    mode: GeoIpSelfTest,
+) -> anyhow::Result<()> {
+    let metadata = &reader.metadata;
+    info!(
+        path = %path.display(),
+        database_type = %metadata.database_type,
+        build_epoch = metadata.build_epoch,
+        ip_version = metadata.ip_version,
+        "GeoIP database metadata"
+    );
+    if mode == GeoIpSelfTest::Off {
+        return Ok(());
+    }
+    match self_test_reader(reader) {
+        Ok(country) => {
+            debug!(addr = %SELF_TEST_ADDR, country = %country, "GeoIP self-test passed");
+            Ok(())
+        }
+        Err(e) if mode == GeoIpSelfTest::Fail => Err(anyhow::anyhow!(
+            "GeoIP database {} failed self-test: {}",
+            path.display(),
+            e
+        )),
+        Err(e) => {
+            warn!(path = %path.display(), error = %e, "GeoIP database failed self-test");
+            Ok(())
+        }
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
-    use layer7waf_common::{GeoIpDefaultAction, GeoIpMode};
+    use layer7waf_common::{GeoIpDefaultAction, GeoIpMode, GeoIpUnknownAction};
 
     fn make_config(
         blocked: Vec<&str>,
@@ -178,6 +364,9 @@ mod tests {
             allowed_countries: allowed.into_iter().map(String::from).collect(),
             mode,
             default_action,
+            unknown_action: None,
+            self_test: GeoIpSelfTest::Fail,
+            decision_cache_size: 1024,
         }
     }
 
@@ -210,7 +399,8 @@ mod tests {
         assert_eq!(
             filter.check(addr),
             GeoIpAction::Block {
-                country: "unknown".to_string()
+                country: "unknown".to_string(),
+                reason: GeoBlockReason::UnknownCountry,
             }
         );
     }
@@ -248,6 +438,9 @@ mod tests {
             allowed_countries: vec![],
             mode: GeoIpMode::Block,
             default_action: GeoIpDefaultAction::Allow,
+            unknown_action: None,
+            self_test: GeoIpSelfTest::Warn,
+            decision_cache_size: 0,
         };
         assert!(GeoIpFilter::new(config).is_err());
     }
@@ -313,6 +506,87 @@ mod tests {
             .any(|c| c.to_uppercase() == "CN"));
     }
 
+    #[test]
+    fn test_reason_blocklist_hit() {
+        let config = make_config(vec!["CN"], vec![], GeoIpMode::Block, GeoIpDefaultAction::Allow);
+        let filter = GeoIpFilter::new_empty(config);
+        assert_eq!(
+            filter.check_country(Some("cn".to_string())),
+            GeoIpAction::Block {
+                country: "cn".to_string(),
+                reason: GeoBlockReason::Blocklisted,
+            }
+        );
+        assert_eq!(filter.check_country(Some("US".to_string())), GeoIpAction::Allow);
+    }
+
+    #[test]
+    fn test_reason_allowlist_miss() {
+        let config = make_config(vec![], vec!["US"], GeoIpMode::Detect, GeoIpDefaultAction::Allow);
+        let filter = GeoIpFilter::new_empty(config);
+        assert_eq!(
+            filter.check_country(Some("RU".to_string())),
+            GeoIpAction::Detect {
+                country: "RU".to_string(),
+                reason: GeoBlockReason::NotAllowlisted,
+            }
+        );
+        assert_eq!(filter.check_country(Some("US".to_string())), GeoIpAction::Allow);
+    }
+
+    #[test]
+    fn test_reason_unknown_default_block() {
+        let config = make_config(vec![], vec!["US"], GeoIpMode::Block, GeoIpDefaultAction::Block);
+        let filter = GeoIpFilter::new_empty(config);
+        match filter.check_country(None) {
+            GeoIpAction::Block { reason, .. } => {
+                assert_eq!(reason, GeoBlockReason::UnknownCountry);
+                assert_eq!(reason.to_string(), "unknown_country");
+            }
+            other => panic!("expected Block, got {:?}", other),
+        }
+    }
+
+    fn unknown_action_filter(action: GeoIpUnknownAction) -> GeoIpFilter {
+        // default_action Allow shows that unknown_action takes precedence
+        let mut config =
+            make_config(vec!["CN"], vec![], GeoIpMode::Block, GeoIpDefaultAction::Allow);
+        config.unknown_action = Some(action);
+        GeoIpFilter::new_empty(config)
+    }
+
+    #[test]
+    fn test_unknown_action_allow() {
+        let filter = unknown_action_filter(GeoIpUnknownAction::Allow);
+        assert_eq!(filter.check("10.0.0.1".parse().unwrap()), GeoIpAction::Unknown);
+    }
+
+    #[test]
+    fn test_unknown_action_block() {
+        let filter = unknown_action_filter(GeoIpUnknownAction::Block);
+        assert_eq!(
+            filter.check("10.0.0.1".parse().unwrap()),
+            GeoIpAction::Block {
+                country: "unknown".to_string(),
+                reason: GeoBlockReason::UnknownCountry,
+            }
+        );
+    }
+
+    #[test]
+    fn test_unknown_action_challenge() {
+        let filter = unknown_action_filter(GeoIpUnknownAction::Challenge);
+        assert_eq!(
+            filter.check("10.0.0.1".parse().unwrap()),
+            GeoIpAction::Challenge {
+                country: "unknown".to_string(),
+                reason: GeoBlockReason::UnknownCountry,
+            }
+        );
+        // Known countries still follow the blocklist
+        assert_eq!(filter.check_country(Some("US".to_string())), GeoIpAction::Allow);
+    }
+
     /// When both blocklist and allowlist are empty, everything should be allowed.
     #[test]
     fn test_empty_lists_allow_all() {
@@ -324,4 +598,139 @@ mod tests {
             GeoIpAction::Unknown
         );
     }
+
+    /// Build a minimal IPv4 MaxMind DB: one search-tree node whose records
+    /// both point at `{"country": {"iso_code": country}}`, or at "no data"
+    /// when `country` is `None`.
+    fn tiny_mmdb(country: Option<&str>) -> Vec<u8> {
+        fn string(buf: &mut Vec<u8>, s: &str) {
+            buf.push(0x40 | s.len() as u8);
+            buf.extend_from_slice(s.as_bytes());
+        }
+        /// `control` is the control byte's type bits, followed by the
+        /// extended-type byte for types above 7.
+        fn uint(buf: &mut Vec<u8>, control: &[u8], value: u64) {
+            let bytes: Vec<u8> = value
+                .to_be_bytes()
+                .into_iter()
+                .skip_while(|b| *b == 0)
+                .collect();
+            buf.push(control[0] | bytes.len() as u8);
+            buf.extend_from_slice(&control[1..]);
+            buf.extend_from_slice(&bytes);
+        }
+
+        const NODE_COUNT: u32 = 1;
+        let record = match country {
+            Some(_) => NODE_COUNT + 16, // first byte of the data section
+            None => NODE_COUNT,         // "address not found"
+        };
+        let mut buf = Vec::new();
+        for _ in 0..2 {
+            buf.extend_from_slice(&record.to_be_bytes()[1..]);
+        }
+        buf.extend_from_slice(&[0; 16]);
+
+        if let Some(country) = country {
+            buf.push(0xE1);
+            string(&mut buf, "country");
+            buf.push(0xE1);
+            string(&mut buf, "iso_code");
+            string(&mut buf, country);
+        }
+
+        buf.extend_from_slice(b"\xab\xcd\xefMaxMind.com");
+        buf.push(0xE9);
+        string(&mut buf, "binary_format_major_version");
+        uint(&mut buf, &[0xA0], 2);
+        string(&mut buf, "binary_format_minor_version");
+        uint(&mut buf, &[0xA0], 0);
+        string(&mut buf, "build_epoch");
+        uint(&mut buf, &[0x00, 0x02], 1_700_000_000); // extended type: uint64
+        string(&mut buf, "database_type");
+        string(&mut buf, "Test-Country");
+        string(&mut buf, "description");
+        buf.push(0xE0);
+        string(&mut buf, "ip_version");
+        uint(&mut buf, &[0xA0], 4);
+        string(&mut buf, "languages");
+        buf.extend_from_slice(&[0x00, 0x04]); // extended type: empty array
+        string(&mut buf, "node_count");
+        uint(&mut buf, &[0xC0], NODE_COUNT as u64);
+        string(&mut buf, "record_size");
+        uint(&mut buf, &[0xA0], 24);
+        buf
+    }
+
+    fn mmdb_config(name: &str, country: Option<&str>, self_test: GeoIpSelfTest) -> GeoIpConfig {
+        let path = std::env::temp_dir()
+            .join(format!("layer7waf_geoip_{}_{}.mmdb", name, std::process::id()));
+        std::fs::write(&path, tiny_mmdb(country)).unwrap();
+        let mut config =
+            make_config(vec!["US"], vec![], GeoIpMode::Block, GeoIpDefaultAction::Allow);
+        config.database_path = Some(path);
+        config.self_test = self_test;
+        config
+    }
+
+    #[test]
+    fn test_self_test_valid_database() {
+        let config = mmdb_config("valid", Some("US"), GeoIpSelfTest::Fail);
+        let path = config.database_path.clone().unwrap();
+        let filter = GeoIpFilter::new(config).unwrap();
+        std::fs::remove_file(path).unwrap();
+
+        assert_eq!(filter.self_test().unwrap(), "US");
+        assert_eq!(filter.lookup_country("1.2.3.4".parse().unwrap()).as_deref(), Some("US"));
+    }
+
+    #[test]
+    fn test_self_test_empty_database() {
+        let config = mmdb_config("empty_fail", None, GeoIpSelfTest::Fail);
+        let path = config.database_path.clone().unwrap();
+        assert!(GeoIpFilter::new(config).is_err());
+        std::fs::remove_file(path).unwrap();
+
+        // In warn mode the database is still used, but the self-test fails
+        let config = mmdb_config("empty_warn", None, GeoIpSelfTest::Warn);
+        let path = config.database_path.clone().unwrap();
+        let filter = GeoIpFilter::new(config).unwrap();
+        std::fs::remove_file(path).unwrap();
+        assert!(filter.self_test().is_err());
+    }
+
+    #[test]
+    fn test_decisions_cached_until_reload() {
+        let config = mmdb_config("cache", Some("US"), GeoIpSelfTest::Fail);
+        let path = config.database_path.clone().unwrap();
+        let filter = GeoIpFilter::new(config).unwrap();
+        let addr: IpAddr = "1.2.3.4".parse().unwrap();
+
+        for _ in 0..3 {
+            assert!(matches!(filter.check(addr), GeoIpAction::Block { .. }));
+        }
+        assert_eq!(filter.database_lookups(), 1);
+        assert_eq!(filter.map_footprint().entries, 1);
+
+        filter.reload(&path).unwrap();
+        std::fs::remove_file(path).unwrap();
+        filter.check(addr);
+        assert_eq!(filter.database_lookups(), 2);
+    }
+
+    #[test]
+    fn test_reload_rejects_failing_database() {
+        let config = mmdb_config("reload_valid", Some("US"), GeoIpSelfTest::Fail);
+        let valid = config.database_path.clone().unwrap();
+        let filter = GeoIpFilter::new(config).unwrap();
+
+        let empty = std::env::temp_dir()
+            .join(format!("layer7waf_geoip_reload_empty_{}.mmdb", std::process::id()));
+        std::fs::write(&empty, tiny_mmdb(None)).unwrap();
+        assert!(filter.reload(&empty).is_err());
+        assert_eq!(filter.self_test().unwrap(), "US");
+
+        std::fs::remove_file(valid).unwrap();
+        std::fs::remove_file(empty).unwrap();
+    }
 }
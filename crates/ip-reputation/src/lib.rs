@@ -1,16 +1,30 @@
+pub mod penalty;
+mod score;
 mod trie;
 
 use std::io::BufRead;
 use std::net::IpAddr;
 use std::path::Path;
 use std::sync::Arc;
+use std::time::{Duration, Instant};
 
 use arc_swap::ArcSwap;
+use dashmap::DashMap;
 use ipnet::IpNet;
+use layer7waf_common::{IpFamilyPolicy, KeyCapacity, OverflowAction, WafPenaltyConfig};
+use serde::Serialize;
 use tracing::{debug, info, warn};
 
+use crate::penalty::WafPenalty;
+use crate::score::ScoreMap;
 use crate::trie::IpTrie;
 
+/// Entries listed in a [`ParseReport`]'s sample.
+const REPORT_SAMPLE_SIZE: usize = 10;
+
+/// Runtime blocks held at once without a WAF penalty setting a cap.
+const DEFAULT_MAX_DYNAMIC_BLOCKS: usize = 100_000;
+
 /// The result of checking an IP address against the reputation lists.
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum IpAction {
@@ -18,34 +32,238 @@ pub enum IpAction {
     Allow,
     /// The IP is blocked (present in the blocklist).
     Block,
+    /// The IP is only on a newly loaded blocklist that is still in its
+    /// grace period: log that it would be blocked, but let it through.
+    WouldBlock,
     /// The IP is not in any list; no opinion.
     None,
 }
 
+/// The blocklist and allowlist in effect at one point in time.
+///
+/// Both tries live behind a single pointer so a reload replaces them together;
+/// a lookup never pairs a new blocklist with an old allowlist.
+#[derive(Clone)]
+struct ReputationState {
+    block: Arc<IpTrie>,
+    allow: Arc<IpTrie>,
+    /// Set while `block` is a replacement still in its grace period.
+    grace: Option<BlocklistGrace>,
+}
+
+impl ReputationState {
+    fn empty() -> Self {
+        Self {
+            block: Arc::new(IpTrie::new()),
+            allow: Arc::new(IpTrie::new()),
+            grace: None,
+        }
+    }
+
+    /// Whether `addr` is blocked by the blocklist at `now`. During a grace
+    /// period only entries the previous blocklist also had are enforced.
+    fn blocklist_action(&self, addr: IpAddr, now: Instant) -> IpAction {
+        if !self.block.contains(addr) {
+            return IpAction::None;
+        }
+        match self.grace {
+            Some(ref grace) if grace.active(now) && !grace.previous.contains(addr) => {
+                IpAction::WouldBlock
+            }
+            _ => IpAction::Block,
+        }
+    }
+}
+
+/// A blocklist replacement evaluated in detect mode until `grace` after
+/// `loaded_at`.
+#[derive(Clone)]
+struct BlocklistGrace {
+    /// The blocklist that was replaced.
+    previous: Arc<IpTrie>,
+    loaded_at: Instant,
+    grace: Duration,
+}
+
+impl BlocklistGrace {
+    fn active(&self, now: Instant) -> bool {
+        now < self.loaded_at + self.grace
+    }
+}
+
 /// IP reputation engine backed by prefix tries for efficient CIDR matching.
 ///
 /// Uses `ArcSwap` for lock-free reads, allowing blocklists and allowlists to
 /// be hot-reloaded without blocking lookups in the request path.
 pub struct IpReputation {
-    blocklist: ArcSwap<IpTrie>,
-    allowlist: ArcSwap<IpTrie>,
+    state: ArcSwap<ReputationState>,
+    /// IPs blocked at runtime, with when the block ends. Not affected by
+    /// reloading the lists.
+    dynamic_blocks: DashMap<IpAddr, Instant>,
+    /// Bounds `dynamic_blocks`; the blocks ending soonest make room.
+    dynamic_block_capacity: KeyCapacity,
+    waf_penalty: Option<WafPenalty>,
+    /// How long a replaced blocklist's new entries are only logged.
+    blocklist_grace: Duration,
+    ipv4_policy: IpFamilyPolicy,
+    ipv6_policy: IpFamilyPolicy,
+    /// Probabilistic feed entries, scored rather than blocked.
+    scores: ArcSwap<ScoreMap>,
 }
 
 impl IpReputation {
     /// Create a new `IpReputation` instance with empty blocklist and allowlist.
     pub fn new() -> Self {
         Self {
-            blocklist: ArcSwap::from_pointee(IpTrie::new()),
-            allowlist: ArcSwap::from_pointee(IpTrie::new()),
+            state: ArcSwap::from_pointee(ReputationState::empty()),
+            dynamic_blocks: DashMap::new(),
+            dynamic_block_capacity: KeyCapacity {
+                max_keys: DEFAULT_MAX_DYNAMIC_BLOCKS,
+                on_overflow: OverflowAction::EvictOldest,
+            },
+            waf_penalty: None,
+            blocklist_grace: Duration::ZERO,
+            ipv4_policy: IpFamilyPolicy::Allow,
+            ipv6_policy: IpFamilyPolicy::Allow,
+            scores: ArcSwap::from_pointee(ScoreMap::new()),
         }
     }
 
+    /// Block addresses of a family whose policy is `Block` unless they are
+    /// allowlisted. IPv4-mapped IPv6 addresses count as IPv4.
+    pub fn with_family_policy(mut self, ipv4: IpFamilyPolicy, ipv6: IpFamilyPolicy) -> Self {
+        self.ipv4_policy = ipv4;
+        self.ipv6_policy = ipv6;
+        self
+    }
+
+    /// When a blocklist is replaced, only log matches of entries the old
+    /// one didn't have for `grace`, then enforce them. Entries dropped from
+    /// the list stop matching immediately.
+    pub fn with_blocklist_grace(mut self, grace: Duration) -> Self {
+        self.blocklist_grace = grace;
+        self
+    }
+
+    /// Treat the blocklist this instance loaded as a replacement for
+    /// `previous`'s, starting the grace period now. Does nothing without a
+    /// grace period.
+    pub fn replace_blocklist_of(&self, previous: &IpReputation) {
+        self.replace_blocklist_of_at(previous, Instant::now());
+    }
+
+    fn replace_blocklist_of_at(&self, previous: &IpReputation, now: Instant) {
+        if self.blocklist_grace.is_zero() {
+            return;
+        }
+        let previous = Arc::clone(&previous.state.load().block);
+        let grace = self.blocklist_grace;
+        self.state.rcu(|state| ReputationState {
+            grace: Some(BlocklistGrace {
+                previous: Arc::clone(&previous),
+                loaded_at: now,
+                grace,
+            }),
+            ..ReputationState::clone(state)
+        });
+        info!(grace_secs = grace.as_secs(), "new blocklist entries in grace period");
+    }
+
+    /// Remaining grace period of the current blocklist, if any.
+    pub fn blocklist_grace_remaining(&self) -> Option<Duration> {
+        let state = self.state.load();
+        let grace = state.grace.as_ref()?;
+        let remaining = (grace.loaded_at + grace.grace).saturating_duration_since(Instant::now());
+        (!remaining.is_zero()).then_some(remaining)
+    }
+
+    /// Block IPs that repeatedly trip the WAF; see [`Self::record_waf_trip`].
+    /// At most `max_tracked_ips` are also blocked at once.
+    pub fn with_waf_penalty(mut self, config: &WafPenaltyConfig) -> Self {
+        self.waf_penalty = Some(WafPenalty::new(config));
+        self.dynamic_block_capacity.max_keys = config.max_tracked_ips;
+        self
+    }
+
+    /// Take over `previous`'s runtime blocks and WAF penalty counts, so
+    /// rebuilding the reputation on a reload neither lifts blocks nor
+    /// forgets recent trips.
+    pub fn keep_runtime_state_of(&self, previous: &IpReputation) {
+        for block in previous.dynamic_blocks.iter() {
+            self.block_until(*block.key(), *block.value());
+        }
+        if let (Some(penalty), Some(previous)) = (&self.waf_penalty, &previous.waf_penalty) {
+            penalty.keep_scores_of(previous);
+        }
+    }
+
+    /// Block `addr` until `until`, on top of the loaded blocklist. The
+    /// allowlist still takes precedence.
+    pub fn block_until(&self, addr: IpAddr, until: Instant) {
+        self.dynamic_block_capacity.admit(&self.dynamic_blocks, &addr, |until| *until);
+        self.dynamic_blocks.insert(addr, until);
+    }
+
+    /// Drop expired runtime blocks and WAF penalty counts that have decayed
+    /// away; run by the periodic cleanup.
+    pub fn cleanup_expired(&self) {
+        self.cleanup_expired_at(Instant::now());
+    }
+
+    fn cleanup_expired_at(&self, now: Instant) {
+        self.dynamic_blocks.retain(|_, until| now < *until);
+        if let Some(ref penalty) = self.waf_penalty {
+            penalty.cleanup(now);
+        }
+    }
+
+    /// Number of IPs currently blocked at runtime, expired ones included
+    /// until the next cleanup.
+    pub fn dynamic_block_count(&self) -> usize {
+        self.dynamic_blocks.len()
+    }
+
+    /// Count a WAF block against `addr`. Once its decaying count reaches the
+    /// configured threshold the IP is blocked for the cool-down period.
+    /// Returns whether that happened; always `false` without a WAF penalty.
+    pub fn record_waf_trip(&self, addr: IpAddr) -> bool {
+        self.record_waf_trip_at(addr, Instant::now())
+    }
+
+    fn record_waf_trip_at(&self, addr: IpAddr, now: Instant) -> bool {
+        let Some(ref penalty) = self.waf_penalty else {
+            return false;
+        };
+        if !penalty.record_trip(&addr.to_string(), now) {
+            return false;
+        }
+        let block_for = penalty.block_for();
+        self.block_until(addr, now + block_for);
+        warn!(
+            client_ip = %addr,
+            block_secs = block_for.as_secs(),
+            "IP repeatedly tripped the WAF, blocking"
+        );
+        true
+    }
+
+    /// Whether `addr` is under a runtime block at `now`. Expired blocks are
+    /// dropped as they are found.
+    fn dynamically_blocked(&self, addr: IpAddr, now: Instant) -> bool {
+        if self.dynamic_blocks.is_empty() {
+            return false;
+        }
+        self.dynamic_blocks.remove_if(&addr, |_, until| now >= *until);
+        self.dynamic_blocks.contains_key(&addr)
+    }
+
     /// Load a blocklist from a file.
     ///
     /// The file should contain one IP address or CIDR range per line.
     /// Empty lines and lines starting with `#` are skipped. Single IP
     /// addresses without a prefix length are treated as /32 (IPv4) or
-    /// /128 (IPv6).
+    /// /128 (IPv6). An entry may carry a trailing `# expires=<unix_ts>`
+    /// annotation, after which it no longer matches.
     ///
     /// The new trie is atomically swapped in, so concurrent lookups are
     /// never blocked.
@@ -54,7 +272,12 @@ impl IpReputation {
     pub fn load_blocklist(&self, path: &Path) -> anyhow::Result<usize> {
         let trie = load_trie_from_file(path)?;
         let count = trie.len();
-        self.blocklist.store(Arc::new(trie));
+        let trie = Arc::new(trie);
+        self.state.rcu(|state| ReputationState {
+            block: Arc::clone(&trie),
+            allow: Arc::clone(&state.allow),
+            grace: None,
+        });
         info!(path = %path.display(), count, "loaded blocklist");
         Ok(count)
     }
@@ -67,19 +290,62 @@ impl IpReputation {
     pub fn load_allowlist(&self, path: &Path) -> anyhow::Result<usize> {
         let trie = load_trie_from_file(path)?;
         let count = trie.len();
-        self.allowlist.store(Arc::new(trie));
+        let trie = Arc::new(trie);
+        self.state.rcu(|state| ReputationState {
+            allow: Arc::clone(&trie),
+            ..ReputationState::clone(state)
+        });
         info!(path = %path.display(), count, "loaded allowlist");
         Ok(count)
     }
 
-    /// Returns `true` if the address is in the blocklist.
+    /// Load reputation scores from a file, replacing any loaded before.
+    ///
+    /// Each line holds an IP address or CIDR range and a score from 0.0
+    /// (clean) to 1.0 (malicious), e.g. `1.2.3.0/24 0.7`, for feeds that
+    /// rate addresses rather than list them. Comments and blank lines are
+    /// skipped as in [`Self::load_blocklist`].
+    ///
+    /// Returns the number of entries successfully loaded.
+    pub fn load_scores(&self, path: &Path) -> anyhow::Result<usize> {
+        let lines = read_lines(path)?;
+        let (scores, report) = parse_scored_list(lines.iter().map(String::as_str));
+        log_skipped(path, &report);
+        let count = scores.len();
+        self.scores.store(Arc::new(scores));
+        info!(path = %path.display(), count, "loaded reputation scores");
+        Ok(count)
+    }
+
+    /// Score of the most specific scored network containing `addr`, or
+    /// `None` if no scored network does. Independent of the blocklist and
+    /// allowlist.
+    pub fn reputation_score(&self, addr: IpAddr) -> Option<f64> {
+        self.scores.load().score(addr)
+    }
+
+    /// Returns `true` if the address is in the enforced blocklist or
+    /// blocked at runtime.
     pub fn is_blocked(&self, addr: IpAddr) -> bool {
-        self.blocklist.load().contains(addr)
+        let now = Instant::now();
+        self.state.load().blocklist_action(addr, now) == IpAction::Block
+            || self.dynamically_blocked(addr, now)
     }
 
     /// Returns `true` if the address is in the allowlist.
     pub fn is_allowed(&self, addr: IpAddr) -> bool {
-        self.allowlist.load().contains(addr)
+        self.state.load().allow.contains(addr)
+    }
+
+    /// Every network in the loaded blocklist, including expired ones and
+    /// entries still in their grace period. Runtime blocks are not listed.
+    pub fn blocklist_entries(&self) -> Vec<IpNet> {
+        self.state.load().block.entries()
+    }
+
+    /// Every network in the loaded allowlist.
+    pub fn allowlist_entries(&self) -> Vec<IpNet> {
+        self.state.load().allow.entries()
     }
 
     /// Check an IP address against both lists.
@@ -87,46 +353,64 @@ impl IpReputation {
     /// The allowlist takes precedence: if an address appears in both lists,
     /// `IpAction::Allow` is returned. If the address is only in the blocklist,
     /// `IpAction::Block` is returned. Otherwise, `IpAction::None` is returned.
+    /// Both lists are read from the same snapshot. Runtime blocks count as
+    /// blocklist entries. New blocklist entries in their grace period give
+    /// `IpAction::WouldBlock`. An address on neither list is blocked if its
+    /// family's policy is `Block`.
     pub fn check(&self, addr: IpAddr) -> IpAction {
-        if self.is_allowed(addr) {
-            IpAction::Allow
-        } else if self.is_blocked(addr) {
-            IpAction::Block
-        } else {
-            IpAction::None
+        self.check_at(addr, Instant::now())
+    }
+
+    fn check_at(&self, addr: IpAddr, now: Instant) -> IpAction {
+        let state = self.state.load();
+        if state.allow.contains(addr) {
+            return IpAction::Allow;
+        }
+        if self.dynamically_blocked(addr, now) {
+            return IpAction::Block;
+        }
+        match state.blocklist_action(addr, now) {
+            IpAction::None => self.family_action(addr),
+            action => action,
+        }
+    }
+
+    /// The baseline verdict for `addr`'s IP family.
+    fn family_action(&self, addr: IpAddr) -> IpAction {
+        let policy = match addr.to_canonical() {
+            IpAddr::V4(_) => self.ipv4_policy,
+            IpAddr::V6(_) => self.ipv6_policy,
+        };
+        match policy {
+            IpFamilyPolicy::Allow => IpAction::None,
+            IpFamilyPolicy::Block => IpAction::Block,
         }
     }
 
     /// Reload both lists from the given configuration paths.
     ///
     /// If a path is `None`, the corresponding list is reset to empty.
-    /// If a path is `Some` but loading fails, an error is returned and the
-    /// existing list is left unchanged.
+    /// If a path is `Some` but loading fails, an error is returned and both
+    /// existing lists are left unchanged. Otherwise the two new lists are
+    /// swapped in together, so lookups see either the old pair or the new one.
+    /// With a blocklist grace period, the new blocklist starts in it.
     pub fn reload_from_config(
         &self,
         blocklist_path: Option<&Path>,
         allowlist_path: Option<&Path>,
     ) -> anyhow::Result<()> {
-        match blocklist_path {
-            Some(path) => {
-                self.load_blocklist(path)?;
-            }
-            None => {
-                self.blocklist.store(Arc::new(IpTrie::new()));
-                debug!("cleared blocklist (no path configured)");
-            }
-        }
-
-        match allowlist_path {
-            Some(path) => {
-                self.load_allowlist(path)?;
-            }
-            None => {
-                self.allowlist.store(Arc::new(IpTrie::new()));
-                debug!("cleared allowlist (no path configured)");
-            }
-        }
-
+        let block = load_optional(blocklist_path, "blocklist")?;
+        let allow = load_optional(allowlist_path, "allowlist")?;
+        let grace = (!self.blocklist_grace.is_zero()).then(|| BlocklistGrace {
+            previous: Arc::clone(&self.state.load().block),
+            loaded_at: Instant::now(),
+            grace: self.blocklist_grace,
+        });
+        self.state.store(Arc::new(ReputationState {
+            block: Arc::new(block),
+            allow: Arc::new(allow),
+            grace,
+        }));
         Ok(())
     }
 }
@@ -137,21 +421,92 @@ impl Default for IpReputation {
     }
 }
 
-/// Parse a file into an `IpTrie`.
-///
-/// Each line is parsed as either an `IpNet` (CIDR notation) or a bare `IpAddr`
-/// (which is wrapped in /32 or /128). Empty lines and comment lines (starting
-/// with `#`) are skipped. Lines that fail to parse are logged as warnings and
-/// skipped.
+/// A line of a list file that was skipped.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct LineError {
+    /// 1-based line number.
+    pub line: usize,
+    pub content: String,
+    pub reason: String,
+}
+
+/// What parsing a blocklist or allowlist produced.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct ParseReport {
+    /// Lines parsed into an entry.
+    pub loaded: usize,
+    /// Lines that were neither blank, a comment nor a valid entry.
+    pub skipped: usize,
+    /// Why each skipped line was skipped.
+    pub errors: Vec<LineError>,
+    /// Distinct networks in the resulting list; duplicates count once.
+    pub entries: usize,
+    /// The first few networks, in file order.
+    pub sample: Vec<String>,
+}
+
+/// Parse list `contents` exactly as [`IpReputation::load_blocklist`] would,
+/// without applying the result, and report what it would load.
+pub fn validate_list(contents: &str) -> ParseReport {
+    parse_list(contents.lines()).1
+}
+
+/// Load the list named `kind` from `path`, or an empty trie when no path is
+/// configured.
+fn load_optional(path: Option<&Path>, kind: &str) -> anyhow::Result<IpTrie> {
+    match path {
+        Some(path) => {
+            let trie = load_trie_from_file(path)?;
+            info!(path = %path.display(), count = trie.len(), "loaded {}", kind);
+            Ok(trie)
+        }
+        None => {
+            debug!("cleared {} (no path configured)", kind);
+            Ok(IpTrie::new())
+        }
+    }
+}
+
+/// Parse a file into an `IpTrie`. Lines that fail to parse are logged as
+/// warnings and skipped; see [`parse_list`] for the format.
 fn load_trie_from_file(path: &Path) -> anyhow::Result<IpTrie> {
+    let lines = read_lines(path)?;
+    let (trie, report) = parse_list(lines.iter().map(String::as_str));
+    log_skipped(path, &report);
+    Ok(trie)
+}
+
+fn read_lines(path: &Path) -> anyhow::Result<Vec<String>> {
     let file = std::fs::File::open(path)
         .map_err(|e| anyhow::anyhow!("failed to open {}: {}", path.display(), e))?;
-    let reader = std::io::BufReader::new(file);
+    Ok(std::io::BufReader::new(file)
+        .lines()
+        .collect::<std::io::Result<Vec<String>>>()?)
+}
 
+fn log_skipped(path: &Path, report: &ParseReport) {
+    for error in &report.errors {
+        warn!(
+            path = %path.display(),
+            line = error.line,
+            content = %error.content,
+            "skipping line: {}",
+            error.reason
+        );
+    }
+}
+
+/// Parse list lines into an `IpTrie`, reporting what was loaded and skipped.
+///
+/// Each line is parsed as either an `IpNet` (CIDR notation) or a bare `IpAddr`
+/// (which is wrapped in /32 or /128), optionally followed by a comment that
+/// may hold an `expires=<unix_ts>` annotation. Empty lines and comment lines
+/// (starting with `#`) are ignored.
+fn parse_list<'a>(lines: impl Iterator<Item = &'a str>) -> (IpTrie, ParseReport) {
     let mut trie = IpTrie::new();
+    let mut report = ParseReport::default();
 
-    for (line_num, line_result) in reader.lines().enumerate() {
-        let line = line_result?;
+    for (line_num, line) in lines.enumerate() {
         let trimmed = line.trim();
 
         // Skip empty lines and comments.
@@ -159,27 +514,119 @@ fn load_trie_from_file(path: &Path) -> anyhow::Result<IpTrie> {
             continue;
         }
 
-        // Try parsing as CIDR first, then as a bare IP address.
-        if let Ok(network) = trimmed.parse::<IpNet>() {
-            trie.insert(network);
-        } else if let Ok(addr) = trimmed.parse::<IpAddr>() {
-            let network = match addr {
-                IpAddr::V4(_) => IpNet::new(addr, 32),
-                IpAddr::V6(_) => IpNet::new(addr, 128),
+        match parse_entry(trimmed) {
+            Ok((network, expires_at)) => {
+                trie.insert_with_expiry(network, expires_at);
+                report.loaded += 1;
+                if report.sample.len() < REPORT_SAMPLE_SIZE {
+                    report.sample.push(network.trunc().to_string());
+                }
+            }
+            Err(reason) => {
+                report.skipped += 1;
+                report.errors.push(LineError {
+                    line: line_num + 1,
+                    content: trimmed.to_string(),
+                    reason,
+                });
+            }
+        }
+    }
+
+    report.entries = trie.len();
+    (trie, report)
+}
+
+/// Parse scored list lines into a [`ScoreMap`], reporting what was loaded
+/// and skipped. Each entry is a network as in [`parse_list`] followed by a
+/// score in `[0.0, 1.0]`.
+fn parse_scored_list<'a>(lines: impl Iterator<Item = &'a str>) -> (ScoreMap, ParseReport) {
+    let mut scores = ScoreMap::new();
+    let mut report = ParseReport::default();
+
+    for (line_num, line) in lines.enumerate() {
+        let trimmed = line.trim();
+        if trimmed.is_empty() || trimmed.starts_with('#') {
+            continue;
+        }
+
+        match parse_scored_entry(trimmed) {
+            Ok((network, score)) => {
+                scores.insert(network, score);
+                report.loaded += 1;
+                if report.sample.len() < REPORT_SAMPLE_SIZE {
+                    report.sample.push(format!("{} {}", network.trunc(), score));
+                }
+            }
+            Err(reason) => {
+                report.skipped += 1;
+                report.errors.push(LineError {
+                    line: line_num + 1,
+                    content: trimmed.to_string(),
+                    reason,
+                });
             }
-            .expect("valid prefix length for host address");
-            trie.insert(network);
-        } else {
-            warn!(
-                path = %path.display(),
-                line = line_num + 1,
-                content = trimmed,
-                "skipping unparseable line"
-            );
         }
     }
 
-    Ok(trie)
+    report.entries = scores.len();
+    (scores, report)
+}
+
+/// Parse one non-comment scored line into its network and score.
+fn parse_scored_entry(line: &str) -> Result<(IpNet, f64), String> {
+    let entry = line.split_once('#').map_or(line, |(entry, _)| entry);
+    let mut fields = entry.split_whitespace();
+    let (Some(network), Some(score), None) = (fields.next(), fields.next(), fields.next()) else {
+        return Err("expected an IP address or CIDR range and a score".to_string());
+    };
+    let network = parse_network(network)?;
+    match score.parse::<f64>() {
+        Ok(score) if (0.0..=1.0).contains(&score) => Ok((network, score)),
+        _ => Err(format!("invalid score `{}`, expected 0.0 to 1.0", score)),
+    }
+}
+
+/// Parse one non-comment line into its network and expiry.
+fn parse_entry(line: &str) -> Result<(IpNet, Option<u64>), String> {
+    let (entry, comment) = match line.split_once('#') {
+        Some((entry, comment)) => (entry.trim(), Some(comment)),
+        None => (line, None),
+    };
+    let expires_at = comment
+        .map(parse_expiry)
+        .unwrap_or(Ok(None))
+        .map_err(|value| format!("invalid expiry `{}`", value))?;
+
+    Ok((parse_network(entry)?, expires_at))
+}
+
+/// Parse a CIDR range, or a bare IP address as its /32 or /128.
+fn parse_network(entry: &str) -> Result<IpNet, String> {
+    if let Ok(network) = entry.parse::<IpNet>() {
+        Ok(network)
+    } else if let Ok(addr) = entry.parse::<IpAddr>() {
+        let network = match addr {
+            IpAddr::V4(_) => IpNet::new(addr, 32),
+            IpAddr::V6(_) => IpNet::new(addr, 128),
+        }
+        .expect("valid prefix length for host address");
+        Ok(network)
+    } else {
+        Err("not an IP address or CIDR range".to_string())
+    }
+}
+
+/// Extract the `expires=<unix_ts>` annotation from a line comment. Returns
+/// the unparseable value as the error.
+fn parse_expiry(comment: &str) -> Result<Option<u64>, &str> {
+    let Some(value) = comment
+        .split_whitespace()
+        .find_map(|word| word.strip_prefix("expires="))
+    else {
+        return Ok(None);
+    };
+    value.parse().map(Some).map_err(|_| value)
 }
 
 #[cfg(test)]
@@ -228,6 +675,19 @@ mod tests {
         assert!(!rep.is_allowed(addr));
     }
 
+    #[test]
+    fn test_ipv6_policy_blocks_unlisted_ipv6() {
+        let allowlist = TempFile::new("2001:db8::1\n");
+        let rep = IpReputation::new()
+            .with_family_policy(IpFamilyPolicy::Allow, IpFamilyPolicy::Block);
+        rep.load_allowlist(allowlist.path()).unwrap();
+
+        assert_eq!(rep.check("2001:db8::2".parse().unwrap()), IpAction::Block);
+        assert_eq!(rep.check("2001:db8::1".parse().unwrap()), IpAction::Allow);
+        assert_eq!(rep.check("203.0.113.7".parse().unwrap()), IpAction::None);
+        assert_eq!(rep.check("::ffff:203.0.113.7".parse().unwrap()), IpAction::None);
+    }
+
     #[test]
     fn test_load_blocklist() {
         let file = TempFile::new(
@@ -317,6 +777,181 @@ mod tests {
         assert_eq!(count, 2);
     }
 
+    #[test]
+    fn test_validate_reports_mixed_lines() {
+        let report = validate_list(
+            "# feed\n\
+             10.0.0.0/8\n\
+             not-an-ip\n\
+             \n\
+             192.168.1.1\n\
+             10.0.0.0/8\n\
+             10.0.0.9 # expires=soon\n\
+             2001:db8::/32\n",
+        );
+
+        assert_eq!(report.loaded, 4);
+        assert_eq!(report.skipped, 2);
+        // The duplicate /8 is one entry
+        assert_eq!(report.entries, 3);
+        let lines: Vec<usize> = report.errors.iter().map(|e| e.line).collect();
+        assert_eq!(lines, [3, 7]);
+        assert_eq!(report.errors[0].content, "not-an-ip");
+        assert!(report.errors[1].reason.contains("soon"));
+        assert_eq!(report.sample[..2], ["10.0.0.0/8", "192.168.1.1/32"]);
+    }
+
+    #[test]
+    fn test_validate_matches_load() {
+        let contents = "10.0.0.1\nbad\n172.16.0.0/12\n";
+        let file = TempFile::new(contents);
+        let rep = IpReputation::new();
+        let count = rep.load_blocklist(file.path()).unwrap();
+        assert_eq!(validate_list(contents).entries, count);
+    }
+
+    #[test]
+    fn test_parse_scored_entries() {
+        let (scores, report) = parse_scored_list(
+            "# feed\n\
+             1.2.3.4/32 0.7\n\
+             10.0.0.0/8 0.2  # expires=never\n\
+             2001:db8::1 1\n\
+             5.6.7.8\n\
+             5.6.7.9 1.5\n\
+             5.6.7.10 0.5 extra\n"
+                .lines(),
+        );
+        assert_eq!(report.loaded, 3);
+        assert_eq!(report.skipped, 3);
+        assert_eq!(report.errors[1].reason, "invalid score `1.5`, expected 0.0 to 1.0");
+        assert_eq!(scores.score("1.2.3.4".parse().unwrap()), Some(0.7));
+        assert_eq!(scores.score("2001:db8::1".parse().unwrap()), Some(1.0));
+    }
+
+    #[test]
+    fn test_reputation_score_longest_match() {
+        let file = TempFile::new("10.0.0.0/8 0.3\n10.1.0.0/16 0.8\n");
+        let rep = IpReputation::new();
+        assert_eq!(rep.load_scores(file.path()).unwrap(), 2);
+
+        assert_eq!(rep.reputation_score("10.1.2.3".parse().unwrap()), Some(0.8));
+        assert_eq!(rep.reputation_score("10.2.0.1".parse().unwrap()), Some(0.3));
+        assert_eq!(rep.reputation_score("192.0.2.1".parse().unwrap()), None);
+        // Scores never block by themselves
+        assert_eq!(rep.check("10.1.2.3".parse().unwrap()), IpAction::None);
+    }
+
+    fn waf_penalty() -> WafPenaltyConfig {
+        WafPenaltyConfig {
+            enabled: true,
+            threshold: 3,
+            half_life_secs: 60,
+            block_secs: 600,
+            max_tracked_ips: 100,
+        }
+    }
+
+    #[test]
+    fn test_repeated_waf_trips_block_ip() {
+        use std::time::Duration;
+
+        let rep = IpReputation::new().with_waf_penalty(&waf_penalty());
+        let prober: IpAddr = "203.0.113.7".parse().unwrap();
+        let start = Instant::now();
+
+        assert!(!rep.record_waf_trip_at(prober, start));
+        assert!(!rep.record_waf_trip_at(prober, start + Duration::from_secs(2)));
+        assert_eq!(rep.check_at(prober, start + Duration::from_secs(3)), IpAction::None);
+        assert!(rep.record_waf_trip_at(prober, start + Duration::from_secs(4)));
+        assert_eq!(rep.check_at(prober, start + Duration::from_secs(5)), IpAction::Block);
+
+        // The block lifts after the cool-down
+        let after = start + Duration::from_secs(4 + 600);
+        assert_eq!(rep.check_at(prober, after), IpAction::None);
+    }
+
+    #[test]
+    fn test_sparse_waf_trips_not_blocked() {
+        use std::time::Duration;
+
+        let rep = IpReputation::new().with_waf_penalty(&waf_penalty());
+        let addr: IpAddr = "203.0.113.8".parse().unwrap();
+        let start = Instant::now();
+        for i in 0..10 {
+            let at = start + Duration::from_secs(i * 600);
+            assert!(!rep.record_waf_trip_at(addr, at));
+            assert_eq!(rep.check_at(addr, at), IpAction::None);
+        }
+    }
+
+    #[test]
+    fn test_waf_penalty_respects_allowlist_and_reload() {
+        let allowlist = TempFile::new("10.0.0.1\n");
+        let rep = IpReputation::new().with_waf_penalty(&waf_penalty());
+        rep.load_allowlist(allowlist.path()).unwrap();
+        let allowed: IpAddr = "10.0.0.1".parse().unwrap();
+        let other: IpAddr = "10.0.0.2".parse().unwrap();
+        for _ in 0..3 {
+            rep.record_waf_trip(allowed);
+            rep.record_waf_trip(other);
+        }
+        assert_eq!(rep.check(allowed), IpAction::Allow);
+        assert_eq!(rep.check(other), IpAction::Block);
+
+        // Reloading the lists keeps runtime blocks
+        rep.reload_from_config(None, Some(allowlist.path())).unwrap();
+        assert!(rep.is_blocked(other));
+    }
+
+    #[test]
+    fn test_runtime_state_kept_across_rebuild() {
+        let previous = IpReputation::new().with_waf_penalty(&waf_penalty());
+        let blocked: IpAddr = "203.0.113.7".parse().unwrap();
+        let tripped: IpAddr = "203.0.113.8".parse().unwrap();
+        for _ in 0..3 {
+            previous.record_waf_trip(blocked);
+        }
+        previous.record_waf_trip(tripped);
+        previous.record_waf_trip(tripped);
+
+        let rebuilt = IpReputation::new().with_waf_penalty(&waf_penalty());
+        rebuilt.keep_runtime_state_of(&previous);
+        assert!(rebuilt.is_blocked(blocked));
+        // The earlier trips still count toward the threshold
+        assert!(rebuilt.record_waf_trip(tripped));
+    }
+
+    #[test]
+    fn test_dynamic_blocks_bounded_and_swept() {
+        use std::time::Duration;
+
+        let mut config = waf_penalty();
+        config.max_tracked_ips = 10;
+        let rep = IpReputation::new().with_waf_penalty(&config);
+        let now = Instant::now();
+        for i in 0..50u64 {
+            let addr = IpAddr::from([10, 0, 0, i as u8]);
+            rep.block_until(addr, now + Duration::from_secs(60 + i));
+        }
+        assert!(rep.dynamic_block_count() <= 10);
+        // The blocks ending last are the ones kept
+        assert!(rep.is_blocked(IpAddr::from([10, 0, 0, 49])));
+
+        rep.cleanup_expired_at(now + Duration::from_secs(3600));
+        assert_eq!(rep.dynamic_block_count(), 0);
+    }
+
+    #[test]
+    fn test_waf_trips_ignored_without_penalty() {
+        let rep = IpReputation::new();
+        let addr: IpAddr = "10.0.0.2".parse().unwrap();
+        for _ in 0..100 {
+            assert!(!rep.record_waf_trip(addr));
+        }
+        assert!(!rep.is_blocked(addr));
+    }
+
     #[test]
     fn test_file_not_found() {
         let rep = IpReputation::new();
@@ -337,4 +972,155 @@ mod tests {
         assert!(rep.is_blocked("2001:db8::1".parse().unwrap()));
         assert!(!rep.is_blocked("2001:db8::2".parse().unwrap()));
     }
+
+    #[test]
+    fn test_blocklist_entry_expiry() {
+        let now = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap()
+            .as_secs();
+        let file = TempFile::new(&format!(
+            "10.0.0.1 # expires={}\n\
+             10.0.0.2  # from feed X, expires={}\n\
+             10.0.0.3 # no expiry here\n\
+             10.0.0.4 # expires=soon\n",
+            now - 60,
+            now + 3600,
+        ));
+
+        let rep = IpReputation::new();
+        assert_eq!(rep.load_blocklist(file.path()).unwrap(), 3);
+
+        assert!(!rep.is_blocked("10.0.0.1".parse().unwrap()), "expired entry must not match");
+        assert!(rep.is_blocked("10.0.0.2".parse().unwrap()), "future-dated entry must match");
+        assert!(rep.is_blocked("10.0.0.3".parse().unwrap()));
+        assert!(!rep.is_blocked("10.0.0.4".parse().unwrap()));
+    }
+
+    #[test]
+    fn test_reload_swaps_both_lists_together() {
+        use std::sync::atomic::{AtomicBool, Ordering};
+
+        // Generation A blocks X and allows Y; generation B the reverse. A
+        // lookup pairing B's blocklist with A's allowlist would see X in
+        // neither list.
+        let block_a = TempFile::new("10.0.0.1\n");
+        let allow_a = TempFile::new("10.0.0.2\n");
+        let block_b = TempFile::new("10.0.0.2\n");
+        let allow_b = TempFile::new("10.0.0.1\n");
+        let x: IpAddr = "10.0.0.1".parse().unwrap();
+
+        let rep = Arc::new(IpReputation::new());
+        rep.reload_from_config(Some(block_a.path()), Some(allow_a.path()))
+            .unwrap();
+        let done = Arc::new(AtomicBool::new(false));
+
+        let reader = {
+            let rep = Arc::clone(&rep);
+            let done = Arc::clone(&done);
+            std::thread::spawn(move || {
+                while !done.load(Ordering::Relaxed) {
+                    assert_ne!(rep.check(x), IpAction::None, "observed a half-updated state");
+                }
+            })
+        };
+
+        for i in 0..200 {
+            let (block, allow) = if i % 2 == 0 {
+                (&block_b, &allow_b)
+            } else {
+                (&block_a, &allow_a)
+            };
+            rep.reload_from_config(Some(block.path()), Some(allow.path()))
+                .unwrap();
+        }
+        done.store(true, Ordering::Relaxed);
+        reader.join().unwrap();
+    }
+
+    #[test]
+    fn test_failed_reload_keeps_both_lists() {
+        let blocklist_file = TempFile::new("10.0.0.0/8\n");
+        let allowlist_file = TempFile::new("10.0.0.1\n");
+
+        let rep = IpReputation::new();
+        rep.reload_from_config(Some(blocklist_file.path()), Some(allowlist_file.path()))
+            .unwrap();
+
+        let result = rep.reload_from_config(None, Some(Path::new("/nonexistent/allowlist.txt")));
+        assert!(result.is_err());
+        assert!(rep.is_blocked("10.0.0.2".parse().unwrap()));
+        assert!(rep.is_allowed("10.0.0.1".parse().unwrap()));
+    }
+    #[test]
+    fn test_list_entries_read_back() {
+        let blocklist = TempFile::new("10.0.0.0/8\n192.168.1.5\n2001:db8::/32\n");
+        let allowlist = TempFile::new("10.0.0.1\n");
+        let rep = IpReputation::new();
+        rep.reload_from_config(Some(blocklist.path()), Some(allowlist.path()))
+            .unwrap();
+
+        let mut blocked: Vec<String> =
+            rep.blocklist_entries().iter().map(|n| n.to_string()).collect();
+        blocked.sort();
+        assert_eq!(blocked, ["10.0.0.0/8", "192.168.1.5/32", "2001:db8::/32"]);
+        let allowed: Vec<String> = rep.allowlist_entries().iter().map(|n| n.to_string()).collect();
+        assert_eq!(allowed, ["10.0.0.1/32"]);
+    }
+
+    #[test]
+    fn test_new_blocklist_entries_logged_during_grace() {
+        let old_list = TempFile::new("10.0.0.1\n10.0.0.2\n");
+        let new_list = TempFile::new("10.0.0.1\n10.0.0.3\n");
+        let grace = Duration::from_secs(300);
+
+        let previous = IpReputation::new();
+        previous.load_blocklist(old_list.path()).unwrap();
+        let rep = IpReputation::new().with_blocklist_grace(grace);
+        rep.load_blocklist(new_list.path()).unwrap();
+        let loaded_at = Instant::now();
+        rep.replace_blocklist_of_at(&previous, loaded_at);
+
+        let kept: IpAddr = "10.0.0.1".parse().unwrap();
+        let added: IpAddr = "10.0.0.3".parse().unwrap();
+        let dropped: IpAddr = "10.0.0.2".parse().unwrap();
+        let during = loaded_at + Duration::from_secs(10);
+        assert_eq!(rep.check_at(kept, during), IpAction::Block);
+        assert_eq!(rep.check_at(added, during), IpAction::WouldBlock);
+        assert_eq!(rep.check_at(dropped, during), IpAction::None);
+
+        let after = loaded_at + grace;
+        assert_eq!(rep.check_at(added, after), IpAction::Block);
+        assert_eq!(rep.check_at(dropped, after), IpAction::None);
+    }
+
+    #[test]
+    fn test_reload_starts_grace_period() {
+        let old_list = TempFile::new("10.0.0.1\n");
+        let new_list = TempFile::new("10.0.0.1\n10.0.0.3\n");
+
+        let rep = IpReputation::new().with_blocklist_grace(Duration::from_secs(300));
+        rep.load_blocklist(old_list.path()).unwrap();
+        // The first load is enforced right away
+        assert_eq!(rep.check("10.0.0.1".parse().unwrap()), IpAction::Block);
+        assert!(rep.blocklist_grace_remaining().is_none());
+
+        rep.reload_from_config(Some(new_list.path()), None).unwrap();
+        let added: IpAddr = "10.0.0.3".parse().unwrap();
+        assert_eq!(rep.check(added), IpAction::WouldBlock);
+        assert!(!rep.is_blocked(added));
+        assert!(rep.blocklist_grace_remaining().is_some());
+    }
+
+    #[test]
+    fn test_no_grace_enforces_immediately() {
+        let old_list = TempFile::new("10.0.0.1\n");
+        let new_list = TempFile::new("10.0.0.3\n");
+        let previous = IpReputation::new();
+        previous.load_blocklist(old_list.path()).unwrap();
+        let rep = IpReputation::new();
+        rep.load_blocklist(new_list.path()).unwrap();
+        rep.replace_blocklist_of(&previous);
+        assert_eq!(rep.check("10.0.0.3".parse().unwrap()), IpAction::Block);
+    }
 }
@@ -0,0 +1,149 @@
+use std::time::{Duration, Instant};
+
+use dashmap::DashMap;
+use layer7waf_common::{KeyCapacity, OverflowAction, WafPenaltyConfig};
+
+/// Half-lives after which a count is negligible and dropped by
+/// [`WafPenalty::cleanup`].
+const FORGET_AFTER_HALF_LIVES: u32 = 10;
+
+/// One IP's decaying WAF block count.
+#[derive(Clone, Copy)]
+struct PenaltyScore {
+    score: f64,
+    last_trip: Instant,
+}
+
+/// Per-IP count of WAF blocks that halves every `half_life_secs`, so only
+/// trips close together add up to the threshold.
+pub struct WafPenalty {
+    threshold: f64,
+    half_life: Duration,
+    block_for: Duration,
+    scores: DashMap<String, PenaltyScore>,
+    capacity: KeyCapacity,
+}
+
+impl WafPenalty {
+    pub fn new(config: &WafPenaltyConfig) -> Self {
+        Self {
+            threshold: f64::from(config.threshold),
+            half_life: Duration::from_secs(config.half_life_secs),
+            block_for: Duration::from_secs(config.block_secs),
+            scores: DashMap::new(),
+            capacity: KeyCapacity {
+                max_keys: config.max_tracked_ips,
+                on_overflow: OverflowAction::EvictOldest,
+            },
+        }
+    }
+
+    /// How long an IP stays blocked once over the threshold.
+    pub fn block_for(&self) -> Duration {
+        self.block_for
+    }
+
+    /// Record a WAF block for `client_ip` at `now`. Returns `true` when its
+    /// decayed count, rounded to the nearest trip, reaches the threshold;
+    /// the count then starts over. Rounding lets a quick burst of
+    /// `threshold` trips count in full despite the decay between them.
+    pub fn record_trip(&self, client_ip: &str, now: Instant) -> bool {
+        self.capacity.admit(&self.scores, client_ip, |s| s.last_trip);
+        let mut entry = self
+            .scores
+            .entry(client_ip.to_string())
+            .or_insert(PenaltyScore { score: 0.0, last_trip: now });
+        let elapsed = now.saturating_duration_since(entry.last_trip);
+        let decay = 0.5f64.powf(elapsed.as_secs_f64() / self.half_life.as_secs_f64());
+        entry.score = entry.score * decay + 1.0;
+        entry.last_trip = now;
+        if entry.score.round() >= self.threshold {
+            drop(entry);
+            self.scores.remove(client_ip);
+            return true;
+        }
+        false
+    }
+
+    /// Number of IPs with a penalty count.
+    pub fn tracked(&self) -> usize {
+        self.scores.len()
+    }
+
+    /// Take over `previous`'s counts, within this penalty's capacity.
+    pub fn keep_scores_of(&self, previous: &WafPenalty) {
+        for entry in previous.scores.iter() {
+            self.capacity.admit(&self.scores, entry.key().as_str(), |s| s.last_trip);
+            self.scores.insert(entry.key().clone(), *entry.value());
+        }
+    }
+
+    /// Forget counts that have decayed to nothing by `now`.
+    pub fn cleanup(&self, now: Instant) {
+        let forget_after = self.half_life * FORGET_AFTER_HALF_LIVES;
+        self.scores
+            .retain(|_, s| now.saturating_duration_since(s.last_trip) < forget_after);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn penalty(threshold: u32) -> WafPenalty {
+        WafPenalty::new(&WafPenaltyConfig {
+            enabled: true,
+            threshold,
+            half_life_secs: 60,
+            block_secs: 900,
+            max_tracked_ips: 100,
+        })
+    }
+
+    #[test]
+    fn test_burst_of_trips_crosses_threshold() {
+        let penalty = penalty(5);
+        let start = Instant::now();
+        let crossed: Vec<bool> = (0..5)
+            .map(|i| penalty.record_trip("10.0.0.1", start + Duration::from_secs(i)))
+            .collect();
+        assert_eq!(crossed, [false, false, false, false, true]);
+        // The count starts over once the IP is blocked
+        assert_eq!(penalty.tracked(), 0);
+    }
+
+    #[test]
+    fn test_sparse_trips_decay() {
+        let penalty = penalty(5);
+        let start = Instant::now();
+        for i in 0..20 {
+            let at = start + Duration::from_secs(i * 300);
+            assert!(!penalty.record_trip("10.0.0.1", at), "trip {} crossed", i);
+        }
+    }
+
+    #[test]
+    fn test_tracked_ips_bounded() {
+        let mut config = WafPenaltyConfig {
+            enabled: true,
+            ..Default::default()
+        };
+        config.max_tracked_ips = 10;
+        let penalty = WafPenalty::new(&config);
+        let now = Instant::now();
+        for i in 0..50 {
+            penalty.record_trip(&format!("10.0.0.{}", i), now);
+        }
+        assert!(penalty.tracked() <= 10);
+    }
+
+    #[test]
+    fn test_decayed_counts_cleaned_up() {
+        let penalty = penalty(5);
+        let start = Instant::now();
+        penalty.record_trip("10.0.0.1", start);
+        penalty.record_trip("10.0.0.2", start + Duration::from_secs(600));
+        penalty.cleanup(start + Duration::from_secs(601));
+        assert_eq!(penalty.tracked(), 1);
+    }
+}
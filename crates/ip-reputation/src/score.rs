@@ -0,0 +1,83 @@
+use std::collections::HashMap;
+use std::net::IpAddr;
+
+use ipnet::IpNet;
+
+/// Reputation scores of networks, looked up by longest matching prefix.
+///
+/// Scores are kept per network; a lookup tries each prefix length present
+/// in the map, longest first, so it costs one hash lookup per distinct
+/// length rather than one per entry.
+#[derive(Default)]
+pub struct ScoreMap {
+    scores: HashMap<IpNet, f64>,
+    /// Prefix lengths in use per family, longest first.
+    prefix_lens_v4: Vec<u8>,
+    prefix_lens_v6: Vec<u8>,
+}
+
+impl ScoreMap {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Score `network`, replacing any earlier score of the same network.
+    pub fn insert(&mut self, network: IpNet, score: f64) {
+        let network = network.trunc();
+        let lens = match network {
+            IpNet::V4(_) => &mut self.prefix_lens_v4,
+            IpNet::V6(_) => &mut self.prefix_lens_v6,
+        };
+        if let Err(i) = lens.binary_search_by(|len| network.prefix_len().cmp(len)) {
+            lens.insert(i, network.prefix_len());
+        }
+        self.scores.insert(network, score);
+    }
+
+    /// Score of the most specific network containing `addr`.
+    pub fn score(&self, addr: IpAddr) -> Option<f64> {
+        let lens = match addr {
+            IpAddr::V4(_) => &self.prefix_lens_v4,
+            IpAddr::V6(_) => &self.prefix_lens_v6,
+        };
+        lens.iter().find_map(|&len| {
+            let network = IpNet::new(addr, len).ok()?.trunc();
+            self.scores.get(&network).copied()
+        })
+    }
+
+    /// Number of scored networks.
+    pub fn len(&self) -> usize {
+        self.scores.len()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_longest_match_wins() {
+        let mut map = ScoreMap::new();
+        map.insert("10.0.0.0/8".parse().unwrap(), 0.3);
+        map.insert("10.1.0.0/16".parse().unwrap(), 0.7);
+        map.insert("10.1.2.3/32".parse().unwrap(), 0.9);
+        map.insert("2001:db8::/32".parse().unwrap(), 0.5);
+
+        assert_eq!(map.score("10.1.2.3".parse().unwrap()), Some(0.9));
+        assert_eq!(map.score("10.1.2.4".parse().unwrap()), Some(0.7));
+        assert_eq!(map.score("10.2.0.1".parse().unwrap()), Some(0.3));
+        assert_eq!(map.score("11.0.0.1".parse().unwrap()), None);
+        assert_eq!(map.score("2001:db8::1".parse().unwrap()), Some(0.5));
+        assert_eq!(map.len(), 4);
+    }
+
+    #[test]
+    fn test_host_bits_ignored() {
+        let mut map = ScoreMap::new();
+        map.insert("192.168.1.77/24".parse().unwrap(), 0.4);
+        map.insert("192.168.1.0/24".parse().unwrap(), 0.6);
+        assert_eq!(map.len(), 1);
+        assert_eq!(map.score("192.168.1.1".parse().unwrap()), Some(0.6));
+    }
+}
@@ -1,6 +1,7 @@
-use std::net::IpAddr;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::time::{SystemTime, UNIX_EPOCH};
 
-use ipnet::IpNet;
+use ipnet::{IpNet, Ipv4Net, Ipv6Net};
 
 /// A binary prefix trie for fast IP/CIDR lookups.
 ///
@@ -14,6 +15,9 @@ pub struct IpTrie {
 struct TrieNode {
     children: [Option<Box<TrieNode>>; 2],
     is_terminal: bool,
+    /// Unix timestamp after which a terminal entry no longer matches;
+    /// `None` never expires.
+    expires_at: Option<u64>,
 }
 
 impl TrieNode {
@@ -21,20 +25,48 @@ impl TrieNode {
         Self {
             children: [None, None],
             is_terminal: false,
+            expires_at: None,
         }
     }
 
+    /// Whether this node is a terminal entry that is still live at `now`.
+    fn matches_at(&self, now: u64) -> bool {
+        self.is_terminal && self.expires_at.is_none_or(|expiry| now < expiry)
+    }
+
     /// Recursively count the number of terminal nodes in this subtree
     /// (including this node).
     fn count_terminals(&self) -> usize {
         let mut count = if self.is_terminal { 1 } else { 0 };
-        for child in &self.children {
-            if let Some(ref node) = child {
-                count += node.count_terminals();
-            }
+        for node in self.children.iter().flatten() {
+            count += node.count_terminals();
         }
         count
     }
+
+    /// Push the network of every terminal in this subtree onto `out`, in
+    /// address order. `prefix` holds the address bits leading here, packed
+    /// from the most significant bit of a `width`-bit address.
+    fn collect_entries(&self, prefix: u128, depth: u8, width: u8, out: &mut Vec<IpNet>) {
+        if self.is_terminal {
+            out.push(bits_to_net(prefix, depth, width));
+        }
+        let children = self.children.iter().enumerate();
+        for (bit, node) in children.filter_map(|(bit, c)| Some((bit, c.as_deref()?))) {
+            let prefix = prefix | ((bit as u128) << (width - depth - 1));
+            node.collect_entries(prefix, depth + 1, width, out);
+        }
+    }
+}
+
+/// The network of the first `prefix_len` bits of `bits`, a `width`-bit
+/// (32 or 128) address.
+fn bits_to_net(bits: u128, prefix_len: u8, width: u8) -> IpNet {
+    if width == 32 {
+        IpNet::V4(Ipv4Net::new(Ipv4Addr::from(bits as u32), prefix_len).unwrap())
+    } else {
+        IpNet::V6(Ipv6Net::new(Ipv6Addr::from(bits), prefix_len).unwrap())
+    }
 }
 
 /// Convert an IP address into a vector of individual bits (0 or 1).
@@ -79,7 +111,15 @@ impl IpTrie {
     /// Converts the network address to bits, walks (or creates) nodes down to
     /// the prefix length, and marks the final node as terminal. Any IP that
     /// falls within this CIDR range will match during lookups.
+    #[cfg(test)]
     pub fn insert(&mut self, network: IpNet) {
+        self.insert_with_expiry(network, None);
+    }
+
+    /// Insert a CIDR network that stops matching at the unix timestamp
+    /// `expires_at`. If the network is already present, the later expiry
+    /// wins, with `None` (never) latest of all.
+    pub fn insert_with_expiry(&mut self, network: IpNet, expires_at: Option<u64>) {
         let addr = network.network();
         let prefix_len = network.prefix_len() as usize;
         let bits = ip_to_bits(addr);
@@ -97,6 +137,11 @@ impl IpTrie {
             }
             current = current.children[idx].as_mut().unwrap();
         }
+        current.expires_at = match (current.is_terminal, current.expires_at, expires_at) {
+            (false, _, new) => new,
+            (true, Some(old), Some(new)) => Some(old.max(new)),
+            (true, _, _) => None,
+        };
         current.is_terminal = true;
     }
 
@@ -105,8 +150,19 @@ impl IpTrie {
     /// Walks the trie bit by bit. If any terminal node is encountered along
     /// the path, the address is contained within that CIDR and `true` is
     /// returned. This naturally handles prefix matching -- a /16 terminal
-    /// will match all /32 addresses within it.
+    /// will match all /32 addresses within it. Entries whose expiry has
+    /// passed are ignored.
     pub fn contains(&self, addr: IpAddr) -> bool {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or(0);
+        self.contains_at(addr, now)
+    }
+
+    /// Like [`contains`](Self::contains), evaluating expiry at the unix
+    /// timestamp `now`.
+    pub fn contains_at(&self, addr: IpAddr, now: u64) -> bool {
         let bits = ip_to_bits(addr);
 
         let root = match addr {
@@ -115,7 +171,7 @@ impl IpTrie {
         };
 
         // Check if the root itself is terminal (a /0 network -- matches everything).
-        if root.is_terminal {
+        if root.matches_at(now) {
             return true;
         }
 
@@ -125,7 +181,7 @@ impl IpTrie {
             match &current.children[idx] {
                 Some(node) => {
                     current = node;
-                    if current.is_terminal {
+                    if current.matches_at(now) {
                         return true;
                     }
                 }
@@ -143,9 +199,20 @@ impl IpTrie {
     }
 
     /// Returns `true` if the trie contains no entries.
+    #[cfg(test)]
     pub fn is_empty(&self) -> bool {
         self.len() == 0
     }
+
+    /// Every inserted network, expired ones included: the inverse of
+    /// [`insert_with_expiry`](Self::insert_with_expiry). IPv4 networks come first, each family in
+    /// address order with a network before the ones nested in it.
+    pub fn entries(&self) -> Vec<IpNet> {
+        let mut entries = Vec::with_capacity(self.len());
+        self.root_v4.collect_entries(0, 0, 32, &mut entries);
+        self.root_v6.collect_entries(0, 0, 128, &mut entries);
+        entries
+    }
 }
 
 #[cfg(test)]
@@ -277,4 +344,59 @@ mod tests {
         // The /8 should still match addresses outside the /24
         assert!(trie.contains("10.1.0.1".parse().unwrap()));
     }
+
+    #[test]
+    fn test_entries_match_inserted() {
+        let inserted: Vec<IpNet> = [
+            "10.0.0.0/8",
+            "10.0.0.0/24",
+            "192.168.1.7/32",
+            "0.0.0.0/0",
+            "2001:db8::/32",
+            "::1/128",
+            "fd00::/8",
+        ]
+        .iter()
+        .map(|net| net.parse().unwrap())
+        .collect();
+
+        let mut trie = IpTrie::new();
+        for net in inserted.iter().rev() {
+            trie.insert(*net);
+        }
+
+        let mut entries = trie.entries();
+        let mut expected = inserted.clone();
+        entries.sort();
+        expected.sort();
+        assert_eq!(entries, expected);
+        assert_eq!(trie.entries().len(), trie.len());
+    }
+
+    #[test]
+    fn test_expired_entry_ignored() {
+        let mut trie = IpTrie::new();
+        trie.insert_with_expiry("10.0.0.0/8".parse().unwrap(), Some(1_000));
+        trie.insert_with_expiry("10.1.0.0/16".parse().unwrap(), Some(2_000));
+
+        let addr = "10.1.2.3".parse().unwrap();
+        assert!(trie.contains_at(addr, 999));
+        // The /8 has expired but the more specific /16 still matches
+        assert!(trie.contains_at(addr, 1_500));
+        assert!(!trie.contains_at("10.2.0.1".parse().unwrap(), 1_500));
+        assert!(!trie.contains_at(addr, 2_000));
+    }
+
+    #[test]
+    fn test_reinsert_keeps_later_expiry() {
+        let mut trie = IpTrie::new();
+        let net: IpNet = "10.0.0.1/32".parse().unwrap();
+        trie.insert_with_expiry(net, Some(2_000));
+        trie.insert_with_expiry(net, Some(1_000));
+        assert!(trie.contains_at("10.0.0.1".parse().unwrap(), 1_500));
+
+        trie.insert(net);
+        assert!(trie.contains_at("10.0.0.1".parse().unwrap(), u64::MAX));
+        assert_eq!(trie.len(), 1);
+    }
 }
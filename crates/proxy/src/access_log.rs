@@ -0,0 +1,155 @@
+use std::fs::OpenOptions;
+use std::io::{self, LineWriter, Write};
+use std::sync::Mutex;
+
+use layer7waf_common::{AccessLogConfig, AccessLogFormat};
+use tracing::warn;
+
+use crate::context::RequestContext;
+
+/// Writes `common`/`combined` access log lines to a file or stdout.
+pub struct AccessLog {
+    format: AccessLogFormat,
+    /// Where lines go; `None` writes to stdout.
+    file: Option<Mutex<LineWriter<std::fs::File>>>,
+}
+
+impl AccessLog {
+    /// Open the configured log file for appending, if one is set.
+    pub fn open(config: &AccessLogConfig) -> io::Result<Self> {
+        let file = match (&config.path, config.format) {
+            (_, AccessLogFormat::Json) | (None, _) => None,
+            (Some(path), _) => {
+                let file = OpenOptions::new().create(true).append(true).open(path)?;
+                Some(Mutex::new(LineWriter::new(file)))
+            }
+        };
+        Ok(Self {
+            format: config.format,
+            file,
+        })
+    }
+
+    pub fn format(&self) -> AccessLogFormat {
+        self.format
+    }
+
+    /// Write the line for a finished request. JSON entries are logged by
+    /// the caller through tracing, so this does nothing for them.
+    pub fn write(&self, ctx: &RequestContext) {
+        let line = match self.format {
+            AccessLogFormat::Json => return,
+            AccessLogFormat::Common => format_common_log_line(ctx),
+            AccessLogFormat::Combined => format_combined_log_line(ctx),
+        };
+        let result = match &self.file {
+            Some(file) => writeln!(file.lock().unwrap(), "{}", line),
+            None => writeln!(io::stdout().lock(), "{}", line),
+        };
+        if let Err(e) = result {
+            warn!(error = %e, "failed to write access log line");
+        }
+    }
+}
+
+/// A request in NCSA Common Log Format:
+/// `%h %l %u %t "%r" %>s %b`.
+pub fn format_common_log_line(ctx: &RequestContext) -> String {
+    let host = if ctx.client_ip.is_empty() {
+        "-"
+    } else {
+        ctx.client_ip.as_str()
+    };
+    let bytes = if ctx.response_bytes == 0 {
+        "-".to_string()
+    } else {
+        ctx.response_bytes.to_string()
+    };
+    format!(
+        "{} - - [{}] \"{}\" {} {}",
+        host,
+        ctx.received_at.format("%d/%b/%Y:%H:%M:%S %z"),
+        escape(&format!("{} {} {}", ctx.method, ctx.uri, ctx.protocol)),
+        ctx.response_status,
+        bytes,
+    )
+}
+
+/// A request in Combined Log Format: the common line followed by the quoted
+/// Referer and User-Agent, `-` when absent.
+pub fn format_combined_log_line(ctx: &RequestContext) -> String {
+    format!(
+        "{} \"{}\" \"{}\"",
+        format_common_log_line(ctx),
+        escape(ctx.referer.as_deref().unwrap_or("-")),
+        escape(ctx.user_agent.as_deref().unwrap_or("-")),
+    )
+}
+
+/// Escape a client-supplied value for a quoted field, so it can't end the
+/// field early or forge a line.
+fn escape(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    for c in value.chars() {
+        match c {
+            '"' => escaped.push_str("\\\""),
+            '\\' => escaped.push_str("\\\\"),
+            c if c.is_control() => escaped.push_str(&format!("\\x{:02x}", c as u32)),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    fn sample() -> RequestContext {
+        let mut ctx = RequestContext::new();
+        ctx.client_ip = "203.0.113.7".to_string();
+        ctx.received_at = chrono::Utc.with_ymd_and_hms(2024, 10, 10, 13, 55, 36).unwrap();
+        ctx.method = "GET".to_string();
+        ctx.uri = "/apache_pb.gif?size=large".to_string();
+        ctx.protocol = "HTTP/1.1".to_string();
+        ctx.response_status = 200;
+        ctx.response_bytes = 2326;
+        ctx.referer = Some("http://www.example.com/start.html".to_string());
+        ctx.user_agent = Some("Mozilla/4.08 [en] (Win98; I ;Nav)".to_string());
+        ctx
+    }
+
+    #[test]
+    fn test_combined_log_line() {
+        assert_eq!(
+            format_combined_log_line(&sample()),
+            "203.0.113.7 - - [10/Oct/2024:13:55:36 +0000] \"GET /apache_pb.gif?size=large HTTP/1.1\" \
+             200 2326 \"http://www.example.com/start.html\" \"Mozilla/4.08 [en] (Win98; I ;Nav)\""
+        );
+    }
+
+    #[test]
+    fn test_common_log_line_placeholders() {
+        let mut ctx = sample();
+        ctx.client_ip = String::new();
+        ctx.response_status = 403;
+        ctx.response_bytes = 0;
+        ctx.referer = None;
+        assert_eq!(
+            format_common_log_line(&ctx),
+            "- - - [10/Oct/2024:13:55:36 +0000] \"GET /apache_pb.gif?size=large HTTP/1.1\" 403 -"
+        );
+        assert!(format_combined_log_line(&ctx)
+            .ends_with(" 403 - \"-\" \"Mozilla/4.08 [en] (Win98; I ;Nav)\""));
+    }
+
+    #[test]
+    fn test_quoted_fields_escaped() {
+        let mut ctx = sample();
+        ctx.user_agent = Some("evil\" 200 1\nforged".to_string());
+        let line = format_combined_log_line(&ctx);
+        assert!(line.ends_with("\"evil\\\" 200 1\\x0aforged\""));
+        assert_eq!(line.lines().count(), 1);
+    }
+}
@@ -0,0 +1,160 @@
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+
+use http::HeaderMap;
+use layer7waf_common::{AuthenticatedRateLimitConfig, KeyCapacity, RouteRateLimitConfig};
+use layer7waf_rate_limit::RateLimiter;
+
+/// Rate limiter for authenticated requests, keyed by session identifier,
+/// with a per-IP cap over all of a client's sessions.
+pub struct AuthenticatedLimiter {
+    cookie: Option<String>,
+    header: Option<String>,
+    pub limiter: RateLimiter,
+    /// Keyed by client, like the anonymous limiters.
+    pub ip_limiter: RateLimiter,
+}
+
+/// The limit a request counts against.
+pub enum RequestLimiter<'a> {
+    /// A route's own limiter or the global one, keyed by client.
+    Client(&'a RateLimiter),
+    /// The authenticated limiter, keyed by the request's session.
+    Authenticated {
+        limiter: &'a AuthenticatedLimiter,
+        session_key: String,
+    },
+}
+
+impl RequestLimiter<'_> {
+    /// Count a request from `client_key`, returning whether it is allowed.
+    pub fn check(&self, client_key: &str) -> bool {
+        match self {
+            Self::Client(limiter) => limiter.check(client_key),
+            Self::Authenticated { limiter, session_key } => limiter.check(session_key, client_key),
+        }
+    }
+}
+
+impl AuthenticatedLimiter {
+    pub fn new(config: &AuthenticatedRateLimitConfig, capacity: KeyCapacity) -> Self {
+        let per_ip = RouteRateLimitConfig {
+            rps: config.limit.rps.saturating_mul(config.per_ip_factor),
+            burst: config.limit.burst.saturating_mul(config.per_ip_factor),
+            ..config.limit.clone()
+        };
+        Self {
+            cookie: config.cookie.clone(),
+            header: config.header.clone(),
+            limiter: RateLimiter::from_route_config(&config.limit).with_key_capacity(capacity),
+            ip_limiter: RateLimiter::from_route_config(&per_ip).with_key_capacity(capacity),
+        }
+    }
+
+    /// Count a request of session `session_key` from `client_key`. The
+    /// per-IP cap is checked first, so a refused client uses up none of its
+    /// session's allowance.
+    pub fn check(&self, session_key: &str, client_key: &str) -> bool {
+        self.ip_limiter.check(client_key) && self.limiter.check(session_key)
+    }
+
+    /// Rate-limit key of an authenticated request, or `None` for an
+    /// anonymous one. The identifier is hashed so session tokens are neither
+    /// held in the limiter nor shown by the admin API.
+    pub fn key(&self, headers: &HeaderMap) -> Option<String> {
+        let cookie = self.cookie.as_deref().and_then(|name| cookie_value(headers, name));
+        let header = || {
+            let name = self.header.as_deref()?;
+            headers.get(name)?.to_str().ok().filter(|v| !v.is_empty())
+        };
+        let identifier = cookie.or_else(header)?;
+        let mut hasher = DefaultHasher::new();
+        identifier.hash(&mut hasher);
+        Some(format!("auth:{:016x}", hasher.finish()))
+    }
+}
+
+/// Value of cookie `name` across the request's `Cookie` headers, if set
+/// and non-empty.
+fn cookie_value<'a>(headers: &'a HeaderMap, name: &str) -> Option<&'a str> {
+    headers
+        .get_all(http::header::COOKIE)
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(';'))
+        .filter_map(|pair| pair.trim().split_once('='))
+        .find(|(key, _)| *key == name)
+        .map(|(_, value)| value)
+        .filter(|value| !value.is_empty())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use http::HeaderValue;
+    use layer7waf_common::{FailurePolicy, KeyOverflowPolicy};
+
+    fn limiter() -> AuthenticatedLimiter {
+        let config: AuthenticatedRateLimitConfig = serde_json::from_value(serde_json::json!({
+            "cookie": "session_id",
+            "header": "authorization",
+            "rps": 10,
+            "burst": 20
+        }))
+        .unwrap();
+        AuthenticatedLimiter::new(&config, KeyCapacity::new(100, KeyOverflowPolicy::Evict, FailurePolicy::Allow))
+    }
+
+    fn headers(pairs: &[(&'static str, &'static str)]) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        for (name, value) in pairs {
+            headers.append(*name, HeaderValue::from_static(value));
+        }
+        headers
+    }
+
+    #[test]
+    fn test_key_from_cookie_or_header() {
+        let limiter = limiter();
+        let by_cookie = limiter
+            .key(&headers(&[("cookie", "theme=dark; session_id=abc123")]))
+            .unwrap();
+        assert!(by_cookie.starts_with("auth:"));
+        assert!(!by_cookie.contains("abc123"));
+        assert_eq!(
+            limiter.key(&headers(&[("cookie", "session_id=abc123")])),
+            Some(by_cookie.clone())
+        );
+
+        let by_header = limiter.key(&headers(&[("authorization", "Bearer xyz")])).unwrap();
+        assert_ne!(by_header, by_cookie);
+    }
+
+    #[test]
+    fn test_rotating_identifiers_still_limited_per_ip() {
+        let limiter = limiter();
+        // 4x the burst of 20 across all sessions from one IP
+        let allowed = (0..200)
+            .filter(|i| {
+                let mut headers = HeaderMap::new();
+                let cookie = format!("session_id=forged{}", i);
+                headers.insert("cookie", HeaderValue::from_str(&cookie).unwrap());
+                let key = limiter.key(&headers).unwrap();
+                limiter.check(&key, "203.0.113.9")
+            })
+            .count();
+        assert_eq!(allowed, 80);
+
+        // Another client is unaffected
+        let key = limiter.key(&headers(&[("cookie", "session_id=abc123")])).unwrap();
+        assert!(limiter.check(&key, "198.51.100.7"));
+    }
+
+    #[test]
+    fn test_anonymous_request_has_no_key() {
+        let limiter = limiter();
+        assert_eq!(limiter.key(&HeaderMap::new()), None);
+        assert_eq!(limiter.key(&headers(&[("cookie", "theme=dark; session_id=")])), None);
+        assert_eq!(limiter.key(&headers(&[("cookie", "my_session_id=abc")])), None);
+    }
+}
@@ -0,0 +1,124 @@
+use http::StatusCode;
+use layer7waf_common::wants_problem_json;
+use serde::Serialize;
+
+/// Content type of an RFC 7807 problem document.
+pub const PROBLEM_JSON: &str = "application/problem+json";
+
+/// Body and headers for a request the proxy refuses itself.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BlockResponse {
+    pub status: StatusCode,
+    pub content_type: &'static str,
+    pub body: String,
+    /// Seconds for the `Retry-After` header, if the client may retry.
+    pub retry_after: Option<u64>,
+}
+
+/// RFC 7807 problem details describing why a request was refused.
+#[derive(Debug, Serialize)]
+struct ProblemDetails<'a> {
+    #[serde(rename = "type")]
+    problem_type: String,
+    title: &'a str,
+    status: u16,
+    detail: &'a str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    retry_after: Option<u64>,
+}
+
+/// Build the response for a request refused with `status`.
+///
+/// `kind` names the block (e.g. `"rate-limited"`) and becomes the problem
+/// `type`; `detail` is the human-readable message, sent as the plain-text
+/// body to clients that don't want JSON.
+pub fn block_response(
+    status: StatusCode,
+    kind: &str,
+    detail: &str,
+    retry_after: Option<u64>,
+    accept: Option<&str>,
+) -> BlockResponse {
+    if !wants_problem_json(accept) {
+        return BlockResponse {
+            status,
+            content_type: "text/plain",
+            body: format!("{}\n", detail),
+            retry_after,
+        };
+    }
+
+    let problem = ProblemDetails {
+        problem_type: format!("urn:layer7waf:problem:{}", kind),
+        title: status.canonical_reason().unwrap_or("Blocked"),
+        status: status.as_u16(),
+        detail,
+        retry_after,
+    };
+    BlockResponse {
+        status,
+        content_type: PROBLEM_JSON,
+        body: serde_json::to_string(&problem).expect("problem details serialize"),
+        retry_after,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::Value;
+
+    const BROWSER_ACCEPT: &str =
+        "text/html,application/xhtml+xml,application/xml;q=0.9,*/*;q=0.8";
+
+    #[test]
+    fn test_json_client_gets_problem_document() {
+        let resp = block_response(
+            StatusCode::TOO_MANY_REQUESTS,
+            "rate-limited",
+            "Rate limit exceeded",
+            Some(1),
+            Some("application/json"),
+        );
+        assert_eq!(resp.content_type, PROBLEM_JSON);
+        assert_eq!(resp.retry_after, Some(1));
+
+        let body: Value = serde_json::from_str(&resp.body).unwrap();
+        assert_eq!(body["type"], "urn:layer7waf:problem:rate-limited");
+        assert_eq!(body["title"], "Too Many Requests");
+        assert_eq!(body["status"], 429);
+        assert_eq!(body["detail"], "Rate limit exceeded");
+        assert_eq!(body["retry_after"], 1);
+    }
+
+    #[test]
+    fn test_browser_gets_plain_body() {
+        let resp = block_response(
+            StatusCode::TOO_MANY_REQUESTS,
+            "rate-limited",
+            "Rate limit exceeded",
+            Some(1),
+            Some(BROWSER_ACCEPT),
+        );
+        assert_eq!(resp.content_type, "text/plain");
+        assert_eq!(resp.body, "Rate limit exceeded\n");
+        assert_eq!(resp.retry_after, Some(1));
+
+        let resp = block_response(StatusCode::FORBIDDEN, "ip-blocked", "Forbidden", None, None);
+        assert_eq!(resp.content_type, "text/plain");
+    }
+
+    #[test]
+    fn test_problem_without_retry_after_omits_field() {
+        let resp = block_response(
+            StatusCode::FORBIDDEN,
+            "waf",
+            "Forbidden: WAF rule triggered",
+            None,
+            Some("application/problem+json"),
+        );
+        let body: Value = serde_json::from_str(&resp.body).unwrap();
+        assert_eq!(body["status"], 403);
+        assert!(body.get("retry_after").is_none());
+    }
+}
@@ -0,0 +1,135 @@
+use bytes::Bytes;
+use http::HeaderMap;
+
+/// Keeps request body reads behind the header-phase checks.
+///
+/// IP reputation, rate limiting and the header-phase WAF decide on headers
+/// alone, so nothing of the body is read until they have all let the
+/// request through; a client about to be rejected never gets to make us
+/// read (or buffer) megabytes of body first.
+#[derive(Debug)]
+pub struct BodyGate {
+    state: GateState,
+    chunks_read: u64,
+    bytes_read: u64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum GateState {
+    /// Header-phase checks haven't finished.
+    Pending,
+    /// The request passed them; its body may be read.
+    Open,
+    /// It was answered at the header phase; its body is never read.
+    Closed,
+}
+
+impl BodyGate {
+    pub fn new() -> Self {
+        Self {
+            state: GateState::Pending,
+            chunks_read: 0,
+            bytes_read: 0,
+        }
+    }
+
+    /// The header-phase checks let the request through.
+    pub fn open(&mut self) {
+        if self.state == GateState::Pending {
+            self.state = GateState::Open;
+        }
+    }
+
+    /// The request was answered at the header phase.
+    pub fn close(&mut self) {
+        self.state = GateState::Closed;
+    }
+
+    /// Whether `chunk` of the body may be read, counting it if so.
+    pub fn admit(&mut self, chunk: &Option<Bytes>) -> bool {
+        if self.state != GateState::Open {
+            return false;
+        }
+        if let Some(chunk) = chunk {
+            self.chunks_read += 1;
+            self.bytes_read += chunk.len() as u64;
+        }
+        true
+    }
+
+    pub fn chunks_read(&self) -> u64 {
+        self.chunks_read
+    }
+
+    pub fn bytes_read(&self) -> u64 {
+        self.bytes_read
+    }
+}
+
+impl Default for BodyGate {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Whether a request with `headers` carries a body. Answering such a request
+/// on a reused connection would mean draining that body, so the connection
+/// is closed instead.
+pub fn has_request_body(headers: &HeaderMap) -> bool {
+    let content_length = headers
+        .get("content-length")
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.trim().parse::<u64>().ok());
+    match content_length {
+        Some(len) => len > 0,
+        None => headers.contains_key("transfer-encoding"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn chunk() -> Option<Bytes> {
+        Some(Bytes::from(vec![0u8; 64 * 1024]))
+    }
+
+    #[test]
+    fn test_blocked_request_never_reads_body() {
+        let mut gate = BodyGate::new();
+        // Rejected at the header phase: nothing read, before or after
+        assert!(!gate.admit(&chunk()));
+        gate.close();
+        gate.open();
+        for _ in 0..16 {
+            assert!(!gate.admit(&chunk()));
+        }
+        assert_eq!(gate.chunks_read(), 0);
+        assert_eq!(gate.bytes_read(), 0);
+    }
+
+    #[test]
+    fn test_passed_request_reads_body() {
+        let mut gate = BodyGate::new();
+        gate.open();
+        assert!(gate.admit(&chunk()));
+        assert!(gate.admit(&chunk()));
+        assert!(gate.admit(&None));
+        assert_eq!(gate.chunks_read(), 2);
+        assert_eq!(gate.bytes_read(), 128 * 1024);
+    }
+
+    #[test]
+    fn test_has_request_body() {
+        let mut headers = HeaderMap::new();
+        assert!(!has_request_body(&headers));
+        headers.insert("content-length", "0".parse().unwrap());
+        assert!(!has_request_body(&headers));
+        headers.insert("content-length", "1048576".parse().unwrap());
+        assert!(has_request_body(&headers));
+
+        let mut chunked = HeaderMap::new();
+        chunked.insert("transfer-encoding", "chunked".parse().unwrap());
+        assert!(has_request_body(&chunked));
+    }
+}
@@ -0,0 +1,93 @@
+use bytes::Bytes;
+
+/// Holds a request body back from the upstream until the WAF has scanned
+/// it.
+///
+/// Chunks are held until the body ends or `waf.request_body_limit` bytes
+/// have arrived; that much is scanned once, then everything held is sent
+/// on and the rest of the body streams through unscanned.
+#[derive(Debug, Default)]
+pub struct BodyScan {
+    held: Vec<u8>,
+    scanned: bool,
+}
+
+impl BodyScan {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Take `body`'s chunk into the held bytes unless the body was already
+    /// scanned. Returns whether the held bytes are now ready to scan.
+    pub fn hold(&mut self, body: &mut Option<Bytes>, end_of_stream: bool, limit: usize) -> bool {
+        if self.scanned {
+            return false;
+        }
+        if let Some(chunk) = body.take() {
+            self.held.extend_from_slice(&chunk);
+        }
+        end_of_stream || self.held.len() >= limit
+    }
+
+    /// The held bytes the WAF sees, at most `limit` of them.
+    pub fn scanned_part(&self, limit: usize) -> &[u8] {
+        &self.held[..self.held.len().min(limit)]
+    }
+
+    /// Bytes held and not yet sent. A body blocked on is never released,
+    /// so it stays here.
+    pub fn held(&self) -> &[u8] {
+        &self.held
+    }
+
+    /// Mark the body scanned and put the held bytes in `body` to be sent.
+    pub fn release(&mut self, body: &mut Option<Bytes>) {
+        self.scanned = true;
+        if !self.held.is_empty() {
+            *body = Some(Bytes::from(std::mem::take(&mut self.held)));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn chunk(len: usize) -> Option<Bytes> {
+        Some(Bytes::from(vec![b'a'; len]))
+    }
+
+    #[test]
+    fn test_body_held_until_complete() {
+        let mut scan = BodyScan::new();
+        let mut body = chunk(100);
+        assert!(!scan.hold(&mut body, false, 1024));
+        assert!(body.is_none(), "chunk held back");
+        let mut body = chunk(50);
+        assert!(scan.hold(&mut body, true, 1024));
+        assert_eq!(scan.scanned_part(1024).len(), 150);
+
+        assert_eq!(scan.held().len(), 150);
+        scan.release(&mut body);
+        assert_eq!(body.unwrap().len(), 150);
+        assert!(scan.held().is_empty());
+        assert!(!scan.hold(&mut chunk(10), true, 1024), "scanned only once");
+    }
+
+    #[test]
+    fn test_body_over_limit_scanned_once_then_streamed() {
+        let mut scan = BodyScan::new();
+        let mut body = chunk(600);
+        assert!(!scan.hold(&mut body, false, 1000));
+        let mut body = chunk(600);
+        assert!(scan.hold(&mut body, false, 1000));
+        assert_eq!(scan.scanned_part(1000).len(), 1000);
+        scan.release(&mut body);
+        assert_eq!(body.unwrap().len(), 1200);
+
+        // Later chunks pass straight through
+        let mut body = chunk(600);
+        assert!(!scan.hold(&mut body, true, 1000));
+        assert_eq!(body.unwrap().len(), 600);
+    }
+}
@@ -0,0 +1,214 @@
+use std::fs::{File, OpenOptions};
+use std::io::{self, LineWriter, Write};
+use std::sync::mpsc::{self, Receiver, SyncSender, TrySendError};
+use std::thread::JoinHandle;
+
+use chrono::{DateTime, Utc};
+use layer7waf_admin::audit::redact_secrets;
+use layer7waf_common::CaptureConfig;
+use rand::Rng;
+use serde::Serialize;
+use tracing::{debug, warn};
+
+use crate::context::RequestContext;
+
+/// One captured request, a line of the capture file.
+///
+/// Holds only what rule tuning needs: no client IP, no bodies, and header
+/// names without their values, which may carry credentials. Secrets left in
+/// the path are redacted as in the audit log.
+#[derive(Debug, Serialize)]
+pub struct CapturedRequest {
+    pub timestamp: DateTime<Utc>,
+    pub method: String,
+    /// Path with query parameter values dropped, see [`normalize_uri`], and
+    /// secrets redacted.
+    pub uri: String,
+    /// Lowercase request header names, in the order they were sent.
+    pub header_names: Vec<String>,
+    pub geo_country: Option<String>,
+    pub bot_score: Option<f64>,
+    pub scraping_score: Option<f64>,
+    pub status: u16,
+    pub blocked: bool,
+    pub block_reason: Option<String>,
+}
+
+impl CapturedRequest {
+    pub fn from_context<'a>(
+        ctx: &RequestContext,
+        header_names: impl IntoIterator<Item = &'a str>,
+        status: u16,
+    ) -> Self {
+        Self {
+            timestamp: ctx.received_at,
+            method: ctx.method.clone(),
+            uri: redact_secrets(&normalize_uri(&ctx.uri)),
+            header_names: header_names.into_iter().map(|n| n.to_ascii_lowercase()).collect(),
+            geo_country: ctx.geo_country.clone(),
+            bot_score: ctx.bot_score,
+            scraping_score: ctx.scraping_score,
+            status,
+            blocked: ctx.block_reason.is_some(),
+            block_reason: ctx.block_reason.as_ref().map(|r| format!("{:?}", r)),
+        }
+    }
+}
+
+/// Captured requests queued for the writer thread; more are dropped.
+const QUEUE_CAPACITY: usize = 4096;
+
+/// Appends a `sample_rate` fraction of requests to the capture file.
+///
+/// Requests are queued for a writer thread, so the file I/O stays off the
+/// proxy's workers. Dropping the capture waits for the queue to be written.
+pub struct RequestCapture {
+    sample_rate: f64,
+    queue: Option<SyncSender<CapturedRequest>>,
+    writer: Option<JoinHandle<()>>,
+}
+
+impl RequestCapture {
+    /// Open the capture file for appending; `None` when capture is disabled.
+    pub fn open(config: &CaptureConfig) -> io::Result<Option<Self>> {
+        let Some(path) = config.path.as_ref().filter(|_| config.enabled) else {
+            return Ok(None);
+        };
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        let (queue, requests) = mpsc::sync_channel(QUEUE_CAPACITY);
+        let writer = std::thread::Builder::new()
+            .name("request-capture".into())
+            .spawn(move || write_requests(LineWriter::new(file), requests))?;
+        Ok(Some(Self {
+            sample_rate: config.sample_rate.clamp(0.0, 1.0),
+            queue: Some(queue),
+            writer: Some(writer),
+        }))
+    }
+
+    /// Whether to capture the current request.
+    pub fn sampled(&self) -> bool {
+        rand::thread_rng().gen_bool(self.sample_rate)
+    }
+
+    /// Queue one captured request to be written as a JSON line. Dropped
+    /// when the writer has fallen [`QUEUE_CAPACITY`] requests behind.
+    pub fn write(&self, request: CapturedRequest) {
+        let Some(queue) = &self.queue else {
+            return;
+        };
+        if let Err(TrySendError::Full(_)) = queue.try_send(request) {
+            debug!("request capture queue full, dropping captured request");
+        }
+    }
+}
+
+impl Drop for RequestCapture {
+    fn drop(&mut self) {
+        // Closing the queue ends the writer once it has written the rest
+        self.queue.take();
+        if let Some(writer) = self.writer.take() {
+            let _ = writer.join();
+        }
+    }
+}
+
+/// Write each request received as a JSON line until the queue is closed.
+fn write_requests(mut file: LineWriter<File>, requests: Receiver<CapturedRequest>) {
+    for request in requests {
+        let result = serde_json::to_string(&request)
+            .map_err(io::Error::from)
+            .and_then(|line| writeln!(file, "{}", line));
+        if let Err(e) = result {
+            warn!(error = %e, "failed to write captured request");
+        }
+    }
+}
+
+/// The URI with query parameter values removed, keeping parameter names so
+/// rules matching on them can still be tuned: `/search?q=shoes&page=2`
+/// becomes `/search?q&page`.
+pub fn normalize_uri(uri: &str) -> String {
+    let Some((path, query)) = uri.split_once('?') else {
+        return uri.to_string();
+    };
+    let names: Vec<&str> = query
+        .split('&')
+        .filter(|p| !p.is_empty())
+        .map(|p| p.split_once('=').map_or(p, |(name, _)| name))
+        .collect();
+    if names.is_empty() {
+        path.to_string()
+    } else {
+        format!("{}?{}", path, names.join("&"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::context::BlockReason;
+    use layer7waf_admin::audit::REDACTED;
+
+    #[test]
+    fn test_normalize_uri_drops_query_values() {
+        assert_eq!(normalize_uri("/search?q=shoes&page=2"), "/search?q&page");
+        assert_eq!(normalize_uri("/login?token=s3cret&debug"), "/login?token&debug");
+        assert_eq!(normalize_uri("/about"), "/about");
+        assert_eq!(normalize_uri("/about?"), "/about");
+    }
+
+    #[test]
+    fn test_full_sample_rate_writes_jsonl_line() {
+        let path = std::env::temp_dir()
+            .join(format!("layer7waf_capture_{}.jsonl", std::process::id()));
+        let config = CaptureConfig {
+            enabled: true,
+            sample_rate: 1.0,
+            path: Some(path.clone()),
+        };
+        let capture = RequestCapture::open(&config).unwrap().unwrap();
+
+        let mut ctx = RequestContext::new();
+        ctx.client_ip = "203.0.113.7".to_string();
+        ctx.method = "POST".to_string();
+        ctx.uri = "/login?user=alice".to_string();
+        ctx.geo_country = Some("DE".to_string());
+        ctx.bot_score = Some(0.8);
+        ctx.block_reason = Some(BlockReason::BotDetected { score: 0.8 });
+        assert!(capture.sampled());
+        capture.write(CapturedRequest::from_context(
+            &ctx,
+            ["Host", "Authorization", "Cookie"],
+            403,
+        ));
+        drop(capture);
+
+        let contents = std::fs::read_to_string(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        let lines: Vec<&str> = contents.lines().collect();
+        assert_eq!(lines.len(), 1);
+        let line: serde_json::Value = serde_json::from_str(lines[0]).unwrap();
+        assert_eq!(line["method"], "POST");
+        assert_eq!(line["uri"], "/login?user");
+        assert_eq!(line["header_names"], serde_json::json!(["host", "authorization", "cookie"]));
+        assert_eq!(line["geo_country"], "DE");
+        assert_eq!(line["status"], 403);
+        assert_eq!(line["blocked"], true);
+        assert!(!contents.contains("203.0.113.7"));
+        assert!(!contents.contains("alice"));
+    }
+
+    #[test]
+    fn test_secrets_in_path_redacted() {
+        let mut ctx = RequestContext::new();
+        ctx.uri = "/reset;token=abc123/confirm?code=9".to_string();
+        let request = CapturedRequest::from_context(&ctx, [], 200);
+        assert_eq!(request.uri, format!("/reset;token={}", REDACTED));
+    }
+
+    #[test]
+    fn test_disabled_capture_not_opened() {
+        assert!(RequestCapture::open(&CaptureConfig::default()).unwrap().is_none());
+    }
+}
@@ -0,0 +1,140 @@
+use http::HeaderMap;
+use layer7waf_anti_scraping::{AntiScraper, ScrapingCheckResult};
+use layer7waf_common::{ChallengeSelectionConfig, ChallengeType};
+
+/// What to answer a client that bot detection or GeoIP challenges.
+#[derive(Debug)]
+pub enum Challenge {
+    /// Serve the JS challenge page.
+    Js(String),
+    /// Serve the CAPTCHA page.
+    Captcha(String),
+    /// The client already solved a CAPTCHA in place of the JS challenge.
+    Passed,
+    /// No suitable challenge; reject the request.
+    Block,
+}
+
+/// The challenge type fitting the client that sent `headers`.
+pub fn select_challenge_type(
+    headers: &HeaderMap,
+    config: &ChallengeSelectionConfig,
+) -> ChallengeType {
+    if runs_js(headers, &config.no_js_user_agents) {
+        config.js_capable
+    } else {
+        config.no_js
+    }
+}
+
+/// Whether the client can be expected to solve the JS challenge. XHR
+/// requests can: the page that sent them solves the JSON form of it.
+fn runs_js(headers: &HeaderMap, no_js_user_agents: &[String]) -> bool {
+    let header = |name: &str| headers.get(name).and_then(|v| v.to_str().ok());
+    if header("x-requested-with").is_some_and(|v| !v.trim().is_empty()) {
+        return true;
+    }
+    if let Some(ua) = header("user-agent") {
+        let ua = ua.to_ascii_lowercase();
+        if no_js_user_agents.iter().any(|agent| ua.contains(&agent.to_ascii_lowercase())) {
+            return false;
+        }
+    }
+    header("accept").is_some_and(|accept| accept.to_ascii_lowercase().contains("text/html"))
+}
+
+/// Resolve a challenge of `challenge_type` for a client that would
+/// otherwise get the JS challenge `js_html`. A CAPTCHA comes from the
+/// anti-scraper, so the client is blocked without one.
+pub fn resolve_challenge(
+    challenge_type: ChallengeType,
+    js_html: String,
+    anti_scraper: Option<&AntiScraper>,
+    client_ip: &str,
+    path: &str,
+    cookie_header: Option<&str>,
+) -> Challenge {
+    match challenge_type {
+        ChallengeType::JsChallenge => Challenge::Js(js_html),
+        ChallengeType::Block => Challenge::Block,
+        ChallengeType::Captcha => {
+            match anti_scraper.map(|a| a.captcha(client_ip, path, cookie_header)) {
+                Some(ScrapingCheckResult::Allow) => Challenge::Passed,
+                Some(ScrapingCheckResult::Challenge(html)) => Challenge::Captcha(html),
+                _ => Challenge::Block,
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use http::HeaderValue;
+    use layer7waf_common::AntiScrapingConfig;
+
+    fn headers(pairs: &[(&'static str, &'static str)]) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        for (name, value) in pairs {
+            headers.append(*name, HeaderValue::from_static(value));
+        }
+        headers
+    }
+
+    fn selection() -> ChallengeSelectionConfig {
+        ChallengeSelectionConfig {
+            no_js: ChallengeType::Captcha,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_browser_gets_js_challenge() {
+        let browser = headers(&[
+            ("user-agent", "Mozilla/5.0 (X11; Linux x86_64) Firefox/128.0"),
+            ("accept", "text/html,application/xhtml+xml,*/*;q=0.8"),
+        ]);
+        assert_eq!(select_challenge_type(&browser, &selection()), ChallengeType::JsChallenge);
+
+        let xhr = headers(&[
+            ("accept", "application/json"),
+            ("x-requested-with", "XMLHttpRequest"),
+        ]);
+        assert_eq!(select_challenge_type(&xhr, &selection()), ChallengeType::JsChallenge);
+    }
+
+    #[test]
+    fn test_client_without_js_gets_captcha() {
+        let selection = selection();
+        let api = headers(&[("user-agent", "python-requests/2.32"), ("accept", "*/*")]);
+        assert_eq!(select_challenge_type(&api, &selection), ChallengeType::Captcha);
+        assert_eq!(select_challenge_type(&HeaderMap::new(), &selection), ChallengeType::Captcha);
+
+        let lynx = headers(&[("user-agent", "Lynx/2.9.0 libwww-FM/2.14"), ("accept", "text/html")]);
+        assert_eq!(select_challenge_type(&lynx, &selection), ChallengeType::Captcha);
+
+        let blocking = ChallengeSelectionConfig {
+            no_js: ChallengeType::Block,
+            ..Default::default()
+        };
+        assert_eq!(select_challenge_type(&lynx, &blocking), ChallengeType::Block);
+    }
+
+    #[test]
+    fn test_default_always_js_challenge() {
+        let lynx = headers(&[("user-agent", "Lynx/2.9.0")]);
+        let config = ChallengeSelectionConfig::default();
+        assert_eq!(select_challenge_type(&lynx, &config), ChallengeType::JsChallenge);
+    }
+
+    #[test]
+    fn test_captcha_needs_anti_scraper() {
+        let captcha = |anti_scraper| {
+            let html = String::new();
+            resolve_challenge(ChallengeType::Captcha, html, anti_scraper, "1.2.3.4", "/", None)
+        };
+        assert!(matches!(captcha(None), Challenge::Block));
+        let anti_scraper = AntiScraper::new(AntiScrapingConfig::default());
+        assert!(matches!(captcha(Some(&anti_scraper)), Challenge::Captcha(_)));
+    }
+}
@@ -0,0 +1,128 @@
+use std::path::Path;
+
+use layer7waf_common::AppConfig;
+
+/// Load and validate the config file at `path` the way startup does, then
+/// check that every file it points to exists. Returns the problems found,
+/// empty when the config is good to deploy.
+///
+/// Backs `layer7waf --check <config>`.
+pub fn check_config(path: &str) -> Vec<String> {
+    let config = match AppConfig::load(path) {
+        Ok(config) => config,
+        Err(e) => return vec![format!("{:#}", e)],
+    };
+    missing_files(&config)
+}
+
+/// Files named by `config` that don't exist, and rule globs that match
+/// nothing.
+fn missing_files(config: &AppConfig) -> Vec<String> {
+    let mut problems = Vec::new();
+    let mut require = |setting: &str, file: &Path| {
+        if !file.is_file() {
+            problems.push(format!("{}: {} does not exist", setting, file.display()));
+        }
+    };
+
+    if let Some(ref tls) = config.server.tls {
+        require("server.tls.cert", &tls.cert);
+        require("server.tls.key", &tls.key);
+    }
+    if let Some(ref blocklist) = config.ip_reputation.blocklist {
+        require("ip_reputation.blocklist", blocklist);
+    }
+    if let Some(ref allowlist) = config.ip_reputation.allowlist {
+        require("ip_reputation.allowlist", allowlist);
+    }
+    if config.geoip.enabled {
+        if let Some(ref database) = config.geoip.database_path {
+            require("geoip.database_path", database);
+        }
+    }
+
+    let mut rule_globs = vec![("waf.rules".to_string(), &config.waf.rules)];
+    let mut names: Vec<&String> = config.waf.rulesets.keys().collect();
+    names.sort();
+    for name in names {
+        rule_globs.push((format!("waf.rulesets.{}", name), &config.waf.rulesets[name]));
+    }
+    for (setting, patterns) in rule_globs {
+        for pattern in patterns {
+            let matched = glob::glob(pattern)
+                .map(|mut paths| paths.any(|p| p.is_ok_and(|p| p.is_file())))
+                .unwrap_or(false);
+            if !matched {
+                problems.push(format!("{}: {} matches no rule files", setting, pattern));
+            }
+        }
+    }
+    problems
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn write_config(name: &str, yaml: &str) -> std::path::PathBuf {
+        let path = std::env::temp_dir()
+            .join(format!("layer7waf_check_{}_{}.yaml", name, std::process::id()));
+        std::fs::write(&path, yaml).unwrap();
+        path
+    }
+
+    const BASE: &str = "
+server:
+  listen: [\"0.0.0.0:8080\"]
+upstreams:
+  - name: backend
+    servers:
+      - addr: \"127.0.0.1:8000\"
+";
+
+    #[test]
+    fn test_unknown_upstream_reported() {
+        let yaml = format!("{}waf: {{}}\nroutes:\n  - path_prefix: /\n    upstream: missing\n", BASE);
+        let path = write_config("upstream", &yaml);
+        let problems = check_config(&path.display().to_string());
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(problems.len(), 1);
+        assert!(problems[0].contains("unknown upstream 'missing'"), "{:?}", problems);
+    }
+
+    #[test]
+    fn test_missing_files_reported() {
+        let yaml = format!(
+            "{}routes:\n  - path_prefix: /\n    upstream: backend\n\
+             ip_reputation:\n  blocklist: /nonexistent/blocklist.txt\n\
+             waf:\n  rules: [\"/nonexistent/*.conf\"]\n",
+            BASE
+        );
+        let path = write_config("files", &yaml);
+        let problems = check_config(&path.display().to_string());
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(
+            problems,
+            [
+                "ip_reputation.blocklist: /nonexistent/blocklist.txt does not exist",
+                "waf.rules: /nonexistent/*.conf matches no rule files",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_valid_config_passes() {
+        let yaml = format!("{}waf: {{}}\nroutes:\n  - path_prefix: /\n    upstream: backend\n", BASE);
+        let path = write_config("valid", &yaml);
+        let problems = check_config(&path.display().to_string());
+        std::fs::remove_file(&path).unwrap();
+        assert!(problems.is_empty(), "{:?}", problems);
+    }
+
+    #[test]
+    fn test_unreadable_file_reported() {
+        let problems = check_config("/nonexistent/layer7waf.yaml");
+        assert_eq!(problems.len(), 1);
+        assert!(problems[0].contains("No such file"), "{:?}", problems);
+    }
+}
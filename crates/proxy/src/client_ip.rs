@@ -0,0 +1,262 @@
+use http::HeaderMap;
+use ipnet::IpNet;
+use layer7waf_common::{FailurePolicy, ForwardedHopsOverflow};
+use std::net::{IpAddr, SocketAddr};
+
+/// Outcome of determining the client IP for a request.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ClientIpResolution {
+    /// A usable client IP was found.
+    Known(IpAddr),
+    /// No IP could be determined and the failure policy allows the request.
+    /// IP-keyed checks (rate limiting, bot detection, anti-scraping) are skipped.
+    Unknown,
+    /// No IP could be determined and the failure policy blocks the request.
+    Reject,
+    /// `X-Forwarded-For` has more entries than allowed and overflowing
+    /// headers are rejected.
+    TooManyHops,
+}
+
+/// Determine the client IP from the `X-Forwarded-For` header, falling back to
+/// the socket peer address. Values that do not parse as an IP (e.g. `unknown`)
+/// are ignored.
+///
+/// Only the last `max_hops` entries of the header are looked at, so a huge
+/// header costs no more than a short one; the client is the leftmost of them.
+pub fn extract_client_ip(
+    forwarded_for: Option<&str>,
+    peer_addr: Option<&str>,
+    max_hops: usize,
+) -> Option<IpAddr> {
+    forwarded_for
+        .and_then(|v| v.rsplit(',').take(max_hops).last())
+        .and_then(parse_ip)
+        .or_else(|| peer_addr.and_then(parse_ip))
+}
+
+/// Whether `forwarded_for` lists more than `max_hops` entries. Stops
+/// scanning once past the limit.
+pub fn too_many_hops(forwarded_for: &str, max_hops: usize) -> bool {
+    forwarded_for.rsplit(',').nth(max_hops).is_some()
+}
+
+/// Value of the first of the `names` headers the request carries with an IP
+/// in it, provided the socket `peer` is in one of the `trusted_proxies`.
+/// Headers whose value isn't an IP are passed over. A CDN sets
+/// headers like `CF-Connecting-IP` to the client address, but a client
+/// connecting directly could send them too.
+pub fn trusted_header_ip<'a>(
+    headers: &'a HeaderMap,
+    names: &[String],
+    peer: Option<IpAddr>,
+    trusted_proxies: &[IpNet],
+) -> Option<&'a str> {
+    let value = names.iter().find_map(|name| {
+        let value = headers.get(name.as_str())?.to_str().ok()?;
+        parse_ip(value).is_some().then_some(value)
+    })?;
+    let peer = peer?.to_canonical();
+    trusted_proxies
+        .iter()
+        .any(|net| net.contains(&peer))
+        .then_some(value)
+}
+
+/// Determine the client IP, applying `overflow` to an `X-Forwarded-For`
+/// longer than `max_hops` and `policy` when no IP can be found. A parseable
+/// `header_ip`, from [`trusted_header_ip`], takes precedence over both
+/// `X-Forwarded-For` and the peer address.
+pub fn resolve_client_ip(
+    header_ip: Option<&str>,
+    forwarded_for: Option<&str>,
+    peer_addr: Option<&str>,
+    max_hops: usize,
+    overflow: ForwardedHopsOverflow,
+    policy: FailurePolicy,
+) -> ClientIpResolution {
+    if overflow == ForwardedHopsOverflow::Reject
+        && forwarded_for.is_some_and(|v| too_many_hops(v, max_hops))
+    {
+        return ClientIpResolution::TooManyHops;
+    }
+    let ip = header_ip
+        .and_then(parse_ip)
+        .or_else(|| extract_client_ip(forwarded_for, peer_addr, max_hops));
+    match ip {
+        Some(ip) => ClientIpResolution::Known(ip),
+        None => match policy {
+            FailurePolicy::Allow => ClientIpResolution::Unknown,
+            FailurePolicy::Block => ClientIpResolution::Reject,
+        },
+    }
+}
+
+/// Parse a bare IP or an `ip:port` / `[ipv6]:port` socket address.
+fn parse_ip(value: &str) -> Option<IpAddr> {
+    let value = value.trim();
+    value
+        .parse::<IpAddr>()
+        .ok()
+        .or_else(|| value.parse::<SocketAddr>().ok().map(|a| a.ip()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::context::RequestContext;
+
+    const HOPS: usize = 20;
+    const TRUNCATE: ForwardedHopsOverflow = ForwardedHopsOverflow::Truncate;
+
+    #[test]
+    fn test_forwarded_for_takes_precedence() {
+        let ip = extract_client_ip(Some("203.0.113.7, 10.0.0.1"), Some("10.0.0.2:4000"), HOPS);
+        assert_eq!(ip, Some("203.0.113.7".parse().unwrap()));
+    }
+
+    #[test]
+    fn test_peer_addr_port_stripped() {
+        assert_eq!(
+            extract_client_ip(None, Some("192.168.1.5:51234"), HOPS),
+            Some("192.168.1.5".parse().unwrap())
+        );
+        assert_eq!(
+            extract_client_ip(None, Some("[2001:db8::1]:443"), HOPS),
+            Some("2001:db8::1".parse().unwrap())
+        );
+    }
+
+    #[test]
+    fn test_invalid_forwarded_for_falls_back_to_peer() {
+        let ip = extract_client_ip(Some("unknown"), Some("192.168.1.5:51234"), HOPS);
+        assert_eq!(ip, Some("192.168.1.5".parse().unwrap()));
+    }
+
+    #[test]
+    fn test_undeterminable_ip() {
+        assert_eq!(extract_client_ip(None, None, HOPS), None);
+        assert_eq!(extract_client_ip(Some(""), Some(""), HOPS), None);
+    }
+
+    #[test]
+    fn test_fail_open_yields_unknown() {
+        let res = resolve_client_ip(None, Some("  "), None, HOPS, TRUNCATE, FailurePolicy::Allow);
+        assert_eq!(res, ClientIpResolution::Unknown);
+    }
+
+    #[test]
+    fn test_fail_closed_rejects() {
+        let res = resolve_client_ip(None, None, None, HOPS, TRUNCATE, FailurePolicy::Block);
+        assert_eq!(res, ClientIpResolution::Reject);
+    }
+
+    #[test]
+    fn test_unknown_clients_never_rate_limited_together() {
+        // Key the limiter the way the proxy does; `None` when it's skipped
+        let limiter = layer7waf_rate_limit::RateLimiter::new_token_bucket(1, 1);
+        let check = |peer: Option<&str>| {
+            let mut ctx = RequestContext::new();
+            let res = resolve_client_ip(None, None, peer, HOPS, TRUNCATE, FailurePolicy::Allow);
+            if let ClientIpResolution::Known(ip) = res {
+                ctx.client_ip = ip.to_string();
+            }
+            ctx.client_key().map(|key| limiter.check(key))
+        };
+
+        assert_eq!(check(Some("192.0.2.1:5000")), Some(true));
+        assert_eq!(check(Some("192.0.2.1:5001")), Some(false));
+        for _ in 0..3 {
+            assert_eq!(check(None), None);
+        }
+        assert_eq!(limiter.tracked_keys(), 1);
+    }
+
+    #[test]
+    fn test_oversized_forwarded_for_truncated() {
+        let junk = vec!["198.51.100.1"; 10_000].join(", ");
+        let header = format!("{}, 203.0.113.7, 10.0.0.1", junk);
+        assert!(too_many_hops(&header, 3));
+
+        // Only the nearest hops count, so the header's length doesn't matter
+        let ip = extract_client_ip(Some(&header), Some("10.0.0.2:4000"), 2);
+        assert_eq!(ip, Some("203.0.113.7".parse().unwrap()));
+
+        // A normal chain within the limit is unaffected
+        let chain = "203.0.113.7, 10.0.0.1";
+        assert!(!too_many_hops(chain, 2));
+        let res = resolve_client_ip(None, Some(chain), None, 2, TRUNCATE, FailurePolicy::Block);
+        assert_eq!(res, ClientIpResolution::Known("203.0.113.7".parse().unwrap()));
+    }
+
+    #[test]
+    fn test_oversized_forwarded_for_rejected() {
+        let header = Some("198.51.100.1, 203.0.113.7, 10.0.0.1");
+        let peer = Some("10.0.0.2:4000");
+        let reject = ForwardedHopsOverflow::Reject;
+        let res = resolve_client_ip(None, header, peer, 2, reject, FailurePolicy::Allow);
+        assert_eq!(res, ClientIpResolution::TooManyHops);
+
+        let res = resolve_client_ip(None, header, peer, 3, reject, FailurePolicy::Allow);
+        assert_eq!(res, ClientIpResolution::Known("198.51.100.1".parse().unwrap()));
+    }
+
+    fn cdn_headers() -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert("cf-connecting-ip", "198.51.100.23".parse().unwrap());
+        headers.insert("x-forwarded-for", "203.0.113.7".parse().unwrap());
+        headers
+    }
+
+    #[test]
+    fn test_client_ip_header_from_trusted_proxy() {
+        let headers = cdn_headers();
+        let names = vec!["True-Client-IP".to_string(), "CF-Connecting-IP".to_string()];
+        let trusted: Vec<IpNet> = vec!["173.245.48.0/20".parse().unwrap()];
+        let cdn: IpAddr = "173.245.48.10".parse().unwrap();
+
+        let header_ip = trusted_header_ip(&headers, &names, Some(cdn), &trusted);
+        assert_eq!(header_ip, Some("198.51.100.23"));
+        let res = resolve_client_ip(
+            header_ip,
+            Some("203.0.113.7"),
+            Some("173.245.48.10:443"),
+            HOPS,
+            TRUNCATE,
+            FailurePolicy::Allow,
+        );
+        assert_eq!(res, ClientIpResolution::Known("198.51.100.23".parse().unwrap()));
+
+        // Not configured: the header means nothing
+        assert_eq!(trusted_header_ip(&headers, &[], Some(cdn), &trusted), None);
+    }
+
+    #[test]
+    fn test_garbage_client_ip_header_skipped() {
+        let mut headers = cdn_headers();
+        headers.insert("cf-connecting-ip", "unknown".parse().unwrap());
+        headers.insert("true-client-ip", "198.51.100.9".parse().unwrap());
+        let names = vec!["CF-Connecting-IP".to_string(), "True-Client-IP".to_string()];
+        let trusted: Vec<IpNet> = vec!["173.245.48.0/20".parse().unwrap()];
+        let cdn: IpAddr = "173.245.48.10".parse().unwrap();
+
+        let header_ip = trusted_header_ip(&headers, &names, Some(cdn), &trusted);
+        assert_eq!(header_ip, Some("198.51.100.9"));
+        assert_eq!(trusted_header_ip(&headers, &names[..1], Some(cdn), &trusted), None);
+    }
+
+    #[test]
+    fn test_client_ip_header_ignored_from_untrusted_peer() {
+        let headers = cdn_headers();
+        let names = vec!["CF-Connecting-IP".to_string()];
+        let trusted: Vec<IpNet> = vec!["173.245.48.0/20".parse().unwrap()];
+        let direct: IpAddr = "192.0.2.50".parse().unwrap();
+
+        assert_eq!(
+            trusted_header_ip(&headers, &names, Some(direct), &trusted),
+            None
+        );
+        assert_eq!(trusted_header_ip(&headers, &names, None, &trusted), None);
+        assert_eq!(trusted_header_ip(&headers, &names, Some(direct), &[]), None);
+    }
+}
@@ -0,0 +1,1429 @@
+use std::borrow::Borrow;
+use std::collections::{BTreeMap, HashMap};
+use std::net::IpAddr;
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+
+use arc_swap::ArcSwap;
+use ipnet::IpNet;
+use layer7waf_admin::reload::{
+    ConfigReloader, FlaggedSession, IdentifiedWatermark, IpList, Subsystem,
+};
+use layer7waf_anti_scraping::obfuscation::extract_watermark;
+use layer7waf_anti_scraping::AntiScraper;
+use layer7waf_bot_detect::diversity::FingerprintCount;
+use layer7waf_bot_detect::{BotCheckResult, BotDetector};
+use layer7waf_common::{
+    AppConfig, HealthProbeAction, KeyCapacity, KeyOverflowPolicy, MapFootprint,
+    RateLimitAlgorithm,
+};
+use layer7waf_coraza::{BodyBudget, RuleGraceRegistry, RuleHitSampler, WafEngine};
+use layer7waf_geoip::GeoIpFilter;
+use layer7waf_ip_reputation::IpReputation;
+use layer7waf_rate_limit::RateLimiter;
+use tracing::{error, info, warn};
+
+use crate::access_log::AccessLog;
+use crate::auth_limit::{AuthenticatedLimiter, RequestLimiter};
+use crate::capture::RequestCapture;
+use crate::health_probe::HealthProbe;
+use crate::router::RouteMatcher;
+use crate::status_metrics::ErrorRateAlert;
+use crate::upstream::{UpstreamSelector, CONNECT_FAILURE_DOWN_TIME};
+use crate::waf_directives::{build_ruleset_directives, build_waf_directives};
+
+/// The request-processing components built from the config.
+///
+/// Every field is cheap to clone and a clone shares state with the original,
+/// so a reload can replace some components while the others keep their
+/// per-client state (rate-limit buckets, bot and scraping sessions).
+#[derive(Clone)]
+pub struct Components {
+    pub waf_engine: Option<Arc<WafEngine>>,
+    /// One engine per `waf.rulesets` entry, for routes that select it.
+    pub ruleset_engines: Arc<HashMap<String, Arc<WafEngine>>>,
+    /// Bounds body bytes in flight to any of the WAF engines.
+    pub waf_body_budget: Arc<BodyBudget>,
+    /// Samples WAF rule hits for logging, when `waf.rule_hit_sampling` is on.
+    pub rule_hit_sampler: Option<Arc<RuleHitSampler>>,
+    /// Rules in `waf.rule_grace` whose matches don't block yet.
+    pub rule_grace: Option<Arc<RuleGraceRegistry>>,
+    pub upstreams: Arc<Vec<UpstreamSelector>>,
+    pub router: Arc<RouteMatcher>,
+    pub health_probe: Option<Arc<HealthProbe>>,
+    /// `server.trusted_proxies`, whose `server.client_ip_headers` are believed.
+    pub trusted_proxies: Arc<Vec<IpNet>>,
+    pub rate_limiter: Option<Arc<RateLimiter>>,
+    /// Per-route limiters, indexed like `config.routes`.
+    pub route_rate_limiters: Vec<Option<RateLimiter>>,
+    /// Limits new downstream connections per client, apart from requests.
+    pub connection_limiter: Option<Arc<RateLimiter>>,
+    /// Replaces the route and global limiters for authenticated requests.
+    pub authenticated_limiter: Option<Arc<AuthenticatedLimiter>>,
+    /// Caps requests per client per UTC day, on top of the other limiters.
+    pub daily_quota_limiter: Option<Arc<RateLimiter>>,
+    pub ip_reputation: Arc<IpReputation>,
+    pub bot_detector: Option<Arc<BotDetector>>,
+    pub anti_scraper: Option<Arc<AntiScraper>>,
+    pub geoip_filter: Option<Arc<GeoIpFilter>>,
+    /// Rules added through the admin API, compiled into `waf_engine` after
+    /// the configured ones.
+    pub custom_rules: Arc<Vec<String>>,
+    /// Destination of `common`/`combined` access log lines.
+    pub access_log: Arc<AccessLog>,
+    /// Sampled request metadata for offline rule tuning, when enabled.
+    pub capture: Option<Arc<RequestCapture>>,
+    /// 5xx surge warning (`server.error_rate_alert`), when enabled.
+    pub error_rate_alert: Option<Arc<ErrorRateAlert>>,
+}
+
+impl Components {
+    /// Build every component. Failures are logged and leave the affected
+    /// component disabled, so the proxy can still start; only an access log
+    /// or capture file that can't be opened stops it.
+    pub fn build(config: &AppConfig) -> Self {
+        let (rate_limiter, route_rate_limiters) = build_rate_limiters(config);
+        Self {
+            waf_engine: build_waf_engine(config, &[]).unwrap_or_else(|e| {
+                error!("failed to initialize WAF engine: {}", e);
+                None
+            }),
+            ruleset_engines: build_ruleset_engines(config, &[]).unwrap_or_else(|e| {
+                error!(
+                    "failed to initialize WAF ruleset engines, routes selecting them use the \
+                     default engine: {}",
+                    e
+                );
+                Arc::default()
+            }),
+            waf_body_budget: build_waf_body_budget(config),
+            rule_hit_sampler: build_rule_hit_sampler(config),
+            rule_grace: build_rule_grace(config),
+            upstreams: build_upstreams(config),
+            router: Arc::new(RouteMatcher::new(&config.routes)),
+            health_probe: build_health_probe(config),
+            trusted_proxies: build_trusted_proxies(config),
+            rate_limiter,
+            route_rate_limiters,
+            connection_limiter: build_connection_limiter(config),
+            authenticated_limiter: build_authenticated_limiter(config).map(Arc::new),
+            daily_quota_limiter: build_daily_quota_limiter(config, None),
+            ip_reputation: build_ip_reputation(config),
+            bot_detector: build_bot_detector(config),
+            anti_scraper: build_anti_scraper(config),
+            geoip_filter: build_geoip_filter(config).unwrap_or_else(|e| {
+                warn!(error = %e, "failed to initialize GeoIP filter, continuing without it");
+                None
+            }),
+            custom_rules: Arc::new(Vec::new()),
+            access_log: build_access_log(config)
+                .unwrap_or_else(|e| panic!("failed to open access log: {}", e)),
+            capture: build_capture(config)
+                .unwrap_or_else(|e| panic!("failed to open request capture file: {}", e)),
+            error_rate_alert: build_error_rate_alert(config),
+        }
+    }
+
+    /// Return a copy with only `subsystems` rebuilt from `config`, these
+    /// components having been built from `previous`. Unlike
+    /// [`build`](Self::build), a component that fails to build is an error,
+    /// so a bad reload never silently disables protection.
+    pub fn rebuild(
+        &self,
+        previous: &AppConfig,
+        config: &AppConfig,
+        subsystems: &[Subsystem],
+    ) -> anyhow::Result<Self> {
+        let mut next = self.clone();
+        for subsystem in subsystems {
+            match subsystem {
+                Subsystem::Routing => {
+                    next.upstreams = build_upstreams(config);
+                    next.router = Arc::new(RouteMatcher::new(&config.routes));
+                    next.health_probe = build_health_probe(config);
+                    next.trusted_proxies = build_trusted_proxies(config);
+                }
+                Subsystem::IpReputation => {
+                    next.ip_reputation = build_ip_reputation(config);
+                    next.ip_reputation.replace_blocklist_of(&self.ip_reputation);
+                    next.ip_reputation.keep_runtime_state_of(&self.ip_reputation);
+                }
+                Subsystem::GeoIp => next.geoip_filter = build_geoip_filter(config)?,
+                Subsystem::RateLimit => {
+                    // Limiters built just like the running ones are kept,
+                    // so a reload only resets the clients of those it changes
+                    let (rate_limiter, route_rate_limiters) = build_rate_limiters(config);
+                    next.rate_limiter =
+                        rate_limiter.map(|l| keep_if_unchanged(self.rate_limiter.as_ref(), l));
+                    next.route_rate_limiters = route_rate_limiters
+                        .into_iter()
+                        .zip(&config.routes)
+                        .map(|(limiter, route)| {
+                            let current = previous
+                                .routes
+                                .iter()
+                                .position(|r| {
+                                    r.host == route.host && r.path_prefix == route.path_prefix
+                                })
+                                .and_then(|i| self.route_rate_limiters.get(i)?.as_ref());
+                            limiter.map(|l| keep_if_unchanged(current, l))
+                        })
+                        .collect();
+                    next.connection_limiter = build_connection_limiter(config)
+                        .map(|l| keep_if_unchanged(self.connection_limiter.as_ref(), l));
+                    let current = self.authenticated_limiter.as_deref();
+                    next.authenticated_limiter = build_authenticated_limiter(config).map(|mut l| {
+                        if let Some(current) = current {
+                            l.limiter = keep_if_unchanged(Some(&current.limiter), l.limiter);
+                            l.ip_limiter =
+                                keep_if_unchanged(Some(&current.ip_limiter), l.ip_limiter);
+                        }
+                        Arc::new(l)
+                    });
+                    next.daily_quota_limiter =
+                        build_daily_quota_limiter(config, self.daily_quota_limiter.as_deref());
+                }
+                Subsystem::BotDetection => next.bot_detector = build_bot_detector(config),
+                Subsystem::AntiScraping => next.anti_scraper = build_anti_scraper(config),
+                Subsystem::Waf => {
+                    next.waf_engine = build_waf_engine(config, &self.custom_rules)
+                        .map_err(|e| anyhow::anyhow!(e))?;
+                    next.ruleset_engines = build_ruleset_engines(config, &self.custom_rules)
+                        .map_err(|e| anyhow::anyhow!(e))?;
+                    // Scans in flight hold permits of the current budget; a
+                    // fresh one would let them go uncounted
+                    if !next.waf_body_budget.is_configured_as(
+                        config.waf.body_budget_bytes,
+                        config.waf.on_body_budget_exhausted,
+                    ) {
+                        next.waf_body_budget = build_waf_body_budget(config);
+                    }
+                    next.rule_hit_sampler = build_rule_hit_sampler(config);
+                    next.rule_grace = build_rule_grace(config);
+                }
+                Subsystem::Logging => {
+                    next.access_log = build_access_log(config)
+                        .map_err(|e| anyhow::anyhow!("failed to open access log: {}", e))?;
+                    next.capture = build_capture(config).map_err(|e| {
+                        anyhow::anyhow!("failed to open request capture file: {}", e)
+                    })?;
+                    next.error_rate_alert = build_error_rate_alert(config);
+                }
+            }
+            info!(subsystem = ?subsystem, "subsystem reloaded");
+        }
+        Ok(next)
+    }
+
+    /// Return a copy whose WAF engine enforces `custom_rules` on top of the
+    /// rules in `config`. Fails if the engine rejects the rules.
+    pub fn with_custom_rules(
+        &self,
+        config: &AppConfig,
+        custom_rules: Vec<String>,
+    ) -> anyhow::Result<Self> {
+        let mut next = self.clone();
+        next.waf_engine =
+            build_waf_engine(config, &custom_rules).map_err(|e| anyhow::anyhow!(e))?;
+        next.ruleset_engines =
+            build_ruleset_engines(config, &custom_rules).map_err(|e| anyhow::anyhow!(e))?;
+        next.custom_rules = Arc::new(custom_rules);
+        info!(count = next.custom_rules.len(), "custom WAF rules applied");
+        Ok(next)
+    }
+
+    /// The engine for a route using `ruleset`, or the default engine when
+    /// the route names none. A ruleset whose engine failed to build also
+    /// gets the default engine rather than none, so a route asking for
+    /// stricter rules never ends up without a WAF.
+    pub fn waf_engine_for(&self, ruleset: Option<&str>) -> Option<&Arc<WafEngine>> {
+        ruleset
+            .and_then(|name| self.ruleset_engines.get(name))
+            .or(self.waf_engine.as_ref())
+    }
+
+    /// The rate limit for a request: the authenticated limiter keyed by
+    /// session identifier (and capped per client) when it is authenticated,
+    /// otherwise the route's own limiter or the global one, keyed by client.
+    pub fn request_limiter(
+        &self,
+        route_index: Option<usize>,
+        headers: &http::HeaderMap,
+    ) -> Option<RequestLimiter<'_>> {
+        if let Some(ref auth) = self.authenticated_limiter {
+            if let Some(session_key) = auth.key(headers) {
+                return Some(RequestLimiter::Authenticated { limiter: auth, session_key });
+            }
+        }
+        route_index
+            .and_then(|i| self.route_rate_limiters.get(i))
+            .and_then(|l| l.as_ref())
+            .or(self.rate_limiter.as_deref())
+            .map(RequestLimiter::Client)
+    }
+
+    /// How to handle a request that is a configured health probe, or `None`
+    /// for normal traffic. `peer` is the socket address of the connection.
+    pub fn health_probe_action(
+        &self,
+        method: &http::Method,
+        uri: &http::Uri,
+        headers: &http::HeaderMap,
+        peer: Option<IpAddr>,
+    ) -> Option<HealthProbeAction> {
+        self.health_probe.as_ref()?.action_for(method, uri, headers, peer)
+    }
+
+    /// Address of the next available server of upstream `name`. `None` when
+    /// every server is marked down or the upstream doesn't exist.
+    pub fn select_upstream(&self, name: &str) -> Option<&str> {
+        self.upstreams.iter().find(|u| u.name == name)?.select()
+    }
+
+    /// Skip server `addr` of upstream `name` for
+    /// [`CONNECT_FAILURE_DOWN_TIME`] after a failed connection.
+    pub fn mark_upstream_down(&self, name: &str, addr: &str) {
+        if let Some(upstream) = self.upstreams.iter().find(|u| u.name == name) {
+            upstream.mark_down(addr, CONNECT_FAILURE_DOWN_TIME);
+        }
+    }
+
+    /// All active rate limiters labelled by scope, for the admin API.
+    pub fn rate_limiters(&self, config: &AppConfig) -> Vec<(String, RateLimiter)> {
+        let global = self
+            .rate_limiter
+            .iter()
+            .map(|l| ("global".to_string(), l.as_ref().clone()));
+        let routes = self
+            .route_rate_limiters
+            .iter()
+            .zip(config.routes.iter())
+            .filter_map(|(limiter, route)| {
+                let scope = format!(
+                    "{}{}",
+                    route.host.as_deref().unwrap_or(""),
+                    route.path_prefix
+                );
+                limiter.clone().map(|l| (scope, l))
+            });
+        let authenticated = self.authenticated_limiter.iter().flat_map(|l| {
+            [
+                ("authenticated".to_string(), l.limiter.clone()),
+                ("authenticated_ip".to_string(), l.ip_limiter.clone()),
+            ]
+        });
+        let connections = self
+            .connection_limiter
+            .iter()
+            .map(|l| ("connections".to_string(), l.as_ref().clone()));
+        let daily_quota = self
+            .daily_quota_limiter
+            .iter()
+            .map(|l| ("daily_quota".to_string(), l.as_ref().clone()));
+        global
+            .chain(routes)
+            .chain(authenticated)
+            .chain(connections)
+            .chain(daily_quota)
+            .collect()
+    }
+
+    /// Entry count and approximate memory use of every per-key map, rate
+    /// limiters named `rate_limit:<scope>` as in [`rate_limiters`].
+    ///
+    /// [`rate_limiters`]: Self::rate_limiters
+    pub fn map_footprints(&self, config: &AppConfig) -> Vec<MapFootprint> {
+        let mut maps: Vec<MapFootprint> = self
+            .rate_limiters(config)
+            .into_iter()
+            .map(|(scope, limiter)| limiter.map_footprint(format!("rate_limit:{}", scope)))
+            .collect();
+        if let Some(ref detector) = self.bot_detector {
+            maps.extend(detector.map_footprints());
+        }
+        if let Some(ref scraper) = self.anti_scraper {
+            maps.extend(scraper.map_footprints());
+        }
+        if let Some(ref geoip) = self.geoip_filter {
+            maps.push(geoip.map_footprint());
+        }
+        maps
+    }
+
+    /// The GeoIP filter to apply to a client on a route whose `geoip.enabled`
+    /// is `route_geoip`. An allowlisted client skips GeoIP only when
+    /// `security.allowlist_overrides_geoip` is set.
+    pub fn geoip_filter_for(
+        &self,
+        route_geoip: bool,
+        allowlisted: bool,
+        allowlist_overrides_geoip: bool,
+    ) -> Option<&Arc<GeoIpFilter>> {
+        if !route_geoip || (allowlisted && allowlist_overrides_geoip) {
+            return None;
+        }
+        self.geoip_filter.as_ref()
+    }
+
+    /// Bot verdict while under-attack mode is on: allowlisted IPs and known
+    /// good bots pass, every other client must hold a valid challenge cookie.
+    /// Without a bot detector there is no challenge to serve, so all pass.
+    pub fn under_attack_check(
+        &self,
+        client_ip: &str,
+        headers: &[(String, String)],
+        cookie_header: Option<&str>,
+    ) -> BotCheckResult {
+        let allowlisted = client_ip
+            .parse()
+            .is_ok_and(|addr| self.ip_reputation.is_allowed(addr));
+        match self.bot_detector {
+            Some(ref detector) if !allowlisted => {
+                detector.check_under_attack(client_ip, headers, cookie_header)
+            }
+            _ => BotCheckResult::Allow,
+        }
+    }
+
+    fn all_rate_limiters(&self) -> Vec<RateLimiter> {
+        self.rate_limiter
+            .iter()
+            .map(|l| l.as_ref().clone())
+            .chain(self.route_rate_limiters.iter().flatten().cloned())
+            .chain(
+                self.authenticated_limiter
+                    .iter()
+                    .flat_map(|l| [l.limiter.clone(), l.ip_limiter.clone()]),
+            )
+            .chain(self.connection_limiter.iter().map(|l| l.as_ref().clone()))
+            .chain(self.daily_quota_limiter.iter().map(|l| l.as_ref().clone()))
+            .collect()
+    }
+}
+
+/// Spawn a background thread that cleans up whichever rate limiters are
+/// live every 60 seconds, applying each limiter's own key TTL.
+pub fn start_rate_limit_cleanup(components: Arc<ArcSwap<Components>>) {
+    std::thread::Builder::new()
+        .name("rate-limit-cleanup".into())
+        .spawn(move || loop {
+            std::thread::sleep(Duration::from_secs(60));
+            let limiters = components.load().all_rate_limiters();
+            layer7waf_rate_limit::cleanup_all(&limiters);
+        })
+        .expect("failed to spawn rate-limit cleanup thread");
+}
+
+/// Log the WAF rule hit summary every `summary_interval_secs`, following
+/// reloads of the sampler.
+pub fn start_rule_hit_summary(components: Arc<ArcSwap<Components>>) {
+    std::thread::Builder::new()
+        .name("rule-hit-summary".into())
+        .spawn(move || loop {
+            let sampler = components.load().rule_hit_sampler.clone();
+            let interval = sampler
+                .as_ref()
+                .map_or(Duration::from_secs(60), |s| s.summary_interval());
+            std::thread::sleep(interval);
+            if let Some(sampler) = sampler {
+                sampler.log_summary();
+            }
+        })
+        .expect("failed to spawn rule-hit summary thread");
+}
+
+/// Applies admin API config changes to the running proxy.
+pub struct ProxyReloader {
+    pub config: Arc<RwLock<AppConfig>>,
+    pub components: Arc<ArcSwap<Components>>,
+}
+
+impl ConfigReloader for ProxyReloader {
+    fn reload(&self, config: &AppConfig, subsystems: &[Subsystem]) -> anyhow::Result<()> {
+        // Build first so a failure leaves both config and components untouched
+        let previous = self.config.read().unwrap().clone();
+        let next = self.components.load().rebuild(&previous, config, subsystems)?;
+        let mut current = self.config.write().unwrap();
+        self.components.store(Arc::new(next));
+        *current = config.clone();
+        Ok(())
+    }
+
+    fn rate_limiters(&self) -> Vec<(String, RateLimiter)> {
+        let config = self.config.read().unwrap();
+        self.components.load().rate_limiters(&config)
+    }
+
+    fn apply_custom_rules(&self, rules: &[String]) -> anyhow::Result<()> {
+        let config = self.config.read().unwrap();
+        let next = self.components.load().with_custom_rules(&config, rules.to_vec())?;
+        self.components.store(Arc::new(next));
+        Ok(())
+    }
+
+    fn top_fingerprints(&self, limit: usize) -> Vec<FingerprintCount> {
+        self.components
+            .load()
+            .bot_detector
+            .as_ref()
+            .map(|detector| detector.top_fingerprints(limit))
+            .unwrap_or_default()
+    }
+
+    fn flagged_sessions(&self) -> Vec<FlaggedSession> {
+        let components = self.components.load();
+        let mut flagged: BTreeMap<String, FlaggedSession> = BTreeMap::new();
+        if let Some(ref detector) = components.bot_detector {
+            for (ip, score) in detector.flagged_sessions() {
+                flagged_entry(&mut flagged, ip).bot_score = Some(score);
+            }
+        }
+        if let Some(ref scraper) = components.anti_scraper {
+            for (ip, score) in scraper.flagged_sessions() {
+                flagged_entry(&mut flagged, ip).scraping_score = Some(score);
+            }
+        }
+        flagged.into_values().collect()
+    }
+
+    fn clear_sessions(&self, ip: Option<&str>) -> usize {
+        let components = self.components.load();
+        let bot = components.bot_detector.as_ref().map_or(0, |detector| match ip {
+            Some(ip) => usize::from(detector.clear_session(ip)),
+            None => detector.clear_sessions(),
+        });
+        let scraping = components.anti_scraper.as_ref().map_or(0, |scraper| match ip {
+            Some(ip) => usize::from(scraper.clear_session(ip)),
+            None => scraper.clear_sessions(),
+        });
+        bot + scraping
+    }
+
+    fn cleanup(&self, session_max_age: Duration) {
+        let components = self.components.load();
+        layer7waf_rate_limit::cleanup_all(&components.all_rate_limiters());
+        components.ip_reputation.cleanup_expired();
+        if let Some(ref detector) = components.bot_detector {
+            detector.cleanup_sessions(session_max_age);
+        }
+        if let Some(ref scraper) = components.anti_scraper {
+            scraper.cleanup_sessions(session_max_age);
+        }
+    }
+
+    fn map_footprints(&self) -> Vec<MapFootprint> {
+        let config = self.config.read().unwrap();
+        self.components.load().map_footprints(&config)
+    }
+
+    fn identify_watermark(&self, text: &str) -> Option<IdentifiedWatermark> {
+        match self.components.load().anti_scraper {
+            Some(ref scraper) => {
+                let (watermark, ip) = scraper.identify(text)?;
+                Some(IdentifiedWatermark { watermark, ip })
+            }
+            None => {
+                extract_watermark(text).map(|watermark| IdentifiedWatermark { watermark, ip: None })
+            }
+        }
+    }
+
+    fn ip_list_entries(&self, list: IpList) -> Vec<String> {
+        let components = self.components.load();
+        let entries = match list {
+            IpList::Blocklist => components.ip_reputation.blocklist_entries(),
+            IpList::Allowlist => components.ip_reputation.allowlist_entries(),
+        };
+        entries.iter().map(|net| net.to_string()).collect()
+    }
+}
+
+fn flagged_entry(flagged: &mut BTreeMap<String, FlaggedSession>, ip: String) -> &mut FlaggedSession {
+    flagged.entry(ip.clone()).or_insert(FlaggedSession {
+        ip,
+        bot_score: None,
+        scraping_score: None,
+    })
+}
+
+/// `next`, or `current` if that is configured just like it, so the clients
+/// it tracks keep their state.
+fn keep_if_unchanged<L: Borrow<RateLimiter> + Clone>(current: Option<&L>, next: L) -> L {
+    match current {
+        Some(current) if current.borrow().is_configured_like(next.borrow()) => current.clone(),
+        _ => next,
+    }
+}
+
+fn build_waf_body_budget(config: &AppConfig) -> Arc<BodyBudget> {
+    Arc::new(BodyBudget::new(
+        config.waf.body_budget_bytes,
+        config.waf.on_body_budget_exhausted,
+    ))
+}
+
+fn build_rule_hit_sampler(config: &AppConfig) -> Option<Arc<RuleHitSampler>> {
+    let sampling = &config.waf.rule_hit_sampling;
+    if !sampling.enabled {
+        return None;
+    }
+    info!(sample_rate = sampling.sample_rate, "WAF rule hit sampling enabled");
+    Some(Arc::new(RuleHitSampler::new(sampling)))
+}
+
+fn build_rule_grace(config: &AppConfig) -> Option<Arc<RuleGraceRegistry>> {
+    if config.waf.rule_grace.is_empty() {
+        return None;
+    }
+    info!(rules = config.waf.rule_grace.len(), "WAF rule grace periods configured");
+    Some(Arc::new(RuleGraceRegistry::new(&config.waf.rule_grace)))
+}
+
+fn build_waf_engine(
+    config: &AppConfig,
+    custom_rules: &[String],
+) -> Result<Option<Arc<WafEngine>>, String> {
+    if config.waf.rules.is_empty() && config.waf.inline_rules.is_empty() && custom_rules.is_empty()
+    {
+        info!("no WAF rules configured, WAF engine disabled");
+        return Ok(None);
+    }
+    let engine = WafEngine::new(&build_waf_directives(config, custom_rules))?;
+    info!(
+        "WAF engine initialized with {} rule patterns, {} inline rules and {} custom rules",
+        config.waf.rules.len(),
+        config.waf.inline_rules.len(),
+        custom_rules.len()
+    );
+    Ok(Some(Arc::new(engine)))
+}
+
+fn build_ruleset_engines(
+    config: &AppConfig,
+    custom_rules: &[String],
+) -> Result<Arc<HashMap<String, Arc<WafEngine>>>, String> {
+    let mut engines = HashMap::new();
+    for (name, patterns) in &config.waf.rulesets {
+        let directives = build_ruleset_directives(config, patterns, custom_rules);
+        let engine = WafEngine::new(&directives)
+            .map_err(|e| format!("WAF ruleset '{}': {}", name, e))?;
+        info!(ruleset = %name, patterns = patterns.len(), "WAF ruleset engine initialized");
+        engines.insert(name.clone(), Arc::new(engine));
+    }
+    Ok(Arc::new(engines))
+}
+
+fn build_upstreams(config: &AppConfig) -> Arc<Vec<UpstreamSelector>> {
+    Arc::new(
+        config
+            .upstreams
+            .iter()
+            .map(UpstreamSelector::from_config)
+            .collect(),
+    )
+}
+
+fn build_rate_limiters(config: &AppConfig) -> (Option<Arc<RateLimiter>>, Vec<Option<RateLimiter>>) {
+    let (capacity, _, _) = KeyCapacity::from_state_limits(&config.state_limits, config.failure_policy);
+
+    let rate_limiter = if config.rate_limit.enabled {
+        let limiter = RateLimiter::new_token_bucket(
+            config.rate_limit.default_rps,
+            config.rate_limit.default_burst,
+        )
+        .with_key_capacity(capacity);
+        info!(
+            rps = config.rate_limit.default_rps,
+            burst = config.rate_limit.default_burst,
+            "rate limiter enabled"
+        );
+        Some(Arc::new(limiter))
+    } else {
+        None
+    };
+
+    let route_rate_limiters = config
+        .routes
+        .iter()
+        .map(|route| {
+            route.rate_limit.as_ref().map(|rl| {
+                info!(
+                    path_prefix = %route.path_prefix,
+                    rps = rl.rps,
+                    burst = rl.burst,
+                    algorithm = ?rl.algorithm,
+                    key_ttl_secs = ?rl.key_ttl_secs,
+                    adaptive = rl.adaptive.is_some(),
+                    "route rate limiter enabled"
+                );
+                let capacity = match rl.algorithm {
+                    RateLimitAlgorithm::DailyQuota => daily_quota_capacity(config),
+                    RateLimitAlgorithm::TokenBucket | RateLimitAlgorithm::SlidingWindow => capacity,
+                };
+                RateLimiter::from_route_config(rl).with_key_capacity(capacity)
+            })
+        })
+        .collect();
+
+    (rate_limiter, route_rate_limiters)
+}
+
+fn build_health_probe(config: &AppConfig) -> Option<Arc<HealthProbe>> {
+    let probe = config.health_probe.as_ref()?;
+    info!(path = %probe.path, action = ?probe.action, "health probe fast path enabled");
+    Some(Arc::new(HealthProbe::new(probe, &config.server.client_ip_headers)))
+}
+
+/// Parsed once here rather than per request; unparseable CIDRs are skipped,
+/// though validation already rejects them.
+fn build_trusted_proxies(config: &AppConfig) -> Arc<Vec<IpNet>> {
+    let cidrs = &config.server.trusted_proxies;
+    Arc::new(cidrs.iter().filter_map(|c| c.parse().ok()).collect())
+}
+
+fn build_connection_limiter(config: &AppConfig) -> Option<Arc<RateLimiter>> {
+    let rps = config.rate_limit.connection_rps.filter(|_| config.rate_limit.enabled)?;
+    let (capacity, _, _) = KeyCapacity::from_state_limits(&config.state_limits, config.failure_policy);
+    info!(
+        rps,
+        burst = config.rate_limit.connection_burst,
+        "connection rate limiter enabled"
+    );
+    Some(Arc::new(
+        RateLimiter::new_token_bucket(rps, config.rate_limit.connection_burst)
+            .with_key_capacity(capacity),
+    ))
+}
+
+fn build_authenticated_limiter(config: &AppConfig) -> Option<AuthenticatedLimiter> {
+    let auth = config.rate_limit.authenticated.as_ref().filter(|_| config.rate_limit.enabled)?;
+    let (capacity, _, _) = KeyCapacity::from_state_limits(&config.state_limits, config.failure_policy);
+    info!(
+        cookie = ?auth.cookie,
+        header = ?auth.header,
+        rps = auth.limit.rps,
+        burst = auth.limit.burst,
+        "authenticated rate limiter enabled"
+    );
+    Some(AuthenticatedLimiter::new(auth, capacity))
+}
+
+/// Counts carry on from `current`, so a reload doesn't hand out fresh quotas.
+fn build_daily_quota_limiter(
+    config: &AppConfig,
+    current: Option<&RateLimiter>,
+) -> Option<Arc<RateLimiter>> {
+    let quota = config.rate_limit.daily_quota.filter(|_| config.rate_limit.enabled)?;
+    info!(quota, "daily request quota enabled");
+    let limiter = match current {
+        Some(current) => RateLimiter::new_daily_quota_continuing(quota, current),
+        None => RateLimiter::new_daily_quota(quota),
+    };
+    Some(Arc::new(limiter.with_key_capacity(daily_quota_capacity(config))))
+}
+
+/// Key capacity of the daily quotas. An evicted client would start its day
+/// over, so a full quota refuses new clients per `failure_policy` instead,
+/// whatever `state_limits.on_overflow` says.
+fn daily_quota_capacity(config: &AppConfig) -> KeyCapacity {
+    KeyCapacity::new(
+        config.state_limits.max_rate_limit_keys,
+        KeyOverflowPolicy::Refuse,
+        config.failure_policy,
+    )
+}
+
+fn build_access_log(config: &AppConfig) -> std::io::Result<Arc<AccessLog>> {
+    AccessLog::open(&config.server.access_log).map(Arc::new)
+}
+
+fn build_capture(config: &AppConfig) -> std::io::Result<Option<Arc<RequestCapture>>> {
+    let capture = RequestCapture::open(&config.server.capture)?;
+    if capture.is_some() {
+        info!(sample_rate = config.server.capture.sample_rate, "request capture enabled");
+    }
+    Ok(capture.map(Arc::new))
+}
+
+fn build_error_rate_alert(config: &AppConfig) -> Option<Arc<ErrorRateAlert>> {
+    let alert = &config.server.error_rate_alert;
+    alert.enabled.then(|| Arc::new(ErrorRateAlert::new(alert)))
+}
+
+fn build_ip_reputation(config: &AppConfig) -> Arc<IpReputation> {
+    let mut ip_reputation = IpReputation::new()
+        .with_blocklist_grace(Duration::from_secs(config.ip_reputation.blocklist_grace_secs))
+        .with_family_policy(config.ip_reputation.ipv4_policy, config.ip_reputation.ipv6_policy);
+    if config.ip_reputation.waf_penalty.enabled {
+        ip_reputation = ip_reputation.with_waf_penalty(&config.ip_reputation.waf_penalty);
+    }
+    let ip_reputation = Arc::new(ip_reputation);
+    if let Some(ref path) = config.ip_reputation.blocklist {
+        match ip_reputation.load_blocklist(path) {
+            Ok(count) => info!(count, path = %path.display(), "loaded IP blocklist"),
+            Err(e) => warn!(error = %e, "failed to load IP blocklist"),
+        }
+    }
+    if let Some(ref path) = config.ip_reputation.allowlist {
+        match ip_reputation.load_allowlist(path) {
+            Ok(count) => info!(count, path = %path.display(), "loaded IP allowlist"),
+            Err(e) => warn!(error = %e, "failed to load IP allowlist"),
+        }
+    }
+    if let Some(ref path) = config.ip_reputation.scores {
+        if let Err(e) = ip_reputation.load_scores(path) {
+            warn!(error = %e, "failed to load IP reputation scores");
+        }
+    }
+    ip_reputation
+}
+
+fn build_bot_detector(config: &AppConfig) -> Option<Arc<BotDetector>> {
+    if !config.bot_detection.enabled {
+        return None;
+    }
+    let (_, capacity, _) = KeyCapacity::from_state_limits(&config.state_limits, config.failure_policy);
+    info!(
+        mode = ?config.bot_detection.mode,
+        threshold = config.bot_detection.score_threshold,
+        "bot detection enabled"
+    );
+    Some(Arc::new(
+        BotDetector::new(config.bot_detection.clone()).with_session_capacity(capacity),
+    ))
+}
+
+fn build_anti_scraper(config: &AppConfig) -> Option<Arc<AntiScraper>> {
+    if !config.anti_scraping.enabled {
+        return None;
+    }
+    let (_, _, capacity) = KeyCapacity::from_state_limits(&config.state_limits, config.failure_policy);
+    info!(
+        mode = ?config.anti_scraping.mode,
+        threshold = config.anti_scraping.score_threshold,
+        "anti-scraping enabled"
+    );
+    Some(Arc::new(
+        AntiScraper::new(config.anti_scraping.clone()).with_session_capacity(capacity),
+    ))
+}
+
+fn build_geoip_filter(config: &AppConfig) -> anyhow::Result<Option<Arc<GeoIpFilter>>> {
+    if !config.geoip.enabled {
+        return Ok(None);
+    }
+    let filter = GeoIpFilter::new(config.geoip.clone())?;
+    info!(
+        mode = ?config.geoip.mode,
+        blocked_countries = ?config.geoip.blocked_countries,
+        allowed_countries = ?config.geoip.allowed_countries,
+        "GeoIP filtering enabled"
+    );
+    Ok(Some(Arc::new(filter)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn test_config() -> AppConfig {
+        serde_json::from_value(serde_json::json!({
+            "server": { "listen": ["127.0.0.1:8080"] },
+            "upstreams": [{ "name": "backend", "servers": [{ "addr": "127.0.0.1:8000" }] }],
+            "routes": [{ "path_prefix": "/", "upstream": "backend" }],
+            "waf": {},
+            "rate_limit": { "enabled": true, "default_rps": 1, "default_burst": 5 },
+            "geoip": { "enabled": true, "blocked_countries": ["RU"] }
+        }))
+        .unwrap()
+    }
+
+    fn reloader(config: AppConfig) -> ProxyReloader {
+        ProxyReloader {
+            components: Arc::new(ArcSwap::from_pointee(Components::build(&config))),
+            config: Arc::new(RwLock::new(config)),
+        }
+    }
+
+    #[test]
+    fn test_geoip_reload_keeps_rate_limit_state() {
+        let reloader = reloader(test_config());
+        let before = reloader.components.load_full();
+        let limiter = before.rate_limiter.clone().unwrap();
+        limiter.check("10.0.0.1");
+        limiter.check("10.0.0.1");
+
+        let mut config = test_config();
+        config.geoip.blocked_countries.push("CN".to_string());
+        reloader.reload(&config, &[Subsystem::GeoIp]).unwrap();
+
+        let after = reloader.components.load_full();
+        assert!(!Arc::ptr_eq(
+            before.geoip_filter.as_ref().unwrap(),
+            after.geoip_filter.as_ref().unwrap()
+        ));
+        let status = after.rate_limiter.as_ref().unwrap().key_status("10.0.0.1").unwrap();
+        assert_eq!(status.remaining_capacity, 3);
+        assert_eq!(reloader.config.read().unwrap().geoip.blocked_countries, ["RU", "CN"]);
+    }
+
+    #[test]
+    fn test_rate_limit_reload_resets_state() {
+        let reloader = reloader(test_config());
+        reloader.components.load().rate_limiter.as_ref().unwrap().check("10.0.0.1");
+
+        let mut config = test_config();
+        config.rate_limit.default_burst = 10;
+        reloader.reload(&config, &[Subsystem::RateLimit]).unwrap();
+
+        let limiters = reloader.rate_limiters();
+        assert_eq!(limiters.len(), 1);
+        assert_eq!(limiters[0].1.stats().configured_burst, 10);
+        assert!(!limiters[0].1.is_tracking("10.0.0.1"));
+    }
+
+    #[test]
+    fn test_rate_limit_reload_keeps_unchanged_limiters() {
+        let mut config = test_config();
+        config.routes[0].rate_limit =
+            Some(serde_json::from_value(serde_json::json!({ "rps": 1, "burst": 5 })).unwrap());
+        let reloader = reloader(config.clone());
+        let before = reloader.components.load_full();
+        before.rate_limiter.as_ref().unwrap().check("10.0.0.1");
+        before.route_rate_limiters[0].as_ref().unwrap().check("10.0.0.1");
+
+        // A route added ahead of the limited one, and a new connection limit
+        let api = serde_json::json!({ "path_prefix": "/api", "upstream": "backend" });
+        config.routes.insert(0, serde_json::from_value(api).unwrap());
+        config.rate_limit.connection_rps = Some(1);
+        reloader
+            .reload(&config, &[Subsystem::Routing, Subsystem::RateLimit])
+            .unwrap();
+
+        let after = reloader.components.load_full();
+        assert!(after.connection_limiter.is_some());
+        assert!(after.rate_limiter.as_ref().unwrap().is_tracking("10.0.0.1"));
+        assert!(after.route_rate_limiters[0].is_none());
+        assert!(after.route_rate_limiters[1].as_ref().unwrap().is_tracking("10.0.0.1"));
+    }
+
+    #[test]
+    fn test_logging_reload_swaps_only_logging() {
+        let reloader = reloader(test_config());
+        let before = reloader.components.load_full();
+        before.rate_limiter.as_ref().unwrap().check("10.0.0.1");
+        assert!(before.error_rate_alert.is_none());
+
+        let mut config = test_config();
+        config.server.error_rate_alert.enabled = true;
+        reloader.reload(&config, &[Subsystem::Logging]).unwrap();
+        let after = reloader.components.load_full();
+        assert!(after.error_rate_alert.is_some());
+        assert!(after.rate_limiter.as_ref().unwrap().is_tracking("10.0.0.1"));
+
+        // A capture file that can't be opened fails the reload instead
+        config.server.capture.enabled = true;
+        config.server.capture.path = Some("/nonexistent/capture.jsonl".into());
+        assert!(reloader.reload(&config, &[Subsystem::Logging]).is_err());
+        assert!(reloader.components.load().capture.is_none());
+    }
+
+    #[test]
+    fn test_connection_limit_independent_of_request_limit() {
+        let mut config = test_config();
+        config.rate_limit.connection_rps = Some(1);
+        config.rate_limit.connection_burst = 2;
+        let components = Components::build(&config);
+        let connections = components.connection_limiter.as_ref().unwrap();
+        let requests = components.rate_limiter.as_ref().unwrap();
+
+        // Exhausting the connection budget leaves the request budget intact
+        assert!(connections.check("10.0.0.1"));
+        assert!(connections.check("10.0.0.1"));
+        assert!(!connections.check("10.0.0.1"));
+        assert!(requests.key_status("10.0.0.1").is_none());
+
+        // And the other way round
+        for _ in 0..5 {
+            requests.check("10.0.0.2");
+        }
+        assert!(!requests.check("10.0.0.2"));
+        assert!(connections.check("10.0.0.2"));
+
+        let scopes: Vec<String> = components
+            .rate_limiters(&config)
+            .into_iter()
+            .map(|(scope, _)| scope)
+            .collect();
+        assert_eq!(scopes, ["global", "connections"]);
+    }
+
+    #[test]
+    fn test_health_probe_skips_rate_limit_unless_spoofed() {
+        let mut config = test_config();
+        config.health_probe = Some(
+            serde_json::from_value(serde_json::json!({
+                "path": "/healthz",
+                "user_agent": "kube-probe",
+                "source_cidrs": ["10.0.0.0/8"]
+            }))
+            .unwrap(),
+        );
+        let components = Components::build(&config);
+        let limiter = components.rate_limiter.as_ref().unwrap();
+        // What the request filter does: probes return before rate limiting
+        let mut headers = http::HeaderMap::new();
+        headers.insert("user-agent", "kube-probe/1.29".parse().unwrap());
+        let uri: http::Uri = "/healthz".parse().unwrap();
+        let handle = |peer: &str| {
+            let peer: IpAddr = peer.parse().unwrap();
+            let action =
+                components.health_probe_action(&http::Method::GET, &uri, &headers, Some(peer));
+            if action.is_none() {
+                limiter.check(&peer.to_string());
+            }
+            action
+        };
+
+        assert_eq!(handle("10.0.0.7"), Some(HealthProbeAction::Respond));
+        assert!(!limiter.is_tracking("10.0.0.7"));
+
+        assert_eq!(handle("203.0.113.9"), None);
+        assert!(limiter.is_tracking("203.0.113.9"));
+    }
+
+    #[test]
+    fn test_daily_quota_layered_on_request_limit() {
+        let mut config = test_config();
+        config.rate_limit.daily_quota = Some(2);
+        let components = Components::build(&config);
+        let quota = components.daily_quota_limiter.as_ref().unwrap();
+        let requests = components.rate_limiter.as_ref().unwrap();
+
+        // The quota runs out while the per-second budget still has room
+        assert!(quota.check("10.0.0.1") && requests.check("10.0.0.1"));
+        assert!(quota.check("10.0.0.1") && requests.check("10.0.0.1"));
+        assert!(!quota.check("10.0.0.1"));
+        assert!(requests.check("10.0.0.1"));
+
+        let scopes: Vec<String> = components
+            .rate_limiters(&config)
+            .into_iter()
+            .map(|(scope, _)| scope)
+            .collect();
+        assert_eq!(scopes, ["global", "daily_quota"]);
+
+        config.rate_limit.enabled = false;
+        assert!(Components::build(&config).daily_quota_limiter.is_none());
+    }
+
+    #[test]
+    fn test_daily_quota_kept_across_reloads_and_never_evicted() {
+        let mut config = test_config();
+        config.rate_limit.daily_quota = Some(1);
+        config.state_limits.max_rate_limit_keys = 1;
+        let reloader = reloader(config.clone());
+        let quota = reloader.components.load().daily_quota_limiter.clone().unwrap();
+        assert!(quota.check("10.0.0.1"));
+        assert!(!quota.check("10.0.0.1"));
+
+        // A new client at a full quota map doesn't push out the spent one
+        quota.check("10.0.0.2");
+        assert!(quota.is_tracking("10.0.0.1"));
+        assert!(!quota.check("10.0.0.1"));
+
+        config.rate_limit.default_rps += 1;
+        reloader.reload(&config, &[Subsystem::RateLimit]).unwrap();
+        let quota = reloader.components.load().daily_quota_limiter.clone().unwrap();
+        assert!(!quota.check("10.0.0.1"));
+
+        config.rate_limit.daily_quota = Some(2);
+        reloader.reload(&config, &[Subsystem::RateLimit]).unwrap();
+        let quota = reloader.components.load().daily_quota_limiter.clone().unwrap();
+        assert!(quota.check("10.0.0.1"));
+        assert!(!quota.check("10.0.0.1"));
+    }
+
+    #[test]
+    fn test_authenticated_request_uses_own_limit_and_key() {
+        let mut config = test_config();
+        config.rate_limit.authenticated = Some(
+            serde_json::from_value(serde_json::json!({
+                "cookie": "session_id",
+                "rps": 10,
+                "burst": 20
+            }))
+            .unwrap(),
+        );
+        let components = Components::build(&config);
+
+        let mut headers = http::HeaderMap::new();
+        headers.insert("cookie", "session_id=abc123".parse().unwrap());
+        let limiter = components.request_limiter(Some(0), &headers).unwrap();
+        let RequestLimiter::Authenticated { limiter: auth, .. } = &limiter else {
+            panic!("expected the authenticated limiter");
+        };
+        assert_eq!(auth.limiter.stats().configured_burst, 20);
+        for _ in 0..20 {
+            assert!(limiter.check("10.0.0.1"));
+        }
+        assert!(!limiter.check("10.0.0.1"));
+
+        // Anonymous requests from the same IP keep the default limit
+        let limiter = components.request_limiter(Some(0), &http::HeaderMap::new()).unwrap();
+        let RequestLimiter::Client(client) = limiter else {
+            panic!("expected the client limiter");
+        };
+        assert_eq!(client.stats().configured_burst, 5);
+        assert!(client.check("10.0.0.1"));
+
+        let scopes: Vec<String> = components
+            .rate_limiters(&config)
+            .into_iter()
+            .map(|(scope, _)| scope)
+            .collect();
+        assert_eq!(scopes, ["global", "authenticated", "authenticated_ip"]);
+    }
+
+    #[test]
+    fn test_all_servers_down_serves_unavailable_page() {
+        let mut config = test_config();
+        let second = serde_json::json!({ "addr": "127.0.0.1:8001" });
+        config.upstreams[0].servers.push(serde_json::from_value(second).unwrap());
+        config.server.unavailable_response.body = "<h1>Back soon</h1>".to_string();
+        config.validate().unwrap();
+        let components = Components::build(&config);
+
+        components.mark_upstream_down("backend", "127.0.0.1:8000");
+        assert_eq!(components.select_upstream("backend"), Some("127.0.0.1:8001"));
+        components.mark_upstream_down("backend", "127.0.0.1:8001");
+        assert_eq!(components.select_upstream("backend"), None);
+
+        let page = &config.server.unavailable_response;
+        assert_eq!(page.status, 503);
+        assert_eq!(page.content_type, "text/html; charset=utf-8");
+        assert_eq!(page.body, "<h1>Back soon</h1>");
+    }
+
+    #[test]
+    fn test_failed_reload_leaves_config_untouched() {
+        let reloader = reloader(test_config());
+        let mut config = test_config();
+        config.geoip.database_path = Some("/nonexistent/GeoLite2-Country.mmdb".into());
+
+        assert!(reloader.reload(&config, &[Subsystem::GeoIp]).is_err());
+        assert!(reloader.config.read().unwrap().geoip.database_path.is_none());
+    }
+
+    #[test]
+    fn test_under_attack_challenges_all_but_allowlisted() {
+        let allowlist = std::env::temp_dir()
+            .join(format!("layer7waf_under_attack_allow_{}", std::process::id()));
+        std::fs::write(&allowlist, "10.9.9.9\n").unwrap();
+        let mut config = test_config();
+        config.bot_detection.enabled = true;
+        config.ip_reputation.allowlist = Some(allowlist.clone());
+        let components = Components::build(&config);
+        std::fs::remove_file(&allowlist).unwrap();
+
+        let browser = vec![
+            ("Host".to_string(), "example.com".to_string()),
+            (
+                "User-Agent".to_string(),
+                "Mozilla/5.0 (X11; Linux x86_64) AppleWebKit/537.36 Chrome/120.0".to_string(),
+            ),
+            ("Accept".to_string(), "text/html,application/xhtml+xml".to_string()),
+            ("Accept-Language".to_string(), "en-US,en;q=0.9".to_string()),
+        ];
+        assert!(matches!(
+            components.under_attack_check("10.0.0.1", &browser, None),
+            BotCheckResult::Challenge(_)
+        ));
+        assert!(matches!(
+            components.under_attack_check("10.9.9.9", &browser, None),
+            BotCheckResult::Allow
+        ));
+    }
+
+    #[test]
+    fn test_allowlisted_ip_from_blocked_country() {
+        use layer7waf_common::GeoIpUnknownAction;
+        use layer7waf_geoip::GeoIpAction;
+
+        let allowlist =
+            std::env::temp_dir().join(format!("layer7waf_geo_allow_{}", std::process::id()));
+        std::fs::write(&allowlist, "10.9.9.9\n").unwrap();
+        let mut config = test_config();
+        config.ip_reputation.allowlist = Some(allowlist.clone());
+        // Without a database every country is unknown, and unknown is blocked
+        config.geoip.unknown_action = Some(GeoIpUnknownAction::Block);
+        let components = Components::build(&config);
+        std::fs::remove_file(&allowlist).unwrap();
+
+        let addr = "10.9.9.9".parse().unwrap();
+        assert!(components.ip_reputation.is_allowed(addr));
+        let verdict = |overrides: bool| {
+            components
+                .geoip_filter_for(true, components.ip_reputation.is_allowed(addr), overrides)
+                .map(|geoip| geoip.check(addr))
+        };
+
+        // The allowlist wins: GeoIP never runs
+        assert!(verdict(true).is_none());
+        // GeoIP still applies to the allowlisted IP
+        assert!(matches!(verdict(false), Some(GeoIpAction::Block { .. })));
+        // Other clients are checked either way
+        let other = components.geoip_filter_for(true, false, true).unwrap();
+        assert!(matches!(other.check("10.0.0.1".parse().unwrap()), GeoIpAction::Block { .. }));
+    }
+
+    #[test]
+    fn test_geoip_disabled_route_skips_lookup() {
+        let mut config = test_config();
+        config.routes[0].geoip.enabled = false;
+        let components = Components::build(&config);
+        let geoip = components.geoip_filter.clone().unwrap();
+        let addr = "10.0.0.1".parse().unwrap();
+
+        let route_geoip = config.routes[0].geoip.enabled;
+        for _ in 0..3 {
+            if let Some(geoip) = components.geoip_filter_for(route_geoip, false, false) {
+                geoip.check(addr);
+            }
+        }
+        assert_eq!(geoip.database_lookups(), 0);
+
+        // An enabled route looks the client up once, then uses the cache
+        for _ in 0..3 {
+            components.geoip_filter_for(true, false, false).unwrap().check(addr);
+        }
+        assert_eq!(geoip.database_lookups(), 1);
+    }
+
+    #[test]
+    fn test_blocklist_reload_starts_grace_period() {
+        use layer7waf_ip_reputation::IpAction;
+
+        let blocklist =
+            std::env::temp_dir().join(format!("layer7waf_grace_block_{}", std::process::id()));
+        std::fs::write(&blocklist, "10.0.0.1\n").unwrap();
+        let mut config = test_config();
+        config.ip_reputation.blocklist = Some(blocklist.clone());
+        config.ip_reputation.blocklist_grace_secs = 300;
+        let reloader = reloader(config.clone());
+
+        std::fs::write(&blocklist, "10.0.0.1\n10.0.0.2\n").unwrap();
+        reloader.reload(&config, &[Subsystem::IpReputation]).unwrap();
+        std::fs::remove_file(&blocklist).unwrap();
+
+        let ip_reputation = reloader.components.load().ip_reputation.clone();
+        assert_eq!(ip_reputation.check("10.0.0.1".parse().unwrap()), IpAction::Block);
+        assert_eq!(ip_reputation.check("10.0.0.2".parse().unwrap()), IpAction::WouldBlock);
+    }
+
+    #[test]
+    fn test_ip_reputation_reload_keeps_penalties_and_blocks() {
+        let mut config = test_config();
+        config.ip_reputation.waf_penalty.enabled = true;
+        config.ip_reputation.waf_penalty.threshold = 3;
+        let reloader = reloader(config.clone());
+
+        let blocked: IpAddr = "203.0.113.7".parse().unwrap();
+        let tripped: IpAddr = "203.0.113.8".parse().unwrap();
+        let ip_reputation = reloader.components.load().ip_reputation.clone();
+        for _ in 0..3 {
+            ip_reputation.record_waf_trip(blocked);
+        }
+        ip_reputation.record_waf_trip(tripped);
+        ip_reputation.record_waf_trip(tripped);
+
+        reloader.reload(&config, &[Subsystem::IpReputation]).unwrap();
+        let ip_reputation = reloader.components.load().ip_reputation.clone();
+        assert!(ip_reputation.is_blocked(blocked));
+        assert!(ip_reputation.record_waf_trip(tripped));
+    }
+
+    #[test]
+    fn test_flagged_sessions_merged_and_cleared() {
+        let mut config = test_config();
+        config.bot_detection.enabled = true;
+        config.anti_scraping.enabled = true;
+        let reloader = reloader(config);
+        let components = reloader.components.load_full();
+        let detector = components.bot_detector.as_ref().unwrap();
+        let scraper = components.anti_scraper.as_ref().unwrap();
+
+        let curl = vec![("User-Agent".to_string(), "curl/8.0".to_string())];
+        detector.check("10.0.0.1", &curl, "GET", "/", None);
+        let trap = "/.well-known/l7w-trap/x";
+        scraper.check_request("10.0.0.1", trap, "GET", None, 0.0, None);
+        scraper.check_request("10.0.0.2", trap, "GET", None, 0.0, None);
+
+        let flagged = reloader.flagged_sessions();
+        assert_eq!(flagged.len(), 2);
+        assert_eq!(flagged[0].ip, "10.0.0.1");
+        assert!(flagged[0].bot_score.is_some() && flagged[0].scraping_score.is_some());
+        assert_eq!(flagged[1].ip, "10.0.0.2");
+        assert!(flagged[1].bot_score.is_none());
+
+        assert_eq!(reloader.clear_sessions(Some("10.0.0.1")), 2);
+        assert_eq!(reloader.flagged_sessions().len(), 1);
+        assert_eq!(reloader.clear_sessions(None), 1);
+        assert!(reloader.flagged_sessions().is_empty());
+    }
+
+    #[test]
+    fn test_map_footprints_count_tracked_keys() {
+        let mut config = test_config();
+        config.bot_detection.enabled = true;
+        config.anti_scraping.enabled = true;
+        let reloader = reloader(config);
+        let components = reloader.components.load_full();
+
+        let limiter = components.rate_limiter.as_ref().unwrap();
+        for ip in ["10.0.0.1", "10.0.0.2", "10.0.0.3"] {
+            limiter.check(ip);
+        }
+        let curl = vec![("User-Agent".to_string(), "curl/8.0".to_string())];
+        components.bot_detector.as_ref().unwrap().check("10.0.0.1", &curl, "GET", "/", None);
+        let scraper = components.anti_scraper.as_ref().unwrap();
+        scraper.check_request("10.0.0.1", "/a", "GET", None, 0.0, None);
+        scraper.check_request("10.0.0.2", "/b", "GET", None, 0.0, None);
+
+        let maps = reloader.map_footprints();
+        let entries = |name: &str| maps.iter().find(|m| m.name == name).map(|m| m.entries);
+        assert_eq!(entries("rate_limit:global"), Some(3));
+        assert_eq!(entries("bot_sessions"), Some(1));
+        assert_eq!(entries("scraping_sessions"), Some(2));
+        assert!(maps.iter().all(|m| (m.entries == 0) == (m.approx_bytes == 0)));
+    }
+
+    #[test]
+    fn test_watermark_identified_through_reloader() {
+        let mut config = test_config();
+        config.anti_scraping.enabled = true;
+        config.anti_scraping.obfuscation.enabled = true;
+        let reloader = reloader(config);
+        let scraper = reloader.components.load().anti_scraper.clone().unwrap();
+        let body = b"<html><body><p>Catalogue</p></body></html>";
+        let served = scraper
+            .process_response("10.0.0.7", Some("text/html"), body, None)
+            .unwrap();
+
+        let identified = reloader
+            .identify_watermark(std::str::from_utf8(&served).unwrap())
+            .unwrap();
+        assert_eq!(identified.ip.as_deref(), Some("10.0.0.7"));
+        assert!(reloader.identify_watermark("Catalogue").is_none());
+    }
+
+    #[test]
+    fn test_stricter_ruleset_engine_blocks() {
+        use layer7waf_coraza::{WafAction, WafTransaction};
+
+        let dir = std::env::temp_dir().join(format!("layer7waf_rulesets_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let base = dir.join("base.conf");
+        let strict = dir.join("strict.conf");
+        std::fs::write(&base, "SecRule ARGS \"@contains evil\" \"id:9200,phase:1,deny,status:403\"\n")
+            .unwrap();
+        std::fs::write(&strict, "SecRule ARGS \"@contains probe\" \"id:9201,phase:1,deny,status:403\"\n")
+            .unwrap();
+
+        let mut config = test_config();
+        config.waf.rules = vec![base.display().to_string()];
+        config.waf.rulesets.insert(
+            "strict".to_string(),
+            vec![base.display().to_string(), strict.display().to_string()],
+        );
+        config.routes[0].waf.ruleset = Some("strict".to_string());
+        config.validate().unwrap();
+        let components = Components::build(&config);
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        let check = |ruleset: Option<&str>, uri: &str| {
+            let engine = components.waf_engine_for(ruleset).unwrap();
+            WafTransaction::new(engine).unwrap().process_request_headers(
+                "GET",
+                uri,
+                "HTTP/1.1",
+                &[],
+            )
+        };
+        assert_eq!(check(None, "/?q=probe"), WafAction::Pass);
+        assert_eq!(check(Some("strict"), "/?q=probe"), WafAction::Block { status: 403 });
+        assert_eq!(check(None, "/?q=evil"), WafAction::Block { status: 403 });
+        assert_eq!(check(Some("strict"), "/?q=evil"), WafAction::Block { status: 403 });
+    }
+
+    #[test]
+    fn test_broken_ruleset_falls_back_to_default_engine() {
+        use layer7waf_coraza::{WafAction, WafTransaction};
+
+        let dir = std::env::temp_dir().join(format!("layer7waf_broken_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let base = dir.join("base.conf");
+        let broken = dir.join("broken.conf");
+        std::fs::write(&base, "SecRule ARGS \"@contains evil\" \"id:9202,phase:1,deny,status:403\"\n")
+            .unwrap();
+        std::fs::write(&broken, "SecRule ARGS \"@nosuchoperator x\" \"id:9203\"\n").unwrap();
+
+        let mut config = test_config();
+        config.waf.rules = vec![base.display().to_string()];
+        config.waf.rulesets.insert("strict".to_string(), vec![broken.display().to_string()]);
+        config.routes[0].waf.ruleset = Some("strict".to_string());
+        let components = Components::build(&config);
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        assert!(components.ruleset_engines.is_empty());
+        let engine = components.waf_engine_for(Some("strict")).unwrap();
+        let action = WafTransaction::new(engine).unwrap().process_request_headers(
+            "GET",
+            "/?q=evil",
+            "HTTP/1.1",
+            &[],
+        );
+        assert_eq!(action, WafAction::Block { status: 403 });
+    }
+
+    #[test]
+    fn test_waf_reload_keeps_body_budget() {
+        let reloader = reloader(test_config());
+        let budget = reloader.components.load().waf_body_budget.clone();
+
+        let mut config = test_config();
+        config.waf.request_body_limit += 1;
+        reloader.reload(&config, &[Subsystem::Waf]).unwrap();
+        assert!(Arc::ptr_eq(&reloader.components.load().waf_body_budget, &budget));
+
+        config.waf.body_budget_bytes += 1;
+        reloader.reload(&config, &[Subsystem::Waf]).unwrap();
+        assert!(!Arc::ptr_eq(&reloader.components.load().waf_body_budget, &budget));
+    }
+
+    #[test]
+    fn test_custom_rule_engine_blocks() {
+        use layer7waf_coraza::{WafAction, WafTransaction};
+
+        let reloader = reloader(test_config());
+        assert!(reloader.components.load().waf_engine.is_none());
+
+        let rule = r#"SecRule ARGS "@contains evil" "id:9100,phase:1,deny,status:403""#;
+        reloader.apply_custom_rules(&[rule.to_string()]).unwrap();
+
+        let components = reloader.components.load_full();
+        let engine = components.waf_engine.as_ref().expect("engine built from custom rules");
+        let tx = WafTransaction::new(engine).unwrap();
+        let action = tx.process_request_headers("GET", "/?q=evil", "HTTP/1.1", &[]);
+        assert_eq!(action, WafAction::Block { status: 403 });
+
+        // A later WAF reload keeps the custom rules
+        let mut config = test_config();
+        config.waf.request_body_limit += 1;
+        reloader.reload(&config, &[Subsystem::Waf]).unwrap();
+        let components = reloader.components.load_full();
+        let tx = WafTransaction::new(components.waf_engine.as_ref().unwrap()).unwrap();
+        let action = tx.process_request_headers("GET", "/?q=evil", "HTTP/1.1", &[]);
+        assert_eq!(action, WafAction::Block { status: 403 });
+    }
+}
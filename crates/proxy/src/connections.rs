@@ -0,0 +1,231 @@
+use dashmap::DashMap;
+use layer7waf_rate_limit::RateLimiter;
+use prometheus::{IntCounter, IntGauge, Registry};
+use std::sync::Arc;
+
+/// Connection-level metrics.
+#[derive(Clone)]
+pub struct ConnectionMetrics {
+    pub active: IntGauge,
+    pub accepted: IntCounter,
+    pub reused: IntCounter,
+}
+
+impl ConnectionMetrics {
+    pub fn new() -> Self {
+        Self {
+            active: IntGauge::new(
+                "layer7waf_connections_active",
+                "Currently open downstream connections",
+            )
+            .unwrap(),
+            accepted: IntCounter::new(
+                "layer7waf_connections_accepted_total",
+                "Total downstream connections accepted",
+            )
+            .unwrap(),
+            reused: IntCounter::new(
+                "layer7waf_connections_reused_total",
+                "Requests served on a kept-alive downstream connection",
+            )
+            .unwrap(),
+        }
+    }
+
+    /// Register the metrics with `registry`. May be called for several
+    /// registries; they all observe the same values.
+    pub fn register(&self, registry: &Registry) -> prometheus::Result<()> {
+        registry.register(Box::new(self.active.clone()))?;
+        registry.register(Box::new(self.accepted.clone()))?;
+        registry.register(Box::new(self.reused.clone()))?;
+        Ok(())
+    }
+}
+
+impl Default for ConnectionMetrics {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Requests seen on the open connections from one peer address.
+#[derive(Debug, Default)]
+struct PeerConnections {
+    open: usize,
+    requests: u64,
+}
+
+/// Tracks open downstream connections by their peer address (`ip:port`),
+/// which identifies a connection for as long as it stays open.
+///
+/// Pingora doesn't expose accept/close hooks to `ProxyHttp`, so the listener
+/// wrapper in `main.rs` calls [`accept`](Self::accept) for each accepted
+/// connection and holds the returned guard until the connection closes.
+/// Requests then look their connection up by peer address to tell the first
+/// request on it from keepalive reuse.
+#[derive(Clone)]
+pub struct ConnectionTracker {
+    open: Arc<DashMap<String, PeerConnections>>,
+    pub metrics: ConnectionMetrics,
+}
+
+/// An open downstream connection, counted as closed when dropped.
+pub struct OpenConnection {
+    tracker: ConnectionTracker,
+    peer: String,
+}
+
+impl Drop for OpenConnection {
+    fn drop(&mut self) {
+        self.tracker
+            .open
+            .remove_if_mut(&self.peer, |_, connections| {
+                connections.open -= 1;
+                connections.open == 0
+            });
+        self.tracker.metrics.active.dec();
+    }
+}
+
+impl ConnectionTracker {
+    pub fn new() -> Self {
+        Self {
+            open: Arc::new(DashMap::new()),
+            metrics: ConnectionMetrics::new(),
+        }
+    }
+
+    /// A connection from `peer` was accepted. It stays open until the
+    /// returned guard is dropped.
+    pub fn accept(&self, peer: &str) -> OpenConnection {
+        self.open.entry(peer.to_string()).or_default().open += 1;
+        self.metrics.accepted.inc();
+        self.metrics.active.inc();
+        OpenConnection {
+            tracker: self.clone(),
+            peer: peer.to_string(),
+        }
+    }
+
+    /// A request started on `peer`'s connection. Returns `true` if earlier
+    /// requests were served on it (keepalive reuse). A peer with no accepted
+    /// connection is treated as a new one.
+    pub fn request_started(&self, peer: &str) -> bool {
+        let reused = self.open.get_mut(peer).is_some_and(|mut connections| {
+            connections.requests += 1;
+            connections.requests > 1
+        });
+        if reused {
+            self.metrics.reused.inc();
+        }
+        reused
+    }
+}
+
+impl Default for ConnectionTracker {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Whether a request on a connection from `client_key` may proceed under
+/// the connection-rate `limiter`. Only the first request on a connection
+/// takes a token; requests on a kept-alive connection always pass.
+pub fn connection_admitted(
+    limiter: Option<&RateLimiter>,
+    client_key: &str,
+    new_connection: bool,
+) -> bool {
+    match limiter {
+        Some(limiter) if new_connection => limiter.check(client_key),
+        _ => true,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_connection_limit_counts_new_connections_only() {
+        let limiter = RateLimiter::new_token_bucket(1, 2);
+
+        assert!(connection_admitted(Some(&limiter), "10.0.0.1", true));
+        assert!(connection_admitted(Some(&limiter), "10.0.0.1", true));
+        assert!(!connection_admitted(Some(&limiter), "10.0.0.1", true));
+
+        // Keepalive requests and other clients are unaffected
+        assert!(connection_admitted(Some(&limiter), "10.0.0.1", false));
+        assert!(connection_admitted(Some(&limiter), "10.0.0.2", true));
+        assert!(connection_admitted(None, "10.0.0.1", true));
+    }
+
+    #[test]
+    fn test_session_lifecycle_counters() {
+        let tracker = ConnectionTracker::new();
+        let m = &tracker.metrics;
+
+        // Accepted connections count before any request arrives
+        let first = tracker.accept("10.0.0.1:5000");
+        let second = tracker.accept("10.0.0.2:6000");
+        assert_eq!(m.active.get(), 2);
+
+        // Two requests on one kept-alive connection, then it closes.
+        assert!(!tracker.request_started("10.0.0.1:5000"));
+        assert!(tracker.request_started("10.0.0.1:5000"));
+        drop(first);
+        assert_eq!(m.active.get(), 1);
+
+        // A connection closed without sending a request
+        drop(second);
+        assert_eq!(m.accepted.get(), 2);
+        assert_eq!(m.reused.get(), 1);
+        assert_eq!(m.active.get(), 0);
+        assert!(tracker.open.is_empty());
+    }
+
+    #[test]
+    fn test_shared_peer_address_closed_per_connection() {
+        let tracker = ConnectionTracker::new();
+        let first = tracker.accept("10.0.0.1:5000");
+        let second = tracker.accept("10.0.0.1:5000");
+        drop(first);
+        assert_eq!(tracker.open.get("10.0.0.1:5000").unwrap().open, 1);
+        assert_eq!(tracker.metrics.active.get(), 1);
+        drop(second);
+        assert!(tracker.open.is_empty());
+
+        // A request on a connection that was never accepted is a new one
+        assert!(!tracker.request_started("10.0.0.1:5000"));
+        assert!(!tracker.request_started("10.0.0.1:5000"));
+    }
+
+    #[test]
+    fn test_registered_in_multiple_registries() {
+        let tracker = ConnectionTracker::new();
+        let proxy_registry = Registry::new();
+        let admin_registry = Registry::new();
+        tracker.metrics.register(&proxy_registry).unwrap();
+        tracker.metrics.register(&admin_registry).unwrap();
+
+        let _connection = tracker.accept("10.0.0.1:5000");
+
+        let names: Vec<String> = admin_registry
+            .gather()
+            .iter()
+            .map(|f| f.get_name().to_string())
+            .collect();
+        assert!(names.contains(&"layer7waf_connections_active".to_string()));
+        assert_eq!(
+            admin_registry
+                .gather()
+                .iter()
+                .find(|f| f.get_name() == "layer7waf_connections_accepted_total")
+                .unwrap()
+                .get_metric()[0]
+                .get_counter()
+                .get_value(),
+            1.0
+        );
+    }
+}
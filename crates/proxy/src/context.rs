@@ -1,5 +1,12 @@
 use layer7waf_coraza::WafTransaction;
+use layer7waf_geoip::GeoBlockReason;
+
+use crate::body_gate::BodyGate;
+use crate::body_scan::BodyScan;
+use crate::load_shed::InFlightGuard;
+use chrono::{DateTime, Utc};
 use std::time::Instant;
+use tracing::Span;
 
 /// Per-request context carried through the Pingora proxy pipeline.
 pub struct RequestContext {
@@ -9,12 +16,38 @@ pub struct RequestContext {
     /// Matched route index (into the config's routes vec).
     pub route_index: Option<usize>,
 
-    /// Client IP address string.
+    /// Name of the upstream the request was sent to, once one is selected.
+    pub upstream: Option<String>,
+
+    /// Address of the upstream server selected for the request.
+    pub upstream_addr: Option<String>,
+
+    /// Whether no server of the request's upstream was available, so the
+    /// `server.unavailable_response` page is served.
+    pub upstream_unavailable: bool,
+
+    /// Client IP address string. Empty when the IP could not be determined.
     pub client_ip: String,
 
+    /// Whether the client IP is on the IP allowlist.
+    pub ip_allowlisted: bool,
+
+    /// Whether the request is a configured health probe, kept out of the
+    /// security checks, metrics and access log.
+    pub health_probe: bool,
+
     /// Request start time for latency measurement.
     pub request_start: Instant,
 
+    /// Wall-clock time the request arrived, for the access log.
+    pub received_at: DateTime<Utc>,
+
+    /// When the request times out, if `server.request_timeout_ms` is set.
+    pub deadline: Option<Instant>,
+
+    /// Whether the request was answered with 504 for exceeding its deadline.
+    pub timed_out: bool,
+
     /// Whether the request was blocked (and by what).
     pub block_reason: Option<BlockReason>,
 
@@ -24,15 +57,29 @@ pub struct RequestContext {
     /// Request URI (cached for logging).
     pub uri: String,
 
+    /// HTTP version of the request line, e.g. `HTTP/1.1` (cached for logging).
+    pub protocol: String,
+
+    /// Referer and User-Agent headers (cached for logging).
+    pub referer: Option<String>,
+    pub user_agent: Option<String>,
+
     /// Response status code (set during response phase).
     pub response_status: u16,
 
+    /// Response body bytes sent to the client (set when logging).
+    pub response_bytes: u64,
+
     /// Bot detection score (set during request phase).
     pub bot_score: Option<f64>,
 
     /// Anti-scraping score (set during request phase).
     pub scraping_score: Option<f64>,
 
+    /// Score of the client IP in the `ip_reputation.scores` feed, if listed
+    /// there and on neither IP list.
+    pub ip_reputation_score: Option<f64>,
+
     /// Whether the request hit a honeypot trap.
     pub is_trap_request: bool,
 
@@ -47,17 +94,48 @@ pub struct RequestContext {
 
     /// Buffer for collecting response body chunks for rewriting.
     pub response_body_buffer: Vec<u8>,
+
+    /// Span covering the whole request; phase spans are its children.
+    pub span: Span,
+
+    /// Keeps the request counted as in flight until the context is dropped.
+    pub in_flight: Option<InFlightGuard>,
+
+    /// Holds back request body reads until the header-phase checks pass.
+    pub body_gate: BodyGate,
+
+    /// Holds the request body from the upstream until the WAF scanned it.
+    pub body_scan: BodyScan,
 }
 
 #[derive(Debug, Clone)]
 pub enum BlockReason {
     Waf { status: u16 },
     RateLimit,
+    /// Client opened connections faster than `rate_limit.connection_rps`.
+    ConnectionRateLimit,
+    /// Client used up its `rate_limit.daily_quota` for the UTC day.
+    DailyQuota,
     IpBlocked,
     BotDetected { score: f64 },
     ScraperDetected { score: f64 },
     HoneypotTriggered,
-    GeoBlocked { country: String },
+    GeoBlocked { country: String, reason: GeoBlockReason },
+    UnknownClientIp,
+    /// `X-Forwarded-For` longer than `server.max_forwarded_hops`.
+    TooManyForwardedHops,
+    /// URI still percent-encoded after `waf.decode_depth` decoding passes.
+    OverEncodedUri,
+    /// Ambiguous `Content-Length`/`Transfer-Encoding` under `server.strict_framing`.
+    AmbiguousFraming,
+    /// The WAF couldn't create a transaction and `failure_policy` is `block`.
+    WafUnavailable,
+    /// Shed because too many requests were in flight.
+    Overloaded,
+    /// State-changing request whose Origin/Referer isn't allowed.
+    CrossOrigin,
+    /// Weighted subsystem scores reached `security.aggregation.threshold`.
+    RiskScore { score: f64 },
 }
 
 impl RequestContext {
@@ -65,19 +143,81 @@ impl RequestContext {
         Self {
             waf_tx: None,
             route_index: None,
+            upstream: None,
+            upstream_addr: None,
+            upstream_unavailable: false,
             client_ip: String::new(),
+            ip_allowlisted: false,
+            health_probe: false,
             request_start: Instant::now(),
+            received_at: Utc::now(),
+            deadline: None,
+            timed_out: false,
             block_reason: None,
             method: String::new(),
             uri: String::new(),
+            protocol: String::new(),
+            referer: None,
+            user_agent: None,
             response_status: 0,
+            response_bytes: 0,
             bot_score: None,
             scraping_score: None,
+            ip_reputation_score: None,
             geo_country: None,
             is_trap_request: false,
             should_process_response: false,
             response_content_type: None,
             response_body_buffer: Vec::new(),
+            span: Span::none(),
+            in_flight: None,
+            body_gate: BodyGate::new(),
+            body_scan: BodyScan::new(),
+        }
+    }
+
+    /// Key for per-client state (rate limits, bot and scraping sessions), or
+    /// `None` when the client IP is unknown. Unknown clients must never share
+    /// a bucket keyed on the empty string.
+    pub fn client_key(&self) -> Option<&str> {
+        if self.client_ip.is_empty() {
+            None
+        } else {
+            Some(&self.client_ip)
+        }
+    }
+
+    /// Action recorded in the audit log for this request: its block, or,
+    /// with `log_allowlisted`, an allowlisted client skipping the checks.
+    /// `None` for requests that aren't audited.
+    pub fn audit_action(&self, log_allowlisted: bool) -> Option<String> {
+        match &self.block_reason {
+            Some(reason) => Some(format!("blocked: {:?}", reason)),
+            None if self.ip_allowlisted && log_allowlisted => Some("allowlisted".to_string()),
+            None => None,
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_allowlisted_bypass_audited() {
+        let mut ctx = RequestContext::new();
+        ctx.client_ip = "10.0.0.5".to_string();
+        ctx.ip_allowlisted = true;
+        assert_eq!(ctx.audit_action(true).as_deref(), Some("allowlisted"));
+        assert_eq!(ctx.audit_action(false), None);
+
+        // A block from before the allowlist check is audited as the block
+        ctx.block_reason = Some(BlockReason::Overloaded);
+        assert_eq!(ctx.audit_action(true).as_deref(), Some("blocked: Overloaded"));
+    }
+
+    #[test]
+    fn test_passed_request_not_audited() {
+        assert_eq!(RequestContext::new().audit_action(true), None);
+    }
+}
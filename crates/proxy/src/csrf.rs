@@ -0,0 +1,144 @@
+use http::Uri;
+use layer7waf_common::{CsrfRule, NormalizedPath};
+
+/// Whether a request passes the `csrf_protection` rules.
+///
+/// The first rule whose `path_prefix` covers the normalized `path` applies.
+/// If it covers `method`, the scheme, host and port of `origin` (or of
+/// `referer` when there is no `Origin`) must equal one of its allowed
+/// origins; a request with neither header, or with `Origin: null`, is
+/// rejected.
+pub fn origin_allowed(
+    rules: &[CsrfRule],
+    method: &str,
+    path: &str,
+    origin: Option<&str>,
+    referer: Option<&str>,
+) -> bool {
+    let path = NormalizedPath::new(path);
+    let Some(rule) = rules.iter().find(|r| path.has_prefix(&r.path_prefix)) else {
+        return true;
+    };
+    if !rule.methods.iter().any(|m| m.eq_ignore_ascii_case(method)) {
+        return true;
+    }
+    let Some(source) = origin.or(referer) else {
+        return false;
+    };
+    let Some(origin) = Origin::parse(source.trim()) else {
+        return false;
+    };
+
+    rule.allowed_origins.iter().any(|allowed| {
+        let allowed = if allowed.contains("://") {
+            Origin::parse(allowed)
+        } else {
+            Origin::parse(&format!("https://{}", allowed))
+        };
+        allowed.as_ref() == Some(&origin)
+    })
+}
+
+/// The scheme, host and port a browser compares for same-origin checks,
+/// with the port defaulted from the scheme.
+#[derive(Debug, PartialEq, Eq)]
+struct Origin {
+    https: bool,
+    host: String,
+    port: u16,
+}
+
+impl Origin {
+    fn parse(s: &str) -> Option<Self> {
+        let uri = s.parse::<Uri>().ok()?;
+        let https = match uri.scheme_str()?.to_ascii_lowercase().as_str() {
+            "https" => true,
+            "http" => false,
+            _ => return None,
+        };
+        let authority = uri.authority()?;
+        Some(Self {
+            https,
+            host: authority.host().to_ascii_lowercase(),
+            port: authority.port_u16().unwrap_or(if https { 443 } else { 80 }),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rules() -> Vec<CsrfRule> {
+        vec![CsrfRule {
+            path_prefix: "/account".to_string(),
+            allowed_origins: vec!["shop.example.com".to_string(), "localhost:8443".to_string()],
+            methods: vec!["POST".to_string(), "PUT".to_string(), "DELETE".to_string()],
+        }]
+    }
+
+    #[test]
+    fn test_foreign_origin_blocked() {
+        let rules = rules();
+        let evil = Some("https://evil.example.net");
+        assert!(!origin_allowed(&rules, "POST", "/account/email", evil, None));
+        assert!(!origin_allowed(&rules, "delete", "/account", evil, None));
+        // Origin wins over a matching Referer
+        let referer = Some("https://shop.example.com/account");
+        assert!(!origin_allowed(&rules, "POST", "/account", evil, referer));
+    }
+
+    #[test]
+    fn test_same_origin_passes() {
+        let rules = rules();
+        let origin = Some("https://Shop.Example.com");
+        assert!(origin_allowed(&rules, "POST", "/account/email", origin, None));
+        let referer = Some("https://shop.example.com/account/settings?tab=1");
+        assert!(origin_allowed(&rules, "PUT", "/account", None, referer));
+        assert!(origin_allowed(&rules, "POST", "/account", Some("https://localhost:8443"), None));
+        assert!(!origin_allowed(&rules, "POST", "/account", Some("https://localhost:9000"), None));
+    }
+
+    #[test]
+    fn test_origin_scheme_and_port_compared() {
+        let mut rules = rules();
+        // Entries without a scheme are https origins
+        let plain = Some("http://shop.example.com");
+        assert!(!origin_allowed(&rules, "POST", "/account", plain, None));
+        assert!(!origin_allowed(&rules, "POST", "/account", Some("http://localhost:8443"), None));
+        let default_port = Some("https://shop.example.com:443");
+        assert!(origin_allowed(&rules, "POST", "/account", default_port, None));
+        let other_port = Some("https://shop.example.com:8443");
+        assert!(!origin_allowed(&rules, "POST", "/account", other_port, None));
+
+        rules[0].allowed_origins = vec!["http://dev.example.com".to_string()];
+        let dev = Some("http://dev.example.com:80");
+        assert!(origin_allowed(&rules, "POST", "/account", dev, None));
+        let dev_https = Some("https://dev.example.com");
+        assert!(!origin_allowed(&rules, "POST", "/account", dev_https, None));
+    }
+
+    #[test]
+    fn test_respelled_path_still_protected() {
+        let rules = rules();
+        let evil = Some("https://evil.example.net");
+        for path in ["//account", "/%61ccount/email", "/Account", "/static/../account"] {
+            assert!(!origin_allowed(&rules, "POST", path, evil, None), "{}", path);
+        }
+        assert!(origin_allowed(&rules, "POST", "/accounts", evil, None));
+    }
+
+    #[test]
+    fn test_missing_or_null_origin_blocked() {
+        let rules = rules();
+        assert!(!origin_allowed(&rules, "POST", "/account", None, None));
+        assert!(!origin_allowed(&rules, "POST", "/account", Some("null"), None));
+    }
+
+    #[test]
+    fn test_unprotected_requests_pass() {
+        let rules = rules();
+        assert!(origin_allowed(&rules, "GET", "/account", None, None));
+        assert!(origin_allowed(&rules, "POST", "/search", Some("https://evil.example.net"), None));
+    }
+}
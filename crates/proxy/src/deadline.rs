@@ -0,0 +1,65 @@
+use std::future::Future;
+use std::time::{Duration, Instant};
+
+/// The request's deadline passed before processing finished.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DeadlineExceeded;
+
+/// The absolute deadline for a request that started at `start`, or `None`
+/// when no request timeout is configured.
+pub fn deadline_after(start: Instant, timeout_ms: Option<u64>) -> Option<Instant> {
+    timeout_ms.map(|ms| start + Duration::from_millis(ms))
+}
+
+/// Time left before `deadline`; zero once it has passed. `None` means the
+/// request has no deadline.
+pub fn remaining(deadline: Option<Instant>) -> Option<Duration> {
+    deadline.map(|d| d.saturating_duration_since(Instant::now()))
+}
+
+/// Run `fut` to completion, giving up once `deadline` passes.
+pub async fn with_deadline<F: Future>(
+    deadline: Option<Instant>,
+    fut: F,
+) -> Result<F::Output, DeadlineExceeded> {
+    match deadline {
+        Some(at) => tokio::time::timeout_at(at.into(), fut)
+            .await
+            .map_err(|_| DeadlineExceeded),
+        None => Ok(fut.await),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn slow_stage(delay: Duration) -> &'static str {
+        tokio::time::sleep(delay).await;
+        "done"
+    }
+
+    #[tokio::test]
+    async fn test_slow_stage_exceeds_deadline() {
+        let deadline = deadline_after(Instant::now(), Some(20));
+        let result = with_deadline(deadline, slow_stage(Duration::from_secs(5))).await;
+        assert_eq!(result, Err(DeadlineExceeded));
+        assert_eq!(remaining(deadline), Some(Duration::ZERO));
+    }
+
+    #[tokio::test]
+    async fn test_fast_stage_within_deadline() {
+        let deadline = deadline_after(Instant::now(), Some(5_000));
+        let result = with_deadline(deadline, slow_stage(Duration::from_millis(1))).await;
+        assert_eq!(result, Ok("done"));
+        assert!(remaining(deadline).unwrap() > Duration::ZERO);
+    }
+
+    #[tokio::test]
+    async fn test_no_deadline_waits_for_completion() {
+        assert_eq!(deadline_after(Instant::now(), None), None);
+        let result = with_deadline(None, slow_stage(Duration::from_millis(30))).await;
+        assert_eq!(result, Ok("done"));
+        assert_eq!(remaining(None), None);
+    }
+}
@@ -0,0 +1,97 @@
+use layer7waf_common::{DecisionMode, SecurityConfig, SignalWeights};
+
+/// Sub-scores, each 0.0 (clean) to 1.0 (certain), that IP reputation,
+/// GeoIP, bot detection and anti-scraping gave a request.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct RiskSignals {
+    pub ip_reputation: f64,
+    pub geoip: f64,
+    pub bot: f64,
+    pub scraping: f64,
+    /// Whether any of the subsystems would have blocked the request on
+    /// its own.
+    pub subsystem_blocked: bool,
+}
+
+impl RiskSignals {
+    /// Weighted sum of the sub-scores.
+    pub fn aggregate_score(&self, weights: &SignalWeights) -> f64 {
+        self.ip_reputation.clamp(0.0, 1.0) * weights.ip_reputation
+            + self.geoip.clamp(0.0, 1.0) * weights.geoip
+            + self.bot.clamp(0.0, 1.0) * weights.bot
+            + self.scraping.clamp(0.0, 1.0) * weights.scraping
+    }
+
+    /// Whether the request is blocked under `security.decision_mode`:
+    /// when any subsystem blocked it, or when the aggregate score reaches
+    /// the threshold.
+    pub fn blocks(&self, security: &SecurityConfig) -> bool {
+        match security.decision_mode {
+            DecisionMode::ShortCircuit => self.subsystem_blocked,
+            DecisionMode::Aggregate => {
+                self.aggregate_score(&security.aggregation.weights)
+                    >= security.aggregation.threshold
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn security(decision_mode: DecisionMode) -> SecurityConfig {
+        SecurityConfig {
+            decision_mode,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_weak_signals_combine_only_when_aggregated() {
+        // Bot 0.6 and scraping 0.5 are under the default 0.7 and 0.6
+        // thresholds, so neither subsystem blocks by itself
+        let signals = RiskSignals {
+            bot: 0.6,
+            scraping: 0.5,
+            ..Default::default()
+        };
+        assert!(!signals.blocks(&security(DecisionMode::ShortCircuit)));
+        assert!(signals.blocks(&security(DecisionMode::Aggregate)));
+    }
+
+    #[test]
+    fn test_single_weak_signal_passes_aggregation() {
+        let signals = RiskSignals {
+            bot: 0.9,
+            ..Default::default()
+        };
+        assert!(!signals.blocks(&security(DecisionMode::Aggregate)));
+
+        // A blocklisted IP is a full-strength signal and blocks alone
+        let signals = RiskSignals {
+            ip_reputation: 1.0,
+            subsystem_blocked: true,
+            ..Default::default()
+        };
+        assert!(signals.blocks(&security(DecisionMode::Aggregate)));
+        assert!(signals.blocks(&security(DecisionMode::ShortCircuit)));
+    }
+
+    #[test]
+    fn test_weights_scale_sub_scores() {
+        let mut security = security(DecisionMode::Aggregate);
+        security.aggregation.weights.geoip = 0.5;
+        let signals = RiskSignals {
+            geoip: 1.0,
+            bot: 0.4,
+            ..Default::default()
+        };
+        assert!((signals.aggregate_score(&security.aggregation.weights) - 0.9).abs() < 1e-9);
+        assert!(!signals.blocks(&security));
+
+        security.aggregation.weights.geoip = 0.0;
+        security.aggregation.threshold = 0.4;
+        assert!(signals.blocks(&security));
+    }
+}
@@ -0,0 +1,116 @@
+use std::sync::Arc;
+
+use layer7waf_admin::reload::ConfigReloader;
+use prometheus::core::{Collector, Desc};
+use prometheus::proto::MetricFamily;
+use prometheus::{IntGaugeVec, Opts, Registry};
+
+/// Exports the size of every per-key map as gauges, read from the live
+/// components on each scrape so reloads are picked up.
+pub struct MapFootprintCollector {
+    reloader: Arc<dyn ConfigReloader>,
+    entries: IntGaugeVec,
+    approx_bytes: IntGaugeVec,
+}
+
+impl MapFootprintCollector {
+    pub fn new(reloader: Arc<dyn ConfigReloader>) -> Self {
+        Self {
+            reloader,
+            entries: IntGaugeVec::new(
+                Opts::new("layer7waf_map_entries", "Entries in each per-key map"),
+                &["map"],
+            )
+            .unwrap(),
+            approx_bytes: IntGaugeVec::new(
+                Opts::new(
+                    "layer7waf_map_approx_bytes",
+                    "Approximate memory used by each per-key map",
+                ),
+                &["map"],
+            )
+            .unwrap(),
+        }
+    }
+
+    /// Register the gauges with `registry`.
+    pub fn register(self, registry: &Registry) -> prometheus::Result<()> {
+        registry.register(Box::new(self))
+    }
+}
+
+impl Collector for MapFootprintCollector {
+    fn desc(&self) -> Vec<&Desc> {
+        self.entries
+            .desc()
+            .into_iter()
+            .chain(self.approx_bytes.desc())
+            .collect()
+    }
+
+    fn collect(&self) -> Vec<MetricFamily> {
+        // Maps of disabled subsystems disappear instead of reading zero
+        self.entries.reset();
+        self.approx_bytes.reset();
+        for map in self.reloader.map_footprints() {
+            self.entries
+                .with_label_values(&[map.name.as_str()])
+                .set(map.entries as i64);
+            self.approx_bytes
+                .with_label_values(&[map.name.as_str()])
+                .set(map.approx_bytes as i64);
+        }
+        self.entries
+            .collect()
+            .into_iter()
+            .chain(self.approx_bytes.collect())
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::components::{Components, ProxyReloader};
+    use arc_swap::ArcSwap;
+    use layer7waf_common::AppConfig;
+    use std::sync::RwLock;
+
+    #[test]
+    fn test_gauges_follow_live_maps() {
+        let config: AppConfig = serde_json::from_value(serde_json::json!({
+            "server": { "listen": ["127.0.0.1:8080"] },
+            "upstreams": [{ "name": "backend", "servers": [{ "addr": "127.0.0.1:8000" }] }],
+            "routes": [{ "path_prefix": "/", "upstream": "backend" }],
+            "waf": {},
+            "rate_limit": { "enabled": true, "default_rps": 10, "default_burst": 10 }
+        }))
+        .unwrap();
+        let components = Arc::new(ArcSwap::from_pointee(Components::build(&config)));
+        let reloader = Arc::new(ProxyReloader {
+            config: Arc::new(RwLock::new(config)),
+            components: components.clone(),
+        });
+        let registry = Registry::new();
+        MapFootprintCollector::new(reloader)
+            .register(&registry)
+            .unwrap();
+
+        let limiter = components.load().rate_limiter.clone().unwrap();
+        limiter.check("10.0.0.1");
+        limiter.check("10.0.0.2");
+
+        let families = registry.gather();
+        let gauge = |name: &str| {
+            let family = families.iter().find(|f| f.get_name() == name).unwrap();
+            let metric = family
+                .get_metric()
+                .iter()
+                .find(|m| m.get_label()[0].get_value() == "rate_limit:global")
+                .unwrap();
+            metric.get_gauge().get_value()
+        };
+        assert_eq!(gauge("layer7waf_map_entries"), 2.0);
+        assert!(gauge("layer7waf_map_approx_bytes") > 0.0);
+    }
+}
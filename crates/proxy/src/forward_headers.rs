@@ -0,0 +1,77 @@
+use http::header::{CONNECTION, CONTENT_LENGTH, HOST, TRANSFER_ENCODING};
+use http::{HeaderMap, HeaderName};
+use layer7waf_common::ForwardHeadersPolicy;
+
+/// Headers needed to deliver the request at all; never stripped.
+fn always_forwarded(name: &HeaderName) -> bool {
+    *name == HOST || *name == CONTENT_LENGTH || *name == TRANSFER_ENCODING || *name == CONNECTION
+}
+
+/// Select the client headers to remove before forwarding a request, given
+/// the route's `policy` and its `listed` header names (case-insensitive).
+pub fn headers_to_strip(
+    headers: &HeaderMap,
+    policy: ForwardHeadersPolicy,
+    listed: &[String],
+) -> Vec<HeaderName> {
+    let is_listed = |name: &HeaderName| listed.iter().any(|l| l.eq_ignore_ascii_case(name.as_str()));
+
+    headers
+        .keys()
+        .filter(|name| !always_forwarded(name))
+        .filter(|name| match policy {
+            ForwardHeadersPolicy::All => false,
+            ForwardHeadersPolicy::Allowlist => !is_listed(name),
+            ForwardHeadersPolicy::Denylist => is_listed(name),
+        })
+        .cloned()
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn request_headers() -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert("host", "example.com".parse().unwrap());
+        headers.insert("accept", "*/*".parse().unwrap());
+        headers.insert("x-user-id", "42".parse().unwrap());
+        headers.insert("x-internal-auth", "spoofed".parse().unwrap());
+        headers
+    }
+
+    fn names(list: &[&str]) -> Vec<String> {
+        list.iter().map(|s| s.to_string()).collect()
+    }
+
+    #[test]
+    fn test_all_forwards_everything() {
+        let strip = headers_to_strip(&request_headers(), ForwardHeadersPolicy::All, &names(&["accept"]));
+        assert!(strip.is_empty());
+    }
+
+    #[test]
+    fn test_allowlist_passes_only_listed() {
+        let strip = headers_to_strip(
+            &request_headers(),
+            ForwardHeadersPolicy::Allowlist,
+            &names(&["Accept"]),
+        );
+        assert_eq!(strip.len(), 2);
+        assert!(strip.contains(&HeaderName::from_static("x-user-id")));
+        assert!(strip.contains(&HeaderName::from_static("x-internal-auth")));
+        // Host is always forwarded even when not listed
+        assert!(!strip.contains(&HOST));
+    }
+
+    #[test]
+    fn test_denylist_strips_listed() {
+        let strip = headers_to_strip(
+            &request_headers(),
+            ForwardHeadersPolicy::Denylist,
+            &names(&["X-Internal-Auth", "x-not-present"]),
+        );
+        assert_eq!(strip, vec![HeaderName::from_static("x-internal-auth")]);
+    }
+}
@@ -0,0 +1,104 @@
+use http::HeaderMap;
+
+/// A `Content-Length`/`Transfer-Encoding` combination that front and back
+/// ends could disagree on, the basis of request smuggling.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FramingViolation {
+    /// `Content-Length` alongside a `Transfer-Encoding`.
+    LengthWithTransferEncoding,
+    /// More than one `Content-Length` header or value.
+    MultipleContentLength,
+    /// A `Content-Length` that isn't a plain decimal number.
+    InvalidContentLength,
+}
+
+impl FramingViolation {
+    /// Short identifier for logs and the block response.
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            FramingViolation::LengthWithTransferEncoding => "content-length-with-transfer-encoding",
+            FramingViolation::MultipleContentLength => "multiple-content-length",
+            FramingViolation::InvalidContentLength => "invalid-content-length",
+        }
+    }
+}
+
+/// Check that `headers` frame the body unambiguously. Any
+/// `Transfer-Encoding` next to a `Content-Length` is refused, not only
+/// `chunked`: obfuscated spellings of it are how one hop is made to see
+/// the header and the next to ignore it.
+pub fn check_framing(headers: &HeaderMap) -> Result<(), FramingViolation> {
+    let mut lengths = headers.get_all("content-length").iter();
+    let Some(length) = lengths.next() else {
+        return Ok(());
+    };
+    if lengths.next().is_some() {
+        return Err(FramingViolation::MultipleContentLength);
+    }
+    let length = length.as_bytes().trim_ascii();
+    if length.contains(&b',') {
+        return Err(FramingViolation::MultipleContentLength);
+    }
+    if length.is_empty() || !length.iter().all(u8::is_ascii_digit) {
+        return Err(FramingViolation::InvalidContentLength);
+    }
+    if headers.contains_key("transfer-encoding") {
+        return Err(FramingViolation::LengthWithTransferEncoding);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use http::HeaderValue;
+
+    fn headers(pairs: &[(&'static str, &'static str)]) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        for (name, value) in pairs {
+            headers.append(*name, HeaderValue::from_static(value));
+        }
+        headers
+    }
+
+    #[test]
+    fn test_normal_requests_pass() {
+        assert_eq!(check_framing(&headers(&[])), Ok(()));
+        assert_eq!(check_framing(&headers(&[("content-length", "42")])), Ok(()));
+        assert_eq!(check_framing(&headers(&[("transfer-encoding", "chunked")])), Ok(()));
+    }
+
+    #[test]
+    fn test_length_with_chunked_rejected() {
+        let both = headers(&[("content-length", "6"), ("transfer-encoding", "chunked")]);
+        assert_eq!(check_framing(&both), Err(FramingViolation::LengthWithTransferEncoding));
+        let obfuscated = headers(&[("content-length", "6"), ("transfer-encoding", "xchunked")]);
+        assert_eq!(
+            check_framing(&obfuscated),
+            Err(FramingViolation::LengthWithTransferEncoding)
+        );
+    }
+
+    #[test]
+    fn test_multiple_content_length_rejected() {
+        let conflicting = headers(&[("content-length", "6"), ("content-length", "0")]);
+        assert_eq!(check_framing(&conflicting), Err(FramingViolation::MultipleContentLength));
+        let duplicate = headers(&[("content-length", "6"), ("content-length", "6")]);
+        assert_eq!(check_framing(&duplicate), Err(FramingViolation::MultipleContentLength));
+        let listed = headers(&[("content-length", "6, 0")]);
+        assert_eq!(check_framing(&listed), Err(FramingViolation::MultipleContentLength));
+    }
+
+    #[test]
+    fn test_non_numeric_content_length_rejected() {
+        for value in ["abc", "+6", "-1", "0x10", "6 6", ""] {
+            let h = headers(&[("content-length", value)]);
+            assert_eq!(
+                check_framing(&h),
+                Err(FramingViolation::InvalidContentLength),
+                "content-length {:?}",
+                value
+            );
+        }
+    }
+}
@@ -0,0 +1,83 @@
+use http::HeaderMap;
+use std::borrow::Cow;
+
+/// Request or response headers as text for the WAF and bot detector.
+#[derive(Debug, Clone, Default)]
+pub struct HeaderPairs {
+    /// `(name, value)` pairs in map order, values as by [`header_text`].
+    pub pairs: Vec<(String, String)>,
+    /// Whether any value was not UTF-8.
+    pub malformed: bool,
+}
+
+/// Text form of a header value. UTF-8 values pass through unchanged; any
+/// other value maps each byte to the char of the same code point (ISO
+/// 8859-1). Unlike a lossy UTF-8 decode this keeps every byte for the rules
+/// to see, though `caf\xe9` and UTF-8 `café` then read the same.
+pub fn header_text(value: &[u8]) -> Cow<'_, str> {
+    match std::str::from_utf8(value) {
+        Ok(text) => Cow::Borrowed(text),
+        Err(_) => Cow::Owned(value.iter().map(|&b| char::from(b)).collect()),
+    }
+}
+
+/// Whether a header value has bytes no browser sends, i.e. isn't UTF-8.
+/// Control bytes other than tab never get this far: `http` refuses to
+/// build a `HeaderValue` from them.
+pub fn is_malformed(value: &[u8]) -> bool {
+    std::str::from_utf8(value).is_err()
+}
+
+/// Collect `headers` for evaluation, noting whether any value is malformed.
+pub fn collect_headers(headers: &HeaderMap) -> HeaderPairs {
+    let mut malformed = false;
+    let pairs = headers
+        .iter()
+        .map(|(name, value)| {
+            let bytes = value.as_bytes();
+            malformed |= is_malformed(bytes);
+            (name.as_str().to_string(), header_text(bytes).into_owned())
+        })
+        .collect();
+    HeaderPairs { pairs, malformed }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use http::HeaderValue;
+
+    #[test]
+    fn test_high_bytes_preserved() {
+        let raw = b"caf\xe9 <script>";
+        let mut headers = HeaderMap::new();
+        headers.insert("x-note", HeaderValue::from_bytes(raw).unwrap());
+        headers.insert("user-agent", HeaderValue::from_static("Mozilla/5.0"));
+
+        let collected = collect_headers(&headers);
+        assert!(collected.malformed);
+        let (_, value) = collected.pairs.iter().find(|(k, _)| k == "x-note").unwrap();
+        assert_eq!(value, "caf\u{e9} <script>");
+
+        // Every byte round-trips
+        let restored: Vec<u8> = value.chars().map(|c| c as u8).collect();
+        assert_eq!(restored, raw);
+    }
+
+    #[test]
+    fn test_utf8_values_untouched() {
+        let mut headers = HeaderMap::new();
+        headers.insert("x-name", HeaderValue::from_str("Zoë\tB").unwrap());
+        let collected = collect_headers(&headers);
+        assert!(!collected.malformed);
+        assert_eq!(collected.pairs, vec![("x-name".to_string(), "Zoë\tB".to_string())]);
+        assert!(matches!(header_text(b"plain"), Cow::Borrowed("plain")));
+    }
+
+    #[test]
+    fn test_control_bytes_never_reach_headers() {
+        assert!(HeaderValue::from_bytes(b"abc\x01").is_err());
+        assert!(HeaderValue::from_bytes(b"abc\x7f").is_err());
+        assert!(!is_malformed(b"a\tb"));
+    }
+}
@@ -0,0 +1,167 @@
+use std::net::IpAddr;
+
+use http::{HeaderMap, Method, Uri};
+use ipnet::IpNet;
+use layer7waf_common::{HealthProbeAction, HealthProbeConfig};
+
+use crate::body_gate::has_request_body;
+
+/// Headers naming the client behind a proxy, on top of the configured
+/// `server.client_ip_headers`.
+const FORWARDING_HEADERS: &[&str] = &["forwarded", "x-forwarded-for", "x-real-ip"];
+
+/// Recognizes health checks from the load balancer or orchestrator, which
+/// are answered or forwarded without touching any security subsystem.
+pub struct HealthProbe {
+    path: String,
+    /// Lowercased, matched as a substring.
+    user_agent: String,
+    sources: Vec<IpNet>,
+    /// Lowercased `server.client_ip_headers`.
+    client_ip_headers: Vec<String>,
+    pub action: HealthProbeAction,
+}
+
+impl HealthProbe {
+    /// Build from a validated config; unparseable CIDRs are skipped.
+    pub fn new(config: &HealthProbeConfig, client_ip_headers: &[String]) -> Self {
+        Self {
+            path: config.path.clone(),
+            user_agent: config.user_agent.to_ascii_lowercase(),
+            sources: config.source_cidrs.iter().filter_map(|c| c.parse().ok()).collect(),
+            client_ip_headers: client_ip_headers.iter().map(|h| h.to_ascii_lowercase()).collect(),
+            action: config.action,
+        }
+    }
+
+    /// How to handle a request, or `None` if it is normal traffic. A probe
+    /// is only forwarded unchecked (`action: pass`) when
+    /// [`passable`](Self::passable); otherwise it goes through every check.
+    pub fn action_for(
+        &self,
+        method: &Method,
+        uri: &Uri,
+        headers: &HeaderMap,
+        peer: Option<IpAddr>,
+    ) -> Option<HealthProbeAction> {
+        let user_agent = headers.get("user-agent").and_then(|v| v.to_str().ok());
+        if !self.matches(uri.path(), user_agent, peer) {
+            return None;
+        }
+        match self.action {
+            HealthProbeAction::Pass if !self.passable(method, uri, headers) => None,
+            action => Some(action),
+        }
+    }
+
+    /// Whether a matched probe may reach the upstream past the checks: a
+    /// bare GET or HEAD with no query and no body, sent by the load balancer
+    /// itself rather than relayed through it, i.e. with no forwarding or
+    /// client IP header. Behind an L7 load balancer every request comes from
+    /// its network, so the peer alone doesn't tell a probe from a client.
+    pub fn passable(&self, method: &Method, uri: &Uri, headers: &HeaderMap) -> bool {
+        let forwarded = FORWARDING_HEADERS
+            .iter()
+            .copied()
+            .chain(self.client_ip_headers.iter().map(String::as_str))
+            .any(|name| headers.contains_key(name));
+        (method == Method::GET || method == Method::HEAD)
+            && uri.query().is_none()
+            && !has_request_body(headers)
+            && !forwarded
+    }
+
+    /// Whether a request is a probe: it must match the path, the User-Agent
+    /// and come from a source network. `peer` is the socket address, since
+    /// `X-Forwarded-For` is set by the client and could be spoofed.
+    pub fn matches(&self, path: &str, user_agent: Option<&str>, peer: Option<IpAddr>) -> bool {
+        path == self.path
+            && user_agent.is_some_and(|ua| ua.to_ascii_lowercase().contains(&self.user_agent))
+            && peer.is_some_and(|addr| {
+                let addr = addr.to_canonical();
+                self.sources.iter().any(|net| net.contains(&addr))
+            })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn probe() -> HealthProbe {
+        HealthProbe::new(
+            &HealthProbeConfig {
+                path: "/healthz".to_string(),
+                user_agent: "kube-probe".to_string(),
+                source_cidrs: vec!["10.0.0.0/8".to_string(), "fd00::/8".to_string()],
+                action: HealthProbeAction::Respond,
+            },
+            &["CF-Connecting-IP".to_string()],
+        )
+    }
+
+    #[test]
+    fn test_probe_must_match_path_agent_and_source() {
+        let probe = probe();
+        let node: IpAddr = "10.1.2.3".parse().unwrap();
+        let node_v6: IpAddr = "fd00::1".parse().unwrap();
+        let mapped: IpAddr = "::ffff:10.0.0.1".parse().unwrap();
+        assert!(probe.matches("/healthz", Some("kube-probe/1.29"), Some(node)));
+        assert!(probe.matches("/healthz", Some("Kube-Probe/1.29"), Some(node_v6)));
+        assert!(probe.matches("/healthz", Some("kube-probe/1.29"), Some(mapped)));
+
+        assert!(!probe.matches("/healthz/", Some("kube-probe/1.29"), Some(node)));
+        assert!(!probe.matches("/healthz", Some("curl/8.0"), Some(node)));
+        assert!(!probe.matches("/healthz", None, Some(node)));
+        assert!(!probe.matches("/healthz", Some("kube-probe/1.29"), None));
+    }
+
+    #[test]
+    fn test_spoofed_probe_from_wrong_source_not_matched() {
+        let outside: IpAddr = "203.0.113.9".parse().unwrap();
+        assert!(!probe().matches("/healthz", Some("kube-probe/1.29"), Some(outside)));
+    }
+
+    #[test]
+    fn test_relayed_or_loaded_probe_not_passed() {
+        let passing = HealthProbe {
+            action: HealthProbeAction::Pass,
+            ..probe()
+        };
+        // Behind an L7 load balancer every peer is in the probe network
+        let lb: IpAddr = "10.0.0.2".parse().unwrap();
+        let probe_headers = |extra: &[(&'static str, &'static str)]| {
+            let mut headers = HeaderMap::new();
+            headers.insert("user-agent", "kube-probe/1.29".parse().unwrap());
+            for (name, value) in extra {
+                headers.insert(*name, value.parse().unwrap());
+            }
+            headers
+        };
+        let action = |method: Method, uri: &str, headers: &HeaderMap| {
+            passing.action_for(&method, &uri.parse().unwrap(), headers, Some(lb))
+        };
+
+        let bare = probe_headers(&[]);
+        assert_eq!(action(Method::GET, "/healthz", &bare), Some(HealthProbeAction::Pass));
+        assert_eq!(action(Method::HEAD, "/healthz", &bare), Some(HealthProbeAction::Pass));
+
+        // An outside client relayed by the load balancer, claiming to be a probe
+        let relayed = probe_headers(&[("x-forwarded-for", "203.0.113.9")]);
+        assert_eq!(action(Method::GET, "/healthz", &relayed), None);
+        let via_cdn = probe_headers(&[("cf-connecting-ip", "203.0.113.9")]);
+        assert_eq!(action(Method::GET, "/healthz", &via_cdn), None);
+
+        // Payloads in a query or body
+        assert_eq!(action(Method::GET, "/healthz?id=1'--", &bare), None);
+        let body = probe_headers(&[("content-length", "512")]);
+        assert_eq!(action(Method::POST, "/healthz", &body), None);
+        assert_eq!(action(Method::GET, "/healthz", &body), None);
+
+        // Answering from the proxy itself stays safe for any of them
+        let respond = probe();
+        let uri = "/healthz?id=1".parse().unwrap();
+        let answered = respond.action_for(&Method::POST, &uri, &relayed, Some(lb));
+        assert_eq!(answered, Some(HealthProbeAction::Respond));
+    }
+}
@@ -0,0 +1,110 @@
+use prometheus::{IntCounter, IntGauge, Registry};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+
+/// Whether a request arriving with `in_flight` requests in progress
+/// (including itself) should be shed under `max_concurrent_requests`.
+pub fn should_shed(in_flight: usize, max_concurrent_requests: Option<usize>) -> bool {
+    max_concurrent_requests.is_some_and(|max| in_flight > max)
+}
+
+/// Counts requests in progress so that, past `server.max_concurrent_requests`,
+/// new ones can be rejected before any expensive stage runs.
+#[derive(Clone)]
+pub struct LoadShedder {
+    in_flight: Arc<AtomicUsize>,
+    pub in_flight_gauge: IntGauge,
+    pub shed: IntCounter,
+}
+
+/// Marks one request as in flight until dropped.
+pub struct InFlightGuard {
+    in_flight: Arc<AtomicUsize>,
+    gauge: IntGauge,
+}
+
+impl Drop for InFlightGuard {
+    fn drop(&mut self) {
+        self.in_flight.fetch_sub(1, Ordering::AcqRel);
+        self.gauge.dec();
+    }
+}
+
+impl LoadShedder {
+    pub fn new() -> Self {
+        Self {
+            in_flight: Arc::new(AtomicUsize::new(0)),
+            in_flight_gauge: IntGauge::new(
+                "layer7waf_requests_in_flight",
+                "Requests currently being processed",
+            )
+            .unwrap(),
+            shed: IntCounter::new(
+                "layer7waf_requests_shed_total",
+                "Requests rejected with 503 because the proxy was overloaded",
+            )
+            .unwrap(),
+        }
+    }
+
+    /// Register the metrics with `registry`. May be called for several
+    /// registries; they all observe the same values.
+    pub fn register(&self, registry: &Registry) -> prometheus::Result<()> {
+        registry.register(Box::new(self.in_flight_gauge.clone()))?;
+        registry.register(Box::new(self.shed.clone()))?;
+        Ok(())
+    }
+
+    /// Count a new request as in flight. Returns the guard that ends it and
+    /// the number of requests in flight, this one included.
+    pub fn enter(&self) -> (InFlightGuard, usize) {
+        let in_flight = self.in_flight.fetch_add(1, Ordering::AcqRel) + 1;
+        self.in_flight_gauge.inc();
+        let guard = InFlightGuard {
+            in_flight: self.in_flight.clone(),
+            gauge: self.in_flight_gauge.clone(),
+        };
+        (guard, in_flight)
+    }
+
+    pub fn in_flight(&self) -> usize {
+        self.in_flight.load(Ordering::Acquire)
+    }
+}
+
+impl Default for LoadShedder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_shed_only_above_threshold() {
+        assert!(!should_shed(99, Some(100)));
+        assert!(!should_shed(100, Some(100)));
+        assert!(should_shed(101, Some(100)));
+        assert!(!should_shed(1_000_000, None));
+    }
+
+    #[test]
+    fn test_guard_tracks_in_flight() {
+        let shedder = LoadShedder::new();
+        let (first, count) = shedder.enter();
+        assert_eq!(count, 1);
+        let (second, count) = shedder.enter();
+        assert_eq!(count, 2);
+        assert!(should_shed(count, Some(1)));
+        assert_eq!(shedder.in_flight_gauge.get(), 2);
+
+        drop(first);
+        drop(second);
+        assert_eq!(shedder.in_flight(), 0);
+        assert_eq!(shedder.in_flight_gauge.get(), 0);
+        let (_third, count) = shedder.enter();
+        assert!(!should_shed(count, Some(1)));
+    }
+}
@@ -1,45 +1,182 @@
+mod access_log;
+mod auth_limit;
+mod block_response;
+mod body_gate;
+mod body_scan;
+mod capture;
+mod challenge;
+mod check;
+mod client_ip;
+mod components;
 mod config;
+mod connections;
 mod context;
+mod csrf;
+mod deadline;
+mod decision;
+mod footprint;
+mod forward_headers;
+mod framing;
+mod header_bytes;
+mod health_probe;
+mod load_shed;
+mod maintenance;
+mod proxy_protocol;
+mod response_buffering;
+mod router;
+mod security_headers;
 mod service;
+mod status_metrics;
+mod tarpit;
+mod telemetry;
+mod timing;
+mod trust;
 mod upstream;
+mod upstream_bytes;
+mod uri_decode;
+mod waf_directives;
 
 use anyhow::Result;
-use pingora_core::server::Server;
-use pingora_proxy::http_proxy_service;
-use tracing::{error, info};
+use std::sync::Arc;
+use pingora_core::apps::ServerApp;
+use pingora_core::protocols::l4::socket::SocketAddr;
+use pingora_core::protocols::{GetSocketDigest, Stream};
+use pingora_core::server::{RunArgs, Server, ShutdownWatch};
+use pingora_core::services::listening::Service;
+use pingora_proxy::http_proxy;
+use tracing::{debug, error, info, warn};
+use tracing_subscriber::layer::SubscriberExt;
+use tracing_subscriber::util::SubscriberInitExt;
 use tracing_subscriber::{fmt, EnvFilter};
 
 use crate::config::ProxyConfig;
+use crate::connections::ConnectionTracker;
+use crate::footprint::MapFootprintCollector;
 use crate::service::Layer7WafProxy;
 
+const DEFAULT_CONFIG_PATH: &str = "config/layer7waf.yaml";
+
 fn main() -> Result<()> {
+    // Parse command-line args: `[--check] [config path]`
+    let mut args = std::env::args().skip(1).peekable();
+    let check_only = args.next_if(|arg| arg == "--check").is_some();
+    let config_path = args.next().unwrap_or_else(|| DEFAULT_CONFIG_PATH.to_string());
+
+    // `--check`: validate the config for CI and exit without starting
+    if check_only {
+        let problems = check::check_config(&config_path);
+        if problems.is_empty() {
+            println!("{}: OK", config_path);
+            return Ok(());
+        }
+        eprintln!("{}: {} problem(s) found", config_path, problems.len());
+        for problem in &problems {
+            eprintln!("  - {}", problem);
+        }
+        std::process::exit(1);
+    }
+
+    // Load configuration before tracing so span export can be configured
+    let proxy_config = ProxyConfig::load(&config_path)?;
+    let app_config = proxy_config.config.clone();
+
     // Initialize tracing
     let filter = EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new("info"));
-    fmt()
-        .with_env_filter(filter)
-        .json()
-        .init();
+    let registry = tracing_subscriber::registry()
+        .with(filter)
+        .with(fmt::layer().json());
 
-    // Parse command-line args for config path
-    let config_path = std::env::args()
-        .nth(1)
-        .unwrap_or_else(|| "config/layer7waf.yaml".to_string());
+    #[cfg(feature = "otel")]
+    let tracer_provider = match app_config.server.tracing {
+        Some(ref tracing_config) => {
+            let provider = telemetry::otel::init_provider(tracing_config)?;
+            registry
+                .with(telemetry::otel::layer(&provider, &tracing_config.service_name))
+                .init();
+            Some(provider)
+        }
+        None => {
+            registry.init();
+            None
+        }
+    };
+    #[cfg(not(feature = "otel"))]
+    registry.init();
 
     info!(config_path = %config_path, "starting Layer 7 WAF");
-
-    // Load configuration
-    let proxy_config = ProxyConfig::load(&config_path)?;
-    let app_config = proxy_config.config.clone();
+    #[cfg(not(feature = "otel"))]
+    if app_config.server.tracing.is_some() {
+        tracing::warn!("server.tracing is set but the proxy was built without the `otel` feature");
+    }
 
     // Create Pingora server
     let mut server = Server::new(None)?;
     server.bootstrap();
 
+    // Admin state is shared with the proxy so the API can inspect live components
+    let admin_state = layer7waf_admin::new_shared_state(app_config.clone());
+
     // Create the WAF proxy service
-    let waf_proxy = Layer7WafProxy::new(app_config.clone());
+    let waf_proxy = Layer7WafProxy::new(app_config.clone()).with_admin_state(admin_state.clone());
     let _metrics = waf_proxy.metrics.clone();
+    *admin_state.rate_limiters.write().unwrap() = waf_proxy.rate_limiters();
+    let reloader = Arc::new(waf_proxy.reloader());
+    *admin_state.reloader.write().unwrap() = Some(reloader.clone());
+    waf_proxy
+        .connections
+        .metrics
+        .register(&admin_state.metrics.registry)
+        .expect("failed to register connection metrics");
+    waf_proxy
+        .load_shedder
+        .register(&admin_state.metrics.registry)
+        .expect("failed to register load shedding metrics");
+    waf_proxy
+        .tarpit
+        .register(&admin_state.metrics.registry)
+        .expect("failed to register tarpit metrics");
+    waf_proxy
+        .metrics
+        .subsystem_duration
+        .register(&admin_state.metrics.registry)
+        .expect("failed to register subsystem timing metrics");
+    waf_proxy
+        .metrics
+        .upstream_bytes
+        .register(&admin_state.metrics.registry)
+        .expect("failed to register upstream byte metrics");
+    waf_proxy
+        .metrics
+        .responses_by_status
+        .register(&admin_state.metrics.registry)
+        .expect("failed to register response status metrics");
+    admin_state
+        .metrics
+        .registry
+        .register(Box::new(waf_proxy.metrics.ip_blocklist_would_block.clone()))
+        .expect("failed to register blocklist grace metrics");
+    admin_state
+        .metrics
+        .registry
+        .register(Box::new(waf_proxy.metrics.requests_allowlisted.clone()))
+        .expect("failed to register allowlist metrics");
+    MapFootprintCollector::new(reloader)
+        .register(&admin_state.metrics.registry)
+        .expect("failed to register map footprint metrics");
 
-    let mut proxy_service = http_proxy_service(&server.configuration, waf_proxy);
+    let connections = waf_proxy.connections.clone();
+    let proxy = http_proxy(&server.configuration, waf_proxy);
+    let mut proxy_service = Service::new(
+        "Pingora HTTP Proxy Service".to_string(),
+        DownstreamApp {
+            inner: Arc::new(proxy),
+            proxy_protocol: app_config.server.proxy_protocol,
+            connections,
+        },
+    );
+    if app_config.server.proxy_protocol {
+        info!("expecting a PROXY protocol header on every connection");
+    }
 
     // Add listeners from config
     for listen_addr in &app_config.server.listen {
@@ -59,24 +196,92 @@ fn main() -> Result<()> {
 
     // Launch admin API in background
     let admin_listen = app_config.server.admin.listen.clone();
-    let admin_config = app_config.clone();
 
     server.add_service(pingora_core::services::background::background_service(
         "admin API",
         AdminBackgroundService {
             listen_addr: admin_listen,
-            config: admin_config,
+            state: admin_state,
         },
     ));
 
     info!("Layer 7 WAF started successfully");
-    server.run_forever();
+    server.run(RunArgs::default());
+
+    // Export the spans still batched before exiting
+    #[cfg(feature = "otel")]
+    if let Some(provider) = tracer_provider {
+        if let Err(e) = provider.shutdown() {
+            warn!(error = %e, "failed to flush trace spans on shutdown");
+        }
+    }
+    Ok(())
+}
+
+/// Wraps the HTTP proxy to see each downstream connection from accept to
+/// close.
+///
+/// When `server.proxy_protocol` is set it reads the PROXY protocol header
+/// off the connection before the HTTP proxy sees the stream, and records the
+/// client address it carries as the connection's peer address. The
+/// connection is then counted in `connections` until it closes: the proxy
+/// is run for each keepalive request here rather than by the listener, so
+/// the header is read once and the close is observed.
+struct DownstreamApp<A> {
+    inner: Arc<A>,
+    proxy_protocol: bool,
+    connections: ConnectionTracker,
+}
+
+#[async_trait::async_trait]
+impl<A: ServerApp + Send + Sync + 'static> ServerApp for DownstreamApp<A> {
+    async fn process_new(
+        self: &Arc<Self>,
+        mut stream: Stream,
+        shutdown: &ShutdownWatch,
+    ) -> Option<Stream> {
+        if self.proxy_protocol {
+            let header =
+                proxy_protocol::read_header_within(&mut stream, proxy_protocol::HEADER_TIMEOUT);
+            match header.await {
+                Ok(Some(source)) => {
+                    let digest = stream.get_socket_digest();
+                    let recorded = digest
+                        .is_some_and(|d| d.peer_addr.set(Some(SocketAddr::Inet(source))).is_ok());
+                    if !recorded {
+                        warn!(%source, "could not record PROXY protocol source address");
+                    }
+                }
+                // LOCAL or UNKNOWN: the load balancer's own connection
+                Ok(None) => debug!("PROXY header without a client address"),
+                Err(e) => {
+                    warn!(error = %e, "dropping connection without a valid PROXY header");
+                    return None;
+                }
+            }
+        }
+
+        let peer = stream
+            .get_socket_digest()
+            .and_then(|d| d.peer_addr().map(|a| a.to_string()))
+            .unwrap_or_default();
+        let _open = self.connections.accept(&peer);
+        let mut reused = self.inner.process_new(stream, shutdown).await;
+        while let Some(stream) = reused {
+            reused = self.inner.process_new(stream, shutdown).await;
+        }
+        None
+    }
+
+    async fn cleanup(&self) {
+        self.inner.cleanup().await
+    }
 }
 
 /// Background service to run the admin API alongside Pingora.
 struct AdminBackgroundService {
     listen_addr: String,
-    config: layer7waf_common::AppConfig,
+    state: layer7waf_admin::SharedStateType,
 }
 
 #[async_trait::async_trait]
@@ -84,10 +289,8 @@ impl pingora_core::services::background::BackgroundService for AdminBackgroundSe
     async fn start(&self, mut shutdown: pingora_core::server::ShutdownWatch) {
         info!(addr = %self.listen_addr, "starting admin API");
 
-        let state = layer7waf_admin::new_shared_state(self.config.clone());
-
         tokio::select! {
-            result = layer7waf_admin::run_admin_server(state, &self.listen_addr) => {
+            result = layer7waf_admin::run_admin_server(self.state.clone(), &self.listen_addr) => {
                 if let Err(e) = result {
                     error!(error = %e, "admin API server error");
                 }
@@ -0,0 +1,33 @@
+use layer7waf_common::MaintenanceConfig;
+
+/// Whether a request for `path` gets the maintenance response: maintenance
+/// is on and `path` isn't under one of its bypass prefixes.
+pub fn in_maintenance(config: &MaintenanceConfig, path: &str) -> bool {
+    config.enabled && !config.bypass_paths.iter().any(|prefix| path.starts_with(prefix.as_str()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn maintenance() -> MaintenanceConfig {
+        MaintenanceConfig {
+            enabled: true,
+            bypass_paths: vec!["/status".to_string()],
+            ..MaintenanceConfig::default()
+        }
+    }
+
+    #[test]
+    fn test_normal_request_in_maintenance() {
+        assert!(in_maintenance(&maintenance(), "/"));
+        assert!(in_maintenance(&maintenance(), "/api/orders"));
+        assert!(!in_maintenance(&MaintenanceConfig::default(), "/api/orders"));
+    }
+
+    #[test]
+    fn test_bypass_path_still_proxied() {
+        assert!(!in_maintenance(&maintenance(), "/status"));
+        assert!(!in_maintenance(&maintenance(), "/status/db"));
+    }
+}
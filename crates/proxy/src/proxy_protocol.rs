@@ -0,0 +1,229 @@
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::time::Duration;
+
+use tokio::io::{AsyncRead, AsyncReadExt};
+
+/// Signature opening a PROXY protocol v2 header.
+const V2_SIGNATURE: [u8; 12] = *b"\r\n\r\n\0\r\nQUIT\n";
+
+/// Longest v1 header line the spec allows, CRLF included.
+const V1_MAX_LEN: usize = 107;
+
+/// How long a new connection may take to send its PROXY header. The load
+/// balancer sends it at once, so a connection still silent after this is
+/// holding a slot open and is dropped.
+pub const HEADER_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Decode a PROXY protocol v1 header line, e.g.
+/// `PROXY TCP4 192.0.2.1 198.51.100.1 56324 443\r\n`.
+///
+/// Returns the source address, or `None` for `PROXY UNKNOWN`, which a load
+/// balancer sends for connections it didn't proxy for a client.
+pub fn parse_v1(line: &[u8]) -> io::Result<Option<SocketAddr>> {
+    let line = std::str::from_utf8(line)
+        .ok()
+        .and_then(|line| line.strip_suffix("\r\n"))
+        .ok_or_else(|| invalid("PROXY v1 header is not an ASCII line"))?;
+    let mut parts = line.split(' ');
+    if parts.next() != Some("PROXY") {
+        return Err(invalid("missing PROXY v1 signature"));
+    }
+    let ipv6 = match parts.next() {
+        Some("UNKNOWN") => return Ok(None),
+        Some("TCP4") => false,
+        Some("TCP6") => true,
+        _ => return Err(invalid("unsupported PROXY v1 protocol")),
+    };
+    let fields: Vec<&str> = parts.collect();
+    let [source, destination, port, _destination_port] = fields[..] else {
+        return Err(invalid("malformed PROXY v1 header"));
+    };
+    let ip: IpAddr = source
+        .parse()
+        .map_err(|_| invalid("invalid PROXY v1 source address"))?;
+    let destination: IpAddr = destination
+        .parse()
+        .map_err(|_| invalid("invalid PROXY v1 destination address"))?;
+    if ip.is_ipv6() != ipv6 || destination.is_ipv6() != ipv6 {
+        return Err(invalid("PROXY v1 address does not match its protocol"));
+    }
+    let port: u16 = port
+        .parse()
+        .map_err(|_| invalid("invalid PROXY v1 source port"))?;
+    Ok(Some(SocketAddr::new(ip, port)))
+}
+
+/// Decode a PROXY protocol v2 header from its 16-byte fixed part and the
+/// address block that follows it.
+///
+/// Returns the source address, or `None` for a `LOCAL` command (health
+/// checks from the load balancer itself) and for address families other
+/// than TCP/UDP over IPv4 or IPv6.
+pub fn parse_v2(fixed: &[u8; 16], addresses: &[u8]) -> io::Result<Option<SocketAddr>> {
+    if fixed[..12] != V2_SIGNATURE {
+        return Err(invalid("missing PROXY v2 signature"));
+    }
+    let version = fixed[12] >> 4;
+    let command = fixed[12] & 0x0f;
+    if version != 2 {
+        return Err(invalid("unsupported PROXY protocol version"));
+    }
+    match command {
+        0x0 => return Ok(None),
+        0x1 => {}
+        _ => return Err(invalid("unsupported PROXY v2 command")),
+    }
+    let source = match fixed[13] >> 4 {
+        // AF_INET: 4-byte source and destination, then 2-byte ports
+        0x1 if addresses.len() >= 12 => {
+            let ip = Ipv4Addr::new(addresses[0], addresses[1], addresses[2], addresses[3]);
+            let port = u16::from_be_bytes([addresses[8], addresses[9]]);
+            SocketAddr::new(IpAddr::V4(ip), port)
+        }
+        // AF_INET6: 16-byte source and destination, then 2-byte ports
+        0x2 if addresses.len() >= 36 => {
+            let mut octets = [0u8; 16];
+            octets.copy_from_slice(&addresses[..16]);
+            let port = u16::from_be_bytes([addresses[32], addresses[33]]);
+            SocketAddr::new(IpAddr::V6(Ipv6Addr::from(octets)), port)
+        }
+        0x1 | 0x2 => return Err(invalid("truncated PROXY v2 address block")),
+        _ => return Ok(None),
+    };
+    Ok(Some(source))
+}
+
+/// Read the PROXY protocol header, v1 or v2, from the start of a connection
+/// and return the client address it carries.
+///
+/// Reads exactly the header's bytes, so the request that follows is left on
+/// `stream` untouched. A connection that doesn't start with a valid header
+/// is an error.
+pub async fn read_header<S>(stream: &mut S) -> io::Result<Option<SocketAddr>>
+where
+    S: AsyncRead + Unpin,
+{
+    let mut fixed = [0u8; 16];
+    stream.read_exact(&mut fixed[..5]).await?;
+    if &fixed[..5] == b"PROXY" {
+        let mut line = fixed[..5].to_vec();
+        while !line.ends_with(b"\r\n") {
+            if line.len() >= V1_MAX_LEN {
+                return Err(invalid("PROXY v1 header too long"));
+            }
+            line.push(stream.read_u8().await?);
+        }
+        return parse_v1(&line);
+    }
+
+    stream.read_exact(&mut fixed[5..]).await?;
+    if fixed[..12] != V2_SIGNATURE {
+        return Err(invalid("connection did not start with a PROXY header"));
+    }
+    let len = u16::from_be_bytes([fixed[14], fixed[15]]) as usize;
+    let mut addresses = vec![0u8; len];
+    stream.read_exact(&mut addresses).await?;
+    parse_v2(&fixed, &addresses)
+}
+
+/// [`read_header`], failing with `TimedOut` if the header hasn't arrived
+/// within `timeout`.
+pub async fn read_header_within<S>(
+    stream: &mut S,
+    timeout: Duration,
+) -> io::Result<Option<SocketAddr>>
+where
+    S: AsyncRead + Unpin,
+{
+    tokio::time::timeout(timeout, read_header(stream))
+        .await
+        .unwrap_or_else(|_| {
+            Err(io::Error::new(io::ErrorKind::TimedOut, "PROXY header not received in time"))
+        })
+}
+
+fn invalid(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A v2 PROXY header for a TCP over IPv4 connection.
+    fn v2_tcp4(source: [u8; 4], source_port: u16) -> Vec<u8> {
+        let mut header = V2_SIGNATURE.to_vec();
+        header.extend([0x21, 0x11, 0x00, 0x0c]);
+        header.extend(source);
+        header.extend([10, 0, 0, 1]);
+        header.extend(source_port.to_be_bytes());
+        header.extend(443u16.to_be_bytes());
+        header
+    }
+
+    #[test]
+    fn test_v1_header_source() {
+        let addr = parse_v1(b"PROXY TCP4 203.0.113.7 10.0.0.1 56324 443\r\n").unwrap();
+        assert_eq!(addr, Some("203.0.113.7:56324".parse().unwrap()));
+
+        let addr = parse_v1(b"PROXY TCP6 2001:db8::7 2001:db8::1 4000 443\r\n").unwrap();
+        assert_eq!(addr, Some("[2001:db8::7]:4000".parse().unwrap()));
+
+        assert_eq!(parse_v1(b"PROXY UNKNOWN\r\n").unwrap(), None);
+        assert!(parse_v1(b"PROXY TCP4 not-an-ip 10.0.0.1 1 2\r\n").is_err());
+        assert!(parse_v1(b"PROXY TCP4 203.0.113.7 10.0.0.1 56324 443\n").is_err());
+    }
+
+    #[test]
+    fn test_v1_address_family_must_match_protocol() {
+        assert!(parse_v1(b"PROXY TCP4 2001:db8::7 10.0.0.1 4000 443\r\n").is_err());
+        assert!(parse_v1(b"PROXY TCP4 203.0.113.7 2001:db8::1 4000 443\r\n").is_err());
+        assert!(parse_v1(b"PROXY TCP6 203.0.113.7 2001:db8::1 4000 443\r\n").is_err());
+        assert!(parse_v1(b"PROXY TCP6 2001:db8::7 10.0.0.1 4000 443\r\n").is_err());
+    }
+
+    #[test]
+    fn test_v2_header_source() {
+        let header = v2_tcp4([203, 0, 113, 7], 56324);
+        let fixed: [u8; 16] = header[..16].try_into().unwrap();
+        let addr = parse_v2(&fixed, &header[16..]).unwrap();
+        assert_eq!(addr, Some("203.0.113.7:56324".parse().unwrap()));
+
+        // LOCAL command: the load balancer's own health check
+        let mut local = fixed;
+        local[12] = 0x20;
+        assert_eq!(parse_v2(&local, &[]).unwrap(), None);
+
+        assert!(parse_v2(&fixed, &header[16..20]).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_read_header_leaves_request() {
+        let mut stream: &[u8] = b"PROXY TCP4 203.0.113.7 10.0.0.1 56324 443\r\nGET / HTTP/1.1\r\n";
+        let addr = read_header(&mut stream).await.unwrap();
+        assert_eq!(addr, Some("203.0.113.7:56324".parse().unwrap()));
+        assert_eq!(stream, b"GET / HTTP/1.1\r\n");
+
+        let mut bytes = v2_tcp4([198, 51, 100, 9], 1234);
+        bytes.extend(b"GET / HTTP/1.1\r\n");
+        let mut stream = bytes.as_slice();
+        let addr = read_header(&mut stream).await.unwrap();
+        assert_eq!(addr, Some("198.51.100.9:1234".parse().unwrap()));
+        assert_eq!(stream, b"GET / HTTP/1.1\r\n");
+
+        let mut stream: &[u8] = b"GET / HTTP/1.1\r\nHost: example.com\r\n";
+        assert!(read_header(&mut stream).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_silent_connection_times_out() {
+        // Half a header, then nothing
+        let (mut client, mut server) = tokio::io::duplex(64);
+        tokio::io::AsyncWriteExt::write_all(&mut client, b"PROXY TCP4 ").await.unwrap();
+        let err = read_header_within(&mut server, Duration::from_millis(50))
+            .await
+            .unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::TimedOut);
+    }
+}
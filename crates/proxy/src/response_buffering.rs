@@ -0,0 +1,86 @@
+/// The media type of `content_type` without parameters, lowercased.
+fn essence(content_type: &str) -> String {
+    content_type
+        .split(';')
+        .next()
+        .unwrap_or("")
+        .trim()
+        .to_ascii_lowercase()
+}
+
+/// Whether `content_type` is gRPC (or gRPC-Web), e.g. `application/grpc` or
+/// `application/grpc+proto`. Such a body is length-prefixed messages with
+/// the status in trailers, so it must reach the client byte for byte.
+pub fn is_grpc(content_type: &str) -> bool {
+    let essence = essence(content_type);
+    essence == "application/grpc"
+        || essence.starts_with("application/grpc+")
+        || essence.starts_with("application/grpc-web")
+}
+
+/// Whether a response of `content_type` must stream through untouched per
+/// `server.never_buffer_content_types`. Parameters such as `charset` are
+/// ignored and a `type/*` entry matches every subtype.
+pub fn never_buffered(content_type: &str, never_buffer: &[String]) -> bool {
+    let essence = essence(content_type);
+    never_buffer.iter().any(|pattern| {
+        let pattern = pattern.trim().to_ascii_lowercase();
+        match pattern.strip_suffix('*') {
+            Some(prefix) if prefix.ends_with('/') => essence.starts_with(prefix),
+            _ => essence == pattern,
+        }
+    })
+}
+
+/// Whether a response body should be buffered for anti-scraping rewriting:
+/// only HTML is, and never gRPC or a type that must stream through.
+pub fn buffer_for_rewrite(content_type: Option<&str>, never_buffer: &[String]) -> bool {
+    content_type.is_some_and(|ct| {
+        ct.contains("text/html") && !is_grpc(ct) && !never_buffered(ct, never_buffer)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn defaults() -> Vec<String> {
+        vec![
+            "video/*".to_string(),
+            "application/octet-stream".to_string(),
+            "text/event-stream".to_string(),
+        ]
+    }
+
+    #[test]
+    fn test_event_stream_passes_through_html_processed() {
+        let never = defaults();
+        assert!(never_buffered("text/event-stream", &never));
+        assert!(!buffer_for_rewrite(Some("text/event-stream"), &never));
+        assert!(buffer_for_rewrite(Some("text/html; charset=utf-8"), &never));
+        assert!(!buffer_for_rewrite(None, &never));
+    }
+
+    #[test]
+    fn test_wildcards_and_parameters() {
+        let never = defaults();
+        assert!(never_buffered("video/mp4", &never));
+        assert!(never_buffered("Application/Octet-Stream; name=export.csv", &never));
+        assert!(!never_buffered("videos/mp4", &never));
+        assert!(!never_buffered("application/json", &never));
+
+        // Listing HTML itself turns rewriting off for it
+        let never = vec!["text/*".to_string()];
+        assert!(!buffer_for_rewrite(Some("text/html"), &never));
+    }
+
+    #[test]
+    fn test_grpc_never_buffered() {
+        assert!(is_grpc("application/grpc"));
+        assert!(is_grpc("Application/gRPC+proto"));
+        assert!(is_grpc("application/grpc-web-text; charset=utf-8"));
+        assert!(!is_grpc("application/grpcx"));
+        assert!(!is_grpc("application/json"));
+        assert!(!buffer_for_rewrite(Some("application/grpc+proto"), &defaults()));
+    }
+}
@@ -0,0 +1,131 @@
+use layer7waf_common::RouteConfig;
+
+/// Host part of a route, compiled from `RouteConfig.host`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum HostPattern {
+    /// No host configured: matches every request.
+    Any,
+    /// Exact hostname (lowercased).
+    Exact(String),
+    /// `*.example.com`: any single- or multi-level subdomain of the suffix.
+    /// Stored as `.example.com` so the apex domain itself doesn't match.
+    Wildcard(String),
+}
+
+impl HostPattern {
+    fn compile(host: Option<&str>) -> Self {
+        match host {
+            None => HostPattern::Any,
+            Some(h) => match h.strip_prefix("*.") {
+                Some(suffix) => HostPattern::Wildcard(format!(".{}", suffix.to_ascii_lowercase())),
+                None => HostPattern::Exact(h.to_ascii_lowercase()),
+            },
+        }
+    }
+
+    fn matches(&self, host: Option<&str>) -> bool {
+        match (self, host) {
+            (HostPattern::Any, _) => true,
+            (_, None) => false,
+            (HostPattern::Exact(exact), Some(h)) => h == exact,
+            (HostPattern::Wildcard(suffix), Some(h)) => h.ends_with(suffix.as_str()),
+        }
+    }
+}
+
+/// Route lookup table compiled from the configured routes at load time.
+///
+/// A route whose exact host matches wins over wildcard and host-less routes;
+/// otherwise the first matching route in config order is used.
+pub struct RouteMatcher {
+    routes: Vec<(HostPattern, String)>,
+}
+
+impl RouteMatcher {
+    pub fn new(routes: &[RouteConfig]) -> Self {
+        Self {
+            routes: routes
+                .iter()
+                .map(|r| (HostPattern::compile(r.host.as_deref()), r.path_prefix.clone()))
+                .collect(),
+        }
+    }
+
+    /// Return the index of the route matching `host` (the raw `Host` header,
+    /// port allowed) and `path`.
+    pub fn find(&self, host: Option<&str>, path: &str) -> Option<usize> {
+        let host = host.map(normalize_host);
+        let host = host.as_deref();
+
+        let mut fallback = None;
+        for (i, (pattern, prefix)) in self.routes.iter().enumerate() {
+            if !path.starts_with(prefix.as_str()) || !pattern.matches(host) {
+                continue;
+            }
+            if matches!(pattern, HostPattern::Exact(_)) {
+                return Some(i);
+            }
+            fallback.get_or_insert(i);
+        }
+        fallback
+    }
+}
+
+/// Lowercase a `Host` header value and strip any port.
+fn normalize_host(host: &str) -> String {
+    let host = if host.starts_with('[') {
+        // IPv6 literal: keep the brackets, drop a trailing :port
+        host.split_once("]:").map(|(h, _)| format!("{}]", h)).unwrap_or_else(|| host.to_string())
+    } else {
+        host.rsplit_once(':').map(|(h, _)| h).unwrap_or(host).to_string()
+    };
+    host.to_ascii_lowercase()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn route(host: Option<&str>, path_prefix: &str) -> RouteConfig {
+        serde_json::from_value(serde_json::json!({
+            "host": host,
+            "path_prefix": path_prefix,
+            "upstream": "backend"
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn test_wildcard_matches_subdomains() {
+        let matcher = RouteMatcher::new(&[route(Some("*.example.com"), "/")]);
+        assert_eq!(matcher.find(Some("api.example.com"), "/"), Some(0));
+        assert_eq!(matcher.find(Some("a.b.example.com"), "/x"), Some(0));
+        assert_eq!(matcher.find(Some("API.Example.com:8443"), "/"), Some(0));
+    }
+
+    #[test]
+    fn test_wildcard_does_not_match_apex() {
+        let matcher = RouteMatcher::new(&[route(Some("*.example.com"), "/")]);
+        assert_eq!(matcher.find(Some("example.com"), "/"), None);
+        assert_eq!(matcher.find(Some("badexample.com"), "/"), None);
+        assert_eq!(matcher.find(None, "/"), None);
+    }
+
+    #[test]
+    fn test_exact_host_wins_over_wildcard() {
+        let matcher = RouteMatcher::new(&[
+            route(Some("*.example.com"), "/"),
+            route(None, "/"),
+            route(Some("api.example.com"), "/"),
+        ]);
+        assert_eq!(matcher.find(Some("api.example.com"), "/v1"), Some(2));
+        assert_eq!(matcher.find(Some("www.example.com"), "/"), Some(0));
+        assert_eq!(matcher.find(Some("other.org"), "/"), Some(1));
+    }
+
+    #[test]
+    fn test_path_prefix_still_required() {
+        let matcher = RouteMatcher::new(&[route(Some("api.example.com"), "/api")]);
+        assert_eq!(matcher.find(Some("api.example.com"), "/static"), None);
+    }
+}
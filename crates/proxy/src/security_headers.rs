@@ -0,0 +1,151 @@
+use http::HeaderMap;
+use layer7waf_common::SecurityHeadersMode;
+use std::collections::BTreeMap;
+
+use crate::response_buffering::is_grpc;
+
+/// Whether the response with `headers` is gRPC, which gets no security
+/// headers: they are meaningless to gRPC clients and some of them reject
+/// unexpected headers on the stream.
+pub fn is_grpc_response(headers: &HeaderMap) -> bool {
+    headers
+        .get("content-type")
+        .and_then(|v| v.to_str().ok())
+        .is_some_and(is_grpc)
+}
+
+/// Select which configured security headers should be written to a response
+/// that currently has `existing` headers, according to the route's `mode`.
+/// A gRPC response gets none.
+///
+/// The caller inserts each returned pair, overwriting any existing value.
+pub fn headers_to_set<'a>(
+    existing: &HeaderMap,
+    configured: &'a BTreeMap<String, String>,
+    mode: SecurityHeadersMode,
+) -> Vec<(&'a str, &'a str)> {
+    if is_grpc_response(existing) {
+        return Vec::new();
+    }
+    match mode {
+        SecurityHeadersMode::Skip => Vec::new(),
+        SecurityHeadersMode::Replace => configured
+            .iter()
+            .map(|(k, v)| (k.as_str(), v.as_str()))
+            .collect(),
+        SecurityHeadersMode::Append => configured
+            .iter()
+            .filter(|(k, _)| !existing.contains_key(k.as_str()))
+            .map(|(k, v)| (k.as_str(), v.as_str()))
+            .collect(),
+    }
+}
+
+/// A change to an upstream response's headers.
+#[derive(Debug, PartialEq, Eq)]
+pub enum HeaderEdit<'a> {
+    Remove(&'a str),
+    Set(&'a str, &'a str),
+}
+
+/// Edits hiding the backend's fingerprint from a response with `existing`
+/// headers: each of `strip` present is removed, and `Server` is set to
+/// `server_override` if there is one.
+pub fn fingerprint_header_edits<'a>(
+    existing: &HeaderMap,
+    strip: &'a [String],
+    server_override: Option<&'a str>,
+) -> Vec<HeaderEdit<'a>> {
+    let mut edits: Vec<HeaderEdit<'a>> = strip
+        .iter()
+        .filter(|name| existing.contains_key(name.as_str()))
+        .filter(|name| server_override.is_none() || !name.eq_ignore_ascii_case("server"))
+        .map(|name| HeaderEdit::Remove(name))
+        .collect();
+    if let Some(server) = server_override {
+        edits.push(HeaderEdit::Set("server", server));
+    }
+    edits
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn configured() -> BTreeMap<String, String> {
+        [
+            ("Content-Security-Policy", "default-src 'self'"),
+            ("X-Frame-Options", "DENY"),
+        ]
+        .into_iter()
+        .map(|(k, v)| (k.to_string(), v.to_string()))
+        .collect()
+    }
+
+    fn upstream_with_csp() -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert("content-security-policy", "default-src *".parse().unwrap());
+        headers
+    }
+
+    #[test]
+    fn test_replace_overwrites_existing() {
+        let configured = configured();
+        let set = headers_to_set(&upstream_with_csp(), &configured, SecurityHeadersMode::Replace);
+        assert!(set.contains(&("Content-Security-Policy", "default-src 'self'")));
+        assert!(set.contains(&("X-Frame-Options", "DENY")));
+    }
+
+    #[test]
+    fn test_append_keeps_existing() {
+        let configured = configured();
+        let set = headers_to_set(&upstream_with_csp(), &configured, SecurityHeadersMode::Append);
+        assert_eq!(set, vec![("X-Frame-Options", "DENY")]);
+    }
+
+    #[test]
+    fn test_skip_leaves_response_alone() {
+        let configured = configured();
+        let set = headers_to_set(&upstream_with_csp(), &configured, SecurityHeadersMode::Skip);
+        assert!(set.is_empty());
+    }
+
+    #[test]
+    fn test_grpc_response_gets_no_headers() {
+        let configured = configured();
+        let mut grpc = HeaderMap::new();
+        grpc.insert("content-type", "application/grpc+proto".parse().unwrap());
+        assert!(is_grpc_response(&grpc));
+        assert!(headers_to_set(&grpc, &configured, SecurityHeadersMode::Replace).is_empty());
+        assert!(!is_grpc_response(&upstream_with_csp()));
+    }
+
+    fn backend_headers() -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert("server", "Apache/2.4.1".parse().unwrap());
+        headers.insert("x-powered-by", "PHP/7.4.3".parse().unwrap());
+        headers.insert("content-type", "text/html".parse().unwrap());
+        headers
+    }
+
+    fn strip() -> Vec<String> {
+        ["Server", "X-Powered-By", "X-AspNet-Version"].map(String::from).to_vec()
+    }
+
+    #[test]
+    fn test_fingerprint_headers_stripped() {
+        let strip = strip();
+        let edits = fingerprint_header_edits(&backend_headers(), &strip, None);
+        assert_eq!(edits, vec![HeaderEdit::Remove("Server"), HeaderEdit::Remove("X-Powered-By")]);
+    }
+
+    #[test]
+    fn test_server_override_replaces_upstream() {
+        let strip = strip();
+        let edits = fingerprint_header_edits(&backend_headers(), &strip, Some("layer7waf"));
+        assert_eq!(
+            edits,
+            vec![HeaderEdit::Remove("X-Powered-By"), HeaderEdit::Set("server", "layer7waf")]
+        );
+    }
+}
@@ -1,35 +1,75 @@
+use arc_swap::ArcSwap;
 use async_trait::async_trait;
 use bytes::Bytes;
+use chrono::Utc;
 use http::StatusCode;
-use layer7waf_anti_scraping::{AntiScraper, ScrapingCheckResult};
-use layer7waf_bot_detect::{BotCheckResult, BotDetector};
-use layer7waf_common::{AppConfig, WafMode};
-use layer7waf_geoip::{GeoIpAction, GeoIpFilter};
-use layer7waf_coraza::{WafAction, WafEngine, WafTransaction};
-use layer7waf_ip_reputation::IpReputation;
-use layer7waf_rate_limit::RateLimiter;
+use layer7waf_anti_scraping::ScrapingCheckResult;
+use layer7waf_bot_detect::under_attack::UnderAttackMode;
+use layer7waf_bot_detect::{BotCheckResult, BotDetector, RequestSignals};
+use layer7waf_common::{
+    AccessLogFormat, AppConfig, DecisionMode, FailurePolicy, HealthProbeAction,
+    MaintenanceConfig, SecurityHeadersMode, UnavailableResponseConfig, WafMode,
+};
+use layer7waf_geoip::{GeoBlockReason, GeoIpAction};
+use layer7waf_coraza::{WafAction, WafTransaction};
+use layer7waf_admin::audit::body_preview;
+use layer7waf_admin::{AuditLogEntry, SharedStateType};
+use layer7waf_rate_limit::{normalize_rl_key, time_until_reset, RateLimiter};
 use pingora_core::prelude::*;
 use pingora_core::upstreams::peer::HttpPeer;
 use pingora_http::{RequestHeader, ResponseHeader};
-use pingora_proxy::{ProxyHttp, Session};
+use pingora_proxy::{FailToProxy, ProxyHttp, Session};
 use prometheus::{HistogramVec, IntCounter, IntCounterVec, Registry};
+use std::borrow::Cow;
 use std::net::IpAddr;
 use std::sync::{Arc, RwLock};
-use tracing::{debug, error, info, warn};
-
+use std::time::Duration;
+use tracing::{debug, error, info, warn, Instrument};
+
+use crate::capture::CapturedRequest;
+use crate::block_response::block_response;
+use crate::body_gate::has_request_body;
+use crate::challenge::{resolve_challenge, select_challenge_type, Challenge};
+use crate::client_ip::{resolve_client_ip, trusted_header_ip, ClientIpResolution};
+use crate::components::{
+    start_rate_limit_cleanup, start_rule_hit_summary, Components, ProxyReloader,
+};
+use crate::connections::{connection_admitted, ConnectionTracker};
 use crate::context::{BlockReason, RequestContext};
-use crate::upstream::UpstreamSelector;
+use crate::csrf::origin_allowed;
+use crate::decision::RiskSignals;
+use crate::deadline::{self, DeadlineExceeded};
+use crate::forward_headers::headers_to_strip;
+use crate::framing::check_framing;
+use crate::header_bytes::collect_headers;
+use crate::load_shed::{should_shed, LoadShedder};
+use crate::maintenance::in_maintenance;
+use crate::response_buffering::buffer_for_rewrite;
+use crate::security_headers::{
+    fingerprint_header_edits, headers_to_set, is_grpc_response, HeaderEdit,
+};
+use crate::status_metrics::ResponseStatusMetrics;
+use crate::tarpit::Tarpit;
+use crate::telemetry;
+use crate::timing::{Stage, SubsystemTimings};
+use crate::trust::{trust_score, TrustSignals, TRUST_SCORE_HEADER};
+use crate::upstream_bytes::UpstreamByteMetrics;
+use crate::uri_decode::{decode_uri, decode_uri_components};
 
 pub struct Layer7WafProxy {
     pub config: Arc<RwLock<AppConfig>>,
-    pub waf_engine: Option<Arc<WafEngine>>,
-    pub upstreams: Vec<UpstreamSelector>,
-    pub rate_limiter: Option<Arc<RateLimiter>>,
-    pub ip_reputation: Arc<IpReputation>,
-    pub bot_detector: Option<Arc<BotDetector>>,
-    pub anti_scraper: Option<Arc<AntiScraper>>,
-    pub geoip_filter: Option<Arc<GeoIpFilter>>,
+    /// Swapped as a whole when the admin API reloads a subsystem.
+    pub components: Arc<ArcSwap<Components>>,
     pub metrics: Arc<ProxyMetrics>,
+    pub connections: ConnectionTracker,
+    /// In-flight request count for `server.max_concurrent_requests`.
+    pub load_shedder: LoadShedder,
+    /// Rate-limited requests held before their 429 (`rate_limit.tarpit_ms`).
+    pub tarpit: Tarpit,
+    /// Admin API state that blocked requests are audited into.
+    pub admin_state: Option<SharedStateType>,
+    /// "Under attack" toggle; shared with the admin API when one is attached.
+    pub under_attack: Arc<UnderAttackMode>,
 }
 
 pub struct ProxyMetrics {
@@ -48,7 +88,20 @@ pub struct ProxyMetrics {
     pub captchas_solved: IntCounter,
     pub responses_obfuscated: IntCounter,
     pub geoip_blocked: IntCounter,
+    pub geoip_blocked_by_reason: IntCounterVec,
     pub geoip_lookups: IntCounter,
+    pub requests_timed_out: IntCounter,
+    /// Requests from IPs only on a blocklist still in its grace period.
+    pub ip_blocklist_would_block: IntCounter,
+    /// Requests from allowlisted IPs that skipped the checks, counted with
+    /// `ip_reputation.log_allowlisted`.
+    pub requests_allowlisted: IntCounter,
+    /// Per-subsystem decision latency (`server.subsystem_timing`).
+    pub subsystem_duration: SubsystemTimings,
+    /// Body bytes sent to and received from each upstream.
+    pub upstream_bytes: UpstreamByteMetrics,
+    /// Responses sent to clients by status class.
+    pub responses_by_status: ResponseStatusMetrics,
 }
 
 impl ProxyMetrics {
@@ -99,8 +152,31 @@ impl ProxyMetrics {
             IntCounter::new("layer7waf_responses_obfuscated", "Total responses obfuscated").unwrap();
         let geoip_blocked =
             IntCounter::new("layer7waf_geoip_blocked", "Total requests blocked by GeoIP").unwrap();
+        let geoip_blocked_by_reason = IntCounterVec::new(
+            prometheus::Opts::new(
+                "layer7waf_geoip_blocked_by_reason",
+                "Requests blocked by GeoIP, by reason",
+            ),
+            &["reason"],
+        )
+        .unwrap();
         let geoip_lookups =
             IntCounter::new("layer7waf_geoip_lookups", "Total GeoIP lookups performed").unwrap();
+        let requests_timed_out = IntCounter::new(
+            "layer7waf_requests_timed_out",
+            "Total requests that exceeded the request timeout or timed out upstream",
+        )
+        .unwrap();
+        let ip_blocklist_would_block = IntCounter::new(
+            "layer7waf_ip_blocklist_would_block",
+            "Requests matching only blocklist entries still in their grace period",
+        )
+        .unwrap();
+        let requests_allowlisted = IntCounter::new(
+            "layer7waf_requests_allowlisted",
+            "Requests from allowlisted IPs that skipped the security checks",
+        )
+        .unwrap();
 
         registry.register(Box::new(requests_total.clone())).unwrap();
         registry
@@ -138,9 +214,27 @@ impl ProxyMetrics {
         registry
             .register(Box::new(geoip_blocked.clone()))
             .unwrap();
+        registry
+            .register(Box::new(geoip_blocked_by_reason.clone()))
+            .unwrap();
         registry
             .register(Box::new(geoip_lookups.clone()))
             .unwrap();
+        registry
+            .register(Box::new(requests_timed_out.clone()))
+            .unwrap();
+        registry
+            .register(Box::new(ip_blocklist_would_block.clone()))
+            .unwrap();
+        registry
+            .register(Box::new(requests_allowlisted.clone()))
+            .unwrap();
+        let subsystem_duration = SubsystemTimings::new();
+        subsystem_duration.register(&registry).unwrap();
+        let upstream_bytes = UpstreamByteMetrics::new();
+        upstream_bytes.register(&registry).unwrap();
+        let responses_by_status = ResponseStatusMetrics::new();
+        responses_by_status.register(&registry).unwrap();
 
         Self {
             registry,
@@ -158,191 +252,326 @@ impl ProxyMetrics {
             captchas_solved,
             responses_obfuscated,
             geoip_blocked,
+            geoip_blocked_by_reason,
             geoip_lookups,
+            requests_timed_out,
+            ip_blocklist_would_block,
+            requests_allowlisted,
+            subsystem_duration,
+            upstream_bytes,
+            responses_by_status,
         }
     }
 }
 
 impl Layer7WafProxy {
     pub fn new(config: AppConfig) -> Self {
-        // Build upstream selectors
-        let upstreams: Vec<UpstreamSelector> = config
-            .upstreams
-            .iter()
-            .map(UpstreamSelector::from_config)
-            .collect();
-
-        // Initialize WAF engine if rules are configured
-        let waf_engine = if !config.waf.rules.is_empty() {
-            let directives = build_waf_directives(&config);
-            match WafEngine::new(&directives) {
-                Ok(engine) => {
-                    info!("WAF engine initialized with {} rule patterns", config.waf.rules.len());
-                    Some(Arc::new(engine))
-                }
-                Err(e) => {
-                    error!("failed to initialize WAF engine: {}", e);
-                    None
-                }
-            }
-        } else {
-            info!("no WAF rules configured, WAF engine disabled");
-            None
-        };
-
-        // Initialize rate limiter
-        let rate_limiter = if config.rate_limit.enabled {
-            let limiter = RateLimiter::new_token_bucket(
-                config.rate_limit.default_rps,
-                config.rate_limit.default_burst,
-            );
-            limiter.start_cleanup_task();
-            info!(
-                rps = config.rate_limit.default_rps,
-                burst = config.rate_limit.default_burst,
-                "rate limiter enabled"
-            );
-            Some(Arc::new(limiter))
-        } else {
-            None
-        };
-
-        // Initialize IP reputation
-        let ip_reputation = Arc::new(IpReputation::new());
-        if let Some(ref path) = config.ip_reputation.blocklist {
-            match ip_reputation.load_blocklist(path) {
-                Ok(count) => info!(count, path = %path.display(), "loaded IP blocklist"),
-                Err(e) => warn!(error = %e, "failed to load IP blocklist"),
-            }
-        }
-        if let Some(ref path) = config.ip_reputation.allowlist {
-            match ip_reputation.load_allowlist(path) {
-                Ok(count) => info!(count, path = %path.display(), "loaded IP allowlist"),
-                Err(e) => warn!(error = %e, "failed to load IP allowlist"),
-            }
-        }
-
-        // Initialize bot detector
-        let bot_detector = if config.bot_detection.enabled {
-            info!(
-                mode = ?config.bot_detection.mode,
-                threshold = config.bot_detection.score_threshold,
-                "bot detection enabled"
-            );
-            Some(Arc::new(BotDetector::new(config.bot_detection.clone())))
-        } else {
-            None
-        };
-
-        // Initialize anti-scraper
-        let anti_scraper = if config.anti_scraping.enabled {
-            info!(
-                mode = ?config.anti_scraping.mode,
-                threshold = config.anti_scraping.score_threshold,
-                "anti-scraping enabled"
-            );
-            Some(Arc::new(AntiScraper::new(config.anti_scraping.clone())))
-        } else {
-            None
-        };
-
-        // Initialize GeoIP filter
-        let geoip_filter = if config.geoip.enabled {
-            match GeoIpFilter::new(config.geoip.clone()) {
-                Ok(filter) => {
-                    info!(
-                        mode = ?config.geoip.mode,
-                        blocked_countries = ?config.geoip.blocked_countries,
-                        allowed_countries = ?config.geoip.allowed_countries,
-                        "GeoIP filtering enabled"
-                    );
-                    Some(Arc::new(filter))
-                }
-                Err(e) => {
-                    warn!(error = %e, "failed to initialize GeoIP filter, continuing without it");
-                    None
-                }
-            }
-        } else {
-            None
-        };
+        let components = Arc::new(ArcSwap::from_pointee(Components::build(&config)));
+        // One cleanup thread serves every limiter, including reloaded ones.
+        start_rate_limit_cleanup(components.clone());
+        start_rule_hit_summary(components.clone());
 
         let metrics = Arc::new(ProxyMetrics::new());
-
+        let connections = ConnectionTracker::new();
+        connections
+            .metrics
+            .register(&metrics.registry)
+            .expect("failed to register connection metrics");
+        let load_shedder = LoadShedder::new();
+        load_shedder
+            .register(&metrics.registry)
+            .expect("failed to register load shedding metrics");
+        let tarpit = Tarpit::new();
+        tarpit
+            .register(&metrics.registry)
+            .expect("failed to register tarpit metrics");
         Self {
             config: Arc::new(RwLock::new(config)),
-            waf_engine,
-            upstreams,
-            rate_limiter,
-            ip_reputation,
-            bot_detector,
-            anti_scraper,
-            geoip_filter,
+            components,
             metrics,
+            connections,
+            load_shedder,
+            tarpit,
+            admin_state: None,
+            under_attack: Arc::new(UnderAttackMode::new()),
         }
     }
 
-    fn find_route(&self, host: Option<&str>, path: &str) -> Option<usize> {
+    /// Record blocked requests in the admin API's audit log and take the
+    /// under-attack toggle from its state.
+    pub fn with_admin_state(mut self, state: SharedStateType) -> Self {
+        self.under_attack = state.under_attack.clone();
+        self.admin_state = Some(state);
+        self
+    }
+
+    /// All active rate limiters labelled by scope, for the admin API.
+    pub fn rate_limiters(&self) -> Vec<(String, RateLimiter)> {
         let config = self.config.read().unwrap();
-        for (i, route) in config.routes.iter().enumerate() {
-            let host_match = match (&route.host, host) {
-                (Some(route_host), Some(req_host)) => req_host == route_host.as_str(),
-                (Some(_), None) => false,
-                (None, _) => true, // wildcard host
-            };
+        self.components.load().rate_limiters(&config)
+    }
 
-            if host_match && path.starts_with(&route.path_prefix) {
-                return Some(i);
-            }
+    /// A handle the admin API uses to apply config changes to this proxy.
+    pub fn reloader(&self) -> ProxyReloader {
+        ProxyReloader {
+            config: self.config.clone(),
+            components: self.components.clone(),
         }
-        None
     }
 
-    fn find_upstream(&self, name: &str) -> Option<&UpstreamSelector> {
-        self.upstreams.iter().find(|u| u.name == name)
+    /// The matched route's own honeypot trap prefix, if it sets one.
+    fn route_trap_path_prefix(&self, route_index: Option<usize>) -> Option<String> {
+        let config = self.config.read().unwrap();
+        route_index
+            .and_then(|i| config.routes.get(i))
+            .and_then(|r| r.trap_path_prefix.clone())
+    }
+
+    /// Hold a rate-limited request per `rate_limit.tarpit_ms` before its 429
+    /// is sent; immediate when the tarpit is off or full. The request stops
+    /// counting toward `max_concurrent_requests` first, so held requests
+    /// can't get real traffic shed.
+    async fn tarpit(&self, ctx: &mut RequestContext) {
+        ctx.in_flight = None;
+        let (delay, max_concurrent) = {
+            let config = self.config.read().unwrap();
+            (
+                Duration::from_millis(config.rate_limit.tarpit_ms),
+                config.rate_limit.tarpit_max_concurrent,
+            )
+        };
+        self.tarpit.hold(delay, max_concurrent).await;
     }
-}
 
-#[async_trait]
-impl ProxyHttp for Layer7WafProxy {
-    type CTX = RequestContext;
+    /// The request-phase checks, run under the request deadline. Returns
+    /// `true` when a response has already been sent.
+    async fn filter_request(&self, session: &mut Session, ctx: &mut RequestContext) -> Result<bool> {
+        // One snapshot per request, so a concurrent reload can't mix components
+        let components = self.components.load_full();
 
-    fn new_ctx(&self) -> Self::CTX {
-        RequestContext::new()
-    }
+        // Health probes skip every check and stay out of the request metrics
+        let header = session.req_header();
+        let probe = components.health_probe_action(
+            &header.method,
+            &header.uri,
+            &header.headers,
+            session.client_addr().and_then(|a| a.as_inet()).map(|a| a.ip()),
+        );
+        if let Some(action) = probe {
+            ctx.health_probe = true;
+            if action == HealthProbeAction::Pass {
+                let host = header.headers.get("host").and_then(|v| v.to_str().ok());
+                ctx.route_index = components.router.find(host, header.uri.path());
+                return Ok(false);
+            }
+            let mut resp = ResponseHeader::build(StatusCode::OK, Some(3)).unwrap();
+            resp.insert_header("content-type", "text/plain").unwrap();
+            resp.insert_header("cache-control", "no-store").unwrap();
+            session.write_response_header(Box::new(resp), false).await?;
+            session.write_response_body(Some(Bytes::from_static(b"OK")), true).await?;
+            return Ok(true);
+        }
 
-    async fn request_filter(&self, session: &mut Session, ctx: &mut Self::CTX) -> Result<bool> {
         self.metrics.requests_total.inc();
+        let (in_flight_guard, in_flight) = self.load_shedder.enter();
+        ctx.in_flight = Some(in_flight_guard);
 
         // Extract request info
         let header = session.req_header();
         ctx.method = header.method.as_str().to_string();
         ctx.uri = header.uri.path_and_query().map(|pq| pq.as_str()).unwrap_or("/").to_string();
+        ctx.protocol = format!("{:?}", header.version);
+        let cached = |name: &str| {
+            header.headers.get(name).and_then(|v| v.to_str().ok()).map(String::from)
+        };
+        ctx.referer = cached("referer");
+        ctx.user_agent = cached("user-agent");
+        ctx.span = telemetry::request_span(&ctx.method, &ctx.uri);
+        let phase = tracing::info_span!(parent: &ctx.span, "request_filter");
+        self.check_request(session, ctx, &components, in_flight)
+            .instrument(phase)
+            .await
+    }
 
-        // Extract client IP from X-Forwarded-For or socket
-        ctx.client_ip = session
-            .req_header()
+    /// The checks of a request that isn't a health probe, run inside its
+    /// `request_filter` span so their events are attached to the request.
+    async fn check_request(
+        &self,
+        session: &mut Session,
+        ctx: &mut RequestContext,
+        components: &Components,
+        in_flight: usize,
+    ) -> Result<bool> {
+        let header = session.req_header();
+
+        // Maintenance mode answers everything but the bypass paths
+        let maintenance = {
+            let config = self.config.read().unwrap();
+            let maintenance = &config.server.maintenance;
+            let path = session.req_header().uri.path();
+            in_maintenance(maintenance, path).then(|| maintenance.clone())
+        };
+        if let Some(maintenance) = maintenance {
+            debug!(uri = %ctx.uri, "request refused: maintenance mode");
+            Self::send_maintenance(session, &maintenance).await?;
+            return Ok(true);
+        }
+
+        let (max_hops, hops_overflow, proxy_protocol, failure_policy, ip_headers) = {
+            let config = self.config.read().unwrap();
+            (
+                config.server.max_forwarded_hops,
+                config.server.forwarded_hops_overflow,
+                config.server.proxy_protocol,
+                config.failure_policy,
+                config.server.client_ip_headers.clone(),
+            )
+        };
+
+        // Extract client IP from a trusted CDN header, X-Forwarded-For or
+        // socket. Behind a PROXY protocol load balancer the socket address is
+        // already the client's and any such header came from the client itself.
+        let forwarded_for = header
             .headers
             .get("x-forwarded-for")
+            .filter(|_| !proxy_protocol)
             .and_then(|v| v.to_str().ok())
-            .and_then(|v| v.split(',').next())
-            .map(|s| s.trim().to_string())
-            .unwrap_or_else(|| {
-                session
-                    .client_addr()
-                    .map(|a| a.to_string())
-                    .unwrap_or_default()
-            });
+            .map(|s| s.to_string());
+        let peer_ip = session.client_addr().and_then(|a| a.as_inet()).map(|a| a.ip());
+        let trusted = &components.trusted_proxies;
+        let header_ip = trusted_header_ip(&header.headers, &ip_headers, peer_ip, trusted)
+            .filter(|_| !proxy_protocol)
+            .map(|s| s.to_string());
+        let peer_addr = session.client_addr().map(|a| a.to_string());
+        let new_connection = peer_addr
+            .as_deref()
+            .is_some_and(|peer| !self.connections.request_started(peer));
+
+        let resolution = resolve_client_ip(
+            header_ip.as_deref(),
+            forwarded_for.as_deref(),
+            peer_addr.as_deref(),
+            max_hops,
+            hops_overflow,
+            failure_policy,
+        );
+        match resolution {
+            ClientIpResolution::Known(ip) => ctx.client_ip = ip.to_string(),
+            ClientIpResolution::Unknown => {
+                debug!(uri = %ctx.uri, "client IP undeterminable, skipping IP-keyed checks");
+            }
+            ClientIpResolution::Reject => {
+                info!(uri = %ctx.uri, "request blocked: client IP undeterminable");
+                ctx.block_reason = Some(BlockReason::UnknownClientIp);
+                self.metrics.requests_blocked.inc();
+                Self::send_block(
+                    session,
+                    StatusCode::FORBIDDEN,
+                    "client-ip-unknown",
+                    "Forbidden: client IP unknown",
+                    None,
+                )
+                .await?;
+                return Ok(true);
+            }
+            ClientIpResolution::TooManyHops => {
+                info!(uri = %ctx.uri, max_hops, "request blocked: too many X-Forwarded-For hops");
+                ctx.block_reason = Some(BlockReason::TooManyForwardedHops);
+                self.metrics.requests_blocked.inc();
+                Self::send_block(
+                    session,
+                    StatusCode::BAD_REQUEST,
+                    "too-many-forwarded-hops",
+                    "Bad Request: X-Forwarded-For too long",
+                    None,
+                )
+                .await?;
+                return Ok(true);
+            }
+        }
+
+        // Request smuggling: refuse framing the upstream could read differently
+        if self.config.read().unwrap().server.strict_framing {
+            if let Err(violation) = check_framing(&session.req_header().headers) {
+                info!(
+                    client_ip = %ctx.client_ip,
+                    uri = %ctx.uri,
+                    violation = violation.as_str(),
+                    "request blocked: ambiguous body framing"
+                );
+                ctx.block_reason = Some(BlockReason::AmbiguousFraming);
+                self.metrics.requests_blocked.inc();
+                Self::send_block(
+                    session,
+                    StatusCode::BAD_REQUEST,
+                    violation.as_str(),
+                    "Bad Request: ambiguous request framing",
+                    None,
+                )
+                .await?;
+                return Ok(true);
+            }
+        }
 
-        // Remove port from IP if present
-        if let Some(ip_part) = ctx.client_ip.rsplit_once(':') {
-            if ctx.client_ip.starts_with('[') || !ctx.client_ip.contains('.') {
-                // IPv6 - keep as is
+        let client_key = ctx.client_key().map(|k| k.to_string());
+
+        // 0. Connection rate: refuse a client opening connections too fast on
+        //    the first request of the new connection, which then closes
+        if let (Some(limiter), Some(key)) =
+            (components.connection_limiter.as_deref(), client_key.as_deref())
+        {
+            let allowlisted = ctx
+                .client_ip
+                .parse()
+                .is_ok_and(|addr| components.ip_reputation.is_allowed(addr));
+            let key = if self.config.read().unwrap().rate_limit.normalize_keys {
+                normalize_rl_key(key)
             } else {
-                ctx.client_ip = ip_part.0.to_string();
+                Cow::Borrowed(key)
+            };
+            if !allowlisted && !connection_admitted(Some(limiter), &key, new_connection) {
+                info!(client_ip = %ctx.client_ip, "connection rate limited");
+                ctx.block_reason = Some(BlockReason::ConnectionRateLimit);
+                self.metrics.requests_rate_limited.inc();
+                self.metrics.requests_blocked.inc();
+                Self::send_block(
+                    session,
+                    StatusCode::TOO_MANY_REQUESTS,
+                    "connection-rate-limited",
+                    "Connection rate limit exceeded",
+                    Some(1),
+                )
+                .await?;
+                return Ok(true);
             }
         }
 
+        // 0.5 Load shedding: when overloaded, refuse before any expensive stage
+        let max_concurrent_requests = self.config.read().unwrap().server.max_concurrent_requests;
+        if should_shed(in_flight, max_concurrent_requests) {
+            let allowlisted = ctx
+                .client_ip
+                .parse()
+                .is_ok_and(|addr| components.ip_reputation.is_allowed(addr));
+            if !allowlisted {
+                debug!(client_ip = %ctx.client_ip, in_flight, "request shed: proxy overloaded");
+                ctx.block_reason = Some(BlockReason::Overloaded);
+                self.load_shedder.shed.inc();
+                Self::send_block(
+                    session,
+                    StatusCode::SERVICE_UNAVAILABLE,
+                    "overloaded",
+                    "Service Unavailable: server overloaded",
+                    Some(1),
+                )
+                .await?;
+                return Ok(true);
+            }
+        }
+
+        let timed = self.config.read().unwrap().server.subsystem_timing;
+        let timings = &self.metrics.subsystem_duration;
+
         let host = session
             .req_header()
             .headers
@@ -356,69 +585,208 @@ impl ProxyHttp for Layer7WafProxy {
             .uri
             .path()
             .to_string();
-        ctx.route_index = self.find_route(host.as_deref(), &path);
+        ctx.route_index = components.router.find(host.as_deref(), &path);
+        if let Some(i) = ctx.route_index {
+            if let Some(route) = self.config.read().unwrap().routes.get(i) {
+                ctx.span.record("route", route.path_prefix.as_str());
+            }
+        }
+
+        // In the aggregate decision mode, blocks by IP reputation, GeoIP, bot
+        // detection and anti-scraping are deferred and scored together
+        let security = self.config.read().unwrap().security.clone();
+        let aggregate = security.decision_mode == DecisionMode::Aggregate;
+        let mut risk = RiskSignals::default();
 
         // 1. IP reputation check
         if let Ok(addr) = ctx.client_ip.parse() {
-            match self.ip_reputation.check(addr) {
+            let action =
+                timings.time(Stage::IpReputation, timed, || components.ip_reputation.check(addr));
+            match action {
+                layer7waf_ip_reputation::IpAction::Block if aggregate => {
+                    debug!(client_ip = %ctx.client_ip, "IP block deferred to aggregate score");
+                    risk.ip_reputation = 1.0;
+                    risk.subsystem_blocked = true;
+                }
                 layer7waf_ip_reputation::IpAction::Block => {
                     info!(client_ip = %ctx.client_ip, "request blocked by IP blocklist");
                     ctx.block_reason = Some(BlockReason::IpBlocked);
                     self.metrics.requests_blocked.inc();
-                    let mut resp = ResponseHeader::build(StatusCode::FORBIDDEN, Some(4)).unwrap();
-                    resp.insert_header("content-type", "text/plain").unwrap();
-                    session.set_keepalive(None);
-                    session
-                        .write_response_header(Box::new(resp), false)
-                        .await?;
-                    session
-                        .write_response_body(Some(Bytes::from("Forbidden: IP blocked\n")), true)
-                        .await?;
+                    Self::send_block(
+                        session,
+                        StatusCode::FORBIDDEN,
+                        "ip-blocked",
+                        "Forbidden: IP blocked",
+                        None,
+                    )
+                    .await?;
                     return Ok(true);
                 }
                 layer7waf_ip_reputation::IpAction::Allow => {
-                    debug!(client_ip = %ctx.client_ip, "IP allowlisted, skipping checks");
-                    return Ok(false);
+                    ctx.ip_allowlisted = true;
+                    if security.allowlist_overrides_geoip {
+                        debug!(client_ip = %ctx.client_ip, "IP allowlisted, skipping checks");
+                        return Ok(false);
+                    }
+                }
+                layer7waf_ip_reputation::IpAction::WouldBlock => {
+                    info!(
+                        client_ip = %ctx.client_ip,
+                        "IP on a blocklist in its grace period, would block"
+                    );
+                    self.metrics.ip_blocklist_would_block.inc();
                 }
                 layer7waf_ip_reputation::IpAction::None => {}
             }
+            // Scored feed entries only raise the risk scores below
+            if matches!(
+                action,
+                layer7waf_ip_reputation::IpAction::None
+                    | layer7waf_ip_reputation::IpAction::WouldBlock
+            ) {
+                ctx.ip_reputation_score = components.ip_reputation.reputation_score(addr);
+                risk.ip_reputation = ctx.ip_reputation_score.unwrap_or(0.0);
+            }
         }
 
-        // 1.5 GeoIP check
-        if let Some(ref geoip) = self.geoip_filter {
+        // 1.25 Origin/Referer check for state-changing requests
+        let origin_ok = {
+            let config = self.config.read().unwrap();
+            let headers = &session.req_header().headers;
+            let header = |name: &str| headers.get(name).and_then(|v| v.to_str().ok());
+            origin_allowed(
+                &config.csrf_protection,
+                &ctx.method,
+                &path,
+                header("origin"),
+                header("referer"),
+            )
+        };
+        if !origin_ok && !ctx.ip_allowlisted {
+            info!(client_ip = %ctx.client_ip, uri = %ctx.uri, "request blocked: cross-origin");
+            ctx.block_reason = Some(BlockReason::CrossOrigin);
+            self.metrics.requests_blocked.inc();
+            Self::send_block(
+                session,
+                StatusCode::FORBIDDEN,
+                "cross-origin",
+                "Forbidden: origin not allowed",
+                None,
+            )
+            .await?;
+            return Ok(true);
+        }
+
+        // 1.5 GeoIP check, skipped on routes with it off and, when the
+        // allowlist overrides it, for allowlisted IPs
+        let route_geoip = ctx.route_index.is_none_or(|i| {
+            self.config.read().unwrap().routes.get(i).is_none_or(|r| r.geoip.enabled)
+        });
+        let geoip = components.geoip_filter_for(
+            route_geoip,
+            ctx.ip_allowlisted,
+            security.allowlist_overrides_geoip,
+        );
+        if let Some(geoip) = geoip {
             if let Ok(addr) = ctx.client_ip.parse::<IpAddr>() {
                 self.metrics.geoip_lookups.inc();
-                match geoip.check(addr) {
-                    GeoIpAction::Block { country } => {
+                match timings.time(Stage::GeoIp, timed, || geoip.check(addr)) {
+                    // Nothing else is scored for an allowlisted IP, so its
+                    // GeoIP block stands on its own
+                    GeoIpAction::Block { country, reason } if aggregate && !ctx.ip_allowlisted => {
+                        debug!(
+                            client_ip = %ctx.client_ip,
+                            country = %country,
+                            reason = %reason,
+                            "GeoIP block deferred to aggregate score"
+                        );
+                        ctx.geo_country = Some(country);
+                        risk.geoip = 1.0;
+                        risk.subsystem_blocked = true;
+                    }
+                    GeoIpAction::Block { country, reason } => {
                         info!(
                             client_ip = %ctx.client_ip,
                             country = %country,
+                            reason = %reason,
                             "request blocked by GeoIP"
                         );
                         ctx.geo_country = Some(country.clone());
-                        ctx.block_reason = Some(BlockReason::GeoBlocked { country });
-                        self.metrics.geoip_blocked.inc();
-                        self.metrics.requests_blocked.inc();
-                        let mut resp =
-                            ResponseHeader::build(StatusCode::FORBIDDEN, Some(4)).unwrap();
-                        resp.insert_header("content-type", "text/plain").unwrap();
-                        session.set_keepalive(None);
-                        session
-                            .write_response_header(Box::new(resp), false)
-                            .await?;
-                        session
-                            .write_response_body(
-                                Some(Bytes::from("Forbidden: blocked by country\n")),
-                                true,
-                            )
-                            .await?;
+                        self.send_geo_block(session, ctx, country, reason).await?;
                         return Ok(true);
                     }
-                    GeoIpAction::Detect { country } => {
+                    GeoIpAction::Challenge { country, reason } => {
+                        ctx.geo_country = Some(country.clone());
+                        let cookie_header = session
+                            .req_header()
+                            .headers
+                            .get("cookie")
+                            .and_then(|v| v.to_str().ok());
+                        // Without a bot detector there is no challenge to serve.
+                        let result = match components.bot_detector {
+                            Some(ref detector) => detector.challenge(&ctx.client_ip, cookie_header),
+                            None => BotCheckResult::Block,
+                        };
+                        let result = match result {
+                            BotCheckResult::Challenge(html) => {
+                                let challenge = resolve_challenge(
+                                    select_challenge_type(
+                                        &session.req_header().headers,
+                                        &security.challenge_selection,
+                                    ),
+                                    html,
+                                    components.anti_scraper.as_deref(),
+                                    &ctx.client_ip,
+                                    &path,
+                                    cookie_header,
+                                );
+                                match challenge {
+                                    Challenge::Js(html) => BotCheckResult::Challenge(html),
+                                    Challenge::Passed => BotCheckResult::Allow,
+                                    Challenge::Block => BotCheckResult::Block,
+                                    Challenge::Captcha(html) => {
+                                        info!(
+                                            client_ip = %ctx.client_ip,
+                                            reason = %reason,
+                                            "issuing CAPTCHA for GeoIP"
+                                        );
+                                        self.metrics.captchas_issued.inc();
+                                        Self::send_captcha(session, html).await?;
+                                        return Ok(true);
+                                    }
+                                }
+                            }
+                            result => result,
+                        };
+                        match (result, &components.bot_detector) {
+                            (BotCheckResult::Challenge(html), Some(detector)) => {
+                                info!(
+                                    client_ip = %ctx.client_ip,
+                                    reason = %reason,
+                                    "issuing JS challenge for GeoIP"
+                                );
+                                self.metrics.challenges_issued.inc();
+                                Self::send_challenge(session, detector, &ctx.client_ip, html).await?;
+                                return Ok(true);
+                            }
+                            (BotCheckResult::Block, _) => {
+                                info!(
+                                    client_ip = %ctx.client_ip,
+                                    reason = %reason,
+                                    "request blocked by GeoIP (challenge unavailable)"
+                                );
+                                self.send_geo_block(session, ctx, country, reason).await?;
+                                return Ok(true);
+                            }
+                            _ => {}
+                        }
+                    }
+                    GeoIpAction::Detect { country, reason } => {
                         ctx.geo_country = Some(country.clone());
                         debug!(
                             client_ip = %ctx.client_ip,
                             country = %country,
+                            reason = %reason,
                             "GeoIP detected country (detect mode)"
                         );
                     }
@@ -428,41 +796,67 @@ impl ProxyHttp for Layer7WafProxy {
             }
         }
 
-        // 2. Rate limiting
-        if let Some(ref limiter) = self.rate_limiter {
-            if !limiter.check(&ctx.client_ip) {
+        if ctx.ip_allowlisted {
+            debug!(client_ip = %ctx.client_ip, "IP allowlisted, skipping remaining checks");
+            return Ok(false);
+        }
+
+        // 2. Rate limiting (an authenticated request's limiter, else a route's
+        // own limiter, replaces the global one)
+        let limiter = components.request_limiter(ctx.route_index, &session.req_header().headers);
+        if let (Some(limiter), Some(key)) = (limiter, client_key.as_deref()) {
+            let key = if self.config.read().unwrap().rate_limit.normalize_keys {
+                normalize_rl_key(key)
+            } else {
+                Cow::Borrowed(key)
+            };
+            if !timings.time(Stage::RateLimit, timed, || limiter.check(&key)) {
                 info!(client_ip = %ctx.client_ip, "request rate limited");
                 ctx.block_reason = Some(BlockReason::RateLimit);
                 self.metrics.requests_rate_limited.inc();
                 self.metrics.requests_blocked.inc();
-                let mut resp =
-                    ResponseHeader::build(StatusCode::TOO_MANY_REQUESTS, Some(4)).unwrap();
-                resp.insert_header("content-type", "text/plain").unwrap();
-                resp.insert_header("retry-after", "1").unwrap();
-                session.set_keepalive(None);
-                session
-                    .write_response_header(Box::new(resp), false)
-                    .await?;
-                session
-                    .write_response_body(Some(Bytes::from("Rate limit exceeded\n")), true)
-                    .await?;
+                self.tarpit(ctx).await;
+                Self::send_block(
+                    session,
+                    StatusCode::TOO_MANY_REQUESTS,
+                    "rate-limited",
+                    "Rate limit exceeded",
+                    Some(1),
+                )
+                .await?;
+                return Ok(true);
+            }
+        }
+
+        // 2.1 Daily quota, counted per client on top of the rate limits
+        if let (Some(quota), Some(key)) = (&components.daily_quota_limiter, client_key.as_deref()) {
+            let key = if self.config.read().unwrap().rate_limit.normalize_keys {
+                normalize_rl_key(key)
+            } else {
+                Cow::Borrowed(key)
+            };
+            if !timings.time(Stage::RateLimit, timed, || quota.check(&key)) {
+                info!(client_ip = %ctx.client_ip, "daily request quota exceeded");
+                ctx.block_reason = Some(BlockReason::DailyQuota);
+                self.metrics.requests_rate_limited.inc();
+                self.metrics.requests_blocked.inc();
+                self.tarpit(ctx).await;
+                Self::send_block(
+                    session,
+                    StatusCode::TOO_MANY_REQUESTS,
+                    "daily-quota-exceeded",
+                    "Daily request quota exceeded",
+                    Some(time_until_reset().as_secs()),
+                )
+                .await?;
                 return Ok(true);
             }
         }
 
         // 2.5 Bot detection
-        if let Some(ref detector) = self.bot_detector {
-            let headers: Vec<(String, String)> = session
-                .req_header()
-                .headers
-                .iter()
-                .map(|(k, v)| {
-                    (
-                        k.as_str().to_string(),
-                        v.to_str().unwrap_or("").to_string(),
-                    )
-                })
-                .collect();
+        if let (Some(detector), Some(client_key)) = (&components.bot_detector, client_key.as_deref()) {
+            let collected = collect_headers(&session.req_header().headers);
+            let headers = collected.pairs;
 
             let cookie_header = session
                 .req_header()
@@ -471,57 +865,108 @@ impl ProxyHttp for Layer7WafProxy {
                 .and_then(|v| v.to_str().ok())
                 .map(|s| s.to_string());
 
-            let result = detector.check(
-                &ctx.client_ip,
-                &headers,
-                &ctx.method,
-                cookie_header.as_deref(),
-            );
+            // UA + path rules come first; under-attack mode then challenges
+            // every client regardless of score
+            let under_attack = self.under_attack.is_active();
+            // Whether the session score reflects this request
+            let mut scored = false;
+            let result = timings.time(Stage::BotDetection, timed, || {
+                if let Some(result) = detector.ua_path_override(&headers, &path) {
+                    result
+                } else if under_attack {
+                    components.under_attack_check(client_key, &headers, cookie_header.as_deref())
+                } else {
+                    scored = true;
+                    // The aggregate score already weighs the reputation
+                    // on its own, so only the short-circuit mode folds it in
+                    let signals = RequestSignals {
+                        malformed_headers: collected.malformed,
+                        ip_reputation: if aggregate {
+                            0.0
+                        } else {
+                            ctx.ip_reputation_score.unwrap_or(0.0)
+                        },
+                    };
+                    detector.check_with_signals(
+                        client_key,
+                        &headers,
+                        &ctx.method,
+                        &path,
+                        cookie_header.as_deref(),
+                        signals,
+                    )
+                }
+            });
+
+            // Clients that can't run the JS challenge may get a CAPTCHA instead
+            let result = match result {
+                BotCheckResult::Challenge(html) => {
+                    let challenge = resolve_challenge(
+                        select_challenge_type(
+                            &session.req_header().headers,
+                            &security.challenge_selection,
+                        ),
+                        html,
+                        components.anti_scraper.as_deref(),
+                        client_key,
+                        &path,
+                        cookie_header.as_deref(),
+                    );
+                    match challenge {
+                        Challenge::Js(html) => BotCheckResult::Challenge(html),
+                        Challenge::Passed => BotCheckResult::Allow,
+                        Challenge::Block => BotCheckResult::Block,
+                        Challenge::Captcha(html) => {
+                            info!(client_ip = %ctx.client_ip, "issuing CAPTCHA for bot detection");
+                            self.metrics.captchas_issued.inc();
+                            Self::send_captcha(session, html).await?;
+                            return Ok(true);
+                        }
+                    }
+                }
+                result => result,
+            };
 
             match result {
+                BotCheckResult::Block if aggregate => {
+                    debug!(client_ip = %ctx.client_ip, "bot block deferred to aggregate score");
+                    risk.bot = detector.session_score(client_key).unwrap_or(1.0);
+                    risk.subsystem_blocked = true;
+                }
                 BotCheckResult::Block => {
                     info!(client_ip = %ctx.client_ip, "request blocked by bot detection");
                     ctx.block_reason = Some(BlockReason::BotDetected { score: 1.0 });
                     self.metrics.bots_detected.inc();
                     self.metrics.requests_blocked.inc();
-                    let mut resp =
-                        ResponseHeader::build(StatusCode::FORBIDDEN, Some(4)).unwrap();
-                    resp.insert_header("content-type", "text/plain").unwrap();
-                    session.set_keepalive(None);
-                    session
-                        .write_response_header(Box::new(resp), false)
-                        .await?;
-                    session
-                        .write_response_body(Some(Bytes::from("Forbidden: Bot detected\n")), true)
-                        .await?;
+                    Self::send_block(
+                        session,
+                        StatusCode::FORBIDDEN,
+                        "bot-detected",
+                        "Forbidden: Bot detected",
+                        None,
+                    )
+                    .await?;
                     return Ok(true);
                 }
                 BotCheckResult::Challenge(html) => {
                     info!(client_ip = %ctx.client_ip, "issuing JS challenge for bot detection");
                     self.metrics.challenges_issued.inc();
-                    let body_bytes = Bytes::from(html);
-                    let mut resp =
-                        ResponseHeader::build(StatusCode::OK, Some(4)).unwrap();
-                    resp.insert_header("content-type", "text/html; charset=utf-8")
-                        .unwrap();
-                    resp.insert_header("cache-control", "no-store").unwrap();
-                    session.set_keepalive(None);
-                    session
-                        .write_response_header(Box::new(resp), false)
-                        .await?;
-                    session
-                        .write_response_body(Some(body_bytes), true)
-                        .await?;
+                    Self::send_challenge(session, detector, &ctx.client_ip, html).await?;
                     return Ok(true);
                 }
                 BotCheckResult::Detect { score } => {
                     ctx.bot_score = Some(score);
+                    risk.bot = score;
                     if score >= 0.7 {
                         self.metrics.bots_detected.inc();
                     }
                     debug!(client_ip = %ctx.client_ip, score, "bot detection score (detect mode)");
                 }
                 BotCheckResult::Allow => {
+                    // Block mode hides scores under the threshold
+                    if aggregate && scored {
+                        risk.bot = detector.session_score(client_key).unwrap_or(0.0);
+                    }
                     // Check if this was a solved challenge (cookie present means solved)
                     if cookie_header
                         .as_deref()
@@ -535,7 +980,7 @@ impl ProxyHttp for Layer7WafProxy {
         }
 
         // 2.75 Anti-scraping check
-        if let Some(ref anti_scraper) = self.anti_scraper {
+        if let (Some(anti_scraper), Some(client_key)) = (&components.anti_scraper, client_key.as_deref()) {
             let cookie_header = session
                 .req_header()
                 .headers
@@ -543,15 +988,23 @@ impl ProxyHttp for Layer7WafProxy {
                 .and_then(|v| v.to_str().ok())
                 .map(|s| s.to_string());
 
-            let bot_score = ctx.bot_score.unwrap_or(0.0);
-
-            let result = anti_scraper.check_request(
-                &ctx.client_ip,
-                &path,
-                &ctx.method,
-                cookie_header.as_deref(),
-                bot_score,
-            );
+            // A scored IP starts out as suspicious as its reputation
+            let bot_score = ctx
+                .bot_score
+                .unwrap_or(0.0)
+                .max(ctx.ip_reputation_score.unwrap_or(0.0));
+            let trap_path_prefix = self.route_trap_path_prefix(ctx.route_index);
+
+            let result = timings.time(Stage::AntiScraping, timed, || {
+                anti_scraper.check_request(
+                    client_key,
+                    &path,
+                    &ctx.method,
+                    cookie_header.as_deref(),
+                    bot_score,
+                    trap_path_prefix.as_deref(),
+                )
+            });
 
             match result {
                 ScrapingCheckResult::TrapTriggered => {
@@ -573,43 +1026,35 @@ impl ProxyHttp for Layer7WafProxy {
                         .await?;
                     return Ok(true);
                 }
+                ScrapingCheckResult::Block if aggregate => {
+                    debug!(client_ip = %ctx.client_ip, "scraping block deferred to aggregate score");
+                    risk.scraping = anti_scraper.session_score(client_key).unwrap_or(1.0);
+                    risk.subsystem_blocked = true;
+                }
                 ScrapingCheckResult::Block => {
                     info!(client_ip = %ctx.client_ip, "request blocked by anti-scraping");
                     ctx.block_reason = Some(BlockReason::ScraperDetected { score: 1.0 });
                     self.metrics.scrapers_blocked.inc();
                     self.metrics.requests_blocked.inc();
-                    let mut resp =
-                        ResponseHeader::build(StatusCode::FORBIDDEN, Some(4)).unwrap();
-                    resp.insert_header("content-type", "text/plain").unwrap();
-                    session.set_keepalive(None);
-                    session
-                        .write_response_header(Box::new(resp), false)
-                        .await?;
-                    session
-                        .write_response_body(Some(Bytes::from("Forbidden: Scraping detected\n")), true)
-                        .await?;
+                    Self::send_block(
+                        session,
+                        StatusCode::FORBIDDEN,
+                        "scraping-detected",
+                        "Forbidden: Scraping detected",
+                        None,
+                    )
+                    .await?;
                     return Ok(true);
                 }
                 ScrapingCheckResult::Challenge(html) => {
                     info!(client_ip = %ctx.client_ip, "issuing CAPTCHA for anti-scraping");
                     self.metrics.captchas_issued.inc();
-                    let body_bytes = Bytes::from(html);
-                    let mut resp =
-                        ResponseHeader::build(StatusCode::OK, Some(4)).unwrap();
-                    resp.insert_header("content-type", "text/html; charset=utf-8")
-                        .unwrap();
-                    resp.insert_header("cache-control", "no-store").unwrap();
-                    session.set_keepalive(None);
-                    session
-                        .write_response_header(Box::new(resp), false)
-                        .await?;
-                    session
-                        .write_response_body(Some(body_bytes), true)
-                        .await?;
+                    Self::send_captcha(session, html).await?;
                     return Ok(true);
                 }
                 ScrapingCheckResult::Detect { score } => {
                     ctx.scraping_score = Some(score);
+                    risk.scraping = score;
                     if score >= 0.6 {
                         self.metrics.scrapers_blocked.inc();
                     }
@@ -623,11 +1068,30 @@ impl ProxyHttp for Layer7WafProxy {
                         .unwrap_or(false)
                     {
                         self.metrics.captchas_solved.inc();
+                    } else if aggregate {
+                        risk.scraping = anti_scraper.session_score(client_key).unwrap_or(0.0);
                     }
                 }
             }
         }
 
+        // 2.9 Aggregate decision over the deferred blocks and scores
+        if aggregate && risk.blocks(&security) {
+            let score = risk.aggregate_score(&security.aggregation.weights);
+            info!(client_ip = %ctx.client_ip, score, ?risk, "request blocked by risk score");
+            ctx.block_reason = Some(BlockReason::RiskScore { score });
+            self.metrics.requests_blocked.inc();
+            Self::send_block(
+                session,
+                StatusCode::FORBIDDEN,
+                "risk-score",
+                "Forbidden: risk score too high",
+                None,
+            )
+            .await?;
+            return Ok(true);
+        }
+
         // 3. WAF check (request headers phase)
         let waf_mode = ctx.route_index.and_then(|i| {
             let config = self.config.read().unwrap();
@@ -636,91 +1100,173 @@ impl ProxyHttp for Layer7WafProxy {
 
         if let Some(ref waf_config) = waf_mode {
             if waf_config.enabled && waf_config.mode != WafMode::Off {
-                if let Some(ref engine) = self.waf_engine {
-                    let tx = WafTransaction::new(engine);
-
-                    // Collect headers
-                    let headers: Vec<(String, String)> = session
-                        .req_header()
-                        .headers
-                        .iter()
-                        .map(|(k, v)| {
-                            (
-                                k.as_str().to_string(),
-                                v.to_str().unwrap_or("").to_string(),
+                if let Some(engine) = components.waf_engine_for(waf_config.ruleset.as_deref()) {
+                    // Layered percent-encoding is undone for the rules below,
+                    // but only up to waf.decode_depth layers
+                    let decode_depth = self.config.read().unwrap().waf.decode_depth;
+                    if decode_uri(&ctx.uri, decode_depth).over_depth {
+                        warn!(
+                            client_ip = %ctx.client_ip,
+                            uri = %ctx.uri,
+                            decode_depth,
+                            "URI percent-encoded more times than waf.decode_depth"
+                        );
+                        if waf_config.mode == WafMode::Block {
+                            ctx.block_reason = Some(BlockReason::OverEncodedUri);
+                            self.metrics.requests_blocked.inc();
+                            Self::send_block(
+                                session,
+                                StatusCode::BAD_REQUEST,
+                                "over-encoded-uri",
+                                "Bad Request: URI encoded too many times",
+                                None,
                             )
-                        })
-                        .collect();
-
-                    let protocol = format!(
-                        "HTTP/{}",
-                        if session.req_header().version == http::Version::HTTP_2 {
-                            "2.0"
-                        } else {
-                            "1.1"
+                            .await?;
+                            return Ok(true);
                         }
-                    );
-
-                    let action =
-                        tx.process_request_headers(&ctx.method, &ctx.uri, &protocol, &headers);
+                    }
 
-                    match action {
-                        WafAction::Block { status } if waf_config.mode == WafMode::Block => {
-                            info!(
+                    // A failed transaction means the WAF can't inspect this
+                    // request; `failure_policy` decides whether it passes
+                    let tx = match WafTransaction::new(engine) {
+                        Ok(tx) => Some(tx),
+                        Err(e) if failure_policy == FailurePolicy::Allow => {
+                            warn!(
                                 client_ip = %ctx.client_ip,
-                                uri = %ctx.uri,
-                                status,
-                                "request blocked by WAF"
+                                error = %e,
+                                "WAF transaction creation failed, skipping WAF"
                             );
-                            ctx.block_reason = Some(BlockReason::Waf { status });
-                            self.metrics.requests_blocked.inc();
-                            let code = StatusCode::from_u16(status)
-                                .unwrap_or(StatusCode::FORBIDDEN);
-                            let mut resp =
-                                ResponseHeader::build(code, Some(4)).unwrap();
-                            resp.insert_header("content-type", "text/plain").unwrap();
-                            session.set_keepalive(None);
-                            session
-                                .write_response_header(Box::new(resp), false)
-                                .await?;
-                            session
-                                .write_response_body(
-                                    Some(Bytes::from("Forbidden: WAF rule triggered\n")),
-                                    true,
-                                )
-                                .await?;
-                            return Ok(true);
+                            None
                         }
-                        WafAction::Block { status } => {
-                            // Detect mode: log but don't block
-                            warn!(
+                        Err(e) => {
+                            error!(
                                 client_ip = %ctx.client_ip,
-                                uri = %ctx.uri,
-                                status,
-                                "WAF rule triggered (detect mode, not blocking)"
+                                error = %e,
+                                "WAF transaction creation failed, rejecting request"
                             );
+                            ctx.block_reason = Some(BlockReason::WafUnavailable);
+                            self.metrics.requests_blocked.inc();
+                            Self::send_block(
+                                session,
+                                StatusCode::SERVICE_UNAVAILABLE,
+                                "waf-unavailable",
+                                "Service Unavailable: WAF unavailable",
+                                Some(1),
+                            )
+                            .await?;
+                            return Ok(true);
                         }
-                        WafAction::Redirect { status, ref url } => {
-                            if waf_config.mode == WafMode::Block {
+                    };
+
+                    if let Some(tx) = tx {
+                        // Decoded per component, so the engine parses the
+                        // same path and parameters the upstream will
+                        let waf_uri = decode_uri_components(&ctx.uri, decode_depth);
+
+                        // Collect headers, keeping non-UTF-8 bytes visible to rules
+                        let headers = collect_headers(&session.req_header().headers).pairs;
+
+                        let protocol = format!(
+                            "HTTP/{}",
+                            if session.req_header().version == http::Version::HTTP_2 {
+                                "2.0"
+                            } else {
+                                "1.1"
+                            }
+                        );
+
+                        let action = timings.time(Stage::Waf, timed, || {
+                            tx.process_request_headers(
+                                &ctx.method,
+                                &waf_uri,
+                                &protocol,
+                                &headers,
+                            )
+                        });
+
+                        // Matches of rules in their grace period only log
+                        let grace_rules = components.rule_grace.as_deref().filter(|_| {
+                            waf_config.mode == WafMode::Block
+                                && matches!(action, WafAction::Block { .. })
+                        });
+                        let waived_rules = grace_rules.and_then(|grace| {
+                            let matched = tx.matched_rules();
+                            let interrupting = tx.interrupting_rule();
+                            grace
+                                .waives(interrupting.as_deref(), &matched, Utc::now())
+                                .then_some(matched)
+                        });
+                        let in_grace = waived_rules.is_some();
+
+                        match action {
+                            WafAction::Block { status }
+                                if waf_config.mode == WafMode::Block && !in_grace =>
+                            {
+                                info!(
+                                    client_ip = %ctx.client_ip,
+                                    uri = %ctx.uri,
+                                    status,
+                                    "request blocked by WAF"
+                                );
+                                ctx.block_reason = Some(BlockReason::Waf { status });
+                                self.metrics.requests_blocked.inc();
+                                if let Ok(addr) = ctx.client_ip.parse() {
+                                    components.ip_reputation.record_waf_trip(addr);
+                                }
                                 let code = StatusCode::from_u16(status)
-                                    .unwrap_or(StatusCode::FOUND);
-                                let mut resp =
-                                    ResponseHeader::build(code, Some(4)).unwrap();
-                                resp.insert_header("location", url).unwrap();
-                                session.set_keepalive(None);
-                                session
-                                    .write_response_header(Box::new(resp), false)
-                                    .await?;
-                                session
-                                    .write_response_body(None, true)
-                                    .await?;
+                                    .unwrap_or(StatusCode::FORBIDDEN);
+                                Self::send_block(
+                                    session,
+                                    code,
+                                    "waf-rule",
+                                    "Forbidden: WAF rule triggered",
+                                    None,
+                                )
+                                .await?;
                                 return Ok(true);
                             }
+                            WafAction::Block { status } if in_grace => {
+                                warn!(
+                                    client_ip = %ctx.client_ip,
+                                    uri = %ctx.uri,
+                                    status,
+                                    rules = ?waived_rules.unwrap_or_default(),
+                                    "WAF rule in its grace period triggered, not blocking"
+                                );
+                            }
+                            WafAction::Block { status } => {
+                                // Detect mode: log but don't block
+                                warn!(
+                                    client_ip = %ctx.client_ip,
+                                    uri = %ctx.uri,
+                                    status,
+                                    "WAF rule triggered (detect mode, not blocking)"
+                                );
+                            }
+                            WafAction::Redirect { status, ref url } => {
+                                if waf_config.mode == WafMode::Block {
+                                    let code = StatusCode::from_u16(status)
+                                        .unwrap_or(StatusCode::FOUND);
+                                    let mut resp =
+                                        ResponseHeader::build(code, Some(4)).unwrap();
+                                    resp.insert_header("location", url).unwrap();
+                                    session.set_keepalive(None);
+                                    session
+                                        .write_response_header(Box::new(resp), false)
+                                        .await?;
+                                    session
+                                        .write_response_body(None, true)
+                                        .await?;
+                                    return Ok(true);
+                                }
+                            }
+                            WafAction::Pass => {}
                         }
-                        WafAction::Pass => {}
-                    }
 
-                    ctx.waf_tx = Some(tx);
+                        // Kept for the body and response phases, which waive
+                        // the resurfacing interruption the same way
+                        ctx.waf_tx = Some(tx);
+                    }
                 }
             }
         }
@@ -728,11 +1274,201 @@ impl ProxyHttp for Layer7WafProxy {
         Ok(false) // continue to upstream
     }
 
+    /// Refuse the request with `status`: an RFC 7807 problem document for
+    /// clients that accept JSON, the plain-text `detail` otherwise.
+    async fn send_block(
+        session: &mut Session,
+        status: StatusCode,
+        kind: &str,
+        detail: &str,
+        retry_after: Option<u64>,
+    ) -> Result<()> {
+        let accept = session
+            .req_header()
+            .headers
+            .get("accept")
+            .and_then(|v| v.to_str().ok());
+        let block = block_response(status, kind, detail, retry_after, accept);
+
+        let mut resp = ResponseHeader::build(block.status, Some(4)).unwrap();
+        resp.insert_header("content-type", block.content_type).unwrap();
+        if let Some(secs) = block.retry_after {
+            resp.insert_header("retry-after", secs.to_string()).unwrap();
+        }
+        session.set_keepalive(None);
+        session
+            .write_response_header(Box::new(resp), false)
+            .await?;
+        session
+            .write_response_body(Some(Bytes::from(block.body)), true)
+            .await?;
+        Ok(())
+    }
+
+    /// Refuse a request on its GeoIP decision, counted under `reason`.
+    async fn send_geo_block(
+        &self,
+        session: &mut Session,
+        ctx: &mut RequestContext,
+        country: String,
+        reason: GeoBlockReason,
+    ) -> Result<()> {
+        ctx.block_reason = Some(BlockReason::GeoBlocked { country, reason });
+        self.metrics.geoip_blocked.inc();
+        self.metrics
+            .geoip_blocked_by_reason
+            .with_label_values(&[reason.as_str()])
+            .inc();
+        self.metrics.requests_blocked.inc();
+        let detail = match reason {
+            GeoBlockReason::UnknownCountry => "Forbidden: country unknown",
+            _ => "Forbidden: blocked by country",
+        };
+        Self::send_block(session, StatusCode::FORBIDDEN, "geo-blocked", detail, None).await
+    }
+
+    /// Serve a JS challenge: the HTML page for browsers, or a JSON
+    /// description of it for API/XHR clients.
+    async fn send_challenge(
+        session: &mut Session,
+        detector: &BotDetector,
+        client_ip: &str,
+        html: String,
+    ) -> Result<()> {
+        let headers = &session.req_header().headers;
+        let header = |name: &str| headers.get(name).and_then(|v| v.to_str().ok());
+        let challenge =
+            detector.challenge_response(client_ip, html, header("accept"), header("x-requested-with"));
+
+        let status = StatusCode::from_u16(challenge.status).unwrap_or(StatusCode::FORBIDDEN);
+        let mut resp = ResponseHeader::build(status, Some(4)).unwrap();
+        resp.insert_header("content-type", challenge.content_type)
+            .unwrap();
+        resp.insert_header("cache-control", "no-store").unwrap();
+        session.set_keepalive(None);
+        session
+            .write_response_header(Box::new(resp), false)
+            .await?;
+        session
+            .write_response_body(Some(Bytes::from(challenge.body)), true)
+            .await?;
+        Ok(())
+    }
+
+    /// Serve an anti-scraping CAPTCHA page.
+    async fn send_captcha(session: &mut Session, html: String) -> Result<()> {
+        let mut resp = ResponseHeader::build(StatusCode::OK, Some(4)).unwrap();
+        resp.insert_header("content-type", "text/html; charset=utf-8")
+            .unwrap();
+        resp.insert_header("cache-control", "no-store").unwrap();
+        session.set_keepalive(None);
+        session
+            .write_response_header(Box::new(resp), false)
+            .await?;
+        session
+            .write_response_body(Some(Bytes::from(html)), true)
+            .await?;
+        Ok(())
+    }
+
+    /// Serve the `server.unavailable_response` maintenance page.
+    async fn send_unavailable(
+        session: &mut Session,
+        page: &UnavailableResponseConfig,
+    ) -> Result<()> {
+        let status = StatusCode::from_u16(page.status).unwrap_or(StatusCode::SERVICE_UNAVAILABLE);
+        let mut resp = ResponseHeader::build(status, Some(4)).unwrap();
+        resp.insert_header("content-type", page.content_type.as_str())
+            .unwrap();
+        resp.insert_header("cache-control", "no-store").unwrap();
+        session.set_keepalive(None);
+        session
+            .write_response_header(Box::new(resp), false)
+            .await?;
+        session
+            .write_response_body(Some(Bytes::from(page.body.clone())), true)
+            .await?;
+        Ok(())
+    }
+
+    /// Send the maintenance page with its `Retry-After`.
+    async fn send_maintenance(session: &mut Session, page: &MaintenanceConfig) -> Result<()> {
+        let mut resp = ResponseHeader::build(StatusCode::SERVICE_UNAVAILABLE, Some(4)).unwrap();
+        resp.insert_header("content-type", page.content_type.as_str())
+            .unwrap();
+        resp.insert_header("retry-after", page.retry_after_secs.to_string())
+            .unwrap();
+        resp.insert_header("cache-control", "no-store").unwrap();
+        session.set_keepalive(None);
+        session
+            .write_response_header(Box::new(resp), false)
+            .await?;
+        session
+            .write_response_body(Some(Bytes::from(page.body.clone())), true)
+            .await?;
+        Ok(())
+    }
+
+    /// Send a 504 for a request whose deadline passed.
+    async fn respond_timeout(&self, session: &mut Session, ctx: &mut RequestContext) -> Result<()> {
+        warn!(uri = %ctx.uri, client_ip = %ctx.client_ip, "request timed out");
+        self.metrics.requests_timed_out.inc();
+        ctx.timed_out = true;
+        let mut resp = ResponseHeader::build(StatusCode::GATEWAY_TIMEOUT, Some(4)).unwrap();
+        resp.insert_header("content-type", "text/plain").unwrap();
+        session.set_keepalive(None);
+        session
+            .write_response_header(Box::new(resp), false)
+            .await?;
+        session
+            .write_response_body(Some(Bytes::from("Gateway Timeout\n")), true)
+            .await?;
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl ProxyHttp for Layer7WafProxy {
+    type CTX = RequestContext;
+
+    fn new_ctx(&self) -> Self::CTX {
+        RequestContext::new()
+    }
+
+    async fn request_filter(&self, session: &mut Session, ctx: &mut Self::CTX) -> Result<bool> {
+        let timeout_ms = self.config.read().unwrap().server.request_timeout_ms;
+        ctx.deadline = deadline::deadline_after(ctx.request_start, timeout_ms);
+
+        let answered =
+            match deadline::with_deadline(ctx.deadline, self.filter_request(session, ctx)).await {
+                Ok(result) => result?,
+                Err(DeadlineExceeded) => {
+                    self.respond_timeout(session, ctx).await?;
+                    true
+                }
+            };
+
+        // Only a request every header-phase check let through has its body
+        // read. One answered here keeps its body unread: reusing the
+        // connection would mean draining it, so it is closed instead.
+        if answered {
+            ctx.body_gate.close();
+            if has_request_body(&session.req_header().headers) {
+                session.set_keepalive(None);
+            }
+        } else {
+            ctx.body_gate.open();
+        }
+        Ok(answered)
+    }
+
     async fn upstream_peer(
         &self,
         _session: &mut Session,
         ctx: &mut Self::CTX,
     ) -> Result<Box<HttpPeer>> {
+        // Nothing here awaits, so the span can stay entered throughout
+        let _phase = tracing::info_span!(parent: &ctx.span, "upstream_peer").entered();
         let config = self.config.read().unwrap();
         let upstream_name = ctx
             .route_index
@@ -746,26 +1482,160 @@ impl ProxyHttp for Layer7WafProxy {
                     .unwrap_or("backend")
             });
 
-        let addr = self
-            .find_upstream(upstream_name)
-            .and_then(|u| u.select())
-            .ok_or_else(|| {
-                Error::new(ErrorType::ConnectProxyFailure)
-            })?;
+        let components = self.components.load();
+        let Some(addr) = components.select_upstream(upstream_name) else {
+            // Answered with `server.unavailable_response` by `fail_to_proxy`
+            warn!(upstream = upstream_name, "no upstream server available");
+            ctx.upstream_unavailable = true;
+            return Err(Error::explain(
+                ErrorType::ConnectProxyFailure,
+                "no upstream server available",
+            ));
+        };
 
         debug!(upstream = upstream_name, addr, "selected upstream peer");
+        ctx.span.record("upstream", upstream_name);
+        ctx.upstream = Some(upstream_name.to_string());
+        ctx.upstream_addr = Some(addr.to_string());
 
         // Parse addr into host:port
-        let peer = HttpPeer::new(addr, false, String::new());
+        let mut peer = HttpPeer::new(addr, false, String::new());
+
+        // The upstream exchange only gets what is left of the request deadline
+        if let Some(left) = deadline::remaining(ctx.deadline) {
+            if left.is_zero() {
+                self.metrics.requests_timed_out.inc();
+                ctx.timed_out = true;
+                return Err(Error::explain(
+                    ErrorType::HTTPStatus(504),
+                    "request deadline exceeded before upstream",
+                ));
+            }
+            peer.options.total_connection_timeout = Some(left);
+            peer.options.read_timeout = Some(left);
+            peer.options.write_timeout = Some(left);
+        }
         Ok(Box::new(peer))
     }
 
+    async fn request_body_filter(
+        &self,
+        _session: &mut Session,
+        body: &mut Option<Bytes>,
+        end_of_stream: bool,
+        ctx: &mut Self::CTX,
+    ) -> Result<()>
+    where
+        Self::CTX: Send + Sync,
+    {
+        if !ctx.body_gate.admit(body) {
+            return Err(Error::explain(
+                ErrorType::InternalError,
+                "request body read before header-phase checks passed",
+            ));
+        }
+
+        // WAF request body phase, scanned within the shared body budget
+        // before any of the body reaches the upstream
+        if let Some(ref tx) = ctx.waf_tx {
+            let (limit, mode) = {
+                let config = self.config.read().unwrap();
+                let route = ctx.route_index.and_then(|i| config.routes.get(i));
+                (config.waf.request_body_limit, route.map(|r| r.waf.mode))
+            };
+            if ctx.body_scan.hold(body, end_of_stream, limit) {
+                let components = self.components.load_full();
+                let scanned = ctx.body_scan.scanned_part(limit);
+                let action = tx
+                    .process_request_body_within(scanned, &components.waf_body_budget)
+                    .await;
+
+                // A blocked body is never sent on, and stays held for the
+                // audit entry's preview
+                if let Some(WafAction::Block { status }) = action {
+                    let in_grace = components.rule_grace.as_deref().is_some_and(|grace| {
+                        let interrupting = tx.interrupting_rule();
+                        grace.waives(interrupting.as_deref(), &tx.matched_rules(), Utc::now())
+                    });
+                    if mode == Some(WafMode::Block) && !in_grace {
+                        info!(
+                            client_ip = %ctx.client_ip,
+                            uri = %ctx.uri,
+                            status,
+                            "request body blocked by WAF"
+                        );
+                        ctx.block_reason = Some(BlockReason::Waf { status });
+                        self.metrics.requests_blocked.inc();
+                        if let Ok(addr) = ctx.client_ip.parse() {
+                            components.ip_reputation.record_waf_trip(addr);
+                        }
+                        return Err(Error::explain(
+                            ErrorType::HTTPStatus(status),
+                            "request body blocked by WAF",
+                        ));
+                    }
+                    warn!(
+                        client_ip = %ctx.client_ip,
+                        uri = %ctx.uri,
+                        status,
+                        "WAF rule triggered on request body, not blocking"
+                    );
+                }
+                ctx.body_scan.release(body);
+            }
+        }
+        self.metrics
+            .upstream_bytes
+            .chunk_sent(ctx.upstream.as_deref(), body);
+        Ok(())
+    }
+
     async fn upstream_request_filter(
         &self,
         _session: &mut Session,
         upstream_request: &mut RequestHeader,
         ctx: &mut Self::CTX,
     ) -> Result<()> {
+        // Filter client headers before adding our own trusted ones
+        {
+            let config = self.config.read().unwrap();
+            if let Some(route) = ctx.route_index.and_then(|i| config.routes.get(i)) {
+                for name in headers_to_strip(
+                    &upstream_request.headers,
+                    route.forward_headers_policy,
+                    &route.forward_headers,
+                ) {
+                    upstream_request.remove_header(&name);
+                }
+            }
+        }
+
+        // Trust score for the backend's own decisions. A value the client
+        // sent is never passed on, whether or not one is emitted
+        upstream_request.remove_header(TRUST_SCORE_HEADER);
+        if self.config.read().unwrap().server.emit_trust_score {
+            let components = self.components.load();
+            let client_key = ctx.client_key();
+            let signals = TrustSignals {
+                bot_score: ctx.bot_score.or_else(|| {
+                    let detector = components.bot_detector.as_ref()?;
+                    detector.session_score(client_key?)
+                }),
+                scraping_score: ctx.scraping_score.or_else(|| {
+                    let scraper = components.anti_scraper.as_ref()?;
+                    scraper.session_score(client_key?)
+                }),
+                ip_reputation_score: ctx.ip_reputation_score,
+                allowlisted: ctx.ip_allowlisted,
+                client_known: client_key.is_some(),
+            };
+            if let Some(score) = trust_score(&signals) {
+                upstream_request
+                    .insert_header(TRUST_SCORE_HEADER, score.to_string())
+                    .unwrap();
+            }
+        }
+
         // Add X-Forwarded-For header
         if !ctx.client_ip.is_empty() {
             upstream_request
@@ -788,25 +1658,33 @@ impl ProxyHttp for Layer7WafProxy {
     where
         Self::CTX: Send + Sync,
     {
+        // Nothing here awaits, so the span can stay entered throughout
+        let _phase = tracing::info_span!(parent: &ctx.span, "response_filter").entered();
         ctx.response_status = upstream_response.status.as_u16();
 
         // WAF response phase check
         if let Some(ref tx) = ctx.waf_tx {
-            let headers: Vec<(String, String)> = upstream_response
-                .headers
-                .iter()
-                .map(|(k, v)| {
-                    (
-                        k.as_str().to_string(),
-                        v.to_str().unwrap_or("").to_string(),
-                    )
-                })
-                .collect();
+            let headers = collect_headers(&upstream_response.headers).pairs;
 
             let action =
                 tx.process_response_headers(upstream_response.status.as_u16(), &headers);
 
+            // A request-phase match waived for its grace period resurfaces here
+            let in_grace = matches!(action, WafAction::Block { .. })
+                && self.components.load().rule_grace.as_deref().is_some_and(|grace| {
+                    let interrupting = tx.interrupting_rule();
+                    grace.waives(interrupting.as_deref(), &tx.matched_rules(), Utc::now())
+                });
+
             match action {
+                WafAction::Block { status } if in_grace => {
+                    warn!(
+                        client_ip = %ctx.client_ip,
+                        uri = %ctx.uri,
+                        status,
+                        "WAF rule in its grace period triggered on response, not blocking"
+                    );
+                }
                 WafAction::Block { status } => {
                     warn!(
                         client_ip = %ctx.client_ip,
@@ -816,31 +1694,83 @@ impl ProxyHttp for Layer7WafProxy {
                     );
                     ctx.block_reason = Some(BlockReason::Waf { status });
                     self.metrics.requests_blocked.inc();
+                    if let Ok(addr) = ctx.client_ip.parse() {
+                        self.components.load().ip_reputation.record_waf_trip(addr);
+                    }
                 }
                 _ => {}
             }
         }
 
-        // Anti-scraping: check if we need to process the response body
-        if self.anti_scraper.is_some() {
-            if let Some(ct) = upstream_response.headers.get("content-type") {
-                let ct_str = ct.to_str().unwrap_or("");
-                if ct_str.contains("text/html") {
-                    ctx.should_process_response = true;
-                    ctx.response_content_type = Some(ct_str.to_string());
-                    // Remove Content-Length since we'll modify the body
-                    upstream_response.remove_header("content-length");
+        // Security headers, applied per the matched route's mode
+        {
+            let config = self.config.read().unwrap();
+            if config.security_headers.enabled {
+                let mode = ctx
+                    .route_index
+                    .and_then(|i| config.routes.get(i))
+                    .map(|r| r.security_headers_mode)
+                    .unwrap_or(SecurityHeadersMode::Replace);
+                for (name, value) in
+                    headers_to_set(&upstream_response.headers, &config.security_headers.headers, mode)
+                {
+                    if let Err(e) = upstream_response.insert_header(name.to_string(), value) {
+                        warn!(header = name, error = %e, "failed to set security header");
+                    }
+                }
+            }
+
+            // Backend software fingerprints
+            for edit in fingerprint_header_edits(
+                &upstream_response.headers,
+                &config.security_headers.strip_response_headers,
+                config.security_headers.server_header_override.as_deref(),
+            ) {
+                match edit {
+                    HeaderEdit::Remove(name) => {
+                        upstream_response.remove_header(name);
+                    }
+                    HeaderEdit::Set(name, value) => {
+                        if let Err(e) = upstream_response.insert_header(name.to_string(), value) {
+                            warn!(header = name, error = %e, "failed to set Server header");
+                        }
+                    }
                 }
             }
         }
 
-        // Add security headers
-        upstream_response
-            .insert_header("x-content-type-options", "nosniff")
-            .unwrap();
-        upstream_response
-            .insert_header("x-frame-options", "DENY")
-            .unwrap();
+        // Anti-scraping: check if we need to process the response body.
+        // Types in never_buffer_content_types always stream through.
+        if !ctx.health_probe && self.components.load().anti_scraper.is_some() {
+            let content_type = upstream_response
+                .headers
+                .get("content-type")
+                .and_then(|v| v.to_str().ok())
+                .map(str::to_string);
+            let buffer = {
+                let config = self.config.read().unwrap();
+                buffer_for_rewrite(
+                    content_type.as_deref(),
+                    &config.server.never_buffer_content_types,
+                )
+            };
+            if buffer {
+                ctx.should_process_response = true;
+                ctx.response_content_type = content_type;
+                // Remove Content-Length since we'll modify the body
+                upstream_response.remove_header("content-length");
+            }
+        }
+
+        // Add security headers; gRPC responses are left as the backend framed them
+        if !is_grpc_response(&upstream_response.headers) {
+            upstream_response
+                .insert_header("x-content-type-options", "nosniff")
+                .unwrap();
+            upstream_response
+                .insert_header("x-frame-options", "DENY")
+                .unwrap();
+        }
 
         Ok(())
     }
@@ -851,7 +1781,12 @@ impl ProxyHttp for Layer7WafProxy {
         body: &mut Option<Bytes>,
         end_of_stream: bool,
         ctx: &mut Self::CTX,
-    ) -> Result<Option<std::time::Duration>> {
+    ) -> Result<Option<Duration>> {
+        // Counted as received, before any rewriting below
+        self.metrics
+            .upstream_bytes
+            .chunk_received(ctx.upstream.as_deref(), body);
+
         if !ctx.should_process_response {
             return Ok(None);
         }
@@ -867,11 +1802,15 @@ impl ProxyHttp for Layer7WafProxy {
         }
 
         if end_of_stream {
-            if let Some(ref anti_scraper) = self.anti_scraper {
+            if let Some(ref anti_scraper) = self.components.load().anti_scraper {
                 let ct = ctx.response_content_type.as_deref();
-                if let Some(modified) =
-                    anti_scraper.process_response(&ctx.client_ip, ct, &ctx.response_body_buffer)
-                {
+                let trap_path_prefix = self.route_trap_path_prefix(ctx.route_index);
+                if let Some(modified) = anti_scraper.process_response(
+                    &ctx.client_ip,
+                    ct,
+                    &ctx.response_body_buffer,
+                    trap_path_prefix.as_deref(),
+                ) {
                     self.metrics.responses_obfuscated.inc();
                     *body = Some(Bytes::from(modified));
                     ctx.response_body_buffer.clear();
@@ -888,7 +1827,84 @@ impl ProxyHttp for Layer7WafProxy {
         Ok(None)
     }
 
-    async fn logging(&self, _session: &mut Session, _error: Option<&pingora_core::Error>, ctx: &mut Self::CTX) {
+    fn fail_to_connect(
+        &self,
+        _session: &mut Session,
+        _peer: &HttpPeer,
+        ctx: &mut Self::CTX,
+        e: Box<Error>,
+    ) -> Box<Error> {
+        // Send the next requests to the upstream's other servers for a while
+        if let (Some(upstream), Some(addr)) = (&ctx.upstream, &ctx.upstream_addr) {
+            warn!(upstream, addr, error = %e, "upstream connection failed, marking server down");
+            self.components.load().mark_upstream_down(upstream, addr);
+        }
+        e
+    }
+
+    async fn fail_to_proxy(
+        &self,
+        session: &mut Session,
+        e: &Error,
+        ctx: &mut Self::CTX,
+    ) -> FailToProxy
+    where
+        Self::CTX: Send + Sync,
+    {
+        let code = if ctx.upstream_unavailable {
+            let page = self.config.read().unwrap().server.unavailable_response.clone();
+            if let Err(e) = Self::send_unavailable(session, &page).await {
+                error!(error = %e, "failed to send unavailable response");
+            }
+            page.status
+        } else {
+            // Pingora's default handling, except that an upstream that
+            // timed out (the upstream timeouts carry what was left of the
+            // request deadline) is a 504 rather than a 502
+            let code = match e.etype() {
+                ErrorType::HTTPStatus(code) => *code,
+                ErrorType::ConnectTimedout | ErrorType::ReadTimedout | ErrorType::WriteTimedout
+                    if matches!(e.esource(), ErrorSource::Upstream) =>
+                {
+                    warn!(
+                        uri = %ctx.uri,
+                        client_ip = %ctx.client_ip,
+                        error = %e,
+                        "upstream timed out"
+                    );
+                    self.metrics.requests_timed_out.inc();
+                    ctx.timed_out = true;
+                    504
+                }
+                _ => match e.esource() {
+                    ErrorSource::Upstream => 502,
+                    ErrorSource::Downstream => match e.etype() {
+                        ErrorType::WriteError
+                        | ErrorType::ReadError
+                        | ErrorType::ConnectionClosed => 0,
+                        _ => 400,
+                    },
+                    ErrorSource::Internal | ErrorSource::Unset => 500,
+                },
+            };
+            if code > 0 {
+                if let Err(e) = session.respond_error(code).await {
+                    error!(error = %e, "failed to send error response");
+                }
+            }
+            code
+        };
+        FailToProxy {
+            error_code: code,
+            can_reuse_downstream: false,
+        }
+    }
+
+    async fn logging(&self, session: &mut Session, _error: Option<&pingora_core::Error>, ctx: &mut Self::CTX) {
+        if ctx.health_probe {
+            return;
+        }
+
         let duration = ctx.request_start.elapsed();
         let duration_secs = duration.as_secs_f64();
 
@@ -905,51 +1921,85 @@ impl ProxyHttp for Layer7WafProxy {
             .with_label_values(&[&upstream_label])
             .observe(duration_secs);
 
-        // Structured log
-        let blocked = ctx.block_reason.is_some();
-        info!(
-            client_ip = %ctx.client_ip,
-            method = %ctx.method,
-            uri = %ctx.uri,
-            status = ctx.response_status,
-            duration_ms = duration.as_millis() as u64,
-            blocked,
-            block_reason = ?ctx.block_reason,
-            geo_country = ?ctx.geo_country,
-            "request completed"
-        );
+        // What the client got, blocks included: those are answered directly,
+        // without an upstream response
+        if let Some(resp) = session.response_written() {
+            ctx.response_status = resp.status.as_u16();
+        }
+        let status = ctx.response_status;
+        let components = self.components.load();
 
-        // Clean up WAF transaction (Drop will handle it)
-        ctx.waf_tx.take();
-    }
-}
+        // Access log: a structured event, or a Common/Combined Log Format line
+        if components.access_log.format() == AccessLogFormat::Json {
+            let blocked = ctx.block_reason.is_some();
+            info!(
+                client_ip = %ctx.client_ip,
+                method = %ctx.method,
+                uri = %ctx.uri,
+                status,
+                duration_ms = duration.as_millis() as u64,
+                blocked,
+                block_reason = ?ctx.block_reason,
+                timed_out = ctx.timed_out,
+                geo_country = ?ctx.geo_country,
+                "request completed"
+            );
+        } else {
+            ctx.response_bytes = session.body_bytes_sent() as u64;
+            components.access_log.write(ctx);
+        }
 
-/// Build WAF directives string from config rule glob patterns.
-fn build_waf_directives(config: &AppConfig) -> String {
-    let mut directives = String::new();
+        if status != 0 {
+            self.metrics.responses_by_status.record(status);
+            if let Some(alert) = &components.error_rate_alert {
+                if let Some(rate) = alert.record(status) {
+                    let window_secs = alert.window().as_secs();
+                    warn!(rate, window_secs, "5xx response rate over the alert threshold");
+                }
+            }
+        }
 
-    // Add SecRuleEngine
-    directives.push_str("SecRuleEngine On\n");
+        if let Some(capture) = components.capture.as_ref().filter(|c| c.sampled()) {
+            let header_names = session.req_header().headers.keys().map(|n| n.as_str());
+            capture.write(CapturedRequest::from_context(ctx, header_names, status));
+        }
 
-    // Expand glob patterns and include rule files
-    for pattern in &config.waf.rules {
-        match glob::glob(pattern) {
-            Ok(paths) => {
-                for entry in paths.flatten() {
-                    directives.push_str(&format!("Include {}\n", entry.display()));
+        // Blocks, and with `log_allowlisted` the allowlisted requests that
+        // skipped every check, leave an audit trail
+        let log_allowlisted = self.config.read().unwrap().ip_reputation.log_allowlisted;
+        let audit_action = ctx.audit_action(log_allowlisted);
+        if ctx.block_reason.is_none() && audit_action.is_some() {
+            self.metrics.requests_allowlisted.inc();
+        }
+        if let (Some(state), Some(action)) = (&self.admin_state, audit_action) {
+            let mut entry =
+                AuditLogEntry::new(&ctx.client_ip, &ctx.method, &ctx.uri, &action, status);
+
+            let preview_bytes = self.config.read().unwrap().waf.audit_log.body_preview_bytes;
+            if preview_bytes > 0 && ctx.block_reason.is_some() {
+                // Request line and headers, then the body if it was blocked
+                // on; a request blocked earlier never had its body read.
+                let mut raw = format!("{} {}\r\n", ctx.method, ctx.uri).into_bytes();
+                for (name, value) in session.req_header().headers.iter() {
+                    raw.extend_from_slice(name.as_str().as_bytes());
+                    raw.extend_from_slice(b": ");
+                    raw.extend_from_slice(value.as_bytes());
+                    raw.extend_from_slice(b"\r\n");
                 }
+                let held = ctx.body_scan.held();
+                if !held.is_empty() {
+                    raw.extend_from_slice(b"\r\n");
+                    raw.extend_from_slice(&held[..held.len().min(preview_bytes)]);
+                }
+                entry.body_preview = body_preview(&raw, preview_bytes);
             }
-            Err(e) => {
-                warn!(pattern = %pattern, error = %e, "invalid rule glob pattern");
-            }
+            state.push_audit_entry(entry);
         }
-    }
 
-    // Set request body limit
-    directives.push_str(&format!(
-        "SecRequestBodyLimit {}\n",
-        config.waf.request_body_limit
-    ));
+        let block_reason = ctx.block_reason.as_ref().map(|r| format!("{:?}", r));
+        telemetry::record_outcome(&ctx.span, block_reason.as_deref(), ctx.response_status, duration);
 
-    directives
+        // Clean up WAF transaction (Drop will handle it)
+        ctx.waf_tx.take();
+    }
 }
@@ -0,0 +1,196 @@
+use std::collections::VecDeque;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use layer7waf_common::ErrorRateAlertConfig;
+use prometheus::{IntCounterVec, Opts, Registry};
+
+/// Responses sent to clients by status class, to spot upstream error surges.
+#[derive(Clone)]
+pub struct ResponseStatusMetrics {
+    pub responses_by_status: IntCounterVec,
+}
+
+impl ResponseStatusMetrics {
+    pub fn new() -> Self {
+        Self {
+            responses_by_status: IntCounterVec::new(
+                Opts::new(
+                    "layer7waf_responses_by_status_total",
+                    "Responses sent to clients by status class",
+                ),
+                &["class"],
+            )
+            .unwrap(),
+        }
+    }
+
+    /// Register the counter with `registry`. May be called for several
+    /// registries; they all observe the same values.
+    pub fn register(&self, registry: &Registry) -> prometheus::Result<()> {
+        registry.register(Box::new(self.responses_by_status.clone()))
+    }
+
+    /// Count a response with `status`.
+    pub fn record(&self, status: u16) {
+        self.responses_by_status
+            .with_label_values(&[status_class(status)])
+            .inc();
+    }
+}
+
+impl Default for ResponseStatusMetrics {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// `2xx`-style label for `status`.
+pub fn status_class(status: u16) -> &'static str {
+    match status {
+        100..=199 => "1xx",
+        200..=299 => "2xx",
+        300..=399 => "3xx",
+        400..=499 => "4xx",
+        500..=599 => "5xx",
+        _ => "other",
+    }
+}
+
+/// Watches the share of 5xx responses over a sliding window, reporting
+/// when it goes over `server.error_rate_alert.threshold`.
+pub struct ErrorRateAlert {
+    window_secs: u64,
+    threshold: f64,
+    min_responses: u64,
+    origin: Instant,
+    state: Mutex<ErrorWindow>,
+}
+
+#[derive(Default)]
+struct ErrorWindow {
+    /// Per-second (second since `origin`, responses, 5xx responses), oldest
+    /// first.
+    seconds: VecDeque<(u64, u64, u64)>,
+    /// When the rate was last reported; reported at most once per window.
+    alerted_at: Option<u64>,
+}
+
+impl ErrorRateAlert {
+    pub fn new(config: &ErrorRateAlertConfig) -> Self {
+        Self {
+            window_secs: config.window_secs.max(1),
+            threshold: config.threshold,
+            min_responses: config.min_responses,
+            origin: Instant::now(),
+            state: Mutex::new(ErrorWindow::default()),
+        }
+    }
+
+    /// Count a response with `status`, returning the window's 5xx rate when
+    /// it has just crossed the threshold.
+    pub fn record(&self, status: u16) -> Option<f64> {
+        self.record_at(status, Instant::now())
+    }
+
+    fn record_at(&self, status: u16, now: Instant) -> Option<f64> {
+        let second = now.duration_since(self.origin).as_secs();
+        let error = u64::from((500..=599).contains(&status));
+        let mut state = self.state.lock().unwrap();
+
+        match state.seconds.back_mut() {
+            Some((s, total, errors)) if *s == second => {
+                *total += 1;
+                *errors += error;
+            }
+            _ => state.seconds.push_back((second, 1, error)),
+        }
+        while state
+            .seconds
+            .front()
+            .is_some_and(|(s, _, _)| s + self.window_secs <= second)
+        {
+            state.seconds.pop_front();
+        }
+
+        let (total, errors) = state
+            .seconds
+            .iter()
+            .fold((0, 0), |(total, errors), (_, t, e)| (total + t, errors + e));
+        let rate = errors as f64 / total as f64;
+        let quiet = state
+            .alerted_at
+            .is_some_and(|at| at + self.window_secs > second);
+        if total < self.min_responses || rate <= self.threshold || quiet {
+            return None;
+        }
+        state.alerted_at = Some(second);
+        Some(rate)
+    }
+
+    pub fn window(&self) -> Duration {
+        Duration::from_secs(self.window_secs)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_responses_counted_by_class() {
+        let metrics = ResponseStatusMetrics::new();
+        for status in [200, 204, 301, 404, 403, 502, 503, 504] {
+            metrics.record(status);
+        }
+        let count = |class: &str| metrics.responses_by_status.with_label_values(&[class]).get();
+        assert_eq!(count("2xx"), 2);
+        assert_eq!(count("3xx"), 1);
+        assert_eq!(count("4xx"), 2);
+        assert_eq!(count("5xx"), 3);
+        assert_eq!(count("1xx"), 0);
+    }
+
+    fn alert() -> ErrorRateAlert {
+        ErrorRateAlert::new(&ErrorRateAlertConfig {
+            enabled: true,
+            window_secs: 10,
+            threshold: 0.2,
+            min_responses: 10,
+        })
+    }
+
+    #[test]
+    fn test_error_surge_alerts_once_per_window() {
+        let alert = alert();
+        let at = |secs| alert.origin + Duration::from_secs(secs);
+
+        // 1 in 10 failing stays quiet
+        for i in 0..10 {
+            let status = if i == 0 { 500 } else { 200 };
+            assert_eq!(alert.record_at(status, at(0)), None);
+        }
+        // A surge crosses 20% and is reported once
+        let reported: Vec<f64> = (0..10).filter_map(|_| alert.record_at(502, at(1))).collect();
+        assert_eq!(reported.len(), 1);
+        assert!(reported[0] > 0.2);
+        assert_eq!(alert.record_at(502, at(5)), None);
+
+        // Still failing a window later: reported again
+        let reported = (0..10).filter_map(|_| alert.record_at(502, at(12))).count();
+        assert_eq!(reported, 1);
+    }
+
+    #[test]
+    fn test_few_responses_or_old_errors_not_reported() {
+        let alert = alert();
+        let at = |secs| alert.origin + Duration::from_secs(secs);
+        for _ in 0..5 {
+            assert_eq!(alert.record_at(500, at(0)), None);
+        }
+        // The errors have left the window by the time traffic picks up
+        for _ in 0..20 {
+            assert_eq!(alert.record_at(200, at(30)), None);
+        }
+    }
+}
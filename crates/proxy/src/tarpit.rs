@@ -0,0 +1,136 @@
+use prometheus::{IntCounter, IntGauge, Registry};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+/// Holds rate-limited requests for `rate_limit.tarpit_ms` before their 429
+/// goes out, so an attacker's retries are paced by us rather than by them.
+///
+/// Each held request keeps its connection open, so at most
+/// `rate_limit.tarpit_max_concurrent` are held at once; the rest are
+/// answered immediately.
+#[derive(Clone)]
+pub struct Tarpit {
+    held: Arc<AtomicUsize>,
+    pub held_gauge: IntGauge,
+    pub tarpitted: IntCounter,
+}
+
+/// One held request's place in the tarpit, given up when dropped.
+pub struct TarpitSlot {
+    held: Arc<AtomicUsize>,
+    gauge: IntGauge,
+}
+
+impl Drop for TarpitSlot {
+    fn drop(&mut self) {
+        self.held.fetch_sub(1, Ordering::AcqRel);
+        self.gauge.dec();
+    }
+}
+
+impl Tarpit {
+    pub fn new() -> Self {
+        Self {
+            held: Arc::new(AtomicUsize::new(0)),
+            held_gauge: IntGauge::new(
+                "layer7waf_tarpit_held",
+                "Rate-limited requests currently held before their 429",
+            )
+            .unwrap(),
+            tarpitted: IntCounter::new(
+                "layer7waf_tarpit_requests_total",
+                "Rate-limited requests held before their 429",
+            )
+            .unwrap(),
+        }
+    }
+
+    /// Register the metrics with `registry`. May be called for several
+    /// registries; they all observe the same values.
+    pub fn register(&self, registry: &Registry) -> prometheus::Result<()> {
+        registry.register(Box::new(self.held_gauge.clone()))?;
+        registry.register(Box::new(self.tarpitted.clone()))?;
+        Ok(())
+    }
+
+    /// Take a place in the tarpit if fewer than `max_concurrent` requests
+    /// are held. `None` means the 429 should go out now.
+    pub fn enter(&self, max_concurrent: usize) -> Option<TarpitSlot> {
+        let claimed = self
+            .held
+            .fetch_update(Ordering::AcqRel, Ordering::Acquire, |held| {
+                (held < max_concurrent).then_some(held + 1)
+            })
+            .is_ok();
+        if !claimed {
+            return None;
+        }
+        self.held_gauge.inc();
+        self.tarpitted.inc();
+        Some(TarpitSlot {
+            held: self.held.clone(),
+            gauge: self.held_gauge.clone(),
+        })
+    }
+
+    /// Wait `delay` before a rate-limited request is answered, unless it is
+    /// zero or the tarpit is full. Returns whether the request was held.
+    pub async fn hold(&self, delay: Duration, max_concurrent: usize) -> bool {
+        if delay.is_zero() {
+            return false;
+        }
+        let Some(_slot) = self.enter(max_concurrent) else {
+            return false;
+        };
+        tokio::time::sleep(delay).await;
+        true
+    }
+
+    pub fn held(&self) -> usize {
+        self.held.load(Ordering::Acquire)
+    }
+}
+
+impl Default for Tarpit {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Instant;
+
+    #[tokio::test]
+    async fn test_rate_limited_response_delayed() {
+        let tarpit = Tarpit::new();
+        let start = Instant::now();
+        assert!(tarpit.hold(Duration::from_millis(50), 10).await);
+        assert!(start.elapsed() >= Duration::from_millis(50));
+        assert_eq!(tarpit.held(), 0);
+        assert_eq!(tarpit.tarpitted.get(), 1);
+
+        // No delay configured: answered at once
+        assert!(!tarpit.hold(Duration::ZERO, 10).await);
+        assert_eq!(tarpit.tarpitted.get(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_full_tarpit_answers_immediately() {
+        let tarpit = Tarpit::new();
+        let slots: Vec<TarpitSlot> = (0..3).filter_map(|_| tarpit.enter(3)).collect();
+        assert_eq!(slots.len(), 3);
+        assert_eq!(tarpit.held_gauge.get(), 3);
+
+        let start = Instant::now();
+        assert!(!tarpit.hold(Duration::from_secs(5), 3).await);
+        assert!(start.elapsed() < Duration::from_secs(1));
+
+        // A released place is free for the next request
+        drop(slots);
+        assert_eq!(tarpit.held(), 0);
+        assert!(tarpit.enter(3).is_some());
+    }
+}
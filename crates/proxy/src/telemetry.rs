@@ -0,0 +1,118 @@
+use std::time::Duration;
+use tracing::field::Empty;
+use tracing::Span;
+
+/// Open the span covering one proxied request. The remaining fields are
+/// recorded as the request moves through the pipeline.
+pub fn request_span(method: &str, uri: &str) -> Span {
+    tracing::info_span!(
+        "proxy_request",
+        http.method = %method,
+        http.uri = %uri,
+        route = Empty,
+        upstream = Empty,
+        block_reason = Empty,
+        http.status_code = Empty,
+        duration_ms = Empty,
+    )
+}
+
+/// Record the final outcome of a request on its span.
+pub fn record_outcome(
+    span: &Span,
+    block_reason: Option<&str>,
+    status: u16,
+    duration: Duration,
+) {
+    if let Some(reason) = block_reason {
+        span.record("block_reason", reason);
+    }
+    span.record("http.status_code", i64::from(status));
+    span.record("duration_ms", duration.as_secs_f64() * 1000.0);
+}
+
+/// OTLP export of request spans, enabled with the `otel` feature.
+#[cfg(feature = "otel")]
+pub mod otel {
+    use layer7waf_common::TracingConfig;
+    use opentelemetry::trace::TracerProvider as _;
+    use opentelemetry_otlp::{SpanExporter, WithExportConfig};
+    use opentelemetry_sdk::trace::{Sampler, SdkTracerProvider};
+    use opentelemetry_sdk::Resource;
+    use tracing::Subscriber;
+    use tracing_subscriber::registry::LookupSpan;
+    use tracing_subscriber::Layer;
+
+    /// Build a tracer provider exporting spans over OTLP/HTTP to the
+    /// configured endpoint, sampling `sample_rate` of new traces.
+    pub fn init_provider(config: &TracingConfig) -> anyhow::Result<SdkTracerProvider> {
+        let exporter = SpanExporter::builder()
+            .with_http()
+            .with_endpoint(&config.endpoint)
+            .build()?;
+
+        Ok(SdkTracerProvider::builder()
+            .with_batch_exporter(exporter)
+            .with_sampler(Sampler::ParentBased(Box::new(Sampler::TraceIdRatioBased(
+                config.sample_rate,
+            ))))
+            .with_resource(
+                Resource::builder()
+                    .with_service_name(config.service_name.clone())
+                    .build(),
+            )
+            .build())
+    }
+
+    /// A `tracing` layer forwarding spans to `provider`.
+    pub fn layer<S>(provider: &SdkTracerProvider, service_name: &str) -> impl Layer<S>
+    where
+        S: Subscriber + for<'span> LookupSpan<'span>,
+    {
+        tracing_opentelemetry::layer().with_tracer(provider.tracer(service_name.to_string()))
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::super::{record_outcome, request_span};
+        use super::*;
+        use opentelemetry::Value;
+        use opentelemetry_sdk::trace::InMemorySpanExporter;
+        use std::time::Duration;
+        use tracing_subscriber::layer::SubscriberExt;
+
+        #[test]
+        fn test_request_span_attributes_exported() {
+            let exporter = InMemorySpanExporter::default();
+            let provider = SdkTracerProvider::builder()
+                .with_simple_exporter(exporter.clone())
+                .build();
+            let subscriber = tracing_subscriber::registry().with(layer(&provider, "test"));
+
+            tracing::subscriber::with_default(subscriber, || {
+                let span = request_span("GET", "/login");
+                span.record("route", "/login");
+                span.record("upstream", "backend");
+                record_outcome(&span, Some("RateLimit"), 429, Duration::from_millis(5));
+            });
+            provider.force_flush().unwrap();
+
+            let spans = exporter.get_finished_spans().unwrap();
+            assert_eq!(spans.len(), 1);
+            assert_eq!(spans[0].name, "proxy_request");
+
+            let attr = |key: &str| {
+                spans[0]
+                    .attributes
+                    .iter()
+                    .find(|kv| kv.key.as_str() == key)
+                    .map(|kv| kv.value.clone())
+            };
+            assert_eq!(attr("route"), Some(Value::from("/login")));
+            assert_eq!(attr("upstream"), Some(Value::from("backend")));
+            assert_eq!(attr("block_reason"), Some(Value::from("RateLimit")));
+            assert_eq!(attr("http.status_code"), Some(Value::I64(429)));
+            assert!(attr("duration_ms").is_some());
+        }
+    }
+}
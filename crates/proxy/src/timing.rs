@@ -0,0 +1,133 @@
+use prometheus::{HistogramOpts, HistogramVec, Registry};
+use std::time::Instant;
+
+/// A protection stage of `request_filter` whose decision time is measured.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Stage {
+    IpReputation,
+    GeoIp,
+    RateLimit,
+    BotDetection,
+    AntiScraping,
+    Waf,
+}
+
+impl Stage {
+    /// Label value used in the `subsystem` dimension.
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Stage::IpReputation => "ip_reputation",
+            Stage::GeoIp => "geoip",
+            Stage::RateLimit => "rate_limit",
+            Stage::BotDetection => "bot_detection",
+            Stage::AntiScraping => "anti_scraping",
+            Stage::Waf => "waf",
+        }
+    }
+}
+
+/// Decision latency per protection stage, enabled by
+/// `server.subsystem_timing`. Only the decision itself is timed, not writing
+/// the resulting block or challenge response.
+#[derive(Clone)]
+pub struct SubsystemTimings {
+    pub duration: HistogramVec,
+}
+
+impl SubsystemTimings {
+    pub fn new() -> Self {
+        let duration = HistogramVec::new(
+            HistogramOpts::new(
+                "layer7waf_subsystem_duration_seconds",
+                "Time spent deciding on a request, by protection subsystem",
+            )
+            .buckets(vec![
+                0.00001, 0.00005, 0.0001, 0.00025, 0.0005, 0.001, 0.0025, 0.005, 0.01, 0.05,
+            ]),
+            &["subsystem"],
+        )
+        .expect("valid subsystem duration histogram");
+        Self { duration }
+    }
+
+    /// Register the histogram with `registry`. May be called for several
+    /// registries; they all observe the same values.
+    pub fn register(&self, registry: &Registry) -> prometheus::Result<()> {
+        registry.register(Box::new(self.duration.clone()))
+    }
+
+    /// Run `decide`, recording how long it took under `stage` when `enabled`.
+    pub fn time<T>(&self, stage: Stage, enabled: bool, decide: impl FnOnce() -> T) -> T {
+        if !enabled {
+            return decide();
+        }
+        let start = Instant::now();
+        let result = decide();
+        self.duration
+            .with_label_values(&[stage.as_str()])
+            .observe(start.elapsed().as_secs_f64());
+        result
+    }
+}
+
+impl Default for SubsystemTimings {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ALL_STAGES: [Stage; 6] = [
+        Stage::IpReputation,
+        Stage::GeoIp,
+        Stage::RateLimit,
+        Stage::BotDetection,
+        Stage::AntiScraping,
+        Stage::Waf,
+    ];
+
+    fn samples(timings: &SubsystemTimings, stage: Stage) -> u64 {
+        timings
+            .duration
+            .with_label_values(&[stage.as_str()])
+            .get_sample_count()
+    }
+
+    #[test]
+    fn test_each_enabled_stage_observed() {
+        let timings = SubsystemTimings::new();
+        for (i, stage) in ALL_STAGES.into_iter().enumerate() {
+            assert_eq!(timings.time(stage, true, || i * 2), i * 2);
+        }
+        timings.time(Stage::Waf, true, || ());
+
+        for stage in ALL_STAGES {
+            let expected = if stage == Stage::Waf { 2 } else { 1 };
+            assert_eq!(samples(&timings, stage), expected, "{}", stage.as_str());
+        }
+    }
+
+    #[test]
+    fn test_disabled_timing_records_nothing() {
+        let timings = SubsystemTimings::new();
+        assert!(timings.time(Stage::BotDetection, false, || true));
+        assert_eq!(samples(&timings, Stage::BotDetection), 0);
+    }
+
+    #[test]
+    fn test_registered_under_subsystem_label() {
+        let timings = SubsystemTimings::new();
+        let registry = Registry::new();
+        timings.register(&registry).unwrap();
+        timings.time(Stage::RateLimit, true, || ());
+
+        let families = registry.gather();
+        assert_eq!(families.len(), 1);
+        assert_eq!(families[0].get_name(), "layer7waf_subsystem_duration_seconds");
+        let metric = &families[0].get_metric()[0];
+        assert_eq!(metric.get_label()[0].get_value(), "rate_limit");
+    }
+}
@@ -0,0 +1,112 @@
+/// Upstream header carrying [`trust_score`] when `server.emit_trust_score`
+/// is set.
+pub const TRUST_SCORE_HEADER: &str = "x-l7w-trust-score";
+
+/// Highest trust for a request whose client IP couldn't be determined:
+/// nothing per-client was checked.
+const UNKNOWN_CLIENT_MAX_TRUST: u8 = 50;
+
+/// What the protection stages learned about an allowed request.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct TrustSignals {
+    /// Bot score, 0.0 (human) to 1.0 (bot).
+    pub bot_score: Option<f64>,
+    /// Anti-scraping score, 0.0 to 1.0.
+    pub scraping_score: Option<f64>,
+    /// IP reputation feed score, 0.0 (clean) to 1.0.
+    pub ip_reputation_score: Option<f64>,
+    /// Whether the client IP is on the IP allowlist.
+    pub allowlisted: bool,
+    /// Whether the client IP could be determined.
+    pub client_known: bool,
+}
+
+/// How trustworthy the WAF considers an allowed request, from 0 (surely
+/// automated) to 100 (fully trusted). Allowlisted clients get 100; otherwise
+/// the highest of the bot, scraping and IP reputation scores is taken as the
+/// risk. `None` when no check produced a score: an absent header then tells
+/// the backend nothing was judged, rather than claiming full trust.
+pub fn trust_score(signals: &TrustSignals) -> Option<u8> {
+    if signals.allowlisted {
+        return Some(100);
+    }
+    let risk = [
+        signals.bot_score,
+        signals.scraping_score,
+        signals.ip_reputation_score,
+    ]
+    .into_iter()
+    .flatten()
+    .reduce(f64::max)?
+    .clamp(0.0, 1.0);
+    let trust = ((1.0 - risk) * 100.0).round() as u8;
+    if signals.client_known {
+        Some(trust)
+    } else {
+        Some(trust.min(UNKNOWN_CLIENT_MAX_TRUST))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_suspicious_allowed_request_low_trust() {
+        // Below the default 0.7 bot threshold, so allowed, but bot-like
+        let signals = TrustSignals {
+            bot_score: Some(0.6),
+            scraping_score: Some(0.2),
+            client_known: true,
+            ..Default::default()
+        };
+        assert_eq!(trust_score(&signals), Some(40));
+    }
+
+    #[test]
+    fn test_bad_reputation_lowers_trust() {
+        let signals = TrustSignals {
+            bot_score: Some(0.1),
+            ip_reputation_score: Some(0.8),
+            client_known: true,
+            ..Default::default()
+        };
+        assert_eq!(trust_score(&signals), Some(20));
+    }
+
+    #[test]
+    fn test_no_checks_no_score() {
+        let signals = TrustSignals {
+            client_known: true,
+            ..Default::default()
+        };
+        assert_eq!(trust_score(&signals), None);
+    }
+
+    #[test]
+    fn test_clean_and_allowlisted_requests_trusted() {
+        let clean = TrustSignals {
+            bot_score: Some(0.1),
+            client_known: true,
+            ..Default::default()
+        };
+        assert_eq!(trust_score(&clean), Some(90));
+
+        let allowlisted = TrustSignals {
+            bot_score: Some(0.9),
+            allowlisted: true,
+            client_known: true,
+            ..Default::default()
+        };
+        assert_eq!(trust_score(&allowlisted), Some(100));
+    }
+
+    #[test]
+    fn test_unknown_client_capped() {
+        let signals = TrustSignals {
+            bot_score: Some(0.0),
+            ..Default::default()
+        };
+        assert_eq!(trust_score(&signals), Some(UNKNOWN_CLIENT_MAX_TRUST));
+    }
+}
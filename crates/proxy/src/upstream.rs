@@ -1,5 +1,9 @@
 use layer7waf_common::UpstreamConfig;
-use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+
+/// How long a server is skipped after a connection to it fails.
+pub const CONNECT_FAILURE_DOWN_TIME: Duration = Duration::from_secs(10);
 
 /// Manages upstream server selection with weighted round-robin.
 pub struct UpstreamSelector {
@@ -9,11 +13,16 @@ pub struct UpstreamSelector {
     counter: AtomicUsize,
     /// Expanded list of server indices based on weights.
     weighted_indices: Vec<usize>,
+    /// Reference point for the servers' `down_until` times.
+    epoch: Instant,
 }
 
 struct UpstreamEntry {
     pub addr: String,
     pub weight: u32,
+    /// Milliseconds after `epoch` until which the server is skipped; 0 when
+    /// it has never been marked down.
+    down_until_ms: AtomicU64,
 }
 
 impl UpstreamSelector {
@@ -24,6 +33,7 @@ impl UpstreamSelector {
             .map(|s| UpstreamEntry {
                 addr: s.addr.clone(),
                 weight: s.weight,
+                down_until_ms: AtomicU64::new(0),
             })
             .collect();
 
@@ -46,20 +56,70 @@ impl UpstreamSelector {
             servers,
             counter: AtomicUsize::new(0),
             weighted_indices,
+            epoch: Instant::now(),
         }
     }
 
-    /// Select the next upstream server address using weighted round-robin.
+    /// Select the next upstream server address using weighted round-robin,
+    /// skipping servers marked down. `None` when every server is down.
     pub fn select(&self) -> Option<&str> {
-        if self.weighted_indices.is_empty() {
-            return None;
+        let len = self.weighted_indices.len();
+        let start = self.counter.fetch_add(1, Ordering::Relaxed);
+        let now = self.elapsed_ms();
+        (0..len)
+            .map(|i| &self.servers[self.weighted_indices[(start + i) % len]])
+            .find(|server| server.down_until_ms.load(Ordering::Relaxed) <= now)
+            .map(|server| server.addr.as_str())
+    }
+
+    /// Skip the server at `addr` for `duration`, e.g. after a connection to
+    /// it failed.
+    pub fn mark_down(&self, addr: &str, duration: Duration) {
+        let until = self.elapsed_ms() + duration.as_millis() as u64;
+        for server in self.servers.iter().filter(|s| s.addr == addr) {
+            server.down_until_ms.store(until, Ordering::Relaxed);
         }
-        let idx = self.counter.fetch_add(1, Ordering::Relaxed) % self.weighted_indices.len();
-        let server_idx = self.weighted_indices[idx];
-        Some(&self.servers[server_idx].addr)
+    }
+
+    fn elapsed_ms(&self) -> u64 {
+        self.epoch.elapsed().as_millis() as u64
     }
 
     pub fn server_count(&self) -> usize {
         self.servers.len()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn selector() -> UpstreamSelector {
+        let config: UpstreamConfig = serde_json::from_value(serde_json::json!({
+            "name": "backend",
+            "servers": [{ "addr": "10.0.0.1:8000" }, { "addr": "10.0.0.2:8000" }]
+        }))
+        .unwrap();
+        UpstreamSelector::from_config(&config)
+    }
+
+    #[test]
+    fn test_down_server_skipped_until_it_recovers() {
+        let selector = selector();
+        selector.mark_down("10.0.0.1:8000", Duration::from_secs(60));
+        for _ in 0..4 {
+            assert_eq!(selector.select(), Some("10.0.0.2:8000"));
+        }
+
+        selector.mark_down("10.0.0.2:8000", Duration::ZERO);
+        assert_eq!(selector.select(), Some("10.0.0.2:8000"));
+    }
+
+    #[test]
+    fn test_all_servers_down_selects_none() {
+        let selector = selector();
+        selector.mark_down("10.0.0.1:8000", Duration::from_secs(60));
+        selector.mark_down("10.0.0.2:8000", Duration::from_secs(60));
+        assert_eq!(selector.select(), None);
+    }
+}
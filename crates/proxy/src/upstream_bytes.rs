@@ -0,0 +1,112 @@
+use bytes::Bytes;
+use prometheus::{IntCounterVec, Opts, Registry};
+
+/// Body bytes exchanged with each upstream, for cost attribution.
+#[derive(Clone)]
+pub struct UpstreamByteMetrics {
+    /// Request body bytes sent to the upstream.
+    pub upstream_bytes_sent: IntCounterVec,
+    /// Response body bytes received from the upstream, before any rewriting.
+    pub upstream_bytes_received: IntCounterVec,
+}
+
+impl UpstreamByteMetrics {
+    pub fn new() -> Self {
+        Self {
+            upstream_bytes_sent: IntCounterVec::new(
+                Opts::new(
+                    "layer7waf_upstream_bytes_sent_total",
+                    "Request body bytes sent to each upstream",
+                ),
+                &["upstream"],
+            )
+            .unwrap(),
+            upstream_bytes_received: IntCounterVec::new(
+                Opts::new(
+                    "layer7waf_upstream_bytes_received_total",
+                    "Response body bytes received from each upstream",
+                ),
+                &["upstream"],
+            )
+            .unwrap(),
+        }
+    }
+
+    /// Register the counters with `registry`. May be called for several
+    /// registries; they all observe the same values.
+    pub fn register(&self, registry: &Registry) -> prometheus::Result<()> {
+        registry.register(Box::new(self.upstream_bytes_sent.clone()))?;
+        registry.register(Box::new(self.upstream_bytes_received.clone()))?;
+        Ok(())
+    }
+
+    /// Count a request body chunk sent to `upstream`.
+    pub fn chunk_sent(&self, upstream: Option<&str>, chunk: &Option<Bytes>) {
+        add(&self.upstream_bytes_sent, upstream, chunk);
+    }
+
+    /// Count a response body chunk received from `upstream`.
+    pub fn chunk_received(&self, upstream: Option<&str>, chunk: &Option<Bytes>) {
+        add(&self.upstream_bytes_received, upstream, chunk);
+    }
+}
+
+impl Default for UpstreamByteMetrics {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+fn add(counter: &IntCounterVec, upstream: Option<&str>, chunk: &Option<Bytes>) {
+    if let (Some(upstream), Some(chunk)) = (upstream, chunk) {
+        if !chunk.is_empty() {
+            counter.with_label_values(&[upstream]).inc_by(chunk.len() as u64);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn chunks(total: usize, size: usize) -> Vec<Option<Bytes>> {
+        let body = vec![b'x'; total];
+        let mut chunks: Vec<Option<Bytes>> =
+            body.chunks(size).map(|c| Some(Bytes::copy_from_slice(c))).collect();
+        // End of stream arrives without data
+        chunks.push(None);
+        chunks
+    }
+
+    #[test]
+    fn test_known_size_body_counted() {
+        let metrics = UpstreamByteMetrics::new();
+        for chunk in chunks(10_000, 4096) {
+            metrics.chunk_sent(Some("backend"), &chunk);
+        }
+        for chunk in chunks(2_500, 1024) {
+            metrics.chunk_received(Some("backend"), &chunk);
+        }
+        for chunk in chunks(300, 100) {
+            metrics.chunk_received(Some("api"), &chunk);
+        }
+
+        let sent = |u: &str| metrics.upstream_bytes_sent.with_label_values(&[u]).get();
+        let received = |u: &str| metrics.upstream_bytes_received.with_label_values(&[u]).get();
+        assert_eq!(sent("backend"), 10_000);
+        assert_eq!(received("backend"), 2_500);
+        assert_eq!(received("api"), 300);
+        assert_eq!(sent("api"), 0);
+    }
+
+    #[test]
+    fn test_no_upstream_not_counted() {
+        let metrics = UpstreamByteMetrics::new();
+        let registry = Registry::new();
+        metrics.register(&registry).unwrap();
+        metrics.chunk_received(None, &Some(Bytes::from_static(b"local response")));
+
+        // No label values were created
+        assert!(registry.gather().iter().all(|f| f.get_metric().is_empty()));
+    }
+}
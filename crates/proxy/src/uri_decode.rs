@@ -0,0 +1,229 @@
+use std::borrow::Cow;
+
+/// A request URI after repeated percent-decoding for WAF evaluation.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DecodedUri<'a> {
+    pub uri: Cow<'a, str>,
+    /// Decoding passes that changed the URI.
+    pub passes: u32,
+    /// Whether escapes were still left after `depth` passes: the URI was
+    /// encoded more times than any client has a reason to.
+    pub over_depth: bool,
+}
+
+/// Percent-decode `uri` up to `depth` times, stopping early once nothing is
+/// left to decode. `depth` 0 leaves the URI untouched.
+///
+/// `%00` is never decoded, since the WAF engine can't take NUL bytes; it is
+/// not counted as an escape left over either.
+pub fn decode_uri(uri: &str, depth: u32) -> DecodedUri<'_> {
+    let mut current = Cow::Borrowed(uri);
+    let mut passes = 0;
+    while passes < depth {
+        match decode_once(&current) {
+            Some(next) => {
+                current = Cow::Owned(next);
+                passes += 1;
+            }
+            None => break,
+        }
+    }
+    let over_depth = depth > 0 && passes == depth && decode_once(&current).is_some();
+    DecodedUri {
+        uri: current,
+        passes,
+        over_depth,
+    }
+}
+
+/// Bytes escaped again after decoding the path, so the engine can't read a
+/// decoded `%3f` as the start of the query.
+const PATH_DELIMITERS: &[u8] = b"%?# ";
+
+/// Bytes escaped again after decoding a query parameter name or value; a
+/// decoded `+` would otherwise read as a space.
+const QUERY_DELIMITERS: &[u8] = b"%&=#+ ";
+
+/// `uri` as handed to the WAF engine: the path and each query parameter
+/// name and value are percent-decoded up to `depth` times on their own, and
+/// any delimiter the decoding produced is escaped again. The engine's own
+/// decoding pass then sees the decoded component, while a `%2526` can't
+/// invent a parameter and a `%2523` can't hide the ones after it.
+pub fn decode_uri_components(uri: &str, depth: u32) -> Cow<'_, str> {
+    if depth == 0 {
+        return Cow::Borrowed(uri);
+    }
+    let (path, query) = match uri.split_once('?') {
+        Some((path, query)) => (path, Some(query)),
+        None => (uri, None),
+    };
+    let path = decode_component(Cow::Borrowed(path), depth, PATH_DELIMITERS);
+    let mut changed = matches!(path, Cow::Owned(_));
+    let Some(query) = query else {
+        return if changed { Cow::Owned(path.into_owned()) } else { Cow::Borrowed(uri) };
+    };
+
+    let mut decoded_query = String::with_capacity(query.len());
+    for (i, pair) in query.split('&').enumerate() {
+        if i > 0 {
+            decoded_query.push('&');
+        }
+        let (name, value) = match pair.split_once('=') {
+            Some((name, value)) => (name, Some(value)),
+            None => (pair, None),
+        };
+        let name = decode_query_part(name, depth);
+        changed |= matches!(name, Cow::Owned(_));
+        decoded_query.push_str(&name);
+        if let Some(value) = value {
+            let value = decode_query_part(value, depth);
+            changed |= matches!(value, Cow::Owned(_));
+            decoded_query.push('=');
+            decoded_query.push_str(&value);
+        }
+    }
+    if !changed {
+        return Cow::Borrowed(uri);
+    }
+    Cow::Owned(format!("{}?{}", path, decoded_query))
+}
+
+/// Decode one query parameter name or value, reading a literal `+` as the
+/// space form encoding means by it.
+fn decode_query_part(part: &str, depth: u32) -> Cow<'_, str> {
+    let part = if part.contains('+') {
+        Cow::Owned(part.replace('+', "%20"))
+    } else {
+        Cow::Borrowed(part)
+    };
+    decode_component(part, depth, QUERY_DELIMITERS)
+}
+
+/// Decode `component` up to `depth` times and escape the `delimiters` in
+/// the result. Left as is when there was nothing to decode. A `%00` left
+/// undecoded stays as it is for the engine to decode.
+fn decode_component<'a>(component: Cow<'a, str>, depth: u32, delimiters: &[u8]) -> Cow<'a, str> {
+    let decoded = match decode_uri(&component, depth) {
+        DecodedUri { passes: 0, .. } => return component,
+        DecodedUri { uri, .. } => uri.into_owned(),
+    };
+    let mut out = String::with_capacity(decoded.len());
+    for (i, c) in decoded.char_indices() {
+        let nul_escape = c == '%' && decoded[i + 1..].starts_with("00");
+        if c.is_ascii() && delimiters.contains(&(c as u8)) && !nul_escape {
+            out.push_str(&format!("%{:02X}", c as u32));
+        } else {
+            out.push(c);
+        }
+    }
+    Cow::Owned(out)
+}
+
+/// One percent-decoding pass, or `None` if `s` has no decodable escapes.
+fn decode_once(s: &str) -> Option<String> {
+    let bytes = s.as_bytes();
+    let mut out = Vec::with_capacity(bytes.len());
+    let mut decoded_any = false;
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == b'%' && i + 2 < bytes.len() {
+            if let (Some(hi), Some(lo)) = (hex_value(bytes[i + 1]), hex_value(bytes[i + 2])) {
+                let byte = hi << 4 | lo;
+                if byte != 0 {
+                    out.push(byte);
+                    decoded_any = true;
+                    i += 3;
+                    continue;
+                }
+            }
+        }
+        out.push(bytes[i]);
+        i += 1;
+    }
+    decoded_any.then(|| String::from_utf8_lossy(&out).into_owned())
+}
+
+fn hex_value(b: u8) -> Option<u8> {
+    match b {
+        b'0'..=b'9' => Some(b - b'0'),
+        b'a'..=b'f' => Some(b - b'a' + 10),
+        b'A'..=b'F' => Some(b - b'A' + 10),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_double_encoding_resolved_at_depth_two() {
+        let decoded = decode_uri("/files/%252e%252e/%252e%252e/etc/passwd", 2);
+        assert_eq!(decoded.uri, "/files/../../etc/passwd");
+        assert_eq!(decoded.passes, 2);
+        assert!(!decoded.over_depth);
+    }
+
+    #[test]
+    fn test_excessive_encoding_flagged() {
+        // Six layers of encoding around "."
+        let decoded = decode_uri("/%25252525252e%25252525252e/admin", 2);
+        assert!(decoded.over_depth);
+        assert_eq!(decoded.uri, "/%2525252e%2525252e/admin");
+
+        // Three layers are fine with a depth of three
+        let decoded = decode_uri("/%25252e", 3);
+        assert!(!decoded.over_depth);
+        assert_eq!(decoded.uri, "/.");
+    }
+
+    #[test]
+    fn test_plain_uri_untouched() {
+        let decoded = decode_uri("/search?q=rust&page=2", 2);
+        assert!(matches!(decoded.uri, Cow::Borrowed(_)));
+        assert_eq!(decoded.passes, 0);
+        assert!(!decoded.over_depth);
+
+        let decoded = decode_uri("/a%2fb", 0);
+        assert_eq!(decoded.uri, "/a%2fb");
+        assert!(!decoded.over_depth);
+    }
+
+    #[test]
+    fn test_components_keep_uri_structure() {
+        // A double-encoded "&" or "=" stays inside its value
+        let uri = decode_uri_components("/search?q=%2526admin%253d1&page=2", 2);
+        assert_eq!(uri, "/search?q=%26admin%3D1&page=2");
+
+        // A double-encoded "#" can't hide the parameters after it
+        let uri = decode_uri_components("/p?a=%2523&b=%253cscript%253e", 2);
+        assert_eq!(uri, "/p?a=%23&b=<script>");
+
+        // ...nor can a decoded "?" turn the path into a query
+        let uri = decode_uri_components("/files/%252e%252e/x%253fy=1", 2);
+        assert_eq!(uri, "/files/../x%3Fy=1");
+
+        // Form-encoded spaces stay spaces, an encoded "+" stays a plus
+        let uri = decode_uri_components("/s?q=a+b%252B", 2);
+        assert_eq!(uri, "/s?q=a%20b%2B");
+    }
+
+    #[test]
+    fn test_components_untouched_without_escapes() {
+        let uri = "/search?q=rust&page=2&flag";
+        assert!(matches!(decode_uri_components(uri, 2), Cow::Borrowed(_)));
+        assert!(matches!(decode_uri_components("/a%2fb?x=%41", 0), Cow::Borrowed(_)));
+        assert_eq!(decode_uri_components("/a?x=%2500", 2), "/a?x=%00");
+    }
+
+    #[test]
+    fn test_nul_and_malformed_escapes_kept() {
+        let decoded = decode_uri("/x%00y%zz%4", 2);
+        assert_eq!(decoded.uri, "/x%00y%zz%4");
+        assert!(!decoded.over_depth);
+
+        let decoded = decode_uri("/%2500", 2);
+        assert_eq!(decoded.uri, "/%00");
+        assert!(!decoded.over_depth);
+    }
+}
@@ -0,0 +1,151 @@
+use layer7waf_common::AppConfig;
+use tracing::warn;
+
+/// Build the SecLang directives string from the config's rule glob patterns
+/// and inline rules, followed by the custom rules added through the admin API.
+pub fn build_waf_directives(config: &AppConfig, custom_rules: &[String]) -> String {
+    build_ruleset_directives(config, &config.waf.rules, custom_rules)
+}
+
+/// Like [`build_waf_directives`], with `rule_patterns` (a `waf.rulesets`
+/// entry) in place of `waf.rules`. Inline and custom rules apply to every
+/// rule set.
+pub fn build_ruleset_directives(
+    config: &AppConfig,
+    rule_patterns: &[String],
+    custom_rules: &[String],
+) -> String {
+    let mut directives = String::new();
+
+    // Add SecRuleEngine
+    directives.push_str("SecRuleEngine On\n");
+
+    // Expand glob patterns and include rule files
+    for pattern in rule_patterns {
+        match glob::glob(pattern) {
+            Ok(paths) => {
+                for entry in paths.flatten() {
+                    directives.push_str(&format!("Include {}\n", entry.display()));
+                }
+            }
+            Err(e) => {
+                warn!(pattern = %pattern, error = %e, "invalid rule glob pattern");
+            }
+        }
+    }
+
+    // Set request body limit
+    directives.push_str(&format!(
+        "SecRequestBodyLimit {}\n",
+        config.waf.request_body_limit
+    ));
+
+    // Append inline rules verbatim. NUL bytes would truncate the directives at
+    // the FFI boundary, so such rules are dropped (config validation rejects them).
+    for (i, rule) in config.waf.inline_rules.iter().enumerate() {
+        if rule.contains('\0') {
+            warn!(index = i, "skipping inline rule containing a NUL byte");
+            continue;
+        }
+        directives.push_str(rule.trim_end());
+        directives.push('\n');
+    }
+
+    for (i, rule) in custom_rules.iter().enumerate() {
+        if rule.contains('\0') {
+            warn!(index = i, "skipping custom rule containing a NUL byte");
+            continue;
+        }
+        directives.push_str(rule.trim_end());
+        directives.push('\n');
+    }
+
+    directives
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use layer7waf_coraza::{WafAction, WafEngine, WafTransaction};
+
+    const INLINE_RULE: &str =
+        r#"SecRule ARGS "@contains evil" "id:9001,phase:1,deny,status:403,log""#;
+
+    fn config_with_inline_rules(rules: &[&str]) -> AppConfig {
+        let mut config: AppConfig = serde_json::from_value(serde_json::json!({
+            "server": { "listen": ["127.0.0.1:8080"] },
+            "upstreams": [],
+            "routes": [],
+            "waf": {}
+        }))
+        .unwrap();
+        config.waf.inline_rules = rules.iter().map(|r| r.to_string()).collect();
+        config
+    }
+
+    #[test]
+    fn test_inline_rules_appended() {
+        let directives = build_waf_directives(&config_with_inline_rules(&[INLINE_RULE]), &[]);
+        assert!(directives.starts_with("SecRuleEngine On\n"));
+        assert!(directives.ends_with(&format!("{}\n", INLINE_RULE)));
+    }
+
+    #[test]
+    fn test_custom_rules_follow_inline_rules() {
+        let custom = r#"SecRule ARGS "@contains worse" "id:9002,phase:1,deny,status:403""#;
+        let directives = build_waf_directives(
+            &config_with_inline_rules(&[INLINE_RULE]),
+            &[custom.to_string(), "SecRule \0 bad".to_string()],
+        );
+        assert!(directives.ends_with(&format!("{}\n{}\n", INLINE_RULE, custom)));
+        assert!(!directives.contains('\0'));
+    }
+
+    #[test]
+    fn test_ruleset_replaces_rule_files() {
+        let mut config = config_with_inline_rules(&[INLINE_RULE]);
+        let rules = std::env::temp_dir()
+            .join(format!("layer7waf_ruleset_directives_{}.conf", std::process::id()));
+        std::fs::write(&rules, "").unwrap();
+        config.waf.rules = vec!["/nonexistent/*.conf".to_string()];
+
+        let directives = build_ruleset_directives(&config, &[rules.display().to_string()], &[]);
+        std::fs::remove_file(&rules).unwrap();
+        assert!(directives.contains(&format!("Include {}\n", rules.display())));
+        assert!(directives.ends_with(&format!("{}\n", INLINE_RULE)));
+
+        // Routes may only name configured rulesets
+        config.routes.push(serde_json::from_value(serde_json::json!({
+            "upstream": "backend",
+            "waf": { "ruleset": "strict" }
+        })).unwrap());
+        config.upstreams.push(serde_json::from_value(serde_json::json!({
+            "name": "backend",
+            "servers": [{ "addr": "127.0.0.1:8000" }]
+        })).unwrap());
+        assert!(config.validate().is_err());
+        config.waf.rulesets.insert("strict".to_string(), vec![]);
+        assert!(config.validate().is_ok());
+    }
+
+    #[test]
+    fn test_inline_rule_with_nul_skipped() {
+        let directives = build_waf_directives(&config_with_inline_rules(&["SecRule \0 bad"]), &[]);
+        assert!(!directives.contains('\0'));
+        assert!(config_with_inline_rules(&["SecRule \0 bad"]).validate().is_err());
+    }
+
+    #[test]
+    fn test_inline_rule_engine_blocks() {
+        let directives = build_waf_directives(&config_with_inline_rules(&[INLINE_RULE]), &[]);
+        let engine = WafEngine::new(&directives).expect("engine from inline rules");
+
+        let tx = WafTransaction::new(&engine).unwrap();
+        let action = tx.process_request_headers("GET", "/?q=evil", "HTTP/1.1", &[]);
+        assert_eq!(action, WafAction::Block { status: 403 });
+
+        let tx = WafTransaction::new(&engine).unwrap();
+        let action = tx.process_request_headers("GET", "/?q=fine", "HTTP/1.1", &[]);
+        assert_eq!(action, WafAction::Pass);
+    }
+}
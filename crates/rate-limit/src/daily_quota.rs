@@ -0,0 +1,201 @@
+use crate::store::{InMemoryStore, RateLimitStore};
+use layer7waf_common::{Admission, KeyCapacity};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+const SECS_PER_DAY: u64 = 86_400;
+
+/// Requests counted for one key on one UTC day, as kept in a
+/// [`RateLimitStore`].
+#[derive(Clone)]
+pub struct DailyQuotaState {
+    count: u64,
+    /// Days since the Unix epoch, in UTC.
+    day: u64,
+    last_seen: Instant,
+}
+
+/// A concurrent per-key daily request quota.
+///
+/// Each key may make `limit` requests per UTC day; the count resets at
+/// midnight UTC. Unlike the token bucket and sliding window there is no
+/// refill within the day, so a key that spends its quota early is denied
+/// until the next day.
+///
+/// Counts live in `S`, the in-process [`InMemoryStore`] by default.
+pub struct DailyQuotaLimiter<S = InMemoryStore<DailyQuotaState>> {
+    counts: S,
+    limit: u64,
+}
+
+impl DailyQuotaLimiter {
+    /// Create a limiter allowing `limit` requests per key per UTC day.
+    pub fn new(limit: u64) -> Self {
+        Self::with_store(limit, InMemoryStore::new())
+    }
+}
+
+impl<S: RateLimitStore<DailyQuotaState>> DailyQuotaLimiter<S> {
+    /// Create a daily quota limiter that keeps its counts in `store`.
+    pub fn with_store(limit: u64, store: S) -> Self {
+        Self { counts: store, limit }
+    }
+
+    /// Check whether a request identified by `key` is within today's quota.
+    pub fn check(&self, key: &str) -> bool {
+        self.check_with_capacity(key, None)
+    }
+
+    /// Like [`check`](Self::check), but bounds the number of tracked keys
+    /// according to `capacity`.
+    pub fn check_with_capacity(&self, key: &str, capacity: Option<&KeyCapacity>) -> bool {
+        self.check_at(key, capacity, SystemTime::now())
+    }
+
+    fn check_at(&self, key: &str, capacity: Option<&KeyCapacity>, now: SystemTime) -> bool {
+        if let Some(capacity) = capacity {
+            match self.counts.admit(capacity, key, |state| state.last_seen) {
+                Admission::Admitted => {}
+                Admission::RefusedAllow => return true,
+                Admission::RefusedBlock => return false,
+            }
+        }
+
+        let today = utc_day(now);
+        let init = || DailyQuotaState {
+            count: 0,
+            day: today,
+            last_seen: Instant::now(),
+        };
+        self.counts.update(key, init, |state| {
+            if state.day != today {
+                state.count = 0;
+                state.day = today;
+            }
+            state.last_seen = Instant::now();
+            if state.count < self.limit {
+                state.count += 1;
+                true
+            } else {
+                false
+            }
+        })
+    }
+
+    /// Requests `key` has left today, or `None` if the key isn't tracked.
+    pub fn remaining(&self, key: &str) -> Option<u64> {
+        self.remaining_at(key, SystemTime::now())
+    }
+
+    fn remaining_at(&self, key: &str, now: SystemTime) -> Option<u64> {
+        let today = utc_day(now);
+        self.counts.get(key, |state| {
+            let used = if state.day == today { state.count } else { 0 };
+            self.limit.saturating_sub(used)
+        })
+    }
+
+    /// Remove entries counted on an earlier UTC day; they would be reset on
+    /// their next request anyway.
+    pub fn cleanup(&self) {
+        let today = utc_day(SystemTime::now());
+        self.counts.retain(|state| state.day == today);
+        tracing::debug!(remaining = self.counts.len(), "daily quota cleanup complete");
+    }
+
+    /// Remove entries from earlier days and those idle for more than `ttl`.
+    /// An evicted key starts the day over, so a `ttl` shorter than a day
+    /// lets idle clients exceed the quota.
+    pub fn cleanup_older_than(&self, ttl: Duration) {
+        let today = utc_day(SystemTime::now());
+        let now = Instant::now();
+        self.counts
+            .retain(|state| state.day == today && now.duration_since(state.last_seen) < ttl);
+        tracing::debug!(remaining = self.counts.len(), "daily quota cleanup complete");
+    }
+
+    /// Number of keys currently tracked.
+    pub fn tracked_keys(&self) -> usize {
+        self.counts.len()
+    }
+
+    /// The store the counts are kept in.
+    pub fn store(&self) -> &S {
+        &self.counts
+    }
+
+    /// Whether `key` is currently tracked.
+    pub fn is_tracking(&self, key: &str) -> bool {
+        self.counts.contains_key(key)
+    }
+}
+
+/// Time left until the quota resets at the next UTC midnight.
+pub fn time_until_reset() -> Duration {
+    time_until_reset_at(SystemTime::now())
+}
+
+fn time_until_reset_at(now: SystemTime) -> Duration {
+    let secs = now.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs();
+    Duration::from_secs(SECS_PER_DAY - secs % SECS_PER_DAY)
+}
+
+/// Days between the Unix epoch and `now`, in UTC.
+fn utc_day(now: SystemTime) -> u64 {
+    now.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs() / SECS_PER_DAY
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// 2024-03-01T00:00:00Z plus `secs`.
+    fn at(secs: u64) -> SystemTime {
+        UNIX_EPOCH + Duration::from_secs(1_709_251_200 + secs)
+    }
+
+    #[test]
+    fn count_accumulates_through_the_day() {
+        let limiter = DailyQuotaLimiter::new(3);
+        assert!(limiter.check_at("client", None, at(0)));
+        assert!(limiter.check_at("client", None, at(6 * 3600)));
+        assert_eq!(limiter.remaining_at("client", at(12 * 3600)), Some(1));
+        assert!(limiter.check_at("client", None, at(12 * 3600)));
+        assert!(!limiter.check_at("client", None, at(18 * 3600)));
+        assert!(!limiter.check_at("client", None, at(SECS_PER_DAY - 1)));
+
+        // Another key has its own quota
+        assert!(limiter.check_at("other", None, at(SECS_PER_DAY - 1)));
+    }
+
+    #[test]
+    fn quota_resets_at_utc_midnight() {
+        let limiter = DailyQuotaLimiter::new(2);
+        assert!(limiter.check_at("client", None, at(SECS_PER_DAY - 2)));
+        assert!(limiter.check_at("client", None, at(SECS_PER_DAY - 1)));
+        assert!(!limiter.check_at("client", None, at(SECS_PER_DAY - 1)));
+        assert_eq!(limiter.remaining_at("client", at(SECS_PER_DAY)), Some(2));
+
+        assert!(limiter.check_at("client", None, at(SECS_PER_DAY)));
+        assert_eq!(limiter.remaining_at("client", at(SECS_PER_DAY)), Some(1));
+        assert_eq!(limiter.remaining_at("unseen", at(SECS_PER_DAY)), None);
+    }
+
+    #[test]
+    fn reset_time_is_next_utc_midnight() {
+        assert_eq!(time_until_reset_at(at(0)), Duration::from_secs(SECS_PER_DAY));
+        assert_eq!(time_until_reset_at(at(SECS_PER_DAY - 1)), Duration::from_secs(1));
+        assert_eq!(time_until_reset_at(at(23 * 3600)), Duration::from_secs(3600));
+    }
+
+    #[test]
+    fn cleanup_drops_earlier_days() {
+        let limiter = DailyQuotaLimiter::new(10);
+        limiter.check("today");
+        limiter.check_at("yesterday", None, SystemTime::now() - Duration::from_secs(SECS_PER_DAY));
+
+        limiter.cleanup();
+
+        assert!(limiter.is_tracking("today"));
+        assert!(!limiter.is_tracking("yesterday"));
+    }
+}
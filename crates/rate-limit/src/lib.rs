@@ -1,6 +1,6 @@
 //! Rate limiting for the Layer 7 WAF.
 //!
-//! This crate provides two interchangeable rate-limiting algorithms behind a
+//! This crate provides interchangeable rate-limiting algorithms behind a
 //! unified [`RateLimiter`] facade:
 //!
 //! - **Token bucket** -- smooth, burst-tolerant limiting well suited for API
@@ -11,16 +11,34 @@
 //!   sliding window that blends the previous and current fixed-window counts.
 //!   Good when you want hard per-window caps with minimal memory overhead.
 //!
-//! Both implementations use [`DashMap`](dashmap::DashMap) for lock-free
-//! concurrent access and include periodic cleanup to evict stale entries.
+//! - **Daily quota** -- a hard cap on requests per key per UTC day, reset at
+//!   midnight UTC, for billing or abuse limits that span far longer than the
+//!   windows above.
+//!
+//! All algorithms keep their per-key state in a [`RateLimitStore`], so
+//! alternative backends can be plugged in without touching the algorithms.
+//! The default [`InMemoryStore`] uses [`DashMap`](dashmap::DashMap) for
+//! lock-free concurrent access. Both include periodic cleanup to evict stale
+//! entries.
 
+pub mod daily_quota;
 pub mod sliding_window;
+pub mod store;
 pub mod token_bucket;
 
+use std::borrow::Cow;
+use std::net::IpAddr;
 use std::sync::Arc;
+use std::time::Duration;
+
+use layer7waf_common::{
+    AdaptiveLimitConfig, KeyCapacity, MapFootprint, RateLimitAlgorithm, RouteRateLimitConfig,
+};
 
-pub use sliding_window::SlidingWindowLimiter;
-pub use token_bucket::TokenBucketLimiter;
+pub use daily_quota::{time_until_reset, DailyQuotaLimiter, DailyQuotaState};
+pub use sliding_window::{SlidingWindowDebug, SlidingWindowLimiter, SlidingWindowState};
+pub use store::{InMemoryStore, RateLimitStore};
+pub use token_bucket::{TokenBucketLimiter, TokenBucketState};
 
 /// A unified rate limiter that delegates to one of the supported algorithms.
 ///
@@ -33,11 +51,46 @@ pub use token_bucket::TokenBucketLimiter;
 #[derive(Clone)]
 pub struct RateLimiter {
     inner: Arc<RateLimiterInner>,
+    /// Idle time after which a key is evicted. `None` uses the algorithm's
+    /// built-in staleness threshold.
+    key_ttl: Option<Duration>,
+    /// Cap on tracked keys; `None` leaves the map unbounded.
+    capacity: Option<KeyCapacity>,
+    rps: u64,
+    burst: u64,
+}
+
+/// Point-in-time snapshot of a limiter's configuration and live key count.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RateLimitStats {
+    pub algorithm: RateLimitAlgorithm,
+    pub tracked_keys: usize,
+    /// Zero for a daily quota.
+    pub configured_rps: u64,
+    /// Bucket capacity for token bucket; per-window limit for sliding window;
+    /// requests per day for daily quota.
+    pub configured_burst: u64,
+    /// Sliding window only: the window and the requests allowed in it.
+    pub window_secs: Option<u64>,
+    pub effective_limit: Option<u64>,
+}
+
+/// Live limiter state for a single key.
+#[derive(Debug, Clone, PartialEq)]
+pub struct KeyStatus {
+    /// Current token balance, refill applied.
+    pub tokens: f64,
+    /// Requests the key could make right now.
+    pub remaining_capacity: u64,
+    /// Time until the next request would be allowed; zero if one is now.
+    pub retry_after: Duration,
 }
 
 enum RateLimiterInner {
     TokenBucket(TokenBucketLimiter),
     SlidingWindow(SlidingWindowLimiter),
+    /// Counts are shared so a limiter rebuilt with another quota can carry on.
+    DailyQuota(DailyQuotaLimiter<Arc<InMemoryStore<DailyQuotaState>>>),
 }
 
 impl RateLimiter {
@@ -51,6 +104,10 @@ impl RateLimiter {
             inner: Arc::new(RateLimiterInner::TokenBucket(
                 TokenBucketLimiter::new(rps, burst),
             )),
+            key_ttl: None,
+            capacity: None,
+            rps,
+            burst,
         }
     }
 
@@ -60,48 +117,289 @@ impl RateLimiter {
     /// * `window_secs` - window duration in seconds
     pub fn new_sliding_window(rps: u64, window_secs: u64) -> Self {
         tracing::info!(rps, window_secs, "creating sliding window rate limiter");
+        Self::sliding_window(SlidingWindowLimiter::new(rps, window_secs), rps, window_secs)
+    }
+
+    /// Like [`new_sliding_window`](Self::new_sliding_window), but lowers the
+    /// limit of clients that keep hitting it, per `adaptive`.
+    pub fn new_adaptive_sliding_window(
+        rps: u64,
+        window_secs: u64,
+        adaptive: AdaptiveLimitConfig,
+    ) -> Self {
+        tracing::info!(
+            rps,
+            window_secs,
+            ?adaptive,
+            "creating adaptive sliding window rate limiter"
+        );
+        let limiter = SlidingWindowLimiter::new(rps, window_secs).with_adaptive(adaptive);
+        Self::sliding_window(limiter, rps, window_secs)
+    }
+
+    fn sliding_window(limiter: SlidingWindowLimiter, rps: u64, window_secs: u64) -> Self {
         Self {
-            inner: Arc::new(RateLimiterInner::SlidingWindow(
-                SlidingWindowLimiter::new(rps, window_secs),
-            )),
+            inner: Arc::new(RateLimiterInner::SlidingWindow(limiter)),
+            key_ttl: None,
+            capacity: None,
+            rps,
+            burst: rps * window_secs,
         }
     }
 
+    /// Create a rate limiter allowing `limit` requests per key per UTC day,
+    /// reset at midnight UTC.
+    pub fn new_daily_quota(limit: u64) -> Self {
+        Self::daily_quota(limit, Arc::default())
+    }
+
+    /// Like [`new_daily_quota`](Self::new_daily_quota), but counting on from
+    /// today's requests of `current` when that is a daily quota too, so a
+    /// changed quota neither refunds nor forgets what clients have used.
+    /// The two limiters share their counts from then on.
+    pub fn new_daily_quota_continuing(limit: u64, current: &RateLimiter) -> Self {
+        let counts = match current.inner.as_ref() {
+            RateLimiterInner::DailyQuota(limiter) => limiter.store().clone(),
+            RateLimiterInner::TokenBucket(_) | RateLimiterInner::SlidingWindow(_) => {
+                Arc::default()
+            }
+        };
+        Self::daily_quota(limit, counts)
+    }
+
+    fn daily_quota(limit: u64, counts: Arc<InMemoryStore<DailyQuotaState>>) -> Self {
+        tracing::info!(limit, "creating daily quota rate limiter");
+        Self {
+            inner: Arc::new(RateLimiterInner::DailyQuota(DailyQuotaLimiter::with_store(
+                limit, counts,
+            ))),
+            key_ttl: None,
+            capacity: None,
+            rps: 0,
+            burst: limit,
+        }
+    }
+
+    /// Create a rate limiter from a per-route configuration block.
+    ///
+    /// Sliding window limiters use a 1-second window, so `burst` only applies
+    /// to the token bucket algorithm. A daily quota allows `burst` requests
+    /// per day and ignores `rps`.
+    pub fn from_route_config(config: &RouteRateLimitConfig) -> Self {
+        let limiter = match config.algorithm {
+            RateLimitAlgorithm::TokenBucket => Self::new_token_bucket(config.rps, config.burst),
+            RateLimitAlgorithm::SlidingWindow => match config.adaptive.clone() {
+                Some(adaptive) => Self::new_adaptive_sliding_window(config.rps, 1, adaptive),
+                None => Self::new_sliding_window(config.rps, 1),
+            },
+            RateLimitAlgorithm::DailyQuota => Self::new_daily_quota(config.burst),
+        };
+        match config.key_ttl_secs {
+            Some(secs) => limiter.with_key_ttl(Duration::from_secs(secs)),
+            None => limiter,
+        }
+    }
+
+    /// Bound the number of tracked keys, applying the capacity's overflow
+    /// action when a new key arrives at a full limiter.
+    pub fn with_key_capacity(mut self, capacity: KeyCapacity) -> Self {
+        self.capacity = Some(capacity);
+        self
+    }
+
+    /// Evict keys that have been idle for longer than `ttl` during cleanup,
+    /// instead of the algorithm's default threshold.
+    pub fn with_key_ttl(mut self, ttl: Duration) -> Self {
+        self.key_ttl = Some(ttl);
+        self
+    }
+
+    /// Whether `other` limits exactly like this limiter: same algorithm,
+    /// limits, key TTL and key capacity. A reload that builds such a limiter
+    /// can keep this one instead, along with the state of every key.
+    pub fn is_configured_like(&self, other: &RateLimiter) -> bool {
+        let algorithm = |limiter: &RateLimiter| match limiter.inner.as_ref() {
+            RateLimiterInner::TokenBucket(_) => (RateLimitAlgorithm::TokenBucket, None, None),
+            RateLimiterInner::SlidingWindow(limiter) => (
+                RateLimitAlgorithm::SlidingWindow,
+                Some(limiter.window_secs()),
+                limiter.adaptive().cloned(),
+            ),
+            RateLimiterInner::DailyQuota(_) => (RateLimitAlgorithm::DailyQuota, None, None),
+        };
+        algorithm(self) == algorithm(other)
+            && (self.rps, self.burst) == (other.rps, other.burst)
+            && self.key_ttl == other.key_ttl
+            && self.capacity == other.capacity
+    }
+
     /// Check whether a request identified by `key` is allowed.
     ///
     /// Returns `true` if the request is permitted, `false` if the caller has
     /// exceeded the rate limit and should receive a 429 response.
     pub fn check(&self, key: &str) -> bool {
+        let capacity = self.capacity.as_ref();
         match self.inner.as_ref() {
-            RateLimiterInner::TokenBucket(limiter) => limiter.check(key),
-            RateLimiterInner::SlidingWindow(limiter) => limiter.check(key),
+            RateLimiterInner::TokenBucket(limiter) => limiter.check_with_capacity(key, capacity),
+            RateLimiterInner::SlidingWindow(limiter) => limiter.check_with_capacity(key, capacity),
+            RateLimiterInner::DailyQuota(limiter) => limiter.check_with_capacity(key, capacity),
         }
     }
 
-    /// Spawn a background Tokio task that periodically evicts stale entries.
+    /// Async form of [`check`](RateLimiter::check), for call sites that
+    /// shouldn't care where the limiter keeps its state.
     ///
-    /// The cleanup task runs every 60 seconds and will continue until the
-    /// runtime shuts down. It holds an `Arc` reference to the inner limiter,
-    /// so the limiter will stay alive as long as the task is running.
+    /// Both algorithms currently run on in-process stores, where this is the
+    /// same as `check`. A store backed by a remote service would await its
+    /// round trip here instead of blocking the worker.
+    pub async fn check_async(&self, key: &str) -> bool {
+        self.check(key)
+    }
+
+    /// Evict stale keys, honouring the configured key TTL if one is set.
+    pub fn cleanup(&self) {
+        match (self.inner.as_ref(), self.key_ttl) {
+            (RateLimiterInner::TokenBucket(limiter), Some(ttl)) => limiter.cleanup_older_than(ttl),
+            (RateLimiterInner::TokenBucket(limiter), None) => limiter.cleanup(),
+            (RateLimiterInner::SlidingWindow(limiter), Some(ttl)) => limiter.cleanup_older_than(ttl),
+            (RateLimiterInner::SlidingWindow(limiter), None) => limiter.cleanup(),
+            (RateLimiterInner::DailyQuota(limiter), Some(ttl)) => limiter.cleanup_older_than(ttl),
+            (RateLimiterInner::DailyQuota(limiter), None) => limiter.cleanup(),
+        }
+    }
+
+    /// Number of keys currently tracked by the limiter.
+    pub fn tracked_keys(&self) -> usize {
+        match self.inner.as_ref() {
+            RateLimiterInner::TokenBucket(limiter) => limiter.tracked_keys(),
+            RateLimiterInner::SlidingWindow(limiter) => limiter.tracked_keys(),
+            RateLimiterInner::DailyQuota(limiter) => limiter.tracked_keys(),
+        }
+    }
+
+    /// Tracked keys and their approximate memory use, reported as `name`.
+    pub fn map_footprint(&self, name: impl Into<String>) -> MapFootprint {
+        let keys = self.tracked_keys();
+        match self.inner.as_ref() {
+            RateLimiterInner::TokenBucket(_) => MapFootprint::of::<TokenBucketState>(name, keys),
+            RateLimiterInner::SlidingWindow(_) => MapFootprint::of::<SlidingWindowState>(name, keys),
+            RateLimiterInner::DailyQuota(_) => MapFootprint::of::<DailyQuotaState>(name, keys),
+        }
+    }
+
+    /// Whether the limiter currently tracks `key`.
+    pub fn is_tracking(&self, key: &str) -> bool {
+        match self.inner.as_ref() {
+            RateLimiterInner::TokenBucket(limiter) => limiter.is_tracking(key),
+            RateLimiterInner::SlidingWindow(limiter) => limiter.is_tracking(key),
+            RateLimiterInner::DailyQuota(limiter) => limiter.is_tracking(key),
+        }
+    }
+
+    /// Snapshot the limiter's algorithm, configured limits and key count.
+    pub fn stats(&self) -> RateLimitStats {
+        let algorithm = match self.inner.as_ref() {
+            RateLimiterInner::TokenBucket(_) => RateLimitAlgorithm::TokenBucket,
+            RateLimiterInner::SlidingWindow(_) => RateLimitAlgorithm::SlidingWindow,
+            RateLimiterInner::DailyQuota(_) => RateLimitAlgorithm::DailyQuota,
+        };
+        let window = match self.inner.as_ref() {
+            RateLimiterInner::SlidingWindow(limiter) => {
+                Some((limiter.window_secs(), limiter.effective_limit()))
+            }
+            RateLimiterInner::TokenBucket(_) | RateLimiterInner::DailyQuota(_) => None,
+        };
+        RateLimitStats {
+            algorithm,
+            tracked_keys: self.tracked_keys(),
+            configured_rps: self.rps,
+            configured_burst: self.burst,
+            window_secs: window.map(|(secs, _)| secs),
+            effective_limit: window.map(|(_, limit)| limit),
+        }
+    }
+
+    /// Inspect `key`'s current state without consuming anything. Only token
+    /// bucket limiters track a balance; returns `None` for the other
+    /// algorithms and for keys that aren't tracked.
+    pub fn key_status(&self, key: &str) -> Option<KeyStatus> {
+        match self.inner.as_ref() {
+            RateLimiterInner::TokenBucket(limiter) => Some(KeyStatus {
+                tokens: limiter.tokens(key)?,
+                remaining_capacity: limiter.remaining_capacity(key)?,
+                retry_after: limiter.retry_after(key)?,
+            }),
+            RateLimiterInner::SlidingWindow(_) | RateLimiterInner::DailyQuota(_) => None,
+        }
+    }
+
+    /// The blended window calculation for `key`, without counting a
+    /// request. Returns `None` for the other algorithms and for keys that
+    /// aren't tracked.
+    pub fn sliding_window_debug(&self, key: &str) -> Option<SlidingWindowDebug> {
+        match self.inner.as_ref() {
+            RateLimiterInner::TokenBucket(_) | RateLimiterInner::DailyQuota(_) => None,
+            RateLimiterInner::SlidingWindow(limiter) => limiter.debug_state(key),
+        }
+    }
+
+    /// Spawn a background thread that periodically evicts stale entries.
+    ///
+    /// The cleanup thread runs every 60 seconds for the lifetime of the
+    /// process. It holds a clone of the limiter, so the limiter will stay
+    /// alive as long as the thread is running.
     pub fn start_cleanup_task(&self) {
-        let inner = Arc::clone(&self.inner);
+        start_shared_cleanup_task(vec![self.clone()]);
+    }
+}
 
-        std::thread::Builder::new()
-            .name("rate-limit-cleanup".into())
-            .spawn(move || loop {
-                std::thread::sleep(std::time::Duration::from_secs(60));
+/// Canonical form of a rate-limit key, so spellings of the same client
+/// share one bucket: IP addresses are re-rendered canonically (IPv6
+/// compressed and lowercased, IPv4-mapped IPv6 as IPv4, brackets dropped)
+/// and other keys such as host names are lowercased with any trailing dot
+/// removed.
+pub fn normalize_rl_key(key: &str) -> Cow<'_, str> {
+    let unbracketed = key
+        .strip_prefix('[')
+        .and_then(|k| k.strip_suffix(']'))
+        .unwrap_or(key);
+    if let Ok(ip) = unbracketed.parse::<IpAddr>() {
+        let canonical = ip.to_canonical().to_string();
+        return if canonical == key {
+            Cow::Borrowed(key)
+        } else {
+            Cow::Owned(canonical)
+        };
+    }
 
-                match inner.as_ref() {
-                    RateLimiterInner::TokenBucket(limiter) => limiter.cleanup(),
-                    RateLimiterInner::SlidingWindow(limiter) => limiter.cleanup(),
-                }
+    let trimmed = key.strip_suffix('.').unwrap_or(key);
+    if trimmed.len() == key.len() && !key.bytes().any(|b| b.is_ascii_uppercase()) {
+        Cow::Borrowed(key)
+    } else {
+        Cow::Owned(trimmed.to_ascii_lowercase())
+    }
+}
 
-                tracing::trace!("rate limiter cleanup tick completed");
-            })
-            .expect("failed to spawn rate-limit cleanup thread");
+/// Run one cleanup pass over every limiter, each with its own key TTL.
+pub fn cleanup_all(limiters: &[RateLimiter]) {
+    for limiter in limiters {
+        limiter.cleanup();
     }
 }
 
+/// Spawn a single background thread that cleans up all `limiters` every
+/// 60 seconds, applying each limiter's own key TTL.
+pub fn start_shared_cleanup_task(limiters: Vec<RateLimiter>) {
+    std::thread::Builder::new()
+        .name("rate-limit-cleanup".into())
+        .spawn(move || loop {
+            std::thread::sleep(Duration::from_secs(60));
+            cleanup_all(&limiters);
+            tracing::trace!(limiters = limiters.len(), "rate limiter cleanup tick completed");
+        })
+        .expect("failed to spawn rate-limit cleanup thread");
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -122,6 +420,27 @@ mod tests {
         assert!(limiter.check("client-b"));
     }
 
+    #[test]
+    fn configured_like_compares_every_setting() {
+        let limiter = RateLimiter::new_token_bucket(5, 3);
+        assert!(limiter.is_configured_like(&RateLimiter::new_token_bucket(5, 3)));
+        assert!(!limiter.is_configured_like(&RateLimiter::new_token_bucket(5, 4)));
+        assert!(!limiter.is_configured_like(&RateLimiter::new_sliding_window(5, 1)));
+        assert!(!limiter.is_configured_like(
+            &RateLimiter::new_token_bucket(5, 3).with_key_ttl(Duration::from_secs(60))
+        ));
+
+        let window = RateLimiter::new_sliding_window(5, 1);
+        let adaptive =
+            RateLimiter::new_adaptive_sliding_window(5, 1, AdaptiveLimitConfig::default());
+        assert!(!window.is_configured_like(&adaptive));
+        assert!(adaptive.is_configured_like(&RateLimiter::new_adaptive_sliding_window(
+            5,
+            1,
+            AdaptiveLimitConfig::default()
+        )));
+    }
+
     #[test]
     fn sliding_window_through_facade() {
         let limiter = RateLimiter::new_sliding_window(5, 1);
@@ -134,6 +453,32 @@ mod tests {
         assert!(!limiter.check("client-x"), "should deny beyond window limit");
     }
 
+    #[test]
+    fn map_footprint_counts_tracked_keys() {
+        let limiter = RateLimiter::new_token_bucket(5, 3);
+        for key in ["a", "b", "c", "a"] {
+            limiter.check(key);
+        }
+        let footprint = limiter.map_footprint("global");
+        assert_eq!(footprint.name, "global");
+        assert_eq!(footprint.entries, 3);
+        assert_eq!(footprint, MapFootprint::of::<TokenBucketState>("global", 3));
+    }
+
+    #[tokio::test]
+    async fn check_async_matches_check() {
+        for (sync, async_) in [
+            (RateLimiter::new_token_bucket(5, 3), RateLimiter::new_token_bucket(5, 3)),
+            (RateLimiter::new_sliding_window(4, 1), RateLimiter::new_sliding_window(4, 1)),
+        ] {
+            for key in ["client-a", "client-a", "client-b", "client-a", "client-a", "client-a"] {
+                assert_eq!(async_.check_async(key).await, sync.check(key), "key {}", key);
+            }
+            let remaining = |l: &RateLimiter| l.key_status("client-a").map(|s| s.remaining_capacity);
+            assert_eq!(remaining(&async_), remaining(&sync));
+        }
+    }
+
     #[test]
     fn clone_shares_state() {
         let limiter = RateLimiter::new_token_bucket(10, 2);
@@ -146,4 +491,138 @@ mod tests {
         assert!(!limiter.check("shared"));
         assert!(!limiter2.check("shared"));
     }
+
+    #[test]
+    fn per_limiter_key_ttl_on_shared_tick() {
+        let short = RateLimiter::new_token_bucket(10, 10).with_key_ttl(Duration::from_millis(50));
+        let long = RateLimiter::new_token_bucket(10, 10).with_key_ttl(Duration::from_secs(60));
+
+        assert!(short.check("client"));
+        assert!(long.check("client"));
+
+        std::thread::sleep(Duration::from_millis(100));
+        cleanup_all(&[short.clone(), long.clone()]);
+
+        assert_eq!(short.tracked_keys(), 0, "short TTL should evict idle key");
+        assert_eq!(long.tracked_keys(), 1, "long TTL should keep idle key");
+    }
+
+    #[test]
+    fn key_capacity_evicts_oldest() {
+        use layer7waf_common::{FailurePolicy, KeyOverflowPolicy};
+
+        let cap = KeyCapacity::new(3, KeyOverflowPolicy::Evict, FailurePolicy::Allow);
+        let limiter = RateLimiter::new_token_bucket(10, 10).with_key_capacity(cap);
+        for key in ["a", "b", "c", "d", "e"] {
+            assert!(limiter.check(key));
+            std::thread::sleep(Duration::from_millis(2));
+        }
+        assert!(limiter.tracked_keys() <= 3);
+    }
+
+    #[test]
+    fn key_capacity_refuses_per_failure_policy() {
+        use layer7waf_common::{FailurePolicy, KeyOverflowPolicy};
+
+        let cap = KeyCapacity::new(1, KeyOverflowPolicy::Refuse, FailurePolicy::Block);
+        let limiter = RateLimiter::new_sliding_window(10, 1).with_key_capacity(cap);
+        assert!(limiter.check("a"));
+        assert!(!limiter.check("b"), "overflow key should be denied");
+        assert!(limiter.check("a"), "tracked key still checked normally");
+        assert_eq!(limiter.tracked_keys(), 1);
+    }
+
+    #[test]
+    fn stats_reflect_config_and_keys() {
+        let limiter = RateLimiter::new_token_bucket(50, 80);
+        limiter.check("a");
+        limiter.check("b");
+        limiter.check("a");
+
+        let stats = limiter.stats();
+        assert_eq!(stats.algorithm, RateLimitAlgorithm::TokenBucket);
+        assert_eq!(stats.configured_rps, 50);
+        assert_eq!(stats.configured_burst, 80);
+        assert_eq!(stats.tracked_keys, 2);
+
+        let stats = RateLimiter::new_sliding_window(10, 2).stats();
+        assert_eq!(stats.algorithm, RateLimitAlgorithm::SlidingWindow);
+        assert_eq!(stats.configured_burst, 20);
+        assert_eq!(stats.tracked_keys, 0);
+
+        let stats = RateLimiter::new_daily_quota(50_000).stats();
+        assert_eq!(stats.algorithm, RateLimitAlgorithm::DailyQuota);
+        assert_eq!(stats.configured_burst, 50_000);
+    }
+
+    #[test]
+    fn daily_quota_through_facade() {
+        let limiter = RateLimiter::new_daily_quota(3);
+        for i in 0..3 {
+            assert!(limiter.check("client"), "request {} should pass", i);
+        }
+        assert!(!limiter.check("client"), "should deny beyond daily quota");
+        assert!(limiter.check("other"));
+        assert_eq!(
+            limiter.map_footprint("daily"),
+            MapFootprint::of::<DailyQuotaState>("daily", 2)
+        );
+    }
+
+    #[test]
+    fn daily_quota_continues_counting_under_new_quota() {
+        let limiter = RateLimiter::new_daily_quota(3);
+        for _ in 0..3 {
+            assert!(limiter.check("client"));
+        }
+
+        let raised = RateLimiter::new_daily_quota_continuing(4, &limiter);
+        assert_eq!(raised.stats().configured_burst, 4);
+        assert!(raised.check("client"));
+        assert!(!raised.check("client"), "the old quota's requests still count");
+
+        let bucket = RateLimiter::new_token_bucket(1, 1);
+        let fresh = RateLimiter::new_daily_quota_continuing(4, &bucket);
+        assert!(!fresh.is_tracking("client"));
+    }
+
+    #[test]
+    fn from_route_config_applies_ttl() {
+        let config = RouteRateLimitConfig {
+            rps: 5,
+            burst: 2,
+            algorithm: RateLimitAlgorithm::SlidingWindow,
+            key_ttl_secs: Some(0),
+            adaptive: None,
+        };
+        let limiter = RateLimiter::from_route_config(&config);
+
+        assert!(limiter.check("client"));
+        limiter.cleanup();
+        assert_eq!(limiter.tracked_keys(), 0);
+    }
+
+    #[test]
+    fn equivalent_keys_normalize_alike() {
+        assert_eq!(normalize_rl_key("Example.com."), "example.com");
+        assert_eq!(normalize_rl_key("example.com"), "example.com");
+        assert!(matches!(normalize_rl_key("example.com"), Cow::Borrowed(_)));
+
+        assert_eq!(normalize_rl_key("2001:DB8:0:0:0:0:0:1"), "2001:db8::1");
+        assert_eq!(normalize_rl_key("[2001:db8::1]"), "2001:db8::1");
+        assert_eq!(normalize_rl_key("::ffff:192.0.2.1"), "192.0.2.1");
+        assert_eq!(normalize_rl_key("192.0.2.1"), "192.0.2.1");
+    }
+
+    #[test]
+    fn normalized_keys_share_a_bucket() {
+        let limiter = RateLimiter::new_token_bucket(1, 2);
+        assert!(limiter.check(&normalize_rl_key("Example.com.")));
+        assert!(limiter.check(&normalize_rl_key("example.COM")));
+        assert!(!limiter.check(&normalize_rl_key("example.com")));
+
+        assert!(limiter.check(&normalize_rl_key("2001:DB8::1")));
+        assert!(limiter.check(&normalize_rl_key("[2001:db8:0::1]")));
+        assert!(!limiter.check(&normalize_rl_key("2001:db8::1")));
+    }
 }
@@ -1,13 +1,110 @@
-use dashmap::DashMap;
+use crate::store::{InMemoryStore, RateLimitStore};
+use layer7waf_common::{AdaptiveLimitConfig, Admission, KeyCapacity};
 use std::time::{Duration, Instant};
 
-/// Internal state for a single sliding window counter entry.
-struct SlidingWindowState {
+/// State of a single sliding window counter, as kept in a
+/// [`RateLimitStore`].
+#[derive(Clone)]
+pub struct SlidingWindowState {
     current_count: u64,
     previous_count: u64,
     window_start: Instant,
     window_secs: u64,
     limit: u64,
+    /// Multiplier on `limit` from adaptive tightening; 1.0 without it.
+    penalty: f64,
+    /// Whether a request was refused in the current window.
+    refused: bool,
+}
+
+/// The values a sliding window check computes for one key, for debugging
+/// requests limited earlier or later than expected.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SlidingWindowDebug {
+    pub current_count: u64,
+    pub previous_count: u64,
+    /// Fraction of the current window that has elapsed (0.0 .. 1.0).
+    pub elapsed_fraction: f64,
+    /// `previous_count * (1 - elapsed_fraction) + current_count`; the next
+    /// request is allowed while this is below `limit * penalty`.
+    pub weighted_count: f64,
+    pub limit: u64,
+    /// Adaptive tightening of the key's limit; 1.0 when not tightened.
+    pub penalty: f64,
+}
+
+impl SlidingWindowState {
+    /// Advance the windows to `now` and count the request if it fits the
+    /// limit.
+    fn record(
+        &mut self,
+        now: Instant,
+        window_duration: Duration,
+        adaptive: Option<&AdaptiveLimitConfig>,
+    ) -> bool {
+        self.rotate(now, window_duration, adaptive);
+        if self.blend(now).weighted_count < self.limit as f64 * self.penalty {
+            self.current_count += 1;
+            true
+        } else {
+            self.refused = true;
+            false
+        }
+    }
+
+    /// Rotate windows so that the current one contains `now`, adjusting the
+    /// penalty for each window that ends.
+    fn rotate(
+        &mut self,
+        now: Instant,
+        window_duration: Duration,
+        adaptive: Option<&AdaptiveLimitConfig>,
+    ) {
+        // Rotate windows if the current window has elapsed.
+        // We loop in case more than one full window has passed since the last
+        // request (e.g., the client was idle for a long time).
+        while now.duration_since(self.window_start) >= window_duration {
+            if let Some(adaptive) = adaptive {
+                self.penalty = if self.refused {
+                    (self.penalty * adaptive.tighten_factor).max(adaptive.floor)
+                } else {
+                    (self.penalty + adaptive.restore_step).min(1.0)
+                };
+            }
+            self.refused = false;
+            self.previous_count = self.current_count;
+            self.current_count = 0;
+            self.window_start += window_duration;
+        }
+    }
+
+    /// Blend the previous and current window counts at `now`, which must
+    /// fall in the current window.
+    fn blend(&self, now: Instant) -> SlidingWindowDebug {
+        // If the window_start is somehow in the future after rotation (shouldn't
+        // happen, but guard defensively), reset.
+        let elapsed_in_window = now
+            .duration_since(self.window_start)
+            .as_secs_f64();
+        let window_secs_f64 = self.window_secs as f64;
+
+        // Fraction of the current window that has elapsed (0.0 .. 1.0).
+        let elapsed_fraction = (elapsed_in_window / window_secs_f64).min(1.0);
+
+        // Weighted count: blend previous window's contribution with the current
+        // window's count.
+        let weighted_count =
+            (self.previous_count as f64) * (1.0 - elapsed_fraction) + (self.current_count as f64);
+
+        SlidingWindowDebug {
+            current_count: self.current_count,
+            previous_count: self.previous_count,
+            elapsed_fraction,
+            weighted_count,
+            limit: self.limit,
+            penalty: self.penalty,
+        }
+    }
 }
 
 /// A concurrent sliding window counter rate limiter.
@@ -15,10 +112,13 @@ struct SlidingWindowState {
 /// This algorithm approximates a true sliding window by interpolating between
 /// the previous and current fixed windows. It provides smoother rate limiting
 /// than a simple fixed-window counter while using very little memory per key.
-pub struct SlidingWindowLimiter {
-    windows: DashMap<String, SlidingWindowState>,
+///
+/// Counters live in `S`, the in-process [`InMemoryStore`] by default.
+pub struct SlidingWindowLimiter<S = InMemoryStore<SlidingWindowState>> {
+    windows: S,
     window_secs: u64,
     limit: u64,
+    adaptive: Option<AdaptiveLimitConfig>,
 }
 
 impl SlidingWindowLimiter {
@@ -29,63 +129,80 @@ impl SlidingWindowLimiter {
     ///
     /// The effective per-window limit is `rps * window_secs`.
     pub fn new(rps: u64, window_secs: u64) -> Self {
+        Self::with_store(rps, window_secs, InMemoryStore::new())
+    }
+}
+
+impl<S: RateLimitStore<SlidingWindowState>> SlidingWindowLimiter<S> {
+    /// Create a sliding window limiter that keeps its counters in `store`.
+    pub fn with_store(rps: u64, window_secs: u64, store: S) -> Self {
         Self {
-            windows: DashMap::new(),
+            windows: store,
             window_secs,
             limit: rps * window_secs,
+            adaptive: None,
         }
     }
 
+    /// Tighten the limit of keys that keep getting refused, per `adaptive`.
+    pub fn with_adaptive(mut self, adaptive: AdaptiveLimitConfig) -> Self {
+        self.adaptive = Some(adaptive);
+        self
+    }
+
     /// Check whether a request identified by `key` is allowed.
     ///
     /// Returns `true` if the request is permitted, or `false` if the caller
     /// has exceeded the rate limit.
     pub fn check(&self, key: &str) -> bool {
-        let now = Instant::now();
-        let window_duration = Duration::from_secs(self.window_secs);
+        self.check_with_capacity(key, None)
+    }
 
-        let mut entry = self.windows.entry(key.to_string()).or_insert_with(|| {
-            SlidingWindowState {
-                current_count: 0,
-                previous_count: 0,
-                window_start: now,
-                window_secs: self.window_secs,
-                limit: self.limit,
+    /// Like [`check`](Self::check), but bounds the number of tracked keys
+    /// according to `capacity`.
+    pub fn check_with_capacity(&self, key: &str, capacity: Option<&KeyCapacity>) -> bool {
+        if let Some(capacity) = capacity {
+            match self.windows.admit(capacity, key, |state| state.window_start) {
+                Admission::Admitted => {}
+                Admission::RefusedAllow => return true,
+                Admission::RefusedBlock => return false,
             }
-        });
+        }
 
-        let state = entry.value_mut();
+        self.record_at(key, Instant::now())
+    }
 
-        // Rotate windows if the current window has elapsed.
-        // We loop in case more than one full window has passed since the last
-        // request (e.g., the client was idle for a long time).
-        while now.duration_since(state.window_start) >= window_duration {
-            state.previous_count = state.current_count;
-            state.current_count = 0;
-            state.window_start += window_duration;
-        }
+    fn record_at(&self, key: &str, now: Instant) -> bool {
+        let window_duration = Duration::from_secs(self.window_secs);
 
-        // If the window_start is somehow in the future after rotation (shouldn't
-        // happen, but guard defensively), reset.
-        let elapsed_in_window = now
-            .duration_since(state.window_start)
-            .as_secs_f64();
-        let window_secs_f64 = state.window_secs as f64;
+        let init = || SlidingWindowState {
+            current_count: 0,
+            previous_count: 0,
+            window_start: now,
+            window_secs: self.window_secs,
+            limit: self.limit,
+            penalty: 1.0,
+            refused: false,
+        };
 
-        // Fraction of the current window that has elapsed (0.0 .. 1.0).
-        let elapsed_fraction = (elapsed_in_window / window_secs_f64).min(1.0);
+        let adaptive = self.adaptive.as_ref();
+        self.windows.update(key, init, |state| state.record(now, window_duration, adaptive))
+    }
 
-        // Weighted count: blend previous window's contribution with the current
-        // window's count.
-        let weighted_count =
-            (state.previous_count as f64) * (1.0 - elapsed_fraction) + (state.current_count as f64);
+    /// The intermediate values [`check`](Self::check) would compute for `key`
+    /// right now, without counting a request. Returns `None` if the key
+    /// isn't tracked.
+    pub fn debug_state(&self, key: &str) -> Option<SlidingWindowDebug> {
+        self.debug_state_at(key, Instant::now())
+    }
 
-        if weighted_count < state.limit as f64 {
-            state.current_count += 1;
-            true
-        } else {
-            false
-        }
+    fn debug_state_at(&self, key: &str, now: Instant) -> Option<SlidingWindowDebug> {
+        let window_duration = Duration::from_secs(self.window_secs);
+        self.windows.get(key, |state| {
+            let mut state = state.clone();
+            state.rotate(now, window_duration, self.adaptive.as_ref());
+            state.blend(now)
+        })
     }
 
     /// Remove entries whose window started more than `2 * window_secs` ago.
@@ -93,11 +210,15 @@ impl SlidingWindowLimiter {
     /// This should be called periodically (e.g., every 60 seconds) to prevent
     /// unbounded memory growth from one-off client keys.
     pub fn cleanup(&self) {
+        self.cleanup_older_than(Duration::from_secs(self.window_secs * 2));
+    }
+
+    /// Remove entries whose window started more than `ttl` ago.
+    pub fn cleanup_older_than(&self, ttl: Duration) {
         let now = Instant::now();
-        let stale_threshold = Duration::from_secs(self.window_secs * 2);
 
-        self.windows.retain(|_key, state| {
-            now.duration_since(state.window_start) < stale_threshold
+        self.windows.retain(|state| {
+            now.duration_since(state.window_start) < ttl
         });
 
         tracing::debug!(
@@ -105,6 +226,31 @@ impl SlidingWindowLimiter {
             "sliding window cleanup complete"
         );
     }
+
+    /// Requests allowed per window, `rps * window_secs`.
+    pub fn effective_limit(&self) -> u64 {
+        self.limit
+    }
+
+    /// Window duration in seconds.
+    pub fn window_secs(&self) -> u64 {
+        self.window_secs
+    }
+
+    /// How the limit tightens for clients that keep hitting it, if it does.
+    pub fn adaptive(&self) -> Option<&AdaptiveLimitConfig> {
+        self.adaptive.as_ref()
+    }
+
+    /// Number of keys currently tracked.
+    pub fn tracked_keys(&self) -> usize {
+        self.windows.len()
+    }
+
+    /// Whether `key` is currently tracked.
+    pub fn is_tracking(&self, key: &str) -> bool {
+        self.windows.contains_key(key)
+    }
 }
 
 #[cfg(test)]
@@ -125,6 +271,13 @@ mod tests {
         assert!(!limiter.check(key), "should deny beyond limit");
     }
 
+    #[test]
+    fn effective_limit_spans_window() {
+        let limiter = SlidingWindowLimiter::new(5, 60);
+        assert_eq!(limiter.effective_limit(), 300);
+        assert_eq!(limiter.window_secs(), 60);
+    }
+
     #[test]
     fn window_rotation_resets_count() {
         // 5 rps, 1-second window => limit of 5.
@@ -166,14 +319,109 @@ mod tests {
         limiter.check("will-be-stale");
 
         // Manually age one entry.
-        {
-            let mut entry = limiter.windows.get_mut("will-be-stale").unwrap();
-            entry.window_start = Instant::now() - Duration::from_secs(10);
-        }
+        limiter.windows.update("will-be-stale", || unreachable!(), |state| {
+            state.window_start = Instant::now() - Duration::from_secs(10);
+        });
 
         limiter.cleanup();
 
         assert!(limiter.windows.contains_key("keep-alive"));
         assert!(!limiter.windows.contains_key("will-be-stale"));
     }
+
+    #[test]
+    fn debug_state_matches_hand_computed_blend() {
+        // 10 rps, 1-second window => limit of 10.
+        let limiter = SlidingWindowLimiter::new(10, 1);
+        assert_eq!(limiter.debug_state("client"), None);
+        for _ in 0..4 {
+            assert!(limiter.check("client"));
+        }
+
+        let start = Instant::now();
+        limiter.windows.update("client", || unreachable!(), |state| {
+            state.window_start = start;
+        });
+
+        // Halfway through the first window: 4 counted, nothing before.
+        let mid = limiter.debug_state_at("client", start + Duration::from_millis(500));
+        assert_eq!(
+            mid,
+            Some(SlidingWindowDebug {
+                current_count: 4,
+                previous_count: 0,
+                elapsed_fraction: 0.5,
+                weighted_count: 4.0,
+                limit: 10,
+                penalty: 1.0,
+            })
+        );
+
+        // A quarter into the next window the 4 carry over at 75%.
+        let next = limiter.debug_state_at("client", start + Duration::from_millis(1250));
+        assert_eq!(
+            next,
+            Some(SlidingWindowDebug {
+                current_count: 0,
+                previous_count: 4,
+                elapsed_fraction: 0.25,
+                weighted_count: 3.0,
+                limit: 10,
+                penalty: 1.0,
+            })
+        );
+
+        // Inspecting neither rotates nor counts.
+        assert_eq!(
+            limiter.debug_state_at("client", start + Duration::from_millis(500)),
+            mid
+        );
+    }
+
+    #[test]
+    fn adaptive_limit_tightens_for_abusive_key() {
+        // 10 rps, 1-second window; halve on refusal, floor at 0.2
+        let limiter =
+            SlidingWindowLimiter::new(10, 1).with_adaptive(AdaptiveLimitConfig::default());
+        let start = Instant::now();
+        let penalty = |key, secs| {
+            let at = start + Duration::from_secs(secs) + Duration::from_millis(500);
+            limiter.debug_state_at(key, at).unwrap().penalty
+        };
+
+        let mut abusive = Vec::new();
+        for window in 0..4 {
+            let at = start + Duration::from_secs(window) + Duration::from_millis(100);
+            for _ in 0..30 {
+                limiter.record_at("abusive", at);
+            }
+            for _ in 0..5 {
+                assert!(limiter.record_at("polite", at));
+            }
+            abusive.push(penalty("abusive", window + 1));
+            assert_eq!(penalty("polite", window + 1), 1.0);
+        }
+        assert_eq!(abusive, vec![0.5, 0.25, 0.2, 0.2]);
+
+        // At the floor the key gets at most 2 of the base 10 per window
+        let at = start + Duration::from_secs(4) + Duration::from_millis(100);
+        let allowed = (0..30).filter(|_| limiter.record_at("abusive", at)).count();
+        assert!(allowed <= 2, "allowed {} at the floor", allowed);
+    }
+
+    #[test]
+    fn adaptive_limit_restores_after_good_behavior() {
+        let limiter =
+            SlidingWindowLimiter::new(10, 1).with_adaptive(AdaptiveLimitConfig::default());
+        let start = Instant::now();
+        for _ in 0..30 {
+            limiter.record_at("client", start);
+        }
+        let state = |secs| limiter.debug_state_at("client", start + Duration::from_secs(secs));
+        assert_eq!(state(1).unwrap().penalty, 0.5);
+
+        // Each quiet window gives back 0.1, up to the full limit
+        assert!((state(2).unwrap().penalty - 0.6).abs() < 1e-9);
+        assert_eq!(state(10).unwrap().penalty, 1.0);
+    }
 }
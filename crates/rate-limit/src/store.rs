@@ -0,0 +1,253 @@
+use dashmap::DashMap;
+use layer7waf_common::{Admission, KeyCapacity};
+use std::sync::Arc;
+use std::time::Instant;
+
+/// Per-key state storage for the rate-limiting algorithms.
+///
+/// The limiters keep all of their logic and only ask the store to read,
+/// update and evict the state `S` kept for each key. Implementations must
+/// make [`update`](Self::update) atomic per key, since concurrent requests
+/// from the same client race on it.
+pub trait RateLimitStore<S>: Send + Sync {
+    /// Run `f` on `key`'s state, creating it with `init` if the key isn't
+    /// tracked, and return its result.
+    fn update<R>(&self, key: &str, init: impl FnOnce() -> S, f: impl FnOnce(&mut S) -> R) -> R;
+
+    /// Run `f` on `key`'s state without modifying it. Returns `None` if the
+    /// key isn't tracked.
+    fn get<R>(&self, key: &str, f: impl FnOnce(&S) -> R) -> Option<R>;
+
+    /// Keep only the keys whose state satisfies `keep`.
+    fn retain(&self, keep: impl Fn(&S) -> bool);
+
+    /// Number of keys currently tracked.
+    fn len(&self) -> usize;
+
+    /// Whether no keys are tracked.
+    fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Whether `key` is currently tracked.
+    fn contains_key(&self, key: &str) -> bool;
+
+    /// Make room for `key` under `capacity`; see [`KeyCapacity::admit`].
+    fn admit(
+        &self,
+        capacity: &KeyCapacity,
+        key: &str,
+        last_seen: impl Fn(&S) -> Instant,
+    ) -> Admission;
+}
+
+/// The default store: a [`DashMap`] in process memory.
+pub struct InMemoryStore<S> {
+    map: DashMap<String, S>,
+}
+
+impl<S> InMemoryStore<S> {
+    pub fn new() -> Self {
+        Self { map: DashMap::new() }
+    }
+}
+
+impl<S> Default for InMemoryStore<S> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<S: Send + Sync> RateLimitStore<S> for InMemoryStore<S> {
+    fn update<R>(&self, key: &str, init: impl FnOnce() -> S, f: impl FnOnce(&mut S) -> R) -> R {
+        let mut entry = self.map.entry(key.to_string()).or_insert_with(init);
+        f(entry.value_mut())
+    }
+
+    fn get<R>(&self, key: &str, f: impl FnOnce(&S) -> R) -> Option<R> {
+        self.map.get(key).map(|entry| f(entry.value()))
+    }
+
+    fn retain(&self, keep: impl Fn(&S) -> bool) {
+        self.map.retain(|_key, state| keep(state));
+    }
+
+    fn len(&self) -> usize {
+        self.map.len()
+    }
+
+    fn contains_key(&self, key: &str) -> bool {
+        self.map.contains_key(key)
+    }
+
+    fn admit(
+        &self,
+        capacity: &KeyCapacity,
+        key: &str,
+        last_seen: impl Fn(&S) -> Instant,
+    ) -> Admission {
+        capacity.admit(&self.map, key, last_seen)
+    }
+}
+
+/// A shared store, so limiters built apart can count against the same keys.
+impl<S, T: RateLimitStore<S>> RateLimitStore<S> for Arc<T> {
+    fn update<R>(&self, key: &str, init: impl FnOnce() -> S, f: impl FnOnce(&mut S) -> R) -> R {
+        self.as_ref().update(key, init, f)
+    }
+
+    fn get<R>(&self, key: &str, f: impl FnOnce(&S) -> R) -> Option<R> {
+        self.as_ref().get(key, f)
+    }
+
+    fn retain(&self, keep: impl Fn(&S) -> bool) {
+        self.as_ref().retain(keep)
+    }
+
+    fn len(&self) -> usize {
+        self.as_ref().len()
+    }
+
+    fn contains_key(&self, key: &str) -> bool {
+        self.as_ref().contains_key(key)
+    }
+
+    fn admit(
+        &self,
+        capacity: &KeyCapacity,
+        key: &str,
+        last_seen: impl Fn(&S) -> Instant,
+    ) -> Admission {
+        self.as_ref().admit(capacity, key, last_seen)
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use layer7waf_common::{FailurePolicy, KeyOverflowPolicy, OverflowAction};
+    use std::collections::HashMap;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Mutex;
+    use std::time::Duration;
+
+    /// A `HashMap` behind a mutex that counts updates, standing in for an
+    /// external backend in the limiter tests.
+    pub(crate) struct MockStore<S> {
+        map: Mutex<HashMap<String, S>>,
+        pub(crate) updates: AtomicUsize,
+    }
+
+    impl<S> MockStore<S> {
+        pub(crate) fn new() -> Self {
+            Self {
+                map: Mutex::new(HashMap::new()),
+                updates: AtomicUsize::new(0),
+            }
+        }
+    }
+
+    impl<S: Send> RateLimitStore<S> for MockStore<S> {
+        fn update<R>(
+            &self,
+            key: &str,
+            init: impl FnOnce() -> S,
+            f: impl FnOnce(&mut S) -> R,
+        ) -> R {
+            self.updates.fetch_add(1, Ordering::Relaxed);
+            let mut map = self.map.lock().unwrap();
+            f(map.entry(key.to_string()).or_insert_with(init))
+        }
+
+        fn get<R>(&self, key: &str, f: impl FnOnce(&S) -> R) -> Option<R> {
+            self.map.lock().unwrap().get(key).map(f)
+        }
+
+        fn retain(&self, keep: impl Fn(&S) -> bool) {
+            self.map.lock().unwrap().retain(|_key, state| keep(state));
+        }
+
+        fn len(&self) -> usize {
+            self.map.lock().unwrap().len()
+        }
+
+        fn contains_key(&self, key: &str) -> bool {
+            self.map.lock().unwrap().contains_key(key)
+        }
+
+        fn admit(
+            &self,
+            capacity: &KeyCapacity,
+            key: &str,
+            last_seen: impl Fn(&S) -> Instant,
+        ) -> Admission {
+            let mut map = self.map.lock().unwrap();
+            if map.len() < capacity.max_keys || map.contains_key(key) {
+                return Admission::Admitted;
+            }
+            match capacity.on_overflow {
+                OverflowAction::EvictOldest => {
+                    let oldest = map
+                        .iter()
+                        .min_by_key(|(_, state)| last_seen(state))
+                        .map(|(key, _)| key.clone());
+                    if let Some(oldest) = oldest {
+                        map.remove(&oldest);
+                    }
+                    Admission::Admitted
+                }
+                OverflowAction::Allow => Admission::RefusedAllow,
+                OverflowAction::Block => Admission::RefusedBlock,
+            }
+        }
+    }
+
+    /// What the limiters rely on from any store, checked with `u64` state
+    /// standing for the milliseconds since `base` a key was last seen.
+    fn assert_store_contract(store: &impl RateLimitStore<u64>) {
+        let base = Instant::now();
+        let last_seen = |n: &u64| base + Duration::from_millis(*n);
+        assert_eq!(store.get("a", |n| *n), None);
+        assert!(store.is_empty());
+
+        let first = store.update("a", || 10, |n| {
+            *n += 1;
+            *n
+        });
+        assert_eq!(first, 11);
+        assert_eq!(store.update("a", || 10, |n| *n), 11);
+        assert_eq!(store.get("a", |n| *n), Some(11));
+
+        store.update("b", || 0, |_| ());
+        assert_eq!(store.len(), 2);
+        store.retain(|n| *n > 0);
+        assert!(store.contains_key("a"));
+        assert!(!store.contains_key("b"));
+
+        // A full store refuses a new key without tracking it, but still
+        // admits keys it already tracks
+        store.update("c", || 20, |_| ());
+        let refuse = KeyCapacity::new(2, KeyOverflowPolicy::Refuse, FailurePolicy::Block);
+        assert_eq!(store.admit(&refuse, "d", last_seen), Admission::RefusedBlock);
+        assert_eq!(store.admit(&refuse, "a", last_seen), Admission::Admitted);
+        assert!(!store.contains_key("d"));
+
+        // Or makes room by evicting the least recently seen
+        let evict = KeyCapacity::new(2, KeyOverflowPolicy::Evict, FailurePolicy::Block);
+        assert_eq!(store.admit(&evict, "d", last_seen), Admission::Admitted);
+        assert!(!store.contains_key("a"));
+        assert!(store.contains_key("c"));
+    }
+
+    #[test]
+    fn in_memory_store_meets_contract() {
+        assert_store_contract(&InMemoryStore::new());
+    }
+
+    #[test]
+    fn mock_store_meets_contract() {
+        let store = MockStore::new();
+        assert_store_contract(&store);
+        assert_eq!(store.updates.load(Ordering::Relaxed), 4);
+    }
+}
@@ -1,21 +1,32 @@
-use dashmap::DashMap;
-use std::time::Instant;
+use crate::store::{InMemoryStore, RateLimitStore};
+use layer7waf_common::{Admission, KeyCapacity};
+use std::time::{Duration, Instant};
 
-/// Internal state for a single token bucket entry.
-struct TokenBucketState {
+/// State of a single token bucket, as kept in a [`RateLimitStore`].
+pub struct TokenBucketState {
     tokens: f64,
     last_refill: Instant,
     rate: f64,
     burst: f64,
 }
 
+impl TokenBucketState {
+    /// Token balance at `now`, refilled since `last_refill` and capped at `burst`.
+    fn refilled(&self, now: Instant) -> f64 {
+        let elapsed = now.saturating_duration_since(self.last_refill).as_secs_f64();
+        (self.tokens + elapsed * self.rate).min(self.burst)
+    }
+}
+
 /// A concurrent token bucket rate limiter.
 ///
 /// Each key (e.g., client IP) gets its own independent bucket that refills at
 /// `rate` tokens per second up to a maximum of `burst` tokens. Every allowed
 /// request consumes exactly one token.
-pub struct TokenBucketLimiter {
-    buckets: DashMap<String, TokenBucketState>,
+///
+/// Buckets live in `S`, the in-process [`InMemoryStore`] by default.
+pub struct TokenBucketLimiter<S = InMemoryStore<TokenBucketState>> {
+    buckets: S,
     rate: f64,
     burst: f64,
 }
@@ -26,8 +37,15 @@ impl TokenBucketLimiter {
     /// * `rps`   - sustained requests per second (refill rate)
     /// * `burst` - maximum burst size (bucket capacity)
     pub fn new(rps: u64, burst: u64) -> Self {
+        Self::with_store(rps, burst, InMemoryStore::new())
+    }
+}
+
+impl<S: RateLimitStore<TokenBucketState>> TokenBucketLimiter<S> {
+    /// Create a token bucket limiter that keeps its buckets in `store`.
+    pub fn with_store(rps: u64, burst: u64, store: S) -> Self {
         Self {
-            buckets: DashMap::new(),
+            buckets: store,
             rate: rps as f64,
             burst: burst as f64,
         }
@@ -38,31 +56,65 @@ impl TokenBucketLimiter {
     /// Returns `true` if the request is permitted (a token was available and
     /// consumed), or `false` if the caller should be rate-limited.
     pub fn check(&self, key: &str) -> bool {
+        self.check_with_capacity(key, None)
+    }
+
+    /// Like [`check`](Self::check), but bounds the number of tracked keys.
+    /// When the map is full and `key` is new, the capacity's overflow action
+    /// decides whether old keys are evicted or the request is allowed/denied
+    /// without being tracked.
+    pub fn check_with_capacity(&self, key: &str, capacity: Option<&KeyCapacity>) -> bool {
+        if let Some(capacity) = capacity {
+            match self.buckets.admit(capacity, key, |state| state.last_refill) {
+                Admission::Admitted => {}
+                Admission::RefusedAllow => return true,
+                Admission::RefusedBlock => return false,
+            }
+        }
+
         let now = Instant::now();
 
-        let mut entry = self.buckets.entry(key.to_string()).or_insert_with(|| {
-            TokenBucketState {
-                tokens: self.burst,
-                last_refill: now,
-                rate: self.rate,
-                burst: self.burst,
+        let init = || TokenBucketState {
+            tokens: self.burst,
+            last_refill: now,
+            rate: self.rate,
+            burst: self.burst,
+        };
+
+        self.buckets.update(key, init, |state| {
+            // Refill tokens based on elapsed time.
+            state.tokens = state.refilled(now);
+            state.last_refill = now;
+
+            // Try to consume one token.
+            if state.tokens >= 1.0 {
+                state.tokens -= 1.0;
+                true
+            } else {
+                false
             }
-        });
+        })
+    }
 
-        let state = entry.value_mut();
+    /// Current token balance for `key`, with refill applied but nothing
+    /// consumed. Returns `None` if the key isn't tracked (a new client
+    /// would start with a full bucket).
+    pub fn tokens(&self, key: &str) -> Option<f64> {
+        self.buckets.get(key, |state| state.refilled(Instant::now()))
+    }
 
-        // Refill tokens based on elapsed time.
-        let elapsed = now.duration_since(state.last_refill).as_secs_f64();
-        state.tokens = (state.tokens + elapsed * state.rate).min(state.burst);
-        state.last_refill = now;
+    /// Number of requests `key` could make right now without being limited.
+    pub fn remaining_capacity(&self, key: &str) -> Option<u64> {
+        self.tokens(key).map(|tokens| tokens.floor() as u64)
+    }
 
-        // Try to consume one token.
-        if state.tokens >= 1.0 {
-            state.tokens -= 1.0;
-            true
-        } else {
-            false
+    /// Time until `key` has a whole token again; zero if it has one now.
+    pub fn retry_after(&self, key: &str) -> Option<Duration> {
+        let tokens = self.tokens(key)?;
+        if tokens >= 1.0 || self.rate <= 0.0 {
+            return Some(Duration::ZERO);
         }
+        Some(Duration::from_secs_f64((1.0 - tokens) / self.rate))
     }
 
     /// Remove entries that have not been accessed in more than 5 minutes.
@@ -70,11 +122,15 @@ impl TokenBucketLimiter {
     /// This should be called periodically (e.g., every 60 seconds) to prevent
     /// unbounded memory growth from one-off client keys.
     pub fn cleanup(&self) {
+        self.cleanup_older_than(Duration::from_secs(5 * 60));
+    }
+
+    /// Remove entries that have not been accessed within `ttl`.
+    pub fn cleanup_older_than(&self, ttl: Duration) {
         let now = Instant::now();
-        let stale_threshold = std::time::Duration::from_secs(5 * 60);
 
-        self.buckets.retain(|_key, state| {
-            now.duration_since(state.last_refill) < stale_threshold
+        self.buckets.retain(|state| {
+            now.duration_since(state.last_refill) < ttl
         });
 
         tracing::debug!(
@@ -82,13 +138,24 @@ impl TokenBucketLimiter {
             "token bucket cleanup complete"
         );
     }
+
+    /// Number of keys currently tracked.
+    pub fn tracked_keys(&self) -> usize {
+        self.buckets.len()
+    }
+
+    /// Whether `key` is currently tracked.
+    pub fn is_tracking(&self, key: &str) -> bool {
+        self.buckets.contains_key(key)
+    }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::store::tests::MockStore;
+    use std::sync::atomic::Ordering;
     use std::thread;
-    use std::time::Duration;
 
     #[test]
     fn allows_up_to_burst() {
@@ -121,6 +188,39 @@ mod tests {
         assert!(limiter.check(key), "should allow after refill");
     }
 
+    #[test]
+    fn tokens_reflect_consumption_and_refill() {
+        let limiter = TokenBucketLimiter::new(10, 5);
+        let key = "balance-client";
+        assert_eq!(limiter.tokens(key), None);
+
+        limiter.check(key);
+        let after_one = limiter.tokens(key).unwrap();
+        assert!((4.0..4.1).contains(&after_one));
+        assert_eq!(limiter.remaining_capacity(key), Some(4));
+
+        limiter.check(key);
+        limiter.check(key);
+        assert!(limiter.tokens(key).unwrap() < after_one - 1.5);
+        assert_eq!(limiter.remaining_capacity(key), Some(2));
+        assert_eq!(limiter.retry_after(key), Some(Duration::ZERO));
+
+        // Peeking must not consume.
+        for _ in 0..10 {
+            limiter.tokens(key);
+        }
+        assert_eq!(limiter.remaining_capacity(key), Some(2));
+
+        // Drain, then the balance refills with time.
+        while limiter.check(key) {}
+        let drained = limiter.tokens(key).unwrap();
+        assert!(drained < 1.0);
+        assert!(limiter.retry_after(key).unwrap() > Duration::ZERO);
+        thread::sleep(Duration::from_millis(150));
+        assert!(limiter.tokens(key).unwrap() >= drained + 1.0);
+        assert_eq!(limiter.retry_after(key), Some(Duration::ZERO));
+    }
+
     #[test]
     fn independent_keys() {
         let limiter = TokenBucketLimiter::new(10, 2);
@@ -141,14 +241,24 @@ mod tests {
         limiter.check("will-be-stale");
 
         // Manually age one entry by replacing its last_refill.
-        {
-            let mut entry = limiter.buckets.get_mut("will-be-stale").unwrap();
-            entry.last_refill = Instant::now() - Duration::from_secs(6 * 60);
-        }
+        limiter.buckets.update("will-be-stale", || unreachable!(), |state| {
+            state.last_refill = Instant::now() - Duration::from_secs(6 * 60);
+        });
 
         limiter.cleanup();
 
         assert!(limiter.buckets.contains_key("keep-alive"));
         assert!(!limiter.buckets.contains_key("will-be-stale"));
     }
+
+    #[test]
+    fn one_store_update_per_check() {
+        // The store contract itself is tested in `store`
+        let limiter = TokenBucketLimiter::with_store(10, 3, MockStore::new());
+        for _ in 0..4 {
+            limiter.check("client");
+        }
+        assert_eq!(limiter.buckets.updates.load(Ordering::Relaxed), 4);
+        assert_eq!(limiter.remaining_capacity("client"), Some(0));
+    }
 }